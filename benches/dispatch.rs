@@ -0,0 +1,68 @@
+//! Throughput of request parsing, dispatching and reply serialization
+//!
+//! Requests are sent through a `MockChannel`, so these benchmarks measure the library's own
+//! overhead per request with a filesystem that does (almost) nothing. Run with `cargo bench`.
+
+use std::ffi::OsStr;
+use async_fuse::{block_on, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+use async_trait::async_trait;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+const FILE_INO: u64 = 2;
+const ENTRIES: u64 = 100;
+
+/// Filesystem answering every request from memory
+struct Bench {
+    data: Vec<u8>,
+}
+
+#[async_trait]
+impl Filesystem for Bench {
+    async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+        Ok(Entry::new(FileAttr::builder(FILE_INO, FileType::RegularFile).size(self.data.len() as u64).build(), 0))
+    }
+
+    async fn read(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let start = (offset as usize).min(self.data.len());
+        let end = (start + size as usize).min(self.data.len());
+        Ok(Data::from(&self.data[start..end]))
+    }
+
+    async fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        Ok(data.len() as u32)
+    }
+
+    async fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        Ok((offset.position(0)?..ENTRIES).map(|i| {
+            DirEntry { ino: i + 2, offset: DirOffset::new(i + 1, 0), kind: FileType::RegularFile, name: format!("file{:04}", i).into() }
+        }).collect())
+    }
+}
+
+fn dispatch(c: &mut Criterion) {
+    let ch = MockChannel::new(Bench { data: vec![0x55; 128 * 1024] });
+    block_on(ch.init()).unwrap();
+    let buffer = vec![0xaa; 128 * 1024];
+
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("lookup", |b| b.iter(|| {
+        block_on(ch.lookup(FUSE_ROOT_ID, black_box("file"))).unwrap()
+    }));
+    group.throughput(Throughput::Bytes(4096));
+    group.bench_function("read 4K", |b| b.iter(|| {
+        block_on(ch.read(FILE_INO, 0, black_box(0), 4096)).unwrap()
+    }));
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("write 128K", |b| b.iter(|| {
+        block_on(ch.write(FILE_INO, 0, 0, black_box(&buffer))).unwrap()
+    }));
+    group.throughput(Throughput::Elements(ENTRIES));
+    group.bench_function("readdir", |b| b.iter(|| {
+        block_on(ch.readdir(FUSE_ROOT_ID, 0, black_box(0), 4096)).unwrap()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -1,103 +1,99 @@
+//! Hello world filesystem
+//!
+//! A minimal read-only filesystem with a single file `hello.txt` in its root directory. This is
+//! the starting point for new filesystems: it shows how inode numbers, entry and attribute
+//! TTLs, reads at offsets and readdir offsets work together.
+//!
+//! Usage: `hello <mountpoint>`
+
 use std::env;
-use std::ffi::{OsStr, OsString};
-use std::time::{Duration, UNIX_EPOCH};
-use libc::ENOENT;
-use async_fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+use std::ffi::OsStr;
+use std::time::{Duration, SystemTime};
+use async_fuse::{FileType, FileAttr, Filesystem, Request, Entry, Attr, DirEntry, DirOffset, Data, Errno, Mount, Opened, FUSE_ROOT_ID};
 use async_trait::async_trait;
 
-const TTL: Duration = Duration::from_secs(1);           // 1 second
-
-const HELLO_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-};
+/// Time the kernel may cache names and attributes. Nothing ever changes, so this could be
+/// much longer.
+const TTL: Duration = Duration::from_secs(1);
 
+const HELLO_TXT_INO: u64 = 2;
 const HELLO_TXT_CONTENT: &str = "Hello World!\n";
 
-const HELLO_TXT_ATTR: FileAttr = FileAttr {
-    ino: 2,
-    size: 13,
-    blocks: 1,
-    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::RegularFile,
-    perm: 0o644,
-    nlink: 1,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-};
+struct HelloFS {
+    dir_attr: FileAttr,
+    file_attr: FileAttr,
+}
 
-struct HelloFS;
+impl HelloFS {
+    fn new() -> HelloFS {
+        // Files belong to the user running the filesystem, and have been modified on start
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let now = SystemTime::now();
+        HelloFS {
+            dir_attr: FileAttr::builder(FUSE_ROOT_ID, FileType::Directory).time(now).perm(0o555).uid(uid).gid(gid).build(),
+            file_attr: FileAttr::builder(HELLO_TXT_INO, FileType::RegularFile).time(now).perm(0o444).uid(uid).gid(gid)
+                .size(HELLO_TXT_CONTENT.len() as u64).build(),
+        }
+    }
+}
 
 #[async_trait]
 impl Filesystem for HelloFS {
-    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
-        } else {
-            reply.error(ENOENT);
+    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        match (parent, name.to_str()) {
+            (FUSE_ROOT_ID, Some("hello.txt")) => Ok(Entry { ttl: Some(TTL), attr: self.file_attr, generation: 0 }),
+            (FUSE_ROOT_ID, _) => Err(Errno::ENOENT),
+            _ => Err(Errno::ENOTDIR),
         }
     }
 
-    async fn getattr(&self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
         match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
+            FUSE_ROOT_ID => Ok(Attr { ttl: Some(TTL), attr: self.dir_attr }),
+            HELLO_TXT_INO => Ok(Attr { ttl: Some(TTL), attr: self.file_attr }),
+            _ => Err(Errno::ENOENT),
         }
     }
 
-    async fn read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, _size: u32, reply: ReplyData) {
-        if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
-        }
+    async fn open(&self, _req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        if ino != HELLO_TXT_INO { return Err(Errno::EISDIR); }
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY { return Err(Errno::EACCES); }
+        Ok(Opened::default())
     }
 
-    async fn readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
-        }
+    async fn read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        if ino != HELLO_TXT_INO { return Err(Errno::EISDIR); }
+        // Reads may start beyond the end of the file and return less than the requested size
+        let content = HELLO_TXT_CONTENT.as_bytes();
+        let start = (offset as usize).min(content.len());
+        let end = (start + size as usize).min(content.len());
+        Ok(Data::from_static(&content[start..end]))
+    }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
+    async fn readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        if ino != FUSE_ROOT_ID { return Err(Errno::ENOTDIR); }
+
+        let entries = [
+            (FUSE_ROOT_ID, FileType::Directory, "."),
+            (FUSE_ROOT_ID, FileType::Directory, ".."),
+            (HELLO_TXT_INO, FileType::RegularFile, "hello.txt"),
         ];
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
-        }
-        reply.ok();
+        // The offset of an entry is the offset of the following one, the kernel passes it to
+        // the next readdir call if the entries don't fit into its buffer
+        Ok(entries.iter().enumerate().skip(offset.position(0)? as usize).map(|(i, &(ino, kind, name))| {
+            DirEntry { ino, offset: DirOffset::new(i as u64 + 1, 0), kind, name: name.into() }
+        }).collect())
     }
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let mountpoint = env::args_os().nth(1).unwrap();
-    let options = ["-o", "ro", "-o", "fsname=hello"]
-        .iter()
-        .map(|s| OsString::from(&s))
-        .collect::<Vec<OsString>>();
-    async_fuse::mount(HelloFS, mountpoint, &options).unwrap();
+    let mountpoint = env::args_os().nth(1).expect("Usage: hello <mountpoint>");
+    Mount::new(HelloFS::new(), mountpoint)
+        .option("ro")
+        .fsname("hello")
+        .run()
+        .unwrap();
 }
@@ -0,0 +1,455 @@
+//! In-memory filesystem
+//!
+//! A complete read-write filesystem keeping all files, directories, symlinks and extended
+//! attributes in memory. Permissions are checked by the kernel (`default_permissions`), inodes
+//! are kept until they are unlinked and forgotten by the kernel, so open files survive unlinking.
+//!
+//! Usage: `memfs <mountpoint>`
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use async_fuse::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, Mount, OpenFlags, Request, Statfs, Xattr};
+use async_fuse::FUSE_ROOT_ID;
+use async_trait::async_trait;
+
+/// Block size reported in attributes and statistics
+const BLOCK_SIZE: u32 = 4096;
+
+/// Contents of an inode
+#[derive(Debug)]
+enum Contents {
+    File(Vec<u8>),
+    /// Directory entries by their readdir position. Positions of removed entries are not reused,
+    /// so readdir continues correctly if the directory is modified between calls.
+    Dir { parent: u64, entries: BTreeMap<u64, (OsString, u64)>, next_offset: u64 },
+    Symlink(Vec<u8>),
+    Special,
+}
+
+#[derive(Debug)]
+struct Node {
+    attr: FileAttr,
+    contents: Contents,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+    /// Number of lookups the kernel hasn't forgotten yet
+    lookups: u64,
+}
+
+impl Node {
+    fn entries(&self) -> Result<&BTreeMap<u64, (OsString, u64)>, Errno> {
+        match self.contents {
+            Contents::Dir { ref entries, .. } => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn find(&self, name: &OsStr) -> Result<Option<(u64, u64)>, Errno> {
+        Ok(self.entries()?.iter().find(|(_, (entry, _))| entry == name).map(|(offset, (_, ino))| (*offset, *ino)))
+    }
+}
+
+/// Type, permissions, device number and contents of an inode to create
+struct NewNode {
+    kind: FileType,
+    mode: u32,
+    rdev: u32,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+struct Nodes {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+}
+
+impl Nodes {
+    fn get(&self, ino: u64) -> Result<&Node, Errno> {
+        self.nodes.get(&ino).ok_or(Errno::ENOENT)
+    }
+
+    fn get_mut(&mut self, ino: u64) -> Result<&mut Node, Errno> {
+        self.nodes.get_mut(&ino).ok_or(Errno::ENOENT)
+    }
+
+    /// Returns the inode of the given directory entry
+    fn child(&self, parent: u64, name: &OsStr) -> Result<u64, Errno> {
+        let (_, ino) = self.get(parent)?.find(name)?.ok_or(Errno::ENOENT)?;
+        Ok(ino)
+    }
+
+    /// Returns an entry for the given inode, counting it as looked up
+    fn entry(&mut self, ino: u64) -> Result<Entry, Errno> {
+        let node = self.get_mut(ino)?;
+        node.lookups += 1;
+        Ok(Entry::new(node.attr, 0))
+    }
+
+    /// Add a directory entry
+    fn link(&mut self, parent: u64, name: &OsStr, ino: u64) -> Result<(), Errno> {
+        let now = SystemTime::now();
+        let dir = self.get_mut(parent)?;
+        match dir.contents {
+            Contents::Dir { ref mut entries, ref mut next_offset, .. } => {
+                entries.insert(*next_offset, (name.into(), ino));
+                *next_offset += 1;
+            }
+            _ => return Err(Errno::ENOTDIR),
+        }
+        dir.attr.mtime = now;
+        dir.attr.ctime = now;
+        Ok(())
+    }
+
+    /// Remove a directory entry and drop the inode if it's not referenced anymore
+    fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let now = SystemTime::now();
+        let dir = self.get_mut(parent)?;
+        let (offset, ino) = dir.find(name)?.ok_or(Errno::ENOENT)?;
+        if let Contents::Dir { ref mut entries, .. } = dir.contents {
+            entries.remove(&offset);
+        }
+        dir.attr.mtime = now;
+        dir.attr.ctime = now;
+        let node = self.get_mut(ino)?;
+        node.attr.nlink -= 1;
+        node.attr.ctime = now;
+        self.release(ino);
+        Ok(())
+    }
+
+    /// Drop the given inode if it's neither linked nor known by the kernel
+    fn release(&mut self, ino: u64) {
+        let unused = self.nodes.get(&ino).is_some_and(|node| node.lookups == 0 && node.attr.nlink == 0);
+        if unused {
+            self.nodes.remove(&ino);
+        }
+    }
+
+    /// Create a new inode as the given directory entry
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, new: NewNode) -> Result<Entry, Errno> {
+        let NewNode { kind, mode, rdev, contents } = new;
+        if self.get(parent)?.find(name)?.is_some() { return Err(Errno::EEXIST); }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let attr = FileAttr::builder(ino, kind)
+            .time(SystemTime::now())
+            .perm((mode & 0o7777) as u16)
+            .uid(req.uid())
+            .gid(req.gid())
+            .rdev(rdev)
+            .blksize(BLOCK_SIZE)
+            .build();
+        self.nodes.insert(ino, Node { attr, contents, xattrs: BTreeMap::new(), lookups: 0 });
+        self.link(parent, name, ino)?;
+        if kind == FileType::Directory {
+            self.get_mut(parent)?.attr.nlink += 1;
+        }
+        self.entry(ino)
+    }
+}
+
+struct MemFs {
+    nodes: Mutex<Nodes>,
+}
+
+impl MemFs {
+    fn new() -> MemFs {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let attr = FileAttr::builder(FUSE_ROOT_ID, FileType::Directory).time(SystemTime::now()).uid(uid).gid(gid).blksize(BLOCK_SIZE).build();
+        let contents = Contents::Dir { parent: FUSE_ROOT_ID, entries: BTreeMap::new(), next_offset: 3 };
+        let root = Node { attr, contents, xattrs: BTreeMap::new(), lookups: 1 };
+        let nodes = Nodes { nodes: vec![(FUSE_ROOT_ID, root)].into_iter().collect(), next_ino: FUSE_ROOT_ID + 1 };
+        MemFs { nodes: Mutex::new(nodes) }
+    }
+}
+
+/// Returns the given data as file size and number of blocks
+fn size(data: &[u8]) -> (u64, u64) {
+    (data.len() as u64, (data.len() as u64).div_ceil(512))
+}
+
+#[async_trait]
+impl Filesystem for MemFs {
+    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = nodes.child(parent, name)?;
+        nodes.entry(ino)
+    }
+
+    async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Ok(node) = nodes.get_mut(ino) {
+            node.lookups = node.lookups.saturating_sub(nlookup);
+            nodes.release(ino);
+        }
+    }
+
+    async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+        Ok(Attr::new(self.nodes.lock().unwrap().get(ino)?.attr))
+    }
+
+    async fn setattr(&self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(ino)?;
+        let now = SystemTime::now();
+        if let Some(size) = size {
+            match node.contents {
+                Contents::File(ref mut data) => {
+                    data.resize(size as usize, 0);
+                    let (size, blocks) = self::size(data);
+                    node.attr.size = size;
+                    node.attr.blocks = blocks;
+                    node.attr.mtime = now;
+                }
+                Contents::Dir { .. } => return Err(Errno::EISDIR),
+                _ => return Err(Errno::EINVAL),
+            }
+        }
+        if let Some(mode) = mode { node.attr.perm = (mode & 0o7777) as u16; }
+        if let Some(uid) = uid { node.attr.uid = uid; }
+        if let Some(gid) = gid { node.attr.gid = gid; }
+        if let Some(atime) = atime { node.attr.atime = atime; }
+        if let Some(mtime) = mtime { node.attr.mtime = mtime; }
+        node.attr.ctime = now;
+        Ok(Attr::new(node.attr))
+    }
+
+    async fn readlink(&self, _req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        match self.nodes.lock().unwrap().get(ino)?.contents {
+            Contents::Symlink(ref target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let kind = FileType::from_mode(mode).ok_or(Errno::EINVAL)?;
+        let contents = match kind {
+            FileType::RegularFile => Contents::File(Vec::new()),
+            FileType::Directory | FileType::Symlink => return Err(Errno::EINVAL),
+            _ => Contents::Special,
+        };
+        self.nodes.lock().unwrap().create(req, parent, name, NewNode { kind, mode, rdev, contents })
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let contents = Contents::Dir { parent, entries: BTreeMap::new(), next_offset: 3 };
+        self.nodes.lock().unwrap().create(req, parent, name, NewNode { kind: FileType::Directory, mode, rdev: 0, contents })
+    }
+
+    async fn unlink(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = nodes.child(parent, name)?;
+        if nodes.get(ino)?.attr.kind == FileType::Directory { return Err(Errno::EISDIR); }
+        nodes.unlink(parent, name)
+    }
+
+    async fn rmdir(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = nodes.child(parent, name)?;
+        if !nodes.get(ino)?.entries()?.is_empty() { return Err(Errno::ENOTEMPTY); }
+        // Directories have an additional link from their "." entry
+        nodes.get_mut(ino)?.attr.nlink -= 1;
+        nodes.get_mut(parent)?.attr.nlink -= 1;
+        nodes.unlink(parent, name)
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let target = link.as_os_str().as_bytes().to_vec();
+        let mut nodes = self.nodes.lock().unwrap();
+        let entry = nodes.create(req, parent, name, NewNode { kind: FileType::Symlink, mode: 0o777, rdev: 0, contents: Contents::Symlink(target.clone()) })?;
+        let node = nodes.get_mut(entry.attr.ino)?;
+        let (size, blocks) = size(&target);
+        node.attr.size = size;
+        node.attr.blocks = blocks;
+        Ok(Entry::new(node.attr, 0))
+    }
+
+    async fn rename(&self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = nodes.child(parent, name)?;
+        let is_dir = nodes.get(ino)?.attr.kind == FileType::Directory;
+        // Moving a directory into itself or one of its subdirectories would detach it
+        if is_dir {
+            let mut ancestor = newparent;
+            while ancestor != FUSE_ROOT_ID {
+                if ancestor == ino { return Err(Errno::EINVAL); }
+                ancestor = match nodes.get(ancestor)?.contents {
+                    Contents::Dir { parent, .. } => parent,
+                    _ => return Err(Errno::ENOTDIR),
+                };
+            }
+        }
+        // Replace an existing target, which needs to be of the same kind (and empty if a directory)
+        if let Some((_, existing)) = nodes.get(newparent)?.find(newname)? {
+            if existing == ino { return Ok(()); }
+            let target = nodes.get(existing)?;
+            match (is_dir, target.attr.kind == FileType::Directory) {
+                (true, false) => return Err(Errno::ENOTDIR),
+                (false, true) => return Err(Errno::EISDIR),
+                (true, true) if !target.entries()?.is_empty() => return Err(Errno::ENOTEMPTY),
+                (true, true) => {
+                    nodes.get_mut(existing)?.attr.nlink -= 1;
+                    nodes.get_mut(newparent)?.attr.nlink -= 1;
+                }
+                (false, false) => (),
+            }
+            nodes.unlink(newparent, newname)?;
+        }
+        // Move the entry, keeping the inode linked while doing so
+        nodes.get_mut(ino)?.attr.nlink += 1;
+        nodes.unlink(parent, name)?;
+        nodes.link(newparent, newname, ino)?;
+        if is_dir && parent != newparent {
+            nodes.get_mut(parent)?.attr.nlink -= 1;
+            nodes.get_mut(newparent)?.attr.nlink += 1;
+            if let Contents::Dir { ref mut parent, .. } = nodes.get_mut(ino)?.contents {
+                *parent = newparent;
+            }
+        }
+        Ok(())
+    }
+
+    async fn link(&self, _req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.get(ino)?.attr.kind == FileType::Directory { return Err(Errno::EPERM); }
+        if nodes.get(newparent)?.find(newname)?.is_some() { return Err(Errno::EEXIST); }
+        nodes.link(newparent, newname, ino)?;
+        let node = nodes.get_mut(ino)?;
+        node.attr.nlink += 1;
+        node.attr.ctime = SystemTime::now();
+        nodes.entry(ino)
+    }
+
+    async fn read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(ino)?;
+        let data = match node.contents {
+            Contents::File(ref data) => data,
+            Contents::Dir { .. } => return Err(Errno::EISDIR),
+            _ => return Err(Errno::EINVAL),
+        };
+        let start = (offset as usize).min(data.len());
+        let end = (start + size as usize).min(data.len());
+        let data = data[start..end].to_vec();
+        node.attr.atime = SystemTime::now();
+        Ok(data.into())
+    }
+
+    async fn write(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(ino)?;
+        let contents = match node.contents {
+            Contents::File(ref mut contents) => contents,
+            Contents::Dir { .. } => return Err(Errno::EISDIR),
+            _ => return Err(Errno::EINVAL),
+        };
+        let end = offset as usize + data.len();
+        if end > contents.len() {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        let (size, blocks) = size(contents);
+        let now = SystemTime::now();
+        node.attr.size = size;
+        node.attr.blocks = blocks;
+        node.attr.mtime = now;
+        node.attr.ctime = now;
+        Ok(data.len() as u32)
+    }
+
+    async fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    async fn fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    async fn readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        let nodes = self.nodes.lock().unwrap();
+        let dir = nodes.get(ino)?;
+        let parent = match dir.contents {
+            Contents::Dir { parent, .. } => parent,
+            _ => return Err(Errno::ENOTDIR),
+        };
+        // Offsets are positions in the directory, checked against its inode number
+        let position = offset.position(ino)?;
+        let dots = vec![
+            DirEntry { ino, offset: DirOffset::new(1, ino), kind: FileType::Directory, name: ".".into() },
+            DirEntry { ino: parent, offset: DirOffset::new(2, ino), kind: FileType::Directory, name: "..".into() },
+        ];
+        let entries = dir.entries()?.range(position + 1..).filter_map(|(&position, (name, entry))| {
+            let kind = nodes.get(*entry).ok()?.attr.kind;
+            Some(DirEntry { ino: *entry, offset: DirOffset::new(position, ino), kind, name: name.clone() })
+        });
+        Ok(dots.into_iter().skip(position as usize).chain(entries).collect())
+    }
+
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    async fn statfs(&self, _req: &Request, _ino: u64) -> Result<Statfs, Errno> {
+        let nodes = self.nodes.lock().unwrap();
+        let blocks = nodes.nodes.values().map(|node| node.attr.blocks).sum::<u64>() * 512 / BLOCK_SIZE as u64;
+        let total = blocks + (1 << 20);
+        let files = nodes.nodes.len() as u64;
+        Ok(Statfs { blocks: total, bfree: total - blocks, bavail: total - blocks, files: files + (1 << 20), ffree: 1 << 20, bsize: BLOCK_SIZE, namelen: 255, frsize: BLOCK_SIZE })
+    }
+
+    async fn setxattr(&self, _req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(ino)?;
+        let exists = node.xattrs.contains_key(name);
+        if flags as i32 & libc::XATTR_CREATE != 0 && exists { return Err(Errno::EEXIST); }
+        if flags as i32 & libc::XATTR_REPLACE != 0 && !exists { return Err(Errno::ENOATTR); }
+        node.xattrs.insert(name.into(), value.to_vec());
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    async fn getxattr(&self, _req: &Request, ino: u64, name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+        let nodes = self.nodes.lock().unwrap();
+        let value = nodes.get(ino)?.xattrs.get(name).ok_or(Errno::ENOATTR)?;
+        Ok(Xattr::Data(value.clone()))
+    }
+
+    async fn listxattr(&self, _req: &Request, ino: u64, _size: u32) -> Result<Xattr, Errno> {
+        let nodes = self.nodes.lock().unwrap();
+        Ok(Xattr::names(nodes.get(ino)?.xattrs.keys()))
+    }
+
+    async fn removexattr(&self, _req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(ino)?;
+        node.xattrs.remove(name).ok_or(Errno::ENOATTR)?;
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    async fn access(&self, _req: &Request, ino: u64, _mask: u32) -> Result<(), Errno> {
+        // Permissions are checked by the kernel
+        self.nodes.lock().unwrap().get(ino).map(|_| ())
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, _flags: u32) -> Result<Created, Errno> {
+        let entry = self.nodes.lock().unwrap().create(req, parent, name, NewNode { kind: FileType::RegularFile, mode, rdev: 0, contents: Contents::File(Vec::new()) })?;
+        Ok(Created { ttl: entry.ttl, attr: entry.attr, generation: entry.generation, fh: 0, flags: OpenFlags::empty(), backing: None })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).expect("Usage: memfs <mountpoint>");
+    Mount::new(MemFs::new(), mountpoint)
+        .fsname("memfs")
+        .option("default_permissions")
+        .run()
+        .unwrap();
+}
@@ -0,0 +1,304 @@
+//! Passthrough filesystem
+//!
+//! Mirrors a directory of the host: every operation is performed on the corresponding file in
+//! the source directory. Implemented as a `PathFilesystem`, so `PathFs` takes care of inode
+//! numbers. Blocking file I/O runs on tokio's blocking thread pool, open files and directory
+//! listings are kept as handles. Files are created as the user running the filesystem.
+//!
+//! Usage: `passthrough <source> <mountpoint>`
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, DirEntryExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_fuse::{Attr, Created, Data, DirEntry, DirOffset, Errno, FileAttr, FileType, Mount, OpenFlags, Opened, PathFilesystem, PathFs, Request, Statfs};
+use async_trait::async_trait;
+
+/// Open file or directory listing
+#[derive(Debug)]
+enum Handle {
+    File(Arc<File>),
+    /// Directory entries read on opendir. Offsets are indexes into this list, so they stay valid
+    /// while the directory is read in multiple calls.
+    Dir(Arc<Vec<DirEntry>>),
+}
+
+struct Passthrough {
+    source: PathBuf,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_fh: AtomicU64,
+}
+
+/// Run the given blocking I/O operation on tokio's blocking thread pool
+async fn blocking<T, F>(f: F) -> Result<T, Errno>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f).await.map_err(|_| Errno::EIO)??)
+}
+
+/// Returns the given path as C string
+fn cstr(path: &Path) -> Result<CString, Errno> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)
+}
+
+/// Returns the result of a libc call as io::Result
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Returns the given time as timespec for utimensat, leaving the time unchanged if not given
+fn timespec(time: Option<SystemTime>) -> libc::timespec {
+    match time.map(|time| time.duration_since(UNIX_EPOCH)) {
+        Some(Ok(duration)) => libc::timespec { tv_sec: duration.as_secs() as libc::time_t, tv_nsec: duration.subsec_nanos() as _ },
+        Some(Err(_)) => libc::timespec { tv_sec: 0, tv_nsec: 0 },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    }
+}
+
+impl Passthrough {
+    fn new(source: PathBuf) -> Passthrough {
+        Passthrough { source, handles: Mutex::new(HashMap::new()), next_fh: AtomicU64::new(1) }
+    }
+
+    /// Returns the path in the source directory of the given path of the filesystem
+    fn source(&self, path: &Path) -> PathBuf {
+        self.source.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    async fn attr(&self, path: &Path) -> Result<Attr, Errno> {
+        let metadata = tokio::fs::symlink_metadata(self.source(path)).await?;
+        Ok(Attr::new(FileAttr::from(metadata)))
+    }
+
+    fn add_handle(&self, handle: Handle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    fn file(&self, fh: u64) -> Result<Arc<File>, Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::File(file)) => Ok(file.clone()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// Open the given source file with the given flags (without O_CREAT, O_EXCL and O_TRUNC,
+    /// which the kernel handles itself or passes as separate operations)
+    fn open_options(flags: u32) -> OpenOptions {
+        let flags = flags as i32;
+        let mut options = OpenOptions::new();
+        match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => options.write(true),
+            libc::O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options.custom_flags(flags & !(libc::O_ACCMODE | libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC | libc::O_NOCTTY));
+        options
+    }
+}
+
+#[async_trait]
+impl PathFilesystem for Passthrough {
+    async fn getattr(&self, _req: &Request, path: &Path) -> Result<Attr, Errno> {
+        self.attr(path).await
+    }
+
+    async fn setattr(&self, _req: &Request, path: &Path, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        let source = self.source(path);
+        if let Some(mode) = mode {
+            let source = source.clone();
+            blocking(move || fs::set_permissions(source, fs::Permissions::from_mode(mode & 0o7777))).await?;
+        }
+        if uid.is_some() || gid.is_some() {
+            let source = source.clone();
+            blocking(move || std::os::unix::fs::lchown(source, uid, gid)).await?;
+        }
+        if let Some(size) = size {
+            let file = match fh {
+                Some(fh) => self.file(fh)?,
+                None => {
+                    let source = source.clone();
+                    Arc::new(blocking(move || OpenOptions::new().write(true).open(source)).await?)
+                }
+            };
+            blocking(move || file.set_len(size)).await?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            let path = cstr(&source)?;
+            let times = [timespec(atime), timespec(mtime)];
+            blocking(move || check(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })).await?;
+        }
+        self.attr(path).await
+    }
+
+    async fn readlink(&self, _req: &Request, path: &Path) -> Result<Vec<u8>, Errno> {
+        let target = tokio::fs::read_link(self.source(path)).await?;
+        Ok(target.into_os_string().as_bytes().to_vec())
+    }
+
+    async fn mknod(&self, _req: &Request, path: &Path, mode: u32, rdev: u32) -> Result<Attr, Errno> {
+        let source = cstr(&self.source(path))?;
+        blocking(move || check(unsafe { libc::mknod(source.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) })).await?;
+        self.attr(path).await
+    }
+
+    async fn mkdir(&self, _req: &Request, path: &Path, mode: u32) -> Result<Attr, Errno> {
+        let source = self.source(path);
+        blocking(move || fs::DirBuilder::new().mode(mode & 0o7777).create(source)).await?;
+        self.attr(path).await
+    }
+
+    async fn unlink(&self, _req: &Request, path: &Path) -> Result<(), Errno> {
+        Ok(tokio::fs::remove_file(self.source(path)).await?)
+    }
+
+    async fn rmdir(&self, _req: &Request, path: &Path) -> Result<(), Errno> {
+        Ok(tokio::fs::remove_dir(self.source(path)).await?)
+    }
+
+    async fn symlink(&self, _req: &Request, path: &Path, link: &Path) -> Result<Attr, Errno> {
+        tokio::fs::symlink(link, self.source(path)).await?;
+        self.attr(path).await
+    }
+
+    async fn rename(&self, _req: &Request, path: &Path, newpath: &Path) -> Result<(), Errno> {
+        Ok(tokio::fs::rename(self.source(path), self.source(newpath)).await?)
+    }
+
+    async fn link(&self, _req: &Request, path: &Path, newpath: &Path) -> Result<Attr, Errno> {
+        tokio::fs::hard_link(self.source(path), self.source(newpath)).await?;
+        self.attr(newpath).await
+    }
+
+    async fn open(&self, _req: &Request, path: &Path, flags: u32) -> Result<Opened, Errno> {
+        let (source, options) = (self.source(path), Self::open_options(flags));
+        let file = blocking(move || options.open(source)).await?;
+        Ok(Opened { fh: self.add_handle(Handle::File(Arc::new(file))), flags: OpenFlags::empty(), backing: None })
+    }
+
+    async fn read(&self, _req: &Request, _path: &Path, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let file = self.file(fh)?;
+        let data = blocking(move || {
+            let mut data = vec![0; size as usize];
+            let mut len = 0;
+            // Read until the requested size or the end of the file, the kernel expects short
+            // reads only at the end of the file
+            while len < data.len() {
+                match file.read_at(&mut data[len..], offset as u64 + len as u64)? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            data.truncate(len);
+            Ok(data)
+        }).await?;
+        Ok(data.into())
+    }
+
+    async fn write(&self, _req: &Request, _path: &Path, fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        let (file, data) = (self.file(fh)?, data.to_vec());
+        blocking(move || file.write_all_at(&data, offset as u64).map(|()| data.len() as u32)).await
+    }
+
+    async fn flush(&self, _req: &Request, _path: &Path, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    async fn release(&self, _req: &Request, _path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> Result<(), Errno> {
+        self.handles.lock().unwrap().remove(&fh);
+        Ok(())
+    }
+
+    async fn fsync(&self, _req: &Request, _path: &Path, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let file = self.file(fh)?;
+        blocking(move || if datasync { file.sync_data() } else { file.sync_all() }).await
+    }
+
+    async fn opendir(&self, _req: &Request, path: &Path, _flags: u32) -> Result<Opened, Errno> {
+        let source = self.source(path);
+        let entries = blocking(move || {
+            let mut entries = vec![
+                DirEntry { ino: fs::metadata(&source)?.ino(), offset: DirOffset::new(1, 0), kind: FileType::Directory, name: ".".into() },
+                DirEntry { ino: 1, offset: DirOffset::new(2, 0), kind: FileType::Directory, name: "..".into() },
+            ];
+            for entry in fs::read_dir(&source)? {
+                let entry = entry?;
+                let offset = DirOffset::new(entries.len() as u64 + 1, 0);
+                entries.push(DirEntry { ino: entry.ino(), offset, kind: entry.file_type()?.into(), name: entry.file_name() });
+            }
+            Ok(entries)
+        }).await?;
+        Ok(Opened { fh: self.add_handle(Handle::Dir(Arc::new(entries))), flags: OpenFlags::empty(), backing: None })
+    }
+
+    async fn readdir(&self, _req: &Request, _path: &Path, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::Dir(entries)) => Ok(entries.iter().skip(offset.position(0)? as usize).cloned().collect()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    async fn releasedir(&self, _req: &Request, _path: &Path, fh: u64, _flags: u32) -> Result<(), Errno> {
+        self.handles.lock().unwrap().remove(&fh);
+        Ok(())
+    }
+
+    async fn statfs(&self, _req: &Request, path: &Path) -> Result<Statfs, Errno> {
+        let source = cstr(&self.source(path))?;
+        let stat = blocking(move || {
+            let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+            check(unsafe { libc::statvfs(source.as_ptr(), &mut stat) }).map(|()| stat)
+        }).await?;
+        Ok(Statfs {
+            blocks: stat.f_blocks as u64,
+            bfree: stat.f_bfree as u64,
+            bavail: stat.f_bavail as u64,
+            files: stat.f_files as u64,
+            ffree: stat.f_ffree as u64,
+            bsize: stat.f_bsize as u32,
+            namelen: stat.f_namemax as u32,
+            frsize: stat.f_frsize as u32,
+        })
+    }
+
+    async fn create(&self, _req: &Request, path: &Path, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let (source, mut options) = (self.source(path), Self::open_options(flags));
+        options.create_new(true).mode(mode & 0o7777);
+        if flags as i32 & libc::O_ACCMODE == libc::O_RDONLY {
+            // std only creates files opened for writing
+            options.write(true);
+        }
+        let file = blocking(move || options.open(source)).await?;
+        let attr = self.attr(path).await?;
+        let fh = self.add_handle(Handle::File(Arc::new(file)));
+        Ok(Created { ttl: attr.ttl, attr: attr.attr, generation: 0, fh, flags: OpenFlags::empty(), backing: None })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut args = env::args_os().skip(1);
+    let (source, mountpoint) = match (args.next(), args.next()) {
+        (Some(source), Some(mountpoint)) => (source, mountpoint),
+        _ => panic!("Usage: passthrough <source> <mountpoint>"),
+    };
+    let source = fs::canonicalize(source).expect("Invalid source directory");
+    Mount::new(PathFs::new(Passthrough::new(source)), mountpoint)
+        .fsname("passthrough")
+        .option("default_permissions")
+        .run()
+        .unwrap();
+}
+
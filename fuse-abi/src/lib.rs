@@ -16,12 +16,21 @@
 //! - supports ABI 7.19 since FUSE 2.9.1
 //! - supports ABI 7.26 since FUSE 3.0.0
 //!
-//! Items without a version annotation are valid with ABI 7.8 and later
+//! Argument structs implement `zerocopy::FromBytes`, so that they can be read from the bytes of
+//! a request without casting pointers: any bytes of the right length are a valid value. Reply
+//! structs implement `zerocopy::IntoBytes` too, which checks that they have no padding.
+//!
+//! Items without a version annotation are valid with ABI 7.8 and later. Items of later versions
+//! are enabled by the `abi-7-N` features. Items of ABI 7.20 and later are annotated with the
+//! version that introduced them, and only spoken by the Linux and FreeBSD kernel drivers. The
+//! ioctl items are defined for the Linux kernel driver regardless of the ABI features, since it
+//! sends ioctls with any protocol version.
 
 #![warn(missing_debug_implementations, rust_2018_idioms)]
 #![allow(missing_docs)]
 
 use std::convert::TryFrom;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 
@@ -47,13 +56,55 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 16;
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
 #[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
-#[cfg(feature = "abi-7-19")]
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(all(feature = "abi-7-23", not(feature = "abi-7-24")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
+#[cfg(all(feature = "abi-7-24", not(feature = "abi-7-25")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 24;
+#[cfg(all(feature = "abi-7-25", not(feature = "abi-7-26")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 25;
+#[cfg(all(feature = "abi-7-26", not(feature = "abi-7-27")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 26;
+#[cfg(all(feature = "abi-7-27", not(feature = "abi-7-28")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 27;
+#[cfg(all(feature = "abi-7-28", not(feature = "abi-7-29")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 28;
+#[cfg(all(feature = "abi-7-29", not(feature = "abi-7-30")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 29;
+#[cfg(all(feature = "abi-7-30", not(feature = "abi-7-31")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 30;
+#[cfg(all(feature = "abi-7-31", not(feature = "abi-7-32")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+#[cfg(all(feature = "abi-7-32", not(feature = "abi-7-33")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 32;
+#[cfg(all(feature = "abi-7-33", not(feature = "abi-7-34")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 33;
+#[cfg(all(feature = "abi-7-34", not(feature = "abi-7-35")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 34;
+#[cfg(all(feature = "abi-7-35", not(feature = "abi-7-36")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 35;
+#[cfg(all(feature = "abi-7-36", not(feature = "abi-7-37")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 36;
+#[cfg(all(feature = "abi-7-37", not(feature = "abi-7-38")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 37;
+#[cfg(all(feature = "abi-7-38", not(feature = "abi-7-39")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 38;
+#[cfg(all(feature = "abi-7-39", not(feature = "abi-7-40")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 39;
+#[cfg(feature = "abi-7-40")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 40;
 
 pub const FUSE_ROOT_ID: u64 = 1;
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_attr {
     pub ino: u64,
     pub size: u64,
@@ -82,7 +133,7 @@ pub struct fuse_attr {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_kstatfs {
     pub blocks: u64,                                    // Total blocks (in units of frsize)
     pub bfree: u64,                                     // Free blocks
@@ -97,7 +148,7 @@ pub struct fuse_kstatfs {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_file_lock {
     pub start: u64,
     pub end: u64,
@@ -135,6 +186,12 @@ pub mod consts {
     pub const FOPEN_KEEP_CACHE: u32         = 1 << 1;   // don't invalidate the data cache on open
     #[cfg(feature = "abi-7-10")]
     pub const FOPEN_NONSEEKABLE: u32        = 1 << 2;   // the file is not seekable
+    #[cfg(feature = "abi-7-35")]
+    pub const FOPEN_NOFLUSH: u32            = 1 << 5;   // don't flush data cache on close (7.35)
+    #[cfg(feature = "abi-7-36")]
+    pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6; // allow concurrent direct writes on the same inode (7.36)
+    #[cfg(feature = "abi-7-40")]
+    pub const FOPEN_PASSTHROUGH: u32        = 1 << 7;   // reads and writes go to the backing file (7.40)
 
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_ATTR: u32         = 1 << 30;
@@ -159,14 +216,68 @@ pub mod consts {
     pub const FUSE_SPLICE_WRITE: u32        = 1 << 7;   // kernel supports splice write on the device
     #[cfg(all(feature = "abi-7-14", not(target_os = "macos")))]
     pub const FUSE_SPLICE_MOVE: u32         = 1 << 8;   // kernel supports splice move on the device
-    #[cfg(not(target_os = "macos"))]
-
-    #[cfg(feature = "abi-7-14")]
+    #[cfg(all(feature = "abi-7-14", not(target_os = "macos")))]
     pub const FUSE_SPLICE_READ: u32         = 1 << 9;   // kernel supports splice read on the device
     #[cfg(feature = "abi-7-17")]
     pub const FUSE_FLOCK_LOCKS: u32         = 1 << 10;  // remote locking for BSD style file locks
     #[cfg(feature = "abi-7-18")]
     pub const FUSE_HAS_IOCTL_DIR: u32       = 1 << 11;  // kernel supports ioctl on directories
+    #[cfg(feature = "abi-7-20")]
+    pub const FUSE_AUTO_INVAL_DATA: u32     = 1 << 12;  // automatically invalidate cached pages (7.20)
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_DO_READDIRPLUS: u32      = 1 << 13;  // do READDIRPLUS (7.21)
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_READDIRPLUS_AUTO: u32    = 1 << 14;  // adaptive readdirplus (7.21)
+    #[cfg(feature = "abi-7-22")]
+    pub const FUSE_ASYNC_DIO: u32           = 1 << 15;  // asynchronous direct I/O submission (7.22)
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_WRITEBACK_CACHE: u32     = 1 << 16;  // use writeback cache for buffered writes (7.23)
+    #[cfg(feature = "abi-7-27")]
+    pub const FUSE_NO_OPEN_SUPPORT: u32     = 1 << 17;  // kernel supports zero-message opens (7.27)
+    #[cfg(feature = "abi-7-25")]
+    pub const FUSE_PARALLEL_DIROPS: u32     = 1 << 18;  // allow parallel lookups and readdir (7.25)
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_HANDLE_KILLPRIV: u32     = 1 << 19;  // filesystem clears suid/sgid/caps on write, chown and truncate (7.26)
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_POSIX_ACL: u32           = 1 << 20;  // filesystem supports POSIX ACLs (7.26)
+    #[cfg(feature = "abi-7-27")]
+    pub const FUSE_ABORT_ERROR: u32         = 1 << 21;  // reading the device after abort returns ECONNABORTED (7.27)
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_MAX_PAGES: u32           = 1 << 22;  // init_out.max_pages contains the max number of pages (7.28)
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_CACHE_SYMLINKS: u32      = 1 << 23;  // cache READLINK responses (7.28)
+    #[cfg(feature = "abi-7-29")]
+    pub const FUSE_NO_OPENDIR_SUPPORT: u32  = 1 << 24;  // kernel supports zero-message opendirs (7.29)
+    #[cfg(feature = "abi-7-30")]
+    pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;  // only invalidate cached pages on explicit request (7.30)
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_MAP_ALIGNMENT: u32       = 1 << 26;  // init_out.map_alignment is valid (7.31)
+    #[cfg(feature = "abi-7-32")]
+    pub const FUSE_SUBMOUNTS: u32           = 1 << 27;  // kernel supports auto-mounting directory submounts (7.32)
+    #[cfg(feature = "abi-7-33")]
+    pub const FUSE_HANDLE_KILLPRIV_V2: u32  = 1 << 28;  // like FUSE_HANDLE_KILLPRIV, also on open with O_TRUNC (7.33)
+    #[cfg(feature = "abi-7-33")]
+    pub const FUSE_SETXATTR_EXT: u32        = 1 << 29;  // server supports extended struct fuse_setxattr_in (7.33)
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_INIT_EXT: u32            = 1 << 30;  // init request/reply have flags2 (7.36)
+
+    // Init request/reply flags2 (flags above bit 31)
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_SECURITY_CTX: u32        = 1 << 0;   // add security context to create, mkdir, symlink and mknod (7.36)
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_HAS_INODE_DAX: u32       = 1 << 1;   // use per inode DAX (7.36)
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_CREATE_SUPP_GROUP: u32   = 1 << 2;   // add supplementary group info to create, mkdir, symlink and mknod (7.38)
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_HAS_EXPIRE_ONLY: u32     = 1 << 3;   // kernel supports expiry-only entry invalidation (7.38)
+    #[cfg(feature = "abi-7-39")]
+    pub const FUSE_DIRECT_IO_ALLOW_MMAP: u32 = 1 << 4;  // allow shared mmap in FOPEN_DIRECT_IO mode (7.39)
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_PASSTHROUGH: u32         = 1 << 5;   // files can be opened in passthrough mode (7.40)
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_NO_EXPORT_SUPPORT: u32   = 1 << 6;   // filesystem doesn't support export (7.40)
+    #[cfg(feature = "abi-7-40")]
+    pub const FUSE_HAS_RESEND: u32          = 1 << 7;   // kernel supports resending pending requests (7.40)
 
     #[cfg(target_os = "macos")]
     pub const FUSE_ALLOCATE: u32            = 1 << 27;
@@ -207,19 +318,37 @@ pub mod consts {
     pub const FUSE_READ_LOCKOWNER: u32      = 1 << 1;
 
     // IOCTL flags
-    #[cfg(feature = "abi-7-11")]
+    #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
     pub const FUSE_IOCTL_COMPAT: u32        = 1 << 0;   // 32bit compat ioctl on 64bit machine
-    #[cfg(feature = "abi-7-11")]
+    #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
     pub const FUSE_IOCTL_UNRESTRICTED: u32  = 1 << 1;   // not restricted to well-formed ioctls, retry allowed
-    #[cfg(feature = "abi-7-11")]
+    #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
     pub const FUSE_IOCTL_RETRY: u32         = 1 << 2;   // retry with new iovecs
-    #[cfg(feature = "abi-7-16")]
+    #[cfg(any(feature = "abi-7-16", not(target_os = "macos")))]
     pub const FUSE_IOCTL_32BIT: u32         = 1 << 3;   // 32bit ioctl
-    #[cfg(feature = "abi-7-18")]
+    #[cfg(any(feature = "abi-7-18", not(target_os = "macos")))]
     pub const FUSE_IOCTL_DIR: u32           = 1 << 4;   // is a directory
-    #[cfg(feature = "abi-7-11")]
+    #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
     pub const FUSE_IOCTL_MAX_IOV: u32       = 256;      // maximum of in_iovecs + out_iovecs
 
+    // Rename2 flags, like renameat2 (7.23)
+    #[cfg(feature = "abi-7-23")]
+    pub const RENAME_NOREPLACE: u32         = 1 << 0;   // don't overwrite the new name
+    #[cfg(feature = "abi-7-23")]
+    pub const RENAME_EXCHANGE: u32          = 1 << 1;   // exchange the old and the new name
+    #[cfg(feature = "abi-7-23")]
+    pub const RENAME_WHITEOUT: u32          = 1 << 2;   // leave a whiteout at the old name
+
+    // Notify inval entry flags
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_EXPIRE_ONLY: u32         = 1 << 0;   // expire the entry instead of dropping it (7.38)
+
+    // Request extension types
+    #[cfg(feature = "abi-7-36")]
+    pub const FUSE_MAX_NR_SECCTX: u32       = 31;       // types up to this are security contexts (7.36)
+    #[cfg(feature = "abi-7-38")]
+    pub const FUSE_EXT_GROUPS: u32          = 32;       // supplementary groups of the caller (7.38)
+
     // Poll flags
     #[cfg(feature = "abi-7-9")]
     pub const FUSE_POLL_SCHEDULE_NOTIFY: u32= 1 << 0;   // request poll notify
@@ -272,7 +401,7 @@ pub enum fuse_opcode {
     FUSE_INTERRUPT = 36,
     FUSE_BMAP = 37,
     FUSE_DESTROY = 38,
-    #[cfg(feature = "abi-7-11")]
+    #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
     FUSE_IOCTL = 39,
     #[cfg(feature = "abi-7-11")]
     FUSE_POLL = 40,
@@ -282,6 +411,20 @@ pub enum fuse_opcode {
     FUSE_BATCH_FORGET = 42,
     #[cfg(feature = "abi-7-19")]
     FUSE_FALLOCATE = 43,
+    #[cfg(feature = "abi-7-21")]
+    FUSE_READDIRPLUS = 44,                              // (7.21)
+    #[cfg(feature = "abi-7-23")]
+    FUSE_RENAME2 = 45,                                  // (7.23)
+    #[cfg(feature = "abi-7-24")]
+    FUSE_LSEEK = 46,                                    // (7.24)
+    #[cfg(feature = "abi-7-28")]
+    FUSE_COPY_FILE_RANGE = 47,                          // (7.28)
+    #[cfg(feature = "abi-7-34")]
+    FUSE_SYNCFS = 50,                                   // (7.34)
+    #[cfg(feature = "abi-7-37")]
+    FUSE_TMPFILE = 51,                                  // (7.37)
+    #[cfg(feature = "abi-7-39")]
+    FUSE_STATX = 52,                                    // (7.39)
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -335,7 +478,7 @@ impl TryFrom<u32> for fuse_opcode {
             36 => Ok(fuse_opcode::FUSE_INTERRUPT),
             37 => Ok(fuse_opcode::FUSE_BMAP),
             38 => Ok(fuse_opcode::FUSE_DESTROY),
-            #[cfg(feature = "abi-7-11")]
+            #[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
             39 => Ok(fuse_opcode::FUSE_IOCTL),
             #[cfg(feature = "abi-7-11")]
             40 => Ok(fuse_opcode::FUSE_POLL),
@@ -345,6 +488,20 @@ impl TryFrom<u32> for fuse_opcode {
             42 => Ok(fuse_opcode::FUSE_BATCH_FORGET),
             #[cfg(feature = "abi-7-19")]
             43 => Ok(fuse_opcode::FUSE_FALLOCATE),
+            #[cfg(feature = "abi-7-21")]
+            44 => Ok(fuse_opcode::FUSE_READDIRPLUS),
+            #[cfg(feature = "abi-7-23")]
+            45 => Ok(fuse_opcode::FUSE_RENAME2),
+            #[cfg(feature = "abi-7-24")]
+            46 => Ok(fuse_opcode::FUSE_LSEEK),
+            #[cfg(feature = "abi-7-28")]
+            47 => Ok(fuse_opcode::FUSE_COPY_FILE_RANGE),
+            #[cfg(feature = "abi-7-34")]
+            50 => Ok(fuse_opcode::FUSE_SYNCFS),
+            #[cfg(feature = "abi-7-37")]
+            51 => Ok(fuse_opcode::FUSE_TMPFILE),
+            #[cfg(feature = "abi-7-39")]
+            52 => Ok(fuse_opcode::FUSE_STATX),
 
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
@@ -383,6 +540,8 @@ pub enum fuse_notify_code {
     FUSE_NOTIFY_RETRIEVE = 5,
     #[cfg(feature = "abi-7-18")]
     FUSE_NOTIFY_DELETE = 6,
+    #[cfg(feature = "abi-7-40")]
+    FUSE_NOTIFY_RESEND = 7,                             // (7.40)
 }
 
 #[cfg(feature = "abi-7-11")]
@@ -403,6 +562,8 @@ impl TryFrom<u32> for fuse_notify_code {
             5 => Ok(fuse_notify_code::FUSE_NOTIFY_RETRIEVE),
             #[cfg(feature = "abi-7-18")]
             6 => Ok(fuse_notify_code::FUSE_NOTIFY_DELETE),
+            #[cfg(feature = "abi-7-40")]
+            7 => Ok(fuse_notify_code::FUSE_NOTIFY_RESEND),
 
             _ => Err(InvalidNotifyCodeError),
         }
@@ -410,7 +571,7 @@ impl TryFrom<u32> for fuse_notify_code {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_entry_out {
     pub nodeid: u64,
     pub generation: u64,
@@ -422,14 +583,14 @@ pub struct fuse_entry_out {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_forget_in {
     pub nlookup: u64,
 }
 
 #[cfg(feature = "abi-7-16")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_forget_one {
     pub nodeid: u64,
     pub nlookup: u64,
@@ -437,7 +598,7 @@ pub struct fuse_forget_one {
 
 #[cfg(feature = "abi-7-16")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_batch_forget_in {
     pub count: u32,
     pub dummy: u32,
@@ -445,7 +606,7 @@ pub struct fuse_batch_forget_in {
 
 #[cfg(feature = "abi-7-9")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_getattr_in {
     pub getattr_flags: u32,
     pub dummy: u32,
@@ -453,7 +614,7 @@ pub struct fuse_getattr_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_attr_out {
     pub attr_valid: u64,
     pub attr_valid_nsec: u32,
@@ -463,7 +624,7 @@ pub struct fuse_attr_out {
 
 #[cfg(target_os = "macos")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_getxtimes_out {
     pub bkuptime: u64,
     pub crtime: u64,
@@ -472,7 +633,7 @@ pub struct fuse_getxtimes_out {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_mknod_in {
     pub mode: u32,
     pub rdev: u32,
@@ -483,7 +644,7 @@ pub struct fuse_mknod_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_mkdir_in {
     pub mode: u32,
     #[cfg(not(feature = "abi-7-12"))]
@@ -492,15 +653,31 @@ pub struct fuse_mkdir_in {
     pub umask: u32,
 }
 
+impl fuse_mkdir_in {
+    /// Returns the second field, the umask (7.12) or padding (which kernels speaking 7.12 or
+    /// later fill with the umask as well)
+    #[cfg(feature = "abi-7-12")]
+    pub fn umask(&self) -> u32 {
+        self.umask
+    }
+
+    /// Returns the second field, the umask (7.12) or padding (which kernels speaking 7.12 or
+    /// later fill with the umask as well)
+    #[cfg(not(feature = "abi-7-12"))]
+    pub fn umask(&self) -> u32 {
+        self.padding
+    }
+}
+
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_rename_in {
     pub newdir: u64,
 }
 
 #[cfg(target_os = "macos")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_exchange_in {
     pub olddir: u64,
     pub newdir: u64,
@@ -508,13 +685,13 @@ pub struct fuse_exchange_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_link_in {
     pub oldnodeid: u64,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_setattr_in {
     pub valid: u32,
     pub padding: u32,
@@ -552,14 +729,14 @@ pub struct fuse_setattr_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_open_in {
     pub flags: u32,
     pub unused: u32,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_create_in {
     pub flags: u32,
     pub mode: u32,
@@ -570,7 +747,7 @@ pub struct fuse_create_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_open_out {
     pub fh: u64,
     pub open_flags: u32,
@@ -578,7 +755,7 @@ pub struct fuse_open_out {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_release_in {
     pub fh: u64,
     pub flags: u32,
@@ -587,7 +764,7 @@ pub struct fuse_release_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_flush_in {
     pub fh: u64,
     pub unused: u32,
@@ -596,7 +773,7 @@ pub struct fuse_flush_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_read_in {
     pub fh: u64,
     pub offset: u64,
@@ -612,7 +789,7 @@ pub struct fuse_read_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_write_in {
     pub fh: u64,
     pub offset: u64,
@@ -627,20 +804,20 @@ pub struct fuse_write_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_write_out {
     pub size: u32,
     pub padding: u32,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_statfs_out {
     pub st: fuse_kstatfs,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_fsync_in {
     pub fh: u64,
     pub fsync_flags: u32,
@@ -648,7 +825,7 @@ pub struct fuse_fsync_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_setxattr_in {
     pub size: u32,
     pub flags: u32,
@@ -659,7 +836,7 @@ pub struct fuse_setxattr_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_getxattr_in {
     pub size: u32,
     pub padding: u32,
@@ -670,14 +847,14 @@ pub struct fuse_getxattr_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_getxattr_out {
     pub size: u32,
     pub padding: u32,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_lk_in {
     pub fh: u64,
     pub owner: u64,
@@ -689,20 +866,20 @@ pub struct fuse_lk_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_lk_out {
     pub lk: fuse_file_lock,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_access_in {
     pub mask: u32,
     pub padding: u32,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_init_in {
     pub major: u32,
     pub minor: u32,
@@ -711,7 +888,7 @@ pub struct fuse_init_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_init_out {
     pub major: u32,
     pub minor: u32,
@@ -726,9 +903,45 @@ pub struct fuse_init_out {
     pub max_write: u32,
 }
 
+/// Init reply of ABI 7.36 and later, sent if the kernel sets `FUSE_INIT_EXT`
+#[cfg(feature = "abi-7-36")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+pub struct fuse_init_out_ext {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
+}
+
+/// Backing file registered with `FUSE_DEV_IOC_BACKING_OPEN` (7.40)
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_backing_map {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+
+// Ioctls on the device fd: _IOW(229, 1, struct fuse_backing_map) and _IOW(229, 2, uint32_t)
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_OPEN: u32 = 0x4010_e501;
+#[cfg(target_os = "linux")]
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u32 = 0x4004_e502;
+
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct cuse_init_in {
     pub major: u32,
     pub minor: u32,
@@ -738,7 +951,7 @@ pub struct cuse_init_in {
 
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct cuse_init_out {
     pub major: u32,
     pub minor: u32,
@@ -752,13 +965,13 @@ pub struct cuse_init_out {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_interrupt_in {
     pub unique: u64,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_bmap_in {
     pub block: u64,
     pub blocksize: u32,
@@ -766,14 +979,14 @@ pub struct fuse_bmap_in {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct fuse_bmap_out {
     pub block: u64,
 }
 
-#[cfg(feature = "abi-7-11")]
+#[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_ioctl_in {
     pub fh: u64,
     pub flags: u32,
@@ -783,17 +996,17 @@ pub struct fuse_ioctl_in {
     pub out_size: u32,
 }
 
-#[cfg(feature = "abi-7-16")]
+#[cfg(any(feature = "abi-7-16", not(target_os = "macos")))]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_ioctl_iovec {
     pub base: u64,
     pub len: u64,
 }
 
-#[cfg(feature = "abi-7-11")]
+#[cfg(any(feature = "abi-7-11", not(target_os = "macos")))]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_ioctl_out {
     pub result: i32,
     pub flags: u32,
@@ -803,7 +1016,7 @@ pub struct fuse_ioctl_out {
 
 #[cfg(feature = "abi-7-11")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_poll_in {
     pub fh: u64,
     pub kh: u64,
@@ -813,7 +1026,7 @@ pub struct fuse_poll_in {
 
 #[cfg(feature = "abi-7-11")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_poll_out {
     pub revents: u32,
     pub padding: u32,
@@ -821,24 +1034,128 @@ pub struct fuse_poll_out {
 
 #[cfg(feature = "abi-7-11")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_poll_wakeup_out {
     pub kh: u64,
 }
 
 #[cfg(feature = "abi-7-19")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_fallocate_in {
-    fh: u64,
-    offset: u64,
-    length: u64,
-    mode: u32,
-    padding: u32,
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
 }
 
+#[cfg(feature = "abi-7-23")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_rename2_in {                            // (7.23)
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[cfg(feature = "abi-7-24")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_lseek_in {                              // (7.24)
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32,
+    pub padding: u32,
+}
+
+#[cfg(feature = "abi-7-24")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_lseek_out {                             // (7.24)
+    pub offset: u64,
+}
+
+#[cfg(feature = "abi-7-28")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_copy_file_range_in {                    // (7.28)
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+#[cfg(feature = "abi-7-34")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_syncfs_in {                             // (7.34)
+    pub padding: u64,
+}
+
+#[cfg(feature = "abi-7-39")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_statx_in {                              // (7.39)
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+
+#[cfg(feature = "abi-7-39")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_sx_time {                               // (7.39)
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+
+#[cfg(feature = "abi-7-39")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_statx {                                 // (7.39)
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: fuse_sx_time,
+    pub btime: fuse_sx_time,
+    pub ctime: fuse_sx_time,
+    pub mtime: fuse_sx_time,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+
+#[cfg(feature = "abi-7-39")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_statx_out {                             // (7.39)
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: fuse_statx,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_in_header {
     pub len: u32,
     pub opcode: u32,
@@ -847,11 +1164,29 @@ pub struct fuse_in_header {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
-    pub padding: u32,
+    pub total_extlen: u16,                              // length of extensions in 8 byte units (7.36)
+    pub padding: u16,
 }
 
+#[cfg(feature = "abi-7-36")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_ext_header {                            // (7.36)
+    pub size: u32,
+    pub typ: u32,
+    // followed by the extension data, padded to 8 bytes
+}
+
+#[cfg(feature = "abi-7-38")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
+pub struct fuse_supp_groups {                           // (7.38)
+    pub nr_groups: u32,
+    // followed by nr_groups group ids
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_out_header {
     pub len: u32,
     pub error: i32,
@@ -859,7 +1194,7 @@ pub struct fuse_out_header {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_dirent {
     pub ino: u64,
     pub off: u64,
@@ -870,7 +1205,7 @@ pub struct fuse_dirent {
 
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_inval_inode_out {
     pub ino: u64,
     pub off: i64,
@@ -879,7 +1214,7 @@ pub struct fuse_notify_inval_inode_out {
 
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_inval_entry_out {
     pub parent: u64,
     pub namelen: u32,
@@ -888,7 +1223,7 @@ pub struct fuse_notify_inval_entry_out {
 
 #[cfg(feature = "abi-7-18")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_delete_out {
     parent: u64,
     child: u64,
@@ -898,7 +1233,7 @@ pub struct fuse_notify_delete_out {
 
 #[cfg(feature = "abi-7-15")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_store_out {
     pub nodeid: u64,
     pub offset: u64,
@@ -908,7 +1243,7 @@ pub struct fuse_notify_store_out {
 
 #[cfg(feature = "abi-7-15")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_retrieve_out {
     pub notify_unique: u64,
     pub nodeid: u64,
@@ -919,7 +1254,7 @@ pub struct fuse_notify_retrieve_out {
 
 #[cfg(feature = "abi-7-15")]
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromBytes)]
 pub struct fuse_notify_retrieve_in {                    // matches the size of fuse_write_in
     pub dummy1: u64,
     pub offset: u64,
@@ -5,6 +5,11 @@ const LIBFUSE_NAME: &str = "fuse";
 const LIBFUSE_NAME: &str = "osxfuse";
 
 fn main() {
+    // Android has no libfuse, and FreeBSD mounts with mount_fusefs, filesystems are mounted
+    // without it
+    if matches!(std::env::var("CARGO_CFG_TARGET_OS").as_deref(), Ok("android") | Ok("freebsd")) {
+        return;
+    }
     pkg_config::Config::new()
         .atleast_version("2.6.0")
         .probe(LIBFUSE_NAME)
@@ -16,6 +16,8 @@ pub struct fuse_args {
     pub allocated: c_int,
 }
 
+// Not available on Android, which has no libfuse
+#[cfg(not(target_os = "android"))]
 extern "C" {
     // *_compat25 functions were introduced in FUSE 2.6 when function signatures changed.
     // Therefore, the minimum version requirement for *_compat25 functions is libfuse-2.6.0.
@@ -0,0 +1,8 @@
+//! Parse arbitrary data as a kernel request. Run with `cargo fuzz run parse_request`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Any input must either parse or be rejected, but never panic
+    let _ = async_fuse::fuzz::parse_request(data);
+});
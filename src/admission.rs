@@ -0,0 +1,187 @@
+//! Admission control
+//!
+//! On filesystems mounted with `allow_other`, many users share a session. Admission control
+//! limits the requests of every user (by uid) separately, so that a single user flooding the
+//! filesystem with requests can't starve all others. Requests exceeding the rate limit of their
+//! user are rejected with EAGAIN. Requests exceeding the limit of concurrent operations of their
+//! user wait until another operation of the same user completes, without holding up requests
+//! of other users.
+
+use std::collections::HashMap;
+use std::future;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::Instant;
+use fuse_abi::fuse_opcode;
+
+use crate::Errno;
+
+/// Limits of a user
+#[derive(Debug)]
+struct User {
+    tokens: f64,
+    updated: Instant,
+    in_flight: usize,
+    waiters: Vec<Waker>,
+}
+
+/// Per-user limits of requests
+#[derive(Debug, Default)]
+pub struct Admission {
+    rate: Option<(f64, f64)>,
+    max_in_flight: Option<usize>,
+    users: Mutex<HashMap<u32, User>>,
+}
+
+impl Admission {
+    /// Create admission control without limits
+    pub fn new() -> Admission {
+        Admission::default()
+    }
+
+    /// Limit every user to the given number of requests per second on average, allowing
+    /// bursts of up to the given number of requests
+    pub fn rate(mut self, per_second: u32, burst: u32) -> Admission {
+        self.rate = Some((per_second as f64, burst.max(1) as f64));
+        self
+    }
+
+    /// Limit every user to the given number of concurrently dispatched requests
+    pub fn in_flight(mut self, max: usize) -> Admission {
+        self.max_in_flight = Some(max.max(1));
+        self
+    }
+
+    /// Admit a request of the given operation and user. Fails with EAGAIN if the user exceeds
+    /// the rate limit, waits if the user exceeds the limit of concurrent requests. Operations
+    /// that must not fail or wait (like init, forget or release) are always admitted.
+    pub(crate) async fn admit(self: &Arc<Self>, opcode: fuse_opcode, uid: u32) -> Result<Option<Admitted>, Errno> {
+        match opcode {
+            fuse_opcode::FUSE_INIT | fuse_opcode::FUSE_DESTROY | fuse_opcode::FUSE_FORGET |
+            fuse_opcode::FUSE_INTERRUPT | fuse_opcode::FUSE_RELEASE | fuse_opcode::FUSE_RELEASEDIR => return Ok(None),
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_BATCH_FORGET => return Ok(None),
+            _ => (),
+        }
+        if let Some((per_second, burst)) = self.rate {
+            let mut users = self.users.lock().unwrap();
+            let user = users.entry(uid).or_insert_with(|| User { tokens: burst, updated: Instant::now(), in_flight: 0, waiters: Vec::new() });
+            let now = Instant::now();
+            user.tokens = (user.tokens + now.duration_since(user.updated).as_secs_f64() * per_second).min(burst);
+            user.updated = now;
+            if user.tokens < 1.0 {
+                return Err(Errno::EAGAIN);
+            }
+            user.tokens -= 1.0;
+        }
+        let max_in_flight = match self.max_in_flight {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        future::poll_fn(|cx| {
+            let mut users = self.users.lock().unwrap();
+            let user = users.entry(uid).or_insert_with(|| User { tokens: 0.0, updated: Instant::now(), in_flight: 0, waiters: Vec::new() });
+            if user.in_flight < max_in_flight {
+                user.in_flight += 1;
+                Poll::Ready(())
+            } else {
+                if !user.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    user.waiters.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }).await;
+        Ok(Some(Admitted { admission: self.clone(), uid }))
+    }
+
+    fn release(&self, uid: u32) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.get_mut(&uid) {
+            user.in_flight -= 1;
+            // Every waiter checks again, the ones not getting in register again
+            user.waiters.drain(..).for_each(Waker::wake);
+            // Forget idle users that have nothing to remember without a rate limit
+            if user.in_flight == 0 && self.rate.is_none() {
+                users.remove(&uid);
+            }
+        }
+    }
+}
+
+/// Admitted request that counts as in flight for its user until dropped
+#[derive(Debug)]
+pub(crate) struct Admitted {
+    admission: Arc<Admission>,
+    uid: u32,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.admission.release(self.uid);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode::{FUSE_GETATTR, FUSE_RELEASE};
+    use crate::{block_on, Attr, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::Admission;
+
+    #[test]
+    fn rate() {
+        let admission = Arc::new(Admission::new().rate(1, 2));
+        assert!(block_on(admission.admit(FUSE_GETATTR, 1000)).is_ok());
+        assert!(block_on(admission.admit(FUSE_GETATTR, 1000)).is_ok());
+        assert_eq!(block_on(admission.admit(FUSE_GETATTR, 1000)).unwrap_err(), Errno::EAGAIN);
+        // Other users and releases aren't affected
+        assert!(block_on(admission.admit(FUSE_GETATTR, 1001)).is_ok());
+        assert!(block_on(admission.admit(FUSE_RELEASE, 1000)).is_ok());
+    }
+
+    #[test]
+    fn in_flight() {
+        let admission = Arc::new(Admission::new().in_flight(1));
+        let first = block_on(admission.admit(FUSE_GETATTR, 1000)).unwrap();
+        // Other users aren't held up
+        drop(block_on(admission.admit(FUSE_GETATTR, 1001)).unwrap());
+        let admitted = Arc::new(AtomicBool::new(false));
+        let waiting = {
+            let (admission, admitted) = (admission.clone(), admitted.clone());
+            thread::spawn(move || {
+                let second = block_on(admission.admit(FUSE_GETATTR, 1000)).unwrap();
+                admitted.store(true, Ordering::SeqCst);
+                drop(second);
+            })
+        };
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!admitted.load(Ordering::SeqCst));
+        drop(first);
+        waiting.join().unwrap();
+        assert!(admitted.load(Ordering::SeqCst));
+        assert!(admission.users.lock().unwrap().is_empty());
+    }
+
+    struct Root;
+
+    #[async_trait]
+    impl Filesystem for Root {
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::Directory).build()))
+        }
+    }
+
+    #[test]
+    fn session() {
+        let ch = MockChannel::new(Root).admission(Admission::new().rate(1, 1)).user(1000, 100);
+        block_on(ch.init()).unwrap();
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap_err(), Errno::EAGAIN);
+        let ch = ch.user(1001, 100);
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+    }
+}
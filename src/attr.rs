@@ -0,0 +1,350 @@
+//! File attribute helpers
+//!
+//! Building a `FileAttr` field by field is tedious, especially for filesystems that pass through
+//! attributes of another filesystem. `FileAttrBuilder` fills in sensible defaults and derives the
+//! number of blocks from the size, and `FileAttr` can be converted from `std::fs::Metadata` and
+//! `libc::stat`.
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libc::{S_IFMT, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, S_IFSOCK};
+
+use crate::{FileAttr, FileType};
+
+/// Size of the blocks counted in `FileAttr::blocks`
+const BLOCK_SIZE: u64 = 512;
+
+impl FileType {
+    /// Returns the file type of the given file mode (as in `st_mode`)
+    pub fn from_mode(mode: u32) -> Option<FileType> {
+        match mode as libc::mode_t & S_IFMT {
+            S_IFIFO => Some(FileType::NamedPipe),
+            S_IFCHR => Some(FileType::CharDevice),
+            S_IFBLK => Some(FileType::BlockDevice),
+            S_IFDIR => Some(FileType::Directory),
+            S_IFREG => Some(FileType::RegularFile),
+            S_IFLNK => Some(FileType::Symlink),
+            S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Returns the file type bits of a file mode (`S_IFMT` bits of `st_mode`) for this type
+    // mode_t is 16 bit on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    pub fn mode(self) -> u32 {
+        (match self {
+            FileType::NamedPipe => S_IFIFO,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::Directory => S_IFDIR,
+            FileType::RegularFile => S_IFREG,
+            FileType::Symlink => S_IFLNK,
+            FileType::Socket => S_IFSOCK,
+        }) as u32
+    }
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(kind: fs::FileType) -> FileType {
+        if kind.is_dir() {
+            FileType::Directory
+        } else if kind.is_symlink() {
+            FileType::Symlink
+        } else if kind.is_fifo() {
+            FileType::NamedPipe
+        } else if kind.is_char_device() {
+            FileType::CharDevice
+        } else if kind.is_block_device() {
+            FileType::BlockDevice
+        } else if kind.is_socket() {
+            FileType::Socket
+        } else {
+            FileType::RegularFile
+        }
+    }
+}
+
+/// Returns the time of the given seconds and nanoseconds since the epoch. Seconds are negative
+/// for times before the epoch, the nanoseconds are always added.
+pub(crate) fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+    } else {
+        UNIX_EPOCH - Duration::new(secs.unsigned_abs(), 0) + Duration::new(0, nsecs as u32)
+    }
+}
+
+/// Returns the seconds and nanoseconds since the epoch of the given time (the inverse of
+/// `system_time`), keeping full nanosecond precision for times before the epoch too
+pub(crate) fn timespec(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(err) => {
+            let duration = err.duration();
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nsecs => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nsecs),
+            }
+        }
+    }
+}
+
+/// Builder for file attributes
+#[derive(Clone, Copy, Debug)]
+pub struct FileAttrBuilder {
+    attr: FileAttr,
+    blksize: u32,
+    blocks: Option<u64>,
+}
+
+impl FileAttr {
+    /// Create a builder for attributes of a file with the given inode number and kind.
+    /// Permissions default to 0o755 for directories and 0o644 otherwise, directories have
+    /// two links and other files one, timestamps are set to the epoch and owner is root.
+    pub fn builder(ino: u64, kind: FileType) -> FileAttrBuilder {
+        let (perm, nlink) = match kind {
+            FileType::Directory => (0o755, 2),
+            _ => (0o644, 1),
+        };
+        FileAttrBuilder {
+            attr: FileAttr {
+                ino, size: 0, blocks: 0,
+                atime: UNIX_EPOCH, mtime: UNIX_EPOCH, ctime: UNIX_EPOCH, crtime: UNIX_EPOCH,
+                kind, perm, nlink, uid: 0, gid: 0, rdev: 0, flags: 0,
+            },
+            blksize: 4096,
+            blocks: None,
+        }
+    }
+}
+
+impl FileAttrBuilder {
+    /// Set the size in bytes
+    pub fn size(mut self, size: u64) -> FileAttrBuilder {
+        self.attr.size = size;
+        self
+    }
+
+    /// Set the number of allocated 512 byte blocks. If not set, it is derived from the size
+    /// rounded up to whole blocks of the block size.
+    pub fn blocks(mut self, blocks: u64) -> FileAttrBuilder {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// Set the block size the number of blocks is derived with (defaults to 4096)
+    pub fn blksize(mut self, blksize: u32) -> FileAttrBuilder {
+        self.blksize = blksize;
+        self
+    }
+
+    /// Set all timestamps to the given time
+    pub fn time(mut self, time: SystemTime) -> FileAttrBuilder {
+        self.attr.atime = time;
+        self.attr.mtime = time;
+        self.attr.ctime = time;
+        self.attr.crtime = time;
+        self
+    }
+
+    /// Set the time of last access
+    pub fn atime(mut self, atime: SystemTime) -> FileAttrBuilder {
+        self.attr.atime = atime;
+        self
+    }
+
+    /// Set the time of last modification
+    pub fn mtime(mut self, mtime: SystemTime) -> FileAttrBuilder {
+        self.attr.mtime = mtime;
+        self
+    }
+
+    /// Set the time of last change
+    pub fn ctime(mut self, ctime: SystemTime) -> FileAttrBuilder {
+        self.attr.ctime = ctime;
+        self
+    }
+
+    /// Set the time of creation (macOS only)
+    pub fn crtime(mut self, crtime: SystemTime) -> FileAttrBuilder {
+        self.attr.crtime = crtime;
+        self
+    }
+
+    /// Set the permissions
+    pub fn perm(mut self, perm: u16) -> FileAttrBuilder {
+        self.attr.perm = perm;
+        self
+    }
+
+    /// Set the number of hard links
+    pub fn nlink(mut self, nlink: u32) -> FileAttrBuilder {
+        self.attr.nlink = nlink;
+        self
+    }
+
+    /// Set the user id
+    pub fn uid(mut self, uid: u32) -> FileAttrBuilder {
+        self.attr.uid = uid;
+        self
+    }
+
+    /// Set the group id
+    pub fn gid(mut self, gid: u32) -> FileAttrBuilder {
+        self.attr.gid = gid;
+        self
+    }
+
+    /// Set the device id of a device file
+    pub fn rdev(mut self, rdev: u32) -> FileAttrBuilder {
+        self.attr.rdev = rdev;
+        self
+    }
+
+    /// Set the flags (macOS only, see chflags(2))
+    pub fn flags(mut self, flags: u32) -> FileAttrBuilder {
+        self.attr.flags = flags;
+        self
+    }
+
+    /// Returns the file attributes
+    pub fn build(self) -> FileAttr {
+        let blksize = u64::from(self.blksize.max(1));
+        let blocks = self.blocks.unwrap_or_else(|| self.attr.size.div_ceil(blksize) * blksize / BLOCK_SIZE);
+        FileAttr { blocks, ..self.attr }
+    }
+}
+
+impl From<fs::Metadata> for FileAttr {
+    fn from(metadata: fs::Metadata) -> FileAttr {
+        FileAttr::from(&metadata)
+    }
+}
+
+impl From<&fs::Metadata> for FileAttr {
+    fn from(metadata: &fs::Metadata) -> FileAttr {
+        FileAttr {
+            ino: metadata.ino(),
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime: system_time(metadata.atime(), metadata.atime_nsec()),
+            mtime: system_time(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: system_time(metadata.ctime(), metadata.ctime_nsec()),
+            crtime: metadata.created().unwrap_or(UNIX_EPOCH),
+            kind: metadata.file_type().into(),
+            perm: (metadata.mode() & 0o7777) as u16,
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev() as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl From<libc::stat> for FileAttr {
+    // Field types of stat differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    fn from(stat: libc::stat) -> FileAttr {
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        let (crtime, flags) = (system_time(stat.st_birthtime as i64, stat.st_birthtime_nsec as i64), stat.st_flags as u32);
+        #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+        let (crtime, flags) = (UNIX_EPOCH, 0);
+        FileAttr {
+            ino: stat.st_ino as u64,
+            size: stat.st_size as u64,
+            blocks: stat.st_blocks as u64,
+            atime: system_time(stat.st_atime as i64, stat.st_atime_nsec as i64),
+            mtime: system_time(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+            ctime: system_time(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+            crtime,
+            kind: FileType::from_mode(stat.st_mode as u32).unwrap_or(FileType::RegularFile),
+            perm: (stat.st_mode & 0o7777) as u16,
+            nlink: stat.st_nlink as u32,
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            rdev: stat.st_rdev as u32,
+            flags,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, mem};
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::{FileAttr, FileType};
+    use super::{system_time, timespec};
+
+    #[test]
+    fn builder_defaults() {
+        let attr = FileAttr::builder(1, FileType::Directory).build();
+        assert_eq!(attr.ino, 1);
+        assert_eq!(attr.perm, 0o755);
+        assert_eq!(attr.nlink, 2);
+        assert_eq!(attr.blocks, 0);
+        assert_eq!(attr.mtime, UNIX_EPOCH);
+        let attr = FileAttr::builder(2, FileType::RegularFile).perm(0o600).uid(1000).build();
+        assert_eq!((attr.perm, attr.nlink, attr.uid), (0o600, 1, 1000));
+    }
+
+    #[test]
+    fn builder_blocks() {
+        assert_eq!(FileAttr::builder(2, FileType::RegularFile).size(1).build().blocks, 8);
+        assert_eq!(FileAttr::builder(2, FileType::RegularFile).size(4097).build().blocks, 16);
+        assert_eq!(FileAttr::builder(2, FileType::RegularFile).size(1).blksize(512).build().blocks, 1);
+        assert_eq!(FileAttr::builder(2, FileType::RegularFile).size(1).blocks(3).build().blocks, 3);
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_cast)]
+    fn from_mode() {
+        assert_eq!(FileType::from_mode(libc::S_IFDIR as u32 | 0o755), Some(FileType::Directory));
+        assert_eq!(FileType::from_mode(libc::S_IFLNK as u32), Some(FileType::Symlink));
+        assert_eq!(FileType::from_mode(0), None);
+        assert_eq!(FileType::from_mode(FileType::Socket.mode() | 0o644), Some(FileType::Socket));
+    }
+
+    #[test]
+    fn from_metadata() {
+        let metadata = fs::metadata("Cargo.toml").unwrap();
+        let attr = FileAttr::from(&metadata);
+        assert_eq!(attr.ino, metadata.ino());
+        assert_eq!(attr.size, metadata.len());
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.mtime, metadata.modified().unwrap());
+    }
+
+    #[test]
+    fn from_stat() {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        stat.st_ino = 42;
+        stat.st_mode = libc::S_IFREG | 0o640;
+        stat.st_size = 5;
+        stat.st_mtime = 10;
+        stat.st_mtime_nsec = 20;
+        let attr = FileAttr::from(stat);
+        assert_eq!(attr.ino, 42);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o640);
+        assert_eq!(attr.size, 5);
+        assert_eq!(attr.mtime, UNIX_EPOCH + Duration::new(10, 20));
+    }
+
+    #[test]
+    fn timespec_roundtrip() {
+        let after = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        assert_eq!(timespec(after), (1_600_000_000, 123_456_789));
+        let before = UNIX_EPOCH - Duration::new(10, 250_000_000);
+        assert_eq!(timespec(before), (-11, 750_000_000));
+        assert_eq!(timespec(UNIX_EPOCH - Duration::new(10, 0)), (-10, 0));
+        for time in [after, before, UNIX_EPOCH].iter() {
+            let (secs, nsecs) = timespec(*time);
+            assert_eq!(system_time(secs, nsecs as i64), *time);
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Blocking filesystems
+//!
+//! Filesystems written for synchronous FUSE libraries (like fuse-rs) perform blocking I/O in
+//! their operations, which must not run on the threads processing requests asynchronously. A
+//! `BlockingFilesystem` implements the same operations as `Filesystem` synchronously, and the
+//! `BlockingFs` adapter runs each of them on tokio's blocking thread pool. This allows migrating
+//! existing implementations to the async trait one operation at a time.
+
+use std::ffi::OsStr;
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use async_trait::async_trait;
+
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+#[cfg(target_os = "macos")]
+use crate::XTimes;
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+
+/// Synchronous filesystem trait.
+///
+/// The operations are the same as those of `Filesystem`, but they may block. Reasonable default
+/// implementations are provided here to get a mountable filesystem that does nothing.
+pub trait BlockingFilesystem: Send + Sync + 'static {
+    /// Initialize filesystem.
+    fn init(&self, _req: &Request) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy(&self, _req: &Request) {}
+
+    /// Look up a directory entry by name and get its attributes.
+    fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Forget about an inode.
+    fn forget(&self, _req: &Request, _ino: u64, _nlookup: u64) {}
+
+    /// Get file attributes.
+    fn getattr(&self, _req: &Request, _ino: u64) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set file attributes.
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read symbolic link.
+    fn readlink(&self, _req: &Request, _ino: u64) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create file node.
+    fn mknod(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    fn mkdir(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    fn symlink(&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file.
+    fn rename(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link.
+    fn link(&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a file.
+    fn open(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
+    }
+
+    /// Read data.
+    fn read(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32) -> Result<Data, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Write data.
+    fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Flush method.
+    fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open file.
+    fn release(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize file contents.
+    fn fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a directory.
+    fn opendir(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
+    }
+
+    /// Read directory.
+    fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, _offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open directory.
+    fn releasedir(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    fn statfs(&self, _req: &Request, _ino: u64) -> Result<Statfs, Errno> {
+        Ok(Statfs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 })
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get an extended attribute.
+    fn getxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List extended attribute names.
+    fn listxattr(&self, _req: &Request, _ino: u64, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    fn access(&self, _req: &Request, _ino: u64, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open a file.
+    fn create(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    fn getlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    fn setlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Map block index within file to block index within device.
+    fn bmap(&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Control a device.
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl(&self, _req: &Request, _ino: u64, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _data: &[u8], _out_size: u32) -> Result<Ioctl, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Allocate or deallocate space of a file.
+    #[cfg(not(target_os = "macos"))]
+    fn fallocate(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _length: i64, _mode: i32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read directory with the attributes of the entries.
+    #[cfg(not(target_os = "macos"))]
+    fn readdirplus(&self, _req: &Request, _ino: u64, _fh: u64, _offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file with flags.
+    #[cfg(not(target_os = "macos"))]
+    fn rename2(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Find the next data or hole of a file.
+    #[cfg(not(target_os = "macos"))]
+    fn lseek(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _whence: i32) -> Result<i64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Copy a range of data from one file to another.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(not(target_os = "macos"))]
+    fn copy_file_range(&self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: i64, _ino_out: u64, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u64) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Synchronize the filesystem.
+    #[cfg(not(target_os = "macos"))]
+    fn syncfs(&self, _req: &Request, _ino: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open an unnamed file.
+    #[cfg(not(target_os = "macos"))]
+    fn tmpfile(&self, _req: &Request, _parent: u64, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file attributes for statx().
+    #[cfg(not(target_os = "macos"))]
+    fn statx(&self, _req: &Request, _ino: u64, _fh: Option<u64>, _flags: u32, _mask: u32) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    #[cfg(target_os = "macos")]
+    /// macOS only: Rename the volume.
+    fn setvolname(&self, _req: &Request, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    #[cfg(target_os = "macos")]
+    /// macOS only (undocumented)
+    fn exchange(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    #[cfg(target_os = "macos")]
+    /// macOS only: Query extended times (bkuptime and crtime).
+    fn getxtimes(&self, _req: &Request, _ino: u64) -> Result<XTimes, Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// Adapter running a `BlockingFilesystem` as `Filesystem`. Every operation is run using tokio's
+/// `spawn_blocking`, so the session needs to run on a tokio runtime. Arguments passed by
+/// reference are copied, since the blocking tasks can't borrow them.
+#[derive(Debug)]
+pub struct BlockingFs<FS> {
+    inner: Arc<FS>,
+}
+
+impl<FS: BlockingFilesystem> BlockingFs<FS> {
+    /// Run the given blocking filesystem
+    pub fn new(inner: FS) -> BlockingFs<FS> {
+        BlockingFs { inner: Arc::new(inner) }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Run the given operation on the blocking thread pool. Panics of the operation are resumed
+    /// in the calling task, like they would be if the operation ran there.
+    async fn run<T, F>(&self, req: &Request, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&FS, &Request) -> T + Send + 'static,
+    {
+        let (inner, req) = (self.inner.clone(), req.clone());
+        match tokio::task::spawn_blocking(move || f(&inner, &req)).await {
+            Ok(result) => result,
+            Err(err) => match err.try_into_panic() {
+                Ok(payload) => panic::resume_unwind(payload),
+                Err(err) => panic!("Blocking filesystem operation failed: {}", err),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<FS: BlockingFilesystem> Filesystem for BlockingFs<FS> {
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.init(req)).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        self.run(req, move |fs, req| fs.destroy(req)).await
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.lookup(req, parent, &name)).await
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        self.run(req, move |fs, req| fs.forget(req, ino, nlookup)).await
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        self.run(req, move |fs, req| fs.getattr(req, ino)).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        self.run(req, move |fs, req| fs.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags)).await
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.run(req, move |fs, req| fs.readlink(req, ino)).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.mknod(req, parent, &name, mode, rdev)).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.mkdir(req, parent, &name, mode)).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.unlink(req, parent, &name)).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.rmdir(req, parent, &name)).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let name = name.to_owned();
+        let link = link.to_owned();
+        self.run(req, move |fs, req| fs.symlink(req, parent, &name, &link)).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.run(req, move |fs, req| fs.rename(req, parent, &name, newparent, &newname)).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let newname = newname.to_owned();
+        self.run(req, move |fs, req| fs.link(req, ino, newparent, &newname)).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.run(req, move |fs, req| fs.open(req, ino, flags)).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        self.run(req, move |fs, req| fs.read(req, ino, fh, offset, size)).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let data = data.to_vec();
+        self.run(req, move |fs, req| fs.write(req, ino, fh, offset, &data, flags)).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.flush(req, ino, fh, lock_owner)).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.release(req, ino, fh, flags, lock_owner, flush)).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.fsync(req, ino, fh, datasync)).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.run(req, move |fs, req| fs.opendir(req, ino, flags)).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        self.run(req, move |fs, req| fs.readdir(req, ino, fh, offset)).await
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.releasedir(req, ino, fh, flags)).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.fsyncdir(req, ino, fh, datasync)).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        self.run(req, move |fs, req| fs.statfs(req, ino)).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        let name = name.to_owned();
+        let value = value.to_vec();
+        self.run(req, move |fs, req| fs.setxattr(req, ino, &name, &value, flags, position)).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.getxattr(req, ino, &name, size)).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.run(req, move |fs, req| fs.listxattr(req, ino, size)).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.removexattr(req, ino, &name)).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.access(req, ino, mask)).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.create(req, parent, &name, mode, flags)).await
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        self.run(req, move |fs, req| fs.getlk(req, ino, fh, lock_owner, start, end, typ, pid)).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep)).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        self.run(req, move |fs, req| fs.bmap(req, ino, blocksize, idx)).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        let data = data.to_vec();
+        self.run(req, move |fs, req| fs.ioctl(req, ino, fh, flags, cmd, arg, &data, out_size)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.fallocate(req, ino, fh, offset, length, mode)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        self.run(req, move |fs, req| fs.readdirplus(req, ino, fh, offset)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.run(req, move |fs, req| fs.rename2(req, parent, &name, newparent, &newname, flags)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.run(req, move |fs, req| fs.lseek(req, ino, fh, offset, whence)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.run(req, move |fs, req| fs.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        self.run(req, move |fs, req| fs.syncfs(req, ino)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.run(req, move |fs, req| fs.tmpfile(req, parent, mode, flags)).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        self.run(req, move |fs, req| fs.statx(req, ino, fh, flags, mask)).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        let name = name.to_owned();
+        self.run(req, move |fs, req| fs.setvolname(req, &name)).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.run(req, move |fs, req| fs.exchange(req, parent, &name, newparent, &newname, options)).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        self.run(req, move |fs, req| fs.getxtimes(req, ino)).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+    use crate::{Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::{BlockingFilesystem, BlockingFs};
+
+    struct SyncFs(thread::ThreadId);
+
+    impl BlockingFilesystem for SyncFs {
+        fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            assert_ne!(thread::current().id(), self.0);
+            if name != "file" { return Err(Errno::ENOENT); }
+            Ok(Entry::new(FileAttr::builder(2, FileType::RegularFile).time(UNIX_EPOCH).build(), 0))
+        }
+
+        fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            Ok(data.len() as u32)
+        }
+    }
+
+    #[test]
+    fn runs_blocking() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let fs = BlockingFs::new(SyncFs(thread::current().id()));
+        let req = Request::test(0, 0);
+        assert_eq!(rt.block_on(fs.lookup(&req, 1, OsStr::new("file"))).unwrap().attr.ino, 2);
+        assert_eq!(rt.block_on(fs.lookup(&req, 1, OsStr::new("other"))).unwrap_err(), Errno::ENOENT);
+        assert_eq!(rt.block_on(fs.write(&req, 2, 0, 0, b"hello", 0)), Ok(5));
+        assert_eq!(rt.block_on(fs.getattr(&req, 2)).unwrap_err(), Errno::ENOSYS);
+    }
+}
@@ -0,0 +1,380 @@
+//! Attribute and entry cache
+//!
+//! `CachedFs` remembers the results of lookup and getattr for a while, so that metadata heavy
+//! workloads don't call an expensive filesystem (e.g. one doing an RPC per getattr) over and
+//! over again. The kernel caches these results as well, but only for the TTLs of the replies,
+//! which have to be short for filesystems that change behind its back.
+//!
+//! Cached results are dropped when they expire, when operations through the cache change them,
+//! and when the filesystem tells the cache about changes made by others through a
+//! `CacheInvalidator`.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use async_trait::async_trait;
+
+use crate::layer::Wrapper;
+use crate::{Attr, Created, Entry, Errno, Filesystem, Request};
+
+/// Cached results, shared with invalidators
+#[derive(Debug, Default)]
+struct Cache {
+    attrs: Mutex<HashMap<u64, (Attr, Instant)>>,
+    entries: Mutex<HashMap<(u64, OsString), (Entry, Instant)>>,
+}
+
+impl Cache {
+    fn attr(&self, ino: u64) -> Option<Attr> {
+        let mut attrs = self.attrs.lock().unwrap();
+        match attrs.get(&ino) {
+            Some(&(attr, expires)) if Instant::now() < expires => Some(attr),
+            Some(_) => { attrs.remove(&ino); None },
+            None => None,
+        }
+    }
+
+    fn entry(&self, parent: u64, name: &OsStr) -> Option<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (parent, name.to_os_string());
+        match entries.get(&key) {
+            Some(&(entry, expires)) if Instant::now() < expires => Some(entry),
+            Some(_) => { entries.remove(&key); None },
+            None => None,
+        }
+    }
+
+    fn remove_inode(&self, ino: u64) {
+        self.attrs.lock().unwrap().remove(&ino);
+        // Entries carry the attributes of their inode too
+        self.entries.lock().unwrap().retain(|_, (entry, _)| entry.attr.ino != ino);
+    }
+
+    fn remove_entry(&self, parent: u64, name: &OsStr) {
+        let removed = self.entries.lock().unwrap().remove(&(parent, name.to_os_string()));
+        // The link count of the inode the entry refers to may have changed as well
+        if let Some((entry, _)) = removed {
+            self.remove_inode(entry.attr.ino);
+        }
+    }
+}
+
+/// Handle for telling a `CachedFs` about changes made to the filesystem by others (e.g. other
+/// clients of a network filesystem), like the kernel is told with invalidation notifications
+#[derive(Clone, Debug)]
+pub struct CacheInvalidator {
+    cache: Arc<Cache>,
+}
+
+impl CacheInvalidator {
+    /// Drop the cached attributes of the given inode
+    pub fn inval_inode(&self, ino: u64) {
+        self.cache.remove_inode(ino);
+    }
+
+    /// Drop the cached directory entry of the given name in the given directory, and the
+    /// cached attributes of the directory
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) {
+        self.cache.remove_entry(parent, name);
+        self.cache.remove_inode(parent);
+    }
+
+    /// Drop everything cached
+    pub fn clear(&self) {
+        self.cache.attrs.lock().unwrap().clear();
+        self.cache.entries.lock().unwrap().clear();
+    }
+}
+
+/// Filesystem wrapper caching the results of lookup and getattr
+#[derive(Debug)]
+pub struct CachedFs<FS> {
+    inner: FS,
+    ttl: Duration,
+    cache: Arc<Cache>,
+}
+
+impl<FS: Filesystem + Send + Sync> CachedFs<FS> {
+    /// Cache results of the given filesystem for the given time
+    pub fn new(inner: FS, ttl: Duration) -> CachedFs<FS> {
+        CachedFs { inner, ttl, cache: Arc::default() }
+    }
+
+    /// Returns a handle for invalidating cached results
+    pub fn invalidator(&self) -> CacheInvalidator {
+        CacheInvalidator { cache: self.cache.clone() }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Drop cached results that an operation on the given directory entry may have changed
+    fn changed_entry(&self, parent: u64, name: &OsStr) {
+        self.cache.remove_entry(parent, name);
+        // Modification time and link count of the directory change
+        self.cache.remove_inode(parent);
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for CachedFs<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        if let Some(entry) = self.cache.entry(parent, name) {
+            return Ok(entry);
+        }
+        let entry = self.inner.lookup(req, parent, name).await?;
+        let expires = Instant::now() + self.ttl;
+        if !entry.is_negative() {
+            self.cache.attrs.lock().unwrap().insert(entry.attr.ino, (Attr { ttl: entry.ttl, attr: entry.attr }, expires));
+        }
+        self.cache.entries.lock().unwrap().insert((parent, name.to_os_string()), (entry, expires));
+        Ok(entry)
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        if let Some(attr) = self.cache.attr(ino) {
+            return Ok(attr);
+        }
+        let attr = self.inner.getattr(req, ino).await?;
+        self.cache.attrs.lock().unwrap().insert(ino, (attr, Instant::now() + self.ttl));
+        Ok(attr)
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let result = self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await;
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let result = self.inner.mknod(req, parent, name, mode, rdev).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let result = self.inner.mkdir(req, parent, name, mode).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let result = self.inner.unlink(req, parent, name).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let result = self.inner.rmdir(req, parent, name).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let result = self.inner.symlink(req, parent, name, link).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let result = self.inner.rename(req, parent, name, newparent, newname).await;
+        self.changed_entry(parent, name);
+        self.changed_entry(newparent, newname);
+        result
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let result = self.inner.link(req, ino, newparent, newname).await;
+        self.changed_entry(newparent, newname);
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let result = self.inner.write(req, ino, fh, offset, data, flags).await;
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        let result = self.inner.setxattr(req, ino, name, value, flags, position).await;
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let result = self.inner.removexattr(req, ino, name).await;
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let result = self.inner.create(req, parent, name, mode, flags).await;
+        self.changed_entry(parent, name);
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        let result = self.inner.exchange(req, parent, name, newparent, newname, options).await;
+        self.changed_entry(parent, name);
+        self.changed_entry(newparent, newname);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        let result = self.inner.fallocate(req, ino, fh, offset, length, mode).await;
+        self.cache.remove_inode(ino);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        let result = self.inner.rename2(req, parent, name, newparent, newname, flags).await;
+        self.changed_entry(parent, name);
+        self.changed_entry(newparent, newname);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        let result = self.inner.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await;
+        self.cache.remove_inode(ino_out);
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+    use async_trait::async_trait;
+    use crate::{block_on, Attr, Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::CachedFs;
+
+    /// Filesystem with a file named `foo` of inode 2 in the root directory, counting calls
+    #[derive(Default)]
+    struct Counting {
+        lookups: AtomicUsize,
+        getattrs: AtomicUsize,
+        size: AtomicU64,
+    }
+
+    impl Counting {
+        fn attr(&self, ino: u64) -> FileAttr {
+            FileAttr::builder(ino, FileType::RegularFile).size(self.size.load(Ordering::SeqCst)).build()
+        }
+    }
+
+    #[async_trait]
+    impl Filesystem for Counting {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            match name.to_str() {
+                Some("foo") => Ok(Entry::new(self.attr(2), 0)),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            self.getattrs.fetch_add(1, Ordering::SeqCst);
+            Ok(Attr::new(self.attr(ino)))
+        }
+
+        async fn setattr(&self, _req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+            if let Some(size) = size {
+                self.size.store(size, Ordering::SeqCst);
+            }
+            Ok(Attr::new(self.attr(ino)))
+        }
+
+        async fn write(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            self.size.fetch_max(offset as u64 + data.len() as u64, Ordering::SeqCst);
+            Ok(data.len() as u32)
+        }
+
+        async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn caches() {
+        let req = Request::test(0, 0);
+        let fs = CachedFs::new(Counting::default(), Duration::from_secs(60));
+        let foo = OsStr::new("foo");
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.ino, 2);
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.ino, 2);
+        assert_eq!(fs.inner.lookups.load(Ordering::SeqCst), 1);
+        // Lookups fill in the attributes, errors aren't cached
+        assert!(block_on(Filesystem::getattr(&fs, &req, 2)).is_ok());
+        assert_eq!(fs.inner.getattrs.load(Ordering::SeqCst), 0);
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("bar"))).is_err());
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("bar"))).is_err());
+        assert_eq!(fs.inner.lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn expires() {
+        let req = Request::test(0, 0);
+        let fs = CachedFs::new(Counting::default(), Duration::from_millis(10));
+        assert!(block_on(Filesystem::getattr(&fs, &req, 1)).is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(block_on(Filesystem::getattr(&fs, &req, 1)).is_ok());
+        assert_eq!(fs.inner.getattrs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn invalidates() {
+        let req = Request::test(0, 0);
+        let fs = CachedFs::new(Counting::default(), Duration::from_secs(60));
+        let foo = OsStr::new("foo");
+        let getattrs = |fs: &CachedFs<Counting>| fs.inner.getattrs.load(Ordering::SeqCst);
+        // Changing operations drop what they change
+        assert!(block_on(Filesystem::getattr(&fs, &req, 1)).is_ok());
+        assert!(block_on(Filesystem::setattr(&fs, &req, 1, Some(0o755), None, None, None, None, None, None, None, None, None, None)).is_ok());
+        assert!(block_on(Filesystem::getattr(&fs, &req, 1)).is_ok());
+        assert_eq!(getattrs(&fs), 2);
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).is_ok());
+        assert!(block_on(Filesystem::unlink(&fs, &req, 1, foo)).is_ok());
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).is_ok());
+        assert!(block_on(Filesystem::getattr(&fs, &req, 1)).is_ok());
+        assert_eq!((fs.inner.lookups.load(Ordering::SeqCst), getattrs(&fs)), (2, 3));
+        // The filesystem drops what others change
+        fs.invalidator().inval_inode(2);
+        assert!(block_on(Filesystem::getattr(&fs, &req, 2)).is_ok());
+        assert_eq!(getattrs(&fs), 4);
+        fs.invalidator().inval_entry(1, foo);
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).is_ok());
+        assert_eq!(fs.inner.lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn entry_attrs() {
+        let req = Request::test(0, 0);
+        let fs = CachedFs::new(Counting::default(), Duration::from_secs(60));
+        let foo = OsStr::new("foo");
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.size, 0);
+        // Changing the inode of a cached entry drops the entry
+        assert!(block_on(Filesystem::write(&fs, &req, 2, 0, 0, b"data", 0)).is_ok());
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.size, 4);
+        assert!(block_on(Filesystem::setattr(&fs, &req, 2, None, None, None, Some(10), None, None, None, None, None, None, None)).is_ok());
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.size, 10);
+        fs.invalidator().inval_inode(2);
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, foo)).unwrap().attr.size, 10);
+        assert_eq!(fs.inner.lookups.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,141 @@
+//! Cancellation
+//!
+//! Every request comes with a cancellation token (`Request::cancellation`) that fires when the
+//! kernel interrupts the request, when the filesystem is destroyed or when the session ends
+//! because the filesystem was unmounted. Filesystems can check or wait for it to abandon work
+//! whose reply nobody would read, and can hand it on to tasks they spawn.
+
+use std::future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Poll, Waker};
+
+/// State of a token, shared by its clones
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl Inner {
+    fn cancel(&self) {
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            if self.cancelled.swap(true, Ordering::AcqRel) { return; }
+            std::mem::take(&mut *wakers)
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        let children = std::mem::take(&mut *self.children.lock().unwrap());
+        children.iter().filter_map(Weak::upgrade).for_each(|child| child.cancel());
+    }
+}
+
+/// Token telling that the work it was handed to is no longer needed
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    /// Create a token that is never cancelled unless cancelled explicitly
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Create a token that is cancelled together with this one, but can also be cancelled on
+    /// its own
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        {
+            let mut children = self.inner.children.lock().unwrap();
+            if !self.is_cancelled() {
+                // Forget children that are gone, so that long-living tokens don't grow
+                children.retain(|child| child.strong_count() > 0);
+                children.push(Arc::downgrade(&child.inner));
+                return child;
+            }
+        }
+        child.cancel();
+        child
+    }
+
+    /// Cancel this token and all of its children
+    pub fn cancel(&self) {
+        self.inner.cancel()
+    }
+
+    /// Returns true if this token was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Waits until this token is cancelled. Long running operations can select on this to
+    /// bail out early.
+    pub async fn cancelled(&self) {
+        future::poll_fn(|cx| {
+            if self.is_cancelled() { return Poll::Ready(()); }
+            let mut wakers = self.inner.wakers.lock().unwrap();
+            // Check again, the token may have been cancelled before the lock was taken
+            if self.is_cancelled() { return Poll::Ready(()); }
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Entry, Errno, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::CancellationToken;
+
+    #[test]
+    fn tokens() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        let other = parent.child();
+        child.cancel();
+        assert!(child.is_cancelled() && !parent.is_cancelled() && !other.is_cancelled());
+        let waiting = {
+            let other = other.clone();
+            thread::spawn(move || block_on(other.cancelled()))
+        };
+        parent.cancel();
+        waiting.join().unwrap();
+        assert!(other.is_cancelled());
+        // Children of cancelled tokens are cancelled right away
+        assert!(parent.child().is_cancelled());
+    }
+
+    struct Hanging(AtomicBool);
+
+    #[async_trait]
+    impl Filesystem for Hanging {
+        async fn lookup(&self, req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            self.0.store(true, Ordering::SeqCst);
+            req.cancellation().cancelled().await;
+            Err(Errno::EINTR)
+        }
+    }
+
+    #[test]
+    fn destroy() {
+        let ch = MockChannel::new(Hanging(AtomicBool::new(false)));
+        block_on(ch.init()).unwrap();
+        thread::scope(|s| {
+            let lookup = s.spawn(|| block_on(ch.lookup(FUSE_ROOT_ID, "foo")));
+            while !ch.session().filesystem.0.load(Ordering::SeqCst) { thread::yield_now(); }
+            // Destroying the filesystem cancels the requests being dispatched
+            block_on(ch.request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])).unwrap().result().unwrap();
+            assert_eq!(lookup.join().unwrap().unwrap_err(), Errno::EINTR);
+        });
+    }
+}
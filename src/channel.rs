@@ -1,19 +1,38 @@
 //! FUSE kernel driver communication
 //!
-//! Raw communication channel to the FUSE kernel driver.
+//! Raw communication channel to the FUSE kernel driver. Filesystems are mounted using libfuse,
+//! except on Android, which has neither libfuse nor fusermount. There, /dev/fuse is opened and
+//! mounted directly (which needs the right privileges), or an app is handed the fd of an
+//! already mounted /dev/fuse.
+//!
+//! On FreeBSD, the fusefs(5) driver is mounted like its libfuse does: /dev/fuse is opened and
+//! its fd passed to mount_fusefs(8), which regular users can run for mountpoints they own if
+//! vfs.usermount is set. Unmounting is plain unmount(2), without a setuid helper. The device
+//! behaves like Linux' for the session: one request per read, and ENODEV once unmounted.
+//!
+//! Sessions receive requests and send replies through a `Transport`, of which the kernel
+//! channel is just one. Other transports can carry the same protocol by other means.
 
 use std::io;
 use std::ffi::{CStr, CString, OsString};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::path::{PathBuf, Path};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(any(target_os = "android", target_os = "freebsd")))]
 use fuse_sys::{fuse_args, fuse_mount_compat25};
 use libc::{self, c_int, c_void, size_t};
 use log::error;
+#[cfg(target_os = "freebsd")]
+use log::warn;
 
+use crate::error::MountError;
 use crate::reply::ReplySender;
 
 /// Helper function to provide options as a fuse_args struct
 /// (which contains an argc count and an argv pointer)
+#[cfg(not(any(target_os = "android", target_os = "freebsd")))]
 fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[OsString], f: F) -> T {
     let mut args = vec![CString::new("fuse-rs").unwrap()];
     args.extend(options.iter().map(|s| CString::new(s.as_bytes()).unwrap()));
@@ -21,11 +40,107 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[OsString], f: F) ->
     f(&fuse_args { argc: argptrs.len() as i32, argv: argptrs.as_ptr(), allocated: 0 })
 }
 
+/// Mount the given path using libfuse and return the fd to the kernel driver
+#[cfg(not(any(target_os = "android", target_os = "freebsd")))]
+fn mount(mountpoint: &Path, options: &[OsString]) -> io::Result<c_int> {
+    with_fuse_args(options, |args| {
+        let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+        let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
+        if fd < 0 { Err(io::Error::last_os_error()) } else { Ok(fd) }
+    })
+}
+
+/// Mount the given path by opening /dev/fuse and passing its fd to the mount syscall, which
+/// is what fusermount does on other systems
+#[cfg(target_os = "android")]
+fn mount(mountpoint: &Path, options: &[OsString]) -> io::Result<c_int> {
+    let fd = unsafe { libc::open(b"/dev/fuse\0".as_ptr() as *const libc::c_char, libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    let mut source = String::from("fuse");
+    let mut fstype = "fuse\0";
+    let mut data = format!("fd={},rootmode=40000,user_id={},group_id={}", fd, unsafe { libc::getuid() }, unsafe { libc::getgid() });
+    for option in crate::session::mount_options(options) {
+        match option.split_once('=') {
+            _ if option == "ro" => flags |= libc::MS_RDONLY,
+            // Block devices are mounted with another type, the source is the device
+            _ if option == "blkdev" => fstype = "fuseblk\0",
+            // Options of libfuse rather than of the kernel
+            Some(("fsname", fsname)) => source = fsname.to_string(),
+            Some(("subtype", _)) => (),
+            _ => { data.push(','); data.push_str(&option); }
+        }
+    }
+    let source = CString::new(source)?;
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let data = CString::new(data)?;
+    let rc = unsafe { libc::mount(source.as_ptr(), mnt.as_ptr(), fstype.as_ptr() as *const libc::c_char, flags, data.as_ptr() as *const c_void) };
+    if rc < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd); }
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+/// Mount the given path by opening /dev/fuse and running mount_fusefs with its fd, which the
+/// command inherits
+#[cfg(target_os = "freebsd")]
+fn mount(mountpoint: &Path, options: &[OsString]) -> io::Result<c_int> {
+    use std::process::Command;
+
+    let fd = unsafe { libc::open(b"/dev/fuse\0".as_ptr() as *const libc::c_char, libc::O_RDWR) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    let mut fusefs_options = Vec::new();
+    for option in crate::session::mount_options(options) {
+        match option.as_str() {
+            // Options of Linux and its fusermount that fusefs doesn't know
+            "auto_unmount" | "blkdev" => warn!("Mount option {} isn't supported on FreeBSD", option),
+            _ => fusefs_options.push(option),
+        }
+    }
+    let mut command = Command::new("mount_fusefs");
+    if !fusefs_options.is_empty() {
+        command.arg("-o").arg(fusefs_options.join(","));
+    }
+    // Like libfuse, tell mount_fusefs that the fd is served by the calling process, so it
+    // doesn't start a daemon or check the fd for a daemon it started
+    command.env("MOUNT_FUSEFS_SAFE", "1").env("MOUNT_FUSEFS_CALL_BY_LIB", "1");
+    let result = command.arg(fd.to_string()).arg(mountpoint).status().and_then(|status| {
+        if status.success() { Ok(()) } else { Err(io::Error::other(format!("mount_fusefs failed ({})", status))) }
+    });
+    if let Err(err) = result {
+        unsafe { libc::close(fd); }
+        return Err(err);
+    }
+    // Not inherited by anything the filesystem runs later
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+    Ok(fd)
+}
+
+/// Transport of requests to a session and of their replies back, in the FUSE kernel protocol
+pub trait Transport: Send + Sync + 'static {
+    /// Receives the next request into the given buffer, up to its capacity (can block). Every
+    /// call must receive exactly one request. An `ENODEV` error ends the session.
+    fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Send a reply, given as slices of bytes, at once (can block). Sending may happen from
+    /// other threads while receiving.
+    fn send(&self, data: &[&[u8]]) -> io::Result<()>;
+
+    /// Return path of the mounted filesystem (empty if the transport doesn't know it)
+    fn mountpoint(&self) -> &Path {
+        Path::new("")
+    }
+}
+
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    /// True if the channel mounted the filesystem and unmounts it when dropped
+    mounted: AtomicBool,
 }
 
 impl Channel {
@@ -33,17 +148,17 @@ impl Channel {
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
     /// unmounted.
-    pub fn new(mountpoint: &Path, options: &[OsString]) -> io::Result<Channel> {
-        let mountpoint = mountpoint.canonicalize()?;
-        with_fuse_args(options, |args| {
-            let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
-            let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
-            if fd < 0 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(Channel { mountpoint: mountpoint, fd: fd })
-            }
-        })
+    pub fn new(mountpoint: &Path, options: &[OsString]) -> Result<Channel, MountError> {
+        let mountpoint = mountpoint.canonicalize().map_err(|source| MountError::Mountpoint { path: mountpoint.to_path_buf(), source })?;
+        let fd = mount(&mountpoint, options).map_err(|err| MountError::diagnose(&mountpoint, options, err))?;
+        Ok(Channel { mountpoint: mountpoint, fd: fd, mounted: AtomicBool::new(true) })
+    }
+
+    /// Create a communication channel from the fd to the kernel driver of a filesystem that
+    /// was mounted to the given path by someone else (e.g. the fd an Android app is handed
+    /// by the system). The channel closes the fd when dropped, but doesn't unmount the path.
+    pub fn from_fd(fd: OwnedFd, mountpoint: &Path) -> Channel {
+        Channel { mountpoint: mountpoint.to_path_buf(), fd: fd.into_raw_fd(), mounted: AtomicBool::new(false) }
     }
 
     /// Return path of the mounted filesystem
@@ -51,6 +166,17 @@ impl Channel {
         &self.mountpoint
     }
 
+    /// Returns a duplicate of the fd to the kernel driver, and keeps the channel from
+    /// unmounting the filesystem when dropped, since the fd is served by someone else then
+    pub fn disown(&self) -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.mounted.store(false, Ordering::SeqCst);
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
     /// Receives data up to the capacity of the given buffer (can block).
     pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
         let rc = unsafe { libc::read(self.fd, buffer.as_ptr() as *mut c_void, buffer.capacity() as size_t) };
@@ -74,6 +200,26 @@ impl Channel {
     }
 }
 
+impl Transport for Channel {
+    fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        Channel::receive(self, buffer)
+    }
+
+    fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+        self.sender().send(data)
+    }
+
+    fn mountpoint(&self) -> &Path {
+        Channel::mountpoint(self)
+    }
+}
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 impl Drop for Channel {
     fn drop(&mut self) {
         // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on macOS before closing the fd
@@ -81,7 +227,9 @@ impl Drop for Channel {
         // (closing it before unnmount prevents sync unmount deadlock)
         unsafe { libc::close(self.fd); }
         // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        if self.mounted.load(Ordering::SeqCst) {
+            let _ = unmount(&self.mountpoint);
+        }
     }
 }
 
@@ -105,6 +253,14 @@ impl ChannelSender {
     }
 }
 
+#[cfg(test)]
+impl ChannelSender {
+    /// Returns a sender that fails to send anything
+    pub fn null() -> ChannelSender {
+        ChannelSender { fd: -1 }
+    }
+}
+
 impl ReplySender for ChannelSender {
     fn send(&self, data: &[&[u8]]) {
         if let Err(err) = ChannelSender::send(self, data) {
@@ -113,6 +269,18 @@ impl ReplySender for ChannelSender {
     }
 }
 
+/// Reply sender of a session, sending replies through its transport
+#[derive(Debug)]
+pub(crate) struct TransportSender<T>(pub(crate) Arc<T>);
+
+impl<T: Transport> ReplySender for TransportSender<T> {
+    fn send(&self, data: &[&[u8]]) {
+        if let Err(err) = self.0.send(data) {
+            error!("Failed to send FUSE reply: {}", err);
+        }
+    }
+}
+
 /// Unmount an arbitrary mount point
 pub fn unmount(mountpoint: &Path) -> io::Result<()> {
     // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
@@ -122,15 +290,31 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
     // directly, which is what osxfuse does anyway, since we already converted
     // to the real path when we first mounted.
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
+    #[cfg(any(target_os = "macos", target_os = "dragonfly",
               target_os = "openbsd", target_os = "bitrig", target_os = "netbsd"))]
     #[inline]
     fn libc_umount(mnt: &CStr) -> c_int {
         unsafe { libc::unmount(mnt.as_ptr(), 0) }
     }
 
+    #[cfg(target_os = "freebsd")]
+    #[inline]
+    fn libc_umount(mnt: &CStr) -> c_int {
+        // Users may unmount what they mounted (with vfs.usermount), so unlike on Linux there's
+        // no setuid helper to fall back to
+        unsafe { libc::unmount(mnt.as_ptr(), 0) }
+    }
+
+    #[cfg(target_os = "android")]
+    #[inline]
+    fn libc_umount(mnt: &CStr) -> c_int {
+        // There's no fusermount to fall back to
+        unsafe { libc::umount(mnt.as_ptr()) }
+    }
+
     #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
-                  target_os = "openbsd", target_os = "bitrig", target_os = "netbsd")))]
+                  target_os = "openbsd", target_os = "bitrig", target_os = "netbsd",
+                  target_os = "android")))]
     #[inline]
     fn libc_umount(mnt: &CStr) -> c_int {
         use fuse_sys::fuse_unmount_compat22;
@@ -157,7 +341,7 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, not(any(target_os = "android", target_os = "freebsd"))))]
 mod test {
     use super::with_fuse_args;
     use std::ffi::{CStr, OsString};
@@ -0,0 +1,183 @@
+//! Command line parsing
+//!
+//! libfuse filesystems share a command line syntax: `[options] mountpoint`, with mount options
+//! given as `-o opt1,opt2=val`, `-f` to run in the foreground, `-d` to run in the foreground
+//! with debug output and `-s` to handle one request at a time. `CommandLine` parses arguments in
+//! this syntax, so that filesystems replacing libfuse based ones keep their command line.
+//! The libfuse options `entry_timeout`, `attr_timeout`, `max_write` and `debug` are taken as
+//! session settings, other mount options are passed to the mount.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::daemon::Daemon;
+use crate::Filesystem;
+use crate::mount::Mount;
+
+/// Arguments of a filesystem command line in the syntax of libfuse
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandLine {
+    /// Path to mount the filesystem to (the last argument that isn't an option)
+    pub mountpoint: Option<PathBuf>,
+    /// Arguments that aren't options before the mountpoint (e.g. the source of the filesystem)
+    pub args: Vec<OsString>,
+    /// Mount options given with `-o` (excluding the session settings below)
+    pub options: Vec<String>,
+    /// Run in the foreground (`-f`)
+    pub foreground: bool,
+    /// Print debug output, and run in the foreground (`-d` or `-o debug`)
+    pub debug: bool,
+    /// Handle one request at a time (`-s`)
+    pub single_thread: bool,
+    /// Print help (`-h` or `--help`), no mountpoint is needed then
+    pub help: bool,
+    /// Print the version (`-V` or `--version`), no mountpoint is needed then
+    pub version: bool,
+    /// Time the kernel may cache names (`-o entry_timeout=SECS`)
+    pub entry_ttl: Option<Duration>,
+    /// Time the kernel may cache attributes (`-o attr_timeout=SECS`)
+    pub attr_ttl: Option<Duration>,
+    /// Maximum size of write requests (`-o max_write=N`)
+    pub max_write: Option<u32>,
+}
+
+/// Returns an error for an invalid command line
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl CommandLine {
+    /// Parse the given arguments, without the program name (e.g. `env::args_os().skip(1)`).
+    /// Arguments after `--` are never taken as options.
+    pub fn parse<I, A>(args: I) -> io::Result<CommandLine>
+    where I: IntoIterator<Item = A>, A: Into<OsString> {
+        let mut cmdline = CommandLine::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            let arg_str = arg.to_string_lossy();
+            match &*arg_str {
+                "--" => { positional.extend(args.by_ref()); },
+                "-f" => cmdline.foreground = true,
+                "-d" => cmdline.debug = true,
+                "-s" => cmdline.single_thread = true,
+                "-h" | "--help" => cmdline.help = true,
+                "-V" | "--version" => cmdline.version = true,
+                "-o" => {
+                    let opts = args.next().ok_or_else(|| invalid("missing argument after -o".into()))?;
+                    cmdline.add_options(&opts.to_string_lossy())?;
+                }
+                opt if opt.starts_with("-o") => cmdline.add_options(&opt[2..])?,
+                opt if opt.starts_with('-') && opt.len() > 1 => return Err(invalid(format!("unknown option {}", opt))),
+                _ => positional.push(arg),
+            }
+        }
+        cmdline.mountpoint = positional.pop().map(PathBuf::from);
+        cmdline.args = positional;
+        if cmdline.mountpoint.is_none() && !cmdline.help && !cmdline.version {
+            return Err(invalid("no mountpoint specified".into()));
+        }
+        Ok(cmdline)
+    }
+
+    /// Add the given comma separated mount options
+    fn add_options(&mut self, opts: &str) -> io::Result<()> {
+        for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+            let (name, value) = match opt.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (opt, None),
+            };
+            let secs = |value: Option<&str>| value.and_then(|value| value.parse().ok()).and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| invalid(format!("invalid value of option {}", opt)));
+            match name {
+                "debug" => self.debug = true,
+                "entry_timeout" => self.entry_ttl = Some(secs(value)?),
+                "attr_timeout" => self.attr_ttl = Some(secs(value)?),
+                "max_write" => self.max_write = Some(value.and_then(|value| value.parse().ok()).ok_or_else(|| invalid(format!("invalid value of option {}", opt)))?),
+                _ => self.options.push(opt.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the filesystem should run in the foreground
+    pub fn is_foreground(&self) -> bool {
+        self.foreground || self.debug
+    }
+
+    /// Fork into the background unless running in the foreground (see `Daemon::fork`)
+    ///
+    /// # Safety
+    ///
+    /// The process must be single-threaded, like for `Daemon::fork`.
+    pub unsafe fn daemon(&self) -> io::Result<Daemon> {
+        if self.is_foreground() { Ok(Daemon::foreground()) } else { Daemon::fork() }
+    }
+
+    /// Returns a builder for mounting the given filesystem with the parsed mountpoint, options
+    /// and settings
+    pub fn mount<FS: Filesystem + Send + Sync + 'static>(&self, filesystem: FS) -> io::Result<Mount<FS>> {
+        let mountpoint = self.mountpoint.as_ref().ok_or_else(|| invalid("no mountpoint specified".into()))?;
+        let mut mount = Mount::new(filesystem, mountpoint);
+        if !self.options.is_empty() {
+            mount = mount.option(self.options.join(","));
+        }
+        if let Some(ttl) = self.entry_ttl {
+            mount = mount.entry_ttl(ttl);
+        }
+        if let Some(ttl) = self.attr_ttl {
+            mount = mount.attr_ttl(ttl);
+        }
+        if let Some(max_write) = self.max_write {
+            mount = mount.max_write(max_write);
+        }
+        if self.single_thread {
+            mount = mount.workers(1);
+        }
+        Ok(mount)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use crate::Filesystem;
+    use super::CommandLine;
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    #[test]
+    fn parse() {
+        let cmdline = CommandLine::parse(["-o", "ro,fsname=foo,entry_timeout=1.5", "host:/dir", "-f", "-oallow_other,max_write=65536", "/mnt"]).unwrap();
+        assert_eq!(cmdline, CommandLine {
+            mountpoint: Some(PathBuf::from("/mnt")),
+            args: vec!["host:/dir".into()],
+            options: vec!["ro".into(), "fsname=foo".into(), "allow_other".into()],
+            foreground: true,
+            entry_ttl: Some(Duration::from_millis(1500)),
+            max_write: Some(65536),
+            ..CommandLine::default()
+        });
+        assert!(cmdline.is_foreground());
+        let cmdline = CommandLine::parse(["-s", "-o", "debug", "--", "-mnt"]).unwrap();
+        assert_eq!((cmdline.single_thread, cmdline.is_foreground(), cmdline.mountpoint), (true, true, Some(PathBuf::from("-mnt"))));
+        assert!(CommandLine::parse(["--help"]).unwrap().help);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(CommandLine::parse(Vec::<String>::new()).is_err());
+        assert!(CommandLine::parse(["-x", "/mnt"]).is_err());
+        assert!(CommandLine::parse(["/mnt", "-o"]).is_err());
+        assert!(CommandLine::parse(["-o", "attr_timeout=soon", "/mnt"]).is_err());
+        assert!(CommandLine::parse(["--version"]).unwrap().mount(Empty).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Write coalescing
+//!
+//! With the writeback cache, the kernel sends writes of at most a page or a few pages at a time.
+//! Backends that store whole objects (e.g. an object store doing a PUT per write) are better off
+//! with fewer, larger writes. `WriteCoalescing` buffers adjacent writes of a file handle and
+//! writes them to the wrapped filesystem at once, in chunks aligned to the chunk size, when
+//! enough data is buffered, when the buffered data gets too old, and on flush, fsync and
+//! release.
+//!
+//! Like with the writeback cache itself, errors of buffered writes are reported by a later
+//! write, flush, fsync or release of the file handle.
+
+use std::collections::HashMap;
+use std::future;
+use std::sync::Mutex;
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+use async_trait::async_trait;
+use log::error;
+
+use crate::layer::Wrapper;
+use crate::{Attr, Data, Errno, Filesystem, Request};
+
+/// Writes buffered for a file handle
+#[derive(Debug)]
+struct Pending {
+    offset: i64,
+    data: Vec<u8>,
+    flags: u32,
+    since: Instant,
+}
+
+/// Buffered writes of a file handle, and whether an operation is using them
+#[derive(Debug, Default)]
+struct Handle {
+    pending: Option<Pending>,
+    busy: bool,
+    waiters: Vec<Waker>,
+}
+
+/// Exclusive use of the buffered writes of a file handle, so that they're written in order
+struct Used<'a> {
+    handles: &'a Mutex<HashMap<(u64, u64), Handle>>,
+    key: (u64, u64),
+    pending: Option<Pending>,
+}
+
+impl Drop for Used<'_> {
+    fn drop(&mut self) {
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(handle) = handles.get_mut(&self.key) {
+            handle.pending = self.pending.take();
+            handle.busy = false;
+            handle.waiters.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// Filesystem wrapper coalescing adjacent writes of file handles into larger writes
+#[derive(Debug)]
+pub struct WriteCoalescing<FS> {
+    inner: FS,
+    chunk_size: usize,
+    max_age: Duration,
+    handles: Mutex<HashMap<(u64, u64), Handle>>,
+}
+
+impl<FS: Filesystem + Send + Sync> WriteCoalescing<FS> {
+    /// Coalesce writes to the given filesystem into chunks of the given size. Buffered data is
+    /// written when the handle is used after it got older than the given age.
+    pub fn new(inner: FS, chunk_size: usize, max_age: Duration) -> WriteCoalescing<FS> {
+        WriteCoalescing { inner, chunk_size: chunk_size.max(1), max_age, handles: Mutex::default() }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Waits until no other operation uses the buffered writes of the given file handle
+    async fn use_handle(&self, ino: u64, fh: u64) -> Used<'_> {
+        let key = (ino, fh);
+        future::poll_fn(|cx| {
+            let mut handles = self.handles.lock().unwrap();
+            let handle = handles.entry(key).or_default();
+            if handle.busy {
+                if !handle.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    handle.waiters.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            handle.busy = true;
+            Poll::Ready(Used { handles: &self.handles, key, pending: handle.pending.take() })
+        }).await
+    }
+
+    /// Write the given data to the wrapped filesystem, which may write less than asked for
+    async fn write_all(&self, req: &Request, ino: u64, fh: u64, mut offset: i64, mut data: &[u8], flags: u32) -> Result<(), Errno> {
+        while !data.is_empty() {
+            let written = self.inner.write(req, ino, fh, offset, data, flags).await? as usize;
+            if written == 0 {
+                return Err(Errno::EIO);
+            }
+            offset += written as i64;
+            data = &data[written.min(data.len())..];
+        }
+        Ok(())
+    }
+
+    /// Write the buffered data of a used file handle. With `all` false, only whole chunks are
+    /// written and the rest stays buffered.
+    async fn write_pending(&self, req: &Request, used: &mut Used<'_>, all: bool) -> Result<(), Errno> {
+        let (ino, fh) = used.key;
+        let pending = match used.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let end = pending.offset + pending.data.len() as i64;
+        let split = if all { end } else { end - end.rem_euclid(self.chunk_size as i64) };
+        if split <= pending.offset {
+            used.pending = Some(pending);
+            return Ok(());
+        }
+        let len = (split - pending.offset) as usize;
+        self.write_all(req, ino, fh, pending.offset, &pending.data[..len], pending.flags).await?;
+        if len < pending.data.len() {
+            used.pending = Some(Pending { offset: split, data: pending.data[len..].to_vec(), ..pending });
+        }
+        Ok(())
+    }
+
+    /// Write the buffered data of the given file handle
+    async fn flush_handle(&self, req: &Request, ino: u64, fh: u64) -> Result<(), Errno> {
+        let mut used = self.use_handle(ino, fh).await;
+        self.write_pending(req, &mut used, true).await
+    }
+
+    /// Write the buffered data of all file handles of the given file
+    async fn flush_inode(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        let fhs: Vec<u64> = self.handles.lock().unwrap().keys().filter(|(i, _)| *i == ino).map(|(_, fh)| *fh).collect();
+        for fh in fhs {
+            self.flush_handle(req, ino, fh).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for WriteCoalescing<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn destroy(&self, req: &Request) {
+        let keys: Vec<(u64, u64)> = self.handles.lock().unwrap().keys().copied().collect();
+        for (ino, fh) in keys {
+            if let Err(err) = self.flush_handle(req, ino, fh).await {
+                error!("Failed to write buffered data of inode {}: {}", ino, err);
+            }
+        }
+        self.inner.destroy(req).await
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.getattr(req, ino).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.read(req, ino, fh, offset, size).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let mut used = self.use_handle(ino, fh).await;
+        // Data that isn't adjacent to the buffered data starts a new buffer
+        let adjacent = used.pending.as_ref().is_some_and(|pending| pending.offset + pending.data.len() as i64 == offset);
+        let expired = used.pending.as_ref().is_some_and(|pending| pending.since.elapsed() >= self.max_age);
+        if !adjacent || expired {
+            self.write_pending(req, &mut used, true).await?;
+        }
+        match used.pending {
+            Some(ref mut pending) => { pending.data.extend_from_slice(data); pending.flags = flags; },
+            None => used.pending = Some(Pending { offset, data: data.to_vec(), flags, since: Instant::now() }),
+        }
+        self.write_pending(req, &mut used, false).await?;
+        Ok(data.len() as u32)
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.flush_handle(req, ino, fh).await?;
+        self.inner.flush(req, ino, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        let result = self.flush_handle(req, ino, fh).await;
+        self.handles.lock().unwrap().remove(&(ino, fh));
+        let released = self.inner.release(req, ino, fh, flags, lock_owner, flush).await;
+        result.and(released)
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.flush_handle(req, ino, fh).await?;
+        self.inner.fsync(req, ino, fh, datasync).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.fallocate(req, ino, fh, offset, length, mode).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.lseek(req, ino, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.flush_inode(req, ino_in).await?;
+        self.flush_inode(req, ino_out).await?;
+        self.inner.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        let keys: Vec<(u64, u64)> = self.handles.lock().unwrap().keys().copied().collect();
+        for (ino, fh) in keys {
+            self.flush_handle(req, ino, fh).await?;
+        }
+        self.inner.syncfs(req, ino).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        self.flush_inode(req, ino).await?;
+        self.inner.statx(req, ino, fh, flags, mask).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use crate::{block_on, Errno, Filesystem, Request};
+    use super::WriteCoalescing;
+
+    /// Filesystem recording writes
+    #[derive(Default)]
+    struct Writes(Mutex<Vec<(i64, usize)>>);
+
+    #[async_trait]
+    impl Filesystem for Writes {
+        async fn write(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            self.0.lock().unwrap().push((offset, data.len()));
+            Ok(data.len() as u32)
+        }
+
+        async fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        async fn release(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> Result<(), Errno> {
+            Ok(())
+        }
+    }
+
+    fn write(fs: &WriteCoalescing<Writes>, fh: u64, offset: i64, len: usize) {
+        assert_eq!(block_on(Filesystem::write(fs, &Request::test(0, 0), 2, fh, offset, &vec![0; len], 0)), Ok(len as u32));
+    }
+
+    #[test]
+    fn chunks() {
+        let fs = WriteCoalescing::new(Writes::default(), 100, Duration::from_secs(60));
+        for offset in (10..250).step_by(40) {
+            write(&fs, 1, offset, 40);
+        }
+        // Writes up to chunk boundaries, the rest stays buffered until flushed
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(10, 90), (100, 100)]);
+        block_on(Filesystem::flush(&fs, &Request::test(0, 0), 2, 1, 0)).unwrap();
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(10, 90), (100, 100), (200, 50)]);
+    }
+
+    #[test]
+    fn gaps() {
+        let fs = WriteCoalescing::new(Writes::default(), 100, Duration::from_secs(60));
+        write(&fs, 1, 0, 10);
+        write(&fs, 1, 10, 10);
+        write(&fs, 1, 50, 10);
+        // Other handles are buffered separately
+        write(&fs, 2, 20, 10);
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 20)]);
+        block_on(Filesystem::release(&fs, &Request::test(0, 0), 2, 1, 0, 0, false)).unwrap();
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 20), (50, 10)]);
+        // Reading sees all buffered data
+        let _ = block_on(Filesystem::read(&fs, &Request::test(0, 0), 2, 2, 0, 100));
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 20), (50, 10), (20, 10)]);
+        assert!(fs.handles.lock().unwrap().get(&(2, 1)).is_none());
+    }
+
+    #[test]
+    fn age() {
+        let fs = WriteCoalescing::new(Writes::default(), 100, Duration::from_millis(10));
+        write(&fs, 1, 0, 10);
+        std::thread::sleep(Duration::from_millis(20));
+        write(&fs, 1, 10, 10);
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 10)]);
+    }
+}
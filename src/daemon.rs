@@ -0,0 +1,156 @@
+//! Running in the background
+//!
+//! Like libfuse filesystems without `-f`, a filesystem daemon can fork into the background once
+//! the filesystem is mounted, so that the command mounting it returns when the mountpoint can
+//! be used, and fails if mounting fails. Only the calling thread survives a fork, so the
+//! process has to fork before starting other threads, in particular before starting an async
+//! runtime (i.e. in a plain `main` that builds the runtime afterwards, not in `#[tokio::main]`).
+//! The parent then waits until the child tells it that the filesystem is mounted.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use log::debug;
+
+/// Daemon process, that tells the process that started it once the filesystem is mounted
+#[derive(Debug)]
+pub struct Daemon {
+    ready: Option<File>,
+}
+
+impl Daemon {
+    /// Keep running in the foreground (like libfuse's `-f` and `-d`). Telling that the
+    /// filesystem is ready does nothing.
+    pub fn foreground() -> Daemon {
+        Daemon { ready: None }
+    }
+
+    /// Fork into the background. The parent process waits until the child calls `ready` and
+    /// then exits successfully. If the child drops the daemon or exits before, e.g. because
+    /// mounting failed, the parent exits with status 1. The child starts a new session and
+    /// changes its working directory to `/`, so relative paths must be resolved before.
+    ///
+    /// # Safety
+    ///
+    /// The process must be single-threaded: only the calling thread survives in the child, so
+    /// locks and other state held by other threads (e.g. of an async runtime or the allocator)
+    /// would be left inconsistent.
+    pub unsafe fn fork() -> io::Result<Daemon> {
+        let (reader, writer) = pipe()?;
+        match libc::fork() {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                drop(reader);
+                if libc::setsid() < 0 || libc::chdir(b"/\0".as_ptr() as *const libc::c_char) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Daemon { ready: Some(writer) })
+            }
+            _ => {
+                drop(writer);
+                Daemon::wait(reader)
+            }
+        }
+    }
+
+    /// Wait for the child in the parent process, and exit with its result
+    fn wait(mut reader: File) -> ! {
+        let mut buf = [0];
+        let status = match reader.read(&mut buf) {
+            Ok(1) => 0,
+            _ => 1,
+        };
+        // Don't run destructors or exit handlers, they're the child's
+        unsafe { libc::_exit(status) }
+    }
+
+    /// Returns true if the process forked into the background
+    pub fn is_background(&self) -> bool {
+        self.ready.is_some()
+    }
+
+    /// Tell the parent process that the filesystem is mounted, and detach standard input and
+    /// output from the terminal by redirecting them to /dev/null. Errors are reported to the
+    /// terminal until then. Does nothing if running in the foreground or already called.
+    pub fn ready(&mut self) -> io::Result<()> {
+        let mut ready = match self.ready.take() {
+            Some(ready) => ready,
+            None => return Ok(()),
+        };
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        debug!("Running in the background");
+        ready.write_all(&[0])
+    }
+}
+
+/// Returns the reading and writing end of a new pipe, which aren't inherited by programs the
+/// daemon executes
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from(OwnedFd::from_raw_fd(fds[0])), File::from(OwnedFd::from_raw_fd(fds[1]))) })
+}
+
+/// Returns the reading and writing end of a new pipe, which aren't inherited by programs the
+/// daemon executes. Without pipe2, the close-on-exec flag is set after creating the pipe,
+/// which doesn't race with other threads executing programs since there are none yet.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe { (File::from(OwnedFd::from_raw_fd(fds[0])), File::from(OwnedFd::from_raw_fd(fds[1]))) };
+    for fd in [&reader, &writer] {
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader, writer))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use super::{pipe, Daemon};
+
+    /// Returns the exit status of a process that daemonizes with the given function running in
+    /// the daemon
+    fn status(daemon: fn(&mut Daemon)) -> i32 {
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let mut d = unsafe { Daemon::fork() }.unwrap_or_else(|_| unsafe { libc::_exit(2) });
+            daemon(&mut d);
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        libc::WEXITSTATUS(status)
+    }
+
+    #[test]
+    fn ready() {
+        assert_eq!(status(|daemon| daemon.ready().unwrap()), 0);
+        // Failing before being ready fails the parent
+        assert_eq!(status(|_| ()), 1);
+        assert!(!Daemon::foreground().is_background());
+        Daemon::foreground().ready().unwrap();
+    }
+
+    #[test]
+    fn close_on_exec() {
+        let (reader, writer) = pipe().unwrap();
+        for fd in [reader.as_raw_fd(), writer.as_raw_fd()] {
+            assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+    }
+}
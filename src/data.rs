@@ -0,0 +1,124 @@
+//! Reply data
+//!
+//! Data returned by read is sent to the kernel directly from the buffer it is stored in. Besides
+//! owned vectors, `Data` can hold static slices and (with the `bytes` feature) refcounted
+//! `Bytes` buffers, so that filesystems serving data from a cache or a network body don't need
+//! to copy it for every read.
+
+use std::fmt;
+use std::ops::Deref;
+
+#[derive(Clone)]
+enum Repr {
+    Vec(Vec<u8>),
+    Static(&'static [u8]),
+    #[cfg(feature = "bytes")]
+    Bytes(bytes::Bytes),
+}
+
+/// Data of a read or readlink reply
+#[derive(Clone)]
+pub struct Data(Repr);
+
+impl Data {
+    /// Create data from a static slice without copying
+    pub const fn from_static(data: &'static [u8]) -> Data {
+        Data(Repr::Static(data))
+    }
+}
+
+impl Default for Data {
+    fn default() -> Data {
+        Data::from_static(&[])
+    }
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {
+            Repr::Vec(ref data) => data,
+            Repr::Static(data) => data,
+            #[cfg(feature = "bytes")]
+            Repr::Bytes(ref data) => data,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Data {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for Data {
+    fn eq(&self, other: &Data) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Data {}
+
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Data({} bytes)", self.len())
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(data: Vec<u8>) -> Data {
+        Data(Repr::Vec(data))
+    }
+}
+
+impl From<&[u8]> for Data {
+    fn from(data: &[u8]) -> Data {
+        Data(Repr::Vec(data.to_vec()))
+    }
+}
+
+impl From<String> for Data {
+    fn from(data: String) -> Data {
+        Data(Repr::Vec(data.into_bytes()))
+    }
+}
+
+impl From<&str> for Data {
+    fn from(data: &str) -> Data {
+        data.as_bytes().into()
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<bytes::Bytes> for Data {
+    fn from(data: bytes::Bytes) -> Data {
+        Data(Repr::Bytes(data))
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<Data> for bytes::Bytes {
+    fn from(data: Data) -> bytes::Bytes {
+        match data.0 {
+            Repr::Vec(data) => data.into(),
+            Repr::Static(data) => bytes::Bytes::from_static(data),
+            Repr::Bytes(data) => data,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Data;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(&*Data::from(vec![1, 2, 3]), &[1, 2, 3]);
+        assert_eq!(&*Data::from(&[1u8, 2, 3][..]), &[1, 2, 3]);
+        assert_eq!(&*Data::from("hello"), b"hello");
+        assert_eq!(Data::from_static(b"hello"), Data::from(String::from("hello")));
+        assert!(Data::default().is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+//! Direct I/O
+//!
+//! Processes opening a file with O_DIRECT find the flag in the flags passed to open and create
+//! (see `is_direct`). Unless the filesystem opens the file with `OpenFlags::DIRECT_IO`, the
+//! kernel still goes through its page cache for mmap and writes back cached pages of the range
+//! first, but reads and writes of the process are sent as they are. With `DIRECT_IO`, the page
+//! cache isn't used for the file handle at all (and mmap fails unless the filesystem allows it),
+//! which `Mount::direct_io_on_o_direct` sets for every O_DIRECT open.
+//!
+//! Either way, the kernel doesn't align requests of O_DIRECT opens, like it doesn't for
+//! anything else. Filesystems backing onto raw devices, that need reads and writes aligned to
+//! their logical block size (e.g. `ConnectionInfo::blksize`), expand requests with
+//! `AlignedRange` and read into an `AlignedBuffer`.
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr::NonNull;
+use std::slice;
+
+/// Returns true if the given open flags (of open or create) contain O_DIRECT. Always false on
+/// macOS, which has no O_DIRECT.
+pub fn is_direct(flags: u32) -> bool {
+    #[cfg(not(target_os = "macos"))]
+    { flags & libc::O_DIRECT as u32 != 0 }
+    #[cfg(target_os = "macos")]
+    { let _ = flags; false }
+}
+
+/// Range of a request expanded to whole blocks of an alignment
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AlignedRange {
+    /// Offset of the first block
+    pub offset: u64,
+    /// Length of the blocks, a multiple of the alignment
+    pub len: usize,
+    /// Position of the requested range in the blocks
+    pub start: usize,
+    /// Size of the requested range
+    pub size: usize,
+}
+
+impl AlignedRange {
+    /// Expand the range of the given size at the given offset to whole blocks of the given
+    /// alignment, which must be a power of two
+    pub fn new(offset: u64, size: usize, align: usize) -> AlignedRange {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let aligned = offset & !(align as u64 - 1);
+        let start = (offset - aligned) as usize;
+        let len = (start + size).div_ceil(align) * align;
+        AlignedRange { offset: aligned, len, start, size }
+    }
+
+    /// Returns true if the range was aligned already
+    pub fn is_aligned(&self) -> bool {
+        self.start == 0 && self.len == self.size
+    }
+
+    /// Returns the position of the requested range in the blocks, cut off at the given number of
+    /// bytes read into the blocks (on a short read at the end of a device)
+    pub fn requested(&self, read: usize) -> Range<usize> {
+        self.start.min(read)..(self.start + self.size).min(read)
+    }
+}
+
+/// Zeroed buffer whose start is aligned to a power of two, for reading and writing with
+/// O_DIRECT
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    align: usize,
+}
+
+// The buffer is owned like a Vec<u8>
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a buffer of the given length, aligned to the given power of two
+    pub fn new(len: usize, align: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(len, align).expect("invalid buffer alignment");
+        let ptr = if len == 0 {
+            // Dangling, but aligned like allocated buffers
+            NonNull::new(align as *mut u8).unwrap()
+        } else {
+            NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+        AlignedBuffer { ptr, len, align }
+    }
+
+    /// Allocate a buffer for the blocks of the given range
+    pub fn for_range(range: &AlignedRange, align: usize) -> AlignedBuffer {
+        AlignedBuffer::new(range.len, align)
+    }
+
+    /// Returns the alignment of the buffer
+    pub fn align(&self) -> usize {
+        self.align
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), Layout::from_size_align_unchecked(self.len, self.align)) };
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AlignedBuffer({} bytes, aligned to {})", self.len, self.align)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{is_direct, AlignedBuffer, AlignedRange};
+
+    #[test]
+    fn range() {
+        let range = AlignedRange::new(1000, 100, 512);
+        assert_eq!(range, AlignedRange { offset: 512, len: 1024, start: 488, size: 100 });
+        assert!(!range.is_aligned());
+        assert_eq!(range.requested(1024), 488..588);
+        assert_eq!(range.requested(500), 488..500);
+        assert_eq!(range.requested(0), 0..0);
+        assert!(AlignedRange::new(4096, 4096, 4096).is_aligned());
+        assert_eq!(AlignedRange::new(4096, 0, 512).len, 0);
+    }
+
+    #[test]
+    fn buffer() {
+        let mut buffer = AlignedBuffer::for_range(&AlignedRange::new(1000, 100, 512), 4096);
+        assert_eq!((buffer.len(), buffer.align()), (1024, 4096));
+        assert_eq!(buffer.as_ptr() as usize % 4096, 0);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+        buffer[1023] = 1;
+        assert_eq!(buffer[1023], 1);
+        let empty = AlignedBuffer::new(0, 512);
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_ptr() as usize % 512, 0);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn direct() {
+        assert!(is_direct((libc::O_RDONLY | libc::O_DIRECT) as u32));
+        assert!(!is_direct(libc::O_RDWR as u32));
+    }
+}
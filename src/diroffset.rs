@@ -0,0 +1,116 @@
+//! Directory offsets
+//!
+//! The kernel passes the offset of the last directory entry it received to the next readdir,
+//! including offsets that were handed out before the directory changed, offsets that a program
+//! kept from an earlier telldir, and whatever a program passes to seekdir. A filesystem that
+//! takes such an offset as a position in its current listing silently skips or repeats entries.
+//! `DirOffset` encodes the position of an entry together with a tag of a verifier chosen by the
+//! filesystem (e.g. a version of the directory or an id of the listing), so that offsets of
+//! another listing and garbage offsets are detected when they come back.
+
+use std::fmt;
+
+use crate::Errno;
+
+/// Number of bits of an offset that hold the position
+const POSITION_BITS: u32 = 48;
+
+/// Mask of the position bits of an offset
+const POSITION_MASK: u64 = (1 << POSITION_BITS) - 1;
+
+/// Offset of a directory entry, opaque to the kernel. The offset of an entry tells where to
+/// continue reading after it. Offsets never have the sign bit set, so that they can be passed
+/// to seekdir.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct DirOffset(u64);
+
+impl DirOffset {
+    /// Offset of the beginning of a directory, valid for every verifier
+    pub const START: DirOffset = DirOffset(0);
+
+    /// Create the offset to continue reading at the given position (the number of entries
+    /// before it) of the listing with the given verifier. Positions above 2^48 are truncated.
+    pub fn new(position: u64, verifier: u64) -> DirOffset {
+        let position = position & POSITION_MASK;
+        if position == 0 {
+            return DirOffset::START;
+        }
+        DirOffset(tag(position, verifier) << POSITION_BITS | position)
+    }
+
+    /// Returns the position this offset was created for, if it was created with the given
+    /// verifier. Fails with `ESTALE` if it was created for another listing or wasn't created
+    /// by `DirOffset::new` at all.
+    pub fn position(self, verifier: u64) -> Result<u64, Errno> {
+        let position = self.unverified_position();
+        match DirOffset::new(position, verifier) {
+            offset if offset == self => Ok(position),
+            _ => Err(Errno::ESTALE),
+        }
+    }
+
+    /// Returns the position this offset was created for, without validating it
+    pub(crate) fn unverified_position(self) -> u64 {
+        self.0 & POSITION_MASK
+    }
+
+    /// Returns true if this is the offset of the beginning of a directory
+    pub fn is_start(self) -> bool {
+        self == DirOffset::START
+    }
+
+    /// Create an offset from the value the kernel passes. Filesystems that pass offsets of an
+    /// underlying directory through (e.g. from telldir) use this instead of `DirOffset::new`.
+    pub fn from_raw(offset: u64) -> DirOffset {
+        DirOffset(offset)
+    }
+
+    /// Returns the value passed to the kernel
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for DirOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Returns the non-zero 15 bit tag of the given position and verifier (splitmix64 finalizer)
+fn tag(position: u64, verifier: u64) -> u64 {
+    let mut x = verifier ^ position.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x >> 49).max(1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::Errno;
+    use super::DirOffset;
+
+    #[test]
+    fn position() {
+        let offset = DirOffset::new(3, 42);
+        assert_eq!(offset.position(42), Ok(3));
+        assert!(!offset.is_start());
+        assert!((offset.raw() as i64) > 3);
+        assert_eq!(DirOffset::from_raw(offset.raw()), offset);
+        assert_eq!(DirOffset::new(0, 42), DirOffset::START);
+        assert_eq!(DirOffset::START.position(7), Ok(0));
+    }
+
+    #[test]
+    fn stale() {
+        // Offsets of another listing and offsets not created by the crate are rejected
+        assert_eq!(DirOffset::new(3, 42).position(43), Err(Errno::ESTALE));
+        assert_eq!(DirOffset::from_raw(3).position(42), Err(Errno::ESTALE));
+        assert_eq!(DirOffset::from_raw(u64::MAX).position(42), Err(Errno::ESTALE));
+        let valid = (1..1000).filter(|&raw| DirOffset::from_raw(raw << 20).position(0).is_ok()).count();
+        assert_eq!(valid, 0);
+    }
+}
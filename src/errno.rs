@@ -0,0 +1,245 @@
+//! Error codes
+//!
+//! Filesystem operations fail with an error number (errno) that is passed back to the kernel
+//! driver, which in turn returns it to the process that triggered the operation. `Errno` wraps
+//! such an error number and converts from the error types commonly found in filesystem
+//! implementations, so that errors of backing stores can be forwarded using `?`.
+
+use std::{error, fmt, io};
+use libc::c_int;
+
+/// Error number of a failed filesystem operation
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Errno(c_int);
+
+impl Errno {
+    /// Operation not permitted
+    pub const EPERM: Errno = Errno(libc::EPERM);
+    /// No such file or directory
+    pub const ENOENT: Errno = Errno(libc::ENOENT);
+    /// Interrupted system call
+    pub const EINTR: Errno = Errno(libc::EINTR);
+    /// I/O error
+    pub const EIO: Errno = Errno(libc::EIO);
+    /// No such device or address
+    pub const ENXIO: Errno = Errno(libc::ENXIO);
+    /// Argument list too long
+    pub const E2BIG: Errno = Errno(libc::E2BIG);
+    /// Bad file descriptor
+    pub const EBADF: Errno = Errno(libc::EBADF);
+    /// Resource temporarily unavailable
+    pub const EAGAIN: Errno = Errno(libc::EAGAIN);
+    /// Out of memory
+    pub const ENOMEM: Errno = Errno(libc::ENOMEM);
+    /// Permission denied
+    pub const EACCES: Errno = Errno(libc::EACCES);
+    /// Bad address
+    pub const EFAULT: Errno = Errno(libc::EFAULT);
+    /// Device or resource busy
+    pub const EBUSY: Errno = Errno(libc::EBUSY);
+    /// File exists
+    pub const EEXIST: Errno = Errno(libc::EEXIST);
+    /// Cross-device link
+    pub const EXDEV: Errno = Errno(libc::EXDEV);
+    /// No such device
+    pub const ENODEV: Errno = Errno(libc::ENODEV);
+    /// Not a directory
+    pub const ENOTDIR: Errno = Errno(libc::ENOTDIR);
+    /// Is a directory
+    pub const EISDIR: Errno = Errno(libc::EISDIR);
+    /// Invalid argument
+    pub const EINVAL: Errno = Errno(libc::EINVAL);
+    /// Too many open files in system
+    pub const ENFILE: Errno = Errno(libc::ENFILE);
+    /// Too many open files
+    pub const EMFILE: Errno = Errno(libc::EMFILE);
+    /// Inappropriate ioctl for device
+    pub const ENOTTY: Errno = Errno(libc::ENOTTY);
+    /// Text file busy
+    pub const ETXTBSY: Errno = Errno(libc::ETXTBSY);
+    /// File too large
+    pub const EFBIG: Errno = Errno(libc::EFBIG);
+    /// No space left on device
+    pub const ENOSPC: Errno = Errno(libc::ENOSPC);
+    /// Illegal seek
+    pub const ESPIPE: Errno = Errno(libc::ESPIPE);
+    /// Read-only filesystem
+    pub const EROFS: Errno = Errno(libc::EROFS);
+    /// Too many links
+    pub const EMLINK: Errno = Errno(libc::EMLINK);
+    /// Broken pipe
+    pub const EPIPE: Errno = Errno(libc::EPIPE);
+    /// Result too large (e.g. a buffer is too small for an xattr value)
+    pub const ERANGE: Errno = Errno(libc::ERANGE);
+    /// Resource deadlock would occur
+    pub const EDEADLK: Errno = Errno(libc::EDEADLK);
+    /// File name too long
+    pub const ENAMETOOLONG: Errno = Errno(libc::ENAMETOOLONG);
+    /// No locks available
+    pub const ENOLCK: Errno = Errno(libc::ENOLCK);
+    /// Function not implemented
+    pub const ENOSYS: Errno = Errno(libc::ENOSYS);
+    /// Directory not empty
+    pub const ENOTEMPTY: Errno = Errno(libc::ENOTEMPTY);
+    /// Too many levels of symbolic links
+    pub const ELOOP: Errno = Errno(libc::ELOOP);
+    /// No data available
+    #[cfg(not(target_os = "freebsd"))]
+    pub const ENODATA: Errno = Errno(libc::ENODATA);
+    /// No data available (same as ENOATTR on FreeBSD, which has no ENODATA)
+    #[cfg(target_os = "freebsd")]
+    pub const ENODATA: Errno = Errno(libc::ENOATTR);
+    /// Attribute not found (same as ENODATA on Linux)
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub const ENOATTR: Errno = Errno(libc::ENOATTR);
+    /// Attribute not found (same as ENODATA on Linux)
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    pub const ENOATTR: Errno = Errno(libc::ENODATA);
+    /// Protocol error
+    pub const EPROTO: Errno = Errno(libc::EPROTO);
+    /// Value too large for defined data type
+    pub const EOVERFLOW: Errno = Errno(libc::EOVERFLOW);
+    /// Operation not supported
+    pub const ENOTSUP: Errno = Errno(libc::ENOTSUP);
+    /// Operation not supported on socket
+    pub const EOPNOTSUPP: Errno = Errno(libc::EOPNOTSUPP);
+    /// Transport endpoint is not connected
+    pub const ENOTCONN: Errno = Errno(libc::ENOTCONN);
+    /// Connection timed out
+    pub const ETIMEDOUT: Errno = Errno(libc::ETIMEDOUT);
+    /// Stale file handle
+    pub const ESTALE: Errno = Errno(libc::ESTALE);
+    /// Disk quota exceeded
+    pub const EDQUOT: Errno = Errno(libc::EDQUOT);
+    /// Operation canceled
+    pub const ECANCELED: Errno = Errno(libc::ECANCELED);
+
+    /// Create an error from the given raw error number
+    pub const fn from_raw(code: c_int) -> Errno {
+        Errno(code)
+    }
+
+    /// Returns the raw error number
+    pub const fn raw(self) -> c_int {
+        self.0
+    }
+}
+
+impl From<c_int> for Errno {
+    fn from(code: c_int) -> Errno {
+        Errno(code)
+    }
+}
+
+impl From<Errno> for c_int {
+    fn from(errno: Errno) -> c_int {
+        errno.0
+    }
+}
+
+impl From<io::ErrorKind> for Errno {
+    fn from(kind: io::ErrorKind) -> Errno {
+        use io::ErrorKind::*;
+        match kind {
+            NotFound => Errno::ENOENT,
+            PermissionDenied => Errno::EACCES,
+            AlreadyExists => Errno::EEXIST,
+            WouldBlock => Errno::EAGAIN,
+            InvalidInput | InvalidData => Errno::EINVAL,
+            TimedOut => Errno::ETIMEDOUT,
+            Interrupted => Errno::EINTR,
+            Unsupported => Errno::ENOTSUP,
+            OutOfMemory => Errno::ENOMEM,
+            BrokenPipe => Errno::EPIPE,
+            NotConnected => Errno::ENOTCONN,
+            NotADirectory => Errno::ENOTDIR,
+            IsADirectory => Errno::EISDIR,
+            DirectoryNotEmpty => Errno::ENOTEMPTY,
+            ReadOnlyFilesystem => Errno::EROFS,
+            StaleNetworkFileHandle => Errno::ESTALE,
+            StorageFull => Errno::ENOSPC,
+            NotSeekable => Errno::ESPIPE,
+            QuotaExceeded => Errno::EDQUOT,
+            FileTooLarge => Errno::EFBIG,
+            ResourceBusy => Errno::EBUSY,
+            ExecutableFileBusy => Errno::ETXTBSY,
+            Deadlock => Errno::EDEADLK,
+            CrossesDevices => Errno::EXDEV,
+            TooManyLinks => Errno::EMLINK,
+            ArgumentListTooLong => Errno::E2BIG,
+            _ => Errno::EIO,
+        }
+    }
+}
+
+impl From<&io::Error> for Errno {
+    fn from(err: &io::Error) -> Errno {
+        // Errors created by the OS carry the original error number, custom errors may wrap one
+        if let Some(code) = err.raw_os_error() {
+            return Errno(code);
+        }
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<Errno>()) {
+            Some(&errno) => errno,
+            None => err.kind().into(),
+        }
+    }
+}
+
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Errno {
+        Errno::from(&err)
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(errno: Errno) -> io::Error {
+        io::Error::from_raw_os_error(errno.0)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.0))
+    }
+}
+
+impl error::Error for Errno {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw() {
+        assert_eq!(Errno::ENOENT.raw(), libc::ENOENT);
+        assert_eq!(Errno::from(libc::EIO), Errno::EIO);
+        assert_eq!(c_int::from(Errno::EACCES), libc::EACCES);
+    }
+
+    #[test]
+    fn from_io_error() {
+        let err = io::Error::from_raw_os_error(libc::ENOTEMPTY);
+        assert_eq!(Errno::from(err), Errno::ENOTEMPTY);
+        let err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert_eq!(Errno::from(err), Errno::ENOENT);
+        let err = io::Error::other("backend failure");
+        assert_eq!(Errno::from(&err), Errno::EIO);
+        let err = io::Error::other(Errno::ESTALE);
+        assert_eq!(Errno::from(&err), Errno::ESTALE);
+    }
+
+    #[test]
+    fn from_error_kind() {
+        assert_eq!(Errno::from(io::ErrorKind::PermissionDenied), Errno::EACCES);
+        assert_eq!(Errno::from(io::ErrorKind::AlreadyExists), Errno::EEXIST);
+        assert_eq!(Errno::from(io::ErrorKind::UnexpectedEof), Errno::EIO);
+    }
+
+    #[test]
+    fn into_io_error() {
+        let err: io::Error = Errno::EROFS.into();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    }
+}
@@ -0,0 +1,193 @@
+//! Mount errors
+//!
+//! Mounting fails for many reasons that are out of the filesystem's control: a missing kernel
+//! module, a missing fusermount helper, a mount option the system doesn't allow users, a
+//! mountpoint in use. libfuse reports them on stderr only, so the cause is diagnosed from the
+//! system when mounting fails and returned as a `MountError`. It converts into an `io::Error`
+//! wrapping it, for functions that return those.
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+/// Reason mounting a filesystem failed
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MountError {
+    /// The mountpoint doesn't exist or isn't accessible
+    Mountpoint {
+        /// Path of the mountpoint
+        path: PathBuf,
+        /// Error accessing it
+        source: io::Error,
+    },
+    /// The FUSE device (/dev/fuse) is missing, the kernel module may not be loaded
+    NoDevice,
+    /// The setuid fusermount helper that mounts for unprivileged users isn't in the PATH
+    FusermountMissing,
+    /// The `allow_other` or `allow_root` option is used by an unprivileged user, but isn't
+    /// allowed by `user_allow_other` in /etc/fuse.conf
+    AllowOtherNotPermitted,
+    /// The mountpoint is busy, e.g. another filesystem is mounted there already
+    Busy {
+        /// Path of the mountpoint
+        path: PathBuf,
+    },
+    /// The user isn't allowed to mount at the mountpoint
+    PermissionDenied {
+        /// Path of the mountpoint
+        path: PathBuf,
+        /// Error of the mount
+        source: io::Error,
+    },
+    /// The device of a block device mount (`blkdev` option) isn't a block device
+    NotBlockDevice {
+        /// Path of the device
+        path: PathBuf,
+    },
+    /// The kernel driver speaks a FUSE ABI version older than 7.6, which isn't supported
+    UnsupportedAbi {
+        /// Major version of the kernel ABI
+        major: u32,
+        /// Minor version of the kernel ABI
+        minor: u32,
+    },
+    /// Any other error
+    Io(io::Error),
+}
+
+impl MountError {
+    /// Diagnose why mounting the given mountpoint with the given options failed with the
+    /// given error
+    pub(crate) fn diagnose(mountpoint: &Path, options: &[OsString], err: io::Error) -> MountError {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if !Path::new("/dev/fuse").exists() {
+            return MountError::NoDevice;
+        }
+        let root = unsafe { libc::geteuid() } == 0;
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        if !root && !in_path("fusermount") && !in_path("fusermount3") {
+            return MountError::FusermountMissing;
+        }
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        {
+            let options = crate::session::mount_options(options);
+            let allow_other = options.iter().any(|opt| opt == "allow_other" || opt == "allow_root");
+            if !root && allow_other && !std::fs::read_to_string("/etc/fuse.conf").is_ok_and(|conf| user_allow_other(&conf)) {
+                return MountError::AllowOtherNotPermitted;
+            }
+        }
+        #[cfg(not(all(target_os = "linux", not(target_os = "android"))))]
+        let _ = root;
+        if let Some(device) = blkdev(options) {
+            if !std::fs::metadata(&device).is_ok_and(|metadata| metadata.file_type().is_block_device()) {
+                return MountError::NotBlockDevice { path: device };
+            }
+        }
+        match err.raw_os_error() {
+            Some(libc::EBUSY) => MountError::Busy { path: mountpoint.to_path_buf() },
+            Some(libc::EPERM) | Some(libc::EACCES) => MountError::PermissionDenied { path: mountpoint.to_path_buf(), source: err },
+            _ => MountError::Io(err),
+        }
+    }
+}
+
+/// Returns true if an executable of the given name is in the PATH
+#[cfg(all(target_os = "linux", not(target_os = "android")))]
+fn in_path(name: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+}
+
+/// Returns the device of a block device mount with the given options (given as `fsname`)
+fn blkdev(options: &[OsString]) -> Option<PathBuf> {
+    let options = crate::session::mount_options(options);
+    if !options.iter().any(|opt| opt == "blkdev") {
+        return None;
+    }
+    options.iter().rev().find_map(|opt| opt.strip_prefix("fsname=")).map(PathBuf::from)
+}
+
+/// Returns true if the given fuse.conf allows users to use `allow_other`
+#[cfg_attr(not(all(target_os = "linux", not(target_os = "android"))), allow(dead_code))]
+fn user_allow_other(conf: &str) -> bool {
+    conf.lines().any(|line| line.trim() == "user_allow_other")
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::Mountpoint { path, source } => write!(f, "Invalid mountpoint {}: {}", path.display(), source),
+            MountError::NoDevice => write!(f, "/dev/fuse is missing, load the fuse kernel module"),
+            MountError::FusermountMissing => write!(f, "fusermount not found in PATH, install fuse or mount as root"),
+            MountError::AllowOtherNotPermitted => write!(f, "allow_other needs user_allow_other in /etc/fuse.conf"),
+            MountError::Busy { path } => write!(f, "Mountpoint {} is busy", path.display()),
+            MountError::PermissionDenied { path, source } => write!(f, "Not permitted to mount {}: {}", path.display(), source),
+            MountError::NotBlockDevice { path } => write!(f, "{} is not a block device", path.display()),
+            MountError::UnsupportedAbi { major, minor } => write!(f, "Unsupported FUSE ABI version {}.{}", major, minor),
+            MountError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for MountError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MountError::Mountpoint { source, .. } | MountError::PermissionDenied { source, .. } => Some(source),
+            MountError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MountError {
+    fn from(err: io::Error) -> MountError {
+        MountError::Io(err)
+    }
+}
+
+impl From<MountError> for io::Error {
+    fn from(err: MountError) -> io::Error {
+        let kind = match err {
+            MountError::Io(err) => return err,
+            MountError::Mountpoint { ref source, .. } => source.kind(),
+            MountError::NoDevice | MountError::FusermountMissing => io::ErrorKind::NotFound,
+            MountError::AllowOtherNotPermitted | MountError::PermissionDenied { .. } => io::ErrorKind::PermissionDenied,
+            MountError::NotBlockDevice { .. } => io::ErrorKind::InvalidInput,
+            MountError::Busy { .. } | MountError::UnsupportedAbi { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use super::{blkdev, user_allow_other, MountError};
+
+    #[test]
+    fn fuse_conf() {
+        assert!(!user_allow_other("# user_allow_other\nmount_max = 1000\n"));
+        assert!(user_allow_other("mount_max = 1000\n user_allow_other\n"));
+    }
+
+    #[test]
+    fn blkdev_option() {
+        assert_eq!(blkdev(&["-o".into(), "blkdev,fsname=/dev/loop0".into()]), Some(Path::new("/dev/loop0").into()));
+        assert_eq!(blkdev(&["-o".into(), "fsname=/dev/loop0".into()]), None);
+        assert_eq!(blkdev(&["-o".into(), "blkdev".into()]), None);
+    }
+
+    #[test]
+    fn into_io() {
+        let err = io::Error::from(MountError::Busy { path: Path::new("/mnt").into() });
+        assert_eq!(err.to_string(), "Mountpoint /mnt is busy");
+        assert!(matches!(err.get_ref().and_then(|err| err.downcast_ref::<MountError>()), Some(MountError::Busy { .. })));
+        let err = io::Error::from(MountError::Io(io::Error::from_raw_os_error(libc::EIO)));
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
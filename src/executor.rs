@@ -0,0 +1,111 @@
+//! Executors
+//!
+//! The session loop reads requests from the kernel driver with blocking calls, so the only thing
+//! it needs from an async runtime is spawning a task for every request. `Executor` abstracts
+//! this, so that filesystems can run on tokio (the default, with the `tokio` feature), async-std,
+//! smol or without any runtime at all.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Future of a dispatched request
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Executor that runs dispatched requests
+pub trait Executor: Send + Sync + 'static {
+    /// Spawn the given future to run it in the background.
+    fn spawn(&self, future: BoxFuture);
+}
+
+/// Closures can be used as executors, e.g. `|future| { async_std::task::spawn(future); }`
+impl<F: Fn(BoxFuture) + Send + Sync + 'static> Executor for F {
+    fn spawn(&self, future: BoxFuture) {
+        self(future)
+    }
+}
+
+impl fmt::Debug for dyn Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Executor")
+    }
+}
+
+/// Executor that spawns tasks on the current tokio runtime
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExecutor;
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::spawn(future);
+    }
+}
+
+/// Executor that runs every future to completion on a thread of its own. It doesn't need an
+/// async runtime, but filesystems can't use runtime specific features (like timers or
+/// async I/O) with it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadExecutor;
+
+impl Executor for ThreadExecutor {
+    fn spawn(&self, future: BoxFuture) {
+        thread::spawn(move || block_on(future));
+    }
+}
+
+/// Returns the executor used if none is specified (tokio if enabled, threads otherwise)
+pub(crate) fn default_executor() -> Arc<dyn Executor> {
+    #[cfg(feature = "tokio")]
+    return Arc::new(TokioExecutor);
+    #[cfg(not(feature = "tokio"))]
+    return Arc::new(ThreadExecutor);
+}
+
+/// Wakes a thread blocked on a future
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run the given future to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use super::{block_on, Executor, ThreadExecutor};
+
+    #[test]
+    fn thread_executor() {
+        let (tx, rx) = channel();
+        ThreadExecutor.spawn(Box::pin(async move { tx.send(42).unwrap() }));
+        assert_eq!(rx.recv().unwrap(), 42);
+    }
+
+    #[test]
+    fn closure_executor() {
+        let executor = |future| block_on(future);
+        let (tx, rx) = channel();
+        executor.spawn(Box::pin(async move { tx.send(42).unwrap() }));
+        assert_eq!(rx.try_recv().unwrap(), 42);
+    }
+}
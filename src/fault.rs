@@ -0,0 +1,441 @@
+//! Fault injection
+//!
+//! Applications running on top of a filesystem need to cope with failing and slow operations.
+//! `FaultInject` wraps a filesystem and injects errors, delays and short reads into matching
+//! operations with a given probability, to chaos-test how an application handles them.
+
+use std::ffi::OsStr;
+use std::future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use log::debug;
+
+use crate::layer::Wrapper;
+#[cfg(target_os = "macos")]
+use crate::XTimes;
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+
+/// Fault to inject into an operation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Fail the operation with the given error without passing it on
+    Error(Errno),
+    /// Pass the operation on after the given time
+    Delay(Duration),
+    /// Return a random part of the data read (only applies to read, successful reads of
+    /// empty data stay empty)
+    ShortRead,
+}
+
+/// Fault injected into operations of the given name with the given probability
+#[derive(Clone, Copy, Debug)]
+struct Rule {
+    op: &'static str,
+    fault: Fault,
+    probability: f64,
+}
+
+/// Filesystem wrapper injecting faults into operations. Rules are checked in the order they
+/// were added, the first one that matches and fires is applied. Operations without a firing
+/// rule are passed on unchanged.
+#[derive(Debug)]
+pub struct FaultInject<FS> {
+    inner: FS,
+    rules: Vec<Rule>,
+    state: Mutex<u64>,
+}
+
+impl<FS> FaultInject<FS> {
+    /// Inject faults into the operations of the given filesystem. No faults are injected
+    /// until rules are added.
+    pub fn new(inner: FS) -> FaultInject<FS> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        FaultInject { inner, rules: Vec::new(), state: Mutex::new(seed | 1) }
+    }
+
+    /// Inject the given fault into operations of the given name (like `"read"`, or `"*"` for
+    /// all operations) with the given probability between 0 and 1
+    pub fn inject(mut self, op: &'static str, fault: Fault, probability: f64) -> FaultInject<FS> {
+        self.rules.push(Rule { op, fault, probability });
+        self
+    }
+
+    /// Seed the random number generator to inject the same faults in repeated runs
+    pub fn seed(self, seed: u64) -> FaultInject<FS> {
+        *self.state.lock().unwrap() = seed | 1;
+        self
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Returns the next pseudo random number (xorshift64*)
+    fn random(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns the fault to inject into the given operation, if any rule fires
+    fn pick(&self, op: &str) -> Option<Fault> {
+        self.rules.iter()
+            .filter(|rule| rule.op == "*" || rule.op == op)
+            .find(|rule| ((self.random() >> 11) as f64 / (1u64 << 53) as f64) < rule.probability)
+            .map(|rule| rule.fault)
+    }
+
+    /// Applies the fault picked for the given operation. Returns true if a short read needs
+    /// to be injected.
+    async fn fault(&self, op: &str) -> Result<bool, Errno> {
+        match self.pick(op) {
+            Some(Fault::Error(err)) => {
+                debug!("Injecting {} into {}", err, op);
+                Err(err)
+            }
+            Some(Fault::Delay(duration)) => {
+                debug!("Delaying {} by {:?}", op, duration);
+                sleep(duration).await;
+                Ok(false)
+            }
+            Some(Fault::ShortRead) => Ok(op == "read"),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Waits for the given time without depending on the timer of a specific runtime, by sleeping
+/// on a thread of its own
+async fn sleep(duration: Duration) {
+    let state: Arc<Mutex<(bool, Option<Waker>)>> = Arc::default();
+    let timer = state.clone();
+    thread::spawn(move || {
+        thread::sleep(duration);
+        let mut timer = timer.lock().unwrap();
+        timer.0 = true;
+        if let Some(waker) = timer.1.take() { waker.wake(); }
+    });
+    future::poll_fn(|cx| {
+        let mut state = state.lock().unwrap();
+        if state.0 { return Poll::Ready(()); }
+        state.1 = Some(cx.waker().clone());
+        Poll::Pending
+    }).await
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for FaultInject<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.fault("init").await?;
+        self.inner.init(req).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        let _ = self.fault("destroy").await;
+        self.inner.destroy(req).await
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        self.fault("lookup").await?;
+        self.inner.lookup(req, parent, name).await
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        let _ = self.fault("forget").await;
+        self.inner.forget(req, ino, nlookup).await
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        self.fault("getattr").await?;
+        self.inner.getattr(req, ino).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        self.fault("setattr").await?;
+        self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.fault("readlink").await?;
+        self.inner.readlink(req, ino).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        self.fault("mknod").await?;
+        self.inner.mknod(req, parent, name, mode, rdev).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        self.fault("mkdir").await?;
+        self.inner.mkdir(req, parent, name, mode).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.fault("unlink").await?;
+        self.inner.unlink(req, parent, name).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.fault("rmdir").await?;
+        self.inner.rmdir(req, parent, name).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        self.fault("symlink").await?;
+        self.inner.symlink(req, parent, name, link).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        self.fault("rename").await?;
+        self.inner.rename(req, parent, name, newparent, newname).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        self.fault("link").await?;
+        self.inner.link(req, ino, newparent, newname).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.fault("open").await?;
+        self.inner.open(req, ino, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let short = self.fault("read").await?;
+        let data = self.inner.read(req, ino, fh, offset, size).await?;
+        if short && !data.is_empty() {
+            return Ok(Data::from(data[..self.random() as usize % data.len()].to_vec()));
+        }
+        Ok(data)
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        self.fault("write").await?;
+        self.inner.write(req, ino, fh, offset, data, flags).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.fault("flush").await?;
+        self.inner.flush(req, ino, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.fault("release").await?;
+        self.inner.release(req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.fault("fsync").await?;
+        self.inner.fsync(req, ino, fh, datasync).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.fault("opendir").await?;
+        self.inner.opendir(req, ino, flags).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        self.fault("readdir").await?;
+        self.inner.readdir(req, ino, fh, offset).await
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        self.fault("releasedir").await?;
+        self.inner.releasedir(req, ino, fh, flags).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.fault("fsyncdir").await?;
+        self.inner.fsyncdir(req, ino, fh, datasync).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        self.fault("statfs").await?;
+        self.inner.statfs(req, ino).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        self.fault("setxattr").await?;
+        self.inner.setxattr(req, ino, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        self.fault("getxattr").await?;
+        self.inner.getxattr(req, ino, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.fault("listxattr").await?;
+        self.inner.listxattr(req, ino, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.fault("removexattr").await?;
+        self.inner.removexattr(req, ino, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.fault("access").await?;
+        self.inner.access(req, ino, mask).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.fault("create").await?;
+        self.inner.create(req, parent, name, mode, flags).await
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        self.fault("getlk").await?;
+        self.inner.getlk(req, ino, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        self.fault("setlk").await?;
+        self.inner.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        self.fault("bmap").await?;
+        self.inner.bmap(req, ino, blocksize, idx).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        self.fault("ioctl").await?;
+        self.inner.ioctl(req, ino, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.fault("fallocate").await?;
+        self.inner.fallocate(req, ino, fh, offset, length, mode).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        self.fault("readdirplus").await?;
+        self.inner.readdirplus(req, ino, fh, offset).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        self.fault("rename2").await?;
+        self.inner.rename2(req, parent, name, newparent, newname, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.fault("lseek").await?;
+        self.inner.lseek(req, ino, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.fault("copy_file_range").await?;
+        self.inner.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        self.fault("syncfs").await?;
+        self.inner.syncfs(req, ino).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.fault("tmpfile").await?;
+        self.inner.tmpfile(req, parent, mode, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        self.fault("statx").await?;
+        self.inner.statx(req, ino, fh, flags, mask).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        self.fault("setvolname").await?;
+        self.inner.setvolname(req, name).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        self.fault("exchange").await?;
+        self.inner.exchange(req, parent, name, newparent, newname, options).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        self.fault("getxtimes").await?;
+        self.inner.getxtimes(req, ino).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::time::{Duration, Instant};
+    use async_trait::async_trait;
+    use crate::{block_on, Data, Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::{Fault, FaultInject};
+
+    struct Inner;
+
+    #[async_trait]
+    impl Filesystem for Inner {
+        async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            Ok(Entry::new(FileAttr::builder(2, FileType::RegularFile).build(), 0))
+        }
+
+        async fn read(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32) -> Result<Data, Errno> {
+            Ok(Data::from(&b"hello world"[..]))
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let req = Request::test(0, 0);
+        let fs = FaultInject::new(Inner).inject("lookup", Fault::Error(Errno::EIO), 1.0);
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).unwrap_err(), Errno::EIO);
+        assert_eq!(block_on(Filesystem::read(&fs, &req, 2, 0, 0, 100)).unwrap(), Data::from("hello world"));
+
+        let fs = FaultInject::new(Inner).inject("*", Fault::Error(Errno::EIO), 0.0);
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).is_ok());
+    }
+
+    #[test]
+    fn probability() {
+        let req = Request::test(0, 0);
+        let fs = FaultInject::new(Inner).seed(42).inject("*", Fault::Error(Errno::EIO), 0.25);
+        let failed = (0..1000).filter(|_| block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).is_err()).count();
+        assert!(failed > 150 && failed < 350, "{} of 1000 failed", failed);
+    }
+
+    #[test]
+    fn short_reads_and_delays() {
+        let req = Request::test(0, 0);
+        let fs = FaultInject::new(Inner).inject("read", Fault::ShortRead, 1.0);
+        assert!(block_on(Filesystem::read(&fs, &req, 2, 0, 0, 100)).unwrap().len() < 11);
+
+        let fs = FaultInject::new(Inner).inject("*", Fault::Delay(Duration::from_millis(20)), 1.0);
+        let started = Instant::now();
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,448 @@
+//! Protocol flags
+//!
+//! Flag sets of the FUSE protocol that are part of the public API. They are defined here rather
+//! than re-exported from the ABI crate, so that filesystems don't depend on its layout. The bit
+//! values are those of the kernel protocol and unknown bits are preserved.
+
+use std::fmt;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign};
+use fuse_abi::fuse_opcode;
+
+macro_rules! flags {
+    ($(#[$attr:meta])* $name:ident: $repr:ty { $($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])* $flag:ident = $bit:expr;)* }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+        pub struct $name($repr);
+
+        impl $name {
+            $($(#[doc = $doc])* $(#[cfg($cfg)])* pub const $flag: $name = $name(1 << $bit);)*
+
+            /// No flags
+            pub const fn empty() -> $name {
+                $name(0)
+            }
+
+            /// Flags from their protocol representation
+            pub const fn from_bits(bits: $repr) -> $name {
+                $name(bits)
+            }
+
+            /// Protocol representation of the flags
+            pub const fn bits(self) -> $repr {
+                self.0
+            }
+
+            /// Returns true if no flag is set
+            pub const fn is_empty(self) -> bool {
+                self.0 == 0
+            }
+
+            /// Returns true if all of the given flags are set
+            pub const fn contains(self, other: $name) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            /// Set the given flags
+            pub fn insert(&mut self, other: $name) {
+                self.0 |= other.0;
+            }
+
+            /// Clear the given flags
+            pub fn remove(&mut self, other: $name) {
+                self.0 &= !other.0;
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = $name;
+
+            fn bitor(self, other: $name) -> $name {
+                $name(self.0 | other.0)
+            }
+        }
+
+        impl BitOrAssign for $name {
+            fn bitor_assign(&mut self, other: $name) {
+                self.0 |= other.0;
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = $name;
+
+            fn bitand(self, other: $name) -> $name {
+                $name(self.0 & other.0)
+            }
+        }
+
+        impl BitAndAssign for $name {
+            fn bitand_assign(&mut self, other: $name) {
+                self.0 &= other.0;
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut rest = self.0;
+                write!(f, "{}(", stringify!($name))?;
+                $(
+                    $(#[cfg($cfg)])*
+                    {
+                        if self.contains($name::$flag) {
+                            if rest != self.0 { f.write_str(" | ")?; }
+                            rest &= !$name::$flag.0;
+                            f.write_str(stringify!($flag))?;
+                        }
+                    }
+                )*
+                if rest != 0 {
+                    if rest != self.0 { f.write_str(" | ")?; }
+                    write!(f, "{:#x}", rest)?;
+                }
+                f.write_str(")")
+            }
+        }
+    };    ($(#[$attr:meta])* $name:ident { $($body:tt)* }) => {
+        flags! { $(#[$attr])* $name: u32 { $($body)* } }
+    };
+}
+
+flags! {
+    /// Flags of an open file (FOPEN_*) returned by open, opendir and create
+    OpenFlags {
+        /// Bypass the page cache for this file
+        DIRECT_IO = 0;
+        /// Don't invalidate the data cache on open
+        KEEP_CACHE = 1;
+        /// The file is not seekable
+        NONSEEKABLE = 2;
+        /// Don't send flush requests when the file is closed (ABI 7.35)
+        #[cfg(not(target_os = "macos"))]
+        NOFLUSH = 5;
+        /// Allow concurrent direct writes to the file, which the kernel serializes otherwise
+        /// (ABI 7.36)
+        #[cfg(not(target_os = "macos"))]
+        PARALLEL_DIRECT_WRITES = 6;
+        /// Purge the cached attributes on open
+        #[cfg(target_os = "macos")]
+        PURGE_ATTR = 30;
+        /// Purge the unified buffer cache on open
+        #[cfg(target_os = "macos")]
+        PURGE_UBC = 31;
+    }
+}
+
+/// Open flags with the protocol minor version that introduced them, older kernel drivers
+/// don't know them
+const OPEN_FLAGS_SINCE: &[(OpenFlags, u32)] = &[
+    #[cfg(not(target_os = "macos"))]
+    (OpenFlags::NOFLUSH, 35),
+    #[cfg(not(target_os = "macos"))]
+    (OpenFlags::PARALLEL_DIRECT_WRITES, 36),
+];
+
+impl OpenFlags {
+    /// Returns the flags known by a kernel driver with the given protocol minor version
+    pub(crate) fn supported(self, proto_minor: u32) -> OpenFlags {
+        let mut flags = self;
+        for &(flag, since) in OPEN_FLAGS_SINCE {
+            if proto_minor < since {
+                flags.remove(flag);
+            }
+        }
+        flags
+    }
+}
+
+flags! {
+    /// Capability flags (FUSE_*) negotiated with the kernel driver on initialization
+    InitFlags {
+        /// Asynchronous read requests
+        ASYNC_READ = 0;
+        /// Remote locking for POSIX file locks
+        POSIX_LOCKS = 1;
+        /// Kernel sends file handles for fstat etc.
+        FILE_OPS = 2;
+        /// Filesystem handles the O_TRUNC open flag
+        ATOMIC_O_TRUNC = 3;
+        /// Filesystem handles lookups of "." and ".."
+        EXPORT_SUPPORT = 4;
+        /// Filesystem can handle writes larger than 4 kB
+        BIG_WRITES = 5;
+        /// Don't apply the umask to the file mode on create operations
+        DONT_MASK = 6;
+        /// Kernel supports splice writes on the device
+        #[cfg(not(target_os = "macos"))]
+        SPLICE_WRITE = 7;
+        /// Kernel supports splice moves on the device
+        #[cfg(not(target_os = "macos"))]
+        SPLICE_MOVE = 8;
+        /// Kernel supports splice reads on the device
+        #[cfg(not(target_os = "macos"))]
+        SPLICE_READ = 9;
+        /// Remote locking for BSD style file locks
+        FLOCK_LOCKS = 10;
+        /// Kernel supports ioctl on directories
+        HAS_IOCTL_DIR = 11;
+        /// Kernel sends readdirplus instead of readdir requests (ABI 7.21)
+        #[cfg(not(target_os = "macos"))]
+        DO_READDIRPLUS = 13;
+        /// Kernel decides whether to send readdirplus or readdir requests (ABI 7.21)
+        #[cfg(not(target_os = "macos"))]
+        READDIRPLUS_AUTO = 14;
+        /// Kernel treats an ENOSYS reply to open as success and doesn't send opens anymore
+        #[cfg(not(target_os = "macos"))]
+        NO_OPEN_SUPPORT = 17;
+        /// Kernel treats an ENOSYS reply to opendir as success and doesn't send opendirs anymore
+        #[cfg(not(target_os = "macos"))]
+        NO_OPENDIR_SUPPORT = 24;
+        /// Filesystem supports fallocate
+        #[cfg(target_os = "macos")]
+        ALLOCATE = 27;
+        /// Filesystem supports exchanging data of files
+        #[cfg(target_os = "macos")]
+        EXCHANGE_DATA = 28;
+        /// Filesystem is case insensitive
+        #[cfg(target_os = "macos")]
+        CASE_INSENSITIVE = 29;
+        /// Filesystem supports renaming the volume
+        #[cfg(target_os = "macos")]
+        VOL_RENAME = 30;
+        /// Filesystem supports extended times (backup and creation time)
+        #[cfg(target_os = "macos")]
+        XTIMES = 31;
+    }
+}
+
+flags! {
+    /// Flags of ioctl requests (FUSE_IOCTL_*)
+    IoctlFlags {
+        /// 32 bit compat ioctl on a 64 bit machine
+        COMPAT = 0;
+        /// Ioctl of a CUSE device that isn't restricted to commands encoding the size of their
+        /// argument, so that the filesystem can ask the kernel to retry it with memory areas of
+        /// the caller (`Ioctl::Retry`)
+        UNRESTRICTED = 1;
+        /// Ioctl of a 32 bit process
+        CALLER_32BIT = 3;
+        /// Ioctl on a directory (ABI 7.18)
+        DIR = 4;
+    }
+}
+
+flags! {
+    /// Set of filesystem operations, named like the methods of `Filesystem`. Init, destroy,
+    /// forget and interrupts are always dispatched, so they're not part of the set.
+    OperationSet: u128 {
+        /// Look up a directory entry by name
+        LOOKUP = 1;
+        /// Get file attributes
+        GETATTR = 3;
+        /// Set file attributes
+        SETATTR = 4;
+        /// Read a symbolic link
+        READLINK = 5;
+        /// Create a symbolic link
+        SYMLINK = 6;
+        /// Create a file node
+        MKNOD = 8;
+        /// Create a directory
+        MKDIR = 9;
+        /// Remove a file
+        UNLINK = 10;
+        /// Remove a directory
+        RMDIR = 11;
+        /// Rename a file
+        RENAME = 12;
+        /// Create a hard link
+        LINK = 13;
+        /// Open a file
+        OPEN = 14;
+        /// Read data
+        READ = 15;
+        /// Write data
+        WRITE = 16;
+        /// Get filesystem statistics
+        STATFS = 17;
+        /// Release an open file
+        RELEASE = 18;
+        /// Synchronize file contents
+        FSYNC = 20;
+        /// Set an extended attribute
+        SETXATTR = 21;
+        /// Get an extended attribute
+        GETXATTR = 22;
+        /// List extended attribute names
+        LISTXATTR = 23;
+        /// Remove an extended attribute
+        REMOVEXATTR = 24;
+        /// Flush an open file
+        FLUSH = 25;
+        /// Open a directory
+        OPENDIR = 27;
+        /// Read a directory
+        READDIR = 28;
+        /// Release an open directory
+        RELEASEDIR = 29;
+        /// Synchronize directory contents
+        FSYNCDIR = 30;
+        /// Test for a POSIX file lock
+        GETLK = 31;
+        /// Acquire, modify or release a POSIX file lock, with or without waiting
+        SETLK = 32;
+        /// Check file access permissions
+        ACCESS = 34;
+        /// Create and open a file
+        CREATE = 35;
+        /// Map a block index within a file to a block index within the device
+        BMAP = 37;
+        /// Control a device
+        IOCTL = 39;
+        /// Allocate or deallocate space of a file
+        #[cfg(not(target_os = "macos"))]
+        FALLOCATE = 43;
+        /// Read a directory with the attributes of its entries
+        #[cfg(not(target_os = "macos"))]
+        READDIRPLUS = 44;
+        /// Rename a file with flags
+        #[cfg(not(target_os = "macos"))]
+        RENAME2 = 45;
+        /// Find the next data or hole of a file
+        #[cfg(not(target_os = "macos"))]
+        LSEEK = 46;
+        /// Copy a range of data from one file to another
+        #[cfg(not(target_os = "macos"))]
+        COPY_FILE_RANGE = 47;
+        /// Synchronize the filesystem
+        #[cfg(not(target_os = "macos"))]
+        SYNCFS = 50;
+        /// Create and open an unnamed file
+        #[cfg(not(target_os = "macos"))]
+        TMPFILE = 51;
+        /// Get extended file attributes
+        #[cfg(not(target_os = "macos"))]
+        STATX = 52;
+        /// Set the volume name
+        #[cfg(target_os = "macos")]
+        SETVOLNAME = 61;
+        /// Get the extended times of a file
+        #[cfg(target_os = "macos")]
+        GETXTIMES = 62;
+        /// Exchange the data of two files
+        #[cfg(target_os = "macos")]
+        EXCHANGE = 63;
+    }
+}
+
+impl OperationSet {
+    /// All operations (the default of filesystems)
+    pub const fn all() -> OperationSet {
+        OperationSet(!0)
+    }
+
+    /// Returns true if the operation with the given opcode is in the set. Operations that are
+    /// always dispatched are always in the set.
+    pub(crate) fn contains_opcode(self, opcode: fuse_opcode) -> bool {
+        match opcode {
+            fuse_opcode::FUSE_INIT | fuse_opcode::FUSE_DESTROY | fuse_opcode::FUSE_FORGET | fuse_opcode::FUSE_INTERRUPT => true,
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_BATCH_FORGET => true,
+            fuse_opcode::FUSE_SETLKW => self.contains(OperationSet::SETLK),
+            opcode => (opcode as u32) < 128 && self.0 & (1 << opcode as u32) != 0,
+        }
+    }
+
+    /// Returns the given capability flags to enable, adjusted to the operations in the set.
+    /// Without locking operations, the kernel handles locks locally. Without open operations,
+    /// it doesn't send them anymore after the first ENOSYS reply. Without readdirplus, it
+    /// sends readdir requests.
+    pub(crate) fn init_flags(self, mut flags: InitFlags) -> InitFlags {
+        if !self.contains(OperationSet::GETLK | OperationSet::SETLK) {
+            flags.remove(InitFlags::POSIX_LOCKS | InitFlags::FLOCK_LOCKS);
+        }
+        #[cfg(not(target_os = "macos"))]
+        if !self.contains(OperationSet::OPEN) {
+            flags.insert(InitFlags::NO_OPEN_SUPPORT);
+        }
+        #[cfg(not(target_os = "macos"))]
+        if !self.contains(OperationSet::OPENDIR) {
+            flags.insert(InitFlags::NO_OPENDIR_SUPPORT);
+        }
+        #[cfg(not(target_os = "macos"))]
+        if !self.contains(OperationSet::READDIRPLUS) {
+            flags.remove(InitFlags::DO_READDIRPLUS | InitFlags::READDIRPLUS_AUTO);
+        }
+        flags
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use fuse_abi::consts::*;
+    use fuse_abi::fuse_opcode;
+    use super::{InitFlags, OpenFlags, OperationSet};
+
+    #[test]
+    fn protocol_bits() {
+        assert_eq!(OpenFlags::DIRECT_IO.bits(), FOPEN_DIRECT_IO);
+        assert_eq!(OpenFlags::KEEP_CACHE.bits(), FOPEN_KEEP_CACHE);
+        assert_eq!(InitFlags::ASYNC_READ.bits(), FUSE_ASYNC_READ);
+        assert_eq!(InitFlags::POSIX_LOCKS.bits(), FUSE_POSIX_LOCKS);
+    }
+
+    #[test]
+    fn operations() {
+        let mut flags = OpenFlags::DIRECT_IO | OpenFlags::KEEP_CACHE;
+        assert!(flags.contains(OpenFlags::DIRECT_IO));
+        assert!(!flags.contains(OpenFlags::NONSEEKABLE));
+        flags.remove(OpenFlags::DIRECT_IO);
+        assert_eq!(flags, OpenFlags::KEEP_CACHE);
+        assert!((flags & OpenFlags::DIRECT_IO).is_empty());
+        assert_eq!(InitFlags::from_bits(0x1_0001).bits(), 0x1_0001);
+    }
+
+    #[test]
+    fn operation_set() {
+        let ops = OperationSet::GETATTR | OperationSet::SETLK;
+        assert!(ops.contains_opcode(fuse_opcode::FUSE_GETATTR));
+        assert!(ops.contains_opcode(fuse_opcode::FUSE_SETLKW));
+        assert!(ops.contains_opcode(fuse_opcode::FUSE_FORGET));
+        assert!(!ops.contains_opcode(fuse_opcode::FUSE_READ));
+        assert!(OperationSet::all().contains_opcode(fuse_opcode::FUSE_READ));
+        assert_eq!(format!("{:?}", ops), "OperationSet(GETATTR | SETLK)");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn supported() {
+        let flags = OpenFlags::DIRECT_IO | OpenFlags::NOFLUSH | OpenFlags::PARALLEL_DIRECT_WRITES;
+        assert_eq!(OpenFlags::NOFLUSH.bits(), FOPEN_NOFLUSH);
+        assert_eq!(OpenFlags::PARALLEL_DIRECT_WRITES.bits(), FOPEN_PARALLEL_DIRECT_WRITES);
+        assert_eq!(flags.supported(36), flags);
+        assert_eq!(flags.supported(35), OpenFlags::DIRECT_IO | OpenFlags::NOFLUSH);
+        assert_eq!(flags.supported(8), OpenFlags::DIRECT_IO);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn readdirplus() {
+        assert_eq!(InitFlags::DO_READDIRPLUS.bits(), FUSE_DO_READDIRPLUS);
+        assert_eq!(InitFlags::READDIRPLUS_AUTO.bits(), FUSE_READDIRPLUS_AUTO);
+        assert!(OperationSet::READDIRPLUS.contains_opcode(fuse_opcode::FUSE_READDIRPLUS));
+        assert!(OperationSet::STATX.contains_opcode(fuse_opcode::FUSE_STATX));
+        let flags = InitFlags::ASYNC_READ | InitFlags::DO_READDIRPLUS | InitFlags::READDIRPLUS_AUTO;
+        assert_eq!(OperationSet::all().init_flags(flags), flags);
+        // Without readdirplus, the kernel sends readdir requests only
+        let mut ops = OperationSet::all();
+        ops.remove(OperationSet::READDIRPLUS);
+        assert_eq!(ops.init_flags(flags), InitFlags::ASYNC_READ);
+    }
+
+    #[test]
+    fn debug() {
+        assert_eq!(format!("{:?}", OpenFlags::empty()), "OpenFlags()");
+        assert_eq!(format!("{:?}", OpenFlags::DIRECT_IO | OpenFlags::KEEP_CACHE), "OpenFlags(DIRECT_IO | KEEP_CACHE)");
+        assert_eq!(format!("{:?}", InitFlags::from_bits(0x1_0001)), "InitFlags(ASYNC_READ | 0x10000)");
+    }
+}
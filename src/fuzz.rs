@@ -0,0 +1,19 @@
+//! Fuzzing entry points
+//!
+//! The request parser handles data sent by the kernel driver, or by a virtio peer or proxy that
+//! can't necessarily be trusted. These functions expose it to fuzzers (see the `fuzz` directory
+//! for cargo-fuzz targets). They are only available with the `fuzz` feature and not meant for
+//! any other use.
+
+use std::convert::TryFrom;
+
+use crate::ll;
+
+/// Parse the given data like a request received from the kernel driver. Returns the formatted
+/// request (as it would be logged), or the formatted error if the request is invalid.
+pub fn parse_request(data: &[u8]) -> Result<String, String> {
+    match ll::Request::try_from(data) {
+        Ok(req) => Ok(req.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
@@ -0,0 +1,57 @@
+//! Supplementary groups
+//!
+//! The kernel driver tells the user and group of the calling process in the header of every
+//! request, but not its supplementary groups. Filesystems checking permissions themselves
+//! (e.g. `PermissionCheck`) read them from the `Groups` line in /proc/<pid>/status of the
+//! caller (`Request::supplementary_groups`). The process may have changed its credentials or
+//! exited and its pid may have been reused by the time the file is read, so the groups are only
+//! taken if the filesystem user and group of the process still match the request.
+
+use std::fs;
+
+/// Returns the supplementary groups of the process with the given pid (as seen in /proc), if
+/// its filesystem user and group are the given ones
+pub(crate) fn supplementary_groups(pid: u32, uid: u32, gid: u32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status(&status, uid, gid)
+}
+
+/// Returns the supplementary groups in the given contents of /proc/<pid>/status, if the
+/// filesystem user and group in it are the given ones
+fn parse_status(status: &str, uid: u32, gid: u32) -> Option<Vec<u32>> {
+    let ids = |key: &str| -> Option<Vec<u32>> {
+        let line = status.lines().find_map(|line| line.strip_prefix(key))?;
+        line.split_whitespace().map(|id| id.parse().ok()).collect()
+    };
+    // Real, effective, saved and filesystem ids
+    if ids("Uid:")?.get(3) != Some(&uid) || ids("Gid:")?.get(3) != Some(&gid) {
+        return None;
+    }
+    ids("Groups:")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, supplementary_groups};
+
+    const STATUS: &str = "Name:\tcat\nPid:\t42\nUid:\t1000\t1000\t1000\t1000\nGid:\t100\t100\t100\t100\nGroups:\t10 20 1000 \n";
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_status(STATUS, 1000, 100), Some(vec![10, 20, 1000]));
+        assert_eq!(parse_status(&STATUS.replace("10 20 1000 ", ""), 1000, 100), Some(vec![]));
+        // The process doesn't have the credentials of the request
+        assert_eq!(parse_status(STATUS, 0, 100), None);
+        assert_eq!(parse_status(STATUS, 1000, 0), None);
+        assert_eq!(parse_status("Uid:\t1000\n", 1000, 100), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn current() {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        assert!(supplementary_groups(std::process::id(), uid, gid).is_some());
+        assert_eq!(supplementary_groups(std::process::id(), uid.wrapping_add(1), gid), None);
+    }
+}
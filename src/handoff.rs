@@ -0,0 +1,284 @@
+//! Live upgrades
+//!
+//! To upgrade a filesystem daemon without unmounting the filesystem (and without applications
+//! getting ENOTCONN), the running daemon hands the fd to the kernel driver over to the new
+//! daemon, together with the state of the connection the kernel negotiated at init. The old
+//! daemon sends both over a Unix socket with a `Handoff` of its session, and exits afterwards
+//! without unmounting. The new daemon receives them with `SessionState::receive` and serves the
+//! filesystem with `Session::resume_from_fd`. Requests the old daemon received but didn't reply
+//! to before exiting are never answered, so it should wait for requests in flight first, unless
+//! the new daemon has the kernel send them again with `Notifier::resend` (ABI 7.40). The
+//! replies the old daemon remembers for resent requests (see `Mount::resend_replies`) are
+//! handed over as well.
+
+use std::ffi::OsString;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::channel::Channel;
+use crate::resend::ReplyCache;
+use crate::session::ConnectionInfo;
+use crate::InitFlags;
+
+/// Tag of serialized session states, followed by the format version
+const MAGIC: &[u8; 4] = b"FUSE";
+const VERSION: u32 = 7;
+/// Maximum size of serialized session states
+const MAX_SIZE: usize = 8192;
+/// Number of values following the tag
+const VALUES: usize = 15;
+/// Flags of receiving fds, which shouldn't be inherited by child processes
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// State of a mounted session that another process needs to resume serving it
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SessionState {
+    /// Path of the mounted filesystem
+    pub mountpoint: PathBuf,
+    /// Connection parameters negotiated with the kernel driver
+    pub conn: ConnectionInfo,
+    /// Number of replies the session remembers for answering resent requests, if it does (see
+    /// `Mount::resend_replies`)
+    pub resend_replies: Option<usize>,
+    /// Serialized replies the session remembers, from the oldest to the latest
+    pub replies: Vec<Vec<u8>>,
+}
+
+impl SessionState {
+    /// Serialize the state. The oldest replies are left out if the state would be larger than
+    /// the maximum size of a handed over state otherwise.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let conn = &self.conn;
+        let mountpoint = self.mountpoint.clone().into_os_string().into_vec();
+        let mut size = MAGIC.len() + VALUES * 4 + mountpoint.len();
+        let replies: Vec<&Vec<u8>> = self.replies.iter().rev().take_while(|reply| {
+            size += 4 + reply.len();
+            size <= MAX_SIZE
+        }).collect();
+        let resend_replies = self.resend_replies.map_or(0, |capacity| capacity as u32 + 1);
+        let mut bytes = MAGIC.to_vec();
+        for value in [VERSION, conn.proto_major, conn.proto_minor, conn.capable.bits(), conn.want.bits(), conn.max_readahead, conn.max_write, conn.max_read, conn.default_permissions as u32, conn.passthrough as u32, conn.blksize.unwrap_or(0), conn.capable2, conn.create_supp_group as u32, resend_replies, replies.len() as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for reply in replies.into_iter().rev() {
+            bytes.extend_from_slice(&(reply.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(reply);
+        }
+        bytes.extend_from_slice(&mountpoint);
+        bytes
+    }
+
+    /// Deserialize a state serialized with `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<SessionState> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid session state");
+        if bytes.len() < MAGIC.len() + VALUES * 4 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        let u32_at = |start: usize| bytes.get(start..start + 4).map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+        let value = |i: usize| u32_at(MAGIC.len() + i * 4).unwrap_or(0);
+        if value(0) != VERSION {
+            return Err(invalid());
+        }
+        let conn = ConnectionInfo {
+            proto_major: value(1),
+            proto_minor: value(2),
+            capable: InitFlags::from_bits(value(3)),
+            want: InitFlags::from_bits(value(4)),
+            max_readahead: value(5),
+            max_write: value(6),
+            max_read: value(7),
+            default_permissions: value(8) != 0,
+            passthrough: value(9) != 0,
+            blksize: Some(value(10)).filter(|&blksize| blksize != 0),
+            capable2: value(11),
+            create_supp_group: value(12) != 0,
+        };
+        let resend_replies = value(13).checked_sub(1).map(|capacity| capacity as usize);
+        let mut start = MAGIC.len() + VALUES * 4;
+        let mut replies = Vec::new();
+        for _ in 0..value(14) {
+            let len = u32_at(start).ok_or_else(invalid)? as usize;
+            replies.push(bytes.get(start + 4..start + 4 + len).ok_or_else(invalid)?.to_vec());
+            start += 4 + len;
+        }
+        let mountpoint = PathBuf::from(OsString::from_vec(bytes[start..].to_vec()));
+        Ok(SessionState { mountpoint, conn, resend_replies, replies })
+    }
+
+    /// Send the state and the given fd to the kernel driver over the given socket
+    pub fn send(&self, socket: &UnixStream, fd: BorrowedFd<'_>) -> io::Result<()> {
+        send_fd(socket, fd.as_raw_fd(), &self.to_bytes())
+    }
+
+    /// Receive a state and the fd to the kernel driver sent with `send` from the given socket
+    pub fn receive(socket: &UnixStream) -> io::Result<(SessionState, OwnedFd)> {
+        let (bytes, fd) = receive_fd(socket)?;
+        Ok((SessionState::from_bytes(&bytes)?, fd))
+    }
+}
+
+/// Handle for handing a running session over to another process
+#[derive(Debug)]
+pub struct Handoff {
+    ch: Arc<Channel>,
+    conn: Arc<RwLock<ConnectionInfo>>,
+    replies: Option<Arc<ReplyCache>>,
+}
+
+impl Handoff {
+    pub(crate) fn new(ch: Arc<Channel>, conn: Arc<RwLock<ConnectionInfo>>, replies: Option<Arc<ReplyCache>>) -> Handoff {
+        Handoff { ch, conn, replies }
+    }
+
+    /// Returns the current state of the session
+    pub fn state(&self) -> SessionState {
+        SessionState {
+            mountpoint: self.ch.mountpoint().to_path_buf(),
+            conn: *self.conn.read().unwrap(),
+            resend_replies: self.replies.as_ref().map(|replies| replies.capacity()),
+            replies: self.replies.as_ref().map(|replies| replies.replies()).unwrap_or_default(),
+        }
+    }
+
+    /// Send the state of the session and its fd to the kernel driver over the given socket.
+    /// The session doesn't unmount the filesystem anymore when it ends.
+    pub fn send(&self, socket: &UnixStream) -> io::Result<()> {
+        let fd = self.ch.disown()?;
+        self.state().send(socket, fd.as_fd())
+    }
+}
+
+/// Send the given data and fd over the given socket
+fn send_fd(socket: &UnixStream, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_SIZE {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    let len = (data.len() as u32).to_le_bytes();
+    let iov = [IoSlice::new(&len), IoSlice::new(data)];
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = iov.len() as _;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent as usize != len.len() + data.len() {
+        return Err(io::Error::new(io::ErrorKind::WriteZero, "session state sent partially"));
+    }
+    Ok(())
+}
+
+/// Receive data and a fd sent with `send_fd` from the given socket
+fn receive_fd(socket: &UnixStream) -> io::Result<(Vec<u8>, OwnedFd)> {
+    let mut len = [0; 4];
+    let mut data = vec![0; MAX_SIZE];
+    let mut iov = [IoSliceMut::new(&mut len), IoSliceMut::new(&mut data)];
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = iov.len() as _;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no fd received"));
+        }
+        OwnedFd::from_raw_fd(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    };
+    let len = u32::from_le_bytes(len) as usize;
+    if (received as usize) < 4 || len != received as usize - 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "session state received partially"));
+    }
+    data.truncate(len);
+    Ok((data, fd))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsFd, FromRawFd, OwnedFd};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use async_trait::async_trait;
+    use crate::{Filesystem, InitFlags, Session};
+    use crate::session::ConnectionInfo;
+    use super::SessionState;
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    fn state() -> SessionState {
+        let conn = ConnectionInfo { proto_major: 7, proto_minor: 31, capable: InitFlags::from_bits(0xffff), want: InitFlags::from_bits(0x1234), max_readahead: 131072, max_write: 1 << 20, max_read: 1 << 16, default_permissions: true, passthrough: true, blksize: Some(4096), capable2: 0x20, create_supp_group: true };
+        let replies = vec![vec![0x10, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0], vec![0x10, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 2, 0, 0, 0, 0, 0, 0, 0]];
+        SessionState { mountpoint: PathBuf::from("/mnt/foo"), conn, resend_replies: Some(2), replies }
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(SessionState::from_bytes(&state().to_bytes()).unwrap(), state());
+        assert!(SessionState::from_bytes(b"FUSE").is_err());
+        assert!(SessionState::from_bytes(&state().to_bytes()[1..]).is_err());
+        // Replies that don't fit are left out, starting with the oldest
+        let large = SessionState { replies: vec![vec![1; 5000], vec![2; 5000]], ..state() };
+        assert_eq!(SessionState::from_bytes(&large.to_bytes()).unwrap().replies, [vec![2; 5000]]);
+        let without = SessionState { resend_replies: None, replies: vec![], ..state() };
+        assert_eq!(SessionState::from_bytes(&without.to_bytes()).unwrap(), without);
+    }
+
+    #[test]
+    fn send() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), File::from(OwnedFd::from_raw_fd(fds[1]))) };
+        state().send(&a, reader.as_fd()).unwrap();
+        drop(reader);
+        let (received, fd) = SessionState::receive(&b).unwrap();
+        assert_eq!(received, state());
+        // The received fd refers to the same pipe
+        (&writer).write_all(b"foo").unwrap();
+        let mut buf = [0; 3];
+        File::from(fd).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+    }
+
+    #[test]
+    fn resume() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, _writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let se = Session::resume_from_fd(Empty, reader, &state());
+        // The session is initialized with the connection parameters of the old session
+        assert!(se.initialized.load(Ordering::Relaxed));
+        assert_eq!((se.connection(), se.mountpoint().to_path_buf()), (state().conn, state().mountpoint));
+        assert_eq!(se.handoff().unwrap().state(), state());
+    }
+}
@@ -0,0 +1,111 @@
+//! Health checks
+//!
+//! A daemon whose session loop ended (the connection to the kernel driver failed) or whose
+//! filesystem is stuck on requests looks alive to a process supervisor, while every access to
+//! the mountpoint hangs or fails. `HealthMonitor` checks that the session loop is receiving
+//! requests and that no dispatched request is pending for longer than allowed, so that an
+//! orchestrator can restart wedged daemons. `HealthWatch` waits for changes of the health.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::interrupt::Interrupts;
+use crate::timeout::Sleep;
+
+/// Health of a session
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Health {
+    /// The session loop is receiving requests and no request is pending for too long
+    Healthy,
+    /// The request dispatched longest ago is pending for the given time, longer than allowed
+    Stalled(Duration),
+    /// The session loop isn't running (not started yet, or ended because the filesystem was
+    /// unmounted or the connection to the kernel driver failed)
+    Stopped,
+}
+
+/// Handle for checking the health of a session, also after it was moved into its loop
+#[derive(Clone, Debug)]
+pub struct HealthMonitor {
+    running: Arc<AtomicBool>,
+    interrupts: Arc<Interrupts>,
+    max_age: Duration,
+}
+
+impl HealthMonitor {
+    pub(crate) fn new(running: Arc<AtomicBool>, interrupts: Arc<Interrupts>, max_age: Duration) -> HealthMonitor {
+        HealthMonitor { running, interrupts, max_age }
+    }
+
+    /// Check the health of the session now
+    pub fn check(&self) -> Health {
+        if !self.running.load(Ordering::Acquire) {
+            return Health::Stopped;
+        }
+        match self.interrupts.oldest().map(|received| received.elapsed()) {
+            Some(age) if age > self.max_age => Health::Stalled(age),
+            _ => Health::Healthy,
+        }
+    }
+
+    /// Watch the health of the session, checking it at the given interval
+    pub fn watch(&self, interval: Duration) -> HealthWatch {
+        HealthWatch { monitor: self.clone(), interval, last: None }
+    }
+}
+
+/// Subscription to changes of the health of a session
+#[derive(Debug)]
+pub struct HealthWatch {
+    monitor: HealthMonitor,
+    interval: Duration,
+    last: Option<Health>,
+}
+
+impl HealthWatch {
+    /// Waits until the health changes between healthy, stalled and stopped, and returns it.
+    /// The first call returns the current health.
+    pub async fn changed(&mut self) -> Health {
+        loop {
+            let health = self.monitor.check();
+            let same = match (self.last, health) {
+                (Some(Health::Stalled(_)), Health::Stalled(_)) => true,
+                (last, health) => last == Some(health),
+            };
+            if !same {
+                self.last = Some(health);
+                return health;
+            }
+            Sleep::until(Instant::now() + self.interval).await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+    use crate::block_on;
+    use crate::cancel::CancellationToken;
+    use crate::interrupt::Interrupts;
+    use super::{Health, HealthMonitor};
+
+    #[test]
+    fn check() {
+        let running = Arc::new(AtomicBool::new(false));
+        let interrupts = Arc::new(Interrupts::default());
+        let monitor = HealthMonitor::new(running.clone(), interrupts.clone(), Duration::from_secs(1));
+        let mut watch = monitor.watch(Duration::from_millis(10));
+        assert_eq!(block_on(watch.changed()), Health::Stopped);
+        running.store(true, Ordering::Release);
+        assert_eq!(block_on(watch.changed()), Health::Healthy);
+        let (_interrupt, registered) = interrupts.register(7, Instant::now() - Duration::from_secs(2), &CancellationToken::new());
+        assert!(matches!(monitor.check(), Health::Stalled(age) if age >= Duration::from_secs(2)));
+        assert!(matches!(block_on(watch.changed()), Health::Stalled(_)));
+        drop(registered);
+        assert_eq!(block_on(watch.changed()), Health::Healthy);
+    }
+}
@@ -0,0 +1,217 @@
+//! ID-mapped mounts
+//!
+//! A filesystem may store the user and group ids of another user namespace, e.g. the ids used
+//! inside a container, while being mounted on the host. An `IdMap` maps the ids of the mount
+//! (outside) to the ids of the filesystem (inside) and back, like the uid and gid maps of a user
+//! namespace. The session maps the ids of the calling process (`Request::uid`, `Request::gid`)
+//! and of setattr to inside ids, and the ids in attributes replied by the filesystem to outside
+//! ids. Ids without a mapping are mapped to the overflow id 65534 (nobody).
+//!
+//! The kernel driver tells the ids of callers in the user namespace of the process that mounted
+//! the filesystem. If that's a container's namespace, `IdMap::user_namespace` creates a map from
+//! its uid and gid maps, for a filesystem that sees the ids of the host instead.
+
+use std::fs;
+use std::io;
+
+use crate::{Attr, Created, DirEntryPlus, Entry, Errno, FileAttr};
+
+/// Id that ids without a mapping are mapped to
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// Range of mapped ids
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Range {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+/// Map ids in the given direction using the given ranges. Ids are mapped unchanged if there are
+/// no ranges.
+fn map(ranges: &[Range], id: u32, from: fn(&Range) -> u32, to: fn(&Range) -> u32) -> u32 {
+    if ranges.is_empty() {
+        return id;
+    }
+    ranges.iter()
+        .find(|range| id >= from(range) && id - from(range) < range.count)
+        .map_or(OVERFLOW_ID, |range| to(range) + (id - from(range)))
+}
+
+/// Ids a filesystem mounted in a user namespace sees
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdView {
+    /// Ids of the user namespace of the mount, as the kernel driver tells them
+    Container,
+    /// Ids of the parent user namespace (usually the host), mapped by the namespace's uid and
+    /// gid maps
+    Host,
+}
+
+/// Parse ranges from the given `/proc/<pid>/uid_map` or `gid_map`, whose lines consist of the
+/// first id inside the namespace, the first id outside and the number of ids
+fn parse_map(map: &str) -> io::Result<Vec<Range>> {
+    map.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        let ids: Vec<u32> = line.split_whitespace().map(str::parse).collect::<Result<_, _>>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid id map"))?;
+        match ids[..] {
+            // The namespace's ids are the ids of the mount, the parent's those of the filesystem
+            [inside, outside, count] => Ok(Range { inside: outside, outside: inside, count }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid id map")),
+        }
+    }).collect()
+}
+
+/// Mapping of user and group ids between a mount and its filesystem
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IdMap {
+    uids: Vec<Range>,
+    gids: Vec<Range>,
+}
+
+impl IdMap {
+    /// Create a map that maps all ids unchanged until ranges are added
+    pub fn new() -> IdMap {
+        IdMap::default()
+    }
+
+    /// Create a map for a filesystem mounted in the user namespace of the process with the given
+    /// pid, which sees the ids of the given view. For the host view, the map is read from
+    /// `/proc/<pid>/uid_map` and `gid_map`, ids that aren't mapped to the host are mapped to
+    /// the overflow id.
+    pub fn user_namespace(pid: u32, view: IdView) -> io::Result<IdMap> {
+        match view {
+            IdView::Container => Ok(IdMap::new()),
+            IdView::Host => Ok(IdMap {
+                uids: parse_map(&fs::read_to_string(format!("/proc/{}/uid_map", pid))?)?,
+                gids: parse_map(&fs::read_to_string(format!("/proc/{}/gid_map", pid))?)?,
+            }),
+        }
+    }
+
+    /// Map the given number of uids starting at the given inside uid to the uids starting at the
+    /// given outside uid (like a line of `/proc/<pid>/uid_map`)
+    pub fn uids(mut self, inside: u32, outside: u32, count: u32) -> IdMap {
+        self.uids.push(Range { inside, outside, count });
+        self
+    }
+
+    /// Map the given number of gids starting at the given inside gid to the gids starting at the
+    /// given outside gid (like a line of `/proc/<pid>/gid_map`)
+    pub fn gids(mut self, inside: u32, outside: u32, count: u32) -> IdMap {
+        self.gids.push(Range { inside, outside, count });
+        self
+    }
+
+    /// Returns the inside uid of the given outside uid
+    pub fn uid_inside(&self, uid: u32) -> u32 {
+        map(&self.uids, uid, |range| range.outside, |range| range.inside)
+    }
+
+    /// Returns the outside uid of the given inside uid
+    pub fn uid_outside(&self, uid: u32) -> u32 {
+        map(&self.uids, uid, |range| range.inside, |range| range.outside)
+    }
+
+    /// Returns the inside gid of the given outside gid
+    pub fn gid_inside(&self, gid: u32) -> u32 {
+        map(&self.gids, gid, |range| range.outside, |range| range.inside)
+    }
+
+    /// Returns the outside gid of the given inside gid
+    pub fn gid_outside(&self, gid: u32) -> u32 {
+        map(&self.gids, gid, |range| range.inside, |range| range.outside)
+    }
+
+    /// Map the ids of the attributes in the given result of the filesystem to outside ids
+    pub(crate) fn outside<T: HasAttr>(&self, result: Result<T, Errno>) -> Result<T, Errno> {
+        result.map(|mut reply| {
+            let attr = reply.attr_mut();
+            attr.uid = self.uid_outside(attr.uid);
+            attr.gid = self.gid_outside(attr.gid);
+            reply
+        })
+    }
+}
+
+/// Replies containing file attributes
+pub(crate) trait HasAttr {
+    fn attr_mut(&mut self) -> &mut FileAttr;
+}
+
+impl HasAttr for Attr {
+    fn attr_mut(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+}
+
+impl HasAttr for Entry {
+    fn attr_mut(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+}
+
+impl HasAttr for Created {
+    fn attr_mut(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+}
+
+impl HasAttr for DirEntryPlus {
+    fn attr_mut(&mut self) -> &mut FileAttr {
+        &mut self.entry.attr
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use crate::{block_on, Attr, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::{parse_map, IdMap, IdView, OVERFLOW_ID};
+
+    #[test]
+    fn ranges() {
+        let map = IdMap::new().uids(0, 100000, 65536).uids(65536, 1000, 1).gids(0, 200000, 1000);
+        assert_eq!((map.uid_inside(100000), map.uid_inside(100999), map.uid_inside(1000)), (0, 999, 65536));
+        assert_eq!((map.uid_outside(0), map.uid_outside(65535), map.uid_outside(65536)), (100000, 165535, 1000));
+        assert_eq!((map.uid_inside(0), map.uid_outside(70000)), (OVERFLOW_ID, OVERFLOW_ID));
+        assert_eq!((map.gid_inside(200010), map.gid_outside(10), map.gid_outside(1000)), (10, 200010, OVERFLOW_ID));
+        // Without ranges, ids are mapped unchanged
+        assert_eq!((IdMap::new().uid_inside(1000), IdMap::new().gid_outside(100)), (1000, 100));
+    }
+
+    #[test]
+    fn user_namespace() {
+        let map = IdMap { uids: parse_map("         0     100000      65536\n").unwrap(), gids: parse_map("0 200000 1\n1 300000 10\n").unwrap() };
+        assert_eq!((map.uid_inside(0), map.uid_inside(1000), map.uid_outside(101000)), (100000, 101000, 1000));
+        assert_eq!((map.gid_inside(0), map.gid_inside(5), map.gid_inside(20)), (200000, 300004, OVERFLOW_ID));
+        assert!(parse_map("0 100000\n").is_err());
+        assert!(parse_map("0 x 1\n").is_err());
+        assert_eq!(IdMap::user_namespace(1, IdView::Container).unwrap(), IdMap::new());
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert!(IdMap::user_namespace(std::process::id(), IdView::Host).is_ok());
+    }
+
+    /// Filesystem whose files belong to the calling user
+    struct Owned;
+
+    #[async_trait]
+    impl Filesystem for Owned {
+        async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::Directory).uid(req.uid()).gid(req.gid()).build()))
+        }
+    }
+
+    #[test]
+    fn session() {
+        let ch = MockChannel::new(Owned).id_map(IdMap::new().uids(0, 100000, 65536).gids(0, 100000, 65536)).user(101000, 100100);
+        block_on(ch.init()).unwrap();
+        // The filesystem sees inside ids, the kernel outside ids
+        let attr = block_on(ch.getattr(FUSE_ROOT_ID)).unwrap().attr;
+        assert_eq!((attr.uid, attr.gid), (101000, 100100));
+        let ch = ch.user(1000, 100);
+        let attr = block_on(ch.getattr(FUSE_ROOT_ID)).unwrap().attr;
+        assert_eq!((attr.uid, attr.gid), (100000 + OVERFLOW_ID, 100000 + OVERFLOW_ID));
+    }
+}
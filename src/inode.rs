@@ -0,0 +1,305 @@
+//! Inode table
+//!
+//! Filesystems backed by some other store need to map the store's keys (paths, object ids, ...)
+//! to inode numbers. The kernel counts the lookups of every inode it has been handed out (by
+//! lookup, mknod, mkdir, symlink, link and create) and drops them again using forget. An inode
+//! number must stay valid until all of its lookups have been forgotten, after which it may be
+//! reused with a new generation number.
+//!
+//! The pair of inode number and generation must never refer to two different files over the
+//! lifetime of a filesystem, including restarts, or NFS clients may silently access the wrong
+//! file through a stale handle. Filesystems that can be exported should therefore start each
+//! run with a generation that wasn't used before, e.g. from `time_generation`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use fuse_abi::FUSE_ROOT_ID;
+use log::warn;
+
+use crate::{Attr, Entry, Errno, FileAttr};
+
+/// Inode number of directory entries whose inode number isn't known (because they haven't been
+/// looked up yet)
+pub(crate) const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// Inode known to the kernel
+#[derive(Debug)]
+struct Node<K> {
+    key: K,
+    nlookup: u64,
+    generation: u64,
+    /// The key was removed or taken over by another inode, so the inode no longer refers to it
+    detached: bool,
+}
+
+#[derive(Debug)]
+struct Inner<K> {
+    nodes: HashMap<u64, Node<K>>,
+    inodes: HashMap<K, u64>,
+    /// Forgotten inode numbers with the generation they had
+    free: Vec<(u64, u64)>,
+    next_ino: u64,
+    /// Generation of newly allocated inode numbers
+    generation: u64,
+}
+
+/// Returns a generation number derived from the current time, which is larger than the
+/// generations handed out by any previous run of a filesystem (unless it allocated more than
+/// 2^32 reused inodes per second)
+pub fn time_generation() -> u64 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    secs << 32
+}
+
+/// Concurrency-safe table mapping backend keys to inode numbers.
+///
+/// Every call to `lookup` counts as a lookup of the returned inode, so it must only be called
+/// when the inode is actually handed to the kernel in an entry reply. The root inode is always
+/// present and never forgotten.
+#[derive(Debug)]
+pub struct InodeTable<K> {
+    inner: Mutex<Inner<K>>,
+}
+
+impl<K: Clone + Eq + Hash> InodeTable<K> {
+    /// Create a new table with the given key for the root inode
+    pub fn new(root: K) -> InodeTable<K> {
+        InodeTable::with_generation(root, 0)
+    }
+
+    /// Create a new table with the given key for the root inode, whose inodes start with the
+    /// given generation number. Reused inode numbers get increasing generations.
+    pub fn with_generation(root: K, generation: u64) -> InodeTable<K> {
+        let mut inner = Inner { nodes: HashMap::new(), inodes: HashMap::new(), free: Vec::new(), next_ino: FUSE_ROOT_ID + 1, generation };
+        inner.nodes.insert(FUSE_ROOT_ID, Node { key: root.clone(), nlookup: 1, generation, detached: false });
+        inner.inodes.insert(root, FUSE_ROOT_ID);
+        InodeTable { inner: Mutex::new(inner) }
+    }
+
+    /// Returns the inode number and generation of the given key, allocating a new inode if the
+    /// key isn't known yet, and counts a lookup of it
+    pub fn lookup(&self, key: &K) -> (u64, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let ino = match inner.inodes.get(key) {
+            Some(&ino) => ino,
+            None => {
+                let (ino, generation) = match inner.free.pop() {
+                    Some((ino, generation)) => (ino, generation + 1),
+                    None => {
+                        inner.next_ino += 1;
+                        (inner.next_ino - 1, inner.generation)
+                    }
+                };
+                inner.nodes.insert(ino, Node { key: key.clone(), nlookup: 0, generation, detached: false });
+                inner.inodes.insert(key.clone(), ino);
+                ino
+            }
+        };
+        let node = inner.nodes.get_mut(&ino).unwrap();
+        node.nlookup += 1;
+        (ino, node.generation)
+    }
+
+    /// Drops the given number of lookups of an inode. If no lookups are left, the inode is
+    /// removed and its key is returned.
+    pub fn forget(&self, ino: u64, nlookup: u64) -> Option<K> {
+        if ino == FUSE_ROOT_ID { return None; }
+        let mut inner = self.inner.lock().unwrap();
+        let node = match inner.nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => {
+                warn!("Forget of unknown inode {}", ino);
+                return None;
+            }
+        };
+        if nlookup > node.nlookup {
+            warn!("Forget of {} lookups of inode {} which only has {}", nlookup, ino, node.nlookup);
+        }
+        node.nlookup = node.nlookup.saturating_sub(nlookup);
+        if node.nlookup > 0 { return None; }
+        let node = inner.nodes.remove(&ino).unwrap();
+        if inner.inodes.get(&node.key) == Some(&ino) {
+            inner.inodes.remove(&node.key);
+        }
+        inner.free.push((ino, node.generation));
+        Some(node.key)
+    }
+
+    /// Returns an entry for the given key with the given attributes, with the inode number
+    /// and generation filled in. This counts as a lookup of the inode.
+    pub fn entry(&self, key: &K, attr: Attr) -> Entry {
+        let (ino, generation) = self.lookup(key);
+        Entry { ttl: attr.ttl, attr: FileAttr { ino, ..attr.attr }, generation }
+    }
+
+    /// Returns the key of the given inode if it has the given generation. Fails with ESTALE
+    /// if the inode is unknown or has been reused, e.g. for requests using an NFS handle.
+    pub fn validate(&self, ino: u64, generation: u64) -> Result<K, Errno> {
+        match self.inner.lock().unwrap().nodes.get(&ino) {
+            Some(node) if node.generation == generation && !node.detached => Ok(node.key.clone()),
+            _ => Err(Errno::ESTALE),
+        }
+    }
+
+    /// Returns the key of the given inode, unless it has been removed or replaced (the inode
+    /// may still be open, but the key refers to another file or none)
+    pub fn get(&self, ino: u64) -> Option<K> {
+        self.inner.lock().unwrap().nodes.get(&ino).filter(|node| !node.detached).map(|node| node.key.clone())
+    }
+
+    /// Returns the inode number of the given key without counting a lookup
+    pub fn ino(&self, key: &K) -> Option<u64> {
+        self.inner.lock().unwrap().inodes.get(key).cloned()
+    }
+
+    /// Returns the number of lookups of the given inode
+    pub fn nlookup(&self, ino: u64) -> u64 {
+        self.inner.lock().unwrap().nodes.get(&ino).map_or(0, |node| node.nlookup)
+    }
+
+    /// Returns the generation of the given inode
+    pub fn generation(&self, ino: u64) -> Option<u64> {
+        self.inner.lock().unwrap().nodes.get(&ino).map(|node| node.generation)
+    }
+
+    /// Removes the given key (e.g. after unlink). Its inode stays allocated until the kernel
+    /// forgets it, but no longer has a key (`get` returns None), and further lookups of the
+    /// key allocate a new inode.
+    pub fn remove(&self, key: &K) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let ino = inner.inodes.remove(key)?;
+        inner.detach(ino);
+        Some(ino)
+    }
+
+    /// Changes the key of an inode (e.g. after rename), replacing any inode the new key had
+    pub fn rename(&self, from: &K, to: K) {
+        self.remap(|key| if key == from { Some(to.clone()) } else { None });
+    }
+
+    /// Changes the keys of all inodes for which the given function returns a new key. Inodes
+    /// of keys that are the target of a change are detached like removed ones.
+    pub fn remap<F: FnMut(&K) -> Option<K>>(&self, mut f: F) {
+        let mut inner = self.inner.lock().unwrap();
+        let moved: Vec<(K, K)> = inner.inodes.keys().filter_map(|key| f(key).map(|new| (key.clone(), new))).collect();
+        let moved: Vec<(u64, K)> = moved.into_iter().map(|(key, new)| (inner.inodes.remove(&key).unwrap(), new)).collect();
+        for (ino, key) in moved {
+            if let Some(node) = inner.nodes.get_mut(&ino) {
+                node.key = key.clone();
+            }
+            if let Some(replaced) = inner.inodes.insert(key, ino) {
+                inner.detach(replaced);
+            }
+        }
+    }
+}
+
+impl<K> Inner<K> {
+    /// Marks the given inode as no longer referring to its key
+    fn detach(&mut self, ino: u64) {
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.detached = true;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+    use fuse_abi::FUSE_ROOT_ID;
+    use crate::{Attr, Errno, FileAttr, FileType};
+    use super::{InodeTable, time_generation};
+
+    #[test]
+    fn lookup_and_forget() {
+        let table = InodeTable::new("/");
+        assert_eq!(table.get(FUSE_ROOT_ID), Some("/"));
+        let (ino, generation) = table.lookup(&"foo");
+        assert_eq!(generation, 0);
+        assert_eq!(table.lookup(&"foo"), (ino, 0));
+        assert_eq!(table.nlookup(ino), 2);
+        assert_eq!(table.forget(ino, 1), None);
+        assert_eq!(table.get(ino), Some("foo"));
+        assert_eq!(table.forget(ino, 1), Some("foo"));
+        assert_eq!(table.get(ino), None);
+        assert_eq!(table.ino(&"foo"), None);
+    }
+
+    #[test]
+    fn forget_root() {
+        let table = InodeTable::new("/");
+        assert_eq!(table.forget(FUSE_ROOT_ID, 1), None);
+        assert_eq!(table.get(FUSE_ROOT_ID), Some("/"));
+    }
+
+    #[test]
+    fn reuse_with_new_generation() {
+        let table = InodeTable::new("/");
+        let (ino, _) = table.lookup(&"foo");
+        table.forget(ino, 1);
+        assert_eq!(table.lookup(&"bar"), (ino, 1));
+        assert_eq!(table.generation(ino), Some(1));
+    }
+
+    #[test]
+    fn remove_keeps_inode() {
+        let table = InodeTable::new("/");
+        let (ino, _) = table.lookup(&"foo");
+        assert_eq!(table.remove(&"foo"), Some(ino));
+        assert_eq!(table.get(ino), None);
+        assert_eq!(table.nlookup(ino), 1);
+        assert_ne!(table.lookup(&"foo").0, ino);
+        assert_eq!(table.forget(ino, 1), Some("foo"));
+        assert!(table.ino(&"foo").is_some());
+    }
+
+    #[test]
+    fn rename() {
+        let table = InodeTable::new("/");
+        let (foo, _) = table.lookup(&"foo");
+        let (bar, _) = table.lookup(&"bar");
+        table.rename(&"foo", "bar");
+        assert_eq!(table.get(foo), Some("bar"));
+        assert_eq!(table.ino(&"bar"), Some(foo));
+        assert_eq!(table.ino(&"foo"), None);
+        // The replaced inode no longer refers to the key
+        assert_eq!(table.get(bar), None);
+        assert_eq!(table.validate(bar, 0), Err(Errno::ESTALE));
+        assert_eq!(table.forget(bar, 1), Some("bar"));
+        assert_eq!(table.ino(&"bar"), Some(foo));
+    }
+
+    #[test]
+    fn generation_base() {
+        let table = InodeTable::with_generation("/", 7);
+        let (ino, generation) = table.lookup(&"foo");
+        assert_eq!(generation, 7);
+        table.forget(ino, 1);
+        assert_eq!(table.lookup(&"bar"), (ino, 8));
+        assert!(time_generation() > 0);
+    }
+
+    #[test]
+    fn validate() {
+        let table = InodeTable::new("/");
+        let (ino, generation) = table.lookup(&"foo");
+        assert_eq!(table.validate(ino, generation), Ok("foo"));
+        table.forget(ino, 1);
+        assert_eq!(table.validate(ino, generation), Err(Errno::ESTALE));
+        table.lookup(&"bar");
+        assert_eq!(table.validate(ino, generation), Err(Errno::ESTALE));
+    }
+
+    #[test]
+    fn entry() {
+        let table = InodeTable::with_generation("/", 3);
+        let attr = FileAttr::builder(0, FileType::RegularFile).time(UNIX_EPOCH).build();
+        let entry = table.entry(&"foo", Attr::new(attr));
+        assert_eq!(entry.attr.ino, table.ino(&"foo").unwrap());
+        assert_eq!(entry.generation, 3);
+        assert_eq!(table.nlookup(entry.attr.ino), 1);
+    }
+}
@@ -0,0 +1,163 @@
+//! Request interruption
+//!
+//! If the process that triggered a request gives up on it (e.g. because it received a signal),
+//! the kernel driver sends an interrupt request for it. The session keeps track of the requests
+//! being dispatched, so that filesystems can wait for their interruption (`Request::interrupted`)
+//! and bail out of long running operations early. Interrupting a request also cancels its
+//! cancellation token.
+
+use std::collections::HashMap;
+use std::future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::Instant;
+
+use crate::cancel::CancellationToken;
+
+/// Interruption state of a request
+#[derive(Debug, Default)]
+pub(crate) struct Interrupt {
+    interrupted: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    token: CancellationToken,
+}
+
+impl Interrupt {
+    /// Cancellation token of the request, also cancelled when the request is interrupted
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Returns true if the request was interrupted
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Acquire)
+    }
+
+    /// Waits until the request is interrupted
+    pub(crate) async fn wait(&self) {
+        future::poll_fn(|cx| {
+            if self.is_interrupted() { return Poll::Ready(()); }
+            let mut wakers = self.wakers.lock().unwrap();
+            // Check again, the request may have been interrupted before the lock was taken
+            if self.is_interrupted() { return Poll::Ready(()); }
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }).await
+    }
+
+    fn interrupt(&self) {
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            self.interrupted.store(true, Ordering::Release);
+            std::mem::take(&mut *wakers)
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        self.token.cancel();
+    }
+}
+
+/// Requests being dispatched by a session and the time they were received, by unique id
+#[derive(Debug, Default)]
+pub(crate) struct Interrupts {
+    requests: Mutex<HashMap<u64, (Arc<Interrupt>, Instant)>>,
+}
+
+impl Interrupts {
+    /// Registers a dispatched request received at the given time, whose cancellation token is a
+    /// child of the given token. It can be interrupted until the returned guard is dropped.
+    pub(crate) fn register(self: &Arc<Self>, unique: u64, received: Instant, token: &CancellationToken) -> (Arc<Interrupt>, Registered) {
+        let interrupt = Arc::new(Interrupt { token: token.child(), ..Interrupt::default() });
+        self.requests.lock().unwrap().insert(unique, (interrupt.clone(), received));
+        (interrupt, Registered { interrupts: self.clone(), unique })
+    }
+
+    /// Interrupts the request with the given unique id. Returns false if there is no such
+    /// request (not dispatched yet or already completed).
+    pub(crate) fn interrupt(&self, unique: u64) -> bool {
+        let interrupt = self.requests.lock().unwrap().get(&unique).map(|(interrupt, _)| interrupt.clone());
+        match interrupt {
+            Some(interrupt) => { interrupt.interrupt(); true }
+            None => false,
+        }
+    }
+
+    /// Returns the time the request dispatched longest ago was received
+    pub(crate) fn oldest(&self) -> Option<Instant> {
+        self.requests.lock().unwrap().values().map(|&(_, received)| received).min()
+    }
+}
+
+/// Registration of a dispatched request, removed when dropped
+#[derive(Debug)]
+pub(crate) struct Registered {
+    interrupts: Arc<Interrupts>,
+    unique: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.interrupts.requests.lock().unwrap().remove(&self.unique);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+    use async_trait::async_trait;
+    use fuse_abi::{fuse_in_header, fuse_opcode};
+    use crate::{block_on, Entry, Errno, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use crate::cancel::CancellationToken;
+    use super::Interrupts;
+
+    #[test]
+    fn registry() {
+        let interrupts = Arc::new(Interrupts::default());
+        let (interrupt, registered) = interrupts.register(7, Instant::now(), &CancellationToken::new());
+        assert!(!interrupts.interrupt(8));
+        let waiting = {
+            let interrupt = interrupt.clone();
+            thread::spawn(move || block_on(interrupt.wait()))
+        };
+        assert!(interrupts.interrupt(7));
+        waiting.join().unwrap();
+        assert!(interrupt.is_interrupted() && interrupt.token().is_cancelled());
+        drop(registered);
+        assert!(!interrupts.interrupt(7));
+    }
+
+    struct Hanging;
+
+    #[async_trait]
+    impl Filesystem for Hanging {
+        async fn lookup(&self, req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            req.interrupted().await;
+            Err(Errno::EINTR)
+        }
+    }
+
+    #[test]
+    fn interrupted_request() {
+        let ch = MockChannel::new(Hanging);
+        block_on(ch.init()).unwrap();
+        // Interrupt the lookup (unique 2, after init) with a request of an unrelated unique id
+        let header = fuse_in_header { len: 48, opcode: fuse_opcode::FUSE_INTERRUPT as u32, unique: 100, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let header = unsafe { std::slice::from_raw_parts(&header as *const fuse_in_header as *const u8, 40) };
+        let request = [header, &2u64.to_ne_bytes()].concat();
+        thread::scope(|s| {
+            let lookup = s.spawn(|| block_on(ch.lookup(FUSE_ROOT_ID, "foo")));
+            // The kernel resends interrupts answered with EAGAIN, until the request is known
+            while let Some(reply) = block_on(ch.send(&request)) {
+                assert_eq!(reply.result().unwrap_err(), Errno::EAGAIN);
+                thread::yield_now();
+            }
+            assert_eq!(lookup.join().unwrap().unwrap_err(), Errno::EINTR);
+        });
+    }
+}
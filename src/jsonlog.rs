@@ -0,0 +1,124 @@
+//! Structured request log
+//!
+//! A JSON log writes one line per completed operation, with the operation, the inode, the
+//! calling user and process, the size of the reply, the latency and the error number. Unlike
+//! the debug output of requests, every line can be parsed and ingested by log processing tools
+//! (e.g. ELK or ClickHouse). Operations that aren't replied to (forget, interrupt) aren't logged.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fuse_abi::fuse_opcode;
+use log::error;
+
+use crate::metrics::operation_name;
+
+/// Completed operation as logged
+#[derive(Debug)]
+pub(crate) struct LogEntry {
+    pub(crate) unique: u64,
+    pub(crate) opcode: fuse_opcode,
+    pub(crate) ino: u64,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) pid: u32,
+    pub(crate) size: usize,
+    pub(crate) latency: Duration,
+    pub(crate) errno: i32,
+}
+
+/// Log writing a JSON line per completed operation
+pub struct JsonLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLog {
+    /// Create a log writing to the given writer. Every line is written at once, so the writer
+    /// should buffer if it is slow.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> JsonLog {
+        JsonLog { writer: Mutex::new(Box::new(writer)) }
+    }
+
+    /// Create a log appending to the file at the given path
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<JsonLog> {
+        Ok(JsonLog::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    /// Write a line for the given operation
+    pub(crate) fn log(&self, entry: &LogEntry) {
+        let line = format_line(SystemTime::now(), entry);
+        if let Err(err) = self.writer.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write JSON log: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for JsonLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "JsonLog")
+    }
+}
+
+/// Format a log line. All values are numbers or plain lower case names, so nothing needs to
+/// be escaped.
+fn format_line(time: SystemTime, entry: &LogEntry) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{{\"time\":{}.{:06},\"unique\":{},\"op\":\"{}\",\"ino\":{},\"uid\":{},\"gid\":{},\"pid\":{},\"size\":{},\"latency_us\":{},\"errno\":{}}}\n",
+        time.as_secs(), time.subsec_micros(), entry.unique, operation_name(entry.opcode), entry.ino,
+        entry.uid, entry.gid, entry.pid, entry.size, entry.latency.as_micros(), entry.errno)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Errno, Filesystem, MockChannel, FUSE_ROOT_ID};
+    use super::{format_line, JsonLog, LogEntry};
+
+    #[test]
+    fn line() {
+        let entry = LogEntry { unique: 7, opcode: fuse_opcode::FUSE_READ, ino: 42, uid: 1000, gid: 100, pid: 4711, size: 4096, latency: Duration::from_micros(1500), errno: 0 };
+        assert_eq!(format_line(UNIX_EPOCH + Duration::from_millis(1_500), &entry),
+            "{\"time\":1.500000,\"unique\":7,\"op\":\"read\",\"ino\":42,\"uid\":1000,\"gid\":100,\"pid\":4711,\"size\":4096,\"latency_us\":1500,\"errno\":0}\n");
+    }
+
+    /// Writer collecting everything written to it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    #[test]
+    fn session() {
+        let output = Shared::default();
+        let ch = MockChannel::new(Empty).json_log(JsonLog::new(output.clone()));
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap_err(), Errno::ENOSYS);
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"op\":\"init\"") && lines[0].contains("\"errno\":0"));
+        assert!(lines[1].contains("\"op\":\"getattr\",\"ino\":1,") && lines[1].contains("\"size\":0,"));
+        assert!(lines[1].ends_with(&format!("\"errno\":{}}}", libc::ENOSYS)));
+    }
+}
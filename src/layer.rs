@@ -0,0 +1,633 @@
+//! Filesystem layers
+//!
+//! Crosscutting concerns like logging, metrics, caching or access control can be implemented as
+//! filesystems wrapping another filesystem. A `Wrapper` only implements the operations it cares
+//! about, all others are passed on to the inner filesystem. A `Layer` creates such wrappers, and
+//! layers can be stacked to compose them: `Stack::new(inner, outer).layer(fs)` wraps `fs` with
+//! the `inner` layer and the result with the `outer` layer.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use async_trait::async_trait;
+
+#[cfg(target_os = "macos")]
+use crate::XTimes;
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, OperationSet, Request, Statfs, Xattr};
+
+/// Creates filesystems wrapping an inner filesystem. Functions and closures returning a
+/// filesystem (like the `new` function of a wrapper) can be used as layers.
+pub trait Layer<FS> {
+    /// The wrapping filesystem
+    type Filesystem: Filesystem;
+
+    /// Wrap the given filesystem
+    fn layer(&self, inner: FS) -> Self::Filesystem;
+}
+
+impl<FS, T: Filesystem, F: Fn(FS) -> T> Layer<FS> for F {
+    type Filesystem = T;
+
+    fn layer(&self, inner: FS) -> T {
+        self(inner)
+    }
+}
+
+/// Layer that doesn't wrap filesystems
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl<FS: Filesystem> Layer<FS> for Identity {
+    type Filesystem = FS;
+
+    fn layer(&self, inner: FS) -> FS {
+        inner
+    }
+}
+
+/// Two stacked layers. Filesystems are wrapped by the inner layer first and then by the outer
+/// layer, so the outer layer sees requests first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    /// Stack the given outer layer on top of the given inner layer
+    pub fn new(inner: Inner, outer: Outer) -> Stack<Inner, Outer> {
+        Stack { inner, outer }
+    }
+
+    /// Stack another layer on top of this stack
+    pub fn push<L>(self, outer: L) -> Stack<Stack<Inner, Outer>, L> {
+        Stack::new(self, outer)
+    }
+}
+
+impl<FS, Inner: Layer<FS>, Outer: Layer<Inner::Filesystem>> Layer<FS> for Stack<Inner, Outer> {
+    type Filesystem = Outer::Filesystem;
+
+    fn layer(&self, inner: FS) -> Outer::Filesystem {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// Filesystem wrapping an inner filesystem.
+///
+/// Every wrapper is a `Filesystem`. Operations that are not implemented by the wrapper are
+/// passed on to the inner filesystem unchanged. To call operations on a wrapper directly, use
+/// `Filesystem::lookup(&wrapper, ...)` (both traits have methods of the same names).
+#[async_trait]
+pub trait Wrapper: Send + Sync {
+    /// The wrapped filesystem
+    type Inner: Filesystem + Send + Sync;
+
+    /// Returns the wrapped filesystem
+    fn inner(&self) -> &Self::Inner;
+
+    /// Returns the operations the filesystem implements. Wrappers implementing operations the
+    /// inner filesystem may not implement need to add them.
+    fn operations(&self) -> OperationSet {
+        self.inner().operations()
+    }
+
+    /// Initialize filesystem.
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.inner().init(req).await
+    }
+
+    /// Clean up filesystem.
+    async fn destroy(&self, req: &Request) {
+        self.inner().destroy(req).await
+    }
+
+    /// Look up a directory entry by name and get its attributes.
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        self.inner().lookup(req, parent, name).await
+    }
+
+    /// Forget about an inode.
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        self.inner().forget(req, ino, nlookup).await
+    }
+
+    /// Get file attributes.
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        self.inner().getattr(req, ino).await
+    }
+
+    /// Set file attributes.
+    #[allow(clippy::too_many_arguments)]
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        self.inner().setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    /// Read symbolic link.
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.inner().readlink(req, ino).await
+    }
+
+    /// Create file node.
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        self.inner().mknod(req, parent, name, mode, rdev).await
+    }
+
+    /// Create a directory.
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        self.inner().mkdir(req, parent, name, mode).await
+    }
+
+    /// Remove a file.
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.inner().unlink(req, parent, name).await
+    }
+
+    /// Remove a directory.
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.inner().rmdir(req, parent, name).await
+    }
+
+    /// Create a symbolic link.
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        self.inner().symlink(req, parent, name, link).await
+    }
+
+    /// Rename a file.
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        self.inner().rename(req, parent, name, newparent, newname).await
+    }
+
+    /// Create a hard link.
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        self.inner().link(req, ino, newparent, newname).await
+    }
+
+    /// Open a file.
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.inner().open(req, ino, flags).await
+    }
+
+    /// Read data.
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        self.inner().read(req, ino, fh, offset, size).await
+    }
+
+    /// Write data.
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        self.inner().write(req, ino, fh, offset, data, flags).await
+    }
+
+    /// Flush method.
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.inner().flush(req, ino, fh, lock_owner).await
+    }
+
+    /// Release an open file.
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.inner().release(req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    /// Synchronize file contents.
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.inner().fsync(req, ino, fh, datasync).await
+    }
+
+    /// Open a directory.
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.inner().opendir(req, ino, flags).await
+    }
+
+    /// Read directory.
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        self.inner().readdir(req, ino, fh, offset).await
+    }
+
+    /// Release an open directory.
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        self.inner().releasedir(req, ino, fh, flags).await
+    }
+
+    /// Synchronize directory contents.
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.inner().fsyncdir(req, ino, fh, datasync).await
+    }
+
+    /// Get file system statistics.
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        self.inner().statfs(req, ino).await
+    }
+
+    /// Set an extended attribute.
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        self.inner().setxattr(req, ino, name, value, flags, position).await
+    }
+
+    /// Get an extended attribute.
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        self.inner().getxattr(req, ino, name, size).await
+    }
+
+    /// List extended attribute names.
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.inner().listxattr(req, ino, size).await
+    }
+
+    /// Remove an extended attribute.
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.inner().removexattr(req, ino, name).await
+    }
+
+    /// Check file access permissions.
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.inner().access(req, ino, mask).await
+    }
+
+    /// Create and open a file.
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.inner().create(req, parent, name, mode, flags).await
+    }
+
+    /// Test for a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        self.inner().getlk(req, ino, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        self.inner().setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    /// Map block index within file to block index within device.
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        self.inner().bmap(req, ino, blocksize, idx).await
+    }
+
+    /// Control a device.
+    #[allow(clippy::too_many_arguments)]
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        self.inner().ioctl(req, ino, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    /// Allocate or deallocate space of a file.
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.inner().fallocate(req, ino, fh, offset, length, mode).await
+    }
+
+    /// Read directory with the attributes of the entries.
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        self.inner().readdirplus(req, ino, fh, offset).await
+    }
+
+    /// Rename a file with flags.
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        self.inner().rename2(req, parent, name, newparent, newname, flags).await
+    }
+
+    /// Find the next data or hole of a file.
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.inner().lseek(req, ino, fh, offset, whence).await
+    }
+
+    /// Copy a range of data from one file to another.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.inner().copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    /// Synchronize the filesystem.
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        self.inner().syncfs(req, ino).await
+    }
+
+    /// Create and open an unnamed file.
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.inner().tmpfile(req, parent, mode, flags).await
+    }
+
+    /// Get file attributes for statx().
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        self.inner().statx(req, ino, fh, flags, mask).await
+    }
+
+    /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        self.inner().setvolname(req, name).await
+    }
+
+    /// macOS only (undocumented)
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        self.inner().exchange(req, parent, name, newparent, newname, options).await
+    }
+
+    /// macOS only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        self.inner().getxtimes(req, ino).await
+    }
+}
+
+/// A shared filesystem is a filesystem, e.g. to serve the same filesystem at several
+/// mountpoints. Its `init` and `destroy` are called by every session.
+impl<FS: Filesystem + Send + Sync> Wrapper for Arc<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        self
+    }
+}
+
+#[async_trait]
+impl<W: Wrapper> Filesystem for W {
+    fn operations(&self) -> OperationSet {
+        Wrapper::operations(self)
+    }
+
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        Wrapper::init(self, req).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        Wrapper::destroy(self, req).await
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        Wrapper::lookup(self, req, parent, name).await
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        Wrapper::forget(self, req, ino, nlookup).await
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        Wrapper::getattr(self, req, ino).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        Wrapper::setattr(self, req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        Wrapper::readlink(self, req, ino).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        Wrapper::mknod(self, req, parent, name, mode, rdev).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        Wrapper::mkdir(self, req, parent, name, mode).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        Wrapper::unlink(self, req, parent, name).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        Wrapper::rmdir(self, req, parent, name).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        Wrapper::symlink(self, req, parent, name, link).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        Wrapper::rename(self, req, parent, name, newparent, newname).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        Wrapper::link(self, req, ino, newparent, newname).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        Wrapper::open(self, req, ino, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        Wrapper::read(self, req, ino, fh, offset, size).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        Wrapper::write(self, req, ino, fh, offset, data, flags).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        Wrapper::flush(self, req, ino, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        Wrapper::release(self, req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        Wrapper::fsync(self, req, ino, fh, datasync).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        Wrapper::opendir(self, req, ino, flags).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        Wrapper::readdir(self, req, ino, fh, offset).await
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        Wrapper::releasedir(self, req, ino, fh, flags).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        Wrapper::fsyncdir(self, req, ino, fh, datasync).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        Wrapper::statfs(self, req, ino).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        Wrapper::setxattr(self, req, ino, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        Wrapper::getxattr(self, req, ino, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        Wrapper::listxattr(self, req, ino, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        Wrapper::removexattr(self, req, ino, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        Wrapper::access(self, req, ino, mask).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        Wrapper::create(self, req, parent, name, mode, flags).await
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        Wrapper::getlk(self, req, ino, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        Wrapper::setlk(self, req, ino, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        Wrapper::bmap(self, req, ino, blocksize, idx).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        Wrapper::ioctl(self, req, ino, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        Wrapper::fallocate(self, req, ino, fh, offset, length, mode).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        Wrapper::readdirplus(self, req, ino, fh, offset).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        Wrapper::rename2(self, req, parent, name, newparent, newname, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        Wrapper::lseek(self, req, ino, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        Wrapper::copy_file_range(self, req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        Wrapper::syncfs(self, req, ino).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        Wrapper::tmpfile(self, req, parent, mode, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        Wrapper::statx(self, req, ino, fh, flags, mask).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        Wrapper::setvolname(self, req, name).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        Wrapper::exchange(self, req, parent, name, newparent, newname, options).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        Wrapper::getxtimes(self, req, ino).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::UNIX_EPOCH;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use crate::{block_on, Attr, Entry, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::{Identity, Layer, Stack, Wrapper};
+
+    struct Inner;
+
+    #[async_trait]
+    impl Filesystem for Inner {
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::RegularFile).time(UNIX_EPOCH).build()))
+        }
+    }
+
+    /// Counts lookups and fails them
+    struct Counting<FS> {
+        inner: FS,
+        lookups: AtomicUsize,
+    }
+
+    impl<FS> Counting<FS> {
+        fn new(inner: FS) -> Counting<FS> {
+            Counting { inner, lookups: AtomicUsize::new(0) }
+        }
+    }
+
+    #[async_trait]
+    impl<FS: Filesystem + Send + Sync> Wrapper for Counting<FS> {
+        type Inner = FS;
+
+        fn inner(&self) -> &FS {
+            &self.inner
+        }
+
+        async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            Err(Errno::ENOENT)
+        }
+    }
+
+    #[test]
+    fn wrapper_delegates() {
+        let req = Request::test(0, 0);
+        let fs = Counting::new(Inner);
+        assert_eq!(block_on(Filesystem::getattr(&fs, &req, 2)).unwrap().attr.ino, 2);
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).unwrap_err(), Errno::ENOENT);
+        assert_eq!(block_on(Filesystem::readlink(&fs, &req, 2)).unwrap_err(), Errno::ENOSYS);
+        assert_eq!(fs.lookups.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn stacked_layers() {
+        let req = Request::test(0, 0);
+        let fs = Stack::new(Counting::new, Counting::new).push(Identity).layer(Inner);
+        assert!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).is_err());
+        assert_eq!(fs.lookups.load(Ordering::Relaxed), 1);
+        assert_eq!(fs.inner.lookups.load(Ordering::Relaxed), 0);
+        assert_eq!(block_on(Filesystem::getattr(&fs, &req, 3)).unwrap().attr.ino, 3);
+    }
+
+    #[test]
+    fn shared() {
+        let fs = Arc::new(Counting::new(Inner));
+        let (first, second) = (MockChannel::new(fs.clone()), MockChannel::new(fs.clone()));
+        block_on(first.init()).unwrap();
+        block_on(second.init()).unwrap();
+        let _ = block_on(first.lookup(FUSE_ROOT_ID, "foo"));
+        let _ = block_on(second.lookup(FUSE_ROOT_ID, "bar"));
+        assert_eq!(fs.lookups.load(Ordering::SeqCst), 2);
+    }
+}
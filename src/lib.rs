@@ -6,32 +6,169 @@
 
 #![warn(missing_docs, missing_debug_implementations, rust_2018_idioms)]
 
+// The FUSE kernel protocol and the filesystem API (file modes, OsStr names, fds) are Unix
+// specific. Windows would need an adapter to WinFsp's callback API instead of the kernel
+// channel, which isn't implemented.
+#[cfg(not(unix))]
+compile_error!("async-fuse supports Unix-like systems only (Linux, macOS, FreeBSD, Android)");
+
 use std::ffi::OsString;
 use std::convert::AsRef;
 use std::io;
 use std::ffi::OsStr;
 use std::path::Path;
-use std::time::SystemTime;
-use libc::{c_int, ENOSYS};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, SystemTime};
 use async_trait::async_trait;
 
-pub use fuse_abi::FUSE_ROOT_ID;
-pub use fuse_abi::consts;
-pub use reply::{Reply, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
+pub use admission::Admission;
+pub use attr::FileAttrBuilder;
+pub use cache::{CacheInvalidator, CachedFs};
+pub use cancel::CancellationToken;
+pub use channel::Transport;
+#[cfg(feature = "tokio")]
+pub use blocking::{BlockingFilesystem, BlockingFs};
+pub use cmdline::CommandLine;
+pub use coalesce::WriteCoalescing;
+pub use daemon::Daemon;
+pub use data::Data;
+pub use direct::{is_direct, AlignedBuffer, AlignedRange};
+pub use diroffset::DirOffset;
+pub use errno::Errno;
+pub use error::MountError;
+pub use executor::{block_on, BoxFuture, Executor, ThreadExecutor};
+#[cfg(feature = "tokio")]
+pub use executor::TokioExecutor;
+pub use fault::{Fault, FaultInject};
+pub use flags::{InitFlags, IoctlFlags, OpenFlags, OperationSet};
+pub use reply::{Reply, ReplyResult, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
 pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
-pub use reply::ReplyXattr;
+pub use reply::{ReplyXattr, ReplyBytes};
+#[cfg(not(target_os = "macos"))]
+pub use reply::{ReplyIoctl, ReplyLseek, ReplyStatx, ReplyDirectoryPlus};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
+pub use handoff::{Handoff, SessionState};
+pub use health::{Health, HealthMonitor, HealthWatch};
+pub use idmap::{IdMap, IdView, OVERFLOW_ID};
+pub use inode::{InodeTable, time_generation};
+pub use layer::{Identity, Layer, Stack, Wrapper};
+pub use lifecycle::Lifecycle;
+pub use jsonlog::JsonLog;
+pub use logged::LoggedFs;
+pub use metrics::{Metrics, OperationMetrics, LATENCY_BUCKETS};
+pub use mock::{MockChannel, MockReply};
+pub use mount::Mount;
+pub use nlookup::LookupTracker;
+pub use notify::Notifier;
+pub use observer::{Observer, ReplySummary, RequestSummary};
+pub use overlay::Overlay;
+#[cfg(feature = "tokio")]
+pub use mount::{MountGroup, MountHandle};
+pub use pager::{read_dir_entries, DirPager};
+pub use passthrough::BackingId;
+#[cfg(target_os = "linux")]
+pub use passthrough::BackingFiles;
+pub use path::{PathFilesystem, PathFs};
+pub use permission::PermissionCheck;
+pub use pidns::PidNamespace;
+pub use raw::{RawFilesystem, RawRequest, RawSession};
+pub use readahead::Readahead;
+pub use readonly::ReadOnly;
+pub use record::{Mismatch, Recorder, Recording};
+pub use redact::Redaction;
+pub use remote::{Connection, Forwarder};
 pub use request::Request;
-pub use session::{Session, BackgroundSession};
-
+pub use router::Router;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use sandbox::Sandbox;
+pub use session::{Session, ConnectionInfo};
+#[cfg(feature = "tokio")]
+pub use session::BackgroundSession;
+pub use stats::SessionStats;
+pub use swap::{SwapHandle, Swappable};
+pub use tasks::TaskHandle;
+pub use timeout::Timeouts;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use watch::{Invalidate, InvalidationWatch};
+pub use xattr::{XattrHandler, XattrNamespace, XattrRouter};
+
+mod admission;
+mod attr;
+#[cfg(feature = "tokio")]
+mod blocking;
+mod cache;
+mod cancel;
 mod channel;
+mod cmdline;
+mod coalesce;
+mod daemon;
+mod data;
+mod direct;
+mod diroffset;
+mod errno;
+mod error;
+mod executor;
+mod fault;
+mod flags;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+mod groups;
+mod handoff;
+mod health;
+mod idmap;
+mod inode;
+mod interrupt;
+mod jsonlog;
+mod layer;
+mod lifecycle;
+#[cfg(feature = "ll")]
+pub mod ll;
+#[cfg(not(feature = "ll"))]
 mod ll;
+mod logged;
+mod metrics;
+mod mock;
+mod observer;
+mod overlay;
+mod mount;
+mod nlookup;
+mod notify;
+mod pager;
+mod passthrough;
+mod path;
+mod permission;
+mod pidns;
+mod raw;
+mod readahead;
+mod readonly;
+mod record;
+mod redact;
+mod remote;
 mod reply;
 mod request;
+mod resend;
+mod router;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sandbox;
+#[cfg(feature = "sequence")]
+pub mod sequence;
 mod session;
+mod stats;
+mod swap;
+mod tasks;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+mod timeout;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod watch;
+mod xattr;
+
+/// Inode number of the root directory
+pub const FUSE_ROOT_ID: u64 = 1;
 
 /// File types
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum FileType {
     /// Named pipe (S_IFIFO)
@@ -51,6 +188,7 @@ pub enum FileType {
 }
 
 /// File attributes
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct FileAttr {
     /// Inode number
@@ -65,7 +203,7 @@ pub struct FileAttr {
     pub mtime: SystemTime,
     /// Time of last change
     pub ctime: SystemTime,
-    /// Time of creation (macOS only)
+    /// Time of creation (macOS, and statx on other systems)
     pub crtime: SystemTime,
     /// Kind of file (directory, file, pipe, etc)
     pub kind: FileType,
@@ -83,17 +221,223 @@ pub struct FileAttr {
     pub flags: u32,
 }
 
+/// Entry reply data (lookup, mknod, mkdir, symlink, link)
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// Time the kernel may cache the name and attributes (the session's defaults if not set)
+    pub ttl: Option<Duration>,
+    /// Attributes of the entry
+    pub attr: FileAttr,
+    /// Generation number of the inode
+    pub generation: u64,
+}
+
+impl Entry {
+    /// Create an entry that is cached for the session's default TTLs
+    pub fn new(attr: FileAttr, generation: u64) -> Entry {
+        Entry { ttl: None, attr, generation }
+    }
+
+    /// Create a negative lookup entry, which tells the kernel that the name doesn't exist and
+    /// lets it cache that for the given time (the session's default if not set). Lookups of
+    /// the name fail with ENOENT until then, without asking the filesystem again.
+    pub fn negative(ttl: Option<Duration>) -> Entry {
+        Entry { ttl, attr: FileAttr::builder(0, FileType::RegularFile).build(), generation: 0 }
+    }
+
+    /// Returns true if this is a negative lookup entry (inode number 0)
+    pub fn is_negative(&self) -> bool {
+        self.attr.ino == 0
+    }
+}
+
+/// Attribute reply data (getattr, setattr)
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Attr {
+    /// Time the kernel may cache the attributes (the session's default if not set)
+    pub ttl: Option<Duration>,
+    /// File attributes
+    pub attr: FileAttr,
+}
+
+impl Attr {
+    /// Create attributes that are cached for the session's default TTL
+    pub fn new(attr: FileAttr) -> Attr {
+        Attr { ttl: None, attr }
+    }
+}
+
+/// Open reply data (open, opendir)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Opened {
+    /// File handle passed to further operations on the opened file
+    pub fh: u64,
+    /// Open flags
+    pub flags: OpenFlags,
+    /// Backing file to read and write instead of sending requests (see `BackingFiles`)
+    pub backing: Option<BackingId>,
+}
+
+/// Create reply data
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Created {
+    /// Time the kernel may cache the name and attributes (the session's defaults if not set)
+    pub ttl: Option<Duration>,
+    /// Attributes of the created file
+    pub attr: FileAttr,
+    /// Generation number of the inode
+    pub generation: u64,
+    /// File handle passed to further operations on the opened file
+    pub fh: u64,
+    /// Open flags
+    pub flags: OpenFlags,
+    /// Backing file to read and write instead of sending requests (see `BackingFiles`)
+    pub backing: Option<BackingId>,
+}
+
+/// Filesystem statistics
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Statfs {
+    /// Total blocks (in units of frsize)
+    pub blocks: u64,
+    /// Free blocks
+    pub bfree: u64,
+    /// Free blocks for unprivileged users
+    pub bavail: u64,
+    /// Total inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+    /// Filesystem block size
+    pub bsize: u32,
+    /// Maximum filename length
+    pub namelen: u32,
+    /// Fundamental filesystem block size
+    pub frsize: u32,
+}
+
+/// POSIX file lock
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Lock {
+    /// Start of the locked range
+    pub start: u64,
+    /// End of the locked range
+    pub end: u64,
+    /// Lock type (F_RDLCK, F_WRLCK or F_UNLCK)
+    pub typ: u32,
+    /// Pid of the process holding the lock
+    pub pid: u32,
+}
+
+/// Extended attribute reply data (getxattr, listxattr)
+///
+/// Filesystems can always return the whole value with `Xattr::Data`. The reply takes care of
+/// the size requested by the kernel: it replies with the size of the value if the request
+/// asked for the size only, and with ERANGE if the value doesn't fit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Xattr {
+    /// Size of the value, if the request asked for the size only
+    Size(u32),
+    /// The value (or list of names)
+    Data(Vec<u8>),
+}
+
+impl Xattr {
+    /// Create listxattr reply data from the given attribute names
+    pub fn names<I: IntoIterator<Item = N>, N: AsRef<OsStr>>(names: I) -> Xattr {
+        let mut data = Vec::new();
+        for name in names {
+            data.extend_from_slice(name.as_ref().as_bytes());
+            data.push(0);
+        }
+        Xattr::Data(data)
+    }
+}
+
+/// Result of an ioctl
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Ioctl {
+    /// The ioctl is done, with the given return value of the ioctl call and the data to copy to
+    /// the caller's argument (at most `out_size` bytes)
+    Done(i32, Vec<u8>),
+    /// Ask the kernel to call the ioctl again, with the given memory areas of the caller copied
+    /// in (input) and out (output). Only unrestricted ioctls can be retried, which lets devices
+    /// find the data of commands whose argument doesn't encode its size, e.g. a struct with a
+    /// pointer to a buffer. The kernel fails other retries with EIO.
+    Retry(Vec<IoctlIovec>, Vec<IoctlIovec>),
+}
+
+/// Memory area of the process calling an ioctl
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IoctlIovec {
+    /// Address in the caller's address space
+    pub base: u64,
+    /// Length of the area
+    pub len: u64,
+}
+
+/// Directory entry returned by readdir
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset of the next entry. The kernel passes this value to a further readdir call
+    /// to continue reading after this entry
+    pub offset: DirOffset,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// Directory entry with its attributes returned by readdirplus
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntryPlus {
+    /// Directory entry
+    pub dirent: DirEntry,
+    /// Attributes of the entry, which the kernel caches like those of a lookup. A negative
+    /// entry (inode number 0) tells the kernel nothing about the entry.
+    pub entry: Entry,
+}
+
+/// Extended times (macOS only)
+#[cfg(target_os = "macos")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct XTimes {
+    /// Time of last backup
+    pub bkuptime: SystemTime,
+    /// Time of creation
+    pub crtime: SystemTime,
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
 /// These methods correspond to fuse_lowlevel_ops in libfuse. Reasonable default
 /// implementations are provided here to get a mountable filesystem that does
-/// nothing.
+/// nothing. Every method returns the result of the operation, which the session
+/// sends back to the kernel, so a request can never be left without a reply.
 #[async_trait]
 pub trait Filesystem {
+    /// Returns the operations the filesystem implements (all by default). The session replies
+    /// ENOSYS to others without dispatching them, and tells the kernel driver that it handles
+    /// locks itself or doesn't need open requests if the filesystem doesn't implement them.
+    fn operations(&self) -> OperationSet {
+        OperationSet::all()
+    }
+
     /// Initialize filesystem.
-    /// Called before any other filesystem method.
-    async fn init(&self, _req: &Request) -> Result<(), c_int> {
+    /// Called before any other filesystem method. The parameters the kernel proposed are in
+    /// `Request::connection` and can be adjusted with `Request::set_want`,
+    /// `Request::set_max_readahead` and `Request::set_max_write`.
+    async fn init(&self, _req: &Request) -> Result<(), Errno> {
         Ok(())
     }
 
@@ -102,8 +446,8 @@ pub trait Filesystem {
     async fn destroy(&self, _req: &Request) {}
 
     /// Look up a directory entry by name and get its attributes.
-    async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
-        reply.error(ENOSYS);
+    async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Forget about an inode.
@@ -116,77 +460,78 @@ pub trait Filesystem {
     async fn forget(&self, _req: &Request, _ino: u64, _nlookup: u64) {}
 
     /// Get file attributes.
-    async fn getattr(&self, _req: &Request, _ino: u64, reply: ReplyAttr) {
-        reply.error(ENOSYS);
+    async fn getattr(&self, _req: &Request, _ino: u64) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Set file attributes.
-    async fn setattr(&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
-        reply.error(ENOSYS);
+    async fn setattr(&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Read symbolic link.
-    async fn readlink(&self, _req: &Request, _ino: u64, reply: ReplyData) {
-        reply.error(ENOSYS);
+    async fn readlink(&self, _req: &Request, _ino: u64) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create file node.
     /// Create a regular file, character device, block device, fifo or socket node.
-    async fn mknod(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
-        reply.error(ENOSYS);
+    async fn mknod(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create a directory.
-    async fn mkdir(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        reply.error(ENOSYS);
+    async fn mkdir(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Remove a file.
-    async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Remove a directory.
-    async fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create a symbolic link.
-    async fn symlink(&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path, reply: ReplyEntry) {
-        reply.error(ENOSYS);
+    async fn symlink(&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Rename a file.
-    async fn rename(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn rename(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create a hard link.
-    async fn link(&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
-        reply.error(ENOSYS);
+    async fn link(&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Open a file.
     /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
-    /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
-    /// etc) in fh, and use this in other all other file operations (read, write, flush,
-    /// release, fsync). Filesystem may also implement stateless file I/O and not store
-    /// anything in fh. There are also some flags (direct_io, keep_cache) which the
-    /// filesystem may set, to change the way the file is opened. See fuse_file_info
-    /// structure in <fuse_common.h> for more details.
-    async fn open(&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+    /// available in flags, including O_DIRECT (see `is_direct`). Filesystem may store an
+    /// arbitrary file handle (pointer, index, etc) in fh, and use this in other all other file
+    /// operations (read, write, flush, release, fsync). Filesystem may also implement stateless
+    /// file I/O and not store anything in fh. There are also some flags (direct_io,
+    /// keep_cache) which the filesystem may set, to change the way the file is opened. See
+    /// fuse_file_info structure in <fuse_common.h> for more details.
+    async fn open(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
     }
 
     /// Read data.
-    /// Read should send exactly the number of bytes requested except on EOF or error,
+    /// Read should return exactly the number of bytes requested except on EOF or error,
     /// otherwise the rest of the data will be substituted with zeroes. An exception to
     /// this is when the file has been opened in 'direct_io' mode, in which case the
     /// return value of the read system call will reflect the return value of this
     /// operation. fh will contain the value set by the open method, or will be undefined
-    /// if the open method didn't set any value.
-    async fn read(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
-        reply.error(ENOSYS);
+    /// if the open method didn't set any value. The size is never larger than the `max_read`
+    /// of the connection, data beyond the size is cut off.
+    async fn read(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32) -> Result<Data, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Write data.
@@ -195,8 +540,8 @@ pub trait Filesystem {
     /// which case the return value of the write system call will reflect the return
     /// value of this operation. fh will contain the value set by the open method, or
     /// will be undefined if the open method didn't set any value.
-    async fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
-        reply.error(ENOSYS);
+    async fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Flush method.
@@ -209,8 +554,8 @@ pub trait Filesystem {
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
-    async fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Release an open file.
@@ -221,15 +566,15 @@ pub trait Filesystem {
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
     /// open.
-    async fn release(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
-        reply.ok();
+    async fn release(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> Result<(), Errno> {
+        Ok(())
     }
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    async fn fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Open a directory.
@@ -239,72 +584,75 @@ pub trait Filesystem {
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
-    async fn opendir(&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+    async fn opendir(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
     }
 
     /// Read directory.
-    /// Send a buffer filled using buffer.fill(), with size not exceeding the
-    /// requested size. Send an empty buffer on end of stream. fh will contain the
-    /// value set by the opendir method, or will be undefined if the opendir method
-    /// didn't set any value.
-    async fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, reply: ReplyDirectory) {
-        reply.error(ENOSYS);
+    /// Return the entries following the given offset. The session sends as many of
+    /// them as fit into the buffer size requested by the kernel, the kernel continues
+    /// with a further readdir call at the offset of the last entry it received. Return
+    /// no entries on end of stream. Offsets are created with `DirOffset::new`, and
+    /// offsets that fail to validate (e.g. after the directory changed) should fail
+    /// with the error of `DirOffset::position`. fh will contain the value set by the
+    /// opendir method, or will be undefined if the opendir method didn't set any value.
+    async fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, _offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
-    async fn releasedir(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
-        reply.ok();
+    async fn releasedir(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32) -> Result<(), Errno> {
+        Ok(())
     }
 
     /// Synchronize directory contents.
     /// If the datasync parameter is set, then only the directory contents should
     /// be flushed, not the meta data. fh will contain the value set by the opendir
     /// method, or will be undefined if the opendir method didn't set any value.
-    async fn fsyncdir (&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Get file system statistics.
-    async fn statfs(&self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+    async fn statfs(&self, _req: &Request, _ino: u64) -> Result<Statfs, Errno> {
+        Ok(Statfs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 })
     }
 
     /// Set an extended attribute.
-    async fn setxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn setxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Get an extended attribute.
-    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
-    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
-    async fn getxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
-        reply.error(ENOSYS);
+    /// If `size` is 0, the kernel asks for the size of the value only. Returning the value
+    /// with `Xattr::Data` is always fine, the reply turns it into the size or into ERANGE if
+    /// it doesn't fit into `size` bytes.
+    async fn getxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// List extended attribute names.
-    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
-    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
-    async fn listxattr(&self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
-        reply.error(ENOSYS);
+    /// If `size` is 0, the kernel asks for the size of the list only. Returning the list
+    /// with `Xattr::names` or `Xattr::Data` is always fine, the reply turns it into the size
+    /// or into ERANGE if it doesn't fit into `size` bytes.
+    async fn listxattr(&self, _req: &Request, _ino: u64, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Remove an extended attribute.
-    async fn removexattr(&self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn removexattr(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Check file access permissions.
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    async fn access(&self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn access(&self, _req: &Request, _ino: u64, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create and open a file.
@@ -317,13 +665,13 @@ pub trait Filesystem {
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
     /// and open() methods will be called instead.
-    async fn create(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
-        reply.error(ENOSYS);
+    async fn create(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Test for a POSIX file lock.
-    async fn getlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
-        reply.error(ENOSYS);
+    async fn getlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Acquire, modify or release a POSIX file lock.
@@ -333,54 +681,131 @@ pub trait Filesystem {
     /// used to fill in this field in getlk(). Note: if the locking methods are not
     /// implemented, the kernel will still allow file locking to work locally.
     /// Hence these are only interesting for network filesystems and similar.
-    async fn setlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn setlk(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    async fn bmap(&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
-        reply.error(ENOSYS);
+    async fn bmap(&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Control a device. `cmd` and `arg` are the command and argument of the caller, `data` is
+    /// the input the kernel copied from the caller (according to the size encoded in `cmd`, or
+    /// as asked by a retry) and `out_size` is the size of the caller's output. Ioctls on
+    /// directories have `IoctlFlags::DIR` set. Not sent by macOS.
+    #[allow(clippy::too_many_arguments)]
+    async fn ioctl(&self, _req: &Request, _ino: u64, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _data: &[u8], _out_size: u32) -> Result<Ioctl, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Allocate or deallocate space of a file, as fallocate() does with the given mode.
+    /// Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _length: i64, _mode: i32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read directory with the attributes of the entries.
+    /// Like readdir, but every entry comes with the attributes of a lookup, so that the kernel
+    /// doesn't need to look up the listed entries. Every entry except `.`, `..` and negative
+    /// entries counts as a lookup of its inode (to be forgotten by a forget call), entries that
+    /// don't fit into the buffer are forgotten by the session right away. Only sent if
+    /// `InitFlags::DO_READDIRPLUS` or `InitFlags::READDIRPLUS_AUTO` is enabled (see
+    /// `Mount::init_flags` and `Request::set_want`). Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, _req: &Request, _ino: u64, _fh: u64, _offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file with the flags of renameat2() (`RENAME_NOREPLACE`, `RENAME_EXCHANGE` or
+    /// `RENAME_WHITEOUT`). Renames without flags are sent to rename. The kernel fails the call
+    /// with EINVAL if this method is not implemented. Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Find the next data or hole of a file (`SEEK_DATA` or `SEEK_HOLE` whence). Returns the
+    /// resulting offset. If this method is not implemented, the kernel treats the whole file
+    /// as data. Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _whence: i32) -> Result<i64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Copy a range of data from one file to another without passing it through the kernel.
+    /// Returns the number of bytes copied. If this method is not implemented, the kernel
+    /// copies the data with reads and writes. Not sent by macOS.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: i64, _ino_out: u64, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u64) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Synchronize the filesystem, as syncfs() does. Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, _req: &Request, _ino: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open an unnamed file in the given directory (O_TMPFILE), which can be
+    /// linked into the directory later. The kernel fails the call with EOPNOTSUPP if this
+    /// method is not implemented. Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, _req: &Request, _parent: u64, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file attributes for statx(). `fh` is set if the call was made on an open file,
+    /// `flags` are the `AT_STATX_*` flags and `mask` the `STATX_*` fields the caller asked
+    /// for. The reply contains the basic fields and the creation time (unless it's
+    /// `UNIX_EPOCH`). If this method is not implemented, the kernel uses getattr instead.
+    /// Not sent by macOS.
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, _req: &Request, _ino: u64, _fh: Option<u64>, _flags: u32, _mask: u32) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
-    async fn setvolname(&self, _req: &Request, _name: &OsStr, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn setvolname(&self, _req: &Request, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// macOS only (undocumented)
     #[cfg(target_os = "macos")]
-    async fn exchange(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+    async fn exchange(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
     }
 
     /// macOS only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
     /// during init to FUSE_XTIMES to enable
     #[cfg(target_os = "macos")]
-    async fn getxtimes(&self, _req: &Request, _ino: u64, reply: ReplyXTimes) {
-        reply.error(ENOSYS);
+    async fn getxtimes(&self, _req: &Request, _ino: u64) -> Result<XTimes, Errno> {
+        Err(Errno::ENOSYS)
     }
 }
 
 /// Mount the given filesystem to the given mountpoint. This function will
 /// not return until the filesystem is unmounted.
 ///
-/// Note that you need to lead each option with a separate `"-o"` string. See
-/// `examples/hello.rs`.
+/// Note that you need to lead each option with a separate `"-o"` string. Use `Mount`
+/// for more settings.
 pub fn mount<FS: Filesystem + Send + Sync + 'static, P: AsRef<Path>>(filesystem: FS, mountpoint: P, options: &[OsString]) -> io::Result<()>{
-    let se = Session::new(filesystem, mountpoint.as_ref(), options)?;
-    se.run()
+    Mount::new(filesystem, mountpoint).options(options).run()
 }
 
 /// Mount the given filesystem to the given mountpoint. This function spawns
 /// a background thread to handle filesystem operations while being mounted
 /// and therefore returns immediately. The returned handle should be stored
 /// to reference the mounted filesystem. If it's dropped, the filesystem will
-/// be unmounted.
-pub unsafe fn spawn_mount<FS: Filesystem + Send + Sync +'static, P: AsRef<Path>>(filesystem: FS, mountpoint: P, options: &[OsString]) -> io::Result<BackgroundSession> {
+/// be unmounted. This must be called from within a Tokio runtime.
+#[cfg(feature = "tokio")]
+pub fn spawn_mount<FS: Filesystem + Send + Sync +'static, P: AsRef<Path>>(filesystem: FS, mountpoint: P, options: &[OsString]) -> io::Result<BackgroundSession> {
     let se = Session::new(filesystem, mountpoint.as_ref(), options)?;
     se.spawn()
 }
\ No newline at end of file
@@ -0,0 +1,85 @@
+//! Mount lifecycle hooks
+//!
+//! Mounting returns once the mount syscall succeeded, but accesses to the mountpoint block
+//! until the kernel initialized the filesystem with the session. Daemons that tell a service
+//! manager they're ready (e.g. systemd's `sd_notify`) or write pid files should do so once the
+//! filesystem is initialized. `Lifecycle` calls hooks then, and once the session ended and the
+//! filesystem is unmounted.
+
+use std::fmt;
+use std::path::Path;
+
+/// Hook called with the mountpoint
+type Hook = Box<dyn Fn(&Path) + Send + Sync>;
+
+/// Hooks called when the filesystem is mounted and unmounted
+#[derive(Default)]
+pub struct Lifecycle {
+    on_mounted: Option<Hook>,
+    on_unmounted: Option<Hook>,
+}
+
+impl Lifecycle {
+    /// Create a lifecycle without hooks
+    pub fn new() -> Lifecycle {
+        Lifecycle::default()
+    }
+
+    /// Call the given function with the mountpoint once the kernel initialized the filesystem,
+    /// so that it's accessible to other processes
+    pub fn on_mounted<F: Fn(&Path) + Send + Sync + 'static>(mut self, f: F) -> Lifecycle {
+        self.on_mounted = Some(Box::new(f));
+        self
+    }
+
+    /// Call the given function with the mountpoint once the session ended and the filesystem
+    /// is unmounted (the channel to the kernel driver is closed)
+    pub fn on_unmounted<F: Fn(&Path) + Send + Sync + 'static>(mut self, f: F) -> Lifecycle {
+        self.on_unmounted = Some(Box::new(f));
+        self
+    }
+
+    pub(crate) fn mounted(&self, mountpoint: &Path) {
+        if let Some(ref f) = self.on_mounted {
+            f(mountpoint);
+        }
+    }
+
+    pub(crate) fn unmounted(&self, mountpoint: &Path) {
+        if let Some(ref f) = self.on_unmounted {
+            f(mountpoint);
+        }
+    }
+}
+
+impl fmt::Debug for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Lifecycle")
+            .field("on_mounted", &self.on_mounted.is_some())
+            .field("on_unmounted", &self.on_unmounted.is_some())
+            .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{block_on, Filesystem, MockChannel};
+    use super::Lifecycle;
+
+    struct Empty;
+
+    impl Filesystem for Empty {}
+
+    #[test]
+    fn mounted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mock = MockChannel::new(Empty).lifecycle(Lifecycle::new().on_mounted(move |_| { counter.fetch_add(1, Ordering::SeqCst); }));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        block_on(mock.init()).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
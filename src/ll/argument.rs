@@ -4,8 +4,8 @@
 //! structures (request arguments).
 
 use std::ffi::OsStr;
-use std::mem;
 use std::os::unix::ffi::OsStrExt;
+use zerocopy::FromBytes;
 
 
 /// An iterator that can be used to fetch typed arguments from a byte slice.
@@ -20,11 +20,13 @@ impl<'a> ArgumentIterator<'a> {
     }
 
     /// Returns the size of the remaining data.
+    #[cfg(test)]
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
     /// Fetch a slice of all remaining bytes.
+    #[cfg(test)]
     pub fn fetch_all(&mut self) -> &'a [u8] {
         let bytes = self.data;
         self.data = &[];
@@ -39,18 +41,17 @@ impl<'a> ArgumentIterator<'a> {
         Some(bytes)
     }
 
-    /// Fetch a typed argument. Returns `None` if there's not enough data left. This function is
-    /// unsafe because there is no guarantee that the data actually contains the type T.
-    pub unsafe fn fetch<T>(&mut self) -> Option<&'a T> {
-        let len = mem::size_of::<T>();
-        let bytes = self.fetch_bytes(len)?;
-        (bytes.as_ptr() as *const T).as_ref()
+    /// Fetch a typed argument. Returns `None` if there's not enough data left. The data doesn't
+    /// need to be aligned for the type, and any bytes are a valid value of it (`FromBytes`).
+    pub fn fetch<T: FromBytes>(&mut self) -> Option<T> {
+        let (arg, rest) = T::read_from_prefix(self.data).ok()?;
+        self.data = rest;
+        Some(arg)
     }
 
     /// Fetch a (zero-terminated) string (can be non-utf8). Returns `None` if there's not enough
-    /// data left or no zero-termination could be found. This function is unsafe because there is
-    /// no guarantee that the data actually contains a string.
-    pub unsafe fn fetch_str(&mut self) -> Option<&'a OsStr> {
+    /// data left or no zero-termination could be found.
+    pub fn fetch_str(&mut self) -> Option<&'a OsStr> {
         let len = self.data.iter().position(|&c| c == 0)?;
         let bytes = self.fetch_bytes(len)?;
         let _zero = self.fetch_bytes(1)?;
@@ -66,12 +67,13 @@ mod tests {
     const TEST_DATA: [u8; 10] = [0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61];
 
     #[repr(C)]
+    #[derive(Clone, Copy, FromBytes)]
     struct TestArgument { p1: u8, p2: u8, p3: u16 }
 
     #[test]
     fn all_data() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        unsafe { it.fetch_str().unwrap() };
+        it.fetch_str().unwrap();
         let arg = it.fetch_all();
         assert_eq!(arg, [0x62, 0x61, 0x72, 0x00, 0x62, 0x61]);
     }
@@ -89,11 +91,11 @@ mod tests {
     #[test]
     fn generic_argument() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x62);
         assert_eq!(arg.p2, 0x61);
         assert_eq!(arg.p3, 0x0072);
@@ -103,9 +105,9 @@ mod tests {
     #[test]
     fn string_argument() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "foo");
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
         assert_eq!(it.len(), 2);
     }
@@ -113,11 +115,11 @@ mod tests {
     #[test]
     fn mixed_arguments() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
         let arg = it.fetch_all();
         assert_eq!(arg, [0x62, 0x61]);
@@ -127,10 +129,10 @@ mod tests {
     fn out_of_data() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
         let _arg = it.fetch_bytes(8).unwrap();
-        let arg: Option<&TestArgument> = unsafe { it.fetch() };
+        let arg: Option<TestArgument> = it.fetch();
         assert!(arg.is_none());
         assert_eq!(it.len(), 2);
-        let arg = unsafe { it.fetch_str() };
+        let arg = it.fetch_str();
         assert!(arg.is_none());
         assert_eq!(it.len(), 2);
     }
@@ -1,6 +1,26 @@
 //! Low-level kernel communication.
+//!
+//! Parser of the requests the kernel driver sends on /dev/fuse and serializer of replies (in
+//! the wire format of the vendored ABI version). Sessions use them internally. With the `ll`
+//! feature, they're public for tools that need to make sense of FUSE traffic without serving
+//! a filesystem, like tracers, proxies and test harnesses. A request is parsed from the bytes
+//! of one read with `Request::try_from`. Malformed requests are errors, they never panic.
+//! Requests with opcodes of newer ABI versions fail with `RequestError::UnknownOperation`.
+//! A reply is serialized with `Response` into a buffer that is reused for further replies.
 
 mod argument;
 
+mod reply;
+#[cfg(feature = "ll")]
+pub use reply::{OutArg, Response};
+pub(crate) use reply::push_dirent;
+#[cfg(not(target_os = "macos"))]
+pub(crate) use reply::push_direntplus;
+
 mod request;
+#[cfg_attr(not(feature = "ll"), allow(unused_imports))]
 pub use request::{Operation, Request, RequestError};
+
+/// Types and constants of the kernel ABI, which the arguments of operations are made of
+#[cfg(feature = "ll")]
+pub use fuse_abi as abi;
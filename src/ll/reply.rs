@@ -0,0 +1,193 @@
+//! Low-level reply to a filesystem operation request.
+//!
+//! A reply is a `fuse_out_header` with the length of the reply, an error code and the unique id
+//! of the request, followed by the data of the operation (none for errors). Data is made of ABI
+//! structures, raw bytes (e.g. read data) or packed directory entries.
+
+use fuse_abi::*;
+use std::mem;
+#[cfg(feature = "ll")]
+use std::ffi::OsStr;
+#[cfg(feature = "ll")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "ll")]
+use zerocopy::{Immutable, IntoBytes};
+
+#[cfg(feature = "ll")]
+mod private {
+    pub trait Sealed {}
+}
+
+/// ABI structure that is sent as reply data. Implemented for the `repr(C)` output structures,
+/// whose memory can be sent as is.
+#[cfg(feature = "ll")]
+pub trait OutArg: IntoBytes + Immutable + private::Sealed {}
+
+#[cfg(feature = "ll")]
+macro_rules! out_args {
+    ($($(#[$attr:meta])* $ty:ty,)*) => {
+        $(
+            $(#[$attr])* impl private::Sealed for $ty {}
+            $(#[$attr])* impl OutArg for $ty {}
+        )*
+    };
+}
+
+#[cfg(feature = "ll")]
+out_args! {
+    fuse_entry_out,
+    fuse_attr_out,
+    #[cfg(target_os = "macos")]
+    fuse_getxtimes_out,
+    fuse_open_out,
+    fuse_write_out,
+    fuse_statfs_out,
+    fuse_getxattr_out,
+    fuse_lk_out,
+    fuse_init_out,
+    #[cfg(not(target_os = "macos"))]
+    fuse_init_out_ext,
+    fuse_bmap_out,
+}
+
+/// Append a directory entry to the given buffer, unless the data starting at the given index
+/// would be longer than the given size then. Returns true if the entry doesn't fit.
+pub(crate) fn push_dirent(data: &mut Vec<u8>, start: usize, ino: u64, offset: u64, typ: u32, name: &[u8], size: usize) -> bool {
+    let entlen = mem::size_of::<fuse_dirent>() + name.len();
+    let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
+    if data.len() - start + entsize > size { return true; }
+    for value in [ino, offset] {
+        data.extend_from_slice(&value.to_ne_bytes());
+    }
+    for value in [name.len() as u32, typ] {
+        data.extend_from_slice(&value.to_ne_bytes());
+    }
+    data.extend_from_slice(name);
+    data.resize(data.len() + entsize - entlen, 0);
+    false
+}
+
+/// Append a directory entry with the given entry of its attributes (a `fuse_entry_out`) to the
+/// given buffer, unless the data starting at the given index would be longer than the given
+/// size then. Returns true if the entry doesn't fit.
+#[cfg(not(target_os = "macos"))]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn push_direntplus(data: &mut Vec<u8>, start: usize, entry: &[u8], ino: u64, offset: u64, typ: u32, name: &[u8], size: usize) -> bool {
+    let entlen = entry.len() + mem::size_of::<fuse_dirent>() + name.len();
+    let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
+    if data.len() - start + entsize > size { return true; }
+    data.extend_from_slice(entry);
+    // The entry is 64bit aligned, so that the rest fits into what's left of the size
+    push_dirent(data, start, ino, offset, typ, name, size)
+}
+
+/// Reply to a request, serialized into a buffer that the caller can reuse for further replies
+#[cfg(feature = "ll")]
+#[derive(Debug)]
+pub struct Response<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+#[cfg(feature = "ll")]
+impl<'a> Response<'a> {
+    /// Start a successful reply to the request with the given unique id in the given buffer.
+    /// The buffer is cleared, its allocation is reused.
+    pub fn new(buf: &'a mut Vec<u8>, unique: u64) -> Response<'a> {
+        Response::with_error(buf, unique, 0)
+    }
+
+    /// Serialize an error reply with the given error code (a positive errno) to the request with
+    /// the given unique id in the given buffer, and return its bytes
+    pub fn error(buf: &'a mut Vec<u8>, unique: u64, errno: i32) -> &'a [u8] {
+        Response::with_error(buf, unique, -errno).finish()
+    }
+
+    fn with_error(buf: &'a mut Vec<u8>, unique: u64, error: i32) -> Response<'a> {
+        buf.clear();
+        // The length is set when finishing
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&error.to_ne_bytes());
+        buf.extend_from_slice(&unique.to_ne_bytes());
+        Response { buf }
+    }
+
+    /// Append the given ABI structure to the data
+    pub fn push<T: OutArg>(&mut self, arg: &T) -> &mut Response<'a> {
+        self.buf.extend_from_slice(arg.as_bytes());
+        self
+    }
+
+    /// Append the given bytes to the data
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> &mut Response<'a> {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Append a directory entry to the data of a readdir reply, with the offset to continue
+    /// reading after it and the file type as in `d_type` (the mode's `S_IFMT` bits shifted by
+    /// 12). Returns true without appending it if the data would be longer than the given size
+    /// (the size requested by the kernel driver).
+    pub fn push_dirent<N: AsRef<OsStr>>(&mut self, ino: u64, offset: u64, typ: u32, name: N, size: usize) -> bool {
+        push_dirent(self.buf, mem::size_of::<fuse_out_header>(), ino, offset, typ, name.as_ref().as_bytes(), size)
+    }
+
+    /// Returns the length of the data appended so far
+    pub fn data_len(&self) -> usize {
+        self.buf.len() - mem::size_of::<fuse_out_header>()
+    }
+
+    /// Finish the reply by setting its length in the header, and return its bytes
+    pub fn finish(self) -> &'a [u8] {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+
+#[cfg(all(test, feature = "ll"))]
+mod tests {
+    use fuse_abi::{fuse_open_out, fuse_write_out};
+    use super::Response;
+
+    #[test]
+    fn data() {
+        let mut buf = Vec::new();
+        let mut reply = Response::new(&mut buf, 0xdeadbeef);
+        reply.push(&fuse_open_out { fh: 0x1122, open_flags: 0x33, padding: 0 }).push_bytes(&[0xaa]);
+        assert_eq!(reply.data_len(), 17);
+        assert_eq!(reply.finish(), &[
+            0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xaa,
+        ][..]);
+        // The buffer is reused for the next reply
+        let mut reply = Response::new(&mut buf, 0xdeadbeef);
+        reply.push(&fuse_write_out { size: 0x1122, padding: 0 });
+        assert_eq!(reply.finish().len(), 24);
+    }
+
+    #[test]
+    fn error() {
+        let mut buf = Vec::new();
+        assert_eq!(Response::error(&mut buf, 0xdeadbeef, 66), &[
+            0x10, 0x00, 0x00, 0x00, 0xbe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,
+        ][..]);
+    }
+
+    #[test]
+    fn dirents() {
+        let mut buf = Vec::new();
+        let mut reply = Response::new(&mut buf, 0xdeadbeef);
+        assert!(!reply.push_dirent(0xaabb, 1, 4, "hello", 64));
+        assert!(!reply.push_dirent(0xccdd, 2, 8, "world.rs", 64));
+        assert!(reply.push_dirent(0xeeff, 3, 8, "full", 64));
+        assert_eq!(reply.finish(), &[
+            0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,
+            0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00,
+            0xdd, 0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x08, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,  0x77, 0x6f, 0x72, 0x6c, 0x64, 0x2e, 0x72, 0x73,
+        ][..]);
+    }
+}
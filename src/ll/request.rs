@@ -5,14 +5,16 @@
 
 use fuse_abi::*;
 use std::convert::TryFrom;
-use std::ffi::OsString;
+use std::ffi::OsStr;
 use std::{error, fmt, mem};
 
 use super::argument::ArgumentIterator;
+use crate::redact::Redaction;
 
 
 /// Error that may occur while reading and parsing a request from the kernel driver.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RequestError {
     /// Not enough data for parsing header (short read).
     ShortReadHeader(usize),
@@ -40,153 +42,298 @@ impl error::Error for RequestError {}
 
 /// Filesystem operation (and arguments) the kernel driver wants us to perform. The fields of each
 /// variant needs to match the actual arguments the kernel driver sends for the specific operation.
-#[derive(Debug)]
-pub enum Operation {
+/// Operations of newer ABI versions may be added, so matches need a wildcard arm.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum Operation<'a> {
+    /// Look up a directory entry by name (FUSE_LOOKUP)
     Lookup {
-        name: OsString,
+        /// Name of the entry
+        name: &'a OsStr,
     },
+    /// Forget lookups of an inode (FUSE_FORGET, no reply)
     Forget {
+        /// Number of lookups to forget
         arg: fuse_forget_in,
     },
+    /// Get the attributes of an inode (FUSE_GETATTR)
     GetAttr,
+    /// Set the attributes of an inode (FUSE_SETATTR)
     SetAttr {
+        /// Attributes to set, as given by `valid`
         arg: fuse_setattr_in,
     },
+    /// Read a symbolic link (FUSE_READLINK)
     ReadLink,
+    /// Create a symbolic link (FUSE_SYMLINK)
     SymLink {
-        name: OsString,
-        link: OsString,
+        /// Name of the link
+        name: &'a OsStr,
+        /// Target of the link
+        link: &'a OsStr,
     },
+    /// Create a file node (FUSE_MKNOD)
     MkNod {
+        /// Mode and device number of the node
         arg: fuse_mknod_in,
-        name: OsString,
+        /// Name of the node
+        name: &'a OsStr,
     },
+    /// Create a directory (FUSE_MKDIR)
     MkDir {
+        /// Mode of the directory
         arg: fuse_mkdir_in,
-        name: OsString,
+        /// Name of the directory
+        name: &'a OsStr,
     },
+    /// Remove a file (FUSE_UNLINK)
     Unlink {
-        name: OsString,
+        /// Name of the file
+        name: &'a OsStr,
     },
+    /// Remove a directory (FUSE_RMDIR)
     RmDir {
-        name: OsString,
+        /// Name of the directory
+        name: &'a OsStr,
     },
+    /// Rename an entry (FUSE_RENAME)
     Rename {
+        /// New parent directory
         arg: fuse_rename_in,
-        name: OsString,
-        newname: OsString,
+        /// Name of the entry
+        name: &'a OsStr,
+        /// New name of the entry
+        newname: &'a OsStr,
     },
+    /// Create a hard link (FUSE_LINK)
     Link {
+        /// Inode to link to
         arg: fuse_link_in,
-        name: OsString,
+        /// Name of the link
+        name: &'a OsStr,
     },
+    /// Open a file (FUSE_OPEN)
     Open {
+        /// Open flags
         arg: fuse_open_in,
     },
+    /// Read data from an open file (FUSE_READ)
     Read {
+        /// File handle, offset and size to read
         arg: fuse_read_in,
     },
+    /// Write data to an open file (FUSE_WRITE)
     Write {
+        /// File handle, offset and size to write
         arg: fuse_write_in,
-        data: Vec<u8>,
+        /// Data to write
+        data: &'a [u8],
     },
+    /// Get filesystem statistics (FUSE_STATFS)
     StatFs,
+    /// Release an open file (FUSE_RELEASE)
     Release {
+        /// File handle and release flags
         arg: fuse_release_in,
     },
+    /// Synchronize the contents of an open file (FUSE_FSYNC)
     FSync {
+        /// File handle and fsync flags
         arg: fuse_fsync_in,
     },
+    /// Set an extended attribute (FUSE_SETXATTR)
     SetXAttr {
+        /// Size of the value and flags
         arg: fuse_setxattr_in,
-        name: OsString,
-        value: Vec<u8>,
+        /// Name of the attribute
+        name: &'a OsStr,
+        /// Value of the attribute
+        value: &'a [u8],
     },
+    /// Get an extended attribute (FUSE_GETXATTR)
     GetXAttr {
+        /// Size of the buffer for the value (0 to get the size only)
         arg: fuse_getxattr_in,
-        name: OsString,
+        /// Name of the attribute
+        name: &'a OsStr,
     },
+    /// List extended attribute names (FUSE_LISTXATTR)
     ListXAttr {
+        /// Size of the buffer for the names (0 to get the size only)
         arg: fuse_getxattr_in,
     },
+    /// Remove an extended attribute (FUSE_REMOVEXATTR)
     RemoveXAttr {
-        name: OsString,
+        /// Name of the attribute
+        name: &'a OsStr,
     },
+    /// Flush an open file, on every close of a file descriptor (FUSE_FLUSH)
     Flush {
+        /// File handle and lock owner
         arg: fuse_flush_in,
     },
+    /// Initialize the connection (FUSE_INIT)
     Init {
+        /// ABI version, readahead size and capability flags of the kernel driver
         arg: fuse_init_in,
+        /// Capability flags above bit 31, sent by kernels setting `FUSE_INIT_EXT`
+        flags2: u32,
     },
+    /// Open a directory (FUSE_OPENDIR)
     OpenDir {
+        /// Open flags
         arg: fuse_open_in,
     },
+    /// Read entries of an open directory (FUSE_READDIR)
     ReadDir {
+        /// Directory handle, offset and size to read
         arg: fuse_read_in,
     },
+    /// Release an open directory (FUSE_RELEASEDIR)
     ReleaseDir {
+        /// Directory handle and release flags
         arg: fuse_release_in,
     },
+    /// Synchronize the contents of an open directory (FUSE_FSYNCDIR)
     FSyncDir {
+        /// Directory handle and fsync flags
         arg: fuse_fsync_in,
     },
+    /// Test for a POSIX file lock (FUSE_GETLK)
     GetLk {
+        /// File handle, lock owner and lock
         arg: fuse_lk_in,
     },
+    /// Acquire or release a POSIX file lock (FUSE_SETLK)
     SetLk {
+        /// File handle, lock owner and lock
         arg: fuse_lk_in,
     },
+    /// Acquire or release a POSIX file lock, waiting for it (FUSE_SETLKW)
     SetLkW {
+        /// File handle, lock owner and lock
         arg: fuse_lk_in,
     },
+    /// Check file access permissions (FUSE_ACCESS)
     Access {
+        /// Access mask
         arg: fuse_access_in,
     },
+    /// Create and open a file (FUSE_CREATE)
     Create {
+        /// Mode and open flags
         arg: fuse_create_in,
-        name: OsString,
+        /// Name of the file
+        name: &'a OsStr,
     },
+    /// Interrupt another request (FUSE_INTERRUPT, no reply)
     Interrupt {
+        /// Unique id of the request to interrupt
         arg: fuse_interrupt_in,
     },
+    /// Map a block index of a file to a device block index (FUSE_BMAP)
     BMap {
+        /// Block size and index
         arg: fuse_bmap_in,
     },
+    /// Clean up the filesystem (FUSE_DESTROY)
     Destroy,
-    // TODO: FUSE_IOCTL since ABI 7.11
-    // IoCtl {
-    //     arg: fuse_ioctl_in,
-    //     data: Vec<u8>,
-    // },
-    // TODO: FUSE_POLL since ABI 7.11
-    // Poll {
-    //     arg: fuse_poll_in,
-    // },
+    /// Control a device (FUSE_IOCTL, ABI 7.11)
+    #[cfg(not(target_os = "macos"))]
+    IoCtl {
+        /// File handle, flags, command, argument and sizes
+        arg: fuse_ioctl_in,
+        /// Input data copied from the caller
+        data: &'a [u8],
+    },
+    /// Poll a file for events (FUSE_POLL, ABI 7.11)
+    #[cfg(not(target_os = "macos"))]
+    Poll {
+        /// File handle, poll handle and flags
+        arg: fuse_poll_in,
+    },
     // TODO: FUSE_NOTIFY_REPLY since ABI 7.15
     // NotifyReply {
     //     data: Vec<u8>,
     // },
-    // TODO: FUSE_BATCH_FORGET since ABI 7.16
-    // BatchForget {
-    //     arg: fuse_forget_in,
-    //     nodes: Vec<fuse_forget_one>,
-    // },
-    // TODO: FUSE_FALLOCATE since ABI 7.19
-    // FAllocate {
-    //     arg: fuse_fallocate_in,
-    // },
+    /// Forget lookups of several inodes (FUSE_BATCH_FORGET, ABI 7.16, no reply)
+    #[cfg(not(target_os = "macos"))]
+    BatchForget {
+        /// Number of inodes
+        arg: fuse_batch_forget_in,
+        /// Inodes and numbers of lookups to forget, `arg.count` times `fuse_forget_one`
+        nodes: &'a [u8],
+    },
+    /// Allocate or deallocate space of a file (FUSE_FALLOCATE, ABI 7.19)
+    #[cfg(not(target_os = "macos"))]
+    FAllocate {
+        /// File handle, range and mode
+        arg: fuse_fallocate_in,
+    },
+    /// Read a directory with the attributes of its entries (FUSE_READDIRPLUS, ABI 7.21)
+    #[cfg(not(target_os = "macos"))]
+    ReadDirPlus {
+        /// File handle, offset and size
+        arg: fuse_read_in,
+    },
+    /// Rename a file with renameat2 flags (FUSE_RENAME2, ABI 7.23)
+    #[cfg(not(target_os = "macos"))]
+    Rename2 {
+        /// New directory and flags
+        arg: fuse_rename2_in,
+        /// Old name of the file
+        name: &'a OsStr,
+        /// New name of the file
+        newname: &'a OsStr,
+    },
+    /// Find the next data or hole in a file (FUSE_LSEEK, ABI 7.24)
+    #[cfg(not(target_os = "macos"))]
+    LSeek {
+        /// File handle, offset and whence
+        arg: fuse_lseek_in,
+    },
+    /// Copy a range of data from one file to another (FUSE_COPY_FILE_RANGE, ABI 7.28)
+    #[cfg(not(target_os = "macos"))]
+    CopyFileRange {
+        /// File handles, offsets and length
+        arg: fuse_copy_file_range_in,
+    },
+    /// Synchronize the filesystem (FUSE_SYNCFS, ABI 7.34)
+    #[cfg(not(target_os = "macos"))]
+    SyncFs,
+    /// Create and open an unnamed file (FUSE_TMPFILE, ABI 7.37)
+    #[cfg(not(target_os = "macos"))]
+    TmpFile {
+        /// Mode and open flags
+        arg: fuse_create_in,
+        /// Name the kernel passes along, not an entry of the directory
+        name: &'a OsStr,
+    },
+    /// Get extended file attributes (FUSE_STATX, ABI 7.39)
+    #[cfg(not(target_os = "macos"))]
+    StatX {
+        /// File handle and requested attributes
+        arg: fuse_statx_in,
+    },
 
+    /// Set the volume name (FUSE_SETVOLNAME)
     #[cfg(target_os = "macos")]
     SetVolName {
-        name: OsString,
+        /// Name of the volume
+        name: &'a OsStr,
     },
+    /// Get the backup and creation times of an inode (FUSE_GETXTIMES)
     #[cfg(target_os = "macos")]
     GetXTimes,
+    /// Exchange the data of two files (FUSE_EXCHANGE)
     #[cfg(target_os = "macos")]
     Exchange {
+        /// Directories of the files and options
         arg: fuse_exchange_in,
-        oldname: OsString,
-        newname: OsString,
+        /// Name of the first file
+        oldname: &'a OsStr,
+        /// Name of the second file
+        newname: &'a OsStr,
     },
 
     // TODO: CUSE_INIT since ABI 7.12
@@ -195,33 +342,40 @@ pub enum Operation {
     // },
 }
 
-impl<'a> fmt::Display for Operation {
+impl fmt::Display for Operation<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_redacted(f, Redaction::Off)
+    }
+}
+
+impl<'a> Operation<'a> {
+    /// Format this operation, redacting the names of files as given
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>, redaction: Redaction) -> fmt::Result {
         match self {
-            Operation::Lookup { name } => write!(f, "LOOKUP name {:?}", name),
+            Operation::Lookup { name } => write!(f, "LOOKUP name {}", redaction.name(name)),
             Operation::Forget { arg } => write!(f, "FORGET nlookup {}", arg.nlookup),
             Operation::GetAttr => write!(f, "GETATTR"),
             Operation::SetAttr { arg } => write!(f, "SETATTR valid {:#x}", arg.valid),
             Operation::ReadLink => write!(f, "READLINK"),
-            Operation::SymLink { name, link } => write!(f, "SYMLINK name {:?}, link {:?}", name, link),
-            Operation::MkNod { arg, name } => write!(f, "MKNOD name {:?}, mode {:#05o}, rdev {}", name, arg.mode, arg.rdev),
-            Operation::MkDir { arg, name } => write!(f, "MKDIR name {:?}, mode {:#05o}", name, arg.mode),
-            Operation::Unlink { name } => write!(f, "UNLINK name {:?}", name),
-            Operation::RmDir { name } => write!(f, "RMDIR name {:?}", name),
-            Operation::Rename { arg, name, newname } => write!(f, "RENAME name {:?}, newdir {:#018x}, newname {:?}", name, arg.newdir, newname),
-            Operation::Link { arg, name } => write!(f, "LINK name {:?}, oldnodeid {:#018x}", name, arg.oldnodeid),
+            Operation::SymLink { name, link } => write!(f, "SYMLINK name {}, link {}", redaction.name(name), redaction.name(link)),
+            Operation::MkNod { arg, name } => write!(f, "MKNOD name {}, mode {:#05o}, rdev {}", redaction.name(name), arg.mode, arg.rdev),
+            Operation::MkDir { arg, name } => write!(f, "MKDIR name {}, mode {:#05o}", redaction.name(name), arg.mode),
+            Operation::Unlink { name } => write!(f, "UNLINK name {}", redaction.name(name)),
+            Operation::RmDir { name } => write!(f, "RMDIR name {}", redaction.name(name)),
+            Operation::Rename { arg, name, newname } => write!(f, "RENAME name {}, newdir {:#018x}, newname {}", redaction.name(name), arg.newdir, redaction.name(newname)),
+            Operation::Link { arg, name } => write!(f, "LINK name {}, oldnodeid {:#018x}", redaction.name(name), arg.oldnodeid),
             Operation::Open { arg } => write!(f, "OPEN flags {:#x}", arg.flags),
             Operation::Read { arg } => write!(f, "READ fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
             Operation::Write { arg, .. } => write!(f, "WRITE fh {}, offset {}, size {}, write flags {:#x}", arg.fh, arg.offset, arg.size, arg.write_flags),
             Operation::StatFs => write!(f, "STATFS"),
             Operation::Release { arg } => write!(f, "RELEASE fh {}, flags {:#x}, release flags {:#x}, lock owner {}", arg.fh, arg.flags, arg.release_flags, arg.lock_owner),
             Operation::FSync { arg } => write!(f, "FSYNC fh {}, fsync flags {:#x}", arg.fh, arg.fsync_flags),
-            Operation::SetXAttr { arg, name, .. } => write!(f, "SETXATTR name {:?}, size {}, flags {:#x}", name, arg.size, arg.flags),
-            Operation::GetXAttr { arg, name } => write!(f, "GETXATTR name {:?}, size {}", name, arg.size),
+            Operation::SetXAttr { arg, name, .. } => write!(f, "SETXATTR name {}, size {}, flags {:#x}", redaction.name(name), arg.size, arg.flags),
+            Operation::GetXAttr { arg, name } => write!(f, "GETXATTR name {}, size {}", redaction.name(name), arg.size),
             Operation::ListXAttr { arg } => write!(f, "LISTXATTR size {}", arg.size),
-            Operation::RemoveXAttr { name } => write!(f, "REMOVEXATTR name {:?}", name),
+            Operation::RemoveXAttr { name } => write!(f, "REMOVEXATTR name {}", redaction.name(name)),
             Operation::Flush { arg } => write!(f, "FLUSH fh {}, lock owner {}", arg.fh, arg.lock_owner),
-            Operation::Init { arg } => write!(f, "INIT kernel ABI {}.{}, flags {:#x}, max readahead {}", arg.major, arg.minor, arg.flags, arg.max_readahead),
+            Operation::Init { arg, .. } => write!(f, "INIT kernel ABI {}.{}, flags {:#x}, max readahead {}", arg.major, arg.minor, arg.flags, arg.max_readahead),
             Operation::OpenDir { arg } => write!(f, "OPENDIR flags {:#x}", arg.flags),
             Operation::ReadDir { arg } => write!(f, "READDIR fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
             Operation::ReleaseDir { arg } => write!(f, "RELEASEDIR fh {}, flags {:#x}, release flags {:#x}, lock owner {}", arg.fh, arg.flags, arg.release_flags, arg.lock_owner),
@@ -230,127 +384,256 @@ impl<'a> fmt::Display for Operation {
             Operation::SetLk { arg } => write!(f, "SETLK fh {}, lock owner {}", arg.fh, arg.owner),
             Operation::SetLkW { arg } => write!(f, "SETLKW fh {}, lock owner {}", arg.fh, arg.owner),
             Operation::Access { arg } => write!(f, "ACCESS mask {:#05o}", arg.mask),
-            Operation::Create { arg, name } => write!(f, "CREATE name {:?}, mode {:#05o}, flags {:#x}", name, arg.mode, arg.flags),
+            Operation::Create { arg, name } => write!(f, "CREATE name {}, mode {:#05o}, flags {:#x}", redaction.name(name), arg.mode, arg.flags),
             Operation::Interrupt { arg } => write!(f, "INTERRUPT unique {}", arg.unique),
             Operation::BMap { arg } => write!(f, "BMAP blocksize {}, ids {}", arg.blocksize, arg.block),
             Operation::Destroy => write!(f, "DESTROY"),
+            #[cfg(not(target_os = "macos"))]
+            Operation::IoCtl { arg, .. } => write!(f, "IOCTL fh {}, flags {:#x}, cmd {:#x}, arg {:#x}, in size {}, out size {}", arg.fh, arg.flags, arg.cmd, arg.arg, arg.in_size, arg.out_size),
+            #[cfg(not(target_os = "macos"))]
+            Operation::Poll { arg } => write!(f, "POLL fh {}, kh {}, flags {:#x}", arg.fh, arg.kh, arg.flags),
+            #[cfg(not(target_os = "macos"))]
+            Operation::BatchForget { arg, .. } => write!(f, "BATCH_FORGET count {}", arg.count),
+            #[cfg(not(target_os = "macos"))]
+            Operation::FAllocate { arg } => write!(f, "FALLOCATE fh {}, offset {}, length {}, mode {:#x}", arg.fh, arg.offset, arg.length, arg.mode),
+            #[cfg(not(target_os = "macos"))]
+            Operation::ReadDirPlus { arg } => write!(f, "READDIRPLUS fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
+            #[cfg(not(target_os = "macos"))]
+            Operation::Rename2 { arg, name, newname } => write!(f, "RENAME2 name {}, newdir {:#018x}, newname {}, flags {:#x}", redaction.name(name), arg.newdir, redaction.name(newname), arg.flags),
+            #[cfg(not(target_os = "macos"))]
+            Operation::LSeek { arg } => write!(f, "LSEEK fh {}, offset {}, whence {}", arg.fh, arg.offset, arg.whence),
+            #[cfg(not(target_os = "macos"))]
+            Operation::CopyFileRange { arg } => write!(f, "COPY_FILE_RANGE fh {}, offset {}, nodeid out {:#018x}, fh out {}, offset out {}, len {}, flags {:#x}", arg.fh_in, arg.off_in, arg.nodeid_out, arg.fh_out, arg.off_out, arg.len, arg.flags),
+            #[cfg(not(target_os = "macos"))]
+            Operation::SyncFs => write!(f, "SYNCFS"),
+            #[cfg(not(target_os = "macos"))]
+            Operation::TmpFile { arg, name } => write!(f, "TMPFILE name {}, mode {:#05o}, flags {:#x}", redaction.name(name), arg.mode, arg.flags),
+            #[cfg(not(target_os = "macos"))]
+            Operation::StatX { arg } => write!(f, "STATX fh {}, flags {:#x}, mask {:#x}", arg.fh, arg.sx_flags, arg.sx_mask),
 
             #[cfg(target_os = "macos")]
-            Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
+            Operation::SetVolName { name } => write!(f, "SETVOLNAME name {}", redaction.name(name)),
             #[cfg(target_os = "macos")]
             Operation::GetXTimes => write!(f, "GETXTIMES"),
             #[cfg(target_os = "macos")]
-            Operation::Exchange { arg, oldname, newname } => write!(f, "EXCHANGE olddir {:#018x}, oldname {:?}, newdir {:#018x}, newname {:?}, options {:#x}", arg.olddir, oldname, arg.newdir, newname, arg.options),
+            Operation::Exchange { arg, oldname, newname } => write!(f, "EXCHANGE olddir {:#018x}, oldname {}, newdir {:#018x}, newname {}, options {:#x}", arg.olddir, redaction.name(oldname), arg.newdir, redaction.name(newname), arg.options),
         }
     }
 }
 
-impl Operation {
-    fn parse(opcode: &fuse_opcode, data: &mut ArgumentIterator<'_>) -> Option<Self> {
-        unsafe {
-            Some(match opcode {
-                fuse_opcode::FUSE_LOOKUP => Operation::Lookup {
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_FORGET => Operation::Forget { arg: *data.fetch()? },
-                fuse_opcode::FUSE_GETATTR => Operation::GetAttr,
-                fuse_opcode::FUSE_SETATTR => Operation::SetAttr { arg: *data.fetch()? },
-                fuse_opcode::FUSE_READLINK => Operation::ReadLink,
-                fuse_opcode::FUSE_SYMLINK => Operation::SymLink {
-                    name: data.fetch_str()?.into(),
-                    link: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_MKNOD => Operation::MkNod {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_MKDIR => Operation::MkDir {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_UNLINK => Operation::Unlink {
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_RMDIR => Operation::RmDir {
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_RENAME => Operation::Rename {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                    newname: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_LINK => Operation::Link {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_OPEN => Operation::Open { arg: *data.fetch()? },
-                fuse_opcode::FUSE_READ => Operation::Read { arg: *data.fetch()? },
-                fuse_opcode::FUSE_WRITE => Operation::Write {
-                    arg: *data.fetch()?,
-                    data: data.fetch_all().to_vec(),
-                },
-                fuse_opcode::FUSE_STATFS => Operation::StatFs,
-                fuse_opcode::FUSE_RELEASE => Operation::Release { arg: *data.fetch()? },
-                fuse_opcode::FUSE_FSYNC => Operation::FSync { arg: *data.fetch()? },
-                fuse_opcode::FUSE_SETXATTR => Operation::SetXAttr {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                    value: data.fetch_all().to_vec(),
-                },
-                fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_LISTXATTR => Operation::ListXAttr { arg: *data.fetch()? },
-                fuse_opcode::FUSE_REMOVEXATTR => Operation::RemoveXAttr {
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_FLUSH => Operation::Flush { arg: *data.fetch()? },
-                fuse_opcode::FUSE_INIT => Operation::Init { arg: *data.fetch()? },
-                fuse_opcode::FUSE_OPENDIR => Operation::OpenDir { arg: *data.fetch()? },
-                fuse_opcode::FUSE_READDIR => Operation::ReadDir { arg: *data.fetch()? },
-                fuse_opcode::FUSE_RELEASEDIR => Operation::ReleaseDir { arg: *data.fetch()? },
-                fuse_opcode::FUSE_FSYNCDIR => Operation::FSyncDir { arg: *data.fetch()? },
-                fuse_opcode::FUSE_GETLK => Operation::GetLk { arg: *data.fetch()? },
-                fuse_opcode::FUSE_SETLK => Operation::SetLk { arg: *data.fetch()? },
-                fuse_opcode::FUSE_SETLKW => Operation::SetLkW { arg: *data.fetch()? },
-                fuse_opcode::FUSE_ACCESS => Operation::Access { arg: *data.fetch()? },
-                fuse_opcode::FUSE_CREATE => Operation::Create {
-                    arg: *data.fetch()?,
-                    name: data.fetch_str()?.into(),
-                },
-                fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: *data.fetch()? },
-                fuse_opcode::FUSE_BMAP => Operation::BMap { arg: *data.fetch()? },
-                fuse_opcode::FUSE_DESTROY => Operation::Destroy,
-
-                #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
-                    name: data.fetch_str()?.into(),
-                },
-                #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_GETXTIMES => Operation::GetXTimes,
+impl<'a> Operation<'a> {
+    fn parse(opcode: &fuse_opcode, data: &mut ArgumentIterator<'a>) -> Option<Self> {
+        Some(match opcode {
+            fuse_opcode::FUSE_LOOKUP => Operation::Lookup {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_FORGET => Operation::Forget { arg: data.fetch()? },
+            fuse_opcode::FUSE_GETATTR => Operation::GetAttr,
+            fuse_opcode::FUSE_SETATTR => Operation::SetAttr { arg: data.fetch()? },
+            fuse_opcode::FUSE_READLINK => Operation::ReadLink,
+            fuse_opcode::FUSE_SYMLINK => Operation::SymLink {
+                name: data.fetch_str()?,
+                link: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_MKNOD => Operation::MkNod {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_MKDIR => Operation::MkDir {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_UNLINK => Operation::Unlink {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_RMDIR => Operation::RmDir {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_RENAME => Operation::Rename {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+                newname: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_LINK => Operation::Link {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_OPEN => Operation::Open { arg: data.fetch()? },
+            fuse_opcode::FUSE_READ => Operation::Read { arg: data.fetch()? },
+            fuse_opcode::FUSE_WRITE => {
+                let arg: fuse_write_in = data.fetch()?;
+                Operation::Write { arg, data: data.fetch_bytes(arg.size as usize)? }
+            }
+            fuse_opcode::FUSE_STATFS => Operation::StatFs,
+            fuse_opcode::FUSE_RELEASE => Operation::Release { arg: data.fetch()? },
+            fuse_opcode::FUSE_FSYNC => Operation::FSync { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETXATTR => {
+                let arg: fuse_setxattr_in = data.fetch()?;
+                let name = data.fetch_str()?;
+                Operation::SetXAttr { arg, name, value: data.fetch_bytes(arg.size as usize)? }
+            }
+            fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_LISTXATTR => Operation::ListXAttr { arg: data.fetch()? },
+            fuse_opcode::FUSE_REMOVEXATTR => Operation::RemoveXAttr {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_FLUSH => Operation::Flush { arg: data.fetch()? },
+            fuse_opcode::FUSE_INIT => {
+                let arg: fuse_init_in = data.fetch()?;
+                // Kernels setting FUSE_INIT_EXT send more flags after the fields of ABI 7.8
+                #[cfg(not(target_os = "macos"))]
+                let flags2 = if arg.flags & consts::FUSE_INIT_EXT != 0 { data.fetch().unwrap_or(0) } else { 0 };
                 #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_EXCHANGE => Operation::Exchange {
-                    arg: *data.fetch()?,
-                    oldname: data.fetch_str()?.into(),
-                    newname: data.fetch_str()?.into(),
-                },
-            })
+                let flags2 = 0;
+                Operation::Init { arg, flags2 }
+            }
+            fuse_opcode::FUSE_OPENDIR => Operation::OpenDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_READDIR => Operation::ReadDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_RELEASEDIR => Operation::ReleaseDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_FSYNCDIR => Operation::FSyncDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_GETLK => Operation::GetLk { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETLK => Operation::SetLk { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETLKW => Operation::SetLkW { arg: data.fetch()? },
+            fuse_opcode::FUSE_ACCESS => Operation::Access { arg: data.fetch()? },
+            fuse_opcode::FUSE_CREATE => Operation::Create {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
+            fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
+            fuse_opcode::FUSE_DESTROY => Operation::Destroy,
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_IOCTL => {
+                let arg: fuse_ioctl_in = data.fetch()?;
+                Operation::IoCtl { arg, data: data.fetch_bytes(arg.in_size as usize)? }
+            }
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_POLL => Operation::Poll { arg: data.fetch()? },
+            // Sessions don't retrieve data (FUSE_NOTIFY_RETRIEVE), which this would reply to
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_NOTIFY_REPLY => return None,
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_BATCH_FORGET => {
+                let arg: fuse_batch_forget_in = data.fetch()?;
+                let len = (arg.count as usize).checked_mul(mem::size_of::<fuse_forget_one>())?;
+                Operation::BatchForget { arg, nodes: data.fetch_bytes(len)? }
+            }
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_FALLOCATE => Operation::FAllocate { arg: data.fetch()? },
+            // Sessions don't serve character devices (CUSE)
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::CUSE_INIT => return None,
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_READDIRPLUS => Operation::ReadDirPlus { arg: data.fetch()? },
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_RENAME2 => Operation::Rename2 {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+                newname: data.fetch_str()?,
+            },
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_LSEEK => Operation::LSeek { arg: data.fetch()? },
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_COPY_FILE_RANGE => Operation::CopyFileRange { arg: data.fetch()? },
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_SYNCFS => {
+                let _: fuse_syncfs_in = data.fetch()?;
+                Operation::SyncFs
+            }
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_TMPFILE => Operation::TmpFile {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            #[cfg(not(target_os = "macos"))]
+            fuse_opcode::FUSE_STATX => Operation::StatX { arg: data.fetch()? },
+
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
+                name: data.fetch_str()?,
+            },
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_GETXTIMES => Operation::GetXTimes,
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_EXCHANGE => Operation::Exchange {
+                arg: data.fetch()?,
+                oldname: data.fetch_str()?,
+                newname: data.fetch_str()?,
+            },
+        })
+    }
+}
+
+
+/// Number of argument bytes a request stores inline. This fits the arguments of every operation
+/// on names (an argument struct and up to two names of NAME_MAX bytes), so that parsing the
+/// requests of the metadata path doesn't allocate. Larger arguments (e.g. the data of writes)
+/// are stored on the heap.
+const INLINE_ARGS: usize = 576;
+
+/// Arguments of a request, copied from the buffer the request was received in, which is reused
+/// for the next request while this one is dispatched. Inline arguments are large on purpose.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+enum Args {
+    Inline(usize, [u8; INLINE_ARGS]),
+    Heap(Box<[u8]>),
+}
+
+impl Args {
+    fn new(data: &[u8]) -> Args {
+        if data.len() > INLINE_ARGS {
+            return Args::Heap(data.into());
+        }
+        let mut buf = [0; INLINE_ARGS];
+        buf[..data.len()].copy_from_slice(data);
+        Args::Inline(data.len(), buf)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Args::Inline(len, buf) => &buf[..*len],
+            Args::Heap(data) => data,
         }
     }
 }
 
+impl fmt::Debug for Args {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Args({} bytes)", self.as_bytes().len())
+    }
+}
 
 /// Low-level request of a filesystem operation the kernel driver wants to perform.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Request {
     header: fuse_in_header,
-    operation: Operation,
+    opcode: fuse_opcode,
+    args: Args,
+    /// Length of the extensions at the end of the arguments
+    extlen: usize,
 }
 
 impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "FUSE({:3}) ino {:#018x}: {}", self.header.unique, self.header.nodeid, self.operation)
+        write!(f, "{}", self.redacted(Redaction::Off))
+    }
+}
+
+/// Request that is formatted with the names of files redacted
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RedactedRequest<'a> {
+    request: &'a Request,
+    redaction: Redaction,
+}
+
+impl fmt::Display for RedactedRequest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FUSE({:3}) ino {:#018x}: ", self.request.header.unique, self.request.header.nodeid)?;
+        self.request.operation().fmt_redacted(f, self.redaction)
     }
 }
 
@@ -360,11 +643,12 @@ impl TryFrom<&[u8]> for Request {
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
         // Parse a raw packet as sent by the kernel driver into typed data. Every request always
         // begins with a `fuse_in_header` struct followed by arguments depending on the opcode.
+        // Neither the data nor its length can be trusted, a malformed request must never panic.
         let data_len = data.len();
-        let mut data = ArgumentIterator::new(data);
+        let mut args = ArgumentIterator::new(data);
         // Parse header
-        let header: &fuse_in_header =
-            unsafe { data.fetch() }.ok_or_else(|| RequestError::ShortReadHeader(data.len()))?;
+        let header: fuse_in_header =
+            args.fetch().ok_or(RequestError::ShortReadHeader(data_len))?;
         // Parse/check opcode
         let opcode = fuse_opcode::try_from(header.opcode)
             .map_err(|_: InvalidOpcodeError| RequestError::UnknownOperation(header.opcode))?;
@@ -372,16 +656,28 @@ impl TryFrom<&[u8]> for Request {
         if data_len < header.len as usize {
             return Err(RequestError::ShortRead(data_len, header.len as usize));
         }
-        // Parse/check operation arguments
-        let operation =
-            Operation::parse(&opcode, &mut data).ok_or_else(|| RequestError::InsufficientData)?;
-        let header = *header;
-
-        Ok(Self { header, operation })
+        if (header.len as usize) < mem::size_of::<fuse_in_header>() {
+            return Err(RequestError::InsufficientData);
+        }
+        // Check operation arguments, which end where the header says, followed by the
+        // extensions of the request (ABI 7.36). The operation is parsed again from the stored
+        // arguments whenever it's accessed, which is cheap.
+        let args = &data[mem::size_of::<fuse_in_header>()..header.len as usize];
+        let extlen = header.total_extlen as usize * 8;
+        let operation_args = args.len().checked_sub(extlen).map(|len| &args[..len]).ok_or(RequestError::InsufficientData)?;
+        Operation::parse(&opcode, &mut ArgumentIterator::new(operation_args)).ok_or(RequestError::InsufficientData)?;
+
+        Ok(Self { header, opcode, args: Args::new(args), extlen })
     }
 }
 
 impl Request {
+    /// Returns the header of this request, e.g. to tell its length in a stream of requests.
+    #[inline]
+    pub fn header(&self) -> &fuse_in_header {
+        &self.header
+    }
+
     /// Returns the unique identifier of this request.
     ///
     /// The FUSE kernel driver assigns a unique id to every concurrent request. This allows to
@@ -392,6 +688,12 @@ impl Request {
         self.header.unique
     }
 
+    /// Returns the opcode of the filesystem operation of this request.
+    #[inline]
+    pub fn opcode(&self) -> fuse_opcode {
+        self.opcode
+    }
+
     /// Returns the node id of the inode this request is targeted to.
     #[inline]
     pub fn nodeid(&self) -> u64 {
@@ -416,16 +718,45 @@ impl Request {
         self.header.pid
     }
 
-    /// Returns the filesystem operation (and its arguments) of this request.
+    /// Returns the filesystem operation (and its arguments) of this request, borrowing names
+    /// and data from the request.
     #[inline]
-    pub fn operation(&self) -> &Operation {
-        &self.operation
+    pub fn operation(&self) -> Operation<'_> {
+        let args = self.args.as_bytes();
+        Operation::parse(&self.opcode, &mut ArgumentIterator::new(&args[..args.len() - self.extlen]))
+            .expect("arguments checked when parsing the request")
+    }
+
+    /// Returns the supplementary groups of the caller the kernel sent in an extension of this
+    /// request (on create, mkdir, mknod, symlink and tmpfile if `FUSE_CREATE_SUPP_GROUP` was
+    /// negotiated, ABI 7.38). Malformed extensions are ignored.
+    #[cfg(not(target_os = "macos"))]
+    pub fn supp_groups(&self) -> Vec<u32> {
+        let args = self.args.as_bytes();
+        let mut extensions = ArgumentIterator::new(&args[args.len() - self.extlen..]);
+        while let Some(ext) = extensions.fetch::<fuse_ext_header>() {
+            let data = (ext.size as usize).checked_sub(mem::size_of::<fuse_ext_header>())
+                .and_then(|len| extensions.fetch_bytes(len));
+            let Some(data) = data else { break };
+            if ext.typ == consts::FUSE_EXT_GROUPS {
+                let mut data = ArgumentIterator::new(data);
+                let nr_groups = data.fetch::<fuse_supp_groups>().map_or(0, |groups| groups.nr_groups);
+                return (0..nr_groups).map_while(|_| data.fetch::<u32>()).collect();
+            }
+        }
+        Vec::new()
+    }
+
+    /// Returns this request for formatting it with the names of files redacted as given.
+    pub(crate) fn redacted(&self, redaction: Redaction) -> RedactedRequest<'_> {
+        RedactedRequest { request: self, redaction }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::ffi::OsStrExt;
     use super::*;
 
     #[cfg(target_endian = "big")]
@@ -450,7 +781,7 @@ mod tests {
         0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // max_readahead, flags
     ];
 
-    #[cfg(target_endian = "big")]
+    #[cfg(all(target_endian = "big", target_os = "macos"))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x08, // len, opcode
         0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
@@ -461,7 +792,19 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
-    #[cfg(target_endian = "little")]
+    #[cfg(all(target_endian = "big", not(target_os = "macos")))]
+    const MKNOD_REQUEST: [u8; 64] = [
+        0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x08, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x00, 0x00, 0x01, 0xa4, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ];
+
+    #[cfg(all(target_endian = "little", target_os = "macos"))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x38, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
         0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
@@ -472,6 +815,18 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
+    #[cfg(all(target_endian = "little", not(target_os = "macos")))]
+    const MKNOD_REQUEST: [u8; 64] = [
+        0x40, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0xa4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ];
+
     #[test]
     fn short_read_header() {
         match Request::try_from(&INIT_REQUEST[..20]) {
@@ -499,7 +854,7 @@ mod tests {
         assert_eq!(req.gid(), 0xc001_cafe);
         assert_eq!(req.pid(), 0xc0de_ba5e);
         match req.operation() {
-            Operation::Init { arg } => {
+            Operation::Init { arg, .. } => {
                 assert_eq!(arg.major, 7);
                 assert_eq!(arg.minor, 8);
                 assert_eq!(arg.max_readahead, 4096);
@@ -511,7 +866,7 @@ mod tests {
     #[test]
     fn mknod() {
         let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
-        assert_eq!(req.header.len, 56);
+        assert_eq!(req.header.len as usize, MKNOD_REQUEST.len());
         assert_eq!(req.header.opcode, 8);
         assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
         assert_eq!(req.nodeid(), 0x1122_3344_5566_7788);
@@ -521,9 +876,131 @@ mod tests {
         match req.operation() {
             Operation::MkNod { arg, name } => {
                 assert_eq!(arg.mode, 0o644);
-                assert_eq!(*name, "foo.txt");
+                #[cfg(not(target_os = "macos"))]
+                assert_eq!(arg.umask, 0o022);
+                assert_eq!(name, "foo.txt");
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[test]
+    fn redacted() {
+        let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
+        assert_eq!(req.to_string(), "FUSE(16045690984229367821) ino 0x1122334455667788: MKNOD name \"foo.txt\", mode 0o644, rdev 0");
+        assert_eq!(req.redacted(Redaction::Truncate(2)).to_string(), "FUSE(16045690984229367821) ino 0x1122334455667788: MKNOD name \"fo\"..., mode 0o644, rdev 0");
+        assert!(!req.redacted(Redaction::Hash).to_string().contains("foo"));
+    }
+
+    #[test]
+    fn malformed() {
+        // Length in header shorter than the header
+        let mut data = MKNOD_REQUEST;
+        data[..4].copy_from_slice(&20u32.to_ne_bytes());
+        assert!(matches!(Request::try_from(&data[..]), Err(RequestError::InsufficientData)));
+        // Arguments beyond the length in the header are ignored
+        data[..4].copy_from_slice(&(MKNOD_REQUEST.len() as u32 - 4).to_ne_bytes());
+        assert!(matches!(Request::try_from(&data[..]), Err(RequestError::InsufficientData)));
+        // Unknown opcode
+        data[4..8].copy_from_slice(&0xffffu32.to_ne_bytes());
+        assert!(matches!(Request::try_from(&data[..]), Err(RequestError::UnknownOperation(0xffff))));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn extensions() {
+        // Supplementary groups follow the arguments, their length is in 8 byte units
+        let mut data = MKNOD_REQUEST.to_vec();
+        for value in [16u32, consts::FUSE_EXT_GROUPS, 1, 1000] {
+            data.extend_from_slice(&value.to_ne_bytes());
+        }
+        let len = data.len() as u32;
+        data[..4].copy_from_slice(&len.to_ne_bytes());
+        data[36..38].copy_from_slice(&2u16.to_ne_bytes());
+        let req = Request::try_from(&data[..]).unwrap();
+        assert!(matches!(req.operation(), Operation::MkNod { name, .. } if name == "foo.txt"));
+        assert_eq!(req.supp_groups(), [1000]);
+        assert!(Request::try_from(&MKNOD_REQUEST[..]).unwrap().supp_groups().is_empty());
+        // Extensions longer than the arguments
+        data[36..38].copy_from_slice(&5u16.to_ne_bytes());
+        assert!(matches!(Request::try_from(&data[..]), Err(RequestError::InsufficientData)));
+    }
+
+    #[test]
+    fn truncated() {
+        for len in 0..MKNOD_REQUEST.len() {
+            assert!(Request::try_from(&MKNOD_REQUEST[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn args() {
+        // Arguments of operations on names are stored in the request itself
+        let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
+        assert!(matches!(req.args, Args::Inline(len, _) if len == MKNOD_REQUEST.len() - mem::size_of::<fuse_in_header>()));
+        // Larger arguments are stored on the heap
+        let link = vec![b'x'; 1000];
+        let len = mem::size_of::<fuse_in_header>() + 2 + link.len() + 1;
+        let mut data = MKNOD_REQUEST[..mem::size_of::<fuse_in_header>()].to_vec();
+        data[..4].copy_from_slice(&(len as u32).to_ne_bytes());
+        data[4..8].copy_from_slice(&(fuse_opcode::FUSE_SYMLINK as u32).to_ne_bytes());
+        data.extend_from_slice(b"a\0");
+        data.extend_from_slice(&link);
+        data.push(0);
+        let req = Request::try_from(&data[..]).unwrap();
+        assert!(matches!(req.args, Args::Heap(_)));
+        match req.operation() {
+            Operation::SymLink { name, link: target } => assert_eq!((name.as_bytes(), target.as_bytes()), (&b"a"[..], &link[..])),
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn rename2() {
+        let mut data = MKNOD_REQUEST[..mem::size_of::<fuse_in_header>()].to_vec();
+        data[4..8].copy_from_slice(&(fuse_opcode::FUSE_RENAME2 as u32).to_ne_bytes());
+        data.extend_from_slice(&0x42u64.to_ne_bytes());
+        data.extend_from_slice(&consts::RENAME_NOREPLACE.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+        data.extend_from_slice(b"a\0b\0");
+        let len = data.len() as u32;
+        data[..4].copy_from_slice(&len.to_ne_bytes());
+        let req = Request::try_from(&data[..]).unwrap();
+        match req.operation() {
+            Operation::Rename2 { arg, name, newname } => {
+                assert_eq!((arg.newdir, arg.flags), (0x42, consts::RENAME_NOREPLACE));
+                assert_eq!((name, newname), (OsStr::new("a"), OsStr::new("b")));
             }
             _ => panic!("Unexpected request operation"),
         }
+        assert!(req.to_string().ends_with("RENAME2 name \"a\", newdir 0x0000000000000042, newname \"b\", flags 0x1"));
+    }
+
+    #[test]
+    fn garbage() {
+        // Arguments of any length and content are either parsed or rejected, never misread
+        for opcode in (0..64).chain(Some(4096)) {
+            for len in 0..=96 {
+                let mut data = MKNOD_REQUEST[..mem::size_of::<fuse_in_header>()].to_vec();
+                data.extend((0..len).map(|i| if i % 7 == 6 { 0 } else { 0xa5 }));
+                let total = data.len() as u32;
+                data[..4].copy_from_slice(&total.to_ne_bytes());
+                data[4..8].copy_from_slice(&(opcode as u32).to_ne_bytes());
+                match Request::try_from(&data[..]) {
+                    Ok(req) => { let _ = req.to_string(); }
+                    Err(RequestError::InsufficientData) | Err(RequestError::UnknownOperation(_)) => (),
+                    Err(err) => panic!("Unexpected error {} for opcode {}", err, opcode),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unaligned() {
+        let mut data = vec![0; INIT_REQUEST.len() + 1];
+        data[1..].copy_from_slice(&INIT_REQUEST);
+        let req = Request::try_from(&data[1..]).unwrap();
+        assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
     }
 }
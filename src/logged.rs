@@ -0,0 +1,424 @@
+//! Logging filesystem
+//!
+//! `LoggedFs` wraps a filesystem and logs every operation with its arguments, result and
+//! duration, which is usually the first thing needed when debugging a filesystem.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use async_trait::async_trait;
+use log::{log, Level};
+
+use crate::layer::Wrapper;
+#[cfg(target_os = "macos")]
+use crate::XTimes;
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+
+/// Filesystem wrapper logging every operation. Successful operations are logged at debug
+/// level and failed operations at info level by default.
+#[derive(Debug)]
+pub struct LoggedFs<FS> {
+    inner: FS,
+    level: Level,
+    error_level: Level,
+}
+
+impl<FS> LoggedFs<FS> {
+    /// Log the operations of the given filesystem
+    pub fn new(inner: FS) -> LoggedFs<FS> {
+        LoggedFs { inner, level: Level::Debug, error_level: Level::Info }
+    }
+
+    /// Set the level of successful operations
+    pub fn level(mut self, level: Level) -> LoggedFs<FS> {
+        self.level = level;
+        self
+    }
+
+    /// Set the level of failed operations
+    pub fn error_level(mut self, level: Level) -> LoggedFs<FS> {
+        self.error_level = level;
+        self
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    fn log<T: fmt::Debug>(&self, req: &Request, started: Instant, op: fmt::Arguments<'_>, result: &Result<T, Errno>) {
+        let level = if result.is_ok() { self.level } else { self.error_level };
+        log!(level, "[{}] {} -> {:?} in {:?}", req.unique(), op, result, started.elapsed());
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for LoggedFs<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.init(req).await;
+        self.log(req, started, format_args!("init()"), &result);
+        result
+    }
+
+    async fn destroy(&self, req: &Request) {
+        let started = Instant::now();
+        self.inner.destroy(req).await;
+        self.log(req, started, format_args!("destroy()"), &Ok::<(), Errno>(()));
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let started = Instant::now();
+        let result = self.inner.lookup(req, parent, name).await;
+        self.log(req, started, format_args!("lookup(parent: {}, name: {:?})", parent, name), &result);
+        result
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        let started = Instant::now();
+        self.inner.forget(req, ino, nlookup).await;
+        self.log(req, started, format_args!("forget(ino: {}, nlookup: {})", ino, nlookup), &Ok::<(), Errno>(()));
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        let started = Instant::now();
+        let result = self.inner.getattr(req, ino).await;
+        self.log(req, started, format_args!("getattr(ino: {})", ino), &result);
+        result
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let started = Instant::now();
+        let result = self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await;
+        self.log(req, started, format_args!("setattr(ino: {}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}, atime: {:?}, mtime: {:?}, fh: {:?}, crtime: {:?}, chgtime: {:?}, bkuptime: {:?}, flags: {:?})", ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags), &result);
+        result
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        let started = Instant::now();
+        let result = self.inner.readlink(req, ino).await;
+        self.log(req, started, format_args!("readlink(ino: {})", ino), &result);
+        result
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let started = Instant::now();
+        let result = self.inner.mknod(req, parent, name, mode, rdev).await;
+        self.log(req, started, format_args!("mknod(parent: {}, name: {:?}, mode: {}, rdev: {})", parent, name, mode, rdev), &result);
+        result
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let started = Instant::now();
+        let result = self.inner.mkdir(req, parent, name, mode).await;
+        self.log(req, started, format_args!("mkdir(parent: {}, name: {:?}, mode: {})", parent, name, mode), &result);
+        result
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.unlink(req, parent, name).await;
+        self.log(req, started, format_args!("unlink(parent: {}, name: {:?})", parent, name), &result);
+        result
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.rmdir(req, parent, name).await;
+        self.log(req, started, format_args!("rmdir(parent: {}, name: {:?})", parent, name), &result);
+        result
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let started = Instant::now();
+        let result = self.inner.symlink(req, parent, name, link).await;
+        self.log(req, started, format_args!("symlink(parent: {}, name: {:?}, link: {:?})", parent, name, link), &result);
+        result
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.rename(req, parent, name, newparent, newname).await;
+        self.log(req, started, format_args!("rename(parent: {}, name: {:?}, newparent: {}, newname: {:?})", parent, name, newparent, newname), &result);
+        result
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let started = Instant::now();
+        let result = self.inner.link(req, ino, newparent, newname).await;
+        self.log(req, started, format_args!("link(ino: {}, newparent: {}, newname: {:?})", ino, newparent, newname), &result);
+        result
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        let started = Instant::now();
+        let result = self.inner.open(req, ino, flags).await;
+        self.log(req, started, format_args!("open(ino: {}, flags: {})", ino, flags), &result);
+        result
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let started = Instant::now();
+        let result = self.inner.read(req, ino, fh, offset, size).await;
+        self.log(req, started, format_args!("read(ino: {}, fh: {}, offset: {}, size: {})", ino, fh, offset, size), &result);
+        result
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let started = Instant::now();
+        let result = self.inner.write(req, ino, fh, offset, data, flags).await;
+        self.log(req, started, format_args!("write(ino: {}, fh: {}, offset: {}, data: {} bytes, flags: {})", ino, fh, offset, data.len(), flags), &result);
+        result
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.flush(req, ino, fh, lock_owner).await;
+        self.log(req, started, format_args!("flush(ino: {}, fh: {}, lock_owner: {})", ino, fh, lock_owner), &result);
+        result
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.release(req, ino, fh, flags, lock_owner, flush).await;
+        self.log(req, started, format_args!("release(ino: {}, fh: {}, flags: {}, lock_owner: {}, flush: {})", ino, fh, flags, lock_owner, flush), &result);
+        result
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.fsync(req, ino, fh, datasync).await;
+        self.log(req, started, format_args!("fsync(ino: {}, fh: {}, datasync: {})", ino, fh, datasync), &result);
+        result
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        let started = Instant::now();
+        let result = self.inner.opendir(req, ino, flags).await;
+        self.log(req, started, format_args!("opendir(ino: {}, flags: {})", ino, flags), &result);
+        result
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        let started = Instant::now();
+        let result = self.inner.readdir(req, ino, fh, offset).await;
+        self.log(req, started, format_args!("readdir(ino: {}, fh: {}, offset: {})", ino, fh, offset), &result);
+        result
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.releasedir(req, ino, fh, flags).await;
+        self.log(req, started, format_args!("releasedir(ino: {}, fh: {}, flags: {})", ino, fh, flags), &result);
+        result
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.fsyncdir(req, ino, fh, datasync).await;
+        self.log(req, started, format_args!("fsyncdir(ino: {}, fh: {}, datasync: {})", ino, fh, datasync), &result);
+        result
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        let started = Instant::now();
+        let result = self.inner.statfs(req, ino).await;
+        self.log(req, started, format_args!("statfs(ino: {})", ino), &result);
+        result
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.setxattr(req, ino, name, value, flags, position).await;
+        self.log(req, started, format_args!("setxattr(ino: {}, name: {:?}, value: {} bytes, flags: {}, position: {})", ino, name, value.len(), flags, position), &result);
+        result
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        let started = Instant::now();
+        let result = self.inner.getxattr(req, ino, name, size).await;
+        self.log(req, started, format_args!("getxattr(ino: {}, name: {:?}, size: {})", ino, name, size), &result);
+        result
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        let started = Instant::now();
+        let result = self.inner.listxattr(req, ino, size).await;
+        self.log(req, started, format_args!("listxattr(ino: {}, size: {})", ino, size), &result);
+        result
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.removexattr(req, ino, name).await;
+        self.log(req, started, format_args!("removexattr(ino: {}, name: {:?})", ino, name), &result);
+        result
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.access(req, ino, mask).await;
+        self.log(req, started, format_args!("access(ino: {}, mask: {})", ino, mask), &result);
+        result
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let started = Instant::now();
+        let result = self.inner.create(req, parent, name, mode, flags).await;
+        self.log(req, started, format_args!("create(parent: {}, name: {:?}, mode: {}, flags: {})", parent, name, mode, flags), &result);
+        result
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        let started = Instant::now();
+        let result = self.inner.getlk(req, ino, fh, lock_owner, start, end, typ, pid).await;
+        self.log(req, started, format_args!("getlk(ino: {}, fh: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {})", ino, fh, lock_owner, start, end, typ, pid), &result);
+        result
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await;
+        self.log(req, started, format_args!("setlk(ino: {}, fh: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {}, sleep: {})", ino, fh, lock_owner, start, end, typ, pid, sleep), &result);
+        result
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        let started = Instant::now();
+        let result = self.inner.bmap(req, ino, blocksize, idx).await;
+        self.log(req, started, format_args!("bmap(ino: {}, blocksize: {}, idx: {})", ino, blocksize, idx), &result);
+        result
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        let started = Instant::now();
+        let result = self.inner.ioctl(req, ino, fh, flags, cmd, arg, data, out_size).await;
+        self.log(req, started, format_args!("ioctl(ino: {}, fh: {}, flags: {:?}, cmd: {:#x}, arg: {:#x}, data: {} bytes, out_size: {})", ino, fh, flags, cmd, arg, data.len(), out_size), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.fallocate(req, ino, fh, offset, length, mode).await;
+        self.log(req, started, format_args!("fallocate(ino: {}, fh: {}, offset: {}, length: {}, mode: {:#x})", ino, fh, offset, length, mode), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        let started = Instant::now();
+        let result = self.inner.readdirplus(req, ino, fh, offset).await;
+        self.log(req, started, format_args!("readdirplus(ino: {}, fh: {}, offset: {})", ino, fh, offset), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.rename2(req, parent, name, newparent, newname, flags).await;
+        self.log(req, started, format_args!("rename2(parent: {}, name: {:?}, newparent: {}, newname: {:?}, flags: {:#x})", parent, name, newparent, newname, flags), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        let started = Instant::now();
+        let result = self.inner.lseek(req, ino, fh, offset, whence).await;
+        self.log(req, started, format_args!("lseek(ino: {}, fh: {}, offset: {}, whence: {})", ino, fh, offset, whence), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        let started = Instant::now();
+        let result = self.inner.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await;
+        self.log(req, started, format_args!("copy_file_range(ino_in: {}, fh_in: {}, offset_in: {}, ino_out: {}, fh_out: {}, offset_out: {}, len: {}, flags: {:#x})", ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.syncfs(req, ino).await;
+        self.log(req, started, format_args!("syncfs(ino: {})", ino), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let started = Instant::now();
+        let result = self.inner.tmpfile(req, parent, mode, flags).await;
+        self.log(req, started, format_args!("tmpfile(parent: {}, mode: {}, flags: {})", parent, mode, flags), &result);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        let started = Instant::now();
+        let result = self.inner.statx(req, ino, fh, flags, mask).await;
+        self.log(req, started, format_args!("statx(ino: {}, fh: {:?}, flags: {:#x}, mask: {:#x})", ino, fh, flags, mask), &result);
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.setvolname(req, name).await;
+        self.log(req, started, format_args!("setvolname(name: {:?})", name), &result);
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        let started = Instant::now();
+        let result = self.inner.exchange(req, parent, name, newparent, newname, options).await;
+        self.log(req, started, format_args!("exchange(parent: {}, name: {:?}, newparent: {}, newname: {:?}, options: {})", parent, name, newparent, newname, options), &result);
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        let started = Instant::now();
+        let result = self.inner.getxtimes(req, ino).await;
+        self.log(req, started, format_args!("getxtimes(ino: {})", ino), &result);
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use async_trait::async_trait;
+    use log::Level;
+    use crate::{block_on, Data, Errno, Filesystem, Request};
+    use super::LoggedFs;
+
+    struct Inner;
+
+    #[async_trait]
+    impl Filesystem for Inner {
+        async fn read(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, _size: u32) -> Result<Data, Errno> {
+            Ok(Data::from_static(&b"foo"[offset as usize..]))
+        }
+    }
+
+    #[test]
+    fn passes_results() {
+        let req = Request::test(0, 0);
+        let fs = LoggedFs::new(Inner).level(Level::Trace).error_level(Level::Warn);
+        assert_eq!(&*block_on(Filesystem::read(&fs, &req, 2, 0, 1, 4096)).unwrap(), b"oo");
+        assert_eq!(block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new("foo"))).unwrap_err(), Errno::ENOSYS);
+        block_on(Filesystem::forget(&fs, &req, 2, 1));
+    }
+}
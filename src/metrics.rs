@@ -0,0 +1,254 @@
+//! Operation metrics
+//!
+//! A session can count the requests it receives and measure the time until they're replied
+//! to, by operation. The metrics can be read as a snapshot to feed them into any monitoring
+//! system. With the `prometheus` feature, they can be encoded in the Prometheus text format
+//! and served to a Prometheus scraper.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use fuse_abi::fuse_opcode;
+
+/// Number of opcodes metrics are kept for (higher opcodes are not counted)
+const OPCODES: usize = 64;
+
+/// Upper bounds of the latency histogram buckets
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(100), Duration::from_micros(500),
+    Duration::from_millis(1), Duration::from_millis(5),
+    Duration::from_millis(10), Duration::from_millis(50),
+    Duration::from_millis(100), Duration::from_millis(500),
+    Duration::from_secs(1), Duration::from_secs(5),
+];
+
+/// Counters of a single operation
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency_sum: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// Request counters and latency histograms by operation, shared with a session
+pub struct Metrics {
+    ops: Vec<Counters>,
+}
+
+impl Metrics {
+    /// Create empty metrics
+    pub fn new() -> Metrics {
+        Metrics { ops: (0..OPCODES).map(|_| Counters::default()).collect() }
+    }
+
+    fn counters(&self, opcode: fuse_opcode) -> Option<&Counters> {
+        self.ops.get(opcode as usize)
+    }
+
+    /// Count a received request
+    pub(crate) fn request(&self, opcode: fuse_opcode) {
+        if let Some(counters) = self.counters(opcode) {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a reply that was sent the given time after receiving its request
+    pub(crate) fn reply(&self, opcode: fuse_opcode, error: bool, latency: Duration) {
+        if let Some(counters) = self.counters(opcode) {
+            if error {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            counters.latency_sum.fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+            let bucket = LATENCY_BUCKETS.iter().position(|bound| latency <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+            counters.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the metrics of every operation that was requested at least once
+    pub fn operations(&self) -> Vec<OperationMetrics> {
+        self.ops.iter().enumerate().filter_map(|(opcode, counters)| {
+            let requests = counters.requests.load(Ordering::Relaxed);
+            let opcode = fuse_opcode::try_from(opcode as u32).ok()?;
+            if requests == 0 { return None; }
+            Some(OperationMetrics {
+                name: operation_name(opcode),
+                requests,
+                errors: counters.errors.load(Ordering::Relaxed),
+                latency_sum: Duration::from_nanos(counters.latency_sum.load(Ordering::Relaxed)),
+                latency_buckets: counters.latency_buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            })
+        }).collect()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_list().entries(self.operations()).finish()
+    }
+}
+
+/// Returns the name of an operation in lower case (e.g. `lookup` for `FUSE_LOOKUP`)
+pub(crate) fn operation_name(opcode: fuse_opcode) -> String {
+    let name = format!("{:?}", opcode);
+    name.strip_prefix("FUSE_").unwrap_or(&name).to_lowercase()
+}
+
+/// Snapshot of the metrics of an operation
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OperationMetrics {
+    /// Name of the operation in lower case (e.g. `lookup`)
+    pub name: String,
+    /// Number of received requests
+    pub requests: u64,
+    /// Number of requests replied to with an error
+    pub errors: u64,
+    /// Total time from receiving requests until replying to them
+    pub latency_sum: Duration,
+    /// Number of replies by latency, in the buckets given by `LATENCY_BUCKETS` and a last
+    /// bucket for replies that took longer. Operations that aren't replied to (forget,
+    /// interrupt) are never counted.
+    pub latency_buckets: Vec<u64>,
+}
+
+impl OperationMetrics {
+    /// Returns the number of replies
+    pub fn replies(&self) -> u64 {
+        self.latency_buckets.iter().sum()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use std::fmt::Write as _;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use log::{debug, error};
+    use super::{Metrics, LATENCY_BUCKETS};
+
+    impl Metrics {
+        /// Encode the metrics in the Prometheus text exposition format
+        pub fn encode(&self) -> String {
+            let ops = self.operations();
+            let mut out = String::new();
+            out.push_str("# HELP fuse_requests_total Number of received FUSE requests.\n");
+            out.push_str("# TYPE fuse_requests_total counter\n");
+            for op in &ops {
+                let _ = writeln!(out, "fuse_requests_total{{op=\"{}\"}} {}", op.name, op.requests);
+            }
+            out.push_str("# HELP fuse_errors_total Number of FUSE requests replied to with an error.\n");
+            out.push_str("# TYPE fuse_errors_total counter\n");
+            for op in &ops {
+                let _ = writeln!(out, "fuse_errors_total{{op=\"{}\"}} {}", op.name, op.errors);
+            }
+            out.push_str("# HELP fuse_request_duration_seconds Time from receiving FUSE requests until replying to them.\n");
+            out.push_str("# TYPE fuse_request_duration_seconds histogram\n");
+            for op in &ops {
+                let mut count = 0;
+                for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&op.latency_buckets) {
+                    count += bucket;
+                    let _ = writeln!(out, "fuse_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}", op.name, bound.as_secs_f64(), count);
+                }
+                count += op.latency_buckets[LATENCY_BUCKETS.len()];
+                let _ = writeln!(out, "fuse_request_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}", op.name, count);
+                let _ = writeln!(out, "fuse_request_duration_seconds_sum{{op=\"{}\"}} {}", op.name, op.latency_sum.as_secs_f64());
+                let _ = writeln!(out, "fuse_request_duration_seconds_count{{op=\"{}\"}} {}", op.name, count);
+            }
+            out
+        }
+
+        /// Serve the metrics to Prometheus scrapers connecting to the given listener, in a
+        /// background thread. Every HTTP request is answered with the encoded metrics,
+        /// regardless of its path.
+        pub fn serve(self: Arc<Self>, listener: TcpListener) -> JoinHandle<()> {
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let res = stream.and_then(|mut stream| {
+                        // Skip the request line and headers up to the empty line
+                        let mut reader = BufReader::new(stream.try_clone()?);
+                        let mut line = String::new();
+                        while reader.read_line(&mut line)? > 2 { line.clear(); }
+                        let body = self.encode();
+                        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                    });
+                    match res {
+                        Ok(()) => (),
+                        Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset => debug!("Metrics scraper went away: {}", err),
+                        Err(err) => error!("Failed to serve metrics: {}", err),
+                    }
+                }
+            })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Errno, Filesystem, MockChannel, FUSE_ROOT_ID};
+    use super::{operation_name, Metrics, LATENCY_BUCKETS};
+
+    #[test]
+    fn operations() {
+        let metrics = Metrics::new();
+        assert!(metrics.operations().is_empty());
+        metrics.request(fuse_opcode::FUSE_LOOKUP);
+        metrics.request(fuse_opcode::FUSE_LOOKUP);
+        metrics.reply(fuse_opcode::FUSE_LOOKUP, false, Duration::from_micros(50));
+        metrics.reply(fuse_opcode::FUSE_LOOKUP, true, Duration::from_secs(10));
+        let ops = metrics.operations();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].name, "lookup");
+        assert_eq!(ops[0].requests, 2);
+        assert_eq!(ops[0].errors, 1);
+        assert_eq!(ops[0].replies(), 2);
+        assert_eq!(ops[0].latency_sum, Duration::from_micros(10_000_050));
+        assert_eq!(ops[0].latency_buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(ops[0].latency_buckets[0], 1);
+        assert_eq!(ops[0].latency_buckets[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(operation_name(fuse_opcode::FUSE_GETXATTR), "getxattr");
+    }
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    #[test]
+    fn session() {
+        let metrics = Arc::new(Metrics::new());
+        let ch = MockChannel::new(Empty).metrics(metrics.clone());
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap_err(), Errno::ENOSYS);
+        let ops = metrics.operations();
+        assert_eq!(ops.iter().map(|op| (op.name.as_str(), op.requests, op.errors, op.replies())).collect::<Vec<_>>(), [("getattr", 1, 1, 1), ("init", 1, 0, 1)]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn encode() {
+        let metrics = Metrics::new();
+        metrics.request(fuse_opcode::FUSE_GETATTR);
+        metrics.reply(fuse_opcode::FUSE_GETATTR, false, Duration::from_millis(2));
+        let text = metrics.encode();
+        assert!(text.contains("fuse_requests_total{op=\"getattr\"} 1\n"));
+        assert!(text.contains("fuse_errors_total{op=\"getattr\"} 0\n"));
+        assert!(text.contains("fuse_request_duration_seconds_bucket{op=\"getattr\",le=\"0.001\"} 0\n"));
+        assert!(text.contains("fuse_request_duration_seconds_bucket{op=\"getattr\",le=\"0.005\"} 1\n"));
+        assert!(text.contains("fuse_request_duration_seconds_bucket{op=\"getattr\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("fuse_request_duration_seconds_count{op=\"getattr\"} 1\n"));
+    }
+}
@@ -0,0 +1,736 @@
+//! Mock kernel driver
+//!
+//! A mock channel runs a filesystem in a session that isn't mounted. Tests pass crafted requests
+//! to it the way the kernel driver would, and get back the serialized replies to assert on. This
+//! tests the whole request parsing, dispatching and reply serialization without needing root
+//! privileges or a FUSE kernel driver.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+#[cfg(not(target_os = "macos"))]
+use std::time::UNIX_EPOCH;
+use std::{mem, ptr, slice};
+use fuse_abi::*;
+
+use crate::admission::Admission;
+use crate::timeout::Timeouts;
+use crate::idmap::IdMap;
+use crate::attr::system_time;
+use crate::jsonlog::JsonLog;
+use crate::lifecycle::Lifecycle;
+use crate::redact::Redaction;
+use crate::executor::Executor;
+use crate::metrics::Metrics;
+use crate::observer::Observer;
+use crate::reply::ReplySender;
+use crate::request::Request;
+use crate::resend::ReplyCache;
+use crate::session::{ConnectionInfo, Session};
+use crate::{Attr, BackingId, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, InitFlags, Opened, OpenFlags, Statfs};
+#[cfg(not(target_os = "macos"))]
+use crate::{DirEntryPlus, Ioctl, IoctlFlags, IoctlIovec};
+
+/// Reply sender that collects the replies of a mock channel by their request's unique id
+#[derive(Debug, Default)]
+struct MockSender {
+    replies: Mutex<HashMap<u64, Vec<u8>>>,
+}
+
+impl ReplySender for MockSender {
+    fn send(&self, data: &[&[u8]]) {
+        let reply = MockReply::new(data.concat());
+        self.replies.lock().unwrap().insert(reply.unique(), reply.data);
+    }
+}
+
+/// Serialized reply to a request sent through a mock channel
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MockReply {
+    data: Vec<u8>,
+}
+
+impl MockReply {
+    pub(crate) fn new(data: Vec<u8>) -> MockReply {
+        MockReply { data }
+    }
+
+    fn header(&self) -> fuse_out_header {
+        assert!(self.data.len() >= mem::size_of::<fuse_out_header>(), "Short FUSE reply ({} bytes)", self.data.len());
+        unsafe { ptr::read_unaligned(self.data.as_ptr() as *const fuse_out_header) }
+    }
+
+    /// Returns the unique identifier of the request this is a reply to
+    pub fn unique(&self) -> u64 {
+        self.header().unique
+    }
+
+    /// Returns the data following the reply header on success, or the error code of the reply
+    pub fn result(&self) -> Result<&[u8], Errno> {
+        match self.header().error {
+            0 => Ok(&self.data[mem::size_of::<fuse_out_header>()..]),
+            err => Err(Errno::from_raw(-err)),
+        }
+    }
+
+    /// Returns the serialized reply, starting with the reply header
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Decodes the data of a successful reply as the given `fuse_*_out` type
+    fn decode<T: Copy>(&self) -> Result<T, Errno> {
+        let data = self.result()?;
+        assert!(data.len() >= mem::size_of::<T>(), "Short FUSE reply data ({} < {} bytes)", data.len(), mem::size_of::<T>());
+        Ok(unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })
+    }
+}
+
+/// Channel that passes requests directly to a session instead of receiving them from the kernel
+#[derive(Debug)]
+pub struct MockChannel<FS: Filesystem + Send + Sync + 'static> {
+    session: Arc<Session<FS>>,
+    sender: Arc<MockSender>,
+    next_unique: AtomicU64,
+    uid: u32,
+    gid: u32,
+}
+
+impl<FS: Filesystem + Send + Sync + 'static> MockChannel<FS> {
+    /// Create a mock channel to an unmounted session running the given filesystem
+    pub fn new(filesystem: FS) -> MockChannel<FS> {
+        MockChannel::with_options(filesystem, &[])
+    }
+
+    /// Create a mock channel to a session running the given filesystem with the given mount
+    /// options (e.g. `default_permissions`)
+    pub fn with_options(filesystem: FS, options: &[OsString]) -> MockChannel<FS> {
+        MockChannel {
+            session: Arc::new(Session::unmounted(filesystem, options)),
+            sender: Arc::default(),
+            next_unique: AtomicU64::new(1),
+            uid: 0,
+            gid: 0,
+        }
+    }
+
+    /// Send further requests on behalf of the given user (root by default)
+    pub fn user(mut self, uid: u32, gid: u32) -> MockChannel<FS> {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Set the capability flags the session enables if the kernel supports them, like
+    /// `Mount::init_flags` does
+    pub fn init_flags(mut self, flags: InitFlags) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").init_flags = flags;
+        self
+    }
+
+    /// Reply ENOSYS right away to operations the filesystem replied ENOSYS to before, like a
+    /// session mounted with `Mount::cache_enosys` does
+    pub fn cache_enosys(mut self, cache_enosys: bool) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").cache_enosys = cache_enosys;
+        self
+    }
+
+    /// Add the `.` and `..` entries to directory listings, like a session mounted with
+    /// `Mount::dot_entries` does
+    pub fn dot_entries(mut self, dot_entries: bool) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").dot_entries = dot_entries;
+        self
+    }
+
+    /// Open files that are opened with O_DIRECT in direct I/O mode, like a session mounted with
+    /// `Mount::direct_io_on_o_direct` does
+    pub fn direct_io_on_o_direct(mut self, direct_io_on_o_direct: bool) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").direct_io_on_o_direct = direct_io_on_o_direct;
+        self
+    }
+
+    /// Negotiate passthrough of reads and writes to backing files, like a session mounted with
+    /// `Mount::passthrough` does
+    pub fn passthrough(mut self, passthrough: bool) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").passthrough = passthrough;
+        self
+    }
+
+    /// Negotiate that the kernel sends the supplementary group of callers creating inodes, like
+    /// a session mounted with `Mount::create_supp_group` does
+    pub fn create_supp_group(mut self, create_supp_group: bool) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").create_supp_group = create_supp_group;
+        self
+    }
+
+    /// Remember the replies of the latest requests that change the filesystem, like a session
+    /// mounted with `Mount::resend_replies` does
+    pub fn resend_replies(mut self, capacity: usize) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").resend_replies = Some(Arc::new(ReplyCache::new(capacity)));
+        self
+    }
+
+    /// Count requests and measure their latency in the given metrics, like a session mounted
+    /// with `Mount::metrics` does
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").metrics = Some(metrics);
+        self
+    }
+
+    /// Log completed operations to the given JSON log, like a session mounted with
+    /// `Mount::json_log` does
+    pub fn json_log(mut self, log: JsonLog) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").json_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Watch completed operations with the given observer, like a session mounted with
+    /// `Mount::observer` does
+    pub fn observer<O: Observer>(mut self, observer: Arc<O>) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").observer = Some(observer);
+        self
+    }
+
+    /// Limit the requests of every user, like a session mounted with `Mount::admission` does
+    pub fn admission(mut self, admission: Admission) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").admission = Some(Arc::new(admission));
+        self
+    }
+
+    /// Give up on operations at their timeouts, like a session mounted with `Mount::timeouts`
+    /// does
+    pub fn timeouts(mut self, timeouts: Timeouts) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").timeouts = timeouts;
+        self
+    }
+
+    /// Call the hooks of the given lifecycle, like a session mounted with `Mount::lifecycle`
+    /// does
+    pub fn lifecycle(mut self, lifecycle: Lifecycle) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").lifecycle = lifecycle;
+        self
+    }
+
+    /// Redact the names of files in log output, like a session mounted with `Mount::redaction`
+    /// does
+    pub fn redaction(mut self, redaction: Redaction) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").redaction = redaction;
+        self
+    }
+
+    /// Map user and group ids, like a session mounted with `Mount::id_map` does
+    pub fn id_map(mut self, id_map: IdMap) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").id_map = Some(Arc::new(id_map));
+        self
+    }
+
+    /// Set the executor that runs background tasks of the session, like observers (requests
+    /// are dispatched by the mock channel directly)
+    pub fn executor<E: Executor>(mut self, executor: E) -> MockChannel<FS> {
+        Arc::get_mut(&mut self.session).expect("session in use").executor = Arc::new(executor);
+        self
+    }
+
+    /// Returns the session running the filesystem
+    pub fn session(&self) -> &Session<FS> {
+        &self.session
+    }
+
+    /// Send the given serialized request (starting with a request header) and return its
+    /// reply. Returns `None` if the request is invalid or wasn't replied to (e.g. forget).
+    pub async fn send(&self, data: &[u8]) -> Option<MockReply> {
+        let req = Request::new(self.sender.clone(), data)?;
+        let unique = req.unique();
+        if !req.reply_unimplemented(&self.session) {
+            req.dispatch(self.session.clone()).await;
+        }
+        let data = self.sender.replies.lock().unwrap().remove(&unique)?;
+        Some(MockReply { data })
+    }
+
+    /// Returns the reply to the request of the given unique id that was sent without being
+    /// replied to right away, e.g. a resent request that gets the reply of the original request
+    pub fn reply(&self, unique: u64) -> Option<MockReply> {
+        let data = self.sender.replies.lock().unwrap().remove(&unique)?;
+        Some(MockReply { data })
+    }
+
+    /// Send a request with the given opcode (FUSE_*) for the given inode, followed by the given
+    /// serialized arguments, and return its reply
+    pub async fn request(&self, opcode: u32, nodeid: u64, arg: &[u8]) -> Option<MockReply> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode,
+            unique: self.next_unique.fetch_add(1, Ordering::Relaxed),
+            nodeid,
+            uid: self.uid,
+            gid: self.gid,
+            pid: std::process::id(),
+            total_extlen: 0,
+            padding: 0,
+        };
+        let mut data = as_bytes(&header).to_vec();
+        data.extend_from_slice(arg);
+        self.send(&data).await
+    }
+
+    /// Send a request and return its reply, panicking if it wasn't replied to
+    async fn call(&self, opcode: fuse_opcode, nodeid: u64, arg: &[u8]) -> MockReply {
+        self.request(opcode as u32, nodeid, arg).await
+            .unwrap_or_else(|| panic!("No reply to FUSE request {:?}", opcode))
+    }
+
+    /// Initialize the session like the kernel does after mounting and return the negotiated
+    /// connection parameters. Any other request fails before.
+    pub async fn init(&self) -> Result<ConnectionInfo, Errno> {
+        self.init_capable(InitFlags::ASYNC_READ).await
+    }
+
+    /// Initialize the session like a kernel supporting the given capability flags does
+    pub async fn init_capable(&self, capable: InitFlags) -> Result<ConnectionInfo, Errno> {
+        let arg = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 128 * 1024,
+            flags: capable.bits(),
+        };
+        self.call(fuse_opcode::FUSE_INIT, 0, as_bytes(&arg)).await.result()?;
+        Ok(self.session.connection())
+    }
+
+    /// Initialize the session like a kernel supporting passthrough to backing files (Linux 6.9
+    /// and later) does, which sends the extended init request
+    #[cfg(not(target_os = "macos"))]
+    pub async fn init_passthrough(&self) -> Result<ConnectionInfo, Errno> {
+        self.init_extended(InitFlags::ASYNC_READ, consts::FUSE_PASSTHROUGH).await
+    }
+
+    /// Initialize the session like a kernel supporting the given capability flags and the given
+    /// raw flags2 does, which sends the extended init request (ABI 7.36)
+    #[cfg(not(target_os = "macos"))]
+    pub async fn init_extended(&self, capable: InitFlags, capable2: u32) -> Result<ConnectionInfo, Errno> {
+        let arg = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 128 * 1024,
+            flags: capable.bits() | consts::FUSE_INIT_EXT,
+        };
+        let mut data = as_bytes(&arg).to_vec();
+        data.extend_from_slice(&capable2.to_ne_bytes());
+        data.extend_from_slice(&[0; 11 * 4]);
+        self.call(fuse_opcode::FUSE_INIT, 0, &data).await.result()?;
+        Ok(self.session.connection())
+    }
+
+    /// Look up the given name in the given directory
+    pub async fn lookup<N: AsRef<OsStr>>(&self, parent: u64, name: N) -> Result<Entry, Errno> {
+        let out: fuse_entry_out = self.call(fuse_opcode::FUSE_LOOKUP, parent, &cstr(name.as_ref())).await.decode()?;
+        Ok(entry_from_fuse_entry_out(&out))
+    }
+
+    /// Change the size of the given inode, through the given file handle if it's open
+    pub async fn truncate(&self, ino: u64, fh: Option<u64>, size: u64) -> Result<Attr, Errno> {
+        let mut arg: fuse_setattr_in = unsafe { mem::zeroed() };
+        arg.valid = consts::FATTR_SIZE;
+        arg.size = size;
+        if let Some(fh) = fh {
+            arg.valid |= consts::FATTR_FH;
+            arg.fh = fh;
+        }
+        let out: fuse_attr_out = self.call(fuse_opcode::FUSE_SETATTR, ino, as_bytes(&arg)).await.decode()?;
+        Ok(Attr {
+            ttl: Some(Duration::new(out.attr_valid, out.attr_valid_nsec)),
+            attr: attr_from_fuse_attr(&out.attr),
+        })
+    }
+
+    /// Create a file node of the given mode (type and permissions) and device number in the
+    /// given directory
+    pub async fn mknod<N: AsRef<OsStr>>(&self, parent: u64, name: N, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let mut arg: fuse_mknod_in = unsafe { mem::zeroed() };
+        arg.mode = mode;
+        arg.rdev = rdev;
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(&cstr(name.as_ref()));
+        let out: fuse_entry_out = self.call(fuse_opcode::FUSE_MKNOD, parent, &bytes).await.decode()?;
+        Ok(entry_from_fuse_entry_out(&out))
+    }
+
+    /// Create a directory of the given mode in the given directory
+    pub async fn mkdir<N: AsRef<OsStr>>(&self, parent: u64, name: N, mode: u32) -> Result<Entry, Errno> {
+        let mut arg: fuse_mkdir_in = unsafe { mem::zeroed() };
+        arg.mode = mode;
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(&cstr(name.as_ref()));
+        let out: fuse_entry_out = self.call(fuse_opcode::FUSE_MKDIR, parent, &bytes).await.decode()?;
+        Ok(entry_from_fuse_entry_out(&out))
+    }
+
+    /// Remove the given file from the given directory
+    pub async fn unlink<N: AsRef<OsStr>>(&self, parent: u64, name: N) -> Result<(), Errno> {
+        self.call(fuse_opcode::FUSE_UNLINK, parent, &cstr(name.as_ref())).await.result().map(|_| ())
+    }
+
+    /// Remove the given directory from the given directory
+    pub async fn rmdir<N: AsRef<OsStr>>(&self, parent: u64, name: N) -> Result<(), Errno> {
+        self.call(fuse_opcode::FUSE_RMDIR, parent, &cstr(name.as_ref())).await.result().map(|_| ())
+    }
+
+    /// Rename the given name in the given directory to the given new name in the given new
+    /// directory
+    pub async fn rename<N: AsRef<OsStr>, M: AsRef<OsStr>>(&self, parent: u64, name: N, newparent: u64, newname: M) -> Result<(), Errno> {
+        let arg = fuse_rename_in { newdir: newparent };
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(&cstr(name.as_ref()));
+        bytes.extend_from_slice(&cstr(newname.as_ref()));
+        self.call(fuse_opcode::FUSE_RENAME, parent, &bytes).await.result().map(|_| ())
+    }
+
+    /// Rename like `rename` does, with the given flags (RENAME_*)
+    #[cfg(not(target_os = "macos"))]
+    pub async fn rename2<N: AsRef<OsStr>, M: AsRef<OsStr>>(&self, parent: u64, name: N, newparent: u64, newname: M, flags: u32) -> Result<(), Errno> {
+        let arg = fuse_rename2_in { newdir: newparent, flags, padding: 0 };
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(&cstr(name.as_ref()));
+        bytes.extend_from_slice(&cstr(newname.as_ref()));
+        self.call(fuse_opcode::FUSE_RENAME2, parent, &bytes).await.result().map(|_| ())
+    }
+
+    /// Forget the given number of lookups of the given inode
+    pub async fn forget(&self, ino: u64, nlookup: u64) {
+        let arg = fuse_forget_in { nlookup };
+        self.request(fuse_opcode::FUSE_FORGET as u32, ino, as_bytes(&arg)).await;
+    }
+
+    /// Get the attributes of the given inode
+    pub async fn getattr(&self, ino: u64) -> Result<Attr, Errno> {
+        let out: fuse_attr_out = self.call(fuse_opcode::FUSE_GETATTR, ino, &[]).await.decode()?;
+        Ok(Attr {
+            ttl: Some(Duration::new(out.attr_valid, out.attr_valid_nsec)),
+            attr: attr_from_fuse_attr(&out.attr),
+        })
+    }
+
+    /// Open the given file with the given open flags (O_*)
+    pub async fn open(&self, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.open_with(fuse_opcode::FUSE_OPEN, ino, flags).await
+    }
+
+    /// Open the given directory with the given open flags (O_*)
+    pub async fn opendir(&self, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.open_with(fuse_opcode::FUSE_OPENDIR, ino, flags).await
+    }
+
+    async fn open_with(&self, opcode: fuse_opcode, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        let arg = fuse_open_in { flags, unused: 0 };
+        let out: fuse_open_out = self.call(opcode, ino, as_bytes(&arg)).await.decode()?;
+        #[cfg(not(target_os = "macos"))]
+        if out.open_flags & consts::FOPEN_PASSTHROUGH != 0 {
+            let flags = OpenFlags::from_bits(out.open_flags & !consts::FOPEN_PASSTHROUGH);
+            return Ok(Opened { fh: out.fh, flags, backing: Some(BackingId(out.padding)) });
+        }
+        Ok(Opened { fh: out.fh, flags: OpenFlags::from_bits(out.open_flags), backing: None })
+    }
+
+    /// Read up to the given number of bytes at the given offset of an opened file
+    pub async fn read(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.offset = offset;
+        arg.size = size;
+        self.call(fuse_opcode::FUSE_READ, ino, as_bytes(&arg)).await.result().map(|data| data.to_vec())
+    }
+
+    /// Write the given data at the given offset of an opened file. Returns the number of
+    /// bytes written.
+    pub async fn write(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, Errno> {
+        let mut arg: fuse_write_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.offset = offset;
+        arg.size = data.len() as u32;
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(data);
+        let out: fuse_write_out = self.call(fuse_opcode::FUSE_WRITE, ino, &bytes).await.decode()?;
+        Ok(out.size)
+    }
+
+    /// Find the given offset relative to the given position (SEEK_*) in an opened file, and
+    /// return the resulting offset
+    #[cfg(not(target_os = "macos"))]
+    pub async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        let arg = fuse_lseek_in { fh, offset: offset as u64, whence: whence as u32, padding: 0 };
+        let out: fuse_lseek_out = self.call(fuse_opcode::FUSE_LSEEK, ino, as_bytes(&arg)).await.decode()?;
+        Ok(out.offset as i64)
+    }
+
+    /// Call an ioctl with the given flags, command and input data on an opened file, allowing
+    /// the given size of output data
+    #[cfg(not(target_os = "macos"))]
+    pub async fn ioctl(&self, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        let arg = fuse_ioctl_in { fh, flags: flags.bits(), cmd, arg: 0, in_size: data.len() as u32, out_size };
+        let mut bytes = as_bytes(&arg).to_vec();
+        bytes.extend_from_slice(data);
+        let reply = self.call(fuse_opcode::FUSE_IOCTL, ino, &bytes).await;
+        let out: fuse_ioctl_out = reply.decode()?;
+        let rest = &reply.result()?[mem::size_of::<fuse_ioctl_out>()..];
+        if out.flags & consts::FUSE_IOCTL_RETRY == 0 {
+            return Ok(Ioctl::Done(out.result, rest.to_vec()));
+        }
+        let iovs: Vec<IoctlIovec> = rest.chunks_exact(mem::size_of::<fuse_ioctl_iovec>()).map(|iov| {
+            let iov: fuse_ioctl_iovec = unsafe { ptr::read_unaligned(iov.as_ptr() as *const fuse_ioctl_iovec) };
+            IoctlIovec { base: iov.base, len: iov.len }
+        }).collect();
+        let (input, output) = iovs.split_at(out.in_iovs as usize);
+        Ok(Ioctl::Retry(input.to_vec(), output.to_vec()))
+    }
+
+    /// Flush an opened file (on every close of a file descriptor)
+    pub async fn flush(&self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = fuse_flush_in { fh, unused: 0, padding: 0, lock_owner: 0 };
+        self.call(fuse_opcode::FUSE_FLUSH, ino, as_bytes(&arg)).await.result().map(|_| ())
+    }
+
+    /// Release an opened file
+    pub async fn release(&self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = fuse_release_in { fh, flags: 0, release_flags: 0, lock_owner: 0 };
+        self.call(fuse_opcode::FUSE_RELEASE, ino, as_bytes(&arg)).await.result().map(|_| ())
+    }
+
+    /// Read the entries of an opened directory, starting at the given offset, that fit into
+    /// the given number of bytes
+    pub async fn readdir(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<DirEntry>, Errno> {
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.offset = offset;
+        arg.size = size;
+        let reply = self.call(fuse_opcode::FUSE_READDIR, ino, as_bytes(&arg)).await;
+        let mut data = reply.result()?;
+        let mut entries = Vec::new();
+        while data.len() >= mem::size_of::<fuse_dirent>() {
+            let (entry, len) = decode_dirent(data);
+            entries.push(entry);
+            data = &data[len..];
+        }
+        Ok(entries)
+    }
+
+    /// Read the entries of an opened directory together with their lookups, starting at the
+    /// given offset, that fit into the given number of bytes
+    #[cfg(not(target_os = "macos"))]
+    pub async fn readdirplus(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<DirEntryPlus>, Errno> {
+        let mut arg: fuse_read_in = unsafe { mem::zeroed() };
+        arg.fh = fh;
+        arg.offset = offset;
+        arg.size = size;
+        let reply = self.call(fuse_opcode::FUSE_READDIRPLUS, ino, as_bytes(&arg)).await;
+        let mut data = reply.result()?;
+        let mut entries = Vec::new();
+        while data.len() >= mem::size_of::<fuse_entry_out>() + mem::size_of::<fuse_dirent>() {
+            let out = unsafe { ptr::read_unaligned(data.as_ptr() as *const fuse_entry_out) };
+            let (dirent, len) = decode_dirent(&data[mem::size_of::<fuse_entry_out>()..]);
+            entries.push(DirEntryPlus { dirent, entry: entry_from_fuse_entry_out(&out) });
+            data = &data[mem::size_of::<fuse_entry_out>() + len..];
+        }
+        Ok(entries)
+    }
+
+    /// Release an opened directory
+    pub async fn releasedir(&self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = fuse_release_in { fh, flags: 0, release_flags: 0, lock_owner: 0 };
+        self.call(fuse_opcode::FUSE_RELEASEDIR, ino, as_bytes(&arg)).await.result().map(|_| ())
+    }
+
+    /// Get the statistics of the filesystem containing the given inode
+    pub async fn statfs(&self, ino: u64) -> Result<Statfs, Errno> {
+        let out: fuse_statfs_out = self.call(fuse_opcode::FUSE_STATFS, ino, &[]).await.decode()?;
+        let st = out.st;
+        Ok(Statfs { blocks: st.blocks, bfree: st.bfree, bavail: st.bavail, files: st.files, ffree: st.ffree, bsize: st.bsize, namelen: st.namelen, frsize: st.frsize })
+    }
+}
+
+/// Returns the memory of the given value (useful for fuse_*_in types)
+fn as_bytes<T>(data: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(data as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Returns the given name with zero-termination
+/// Decode the directory entry at the start of the given readdir reply data, and return it
+/// together with its padded size
+fn decode_dirent(data: &[u8]) -> (DirEntry, usize) {
+    let dirent = unsafe { ptr::read_unaligned(data.as_ptr() as *const fuse_dirent) };
+    let name = &data[mem::size_of::<fuse_dirent>()..][..dirent.namelen as usize];
+    let entry = DirEntry {
+        ino: dirent.ino,
+        offset: DirOffset::from_raw(dirent.off),
+        kind: FileType::from_mode(dirent.typ << 12).unwrap_or(FileType::RegularFile),
+        name: OsStr::from_bytes(name).to_os_string(),
+    };
+    // Entries are padded to 64 bit
+    let len = mem::size_of::<fuse_dirent>() + name.len();
+    (entry, len.div_ceil(mem::size_of::<u64>()) * mem::size_of::<u64>())
+}
+
+fn cstr(name: &OsStr) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// Returns the Entry of a fuse_entry_out (the inverse of serializing it in a reply)
+fn entry_from_fuse_entry_out(out: &fuse_entry_out) -> Entry {
+    Entry {
+        ttl: Some(Duration::new(out.entry_valid, out.entry_valid_nsec)),
+        attr: attr_from_fuse_attr(&out.attr),
+        generation: out.generation,
+    }
+}
+
+/// Returns the FileAttr of a fuse_attr (the inverse of serializing it in a reply)
+fn attr_from_fuse_attr(attr: &fuse_attr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: system_time(attr.atime as i64, attr.atimensec as i64),
+        mtime: system_time(attr.mtime as i64, attr.mtimensec as i64),
+        ctime: system_time(attr.ctime as i64, attr.ctimensec as i64),
+        #[cfg(target_os = "macos")]
+        crtime: system_time(attr.crtime as i64, attr.crtimensec as i64),
+        #[cfg(not(target_os = "macos"))]
+        crtime: UNIX_EPOCH,
+        kind: FileType::from_mode(attr.mode).unwrap_or(FileType::RegularFile),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        #[cfg(target_os = "macos")]
+        flags: attr.flags,
+        #[cfg(not(target_os = "macos"))]
+        flags: 0,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::time::{Duration, UNIX_EPOCH};
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Attr, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, InitFlags, Request, FUSE_ROOT_ID};
+    use super::MockChannel;
+
+    struct Hello;
+
+    fn attr(ino: u64) -> FileAttr {
+        match ino {
+            FUSE_ROOT_ID => FileAttr::builder(ino, FileType::Directory).perm(0o755).build(),
+            _ => FileAttr::builder(ino, FileType::RegularFile).perm(0o644).size(6).time(UNIX_EPOCH + Duration::new(1, 5)).build(),
+        }
+    }
+
+    #[async_trait]
+    impl Filesystem for Hello {
+        async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            match (parent, name.to_str()) {
+                (FUSE_ROOT_ID, Some("hello")) => Ok(Entry { ttl: Some(Duration::from_secs(5)), attr: attr(2), generation: 7 }),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(attr(ino)))
+        }
+
+        async fn read(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+            let data = &b"hello\n"[(offset as usize).min(6)..];
+            Ok(Data::from_static(&data[..data.len().min(size as usize)]))
+        }
+
+        async fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+            let entries = [(FUSE_ROOT_ID, FileType::Directory, "."), (2, FileType::RegularFile, "hello")];
+            Ok(entries.iter().enumerate().skip(offset.position(0)? as usize).map(|(i, &(ino, kind, name))| {
+                DirEntry { ino, offset: DirOffset::new(i as u64 + 1, 0), kind, name: name.into() }
+            }).collect())
+        }
+    }
+
+    #[test]
+    fn requires_init() {
+        let ch = MockChannel::new(Hello);
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap_err(), Errno::EIO);
+        let conn = block_on(ch.init()).unwrap();
+        assert_eq!(conn.proto_major, 7);
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap().attr.kind, FileType::Directory);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let ch = MockChannel::new(Hello);
+        block_on(ch.init()).unwrap();
+
+        // The creation time is only passed to the kernel on macOS
+        let mut expected = attr(2);
+        if cfg!(not(target_os = "macos")) { expected.crtime = UNIX_EPOCH; }
+        let entry = block_on(ch.lookup(FUSE_ROOT_ID, "hello")).unwrap();
+        assert_eq!(entry, Entry { ttl: Some(Duration::from_secs(5)), attr: expected, generation: 7 });
+        assert_eq!(block_on(ch.lookup(FUSE_ROOT_ID, "nope")).unwrap_err(), Errno::ENOENT);
+        assert_eq!(block_on(ch.getattr(2)).unwrap().ttl, Some(ch.session().attr_ttl));
+
+        let opened = block_on(ch.open(2, libc::O_RDONLY as u32)).unwrap();
+        assert_eq!(block_on(ch.read(2, opened.fh, 2, 100)).unwrap(), b"llo\n");
+        assert_eq!(block_on(ch.write(2, opened.fh, 0, b"x")).unwrap_err(), Errno::ENOSYS);
+        block_on(ch.release(2, opened.fh)).unwrap();
+        block_on(ch.forget(2, 1));
+        assert_eq!(block_on(ch.mkdir(FUSE_ROOT_ID, "dir", 0o755)).unwrap_err(), Errno::ENOSYS);
+        assert_eq!(block_on(ch.rename(FUSE_ROOT_ID, "hello", FUSE_ROOT_ID, "world")).unwrap_err(), Errno::ENOSYS);
+
+        let entries = block_on(ch.readdir(FUSE_ROOT_ID, 0, DirOffset::new(1, 0).raw(), 4096)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].ino, entries[0].offset, entries[0].kind), (2, DirOffset::new(2, 0), FileType::RegularFile));
+        assert_eq!(block_on(ch.readdir(FUSE_ROOT_ID, 0, 1, 4096)).unwrap_err(), Errno::ESTALE);
+        assert_eq!(entries[0].name, "hello");
+    }
+
+    struct Missing;
+
+    #[async_trait]
+    impl Filesystem for Missing {
+        async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            Ok(Entry::negative(Some(Duration::from_secs(30))))
+        }
+    }
+
+    #[test]
+    fn negative_lookup() {
+        let ch = MockChannel::new(Missing);
+        block_on(ch.init()).unwrap();
+        let entry = block_on(ch.lookup(FUSE_ROOT_ID, "config.h")).unwrap();
+        assert!(entry.is_negative());
+        assert_eq!(entry.ttl, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn init_flags() {
+        let capable = InitFlags::ASYNC_READ | InitFlags::POSIX_LOCKS | InitFlags::ATOMIC_O_TRUNC;
+        let conn = block_on(MockChannel::new(Hello).init_capable(capable)).unwrap();
+        assert_eq!(conn.capable, capable);
+        assert_eq!(conn.want, capable & crate::session::DEFAULT_INIT_FLAGS);
+        let conn = block_on(MockChannel::new(Hello).init_flags(InitFlags::ATOMIC_O_TRUNC).init_capable(capable)).unwrap();
+        assert_eq!(conn.want, InitFlags::ATOMIC_O_TRUNC);
+    }
+
+    #[test]
+    fn raw_reply() {
+        let ch = MockChannel::new(Hello).user(1000, 100);
+        block_on(ch.init()).unwrap();
+        let reply = block_on(ch.request(fuse_opcode::FUSE_GETATTR as u32, 3, &[])).unwrap();
+        assert!(reply.unique() > 1);
+        assert_eq!(reply.as_bytes().len(), 16 + reply.result().unwrap().len());
+        assert!(block_on(ch.request(fuse_opcode::FUSE_LOOKUP as u32, FUSE_ROOT_ID, b"hello")).is_none());
+    }
+}
@@ -0,0 +1,516 @@
+//! Mounting filesystems
+//!
+//! `Mount` collects the mount options and session settings for a filesystem, mounts it and
+//! runs its session either in the current thread (`run`) or in the background (`spawn`). New
+//! settings can be added as builder methods without changing existing calls.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::io::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use log::{error, info};
+#[cfg(feature = "tokio")]
+use tokio::task::{self, JoinHandle};
+
+use crate::admission::Admission;
+use crate::daemon::Daemon;
+use crate::error::MountError;
+use crate::timeout::Timeouts;
+use crate::idmap::IdMap;
+#[cfg(feature = "tokio")]
+use crate::channel;
+use crate::executor::{default_executor, Executor};
+use crate::jsonlog::JsonLog;
+use crate::lifecycle::Lifecycle;
+use crate::metrics::Metrics;
+use crate::observer::Observer;
+use crate::pidns::PidNamespace;
+use crate::record::Recorder;
+use crate::redact::Redaction;
+use crate::reply::DEFAULT_TTL;
+use crate::resend::ReplyCache;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::sandbox::Sandbox;
+use crate::session::{escape_option, DEFAULT_INIT_FLAGS, MAX_WRITE_SIZE, Session};
+use crate::{Filesystem, InitFlags};
+
+/// Builder for mounting a filesystem
+#[derive(Debug)]
+pub struct Mount<FS: Filesystem + Send + Sync + 'static> {
+    filesystem: FS,
+    mountpoint: PathBuf,
+    options: Vec<OsString>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    max_write: u32,
+    workers: usize,
+    inline_dispatch: bool,
+    init_flags: InitFlags,
+    cache_enosys: bool,
+    dot_entries: bool,
+    direct_io_on_o_direct: bool,
+    passthrough: bool,
+    create_supp_group: bool,
+    resend_replies: Option<Arc<ReplyCache>>,
+    executor: Arc<dyn Executor>,
+    recorder: Option<Arc<Recorder>>,
+    metrics: Option<Arc<Metrics>>,
+    json_log: Option<Arc<JsonLog>>,
+    observer: Option<Arc<dyn Observer>>,
+    admission: Option<Arc<Admission>>,
+    timeouts: Timeouts,
+    lifecycle: Lifecycle,
+    redaction: Redaction,
+    id_map: Option<Arc<IdMap>>,
+    pid_ns: Option<PidNamespace>,
+    drop_privileges: Option<(u32, u32)>,
+    daemon: Option<Daemon>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    sandbox: Option<Sandbox>,
+    fd: Option<OwnedFd>,
+}
+
+impl<FS: Filesystem + Send + Sync + 'static> Mount<FS> {
+    /// Create a builder for mounting the given filesystem to the given mountpoint
+    pub fn new<P: AsRef<Path>>(filesystem: FS, mountpoint: P) -> Mount<FS> {
+        Mount {
+            filesystem,
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            options: Vec::new(),
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
+            max_write: MAX_WRITE_SIZE as u32,
+            workers: 0,
+            inline_dispatch: false,
+            init_flags: DEFAULT_INIT_FLAGS,
+            cache_enosys: false,
+            dot_entries: false,
+            direct_io_on_o_direct: false,
+            passthrough: false,
+            create_supp_group: false,
+            resend_replies: None,
+            executor: default_executor(),
+            recorder: None,
+            metrics: None,
+            json_log: None,
+            observer: None,
+            admission: None,
+            timeouts: Timeouts::default(),
+            lifecycle: Lifecycle::new(),
+            redaction: Redaction::Off,
+            id_map: None,
+            pid_ns: None,
+            drop_privileges: None,
+            daemon: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            sandbox: None,
+            fd: None,
+        }
+    }
+
+    /// Add the given mount option (like `ro` or `fsname=hello`)
+    pub fn option<O: AsRef<OsStr>>(mut self, option: O) -> Mount<FS> {
+        self.options.push("-o".into());
+        self.options.push(option.as_ref().to_os_string());
+        self
+    }
+
+    /// Set the name of the filesystem, shown as its source in /proc/mounts and by `df` (like
+    /// `fsname=` but escaped, so it can contain commas). Without one, the source is /dev/fuse.
+    pub fn fsname<N: AsRef<OsStr>>(self, name: N) -> Mount<FS> {
+        let mut option = OsString::from("fsname=");
+        option.push(escape_option(name.as_ref()));
+        self.option(option)
+    }
+
+    /// Set the subtype of the filesystem, shown as its type `fuse.<subtype>` in /proc/mounts
+    /// (like `subtype=` but escaped, so it can contain commas)
+    pub fn subtype<N: AsRef<OsStr>>(self, subtype: N) -> Mount<FS> {
+        let mut option = OsString::from("subtype=");
+        option.push(escape_option(subtype.as_ref()));
+        self.option(option)
+    }
+
+    /// Mount the filesystem as block device (`fuseblk`) backed by the given device, with the
+    /// given block size (a power of two from 512 up to the page size, 512 by default). Block
+    /// devices need root privileges and are Linux only. The kernel then reports the device's
+    /// st_dev and caches blocks like for other block device filesystems.
+    pub fn blkdev<P: AsRef<Path>>(self, device: P, blksize: Option<u32>) -> Mount<FS> {
+        let mount = self.option("blkdev").fsname(device.as_ref());
+        match blksize {
+            Some(blksize) => mount.option(format!("blksize={}", blksize)),
+            None => mount,
+        }
+    }
+
+    /// Add the given raw mount arguments. Each option needs to be lead by a separate `"-o"`.
+    pub fn options(mut self, options: &[OsString]) -> Mount<FS> {
+        self.options.extend_from_slice(options);
+        self
+    }
+
+    /// Set the time the kernel may cache names if the filesystem doesn't specify one
+    pub fn entry_ttl(mut self, ttl: Duration) -> Mount<FS> {
+        self.entry_ttl = ttl;
+        self
+    }
+
+    /// Set the time the kernel may cache attributes if the filesystem doesn't specify one
+    pub fn attr_ttl(mut self, ttl: Duration) -> Mount<FS> {
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// Set the maximum size of write requests (4k up to 16M, the default)
+    pub fn max_write(mut self, max_write: u32) -> Mount<FS> {
+        self.max_write = max_write;
+        self
+    }
+
+    /// Set the maximum number of concurrently dispatched requests (unlimited by default)
+    pub fn workers(mut self, workers: usize) -> Mount<FS> {
+        self.workers = workers;
+        self
+    }
+
+    /// Dispatch every request to completion before receiving the next one, instead of spawning
+    /// it on the executor (off by default). This saves the overhead of a task per request for
+    /// filesystems whose operations complete in microseconds, and keeps them in order. The
+    /// operations must not depend on a runtime of the executor, e.g. tokio's I/O.
+    pub fn inline_dispatch(mut self, inline_dispatch: bool) -> Mount<FS> {
+        self.inline_dispatch = inline_dispatch;
+        self
+    }
+
+    /// Set the capability flags to enable if the kernel supports them. By default, this is
+    /// `ASYNC_READ`, plus `CASE_INSENSITIVE`, `VOL_RENAME` and `XTIMES` on macOS. E.g. a case
+    /// sensitive filesystem on macOS should not enable `CASE_INSENSITIVE`.
+    pub fn init_flags(mut self, flags: InitFlags) -> Mount<FS> {
+        self.init_flags = flags;
+        self
+    }
+
+    /// Reply ENOSYS right away to operations the filesystem replied ENOSYS to before, without
+    /// dispatching them again (off by default). Only enable this if the filesystem replies
+    /// ENOSYS to an operation either always or never, regardless of the inode.
+    pub fn cache_enosys(mut self, cache_enosys: bool) -> Mount<FS> {
+        self.cache_enosys = cache_enosys;
+        self
+    }
+
+    /// Add the `.` and `..` entries to directory listings, so that the filesystem doesn't need
+    /// to (off by default). The filesystem's entries follow them, with their offsets shifted by
+    /// 2. The parent of a directory is the one it was last looked up in or renamed to. After
+    /// resuming a handed off session, directories the kernel driver knew before are listed with
+    /// the root directory as their parent until they're looked up again.
+    pub fn dot_entries(mut self, dot_entries: bool) -> Mount<FS> {
+        self.dot_entries = dot_entries;
+        self
+    }
+
+    /// Open files that are opened with O_DIRECT in direct I/O mode (`OpenFlags::DIRECT_IO`),
+    /// regardless of the flags the filesystem replies (off by default). Otherwise the kernel
+    /// sends reads and writes of O_DIRECT opens as they are, but keeps using its page cache
+    /// for mmap of the file.
+    pub fn direct_io_on_o_direct(mut self, direct_io_on_o_direct: bool) -> Mount<FS> {
+        self.direct_io_on_o_direct = direct_io_on_o_direct;
+        self
+    }
+
+    /// Negotiate passthrough of reads and writes to backing files at init (Linux 6.9 and
+    /// later, needs CAP_SYS_ADMIN, off by default). See `BackingFiles`.
+    pub fn passthrough(mut self, passthrough: bool) -> Mount<FS> {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Have the kernel send the group of the parent directory with requests creating inodes if
+    /// the caller is a member of it through a supplementary group only (Linux 6.6 and later),
+    /// so that inodes created in setgid directories get the right group (see
+    /// `Request::supp_group`)
+    pub fn create_supp_group(mut self, create_supp_group: bool) -> Mount<FS> {
+        self.create_supp_group = create_supp_group;
+        self
+    }
+
+    /// Remember the replies of the given number of latest requests that change the filesystem
+    /// (e.g. mkdir, unlink or write), and answer them with the same reply if the kernel sends
+    /// them again after `Notifier::resend`, instead of applying them twice (none by default).
+    /// Resent requests that are still being dispatched get the reply of the original request.
+    /// The replies are handed over to another process with the session (see `Handoff`).
+    pub fn resend_replies(mut self, capacity: usize) -> Mount<FS> {
+        self.resend_replies = Some(Arc::new(ReplyCache::new(capacity)));
+        self
+    }
+
+    /// Set the executor that runs dispatched requests (tokio by default if the `tokio`
+    /// feature is enabled)
+    pub fn executor<E: Executor>(mut self, executor: E) -> Mount<FS> {
+        self.executor = Arc::new(executor);
+        self
+    }
+
+    /// Record all requests and replies of the session with the given recorder, for replaying
+    /// them later
+    pub fn record(mut self, recorder: Recorder) -> Mount<FS> {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Count requests and measure their latency by operation in the given metrics, which can
+    /// be read while the filesystem is mounted
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Mount<FS> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Log every completed operation as a line of JSON to the given log, for ingestion into
+    /// log processing tools
+    pub fn json_log(mut self, log: JsonLog) -> Mount<FS> {
+        self.json_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Tell the given observer about every completed operation, e.g. for auditing who accessed
+    /// which files
+    pub fn observer<O: Observer>(mut self, observer: Arc<O>) -> Mount<FS> {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Limit the requests of every user with the given admission control, so that a single
+    /// user can't starve others (useful with `allow_other`)
+    pub fn admission(mut self, admission: Admission) -> Mount<FS> {
+        self.admission = Some(Arc::new(admission));
+        self
+    }
+
+    /// Give up on operations that take longer than the given timeouts, replying ETIMEDOUT.
+    /// Filesystems get the deadline of a request from `Request::deadline`.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Mount<FS> {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Call the hooks of the given lifecycle once the filesystem is initialized and accessible
+    /// to other processes (e.g. to signal readiness to a service manager), and once it's
+    /// unmounted after the session ended
+    pub fn lifecycle(mut self, lifecycle: Lifecycle) -> Mount<FS> {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Redact the names of files in the log output of dispatched requests as given, so that
+    /// logs of privacy-sensitive deployments don't leak them (off by default)
+    pub fn redaction(mut self, redaction: Redaction) -> Mount<FS> {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Map the user and group ids of the mount to the given ids of the filesystem, e.g. to
+    /// mount a filesystem storing the ids of a container on the host
+    pub fn id_map(mut self, id_map: IdMap) -> Mount<FS> {
+        self.id_map = Some(Arc::new(id_map));
+        self
+    }
+
+    /// Set the pid namespace the kernel driver tells the pids of callers in, if it's not the
+    /// daemon's own, e.g. with an fd opened by a process in a container (see `Request::local_pid`)
+    pub fn pid_namespace(mut self, pid_ns: PidNamespace) -> Mount<FS> {
+        self.pid_ns = Some(pid_ns);
+        self
+    }
+
+    /// Tell the process that started the given daemon that the filesystem is mounted, once it
+    /// is (see `Daemon::fork`)
+    pub fn daemon(mut self, daemon: Daemon) -> Mount<FS> {
+        self.daemon = Some(daemon);
+        self
+    }
+
+    /// Drop the privileges of the daemon to the given user and group once the filesystem is
+    /// mounted (see `Session::drop_privileges`)
+    pub fn drop_privileges(mut self, uid: u32, gid: u32) -> Mount<FS> {
+        self.drop_privileges = Some((uid, gid));
+        self
+    }
+
+    /// Sandbox the daemon once the filesystem is mounted, restricting the files it can access
+    /// and the system calls it can make (see `Sandbox`)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Mount<FS> {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Serve a filesystem that is already mounted to the mountpoint, using the given fd to the
+    /// kernel driver instead of mounting it (e.g. on Android, where an app is handed the fd of
+    /// a mounted /dev/fuse). The options should be those the filesystem was mounted with.
+    pub fn fd(mut self, fd: OwnedFd) -> Mount<FS> {
+        self.fd = Some(fd);
+        self
+    }
+
+    /// Mount the filesystem and return its session without running it
+    pub fn session(self) -> Result<Session<FS>, MountError> {
+        let mut se = match self.fd {
+            Some(fd) => Session::from_fd(self.filesystem, fd, &self.mountpoint, &self.options),
+            None => Session::new(self.filesystem, &self.mountpoint, &self.options)?,
+        };
+        se.entry_ttl = self.entry_ttl;
+        se.attr_ttl = self.attr_ttl;
+        se.max_write = self.max_write;
+        se.workers = self.workers;
+        se.inline_dispatch = self.inline_dispatch;
+        se.init_flags = self.init_flags;
+        se.cache_enosys = self.cache_enosys;
+        se.dot_entries = self.dot_entries;
+        se.direct_io_on_o_direct = self.direct_io_on_o_direct;
+        se.passthrough = self.passthrough;
+        se.create_supp_group = self.create_supp_group;
+        se.resend_replies = self.resend_replies;
+        se.executor = self.executor;
+        se.recorder = self.recorder;
+        se.metrics = self.metrics;
+        se.json_log = self.json_log;
+        se.observer = self.observer;
+        se.admission = self.admission;
+        se.timeouts = self.timeouts;
+        se.lifecycle = self.lifecycle;
+        se.redaction = self.redaction;
+        se.id_map = self.id_map;
+        if let Some(pid_ns) = self.pid_ns {
+            se.pid_ns = Some(pid_ns);
+        }
+        if let Some((uid, gid)) = self.drop_privileges {
+            se.drop_privileges(uid, gid, None)?;
+        }
+        if let Some(mut daemon) = self.daemon {
+            daemon.ready()?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(sandbox) = self.sandbox {
+            sandbox.apply()?;
+        }
+        Ok(se)
+    }
+
+    /// Mount the filesystem and run its session. This function will not return until the
+    /// filesystem is unmounted.
+    pub fn run(self) -> io::Result<()> {
+        self.session()?.run()
+    }
+
+    /// Mount the filesystem and run its session in the background. The returned handle can
+    /// be used to unmount the filesystem or to wait until it is unmounted. If it's dropped,
+    /// the filesystem is unmounted.
+    #[cfg(feature = "tokio")]
+    pub async fn spawn(self) -> io::Result<MountHandle> {
+        let se = task::spawn_blocking(move || self.session()).await.map_err(io::Error::other)??;
+        let mountpoint = se.mountpoint().to_path_buf();
+        let task = task::spawn_blocking(move || se.run());
+        Ok(MountHandle { mountpoint, task: Some(task) })
+    }
+}
+
+/// Handle of a filesystem mounted in the background
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct MountHandle {
+    mountpoint: PathBuf,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+#[cfg(feature = "tokio")]
+impl MountHandle {
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the filesystem and wait for its session to end
+    pub async fn unmount(mut self) -> io::Result<()> {
+        info!("Unmounting {}", self.mountpoint.display());
+        channel::unmount(&self.mountpoint)?;
+        self.wait().await
+    }
+
+    /// Wait until the filesystem is unmounted (e.g. using fusermount -u)
+    pub async fn join(mut self) -> io::Result<()> {
+        self.wait().await
+    }
+
+    async fn wait(&mut self) -> io::Result<()> {
+        match self.task.take() {
+            Some(task) => task.await.map_err(io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if self.task.is_some() {
+            info!("Unmounting {}", self.mountpoint.display());
+            if let Err(err) = channel::unmount(&self.mountpoint) {
+                error!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+            }
+        }
+    }
+}
+
+/// Filesystems mounted in the background that are unmounted together, e.g. the same shared
+/// filesystem (an `Arc<FS>`) mounted at several mountpoints
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+pub struct MountGroup {
+    handles: Vec<MountHandle>,
+}
+
+#[cfg(feature = "tokio")]
+impl MountGroup {
+    /// Create an empty group
+    pub fn new() -> MountGroup {
+        MountGroup::default()
+    }
+
+    /// Mount the given filesystem in the background and add it to the group
+    pub async fn spawn<FS: Filesystem + Send + Sync + 'static>(&mut self, mount: Mount<FS>) -> io::Result<()> {
+        self.handles.push(mount.spawn().await?);
+        Ok(())
+    }
+
+    /// Add a filesystem mounted in the background to the group
+    pub fn push(&mut self, handle: MountHandle) {
+        self.handles.push(handle);
+    }
+
+    /// Return paths of the mounted filesystems
+    pub fn mountpoints(&self) -> Vec<&Path> {
+        self.handles.iter().map(MountHandle::mountpoint).collect()
+    }
+
+    /// Unmount all filesystems and wait for their sessions to end. Returns the first error,
+    /// after trying to unmount all of them.
+    pub async fn unmount(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for handle in self.handles {
+            result = result.and(handle.unmount().await);
+        }
+        result
+    }
+
+    /// Wait until all filesystems are unmounted
+    pub async fn join(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for handle in self.handles {
+            result = result.and(handle.join().await);
+        }
+        result
+    }
+}
+
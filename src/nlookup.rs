@@ -0,0 +1,199 @@
+//! Lookup counting
+//!
+//! The kernel counts how often an inode was returned by lookup, mknod, mkdir, symlink, link,
+//! create, tmpfile and readdirplus, and sends forget requests to decrease that count when it
+//! drops the inode from its cache. A filesystem may only release the resources of an inode (e.g. the fd of the backing
+//! file) once its count is zero, since the kernel can send requests for it until then. Mixing
+//! this up leaks resources, or reuses inodes the kernel still sends requests for.
+//! `LookupTracker` does the bookkeeping for a filesystem and calls an eviction function when an
+//! inode isn't known to the kernel anymore, or for all remaining inodes on unmount.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use async_trait::async_trait;
+
+use crate::layer::Wrapper;
+use crate::{Created, Entry, Errno, Filesystem, Request, FUSE_ROOT_ID};
+#[cfg(not(target_os = "macos"))]
+use crate::{DirEntryPlus, DirOffset};
+
+/// Filesystem wrapper counting the lookups of inodes, calling the given function with the
+/// inode number when an inode is forgotten. The root inode is never forgotten.
+pub struct LookupTracker<FS, E> {
+    inner: FS,
+    evict: E,
+    counts: Mutex<HashMap<u64, u64>>,
+}
+
+impl<FS: Filesystem + Send + Sync, E: Fn(u64) + Send + Sync> LookupTracker<FS, E> {
+    /// Count lookups of the given filesystem, evicting inodes with the given function
+    pub fn new(inner: FS, evict: E) -> LookupTracker<FS, E> {
+        LookupTracker { inner, evict, counts: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the lookup count of the given inode
+    pub fn nlookup(&self, ino: u64) -> u64 {
+        self.counts.lock().unwrap().get(&ino).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of inodes known to the kernel (besides the root inode)
+    pub fn len(&self) -> usize {
+        self.counts.lock().unwrap().len()
+    }
+
+    /// Returns true if no inodes are known to the kernel (besides the root inode)
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Count a lookup of the given inode (none for negative entries of inode 0)
+    fn looked_up(&self, ino: u64) {
+        if ino != 0 && ino != FUSE_ROOT_ID {
+            *self.counts.lock().unwrap().entry(ino).or_insert(0) += 1;
+        }
+    }
+
+    fn entry(&self, res: Result<Entry, Errno>) -> Result<Entry, Errno> {
+        if let Ok(ref entry) = res {
+            self.looked_up(entry.attr.ino);
+        }
+        res
+    }
+}
+
+impl<FS: fmt::Debug, E> fmt::Debug for LookupTracker<FS, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LookupTracker").field("inner", &self.inner).field("counts", &self.counts).finish()
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync, E: Fn(u64) + Send + Sync> Wrapper for LookupTracker<FS, E> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn destroy(&self, req: &Request) {
+        self.inner.destroy(req).await;
+        let counts: Vec<_> = self.counts.lock().unwrap().drain().collect();
+        for (ino, _) in counts {
+            (self.evict)(ino);
+        }
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        self.entry(self.inner.lookup(req, parent, name).await)
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        self.inner.forget(req, ino, nlookup).await;
+        let evicted = {
+            let mut counts = self.counts.lock().unwrap();
+            match counts.get_mut(&ino) {
+                Some(count) if *count > nlookup => { *count -= nlookup; false }
+                Some(_) => { counts.remove(&ino); true }
+                None => false,
+            }
+        };
+        if evicted {
+            (self.evict)(ino);
+        }
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        self.entry(self.inner.mknod(req, parent, name, mode, rdev).await)
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        self.entry(self.inner.mkdir(req, parent, name, mode).await)
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        self.entry(self.inner.symlink(req, parent, name, link).await)
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        self.entry(self.inner.link(req, ino, newparent, newname).await)
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let res = self.inner.create(req, parent, name, mode, flags).await;
+        if let Ok(ref created) = res {
+            self.looked_up(created.attr.ino);
+        }
+        res
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        let res = self.inner.readdirplus(req, ino, fh, offset).await;
+        if let Ok(ref entries) = res {
+            // The kernel doesn't count lookups for the dot entries
+            for entry in entries.iter().filter(|entry| entry.dirent.name != "." && entry.dirent.name != "..") {
+                self.looked_up(entry.entry.attr.ino);
+            }
+        }
+        res
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let res = self.inner.tmpfile(req, parent, mode, flags).await;
+        if let Ok(ref created) = res {
+            self.looked_up(created.attr.ino);
+        }
+        res
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use crate::{block_on, Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::LookupTracker;
+
+    /// Filesystem with files named by their inode number in the root directory
+    struct Numbered;
+
+    #[async_trait]
+    impl Filesystem for Numbered {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            let ino = name.to_str().and_then(|name| name.parse().ok()).ok_or(Errno::ENOENT)?;
+            Ok(Entry::new(FileAttr::builder(ino, FileType::RegularFile).build(), 0))
+        }
+    }
+
+    #[test]
+    fn evicts() {
+        let req = Request::test(0, 0);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let log = evicted.clone();
+        let fs = LookupTracker::new(Numbered, move |ino| log.lock().unwrap().push(ino));
+        for name in ["2", "2", "3", "1", "0", "foo"] {
+            let _ = block_on(Filesystem::lookup(&fs, &req, 1, OsStr::new(name)));
+        }
+        assert_eq!((fs.nlookup(2), fs.nlookup(3), fs.len()), (2, 1, 2));
+        block_on(Filesystem::forget(&fs, &req, 2, 1));
+        assert_eq!(fs.nlookup(2), 1);
+        assert!(evicted.lock().unwrap().is_empty());
+        block_on(Filesystem::forget(&fs, &req, 2, 1));
+        assert_eq!(*evicted.lock().unwrap(), [2]);
+        // Remaining inodes are evicted on unmount
+        block_on(Filesystem::destroy(&fs, &req));
+        assert_eq!(*evicted.lock().unwrap(), [2, 3]);
+        assert!(fs.is_empty());
+    }
+}
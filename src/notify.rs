@@ -0,0 +1,122 @@
+//! Notifications to the kernel driver
+//!
+//! Besides replying to requests, a filesystem can send notifications to the kernel driver at
+//! any time, e.g. to invalidate the attributes, data or directory entries the kernel cached
+//! after the underlying data changed without the kernel knowing. Notifications are messages
+//! with an unique id of 0 and the notification code in the error field of the header.
+//!
+//! Notifications must not be sent while handling a request for the same inode (the kernel
+//! may wait for the request to finish before handling the notification).
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, RwLock};
+
+use crate::channel::Channel;
+use crate::session::ConnectionInfo;
+
+/// Notification code invalidating the attributes and data of an inode
+const NOTIFY_INVAL_INODE: i32 = 2;
+/// Notification code invalidating a directory entry
+const NOTIFY_INVAL_ENTRY: i32 = 3;
+/// Notification code resending all pending requests (7.40)
+const NOTIFY_RESEND: i32 = 7;
+
+/// Flag of entry invalidations that only expire the entry (7.38)
+const EXPIRE_ONLY: u32 = 1 << 0;
+/// Init flags2 bit of kernels supporting entry invalidations that only expire the entry (7.38)
+const HAS_EXPIRE_ONLY: u32 = 1 << 3;
+
+/// Serialize a notification with the given code and data
+fn message(code: i32, data: &[&[u8]]) -> Vec<u8> {
+    let len = 16 + data.iter().map(|d| d.len()).sum::<usize>();
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&code.to_ne_bytes());
+    msg.extend_from_slice(&0u64.to_ne_bytes());
+    for d in data {
+        msg.extend_from_slice(d);
+    }
+    msg
+}
+
+fn inval_inode_message(ino: u64, offset: i64, len: i64) -> Vec<u8> {
+    message(NOTIFY_INVAL_INODE, &[&ino.to_ne_bytes(), &offset.to_ne_bytes(), &len.to_ne_bytes()])
+}
+
+fn inval_entry_message(parent: u64, name: &OsStr, flags: u32) -> Vec<u8> {
+    let name = name.as_bytes();
+    message(NOTIFY_INVAL_ENTRY, &[&parent.to_ne_bytes(), &(name.len() as u32).to_ne_bytes(), &flags.to_ne_bytes(), name, &[0]])
+}
+
+/// Handle for sending notifications to the kernel driver of a running session
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    ch: Arc<Channel>,
+    conn: Arc<RwLock<ConnectionInfo>>,
+}
+
+impl Notifier {
+    pub(crate) fn new(ch: Arc<Channel>, conn: Arc<RwLock<ConnectionInfo>>) -> Notifier {
+        Notifier { ch, conn }
+    }
+
+    /// Invalidate the cached attributes of the given inode, and its cached data from the given
+    /// offset on for the given length (to the end if the length isn't positive, no data if the
+    /// offset is negative). Fails with ENOENT if the kernel doesn't know the inode.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        self.ch.sender().send(&[&inval_inode_message(ino, offset, len)])
+    }
+
+    /// Invalidate the cached directory entry of the given name in the given directory, and the
+    /// cached attributes of the directory. Fails with ENOENT if the kernel doesn't know it.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        self.ch.sender().send(&[&inval_entry_message(parent, name, 0)])
+    }
+
+    /// Mark the cached directory entry of the given name in the given directory as expired,
+    /// without dropping it. Unlike `inval_entry`, the kernel keeps the entry (and the inodes
+    /// and entries below it) and revalidates it with a lookup at its next use, so that
+    /// invalidating a large tree doesn't cause lookups of all of it at once. Fails with
+    /// EOPNOTSUPP if the kernel doesn't support it (ABI 7.38), and with ENOENT if the kernel
+    /// doesn't know the entry.
+    pub fn expire_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        if self.conn.read().unwrap().capable2 & HAS_EXPIRE_ONLY == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        self.ch.sender().send(&[&inval_entry_message(parent, name, EXPIRE_ONLY)])
+    }
+
+    /// Ask the kernel to send all requests again that it sent before but weren't replied to
+    /// yet, e.g. after resuming a session of a daemon that went away with requests in flight
+    /// (see `Request::is_resend`). Fails with EINVAL if the kernel doesn't support it
+    /// (`FUSE_HAS_RESEND` in `ConnectionInfo::capable2`, ABI 7.40).
+    pub fn resend(&self) -> io::Result<()> {
+        self.ch.sender().send(&[&message(NOTIFY_RESEND, &[])])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use super::{inval_entry_message, inval_inode_message, message, EXPIRE_ONLY, NOTIFY_RESEND};
+
+    #[test]
+    fn messages() {
+        let msg = inval_inode_message(0x1122, 0, -1);
+        assert_eq!(msg.len(), 40);
+        assert_eq!(&msg[..16], &[0x28, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&msg[16..24], &0x1122u64.to_ne_bytes());
+        assert_eq!(&msg[32..], &(-1i64).to_ne_bytes());
+        let msg = inval_entry_message(1, OsStr::new("hello"), 0);
+        assert_eq!(msg.len(), 38);
+        assert_eq!(&msg[..8], &[0x26, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(&msg[24..28], &5u32.to_ne_bytes());
+        assert_eq!(&msg[32..], b"hello\0");
+        let msg = inval_entry_message(1, OsStr::new("hello"), EXPIRE_ONLY);
+        assert_eq!(&msg[28..32], &1u32.to_ne_bytes());
+        assert_eq!(message(NOTIFY_RESEND, &[]), [0x10, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Operation observers
+//!
+//! An observer is told about every completed operation, with a summary of the request (who
+//! accessed which inode or directory entry) and of its reply (the result, size and latency).
+//! Unlike a `Wrapper`, it doesn't need to implement every filesystem operation, and it sees
+//! the operations exactly as the kernel driver requested them. This is meant for auditing and
+//! similar tapping, observers can't change requests or replies.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::time::Duration;
+use async_trait::async_trait;
+
+use crate::ll;
+use crate::metrics::operation_name;
+use crate::Errno;
+
+/// Observer of completed operations
+#[async_trait]
+pub trait Observer: Send + Sync + 'static {
+    /// Called after the given request was replied to. Observers are called in the background,
+    /// so a slow observer doesn't delay replies, but observations of concurrent operations may
+    /// be made in any order. Operations that aren't replied to (forget, interrupt) aren't
+    /// observed.
+    async fn observe(&self, request: &RequestSummary, reply: &ReplySummary);
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+/// Summary of a request
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestSummary {
+    /// Unique identifier of the request
+    pub unique: u64,
+    /// Name of the operation in lower case (e.g. `lookup`)
+    pub operation: String,
+    /// Inode the operation is targeted to. For operations on directory entries, this is the
+    /// directory containing the entry.
+    pub ino: u64,
+    /// User id of the calling process
+    pub uid: u32,
+    /// Group id of the calling process
+    pub gid: u32,
+    /// Process id of the calling process
+    pub pid: u32,
+    /// Name of the directory entry the operation is targeted to (lookup, mknod, mkdir,
+    /// symlink, create, link, unlink, rmdir and rename)
+    pub name: Option<OsString>,
+    /// Directory the entry is moved to (rename)
+    pub newparent: Option<u64>,
+    /// Name the entry is renamed to (rename)
+    pub newname: Option<OsString>,
+}
+
+impl RequestSummary {
+    pub(crate) fn new(req: &ll::Request) -> RequestSummary {
+        let (name, newparent, newname) = match req.operation() {
+            ll::Operation::Lookup { name } | ll::Operation::MkNod { name, .. } | ll::Operation::MkDir { name, .. } |
+            ll::Operation::SymLink { name, .. } | ll::Operation::Create { name, .. } | ll::Operation::Link { name, .. } |
+            ll::Operation::Unlink { name } | ll::Operation::RmDir { name } => (Some(name.to_os_string()), None, None),
+            ll::Operation::Rename { arg, name, newname } => (Some(name.to_os_string()), Some(arg.newdir), Some(newname.to_os_string())),
+            _ => (None, None, None),
+        };
+        RequestSummary {
+            unique: req.unique(),
+            operation: operation_name(req.opcode()),
+            ino: req.nodeid(),
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+            name,
+            newparent,
+            newname,
+        }
+    }
+}
+
+/// Summary of a reply
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplySummary {
+    /// Error the operation failed with, none if it succeeded
+    pub error: Option<Errno>,
+    /// Size of the reply data in bytes
+    pub size: usize,
+    /// Time from receiving the request until replying to it
+    pub latency: Duration,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{OsStr, OsString};
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use crate::{block_on, Entry, Errno, Filesystem, MockChannel, Request, ThreadExecutor, FUSE_ROOT_ID};
+    use super::{Observer, ReplySummary, RequestSummary};
+
+    #[derive(Default)]
+    struct Observations(Mutex<Vec<(RequestSummary, ReplySummary)>>);
+
+    #[async_trait]
+    impl Observer for Observations {
+        async fn observe(&self, request: &RequestSummary, reply: &ReplySummary) {
+            self.0.lock().unwrap().push((request.clone(), reply.clone()));
+        }
+    }
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {
+        async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    #[test]
+    fn observe() {
+        let observations = Arc::new(Observations::default());
+        let ch = MockChannel::new(Empty).observer(observations.clone()).executor(ThreadExecutor).user(1000, 100);
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.lookup(FUSE_ROOT_ID, "foo")).unwrap_err(), Errno::ENOENT);
+        // Observers run in the background
+        while observations.0.lock().unwrap().len() < 2 { std::thread::yield_now(); }
+        let observations = observations.0.lock().unwrap();
+        let (request, reply) = observations.iter().find(|(request, _)| request.operation == "lookup").unwrap();
+        assert_eq!((request.ino, request.uid, request.gid), (FUSE_ROOT_ID, 1000, 100));
+        assert_eq!(request.name, Some(OsString::from("foo")));
+        assert_eq!(request.newname, None);
+        assert_eq!(reply.error, Some(Errno::ENOENT));
+        assert_eq!(reply.size, 0);
+    }
+}
@@ -0,0 +1,928 @@
+//! Overlay filesystem
+//!
+//! `Overlay` merges two filesystems like the kernel's overlayfs: files of the upper filesystem
+//! hide files at the same path in the lower filesystem, and directories of both are merged. The
+//! lower filesystem is never modified. A lower file is copied to the upper filesystem when it's
+//! first modified, and removed lower files are hidden by whiteouts (character devices with device
+//! number 0) in the upper filesystem. Directories replacing a removed lower directory are marked
+//! opaque using the `trusted.overlay.opaque` extended attribute, so the upper filesystem needs to
+//! support extended attributes for that.
+//!
+//! Both filesystems keep their own inode numbers. The overlay assigns inode numbers by path,
+//! like `PathFs`, and holds one lookup of every layer inode it refers to. Renaming directories
+//! that exist in the lower filesystem fails with EXDEV (like overlayfs without `redirect_dir`),
+//! which makes tools like `mv` fall back to copying.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use fuse_abi::FUSE_ROOT_ID;
+use libc::{O_RDONLY, O_WRONLY};
+
+use crate::inode::{InodeTable, time_generation, UNKNOWN_INO};
+use crate::readonly::writes;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, Lock, Opened, OpenFlags, Request, Statfs, Xattr};
+
+/// Extended attribute marking opaque upper directories
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// Size of the chunks in which files are copied up
+const COPY_SIZE: u32 = 128 * 1024;
+
+/// Layer inodes of an overlay inode
+#[derive(Clone, Copy, Debug, Default)]
+struct Layers {
+    upper: Option<u64>,
+    lower: Option<u64>,
+}
+
+/// Layer of an open file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Side {
+    Upper,
+    Lower,
+}
+
+/// Open file or directory of the overlay
+#[derive(Debug)]
+enum Handle {
+    /// File opened in a layer, with its layer inode and file handle
+    File(Side, u64, u64),
+    /// Directory with its layer inodes and merged entries (after the first readdir)
+    Dir(Layers, Option<Arc<Vec<DirEntry>>>),
+}
+
+/// Returns true if the given attributes are those of a whiteout
+fn is_whiteout(attr: &FileAttr) -> bool {
+    attr.kind == FileType::CharDevice && attr.rdev == 0
+}
+
+/// Turns ENOENT (or a negative entry) of a lookup into `None`
+fn found(result: Result<Entry, Errno>) -> Result<Option<Entry>, Errno> {
+    match result {
+        Ok(entry) if entry.is_negative() => Ok(None),
+        Ok(entry) => Ok(Some(entry)),
+        Err(Errno::ENOENT) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns all entries of the given directory of the given filesystem
+async fn list<FS: Filesystem + Send + Sync>(fs: &FS, req: &Request, ino: u64) -> Result<Vec<DirEntry>, Errno> {
+    let opened = fs.opendir(req, ino, O_RDONLY as u32).await?;
+    let mut entries = Vec::new();
+    let result = loop {
+        let offset = entries.last().map_or(DirOffset::START, |entry: &DirEntry| entry.offset);
+        match fs.readdir(req, ino, opened.fh, offset).await {
+            Ok(more) if more.is_empty() => break Ok(entries),
+            Ok(more) => entries.extend(more),
+            Err(err) => break Err(err),
+        }
+    };
+    let _ = fs.releasedir(req, ino, opened.fh, O_RDONLY as u32).await;
+    result
+}
+
+/// Filesystem merging an upper and a lower filesystem. See the module documentation for details.
+#[derive(Debug)]
+pub struct Overlay<U, L> {
+    upper: U,
+    lower: L,
+    table: InodeTable<PathBuf>,
+    nodes: Mutex<HashMap<u64, Layers>>,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_fh: AtomicU64,
+}
+
+impl<U: Filesystem + Send + Sync, L: Filesystem + Send + Sync> Overlay<U, L> {
+    /// Merge the given upper (writable) and lower (read-only) filesystems
+    pub fn new(upper: U, lower: L) -> Overlay<U, L> {
+        let mut nodes = HashMap::new();
+        nodes.insert(FUSE_ROOT_ID, Layers { upper: Some(FUSE_ROOT_ID), lower: Some(FUSE_ROOT_ID) });
+        Overlay {
+            upper,
+            lower,
+            table: InodeTable::with_generation(PathBuf::from("/"), time_generation()),
+            nodes: Mutex::new(nodes),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+        }
+    }
+
+    /// Returns the upper filesystem
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    /// Returns the lower filesystem
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+
+    fn path(&self, ino: u64) -> Result<PathBuf, Errno> {
+        self.table.get(ino).ok_or(Errno::ENOENT)
+    }
+
+    fn layers(&self, ino: u64) -> Result<Layers, Errno> {
+        self.nodes.lock().unwrap().get(&ino).copied().ok_or(Errno::ENOENT)
+    }
+
+    /// Returns the layer inode whose contents are visible for the given overlay inode
+    fn visible(&self, ino: u64) -> Result<(Side, u64), Errno> {
+        match self.layers(ino)? {
+            Layers { upper: Some(upper), .. } => Ok((Side::Upper, upper)),
+            Layers { lower: Some(lower), .. } => Ok((Side::Lower, lower)),
+            _ => Err(Errno::ENOENT),
+        }
+    }
+
+    fn open_handle(&self, handle: Handle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    fn file(&self, fh: u64) -> Result<(Side, u64, u64), Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(&Handle::File(side, ino, fh)) => Ok((side, ino, fh)),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    async fn forget_layer(&self, req: &Request, side: Side, ino: u64) {
+        match side {
+            Side::Upper => self.upper.forget(req, ino, 1).await,
+            Side::Lower => self.lower.forget(req, ino, 1).await,
+        }
+    }
+
+    /// Drops the lookups of the given layer entries
+    async fn forget_entries(&self, req: &Request, upper: Option<Entry>, lower: Option<Entry>) {
+        if let Some(entry) = upper { self.upper.forget(req, entry.attr.ino, 1).await; }
+        if let Some(entry) = lower { self.lower.forget(req, entry.attr.ino, 1).await; }
+    }
+
+    /// Records layer inodes of an overlay inode, of which the caller holds one lookup each.
+    /// Replaced inodes (or the previous lookup of the same inode) are forgotten.
+    async fn record(&self, req: &Request, ino: u64, upper: Option<u64>, lower: Option<u64>) {
+        let mut released = Vec::new();
+        {
+            let mut nodes = self.nodes.lock().unwrap();
+            let node = nodes.entry(ino).or_default();
+            for (side, new, old) in [(Side::Upper, upper, &mut node.upper), (Side::Lower, lower, &mut node.lower)] {
+                if let Some(old) = new.and_then(|new| old.replace(new)) {
+                    released.push((side, old));
+                }
+            }
+        }
+        for (side, ino) in released {
+            self.forget_layer(req, side, ino).await;
+        }
+    }
+
+    /// Returns an overlay entry for the given path from the given layer entries and records
+    /// their inodes
+    async fn entry(&self, req: &Request, path: PathBuf, upper: Option<Entry>, lower: Option<Entry>) -> Result<Entry, Errno> {
+        let visible = upper.or(lower).ok_or(Errno::ENOENT)?;
+        let (ino, generation) = self.table.lookup(&path);
+        self.record(req, ino, upper.map(|entry| entry.attr.ino), lower.map(|entry| entry.attr.ino)).await;
+        Ok(Entry { ttl: visible.ttl, attr: FileAttr { ino, ..visible.attr }, generation })
+    }
+
+    /// Returns true if the given upper directory is opaque
+    async fn opaque(&self, req: &Request, ino: u64) -> bool {
+        matches!(self.upper.getxattr(req, ino, OsStr::new(OPAQUE_XATTR), 16).await, Ok(Xattr::Data(ref value)) if value == b"y")
+    }
+
+    /// Returns true if the given name in the given upper directory is a whiteout
+    async fn whiteout(&self, req: &Request, dir: u64, name: &OsStr) -> bool {
+        match self.upper.lookup(req, dir, name).await {
+            Ok(entry) if !entry.is_negative() => {
+                self.upper.forget(req, entry.attr.ino, 1).await;
+                is_whiteout(&entry.attr)
+            }
+            _ => false,
+        }
+    }
+
+    /// Looks up the given name in the layers of a directory. Returns the visible upper and lower
+    /// entries, and whether the name exists in the lower filesystem (so that removing it needs a
+    /// whiteout). Lower entries are only visible if there's no upper entry, or if both are
+    /// directories and the upper directory isn't opaque.
+    async fn find(&self, req: &Request, dir: Layers, name: &OsStr) -> Result<(Option<Entry>, Option<Entry>, bool), Errno> {
+        let upper = match dir.upper {
+            Some(dir) => found(self.upper.lookup(req, dir, name).await)?,
+            None => None,
+        };
+        if let Some(entry) = upper.filter(|entry| is_whiteout(&entry.attr)) {
+            self.upper.forget(req, entry.attr.ino, 1).await;
+            return Ok((None, None, false));
+        }
+        let lower = match dir.lower {
+            Some(dir) => found(self.lower.lookup(req, dir, name).await)?,
+            None => None,
+        };
+        let visible = match (upper, lower) {
+            (Some(upper), Some(lower)) => upper.attr.kind == FileType::Directory && lower.attr.kind == FileType::Directory && !self.opaque(req, upper.attr.ino).await,
+            _ => true,
+        };
+        match lower {
+            Some(entry) if !visible => {
+                self.lower.forget(req, entry.attr.ino, 1).await;
+                Ok((upper, None, true))
+            }
+            _ => Ok((upper, lower, lower.is_some())),
+        }
+    }
+
+    /// Returns the merged entries of a directory with the given layer inodes, without whiteouts
+    async fn merged(&self, req: &Request, dir: Layers) -> Result<Vec<DirEntry>, Errno> {
+        let mut entries = Vec::new();
+        let mut names = HashSet::new();
+        if let Some(dir) = dir.upper {
+            for entry in list(&self.upper, req, dir).await? {
+                names.insert(entry.name.clone());
+                if entry.kind == FileType::CharDevice && self.whiteout(req, dir, &entry.name).await { continue; }
+                entries.push(entry);
+            }
+        }
+        if let Some(dir) = dir.lower {
+            entries.extend(list(&self.lower, req, dir).await?.into_iter().filter(|entry| !names.contains(&entry.name)));
+        }
+        Ok(entries)
+    }
+
+    /// Fails with ENOTEMPTY unless the directory with the given layer entries is empty, and
+    /// removes the whiteouts of its upper directory so that it can be removed
+    async fn clear(&self, req: &Request, upper: Option<Entry>, lower: Option<Entry>) -> Result<(), Errno> {
+        let dir = Layers { upper: upper.map(|entry| entry.attr.ino), lower: lower.map(|entry| entry.attr.ino) };
+        if self.merged(req, dir).await?.iter().any(|entry| entry.name != "." && entry.name != "..") {
+            return Err(Errno::ENOTEMPTY);
+        }
+        if let Some(dir) = dir.upper {
+            for entry in list(&self.upper, req, dir).await? {
+                if entry.name != "." && entry.name != ".." {
+                    self.upper.unlink(req, dir, &entry.name).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a whiteout for the given name in the given upper directory
+    async fn create_whiteout(&self, req: &Request, dir: u64, name: &OsStr) -> Result<(), Errno> {
+        let entry = self.upper.mknod(req, dir, name, FileType::CharDevice.mode(), 0).await?;
+        self.upper.forget(req, entry.attr.ino, 1).await;
+        Ok(())
+    }
+
+    /// Copies the given inode and its parent directories to the upper filesystem unless they
+    /// are there already, and returns its upper inode
+    async fn copy_up(&self, req: &Request, ino: u64) -> Result<u64, Errno> {
+        let mut missing = Vec::new();
+        let mut path = self.path(ino)?;
+        // The root always exists in the upper filesystem
+        let mut dir = loop {
+            let ino = self.table.ino(&path).ok_or(Errno::ENOENT)?;
+            match self.layers(ino)?.upper {
+                Some(upper) => break upper,
+                None => missing.push((ino, path.clone())),
+            }
+            path = path.parent().ok_or(Errno::ENOENT)?.to_path_buf();
+        };
+        for (ino, path) in missing.into_iter().rev() {
+            dir = self.copy_up_one(req, ino, dir, path.file_name().ok_or(Errno::ENOENT)?).await?;
+        }
+        Ok(dir)
+    }
+
+    /// Copies the given lower inode to the given name in the given upper directory
+    async fn copy_up_one(&self, req: &Request, ino: u64, dir: u64, name: &OsStr) -> Result<u64, Errno> {
+        let lower = self.layers(ino)?.lower.ok_or(Errno::ENOENT)?;
+        let attr = self.lower.getattr(req, lower).await?.attr;
+        let perm = attr.perm as u32;
+        let entry = match attr.kind {
+            FileType::Directory => self.upper.mkdir(req, dir, name, perm).await?,
+            FileType::Symlink => {
+                let link = self.lower.readlink(req, lower).await?;
+                self.upper.symlink(req, dir, name, Path::new(OsStr::from_bytes(&link))).await?
+            }
+            FileType::RegularFile => self.copy_file(req, lower, dir, name, perm).await?,
+            kind => self.upper.mknod(req, dir, name, kind.mode() | perm, attr.rdev).await?,
+        };
+        // Ownership and times can't be set in every filesystem (e.g. by unprivileged users),
+        // the copy is still usable without them
+        let _ = self.upper.setattr(req, entry.attr.ino, None, Some(attr.uid), Some(attr.gid), None, Some(attr.atime), Some(attr.mtime), None, None, None, None, None).await;
+        self.record(req, ino, Some(entry.attr.ino), None).await;
+        Ok(entry.attr.ino)
+    }
+
+    /// Copies the contents of the given lower file to a new file in the given upper directory
+    async fn copy_file(&self, req: &Request, lower: u64, dir: u64, name: &OsStr, perm: u32) -> Result<Entry, Errno> {
+        let flags = O_WRONLY as u32;
+        let created = self.upper.create(req, dir, name, FileType::RegularFile.mode() | perm, flags).await?;
+        let result = self.copy_data(req, lower, created.attr.ino, created.fh).await;
+        let _ = self.upper.release(req, created.attr.ino, created.fh, flags, 0, true).await;
+        if let Err(err) = result {
+            self.upper.forget(req, created.attr.ino, 1).await;
+            let _ = self.upper.unlink(req, dir, name).await;
+            return Err(err);
+        }
+        Ok(Entry { ttl: created.ttl, attr: created.attr, generation: created.generation })
+    }
+
+    async fn copy_data(&self, req: &Request, lower: u64, upper: u64, fh: u64) -> Result<(), Errno> {
+        let opened = self.lower.open(req, lower, O_RDONLY as u32).await?;
+        let result = async {
+            let mut offset = 0;
+            loop {
+                let data = self.lower.read(req, lower, opened.fh, offset, COPY_SIZE).await?;
+                if data.is_empty() { return Ok(()); }
+                let mut written = 0;
+                while written < data.len() {
+                    match self.upper.write(req, upper, fh, offset + written as i64, &data[written..], 0).await? {
+                        0 => return Err(Errno::EIO),
+                        n => written += n as usize,
+                    }
+                }
+                offset += data.len() as i64;
+            }
+        }.await;
+        let _ = self.lower.release(req, lower, opened.fh, O_RDONLY as u32, 0, false).await;
+        result
+    }
+
+    /// Prepares creating the given name in a directory: fails if it exists, copies the directory
+    /// up and removes a whiteout of the name. Returns the upper directory and whether the name
+    /// replaces a removed lower file.
+    async fn prepare(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(u64, bool), Errno> {
+        let (upper, lower, _) = self.find(req, self.layers(parent)?, name).await?;
+        if upper.is_some() || lower.is_some() {
+            self.forget_entries(req, upper, lower).await;
+            return Err(Errno::EEXIST);
+        }
+        let dir = self.copy_up(req, parent).await?;
+        if self.whiteout(req, dir, name).await {
+            self.upper.unlink(req, dir, name).await?;
+            Ok((dir, true))
+        } else {
+            Ok((dir, false))
+        }
+    }
+
+    /// Returns the overlay entry of a file created in the upper filesystem
+    async fn created(&self, req: &Request, parent: u64, name: &OsStr, entry: Entry) -> Result<Entry, Errno> {
+        let path = self.path(parent)?.join(name);
+        self.entry(req, path, Some(entry), None).await
+    }
+
+    async fn remove(&self, req: &Request, parent: u64, name: &OsStr, rmdir: bool) -> Result<(), Errno> {
+        let (upper, lower, hidden) = self.find(req, self.layers(parent)?, name).await?;
+        let result = self.remove_found(req, parent, name, rmdir, upper, lower, hidden).await;
+        self.forget_entries(req, upper, lower).await;
+        if result.is_ok() {
+            self.table.remove(&self.path(parent)?.join(name));
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn remove_found(&self, req: &Request, parent: u64, name: &OsStr, rmdir: bool, upper: Option<Entry>, lower: Option<Entry>, hidden: bool) -> Result<(), Errno> {
+        let is_dir = upper.or(lower).ok_or(Errno::ENOENT)?.attr.kind == FileType::Directory;
+        match (rmdir, is_dir) {
+            (true, false) => return Err(Errno::ENOTDIR),
+            (false, true) => return Err(Errno::EISDIR),
+            (true, true) => self.clear(req, upper, lower).await?,
+            _ => (),
+        }
+        if upper.is_some() {
+            let dir = self.layers(parent)?.upper.ok_or(Errno::ENOENT)?;
+            if rmdir { self.upper.rmdir(req, dir, name).await?; } else { self.upper.unlink(req, dir, name).await?; }
+        }
+        if hidden {
+            let dir = self.copy_up(req, parent).await?;
+            self.create_whiteout(req, dir, name).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the lower inode of the given overlay inode, which is hidden now
+    async fn hide_lower(&self, req: &Request, ino: u64) {
+        let lower = self.nodes.lock().unwrap().get_mut(&ino).and_then(|node| node.lower.take());
+        if let Some(lower) = lower {
+            self.lower.forget(req, lower, 1).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<U: Filesystem + Send + Sync, L: Filesystem + Send + Sync> Filesystem for Overlay<U, L> {
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.upper.init(req).await?;
+        self.lower.init(req).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        self.upper.destroy(req).await;
+        self.lower.destroy(req).await;
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let path = self.path(parent)?.join(name);
+        let (upper, lower, _) = self.find(req, self.layers(parent)?, name).await?;
+        self.entry(req, path, upper, lower).await
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        if self.table.forget(ino, nlookup).is_none() { return; }
+        let layers = self.nodes.lock().unwrap().remove(&ino).unwrap_or_default();
+        if let Some(upper) = layers.upper { self.upper.forget(req, upper, 1).await; }
+        if let Some(lower) = layers.lower { self.lower.forget(req, lower, 1).await; }
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        let attr = match self.visible(ino)? {
+            (Side::Upper, upper) => self.upper.getattr(req, upper).await?,
+            (Side::Lower, lower) => self.lower.getattr(req, lower).await?,
+        };
+        Ok(Attr { ttl: attr.ttl, attr: FileAttr { ino, ..attr.attr } })
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let upper = self.copy_up(req, ino).await?;
+        let fh = fh.and_then(|fh| match self.file(fh) {
+            Ok((Side::Upper, _, fh)) => Some(fh),
+            _ => None,
+        });
+        let attr = self.upper.setattr(req, upper, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await?;
+        Ok(Attr { ttl: attr.ttl, attr: FileAttr { ino, ..attr.attr } })
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        match self.visible(ino)? {
+            (Side::Upper, upper) => self.upper.readlink(req, upper).await,
+            (Side::Lower, lower) => self.lower.readlink(req, lower).await,
+        }
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let (dir, _) = self.prepare(req, parent, name).await?;
+        let entry = self.upper.mknod(req, dir, name, mode, rdev).await?;
+        self.created(req, parent, name, entry).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let (dir, replaces) = self.prepare(req, parent, name).await?;
+        let entry = self.upper.mkdir(req, dir, name, mode).await?;
+        if replaces {
+            self.upper.setxattr(req, entry.attr.ino, OsStr::new(OPAQUE_XATTR), b"y", 0, 0).await?;
+        }
+        self.created(req, parent, name, entry).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.remove(req, parent, name, false).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.remove(req, parent, name, true).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let (dir, _) = self.prepare(req, parent, name).await?;
+        let entry = self.upper.symlink(req, dir, name, link).await?;
+        self.created(req, parent, name, entry).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let from = self.path(parent)?.join(name);
+        let to = self.path(newparent)?.join(newname);
+        let (upper, lower, hidden) = self.find(req, self.layers(parent)?, name).await?;
+        self.forget_entries(req, upper, lower).await;
+        let is_dir = upper.or(lower).ok_or(Errno::ENOENT)?.attr.kind == FileType::Directory;
+        if is_dir && lower.is_some() { return Err(Errno::EXDEV); }
+        let ino = self.table.ino(&from);
+        if upper.is_none() {
+            self.copy_up(req, ino.ok_or(Errno::ENOENT)?).await?;
+        }
+        let dir = self.layers(parent)?.upper.ok_or(Errno::ENOENT)?;
+
+        let (target_upper, target_lower, target_hidden) = self.find(req, self.layers(newparent)?, newname).await?;
+        let cleared = match target_upper.or(target_lower) {
+            Some(target) if is_dir && target.attr.kind == FileType::Directory => self.clear(req, target_upper, target_lower).await,
+            _ => Ok(()),
+        };
+        self.forget_entries(req, target_upper, target_lower).await;
+        cleared?;
+        let newdir = self.copy_up(req, newparent).await?;
+        if target_upper.is_none() && self.whiteout(req, newdir, newname).await {
+            self.upper.unlink(req, newdir, newname).await?;
+        }
+
+        self.upper.rename(req, dir, name, newdir, newname).await?;
+        if hidden {
+            self.create_whiteout(req, dir, name).await?;
+        }
+        if is_dir && target_hidden {
+            let moved = self.upper.lookup(req, newdir, newname).await?;
+            let result = self.upper.setxattr(req, moved.attr.ino, OsStr::new(OPAQUE_XATTR), b"y", 0, 0).await;
+            self.upper.forget(req, moved.attr.ino, 1).await;
+            result?;
+        }
+        if let Some(ino) = ino {
+            self.hide_lower(req, ino).await;
+        }
+        self.table.remap(|path| path.strip_prefix(&from).ok().map(|rest| to.join(rest)));
+        Ok(())
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let upper = self.copy_up(req, ino).await?;
+        let (dir, _) = self.prepare(req, newparent, newname).await?;
+        let entry = self.upper.link(req, upper, dir, newname).await?;
+        self.created(req, newparent, newname, entry).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        let (side, layer_ino) = if writes(flags) {
+            (Side::Upper, self.copy_up(req, ino).await?)
+        } else {
+            self.visible(ino)?
+        };
+        let opened = match side {
+            Side::Upper => self.upper.open(req, layer_ino, flags).await?,
+            Side::Lower => self.lower.open(req, layer_ino, flags).await?,
+        };
+        let fh = self.open_handle(Handle::File(side, layer_ino, opened.fh));
+        Ok(Opened { fh, flags: opened.flags, backing: opened.backing })
+    }
+
+    async fn read(&self, req: &Request, _ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.read(req, ino, fh, offset, size).await,
+            (Side::Lower, ino, fh) => self.lower.read(req, ino, fh, offset, size).await,
+        }
+    }
+
+    async fn write(&self, req: &Request, _ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.write(req, ino, fh, offset, data, flags).await,
+            (Side::Lower, _, _) => Err(Errno::EBADF),
+        }
+    }
+
+    async fn flush(&self, req: &Request, _ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.flush(req, ino, fh, lock_owner).await,
+            (Side::Lower, ino, fh) => self.lower.flush(req, ino, fh, lock_owner).await,
+        }
+    }
+
+    async fn release(&self, req: &Request, _ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        let file = self.file(fh)?;
+        self.handles.lock().unwrap().remove(&fh);
+        match file {
+            (Side::Upper, ino, fh) => self.upper.release(req, ino, fh, flags, lock_owner, flush).await,
+            (Side::Lower, ino, fh) => self.lower.release(req, ino, fh, flags, lock_owner, flush).await,
+        }
+    }
+
+    async fn fsync(&self, req: &Request, _ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.fsync(req, ino, fh, datasync).await,
+            (Side::Lower, ino, fh) => self.lower.fsync(req, ino, fh, datasync).await,
+        }
+    }
+
+    async fn opendir(&self, _req: &Request, ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        let layers = self.layers(ino)?;
+        Ok(Opened { fh: self.open_handle(Handle::Dir(layers, None)), flags: OpenFlags::empty(), backing: None })
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        let (layers, cached) = match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::Dir(layers, cached)) => (*layers, cached.clone()),
+            _ => return Err(Errno::EBADF),
+        };
+        let entries = match cached {
+            Some(entries) if !offset.is_start() => entries,
+            _ => {
+                let path = self.path(ino)?;
+                let entries: Vec<DirEntry> = self.merged(req, layers).await?.into_iter().enumerate().map(|(i, entry)| {
+                    let known = match entry.name.as_bytes() {
+                        b"." => Some(ino),
+                        b".." => path.parent().and_then(|parent| self.table.ino(&parent.to_path_buf())),
+                        _ => self.table.ino(&path.join(&entry.name)),
+                    };
+                    DirEntry { ino: known.unwrap_or(UNKNOWN_INO), offset: DirOffset::new(i as u64 + 1, fh), ..entry }
+                }).collect();
+                let entries = Arc::new(entries);
+                if let Some(Handle::Dir(_, cached)) = self.handles.lock().unwrap().get_mut(&fh) {
+                    *cached = Some(entries.clone());
+                }
+                entries
+            }
+        };
+        Ok(entries.iter().skip(offset.position(fh)? as usize).cloned().collect())
+    }
+
+    async fn releasedir(&self, _req: &Request, _ino: u64, fh: u64, _flags: u32) -> Result<(), Errno> {
+        self.handles.lock().unwrap().remove(&fh);
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    async fn statfs(&self, req: &Request, _ino: u64) -> Result<Statfs, Errno> {
+        self.upper.statfs(req, FUSE_ROOT_ID).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        let upper = self.copy_up(req, ino).await?;
+        self.upper.setxattr(req, upper, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        match self.visible(ino)? {
+            (Side::Upper, upper) => self.upper.getxattr(req, upper, name, size).await,
+            (Side::Lower, lower) => self.lower.getxattr(req, lower, name, size).await,
+        }
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        match self.visible(ino)? {
+            (Side::Upper, upper) => self.upper.listxattr(req, upper, size).await,
+            (Side::Lower, lower) => self.lower.listxattr(req, lower, size).await,
+        }
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let upper = self.copy_up(req, ino).await?;
+        self.upper.removexattr(req, upper, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        match self.visible(ino)? {
+            (Side::Upper, upper) => self.upper.access(req, upper, mask).await,
+            (Side::Lower, lower) => self.lower.access(req, lower, mask).await,
+        }
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let (dir, _) = self.prepare(req, parent, name).await?;
+        let created = self.upper.create(req, dir, name, mode, flags).await?;
+        let layer_entry = Entry { ttl: created.ttl, attr: created.attr, generation: created.generation };
+        let entry = self.created(req, parent, name, layer_entry).await?;
+        let fh = self.open_handle(Handle::File(Side::Upper, created.attr.ino, created.fh));
+        Ok(Created { ttl: entry.ttl, attr: entry.attr, generation: entry.generation, fh, flags: created.flags, backing: created.backing })
+    }
+
+    async fn getlk(&self, req: &Request, _ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.getlk(req, ino, fh, lock_owner, start, end, typ, pid).await,
+            (Side::Lower, ino, fh) => self.lower.getlk(req, ino, fh, lock_owner, start, end, typ, pid).await,
+        }
+    }
+
+    async fn setlk(&self, req: &Request, _ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        match self.file(fh)? {
+            (Side::Upper, ino, fh) => self.upper.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await,
+            (Side::Lower, ino, fh) => self.lower.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::sync::Mutex;
+    use std::time::UNIX_EPOCH;
+    use async_trait::async_trait;
+    use fuse_abi::FUSE_ROOT_ID;
+    use libc::{O_RDONLY, O_RDWR};
+    use crate::{block_on, Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, OpenFlags, Request, Xattr};
+    use super::Overlay;
+
+    struct Node {
+        parent: u64,
+        name: OsString,
+        attr: FileAttr,
+        data: Vec<u8>,
+        xattrs: HashMap<OsString, Vec<u8>>,
+        removed: bool,
+    }
+
+    /// Minimal in-memory filesystem, inode numbers are indexes of nodes plus one
+    struct Mem {
+        nodes: Mutex<Vec<Node>>,
+    }
+
+    impl Mem {
+        fn new() -> Mem {
+            let root = Node { parent: 0, name: "/".into(), attr: FileAttr::builder(FUSE_ROOT_ID, FileType::Directory).time(UNIX_EPOCH).build(), data: Vec::new(), xattrs: HashMap::new(), removed: false };
+            Mem { nodes: Mutex::new(vec![root]) }
+        }
+
+        fn add(&self, parent: u64, name: &OsStr, kind: FileType, rdev: u32, data: &[u8]) -> FileAttr {
+            let mut nodes = self.nodes.lock().unwrap();
+            let attr = FileAttr::builder(nodes.len() as u64 + 1, kind).time(UNIX_EPOCH).size(data.len() as u64).rdev(rdev).build();
+            nodes.push(Node { parent, name: name.into(), attr, data: data.to_vec(), xattrs: HashMap::new(), removed: false });
+            attr
+        }
+
+        fn find(&self, parent: u64, name: &OsStr) -> Option<u64> {
+            let nodes = self.nodes.lock().unwrap();
+            nodes.iter().position(|node| !node.removed && node.parent == parent && node.name == name).map(|i| i as u64 + 1)
+        }
+
+        fn file(self, path: &str, data: &str) -> Mem {
+            let mut parent = FUSE_ROOT_ID;
+            let mut names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+            let name = names.pop().unwrap();
+            for dir in names {
+                parent = match self.find(parent, OsStr::new(dir)) {
+                    Some(ino) => ino,
+                    None => self.add(parent, OsStr::new(dir), FileType::Directory, 0, b"").ino,
+                };
+            }
+            self.add(parent, OsStr::new(name), FileType::RegularFile, 0, data.as_bytes());
+            self
+        }
+    }
+
+    #[async_trait]
+    impl Filesystem for Mem {
+        async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            let ino = self.find(parent, name).ok_or(Errno::ENOENT)?;
+            Ok(Entry::new(self.nodes.lock().unwrap()[ino as usize - 1].attr, 0))
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(self.nodes.lock().unwrap()[ino as usize - 1].attr))
+        }
+
+        async fn mknod(&self, _req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+            Ok(Entry::new(self.add(parent, name, FileType::from_mode(mode).unwrap(), rdev, b""), 0))
+        }
+
+        async fn mkdir(&self, _req: &Request, parent: u64, name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+            Ok(Entry::new(self.add(parent, name, FileType::Directory, 0, b""), 0))
+        }
+
+        async fn unlink(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+            let ino = self.find(parent, name).ok_or(Errno::ENOENT)?;
+            self.nodes.lock().unwrap()[ino as usize - 1].removed = true;
+            Ok(())
+        }
+
+        async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+            let ino = self.find(parent, name).ok_or(Errno::ENOENT)?;
+            if self.nodes.lock().unwrap().iter().any(|node| !node.removed && node.parent == ino) { return Err(Errno::ENOTEMPTY); }
+            self.unlink(req, parent, name).await
+        }
+
+        async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+            let ino = self.find(parent, name).ok_or(Errno::ENOENT)?;
+            let _ = self.unlink(req, newparent, newname).await;
+            let node = &mut self.nodes.lock().unwrap()[ino as usize - 1];
+            node.parent = newparent;
+            node.name = newname.into();
+            Ok(())
+        }
+
+        async fn create(&self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+            let attr = self.add(parent, name, FileType::RegularFile, 0, b"");
+            Ok(Created { ttl: None, attr, generation: 0, fh: 0, flags: OpenFlags::empty(), backing: None })
+        }
+
+        async fn read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+            let data = &self.nodes.lock().unwrap()[ino as usize - 1].data;
+            let start = (offset as usize).min(data.len());
+            Ok(data[start..(start + size as usize).min(data.len())].into())
+        }
+
+        async fn write(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            let node = &mut self.nodes.lock().unwrap()[ino as usize - 1];
+            let end = offset as usize + data.len();
+            if node.data.len() < end { node.data.resize(end, 0); }
+            node.data[offset as usize..end].copy_from_slice(data);
+            node.attr.size = node.data.len() as u64;
+            Ok(data.len() as u32)
+        }
+
+        async fn readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+            let nodes = self.nodes.lock().unwrap();
+            let children = nodes.iter().filter(|node| !node.removed && node.parent == ino);
+            Ok(children.enumerate().skip(offset.position(0)? as usize).map(|(i, node)| {
+                DirEntry { ino: node.attr.ino, offset: DirOffset::new(i as u64 + 1, 0), kind: node.attr.kind, name: node.name.clone() }
+            }).collect())
+        }
+
+        async fn setxattr(&self, _req: &Request, ino: u64, name: &OsStr, value: &[u8], _flags: u32, _position: u32) -> Result<(), Errno> {
+            self.nodes.lock().unwrap()[ino as usize - 1].xattrs.insert(name.into(), value.to_vec());
+            Ok(())
+        }
+
+        async fn getxattr(&self, _req: &Request, ino: u64, name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+            self.nodes.lock().unwrap()[ino as usize - 1].xattrs.get(name).map(|value| Xattr::Data(value.clone())).ok_or(Errno::ENODATA)
+        }
+    }
+
+    type Fs = Overlay<Mem, Mem>;
+
+    fn lookup(fs: &Fs, path: &str) -> Result<u64, Errno> {
+        let req = Request::test(0, 0);
+        path.split('/').filter(|name| !name.is_empty()).try_fold(FUSE_ROOT_ID, |ino, name| {
+            block_on(fs.lookup(&req, ino, OsStr::new(name))).map(|entry| entry.attr.ino)
+        })
+    }
+
+    fn read(fs: &Fs, path: &str) -> Vec<u8> {
+        let req = Request::test(0, 0);
+        let ino = lookup(fs, path).unwrap();
+        let opened = block_on(fs.open(&req, ino, O_RDONLY as u32)).unwrap();
+        let data = block_on(fs.read(&req, ino, opened.fh, 0, 4096)).unwrap().to_vec();
+        block_on(fs.release(&req, ino, opened.fh, 0, 0, false)).unwrap();
+        data
+    }
+
+    fn names(fs: &Fs, path: &str) -> Vec<String> {
+        let req = Request::test(0, 0);
+        let ino = lookup(fs, path).unwrap();
+        let opened = block_on(fs.opendir(&req, ino, 0)).unwrap();
+        let entries = block_on(fs.readdir(&req, ino, opened.fh, DirOffset::START)).unwrap();
+        let mut names: Vec<String> = entries.into_iter().map(|entry| entry.name.into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    fn overlay() -> Fs {
+        Overlay::new(Mem::new().file("/a", "upper a"), Mem::new().file("/a", "lower a").file("/b", "lower b").file("/d/x", "x"))
+    }
+
+    #[test]
+    fn merge() {
+        let fs = overlay();
+        assert_eq!(read(&fs, "/a"), b"upper a");
+        assert_eq!(read(&fs, "/b"), b"lower b");
+        assert_eq!(read(&fs, "/d/x"), b"x");
+        assert_eq!(names(&fs, "/"), ["a", "b", "d"]);
+        assert_eq!(lookup(&fs, "/c"), Err(Errno::ENOENT));
+    }
+
+    #[test]
+    fn copy_up() {
+        let fs = overlay();
+        let req = Request::test(0, 0);
+        let ino = lookup(&fs, "/d/x").unwrap();
+        let opened = block_on(fs.open(&req, ino, O_RDWR as u32)).unwrap();
+        assert_eq!(block_on(fs.write(&req, ino, opened.fh, 1, b"yz", 0)), Ok(2));
+        block_on(fs.release(&req, ino, opened.fh, 0, 0, false)).unwrap();
+        assert_eq!(read(&fs, "/d/x"), b"xyz");
+        assert_eq!(block_on(fs.lower().lookup(&req, 4, OsStr::new("x"))).unwrap().attr.size, 1);
+        assert!(fs.upper().find(FUSE_ROOT_ID, OsStr::new("d")).is_some());
+    }
+
+    #[test]
+    fn whiteouts() {
+        let fs = overlay();
+        let req = Request::test(0, 0);
+        block_on(fs.unlink(&req, FUSE_ROOT_ID, OsStr::new("b"))).unwrap();
+        block_on(fs.unlink(&req, FUSE_ROOT_ID, OsStr::new("a"))).unwrap();
+        assert_eq!(lookup(&fs, "/a"), Err(Errno::ENOENT));
+        assert_eq!(lookup(&fs, "/b"), Err(Errno::ENOENT));
+        assert_eq!(names(&fs, "/"), ["d"]);
+
+        assert_eq!(block_on(fs.rmdir(&req, FUSE_ROOT_ID, OsStr::new("d"))), Err(Errno::ENOTEMPTY));
+        let d = lookup(&fs, "/d").unwrap();
+        block_on(fs.unlink(&req, d, OsStr::new("x"))).unwrap();
+        block_on(fs.rmdir(&req, FUSE_ROOT_ID, OsStr::new("d"))).unwrap();
+        assert_eq!(names(&fs, "/"), Vec::<String>::new());
+
+        block_on(fs.mkdir(&req, FUSE_ROOT_ID, OsStr::new("d"), 0o755)).unwrap();
+        assert_eq!(names(&fs, "/d"), Vec::<String>::new());
+        block_on(fs.create(&req, FUSE_ROOT_ID, OsStr::new("b"), 0o644, 0)).unwrap();
+        assert_eq!(read(&fs, "/b"), b"");
+    }
+
+    #[test]
+    fn rename() {
+        let fs = overlay();
+        let req = Request::test(0, 0);
+        // The kernel looks up files before renaming them
+        lookup(&fs, "/b").unwrap();
+        block_on(fs.rename(&req, FUSE_ROOT_ID, OsStr::new("b"), FUSE_ROOT_ID, OsStr::new("c"))).unwrap();
+        assert_eq!(read(&fs, "/c"), b"lower b");
+        assert_eq!(lookup(&fs, "/b"), Err(Errno::ENOENT));
+        assert_eq!(block_on(fs.rename(&req, FUSE_ROOT_ID, OsStr::new("d"), FUSE_ROOT_ID, OsStr::new("e"))), Err(Errno::EXDEV));
+    }
+}
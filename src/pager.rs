@@ -0,0 +1,183 @@
+//! Directory pagination
+//!
+//! The kernel reads a directory in pages, passing the offset of the last entry it got to the
+//! next readdir. Offsets must stay valid while the directory changes, and the kernel may request
+//! a page again (e.g. after an interrupted readdir), so offsets that are positions in a fresh
+//! listing of the directory skip or repeat entries. `DirPager` takes a listing of a directory
+//! once per open directory handle and serves all pages from it, with the position in this
+//! snapshot as offset. Reading from offset 0 again (rewinddir) takes a new listing, offsets of
+//! the previous listing of the handle are stale then.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::DirEntryExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{DirEntry, DirOffset, Errno, FileType};
+
+/// Boxed iterator of directory entries
+type Entries = Box<dyn Iterator<Item = DirEntry> + Send>;
+
+/// Listing of a directory taken for a directory handle
+struct Listing {
+    verifier: u64,
+    entries: Vec<DirEntry>,
+    rest: Option<Entries>,
+}
+
+impl Listing {
+    fn new(verifier: u64, entries: Entries) -> Listing {
+        Listing { verifier, entries: Vec::new(), rest: Some(entries) }
+    }
+
+    /// Returns up to the given number of entries after the given offset, pulling them from the
+    /// lazily produced entries as needed
+    fn page(&mut self, offset: usize, count: usize) -> Vec<DirEntry> {
+        let end = offset.saturating_add(count);
+        while self.entries.len() < end {
+            let entry = match self.rest.as_mut().and_then(Iterator::next) {
+                Some(entry) => entry,
+                None => { self.rest = None; break; },
+            };
+            let offset = DirOffset::new(self.entries.len() as u64 + 1, self.verifier);
+            self.entries.push(DirEntry { offset, ..entry });
+        }
+        self.entries.get(offset..end.min(self.entries.len())).unwrap_or_default().to_vec()
+    }
+}
+
+/// Pages of directory listings with offsets that stay valid while the directory changes
+pub struct DirPager {
+    page_size: usize,
+    listings: Mutex<HashMap<u64, Arc<Mutex<Listing>>>>,
+    next_verifier: AtomicU64,
+}
+
+impl DirPager {
+    /// Create a pager returning up to the given number of entries per readdir
+    pub fn new(page_size: usize) -> DirPager {
+        DirPager { page_size: page_size.max(1), listings: Mutex::default(), next_verifier: AtomicU64::new(1) }
+    }
+
+    /// Returns the entries of a readdir of the given directory handle at the given offset.
+    /// The given function lists the directory when reading from offset 0 or from a handle
+    /// without a listing. Its entries may be produced lazily, and their offsets are replaced.
+    /// Offsets that weren't handed out for the current listing of the handle fail with
+    /// `ESTALE`.
+    pub async fn readdir<F, Fut, I>(&self, fh: u64, offset: DirOffset, list: F) -> Result<Vec<DirEntry>, Errno>
+    where F: FnOnce() -> Fut, Fut: Future<Output = Result<I, Errno>>, I: IntoIterator<Item = DirEntry>, I::IntoIter: Send + 'static {
+        let listing = match self.listings.lock().unwrap().get(&fh) {
+            Some(listing) if !offset.is_start() => Some(listing.clone()),
+            _ => None,
+        };
+        let (listing, position) = match listing {
+            Some(listing) => {
+                let position = offset.position(listing.lock().unwrap().verifier)?;
+                (listing, position)
+            }
+            None => {
+                // Without a listing (e.g. after the filesystem was restarted) the offset can't
+                // be validated and can only be taken as a position in a new listing
+                let verifier = self.next_verifier.fetch_add(1, Ordering::Relaxed);
+                let listing = Arc::new(Mutex::new(Listing::new(verifier, Box::new(list().await?.into_iter()))));
+                self.listings.lock().unwrap().insert(fh, listing.clone());
+                (listing, offset.unverified_position())
+            }
+        };
+        let position = usize::try_from(position).map_err(|_| Errno::EINVAL)?;
+        let entries = listing.lock().unwrap().page(position, self.page_size);
+        Ok(entries)
+    }
+
+    /// Forget the listing of the given directory handle (on releasedir)
+    pub fn release(&self, fh: u64) {
+        self.listings.lock().unwrap().remove(&fh);
+    }
+}
+
+impl fmt::Debug for DirPager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "DirPager({} listings)", self.listings.lock().unwrap().len())
+    }
+}
+
+/// Returns the entries of the directory at the given path for a readdir, with their file types
+/// taken from the directory (without looking up every entry where the platform supports it).
+/// Entries that vanish while listing are skipped.
+pub fn read_dir_entries<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = DirEntry> + Send + 'static> {
+    Ok(fs::read_dir(path)?.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let kind = FileType::from(entry.file_type().ok()?);
+        Some(DirEntry { ino: entry.ino(), offset: DirOffset::START, kind, name: entry.file_name() })
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{block_on, DirEntry, DirOffset, Errno, FileType};
+    use super::{read_dir_entries, DirPager};
+
+    fn entries(names: &[&str]) -> Vec<DirEntry> {
+        names.iter().enumerate().map(|(i, name)| DirEntry { ino: i as u64 + 2, offset: DirOffset::START, kind: FileType::RegularFile, name: name.into() }).collect()
+    }
+
+    fn names(entries: &[DirEntry]) -> Vec<String> {
+        entries.iter().map(|entry| entry.name.to_str().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn pages() {
+        let pager = DirPager::new(2);
+        let list = |names: &'static [&'static str]| move || async move { Ok::<_, Errno>(entries(names)) };
+        let first = block_on(pager.readdir(1, DirOffset::START, list(&["a", "b", "c"]))).unwrap();
+        assert_eq!(names(&first), ["a", "b"]);
+        // Later pages come from the same listing, even if the directory changed meanwhile
+        let page = block_on(pager.readdir(1, first[1].offset, list(&["b", "c"]))).unwrap();
+        assert_eq!(names(&page), ["c"]);
+        // Pages can be requested again
+        assert_eq!(names(&block_on(pager.readdir(1, first[0].offset, list(&[]))).unwrap()), ["b", "c"]);
+        assert!(block_on(pager.readdir(1, page[0].offset, list(&[]))).unwrap().is_empty());
+        // Rewinding takes a new listing, offsets of the previous one are stale
+        assert_eq!(names(&block_on(pager.readdir(1, DirOffset::START, list(&["b", "c"]))).unwrap()), ["b", "c"]);
+        assert_eq!(block_on(pager.readdir(1, first[0].offset, list(&[]))).unwrap_err(), Errno::ESTALE);
+        assert_eq!(block_on(pager.readdir(1, DirOffset::from_raw(1), list(&[]))).unwrap_err(), Errno::ESTALE);
+        // Without a listing, the offset is taken as a position in a new one
+        pager.release(1);
+        assert_eq!(names(&block_on(pager.readdir(1, first[0].offset, list(&["a", "b", "c"]))).unwrap()), ["b", "c"]);
+    }
+
+    #[test]
+    fn lazy() {
+        let pager = DirPager::new(10);
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let list = move || async move {
+            Ok::<_, Errno>((0..100).map(move |i| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                DirEntry { ino: i + 2, offset: DirOffset::START, kind: FileType::RegularFile, name: format!("{}", i).into() }
+            }))
+        };
+        assert_eq!(block_on(pager.readdir(1, DirOffset::START, list)).unwrap().len(), 10);
+        assert_eq!(produced.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn read_dir() {
+        let dir = std::env::temp_dir().join(format!("async-fuse-pager-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let mut entries: Vec<_> = read_dir_entries(&dir).unwrap().map(|entry| (entry.name.into_string().unwrap(), entry.kind)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, [("file".to_string(), FileType::RegularFile), ("sub".to_string(), FileType::Directory)]);
+    }
+}
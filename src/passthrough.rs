@@ -0,0 +1,101 @@
+//! Passthrough of file reads and writes
+//!
+//! Since Linux 6.9, a filesystem can register a file of its own (e.g. the underlying file of a
+//! stacking filesystem) as the backing file of a file it opens. The kernel then reads and
+//! writes the backing file directly, without sending read and write requests to the daemon.
+//! Passthrough is negotiated at init if the session enables it (`Session::passthrough`), which
+//! needs CAP_SYS_ADMIN. Backing files are registered on the fd to the kernel driver with
+//! `BackingFiles`, and attached to opened files by returning their id in `Opened::backing`
+//! or `Created::backing`.
+
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+#[cfg(target_os = "linux")]
+use std::sync::{Arc, RwLock};
+#[cfg(target_os = "linux")]
+use fuse_abi::{fuse_backing_map, FUSE_DEV_IOC_BACKING_CLOSE, FUSE_DEV_IOC_BACKING_OPEN};
+
+#[cfg(target_os = "linux")]
+use crate::channel::Channel;
+#[cfg(target_os = "linux")]
+use crate::session::ConnectionInfo;
+
+/// Id of a backing file registered with the kernel driver
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct BackingId(pub(crate) u32);
+
+impl BackingId {
+    /// Returns the id the kernel driver assigned to the backing file
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Handle for registering backing files of a running session
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct BackingFiles {
+    ch: Arc<Channel>,
+    conn: Arc<RwLock<ConnectionInfo>>,
+}
+
+#[cfg(target_os = "linux")]
+impl BackingFiles {
+    pub(crate) fn new(ch: Arc<Channel>, conn: Arc<RwLock<ConnectionInfo>>) -> BackingFiles {
+        BackingFiles { ch, conn }
+    }
+
+    /// Register the given file as a backing file. Fails with EOPNOTSUPP if the kernel driver
+    /// didn't enable passthrough at init. The kernel keeps a reference to the file, so the
+    /// given fd can be closed afterwards.
+    pub fn open(&self, file: BorrowedFd<'_>) -> io::Result<BackingId> {
+        if !self.conn.read().unwrap().passthrough {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        let map = fuse_backing_map { fd: file.as_raw_fd(), flags: 0, padding: 0 };
+        let id = unsafe { libc::ioctl(self.ch.as_raw_fd(), FUSE_DEV_IOC_BACKING_OPEN as _, &map) };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(BackingId(id as u32))
+    }
+
+    /// Unregister the given backing file. Files opened with it keep using it until released.
+    pub fn close(&self, id: BackingId) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.ch.as_raw_fd(), FUSE_DEV_IOC_BACKING_CLOSE as _, &id.0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use async_trait::async_trait;
+    use crate::{block_on, Errno, Filesystem, MockChannel, Opened, OpenFlags, Request};
+    use super::BackingId;
+
+    /// Filesystem whose files are backed by the backing file with id 3
+    struct Backed;
+
+    #[async_trait]
+    impl Filesystem for Backed {
+        async fn open(&self, _req: &Request, ino: u64, _flags: u32) -> Result<Opened, Errno> {
+            Ok(Opened { fh: ino, flags: OpenFlags::KEEP_CACHE, backing: Some(BackingId(3)) })
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        // Passthrough is only enabled if the session wants it and the kernel supports it
+        assert!(!block_on(MockChannel::new(Backed).init_passthrough()).unwrap().passthrough);
+        assert!(!block_on(MockChannel::new(Backed).passthrough(true).init()).unwrap().passthrough);
+        let mock = MockChannel::new(Backed).passthrough(true);
+        assert!(block_on(mock.init_passthrough()).unwrap().passthrough);
+        let opened = block_on(mock.open(2, 0)).unwrap();
+        assert_eq!(opened, Opened { fh: 2, flags: OpenFlags::KEEP_CACHE, backing: Some(BackingId(3)) });
+    }
+}
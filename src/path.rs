@@ -0,0 +1,593 @@
+//! Path based filesystem
+//!
+//! Many filesystems (e.g. archive or API mounts) naturally address their files by path and have
+//! no use for inode numbers. A `PathFilesystem` implements operations on full paths instead, and
+//! the `PathFs` adapter turns it into a `Filesystem` by maintaining the table of inode numbers
+//! and paths, including lookup counts and renames.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use async_trait::async_trait;
+
+#[cfg(not(target_os = "macos"))]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(not(target_os = "macos"))]
+use fuse_abi::consts::RENAME_EXCHANGE;
+
+use crate::inode::{InodeTable, time_generation};
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+
+/// Path based filesystem trait.
+///
+/// Operations receive the full path (relative to the mountpoint, starting with `/`) of the file
+/// they operate on. Attributes returned by this trait don't need a valid inode number, the
+/// `PathFs` adapter assigns inode numbers itself. Reasonable default implementations are
+/// provided here to get a mountable filesystem that does nothing.
+#[async_trait]
+pub trait PathFilesystem {
+    /// Initialize filesystem.
+    /// Called before any other filesystem method.
+    async fn init(&self, _req: &Request) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    /// Called on filesystem exit.
+    async fn destroy(&self, _req: &Request) {}
+
+    /// Get file attributes. This is also used to look up directory entries.
+    async fn getattr(&self, _req: &Request, _path: &Path) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set file attributes.
+    #[allow(clippy::too_many_arguments)]
+    async fn setattr(&self, _req: &Request, _path: &Path, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read symbolic link.
+    async fn readlink(&self, _req: &Request, _path: &Path) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create file node.
+    async fn mknod(&self, _req: &Request, _path: &Path, _mode: u32, _rdev: u32) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    async fn mkdir(&self, _req: &Request, _path: &Path, _mode: u32) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    async fn unlink(&self, _req: &Request, _path: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    async fn rmdir(&self, _req: &Request, _path: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link at `path` pointing to `link`.
+    async fn symlink(&self, _req: &Request, _path: &Path, _link: &Path) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file.
+    async fn rename(&self, _req: &Request, _path: &Path, _newpath: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link `newpath` to `path`.
+    async fn link(&self, _req: &Request, _path: &Path, _newpath: &Path) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a file.
+    async fn open(&self, _req: &Request, _path: &Path, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
+    }
+
+    /// Read data.
+    async fn read(&self, _req: &Request, _path: &Path, _fh: u64, _offset: i64, _size: u32) -> Result<Data, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Write data.
+    async fn write(&self, _req: &Request, _path: &Path, _fh: u64, _offset: i64, _data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Flush method.
+    async fn flush(&self, _req: &Request, _path: &Path, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open file.
+    async fn release(&self, _req: &Request, _path: &Path, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize file contents.
+    async fn fsync(&self, _req: &Request, _path: &Path, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a directory.
+    async fn opendir(&self, _req: &Request, _path: &Path, _flags: u32) -> Result<Opened, Errno> {
+        Ok(Opened::default())
+    }
+
+    /// Read directory.
+    /// Return the entries following the given offset. The inode numbers of the entries
+    /// are replaced with the ones assigned by `PathFs` for entries that have been
+    /// looked up before.
+    async fn readdir(&self, _req: &Request, _path: &Path, _fh: u64, _offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open directory.
+    async fn releasedir(&self, _req: &Request, _path: &Path, _fh: u64, _flags: u32) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize directory contents.
+    async fn fsyncdir(&self, _req: &Request, _path: &Path, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    async fn statfs(&self, _req: &Request, _path: &Path) -> Result<Statfs, Errno> {
+        Ok(Statfs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 })
+    }
+
+    /// Set an extended attribute.
+    async fn setxattr(&self, _req: &Request, _path: &Path, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get an extended attribute.
+    async fn getxattr(&self, _req: &Request, _path: &Path, _name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List extended attribute names.
+    async fn listxattr(&self, _req: &Request, _path: &Path, _size: u32) -> Result<Xattr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    async fn removexattr(&self, _req: &Request, _path: &Path, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    async fn access(&self, _req: &Request, _path: &Path, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open a file.
+    async fn create(&self, _req: &Request, _path: &Path, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn getlk(&self, _req: &Request, _path: &Path, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    #[allow(clippy::too_many_arguments)]
+    async fn setlk(&self, _req: &Request, _path: &Path, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Map block index within file to block index within device.
+    async fn bmap(&self, _req: &Request, _path: &Path, _blocksize: u32, _idx: u64) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Control a device.
+    #[allow(clippy::too_many_arguments)]
+    async fn ioctl(&self, _req: &Request, _path: &Path, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _data: &[u8], _out_size: u32) -> Result<Ioctl, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Allocate or deallocate space of a file.
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, _req: &Request, _path: &Path, _fh: u64, _offset: i64, _length: i64, _mode: i32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file with flags.
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, _req: &Request, _path: &Path, _newpath: &Path, _flags: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Find the next data or hole of a file.
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, _req: &Request, _path: &Path, _fh: u64, _offset: i64, _whence: i32) -> Result<i64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Copy a range of data from one file to another.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, _req: &Request, _path_in: &Path, _fh_in: u64, _offset_in: i64, _path_out: &Path, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u64) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Synchronize the filesystem.
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, _req: &Request, _path: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file attributes for statx().
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, _req: &Request, _path: &Path, _fh: Option<u64>, _flags: u32, _mask: u32) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// Adapter that mounts a `PathFilesystem`
+#[derive(Debug)]
+pub struct PathFs<FS: PathFilesystem> {
+    fs: FS,
+    table: InodeTable<PathBuf>,
+}
+
+impl<FS: PathFilesystem> PathFs<FS> {
+    /// Create a new adapter for the given path based filesystem
+    pub fn new(fs: FS) -> PathFs<FS> {
+        PathFs { fs, table: InodeTable::with_generation(PathBuf::from("/"), time_generation()) }
+    }
+
+    /// Returns the path of the given inode
+    fn path(&self, ino: u64) -> Result<PathBuf, Errno> {
+        self.table.get(ino).ok_or(Errno::ENOENT)
+    }
+
+    /// Returns the path of the entry with the given name in the given directory inode
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, Errno> {
+        self.path(parent).map(|path| path.join(name))
+    }
+
+    /// Turns attributes of the given path into an entry, which counts as a lookup
+    fn entry(&self, path: &Path, attr: Attr) -> Entry {
+        self.table.entry(&path.to_path_buf(), attr)
+    }
+
+    /// Returns attributes with the inode number assigned by the adapter
+    fn attr(&self, ino: u64, mut attr: Attr) -> Attr {
+        attr.attr.ino = ino;
+        attr
+    }
+
+    /// Moves the given path and everything below it to a new path
+    fn rename_paths(&self, from: &Path, to: &Path) {
+        self.table.remap(|path| path.strip_prefix(from).ok().map(|rest| to.join(rest)));
+    }
+}
+
+#[async_trait]
+impl<FS: PathFilesystem + Send + Sync> Filesystem for PathFs<FS> {
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.fs.init(req).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        self.fs.destroy(req).await
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let path = self.child_path(parent, name)?;
+        let attr = self.fs.getattr(req, &path).await?;
+        Ok(self.entry(&path, attr))
+    }
+
+    async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+        self.table.forget(ino, nlookup);
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        let path = self.path(ino)?;
+        let attr = self.fs.getattr(req, &path).await?;
+        Ok(self.attr(ino, attr))
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let path = self.path(ino)?;
+        let attr = self.fs.setattr(req, &path, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await?;
+        Ok(self.attr(ino, attr))
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.fs.readlink(req, &self.path(ino)?).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let path = self.child_path(parent, name)?;
+        let attr = self.fs.mknod(req, &path, mode, rdev).await?;
+        Ok(self.entry(&path, attr))
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let path = self.child_path(parent, name)?;
+        let attr = self.fs.mkdir(req, &path, mode).await?;
+        Ok(self.entry(&path, attr))
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child_path(parent, name)?;
+        self.fs.unlink(req, &path).await?;
+        self.table.remove(&path);
+        Ok(())
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child_path(parent, name)?;
+        self.fs.rmdir(req, &path).await?;
+        self.table.remove(&path);
+        Ok(())
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let path = self.child_path(parent, name)?;
+        let attr = self.fs.symlink(req, &path, link).await?;
+        Ok(self.entry(&path, attr))
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let path = self.child_path(parent, name)?;
+        let newpath = self.child_path(newparent, newname)?;
+        self.fs.rename(req, &path, &newpath).await?;
+        self.rename_paths(&path, &newpath);
+        Ok(())
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let path = self.path(ino)?;
+        let newpath = self.child_path(newparent, newname)?;
+        let attr = self.fs.link(req, &path, &newpath).await?;
+        Ok(self.entry(&newpath, attr))
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.fs.open(req, &self.path(ino)?, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        self.fs.read(req, &self.path(ino)?, fh, offset, size).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        self.fs.write(req, &self.path(ino)?, fh, offset, data, flags).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.fs.flush(req, &self.path(ino)?, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.fs.release(req, &self.path(ino)?, fh, flags, lock_owner, flush).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.fs.fsync(req, &self.path(ino)?, fh, datasync).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.fs.opendir(req, &self.path(ino)?, flags).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        let path = self.path(ino)?;
+        let mut entries = self.fs.readdir(req, &path, fh, offset).await?;
+        for entry in &mut entries {
+            if let Some(ino) = self.table.ino(&path.join(&entry.name)) {
+                entry.ino = ino;
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        self.fs.releasedir(req, &self.path(ino)?, fh, flags).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.fs.fsyncdir(req, &self.path(ino)?, fh, datasync).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        self.fs.statfs(req, &self.path(ino)?).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        self.fs.setxattr(req, &self.path(ino)?, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        self.fs.getxattr(req, &self.path(ino)?, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.fs.listxattr(req, &self.path(ino)?, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.fs.removexattr(req, &self.path(ino)?, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.fs.access(req, &self.path(ino)?, mask).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let path = self.child_path(parent, name)?;
+        let mut created = self.fs.create(req, &path, mode, flags).await?;
+        let (ino, generation) = self.table.lookup(&path);
+        created.attr.ino = ino;
+        created.generation = generation;
+        Ok(created)
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        self.fs.getlk(req, &self.path(ino)?, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        self.fs.setlk(req, &self.path(ino)?, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        self.fs.bmap(req, &self.path(ino)?, blocksize, idx).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        self.fs.ioctl(req, &self.path(ino)?, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.fs.fallocate(req, &self.path(ino)?, fh, offset, length, mode).await
+    }
+
+    /// Lists the directory with readdir and looks up every entry with getattr
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        let path = self.path(ino)?;
+        let mut entries = Vec::new();
+        for dirent in self.fs.readdir(req, &path, fh, offset).await? {
+            let entry = match dirent.name.as_bytes() {
+                b"." | b".." => Entry::negative(None),
+                _ => {
+                    let child = path.join(&dirent.name);
+                    let attr = self.fs.getattr(req, &child).await?;
+                    self.entry(&child, attr)
+                }
+            };
+            entries.push(DirEntryPlus { dirent: DirEntry { ino: entry.attr.ino, ..dirent }, entry });
+        }
+        Ok(entries)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        let path = self.child_path(parent, name)?;
+        let newpath = self.child_path(newparent, newname)?;
+        self.fs.rename2(req, &path, &newpath, flags).await?;
+        match flags & RENAME_EXCHANGE {
+            0 => self.rename_paths(&path, &newpath),
+            _ => self.table.remap(|moved| {
+                moved.strip_prefix(&path).ok().map(|rest| newpath.join(rest))
+                    .or_else(|| moved.strip_prefix(&newpath).ok().map(|rest| path.join(rest)))
+            }),
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.fs.lseek(req, &self.path(ino)?, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.fs.copy_file_range(req, &self.path(ino_in)?, fh_in, offset_in, &self.path(ino_out)?, fh_out, offset_out, len, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        self.fs.syncfs(req, &self.path(ino)?).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        let attr = self.fs.statx(req, &self.path(ino)?, fh, flags, mask).await?;
+        Ok(self.attr(ino, attr))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use fuse_abi::FUSE_ROOT_ID;
+    use crate::{block_on, Attr, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::{PathFilesystem, PathFs};
+
+    struct NullFs;
+
+    impl PathFilesystem for NullFs {}
+
+    /// Files by path with their sizes
+    struct Files(Mutex<HashMap<PathBuf, u64>>);
+
+    #[async_trait]
+    impl PathFilesystem for Files {
+        async fn getattr(&self, _req: &Request, path: &Path) -> Result<Attr, Errno> {
+            let size = *self.0.lock().unwrap().get(path).ok_or(Errno::ENOENT)?;
+            Ok(Attr::new(FileAttr::builder(0, FileType::RegularFile).size(size).build()))
+        }
+
+        async fn mknod(&self, _req: &Request, path: &Path, _mode: u32, _rdev: u32) -> Result<Attr, Errno> {
+            self.0.lock().unwrap().insert(path.to_path_buf(), 0);
+            Ok(Attr::new(FileAttr::builder(0, FileType::RegularFile).build()))
+        }
+
+        async fn unlink(&self, _req: &Request, path: &Path) -> Result<(), Errno> {
+            self.0.lock().unwrap().remove(path).map(|_| ()).ok_or(Errno::ENOENT)
+        }
+    }
+
+    #[test]
+    fn child_path() {
+        let fs = PathFs::new(NullFs);
+        assert_eq!(fs.path(FUSE_ROOT_ID).unwrap(), Path::new("/"));
+        let (ino, _) = fs.table.lookup(&Path::new("/a").to_path_buf());
+        assert_eq!(fs.child_path(ino, "b".as_ref()).unwrap(), Path::new("/a/b"));
+        assert!(fs.child_path(ino + 1, "b".as_ref()).is_err());
+    }
+
+    #[test]
+    fn rename_subtree() {
+        let fs = PathFs::new(NullFs);
+        let (dir, _) = fs.table.lookup(&Path::new("/a").to_path_buf());
+        let (file, _) = fs.table.lookup(&Path::new("/a/b/c").to_path_buf());
+        let (other, _) = fs.table.lookup(&Path::new("/ab").to_path_buf());
+        fs.rename_paths(Path::new("/a"), Path::new("/x"));
+        assert_eq!(fs.path(dir).unwrap(), Path::new("/x"));
+        assert_eq!(fs.path(file).unwrap(), Path::new("/x/b/c"));
+        assert_eq!(fs.path(other).unwrap(), Path::new("/ab"));
+        assert_eq!(fs.table.ino(&Path::new("/x/b/c").to_path_buf()), Some(file));
+    }
+
+    #[test]
+    fn unlinked_inode() {
+        let fs = PathFs::new(Files(Mutex::new(vec![(PathBuf::from("/f"), 5)].into_iter().collect())));
+        let req = Request::test(0, 0);
+        let old = block_on(fs.lookup(&req, FUSE_ROOT_ID, OsStr::new("f"))).unwrap().attr.ino;
+        block_on(fs.unlink(&req, FUSE_ROOT_ID, OsStr::new("f"))).unwrap();
+        let new = block_on(fs.mknod(&req, FUSE_ROOT_ID, OsStr::new("f"), 0o644, 0)).unwrap().attr.ino;
+        assert_ne!(new, old);
+        // The unlinked inode, still looked up, doesn't resolve to the new file at its path
+        assert_eq!(block_on(fs.getattr(&req, old)).unwrap_err(), Errno::ENOENT);
+        assert_eq!(block_on(fs.getattr(&req, new)).unwrap().attr.size, 0);
+        block_on(fs.forget(&req, old, 1));
+        assert_eq!(fs.path(new).unwrap(), Path::new("/f"));
+    }
+}
@@ -0,0 +1,274 @@
+//! Permission checks
+//!
+//! The kernel checks file permissions itself if a filesystem is mounted with the
+//! `default_permissions` option. Filesystems that can't use it (e.g. because permissions need
+//! to be checked against attributes the kernel may have cached for too long) can wrap
+//! themselves in `PermissionCheck` instead, which performs the POSIX mode, uid and gid checks
+//! in userspace using the attributes returned by the filesystem's getattr.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, R_OK, W_OK, X_OK};
+
+use crate::layer::Wrapper;
+use crate::{Attr, Created, Entry, Errno, FileAttr, FileType, Filesystem, Opened, Request, Xattr};
+
+const R: u32 = R_OK as u32;
+const W: u32 = W_OK as u32;
+const X: u32 = X_OK as u32;
+
+/// Returns whether the given user, group and supplementary groups may access a file with the
+/// given attributes in the given mode (a combination of `R_OK`, `W_OK` and `X_OK`). Root may
+/// read and write everything, and execute everything that has any execute bit set.
+fn allowed(attr: &FileAttr, uid: u32, gid: u32, groups: &[u32], mask: u32) -> bool {
+    let perm = attr.perm as u32;
+    if uid == 0 {
+        return mask & X == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+    }
+    let bits = if uid == attr.uid {
+        perm >> 6
+    } else if gid == attr.gid || groups.contains(&attr.gid) {
+        perm >> 3
+    } else {
+        perm
+    };
+    bits & mask == mask
+}
+
+/// Returns the access mode needed to open a file with the given flags
+fn open_mask(flags: u32) -> u32 {
+    let flags = flags as i32;
+    let mask = match flags & O_ACCMODE {
+        O_RDONLY => R,
+        O_WRONLY => W,
+        O_RDWR => R | W,
+        _ => 0,
+    };
+    if flags & O_TRUNC != 0 { mask | W } else { mask }
+}
+
+/// Filesystem wrapper checking POSIX file permissions of the requesting user before passing
+/// operations on to the wrapped filesystem. Supplementary groups of the requesting process are
+/// read from /proc (see `Request::supplementary_groups`), access through them is denied if they
+/// can't be read.
+#[derive(Debug)]
+pub struct PermissionCheck<FS> {
+    inner: FS,
+}
+
+impl<FS: Filesystem + Send + Sync> PermissionCheck<FS> {
+    /// Check permissions of operations on the given filesystem
+    pub fn new(inner: FS) -> PermissionCheck<FS> {
+        PermissionCheck { inner }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Fails with EACCES if the requesting user may not access the given inode in the given mode
+    async fn check(&self, req: &Request, ino: u64, mask: u32) -> Result<FileAttr, Errno> {
+        let attr = self.inner.getattr(req, ino).await?.attr;
+        let groups = req.supplementary_groups().unwrap_or_default();
+        if allowed(&attr, req.uid(), req.gid(), groups, mask) { Ok(attr) } else { Err(Errno::EACCES) }
+    }
+
+    /// Fails if the requesting user may not remove the given entry. Besides write access to the
+    /// directory, this needs ownership of the directory or the entry in sticky directories.
+    async fn check_remove(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let dir = self.check(req, parent, W | X).await?;
+        if dir.perm & 0o1000 == 0 || req.uid() == 0 || req.uid() == dir.uid { return Ok(()); }
+        let entry = self.inner.lookup(req, parent, name).await?;
+        self.inner.forget(req, entry.attr.ino, 1).await;
+        if req.uid() == entry.attr.uid { Ok(()) } else { Err(Errno::EPERM) }
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for PermissionCheck<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        self.check(req, parent, X).await?;
+        self.inner.lookup(req, parent, name).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let attr = self.inner.getattr(req, ino).await?.attr;
+        let root = req.uid() == 0;
+        let owner = root || req.uid() == attr.uid;
+        let groups = req.supplementary_groups().unwrap_or_default();
+        let writable = allowed(&attr, req.uid(), req.gid(), groups, W);
+        if mode.is_some() && !owner { return Err(Errno::EPERM); }
+        if uid.is_some_and(|uid| uid != attr.uid) && !root { return Err(Errno::EPERM); }
+        if gid.is_some_and(|gid| gid != attr.gid && !(owner && (gid == req.gid() || groups.contains(&gid)))) && !root { return Err(Errno::EPERM); }
+        if size.is_some() && !writable { return Err(Errno::EACCES); }
+        if (atime.is_some() || mtime.is_some()) && !owner && !writable { return Err(Errno::EACCES); }
+        self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        self.check(req, parent, W | X).await?;
+        self.inner.mknod(req, parent, name, mode, rdev).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        self.check(req, parent, W | X).await?;
+        self.inner.mkdir(req, parent, name, mode).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.check_remove(req, parent, name).await?;
+        self.inner.unlink(req, parent, name).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.check_remove(req, parent, name).await?;
+        self.inner.rmdir(req, parent, name).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        self.check(req, parent, W | X).await?;
+        self.inner.symlink(req, parent, name, link).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        self.check_remove(req, parent, name).await?;
+        self.check(req, newparent, W | X).await?;
+        self.inner.rename(req, parent, name, newparent, newname).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        self.check(req, newparent, W | X).await?;
+        self.inner.link(req, ino, newparent, newname).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.check(req, ino, open_mask(flags)).await?;
+        self.inner.open(req, ino, flags).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.check(req, ino, R).await?;
+        self.inner.opendir(req, ino, flags).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        self.check(req, ino, W).await?;
+        self.inner.setxattr(req, ino, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        self.check(req, ino, R).await?;
+        self.inner.getxattr(req, ino, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.check(req, ino, R).await?;
+        self.inner.listxattr(req, ino, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.check(req, ino, W).await?;
+        self.inner.removexattr(req, ino, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.check(req, ino, mask & (R | W | X)).await.map(|_| ())
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.check(req, parent, W | X).await?;
+        self.inner.create(req, parent, name, mode, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        self.check_remove(req, parent, name).await?;
+        self.check(req, newparent, W | X).await?;
+        self.inner.rename2(req, parent, name, newparent, newname, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.check(req, parent, W | X).await?;
+        self.inner.tmpfile(req, parent, mode, flags).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::time::UNIX_EPOCH;
+    use async_trait::async_trait;
+    use libc::{O_RDONLY, O_RDWR, O_TRUNC};
+    use crate::{block_on, Attr, Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::{allowed, open_mask, PermissionCheck, R, W, X};
+
+    fn attr(ino: u64, kind: FileType, perm: u16, uid: u32) -> FileAttr {
+        FileAttr::builder(ino, kind).time(UNIX_EPOCH).perm(perm).uid(uid).gid(100).build()
+    }
+
+    #[test]
+    fn mode_bits() {
+        let file = attr(2, FileType::RegularFile, 0o640, 1000);
+        assert!(allowed(&file, 1000, 1000, &[], R | W));
+        assert!(!allowed(&file, 1000, 1000, &[], X));
+        assert!(allowed(&file, 2000, 100, &[], R));
+        assert!(!allowed(&file, 2000, 100, &[], W));
+        assert!(!allowed(&file, 2000, 2000, &[], R));
+        assert!(allowed(&file, 2000, 2000, &[10, 100], R));
+        assert!(allowed(&file, 0, 0, &[], R | W));
+        assert!(!allowed(&file, 0, 0, &[], X));
+        assert!(allowed(&attr(1, FileType::Directory, 0o700, 1000), 0, 0, &[], X));
+    }
+
+    #[test]
+    fn open_flags() {
+        assert_eq!(open_mask(O_RDONLY as u32), R);
+        assert_eq!(open_mask(O_RDWR as u32), R | W);
+        assert_eq!(open_mask((O_RDONLY | O_TRUNC) as u32), R | W);
+    }
+
+    struct Inner;
+
+    #[async_trait]
+    impl Filesystem for Inner {
+        async fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            Ok(Entry::new(attr(3, FileType::RegularFile, 0o644, 2000), 0))
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            match ino {
+                1 => Ok(Attr::new(attr(1, FileType::Directory, 0o1777, 0))),
+                2 => Ok(Attr::new(attr(2, FileType::RegularFile, 0o600, 1000))),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+
+        async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checks_requests() {
+        let fs = PermissionCheck::new(Inner);
+        let (owner, other) = (Request::test(1000, 1000), Request::test(2000, 2000));
+        assert!(block_on(Filesystem::open(&fs, &owner, 2, O_RDWR as u32)).is_ok());
+        assert_eq!(block_on(Filesystem::open(&fs, &other, 2, O_RDONLY as u32)), Err(Errno::EACCES));
+        assert_eq!(block_on(Filesystem::access(&fs, &other, 2, R)), Err(Errno::EACCES));
+        assert_eq!(block_on(Filesystem::setattr(&fs, &other, 2, Some(0o666), None, None, None, None, None, None, None, None, None, None)).unwrap_err(), Errno::EPERM);
+        assert!(block_on(Filesystem::lookup(&fs, &other, 1, OsStr::new("foo"))).is_ok());
+        assert_eq!(block_on(Filesystem::unlink(&fs, &owner, 1, OsStr::new("foo"))), Err(Errno::EPERM));
+        assert!(block_on(Filesystem::unlink(&fs, &other, 1, OsStr::new("foo"))).is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+//! PID namespaces
+//!
+//! The kernel driver tells the pid of the calling process (`Request::pid`) in the pid namespace
+//! of the process that opened the channel to it, usually the daemon at mount time. If the daemon
+//! serves the filesystem from another pid namespace (e.g. it was handed the fd by a process in a
+//! container), the pid refers to another process in the daemon's /proc, or to none. Callers that
+//! aren't visible in the namespace of the mount at all are given pid 0. `PidNamespace` detects
+//! these cases and translates pids of descendant namespaces to the pids the daemon sees, using
+//! the `NSpid` line in /proc/<pid>/status (Linux 4.1 and later).
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Pid namespace, identified by the inode of its /proc/<pid>/ns/pid link
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PidNamespace {
+    inode: u64,
+}
+
+impl PidNamespace {
+    /// Returns the pid namespace of the current process
+    pub fn current() -> io::Result<PidNamespace> {
+        PidNamespace::from_link(Path::new("/proc/self/ns/pid"))
+    }
+
+    /// Returns the pid namespace of the process with the given pid (as seen in /proc)
+    pub fn of(pid: u32) -> io::Result<PidNamespace> {
+        PidNamespace::from_link(&Path::new("/proc").join(pid.to_string()).join("ns/pid"))
+    }
+
+    /// Read the namespace from the given link, which points to `pid:[<inode>]`
+    fn from_link(path: &Path) -> io::Result<PidNamespace> {
+        let link = fs::read_link(path)?;
+        link.to_str()
+            .and_then(|link| link.strip_prefix("pid:[")?.strip_suffix(']')?.parse().ok())
+            .map(|inode| PidNamespace { inode })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pid namespace link"))
+    }
+
+    /// Translate the given pid of this namespace to the pid of the same process in /proc of the
+    /// current process. Returns none if the pid is 0 (the process isn't visible in this
+    /// namespace) or if it can't be translated, e.g. because this namespace isn't a descendant
+    /// of the current one. Translating between namespaces scans all processes, so the result
+    /// should be cached by callers that translate often.
+    pub fn translate(&self, pid: u32) -> Option<u32> {
+        if pid == 0 {
+            return None;
+        }
+        if PidNamespace::current().ok()? == *self {
+            return Some(pid);
+        }
+        // The depth of this namespace below the current one, from any process in it
+        let depth = processes().find(|&p| PidNamespace::of(p).ok() == Some(*self))
+            .and_then(|p| nspids(p).map(|nspids| nspids.len() - 1))?;
+        processes().find(|&p| nspids(p).is_some_and(|nspids| nspids.get(depth) == Some(&pid)))
+    }
+}
+
+/// Returns the pids of all processes in /proc
+fn processes() -> impl Iterator<Item = u32> {
+    fs::read_dir("/proc").into_iter().flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+}
+
+/// Returns the pids of the given process in the pid namespaces from the one of /proc down to
+/// its own one
+fn nspids(pid: u32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("NSpid:"))?;
+    let nspids: Option<Vec<u32>> = line.split_whitespace().map(|pid| pid.parse().ok()).collect();
+    nspids.filter(|nspids| !nspids.is_empty())
+}
+
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::{nspids, PidNamespace};
+
+    #[test]
+    fn current() {
+        let pid = std::process::id();
+        let ns = PidNamespace::current().unwrap();
+        assert_eq!(PidNamespace::of(pid).unwrap(), ns);
+        assert_eq!(nspids(pid).unwrap()[0], pid);
+        assert_eq!(ns.translate(pid), Some(pid));
+        assert_eq!(ns.translate(0), None);
+    }
+
+    #[test]
+    fn other() {
+        // No process is in this namespace, so its pids can't be translated
+        let ns = PidNamespace { inode: 0 };
+        assert_eq!(ns.translate(std::process::id()), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Raw filesystem
+//!
+//! Proxies and protocol translators need to see requests exactly as the kernel driver sent them.
+//! A `RawFilesystem` receives every request with only its header decoded, together with the
+//! unprocessed argument bytes, and replies with raw data. No session state (like protocol
+//! negotiation at init) is handled for it.
+
+use std::ffi::OsString;
+use std::{io, mem};
+use std::path::Path;
+use std::sync::Arc;
+use async_trait::async_trait;
+use fuse_abi::fuse_in_header;
+use log::{error, info};
+
+use crate::channel::Channel;
+use crate::executor::{default_executor, Executor};
+use crate::reply::{Reply, ReplyBytes};
+use crate::session::{receive_loop, BUFFER_HEADROOM, MAX_WRITE_SIZE};
+
+/// Request as received from the kernel driver
+#[derive(Debug)]
+pub struct RawRequest {
+    header: fuse_in_header,
+    data: Vec<u8>,
+}
+
+impl RawRequest {
+    /// Create a request from the given data, which must start with a request header
+    pub fn new(data: &[u8]) -> Option<RawRequest> {
+        if data.len() < mem::size_of::<fuse_in_header>() { return None; }
+        let header = unsafe { (data.as_ptr() as *const fuse_in_header).read_unaligned() };
+        let len = header.len as usize;
+        if len < mem::size_of::<fuse_in_header>() || data.len() < len { return None; }
+        Some(RawRequest { header, data: data[mem::size_of::<fuse_in_header>()..len].to_vec() })
+    }
+
+    /// Returns the unique identifier of this request
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Returns the opcode (FUSE_*) of this request
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Returns the node id of the inode this request is targeted to
+    pub fn nodeid(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Returns the uid of this request
+    pub fn uid(&self) -> u32 {
+        self.header.uid
+    }
+
+    /// Returns the gid of this request
+    pub fn gid(&self) -> u32 {
+        self.header.gid
+    }
+
+    /// Returns the pid of this request
+    pub fn pid(&self) -> u32 {
+        self.header.pid
+    }
+
+    /// Returns the argument bytes following the request header
+    pub fn arguments(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Raw filesystem trait.
+#[async_trait]
+pub trait RawFilesystem {
+    /// Handle a request. Every request except forget must be answered using the given reply,
+    /// dropping it replies with an I/O error.
+    async fn request(&self, req: RawRequest, reply: ReplyBytes);
+}
+
+/// Session running a raw filesystem
+#[derive(Debug)]
+pub struct RawSession<FS: RawFilesystem + Send + Sync + 'static> {
+    /// Filesystem operation implementations
+    pub filesystem: FS,
+    /// Communication channel to the kernel driver
+    ch: Channel,
+    /// Executor that runs dispatched requests
+    pub executor: Arc<dyn Executor>,
+}
+
+impl<FS: RawFilesystem + Send + Sync + 'static> RawSession<FS> {
+    /// Create a new session by mounting the given filesystem to the given mountpoint
+    pub fn new(filesystem: FS, mountpoint: &Path, options: &[OsString]) -> io::Result<RawSession<FS>> {
+        info!("Mounting {}", mountpoint.display());
+        let ch = Channel::new(mountpoint, options)?;
+        Ok(RawSession { filesystem, ch, executor: default_executor() })
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// Run the session loop that receives kernel requests and passes them to the filesystem
+    pub fn run(self) -> io::Result<()> {
+        let se = Arc::new(self);
+        let executor = se.executor.clone();
+        receive_loop(&se.ch, MAX_WRITE_SIZE + BUFFER_HEADROOM, |data| match RawRequest::new(data) {
+            Some(req) => {
+                let se = se.clone();
+                let reply = ReplyBytes::new(req.unique(), se.ch.sender());
+                executor.spawn(Box::pin(async move { se.filesystem.request(req, reply).await }));
+                true
+            }
+            None => {
+                error!("Short read of request header ({} bytes)", data.len());
+                false
+            }
+        })
+    }
+}
+
+impl<FS: RawFilesystem + Send + Sync + 'static> Drop for RawSession<FS> {
+    fn drop(&mut self) {
+        info!("Unmounted {}", self.mountpoint().display());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::RawRequest;
+
+    #[test]
+    fn raw_request() {
+        let data = [
+            0x2c, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,  0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde,
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,  0xa1, 0xa2, 0xa3, 0xa4, 0xb1, 0xb2, 0xb3, 0xb4,
+            0xc1, 0xc2, 0xc3, 0xc4, 0x00, 0x00, 0x00, 0x00,  0x66, 0x6f, 0x6f, 0x00, 0xff,
+        ];
+        let req = RawRequest::new(&data).unwrap();
+        assert_eq!(req.opcode(), 1);
+        assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
+        assert_eq!(req.nodeid(), 0x8877_6655_4433_2211);
+        assert_eq!(req.uid(), 0xa4a3_a2a1);
+        assert_eq!(req.gid(), 0xb4b3_b2b1);
+        assert_eq!(req.pid(), 0xc4c3_c2c1);
+        assert_eq!(req.arguments(), b"foo\0");
+        assert!(RawRequest::new(&data[..20]).is_none());
+        assert!(RawRequest::new(&data[..42]).is_none());
+    }
+}
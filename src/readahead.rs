@@ -0,0 +1,207 @@
+//! Readahead
+//!
+//! With `direct_io`, the kernel doesn't read ahead, so every read of a process turns into a
+//! read of the filesystem. `Readahead` detects sequential reads of a file handle, and then
+//! reads the following data from the filesystem together with the requested data. Subsequent
+//! reads are answered from this buffer, without another round-trip to a high latency backend.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use async_trait::async_trait;
+
+use crate::layer::Wrapper;
+use crate::{Attr, Data, Errno, Filesystem, Request};
+
+/// Readahead state of an open file handle
+#[derive(Debug, Default)]
+struct Stream {
+    /// Offset the next read starts at if reading sequentially
+    next: i64,
+    /// Offset of the buffered data
+    start: i64,
+    /// Data read ahead
+    buffer: Vec<u8>,
+    /// True if the buffered data ends at the end of the file
+    eof: bool,
+}
+
+impl Stream {
+    /// Returns the buffered data of the given read, if all of it is buffered
+    fn get(&self, offset: i64, size: u32) -> Option<Data> {
+        let end = self.start + self.buffer.len() as i64;
+        if offset < self.start || (offset >= end && !(self.eof && offset == end)) {
+            return None;
+        }
+        if offset + size as i64 > end && !self.eof {
+            return None;
+        }
+        let from = (offset - self.start) as usize;
+        let to = (from + size as usize).min(self.buffer.len());
+        Some(Data::from(&self.buffer[from..to]))
+    }
+}
+
+/// Filesystem wrapper reading ahead the data of sequentially read file handles
+#[derive(Debug)]
+pub struct Readahead<FS> {
+    inner: FS,
+    window: u32,
+    streams: Mutex<HashMap<(u64, u64), Stream>>,
+}
+
+impl<FS: Filesystem + Send + Sync> Readahead<FS> {
+    /// Read the given number of bytes ahead of sequential reads of the given filesystem
+    pub fn new(inner: FS, window: u32) -> Readahead<FS> {
+        Readahead { inner, window, streams: Mutex::default() }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Drop the data read ahead of the given file, which changed
+    fn changed(&self, ino: u64) {
+        for (_, stream) in self.streams.lock().unwrap().iter_mut().filter(|((i, _), _)| *i == ino) {
+            stream.buffer.clear();
+            stream.eof = false;
+        }
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for Readahead<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let sequential = {
+            let mut streams = self.streams.lock().unwrap();
+            let stream = streams.entry((ino, fh)).or_default();
+            if let Some(data) = stream.get(offset, size) {
+                stream.next = offset + data.len() as i64;
+                return Ok(data);
+            }
+            stream.next == offset
+        };
+        if !sequential {
+            let data = self.inner.read(req, ino, fh, offset, size).await?;
+            if let Some(stream) = self.streams.lock().unwrap().get_mut(&(ino, fh)) {
+                *stream = Stream { next: offset + data.len() as i64, ..Stream::default() };
+            }
+            return Ok(data);
+        }
+        let ahead = size.saturating_add(self.window);
+        let mut buffer = self.inner.read(req, ino, fh, offset, ahead).await?.to_vec();
+        let len = buffer.len().min(size as usize);
+        let data = Data::from(&buffer[..len]);
+        let eof = buffer.len() < ahead as usize;
+        buffer.drain(..len);
+        // The handle may have been released meanwhile
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(&(ino, fh)) {
+            *stream = Stream { next: offset + len as i64, start: offset + len as i64, buffer, eof };
+        }
+        Ok(data)
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let result = self.inner.write(req, ino, fh, offset, data, flags).await;
+        self.changed(ino);
+        result
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let result = self.inner.setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await;
+        if size.is_some() {
+            self.changed(ino);
+        }
+        result
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.streams.lock().unwrap().remove(&(ino, fh));
+        self.inner.release(req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        let result = self.inner.fallocate(req, ino, fh, offset, length, mode).await;
+        self.changed(ino);
+        result
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        let result = self.inner.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await;
+        self.changed(ino_out);
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::{block_on, Data, Errno, Filesystem, Request};
+    use super::Readahead;
+
+    /// File of 100 bytes counting up, recording the reads
+    #[derive(Default)]
+    struct Counting(Mutex<Vec<(i64, u32)>>);
+
+    #[async_trait]
+    impl Filesystem for Counting {
+        async fn read(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+            self.0.lock().unwrap().push((offset, size));
+            let end = (offset + size as i64).min(100);
+            Ok((offset.min(100)..end).map(|b| b as u8).collect::<Vec<_>>().into())
+        }
+
+        async fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            Ok(data.len() as u32)
+        }
+    }
+
+    fn read(fs: &Readahead<Counting>, offset: i64, size: u32) -> Vec<u8> {
+        block_on(Filesystem::read(fs, &Request::test(0, 0), 2, 1, offset, size)).unwrap().to_vec()
+    }
+
+    #[test]
+    fn sequential() {
+        let fs = Readahead::new(Counting::default(), 30);
+        for offset in (0..50).step_by(10) {
+            assert_eq!(read(&fs, offset, 10), (offset as u8..offset as u8 + 10).collect::<Vec<u8>>());
+        }
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 40), (40, 40)]);
+        // Reads beyond the buffer read ahead again, up to the end of file
+        assert_eq!(read(&fs, 50, 10), (50..60).collect::<Vec<u8>>());
+        assert_eq!(read(&fs, 60, 40), (60..100).collect::<Vec<u8>>());
+        assert_eq!(read(&fs, 100, 10), Vec::<u8>::new());
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 40), (40, 40), (60, 70)]);
+    }
+
+    #[test]
+    fn random() {
+        let fs = Readahead::new(Counting::default(), 30);
+        read(&fs, 50, 10);
+        read(&fs, 0, 10);
+        read(&fs, 70, 10);
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(50, 10), (0, 10), (70, 10)]);
+    }
+
+    #[test]
+    fn write() {
+        let fs = Readahead::new(Counting::default(), 30);
+        read(&fs, 0, 10);
+        read(&fs, 10, 10);
+        // Writes drop the data read ahead
+        block_on(Filesystem::write(&fs, &Request::test(0, 0), 2, 3, 25, b"foo", 0)).unwrap();
+        read(&fs, 20, 10);
+        assert_eq!(*fs.inner.0.lock().unwrap(), vec![(0, 40), (20, 40)]);
+    }
+}
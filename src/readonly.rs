@@ -0,0 +1,177 @@
+//! Read-only filesystem
+//!
+//! `ReadOnly` exports any filesystem read-only by rejecting all operations that would modify
+//! it, independent of the mount options and the implementation of the filesystem.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use libc::{O_ACCMODE, O_RDONLY, O_TRUNC, W_OK};
+
+use crate::layer::Wrapper;
+use crate::{Attr, Created, Entry, Errno, Filesystem, Opened, Request};
+
+/// Filesystem wrapper failing all mutating operations with EROFS. Opening files for writing
+/// fails as well, all other operations are passed on to the wrapped filesystem.
+#[derive(Debug)]
+pub struct ReadOnly<FS> {
+    inner: FS,
+}
+
+impl<FS: Filesystem + Send + Sync> ReadOnly<FS> {
+    /// Export the given filesystem read-only
+    pub fn new(inner: FS) -> ReadOnly<FS> {
+        ReadOnly { inner }
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+}
+
+/// Returns true if the given open flags allow modifying the file
+pub(crate) fn writes(flags: u32) -> bool {
+    let flags = flags as i32;
+    flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for ReadOnly<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    async fn setattr(&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> Result<Attr, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn mknod(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32) -> Result<Entry, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn mkdir(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn symlink(&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path) -> Result<Entry, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn rename(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn link(&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr) -> Result<Entry, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        if writes(flags) { return Err(Errno::EROFS); }
+        self.inner.open(req, ino, flags).await
+    }
+
+    async fn write(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32) -> Result<u32, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn setxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn removexattr(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        if mask & W_OK as u32 != 0 { return Err(Errno::EROFS); }
+        self.inner.access(req, ino, mask).await
+    }
+
+    async fn create(&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, _req: &Request, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _length: i64, _mode: i32) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: i64, _ino_out: u64, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u64) -> Result<u32, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, _req: &Request, _parent: u64, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use async_trait::async_trait;
+    use libc::{O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, R_OK, W_OK};
+    use crate::{block_on, Errno, Filesystem, Request};
+    use super::{writes, ReadOnly};
+
+    struct Inner;
+
+    #[async_trait]
+    impl Filesystem for Inner {
+        async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        async fn access(&self, _req: &Request, _ino: u64, _mask: u32) -> Result<(), Errno> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn open_flags() {
+        assert!(!writes(O_RDONLY as u32));
+        assert!(writes(O_WRONLY as u32));
+        assert!(writes(O_RDWR as u32));
+        assert!(writes((O_RDONLY | O_TRUNC) as u32));
+    }
+
+    #[test]
+    fn rejects_mutations() {
+        let req = Request::test(0, 0);
+        let fs = ReadOnly::new(Inner);
+        assert_eq!(block_on(Filesystem::unlink(&fs, &req, 1, OsStr::new("foo"))), Err(Errno::EROFS));
+        assert_eq!(block_on(Filesystem::open(&fs, &req, 2, O_RDWR as u32)).unwrap_err(), Errno::EROFS);
+        assert!(block_on(Filesystem::open(&fs, &req, 2, O_RDONLY as u32)).is_ok());
+        assert_eq!(block_on(Filesystem::access(&fs, &req, 2, W_OK as u32)), Err(Errno::EROFS));
+        assert_eq!(block_on(Filesystem::access(&fs, &req, 2, R_OK as u32)), Ok(()));
+    }
+}
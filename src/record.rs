@@ -0,0 +1,247 @@
+//! Request recording and replay
+//!
+//! A recorder captures the raw requests a session receives from the kernel driver and the raw
+//! replies it sends back. A recording can later be replayed through a `MockChannel` against a
+//! filesystem to reproduce bugs in the interaction with the kernel offline, and to see which
+//! replies differ from the recorded ones.
+//!
+//! A recording is a sequence of records, each made of a direction byte (`>` for requests, `<`
+//! for replies), the length of the data as 32 bit little endian and the data itself.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use log::error;
+
+use crate::mock::{MockChannel, MockReply};
+use crate::reply::ReplySender;
+use crate::Filesystem;
+
+const REQUEST: u8 = b'>';
+const REPLY: u8 = b'<';
+
+/// Recorder writing requests and replies of a session
+pub struct Recorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    /// Create a recorder writing to the given writer. Every record is written at once, so
+    /// the writer should buffer if it is slow.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Recorder {
+        Recorder { writer: Mutex::new(Box::new(writer)) }
+    }
+
+    /// Create a recorder writing to a new file at the given path
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder::new(File::create(path)?))
+    }
+
+    /// Record a request as received from the kernel driver
+    pub(crate) fn request(&self, data: &[u8]) {
+        self.record(REQUEST, &[data]);
+    }
+
+    fn record(&self, direction: u8, data: &[&[u8]]) {
+        let len = data.iter().map(|d| d.len()).sum::<usize>() as u32;
+        let mut record = Vec::with_capacity(5 + len as usize);
+        record.push(direction);
+        record.extend_from_slice(&len.to_le_bytes());
+        data.iter().for_each(|d| record.extend_from_slice(d));
+        if let Err(err) = self.writer.lock().unwrap().write_all(&record) {
+            error!("Failed to record FUSE {}: {}", if direction == REQUEST { "request" } else { "reply" }, err);
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Recorder")
+    }
+}
+
+/// Reply sender that records replies before passing them on
+#[derive(Debug)]
+pub(crate) struct RecordingSender<S> {
+    sender: S,
+    recorder: Arc<Recorder>,
+}
+
+impl<S: ReplySender + Sync> RecordingSender<S> {
+    pub(crate) fn new(sender: S, recorder: Arc<Recorder>) -> RecordingSender<S> {
+        RecordingSender { sender, recorder }
+    }
+}
+
+impl<S: ReplySender + Sync> ReplySender for RecordingSender<S> {
+    fn send(&self, data: &[&[u8]]) {
+        self.recorder.record(REPLY, data);
+        self.sender.send(data);
+    }
+}
+
+/// Requests and replies read from a recording
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    requests: Vec<Vec<u8>>,
+    replies: Vec<MockReply>,
+}
+
+impl Recording {
+    /// Read a recording from the given reader
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Recording> {
+        let mut recording = Recording::default();
+        let mut head = [0u8; 5];
+        loop {
+            // A recording may end with a partial record if the session was killed
+            match reader.read_exact(&mut head) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            let mut data = vec![0; u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize];
+            match reader.read_exact(&mut data) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            match head[0] {
+                REQUEST => recording.requests.push(data),
+                REPLY => recording.replies.push(MockReply::new(data)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid record in FUSE recording")),
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Read a recording from the file at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Recording> {
+        Recording::read(io::BufReader::new(File::open(path)?))
+    }
+
+    /// Returns the recorded requests in the order they were received
+    pub fn requests(&self) -> &[Vec<u8>] {
+        &self.requests
+    }
+
+    /// Returns the recorded reply to the request with the given unique id
+    pub fn reply(&self, unique: u64) -> Option<&MockReply> {
+        self.replies.iter().find(|reply| reply.unique() == unique)
+    }
+
+    /// Send all recorded requests through the given mock channel, one after another. Returns
+    /// the requests whose replies differ from the recorded ones.
+    pub async fn replay<FS: Filesystem + Send + Sync + 'static>(&self, ch: &MockChannel<FS>) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for request in &self.requests {
+            let actual = ch.send(request).await;
+            let expected = unique(request).and_then(|unique| self.reply(unique)).cloned();
+            if actual != expected {
+                mismatches.push(Mismatch { request: request.clone(), expected, actual });
+            }
+        }
+        mismatches
+    }
+}
+
+/// Returns the unique id in the header of the given request
+fn unique(request: &[u8]) -> Option<u64> {
+    let bytes = request.get(8..16)?;
+    Some(u64::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+}
+
+/// Replayed request whose reply differs from the recorded one
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Raw request data
+    pub request: Vec<u8>,
+    /// Recorded reply, if there was one
+    pub expected: Option<MockReply>,
+    /// Reply of the replayed request, if there was one
+    pub actual: Option<MockReply>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use fuse_abi::{fuse_in_header, fuse_opcode};
+    use crate::channel::ChannelSender;
+    use crate::reply::ReplySender;
+    use crate::{block_on, Entry, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::{Recorder, Recording, RecordingSender};
+
+    /// Writer to a shared buffer
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Names(&'static str);
+
+    #[async_trait]
+    impl Filesystem for Names {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            match name.to_str() {
+                Some(name) if name == self.0 => Ok(Entry::new(FileAttr::builder(2, FileType::RegularFile).build(), 0)),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+    }
+
+    /// Returns a serialized request
+    fn request(unique: u64, opcode: fuse_opcode, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header { len: (40 + arg.len()) as u32, opcode: opcode as u32, unique, nodeid, uid: 0, gid: 0, pid: 1, total_extlen: 0, padding: 0 };
+        let mut data = unsafe { std::slice::from_raw_parts(&header as *const fuse_in_header as *const u8, 40) }.to_vec();
+        data.extend_from_slice(arg);
+        data
+    }
+
+    #[test]
+    fn record_and_replay() {
+        // Record requests and replies the way a mounted session does
+        let buffer = Buffer::default();
+        let recorder = Arc::new(Recorder::new(buffer.clone()));
+        let sender = RecordingSender::new(ChannelSender::null(), recorder.clone());
+        let ch = MockChannel::new(Names("foo"));
+        let requests = [
+            request(1, fuse_opcode::FUSE_INIT, 0, &[7, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            request(2, fuse_opcode::FUSE_LOOKUP, FUSE_ROOT_ID, b"foo\0"),
+            request(3, fuse_opcode::FUSE_LOOKUP, FUSE_ROOT_ID, b"bar\0"),
+        ];
+        for data in &requests {
+            recorder.request(data);
+            let reply = block_on(ch.send(data)).unwrap();
+            ReplySender::send(&sender, &[reply.as_bytes()]);
+        }
+
+        let data = buffer.0.lock().unwrap().clone();
+        let recording = Recording::read(&data[..]).unwrap();
+        assert_eq!(recording.requests(), &requests[..]);
+        assert_eq!(recording.reply(3).unwrap().result().unwrap_err(), Errno::ENOENT);
+
+        // Replaying against the same filesystem gives the same replies, a changed one differs
+        assert!(block_on(recording.replay(&MockChannel::new(Names("foo")))).is_empty());
+        let mismatches = block_on(recording.replay(&MockChannel::new(Names("bar"))));
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].request, requests[1]);
+        assert_eq!(mismatches[0].actual.as_ref().unwrap().result().unwrap_err(), Errno::ENOENT);
+
+        // A truncated recording ends with the last complete record
+        assert_eq!(Recording::read(&data[..data.len() - 1]).unwrap().requests().len(), 3);
+        assert!(Recording::read(&b"?\0\0\0\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Redaction of file names in log output
+//!
+//! The session logs every request it dispatches at debug level, including the names of the
+//! files it operates on. Deployments that must not leak user file names to their logs can
+//! have the session write a hash of every name instead, which still allows to correlate
+//! operations on the same name, or write only the first characters of every name.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+
+/// How the session writes file names to its log output
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Redaction {
+    /// Names are written as they are (the default)
+    #[default]
+    Off,
+    /// Names are replaced by a hash of their bytes, which is the same for equal names
+    Hash,
+    /// Names are truncated to the given number of characters
+    Truncate(usize),
+}
+
+impl Redaction {
+    /// Returns the given name for formatting it redacted
+    pub(crate) fn name(self, name: &OsStr) -> RedactedName<'_> {
+        RedactedName { name, redaction: self }
+    }
+}
+
+/// File name that is redacted when formatted
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RedactedName<'a> {
+    name: &'a OsStr,
+    redaction: Redaction,
+}
+
+impl fmt::Display for RedactedName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.redaction {
+            Redaction::Off => write!(f, "{:?}", self.name),
+            Redaction::Hash => write!(f, "#{:016x}", fnv1a(self.name.as_bytes())),
+            Redaction::Truncate(len) => {
+                let name = self.name.to_string_lossy();
+                match name.char_indices().nth(len) {
+                    Some((end, _)) => write!(f, "{:?}...", &name[..end]),
+                    None => write!(f, "{:?}", name),
+                }
+            }
+        }
+    }
+}
+
+/// FNV-1a hash of the given bytes. Unlike the hasher of the standard library, it's the same
+/// across processes and Rust versions, so that logs of different runs can be correlated.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use super::Redaction;
+
+    #[test]
+    fn off() {
+        assert_eq!(Redaction::Off.name(OsStr::new("secret.txt")).to_string(), "\"secret.txt\"");
+    }
+
+    #[test]
+    fn hash() {
+        let hash = Redaction::Hash.name(OsStr::new("secret.txt")).to_string();
+        assert_eq!(hash.len(), 17);
+        assert!(!hash.contains("secret"));
+        assert_eq!(hash, Redaction::Hash.name(OsStr::new("secret.txt")).to_string());
+        assert_ne!(hash, Redaction::Hash.name(OsStr::new("secret.txr")).to_string());
+        assert_eq!(Redaction::Hash.name(OsStr::new("")).to_string(), "#cbf29ce484222325");
+    }
+
+    #[test]
+    fn truncate() {
+        assert_eq!(Redaction::Truncate(3).name(OsStr::new("secret.txt")).to_string(), "\"sec\"...");
+        assert_eq!(Redaction::Truncate(3).name(OsStr::new("géné")).to_string(), "\"gén\"...");
+        assert_eq!(Redaction::Truncate(3).name(OsStr::new("abc")).to_string(), "\"abc\"");
+        assert_eq!(Redaction::Truncate(0).name(OsStr::new("abc")).to_string(), "\"\"...");
+    }
+}
@@ -0,0 +1,258 @@
+//! Remote filesystems
+//!
+//! A filesystem can be served by a daemon on a different host or in a different container than
+//! its mount. On the mount side, a `Forwarder` mounts the filesystem and ships the raw requests
+//! of the kernel driver over a connection, and passes the replies coming back to the kernel. On
+//! the other side, a `Session` uses the `Connection` as its transport to serve the requests.
+//!
+//! Requests and replies are framed by their length as 32 bit little endian, an empty frame ends
+//! the session. When connecting, both sides send a token and check the token of the other side.
+//! The token is sent in the clear, so connections over untrusted networks need to be tunneled
+//! (e.g. through TLS or SSH).
+
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{fmt, thread};
+use libc::{ENODEV, ENOENT};
+use log::{error, info, warn};
+
+use crate::channel::{self, Channel, Transport};
+use crate::session::{receive_loop, BUFFER_HEADROOM, MAX_WRITE_SIZE};
+
+/// Leads the token sent when connecting, to tell FUSE connections from other protocols
+const MAGIC: &[u8] = b"FUSEFWD1";
+
+/// Maximum size of the token sent when connecting
+const MAX_TOKEN_SIZE: usize = 4096;
+
+/// Connection between a `Forwarder` and a remote `Session`
+pub struct Connection {
+    reader: Mutex<Box<dyn Read + Send>>,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Connection {
+    /// Connect using the given reader and writer of a stream, authenticating with the given
+    /// token. Both sides must use the same token.
+    pub fn new<R, W>(reader: R, writer: W, token: &[u8]) -> io::Result<Connection>
+    where R: Read + Send + 'static, W: Write + Send + 'static {
+        let conn = Connection { reader: Mutex::new(Box::new(reader)), writer: Mutex::new(Box::new(writer)) };
+        conn.handshake(token)?;
+        Ok(conn)
+    }
+
+    /// Connect using the given TCP stream, authenticating with the given token
+    pub fn tcp(stream: TcpStream, token: &[u8]) -> io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        Connection::new(stream.try_clone()?, stream, token)
+    }
+
+    /// Connect using the given Unix socket stream, authenticating with the given token
+    pub fn unix(stream: UnixStream, token: &[u8]) -> io::Result<Connection> {
+        Connection::new(stream.try_clone()?, stream, token)
+    }
+
+    /// Send our token and check the token of the other side
+    fn handshake(&self, token: &[u8]) -> io::Result<()> {
+        if token.len() > MAX_TOKEN_SIZE - MAGIC.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "token too long"));
+        }
+        let hello = [MAGIC, token].concat();
+        self.send_frame(&[&hello])?;
+        let mut buffer = Vec::with_capacity(MAX_TOKEN_SIZE);
+        self.receive_frame(&mut buffer)?;
+        if !buffer.starts_with(MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a FUSE forwarding connection"));
+        }
+        if !constant_time_eq(&buffer[MAGIC.len()..], token) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed"));
+        }
+        Ok(())
+    }
+
+    /// Tell the other side that the session ended
+    fn close(&self) -> io::Result<()> {
+        self.send_frame(&[])
+    }
+
+    fn send_frame(&self, data: &[&[u8]]) -> io::Result<()> {
+        let len = data.iter().map(|d| d.len()).sum::<usize>() as u32;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&len.to_le_bytes())?;
+        for d in data {
+            writer.write_all(d)?;
+        }
+        writer.flush()
+    }
+
+    /// Receive a frame up to the capacity of the given buffer. Returns an ENODEV error if the
+    /// other side ended the session or went away.
+    fn receive_frame(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let mut reader = self.reader.lock().unwrap();
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(io::Error::from_raw_os_error(ENODEV)),
+            res => res?,
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            return Err(io::Error::from_raw_os_error(ENODEV));
+        }
+        if len > buffer.capacity() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame too large ({} bytes)", len)));
+        }
+        buffer.resize(len, 0);
+        reader.read_exact(buffer)
+    }
+}
+
+impl Transport for Connection {
+    fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        self.receive_frame(buffer)
+    }
+
+    fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+        self.send_frame(data)
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Connection")
+    }
+}
+
+/// Compare the given byte strings in a time that doesn't depend on where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Mounted filesystem whose requests are forwarded to a remote session
+#[derive(Debug)]
+pub struct Forwarder {
+    ch: Channel,
+}
+
+impl Forwarder {
+    /// Mount a filesystem to the given mountpoint that is served remotely
+    pub fn new(mountpoint: &Path, options: &[OsString]) -> io::Result<Forwarder> {
+        info!("Mounting {}", mountpoint.display());
+        Ok(Forwarder { ch: Channel::new(mountpoint, options)? })
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// Forward kernel requests over the given connection and replies back to the kernel until
+    /// the filesystem is unmounted or the remote session goes away, which unmounts it.
+    pub fn run(self, conn: Connection) -> io::Result<()> {
+        let conn = Arc::new(conn);
+        let done = Arc::new(AtomicBool::new(false));
+        let replies = {
+            let (conn, done, sender) = (conn.clone(), done.clone(), self.ch.sender());
+            let mountpoint = self.mountpoint().to_path_buf();
+            thread::spawn(move || {
+                let mut buffer = Vec::with_capacity(MAX_WRITE_SIZE + BUFFER_HEADROOM);
+                loop {
+                    match conn.receive_frame(&mut buffer) {
+                        Ok(()) => match sender.send(&[&buffer]) {
+                            // The request was interrupted and is gone
+                            Err(ref err) if err.raw_os_error() == Some(ENOENT) => (),
+                            Err(err) => warn!("Failed to pass FUSE reply to kernel: {}", err),
+                            Ok(()) => (),
+                        },
+                        Err(err) => {
+                            if err.raw_os_error() != Some(ENODEV) {
+                                error!("Failed to receive FUSE reply: {}", err);
+                            }
+                            break;
+                        }
+                    }
+                }
+                // Nobody answers requests anymore, unmount to end the session
+                if !done.swap(true, Ordering::Relaxed) {
+                    info!("Remote session ended, unmounting {}", mountpoint.display());
+                    if let Err(err) = channel::unmount(&mountpoint) {
+                        error!("Failed to unmount {}: {}", mountpoint.display(), err);
+                    }
+                }
+            })
+        };
+        let mut result = Ok(());
+        let res = receive_loop(&self.ch, MAX_WRITE_SIZE + BUFFER_HEADROOM, |data| match conn.send_frame(&[data]) {
+            Ok(()) => true,
+            Err(err) => { result = Err(err); false },
+        });
+        if !done.swap(true, Ordering::Relaxed) {
+            // Unmounted locally, tell the remote session to end
+            let _ = conn.close();
+            drop(replies);
+        } else {
+            let _ = replies.join();
+        }
+        res.and(result)
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        info!("Unmounted {}", self.mountpoint().display());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use libc::ENODEV;
+    use crate::Transport;
+    use super::{constant_time_eq, Connection};
+
+    fn connect(token: &[u8], other_token: &'static [u8]) -> (std::io::Result<Connection>, std::io::Result<Connection>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let other = thread::spawn(move || Connection::unix(b, other_token));
+        (Connection::unix(a, token), other.join().unwrap())
+    }
+
+    #[test]
+    fn handshake() {
+        let (a, b) = connect(b"secret", b"secret");
+        assert!(a.is_ok() && b.is_ok());
+        let (a, b) = connect(b"secret", b"guess");
+        assert_eq!(a.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(b.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(constant_time_eq(b"foo", b"foo"));
+        assert!(!constant_time_eq(b"foo", b"fo"));
+        assert!(!constant_time_eq(b"foo", b"bar"));
+    }
+
+    #[test]
+    fn frames() {
+        let (a, b) = connect(b"", b"");
+        let (a, b) = (a.unwrap(), b.unwrap());
+        a.send(&[b"foo", b"bar"]).unwrap();
+        let mut buffer = Vec::with_capacity(16);
+        b.receive(&mut buffer).unwrap();
+        assert_eq!(buffer, b"foobar");
+
+        // Frames larger than the buffer are rejected
+        a.send(&[&[0; 17]]).unwrap();
+        assert_eq!(b.receive(&mut buffer).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        // Closing or going away ends the session
+        let (a, b) = connect(b"", b"");
+        let (a, b) = (a.unwrap(), b.unwrap());
+        a.close().unwrap();
+        assert_eq!(b.receive(&mut buffer).unwrap_err().raw_os_error(), Some(ENODEV));
+        drop(a);
+        assert_eq!(b.receive(&mut buffer).unwrap_err().raw_os_error(), Some(ENODEV));
+    }
+}
@@ -1,33 +1,71 @@
 //! Filesystem operation reply
 //!
-//! A reply is passed to filesystem operation implementations and must be used to send back the
-//! result of an operation. The reply can optionally be sent to another thread to asynchronously
-//! work on an operation and provide the result later. Also it allows replying with a block of
-//! data without cloning the data. A reply *must always* be used (by calling either ok() or
-//! error() exactly once).
-
-use std::{mem, ptr, slice};
+//! A reply is used to send back the result of a filesystem operation. The session creates a reply
+//! for every request and completes it with the result returned by the filesystem implementation.
+//! The reply can optionally be sent to another thread to asynchronously work on an operation and
+//! provide the result later. Also it allows replying with a block of data without cloning the
+//! data. A reply *must always* be used (by calling either ok() or error() exactly once). Both
+//! consume the reply, and a reply that is dropped without being used replies with EIO, so the
+//! process waiting for it is never left hanging.
+
+use std::{mem, slice};
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::fmt;
 use std::marker::PhantomData;
+#[cfg(feature = "stream")]
+use std::future;
 use std::os::unix::ffi::OsStrExt;
-use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(not(target_os = "macos"))]
+use std::time::UNIX_EPOCH;
 use fuse_abi::{fuse_attr, fuse_kstatfs, fuse_file_lock, fuse_entry_out, fuse_attr_out};
 use fuse_abi::{fuse_open_out, fuse_write_out, fuse_statfs_out, fuse_lk_out, fuse_bmap_out};
-use fuse_abi::fuse_getxattr_out;
+use fuse_abi::{fuse_getxattr_out, fuse_opcode};
+#[cfg(not(target_os = "macos"))]
+use fuse_abi::{fuse_ioctl_iovec, fuse_ioctl_out, fuse_lseek_out, fuse_statx, fuse_statx_out, fuse_sx_time};
+#[cfg(not(target_os = "macos"))]
+use fuse_abi::consts::FUSE_IOCTL_RETRY;
 #[cfg(target_os = "macos")]
 use fuse_abi::fuse_getxtimes_out;
-use fuse_abi::{fuse_out_header, fuse_dirent};
-use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, S_IFSOCK, EIO};
-use log::warn;
+#[cfg(not(target_os = "macos"))]
+use fuse_abi::consts::FOPEN_PASSTHROUGH;
+use fuse_abi::fuse_out_header;
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, S_IFSOCK, EIO, ENOSYS, ETIMEDOUT};
+use log::{debug, warn};
+
+use crate::attr::timespec;
+use crate::ll;
+use crate::request::Completion;
+use crate::session::Unimplemented;
+use crate::{BackingId, Data, Errno, FileType, FileAttr, Entry, Attr, Opened, OpenFlags, Created, Statfs, Lock, Xattr, DirEntry, DirOffset};
+#[cfg(not(target_os = "macos"))]
+use crate::{DirEntryPlus, Ioctl, IoctlIovec};
+#[cfg(target_os = "macos")]
+use crate::XTimes;
 
-use crate::{FileType, FileAttr};
+/// Default time the kernel may cache names and attributes, if neither the filesystem nor
+/// the session specify one (same as libfuse)
+pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Opcode of the request to reply to, if known (for diagnostics only)
+    fn opcode(&self) -> Option<fuse_opcode> {
+        None
+    }
+
+    /// Returns true if the deadline of the request to reply to has passed, so that a reply
+    /// dropped without being sent was abandoned by the session
+    fn expired(&self) -> bool {
+        false
+    }
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -36,12 +74,95 @@ impl fmt::Debug for Box<dyn ReplySender> {
     }
 }
 
+impl fmt::Debug for dyn ReplySender + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "ReplySender")
+    }
+}
+
+/// Shared senders, e.g. the one sender of a session that all of its requests reply to
+impl<S: ReplySender + Sync + ?Sized> ReplySender for Arc<S> {
+    fn send(&self, data: &[&[u8]]) {
+        (**self).send(data)
+    }
+
+    fn opcode(&self) -> Option<fuse_opcode> {
+        (**self).opcode()
+    }
+
+    fn expired(&self) -> bool {
+        (**self).expired()
+    }
+}
+
+/// Sender that knows the opcode of the request to reply to, so that a reply that is dropped
+/// without being sent can tell which operation missed its reply. It also remembers the
+/// operations the filesystem replied ENOSYS to, completes the request (e.g. counts it in the
+/// session's metrics) when sending the reply, and knows the deadline of the request.
+#[derive(Debug)]
+pub(crate) struct ReplyTo<S> {
+    sender: S,
+    opcode: fuse_opcode,
+    unimplemented: Arc<Unimplemented>,
+    completion: Option<Completion>,
+    deadline: Option<Instant>,
+}
+
+impl<S: ReplySender> ReplyTo<S> {
+    pub(crate) fn new(sender: S, opcode: fuse_opcode, unimplemented: Arc<Unimplemented>) -> ReplyTo<S> {
+        ReplyTo { sender, opcode, unimplemented, completion: None, deadline: None }
+    }
+
+    /// Complete the request with the given completion when sending the reply
+    pub(crate) fn completion(mut self, completion: Option<Completion>) -> ReplyTo<S> {
+        self.completion = completion;
+        self
+    }
+
+    /// Reply with ETIMEDOUT instead of EIO if dropped without being sent after the given deadline
+    pub(crate) fn deadline(mut self, deadline: Option<Instant>) -> ReplyTo<S> {
+        self.deadline = deadline;
+        self
+    }
+}
+
+impl<S: ReplySender> ReplySender for ReplyTo<S> {
+    fn send(&self, data: &[&[u8]]) {
+        // The error of the reply is in the header, which is always sent in one piece. Init
+        // must never be skipped, even if the filesystem refused it with ENOSYS.
+        let error = data.first().and_then(|header| header.get(4..8));
+        if error == Some(&(-ENOSYS).to_ne_bytes()[..]) && !matches!(self.opcode, fuse_opcode::FUSE_INIT) {
+            self.unimplemented.insert(self.opcode);
+        }
+        let resent = self.completion.as_ref().map(|completion| completion.replied(data)).unwrap_or_default();
+        self.sender.send(data);
+        for reply in resent {
+            self.sender.send(&[&reply]);
+        }
+    }
+
+    fn opcode(&self) -> Option<fuse_opcode> {
+        Some(self.opcode)
+    }
+
+    fn expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
 /// Generic reply trait
 pub trait Reply {
     /// Create a new reply for the given request
     fn new<S: ReplySender>(unique: u64, sender: S) -> Self;
 }
 
+/// Reply that can be completed with the result of a filesystem operation
+pub trait ReplyResult<T>: Sized {
+    /// Reply to a request with the given result, i.e. with the given data on
+    /// success or with the given error code on failure
+    fn result(self, result: Result<T, Errno>);
+}
+
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
 fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     let len = mem::size_of::<T>();
@@ -55,9 +176,10 @@ fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     }
 }
 
-fn time_from_system_time(system_time: &SystemTime) -> Result<(u64, u32), SystemTimeError> {
-    let duration = system_time.duration_since(UNIX_EPOCH)?;
-    Ok((duration.as_secs(), duration.subsec_nanos()))
+/// Returns the protocol representation of a time, with seconds before the epoch being negative
+fn time_from_system_time(system_time: &SystemTime) -> (u64, u32) {
+    let (secs, nsecs) = timespec(*system_time);
+    (secs as u64, nsecs)
 }
 
 // Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
@@ -79,11 +201,10 @@ fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
 /// Returns a fuse_attr from FileAttr
 #[cfg(target_os = "macos")]
 fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
-    // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-    let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime).unwrap();
-    let (mtime_secs, mtime_nanos) = time_from_system_time(&attr.mtime).unwrap();
-    let (ctime_secs, ctime_nanos) = time_from_system_time(&attr.ctime).unwrap();
-    let (crtime_secs, crtime_nanos) = time_from_system_time(&attr.crtime).unwrap();
+    let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime);
+    let (mtime_secs, mtime_nanos) = time_from_system_time(&attr.mtime);
+    let (ctime_secs, ctime_nanos) = time_from_system_time(&attr.ctime);
+    let (crtime_secs, crtime_nanos) = time_from_system_time(&attr.crtime);
 
     fuse_attr {
         ino: attr.ino,
@@ -109,10 +230,9 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
 /// Returns a fuse_attr from FileAttr
 #[cfg(not(target_os = "macos"))]
 fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
-    // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-    let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime).unwrap();
-    let (mtime_secs, mtime_nanos) = time_from_system_time(&attr.mtime).unwrap();
-    let (ctime_secs, ctime_nanos) = time_from_system_time(&attr.ctime).unwrap();
+    let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime);
+    let (mtime_secs, mtime_nanos) = time_from_system_time(&attr.mtime);
+    let (ctime_secs, ctime_nanos) = time_from_system_time(&attr.ctime);
 
     fuse_attr {
         ino: attr.ino,
@@ -129,6 +249,66 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        blksize: 0,                                 // let the kernel use the block size of the filesystem
+        padding: 0,
+    }
+}
+
+/// Fields of statx replies: the basic fields of stat and the creation time (see statx(2))
+#[cfg(not(target_os = "macos"))]
+const STATX_BASIC_STATS: u32 = 0x7ff;
+#[cfg(not(target_os = "macos"))]
+const STATX_BTIME: u32 = 0x800;
+
+/// Returns a fuse_sx_time from a SystemTime
+#[cfg(not(target_os = "macos"))]
+fn sx_time_from_system_time(system_time: &SystemTime) -> fuse_sx_time {
+    let (secs, nsecs) = timespec(*system_time);
+    fuse_sx_time { tv_sec: secs, tv_nsec: nsecs, reserved: 0 }
+}
+
+/// Returns a fuse_statx from FileAttr. The creation time is left out if it's not set
+/// (`UNIX_EPOCH`), the device number is split like the kernel encodes it.
+#[cfg(not(target_os = "macos"))]
+fn fuse_statx_from_attr(attr: &FileAttr) -> fuse_statx {
+    fuse_statx {
+        mask: if attr.crtime == UNIX_EPOCH { STATX_BASIC_STATS } else { STATX_BASIC_STATS | STATX_BTIME },
+        blksize: 0,                                 // let the kernel use the block size of the filesystem
+        attributes: 0,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        mode: mode_from_kind_and_perm(attr.kind, attr.perm) as u16,
+        spare0: [0],
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        attributes_mask: 0,
+        atime: sx_time_from_system_time(&attr.atime),
+        btime: sx_time_from_system_time(&attr.crtime),
+        ctime: sx_time_from_system_time(&attr.ctime),
+        mtime: sx_time_from_system_time(&attr.mtime),
+        rdev_major: (attr.rdev & 0xfff00) >> 8,
+        rdev_minor: (attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xfff00),
+        dev_major: 0,                               // the kernel reports the device of the mount
+        dev_minor: 0,
+        spare2: [0; 14],
+    }
+}
+
+/// Returns a fuse_open_out with the given file handle, flags and backing file
+#[cfg(target_os = "macos")]
+fn fuse_open_out_from(fh: u64, flags: OpenFlags, _backing: Option<BackingId>) -> fuse_open_out {
+    fuse_open_out { fh: fh, open_flags: flags.bits(), padding: 0 }
+}
+
+/// Returns a fuse_open_out with the given file handle, flags and backing file
+#[cfg(not(target_os = "macos"))]
+fn fuse_open_out_from(fh: u64, flags: OpenFlags, backing: Option<BackingId>) -> fuse_open_out {
+    match backing {
+        // The backing id goes in the padding of ABI 7.8
+        Some(id) => fuse_open_out { fh: fh, open_flags: flags.bits() | FOPEN_PASSTHROUGH, padding: id.0 },
+        None => fuse_open_out { fh: fh, open_flags: flags.bits(), padding: 0 },
     }
 }
 
@@ -166,9 +346,17 @@ impl<T> ReplyRaw<T> {
         };
         as_bytes(&header, |headerbytes| {
             let sender = self.sender.take().unwrap();
-            let mut sendbytes = headerbytes.to_vec();
-            sendbytes.extend(bytes);
-            sender.send(&sendbytes);
+            // Replies are sent in a few pieces, which are gathered on the stack
+            let mut sendbytes: [&[u8]; 4] = [&[]; 4];
+            if bytes.len() < sendbytes.len() {
+                sendbytes[0] = headerbytes[0];
+                sendbytes[1..=bytes.len()].copy_from_slice(bytes);
+                sender.send(&sendbytes[..=bytes.len()]);
+            } else {
+                let mut sendbytes = headerbytes.to_vec();
+                sendbytes.extend(bytes);
+                sender.send(&sendbytes);
+            }
         });
     }
 
@@ -179,21 +367,71 @@ impl<T> ReplyRaw<T> {
         })
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(mut self, err: c_int) {
-        self.send(err, &[]);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(mut self, err: E) {
+        self.send(err.into().raw(), &[]);
+    }
+
+    /// Returns the reply as a reply with another type, e.g. an extended structure the request
+    /// asked for
+    pub(crate) fn cast<U>(mut self) -> ReplyRaw<U> {
+        ReplyRaw { unique: self.unique, sender: self.sender.take(), marker: PhantomData }
     }
 }
 
 impl<T> Drop for ReplyRaw<T> {
     fn drop(&mut self) {
-        if self.sender.is_some() {
-            warn!("Reply not sent for operation {}, replying with I/O error", self.unique);
+        if let Some(sender) = &self.sender {
+            // The session gave up on the request at its deadline
+            if sender.expired() {
+                debug!("Operation {} timed out, replying with ETIMEDOUT", self.unique);
+                self.send(ETIMEDOUT, &[]);
+                return;
+            }
+            // Never leave the calling process hanging if a code path (or a panic) skipped the reply
+            match sender.opcode() {
+                Some(opcode) => warn!("Reply not sent for {:?} operation {}, replying with I/O error", opcode, self.unique),
+                None => warn!("Reply not sent for operation {}, replying with I/O error", self.unique),
+            }
             self.send(EIO, &[]);
         }
     }
 }
 
+///
+/// Reply with arbitrary data
+///
+#[derive(Debug)]
+pub struct ReplyBytes {
+    reply: ReplyRaw<()>,
+}
+
+impl Reply for ReplyBytes {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyBytes {
+        ReplyBytes { reply: Reply::new(unique, sender) }
+    }
+}
+
+impl ReplyBytes {
+    /// Reply to a request with the given data, which is sent after the reply header
+    pub fn data(mut self, data: &[&[u8]]) {
+        self.reply.send(0, data);
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+
+    /// Don't reply to the request. Only requests that aren't answered by the kernel
+    /// protocol (forget) may be dropped this way.
+    pub fn none(mut self) {
+        self.reply.sender = None;
+    }
+}
+
 ///
 /// Empty reply
 ///
@@ -214,9 +452,19 @@ impl ReplyEmpty {
         self.reply.send(0, &[]);
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<()> for ReplyEmpty {
+    fn result(self, result: Result<(), Errno>) {
+        match result {
+            Ok(()) => self.ok(),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -226,23 +474,44 @@ impl ReplyEmpty {
 #[derive(Debug)]
 pub struct ReplyData {
     reply: ReplyRaw<()>,
+    max_size: usize,
 }
 
 impl Reply for ReplyData {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyData {
-        ReplyData { reply: Reply::new(unique, sender) }
+        ReplyData { reply: Reply::new(unique, sender), max_size: usize::MAX }
     }
 }
 
 impl ReplyData {
+    /// Set the size of the requested data. The kernel rejects replies with more data than
+    /// requested, so any data beyond it is cut off (unlimited by default).
+    pub fn max_size(mut self, max_size: usize) -> ReplyData {
+        self.max_size = max_size;
+        self
+    }
+
     /// Reply to a request with the given data
     pub fn data(mut self, data: &[u8]) {
-        self.reply.send(0, &[data]);
+        if data.len() > self.max_size {
+            warn!("Cutting off data reply for operation {} at the {} bytes requested ({} given)", self.reply.unique, self.max_size, data.len());
+        }
+        self.reply.send(0, &[&data[..data.len().min(self.max_size)]]);
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
     }
+}
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+impl<T: Into<Data>> ReplyResult<T> for ReplyData {
+    fn result(self, result: Result<T, Errno>) {
+        match result {
+            Ok(data) => self.data(&data.into()),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -252,31 +521,60 @@ impl ReplyData {
 #[derive(Debug)]
 pub struct ReplyEntry {
     reply: ReplyRaw<fuse_entry_out>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
 }
 
 impl Reply for ReplyEntry {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEntry {
-        ReplyEntry { reply: Reply::new(unique, sender) }
+        ReplyEntry { reply: Reply::new(unique, sender), entry_ttl: DEFAULT_TTL, attr_ttl: DEFAULT_TTL }
     }
 }
 
 impl ReplyEntry {
+    /// Set the TTLs used for results that don't specify one
+    pub fn default_ttl(mut self, entry_ttl: Duration, attr_ttl: Duration) -> ReplyEntry {
+        self.entry_ttl = entry_ttl;
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
     /// Reply to a request with the given entry
     pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        self.send(ttl, ttl, attr, generation);
+    }
+
+    fn send(self, entry_ttl: &Duration, attr_ttl: &Duration, attr: &FileAttr, generation: u64) {
         self.reply.ok(&fuse_entry_out {
             nodeid: attr.ino,
             generation: generation,
-            entry_valid: ttl.as_secs(),
-            attr_valid: ttl.as_secs(),
-            entry_valid_nsec: ttl.subsec_nanos(),
-            attr_valid_nsec: ttl.subsec_nanos(),
+            entry_valid: entry_ttl.as_secs(),
+            attr_valid: attr_ttl.as_secs(),
+            entry_valid_nsec: entry_ttl.subsec_nanos(),
+            attr_valid_nsec: attr_ttl.subsec_nanos(),
             attr: fuse_attr_from_attr(attr),
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Entry> for ReplyEntry {
+    fn result(self, result: Result<Entry, Errno>) {
+        match result {
+            Ok(entry) => {
+                let (entry_ttl, attr_ttl) = match entry.ttl {
+                    Some(ttl) => (ttl, ttl),
+                    None => (self.entry_ttl, self.attr_ttl),
+                };
+                self.send(&entry_ttl, &attr_ttl, &entry.attr, entry.generation);
+            }
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -286,15 +584,22 @@ impl ReplyEntry {
 #[derive(Debug)]
 pub struct ReplyAttr {
     reply: ReplyRaw<fuse_attr_out>,
+    attr_ttl: Duration,
 }
 
 impl Reply for ReplyAttr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyAttr {
-        ReplyAttr { reply: Reply::new(unique, sender) }
+        ReplyAttr { reply: Reply::new(unique, sender), attr_ttl: DEFAULT_TTL }
     }
 }
 
 impl ReplyAttr {
+    /// Set the TTL used for results that don't specify one
+    pub fn default_ttl(mut self, attr_ttl: Duration) -> ReplyAttr {
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
     /// Reply to a request with the given attribute
     pub fn attr(self, ttl: &Duration, attr: &FileAttr) {
         self.reply.ok(&fuse_attr_out {
@@ -305,9 +610,78 @@ impl ReplyAttr {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Attr> for ReplyAttr {
+    fn result(self, result: Result<Attr, Errno>) {
+        match result {
+            Ok(attr) => {
+                let ttl = attr.ttl.unwrap_or(self.attr_ttl);
+                self.attr(&ttl, &attr.attr);
+            }
+            Err(err) => self.error(err),
+        }
+    }
+}
+
+///
+/// Statx Reply
+///
+#[cfg(not(target_os = "macos"))]
+#[derive(Debug)]
+pub struct ReplyStatx {
+    reply: ReplyRaw<fuse_statx_out>,
+    attr_ttl: Duration,
+}
+
+#[cfg(not(target_os = "macos"))]
+impl Reply for ReplyStatx {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyStatx {
+        ReplyStatx { reply: Reply::new(unique, sender), attr_ttl: DEFAULT_TTL }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyStatx {
+    /// Set the TTL used for results that don't specify one
+    pub fn default_ttl(mut self, attr_ttl: Duration) -> ReplyStatx {
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    /// Reply to a request with the given attribute
+    pub fn statx(self, ttl: &Duration, attr: &FileAttr) {
+        self.reply.ok(&fuse_statx_out {
+            attr_valid: ttl.as_secs(),
+            attr_valid_nsec: ttl.subsec_nanos(),
+            flags: 0,
+            spare: [0; 2],
+            stat: fuse_statx_from_attr(attr),
+        });
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyResult<Attr> for ReplyStatx {
+    fn result(self, result: Result<Attr, Errno>) {
+        match result {
+            Ok(attr) => {
+                let ttl = attr.ttl.unwrap_or(self.attr_ttl);
+                self.statx(&ttl, &attr.attr);
+            }
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -331,9 +705,8 @@ impl Reply for ReplyXTimes {
 impl ReplyXTimes {
     /// Reply to a request with the given xtimes
     pub fn xtimes(self, bkuptime: SystemTime, crtime: SystemTime) {
-        // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-        let (bkuptime_secs, bkuptime_nanos) = time_from_system_time(&bkuptime).unwrap();
-        let (crtime_secs, crtime_nanos) = time_from_system_time(&crtime).unwrap();
+            let (bkuptime_secs, bkuptime_nanos) = time_from_system_time(&bkuptime);
+        let (crtime_secs, crtime_nanos) = time_from_system_time(&crtime);
         self.reply.ok(&fuse_getxtimes_out {
             bkuptime: bkuptime_secs,
             crtime: crtime_secs,
@@ -342,9 +715,20 @@ impl ReplyXTimes {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl ReplyResult<XTimes> for ReplyXTimes {
+    fn result(self, result: Result<XTimes, Errno>) {
+        match result {
+            Ok(xtimes) => self.xtimes(xtimes.bkuptime, xtimes.crtime),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -354,27 +738,47 @@ impl ReplyXTimes {
 #[derive(Debug)]
 pub struct ReplyOpen {
     reply: ReplyRaw<fuse_open_out>,
+    proto_minor: u32,
 }
 
 impl Reply for ReplyOpen {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyOpen {
-        ReplyOpen { reply: Reply::new(unique, sender) }
+        ReplyOpen { reply: Reply::new(unique, sender), proto_minor: u32::MAX }
     }
 }
 
 impl ReplyOpen {
+    /// Set the protocol minor version of the kernel driver, to drop open flags it doesn't know
+    /// (all flags are sent by default)
+    pub fn proto_minor(mut self, proto_minor: u32) -> ReplyOpen {
+        self.proto_minor = proto_minor;
+        self
+    }
+
     /// Reply to a request with the given open result
-    pub fn opened(self, fh: u64, flags: u32) {
-        self.reply.ok(&fuse_open_out {
-            fh: fh,
-            open_flags: flags,
-            padding: 0,
-        });
+    pub fn opened(self, fh: u64, flags: OpenFlags) {
+        self.reply.ok(&fuse_open_out_from(fh, flags.supported(self.proto_minor), None));
+    }
+
+    /// Reply to a request with the given open result, reading and writing the given backing
+    /// file instead of sending requests (see `BackingFiles`)
+    pub fn passthrough(self, fh: u64, flags: OpenFlags, backing: BackingId) {
+        self.reply.ok(&fuse_open_out_from(fh, flags.supported(self.proto_minor), Some(backing)));
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Opened> for ReplyOpen {
+    fn result(self, result: Result<Opened, Errno>) {
+        match result {
+            Ok(opened) => self.reply.ok(&fuse_open_out_from(opened.fh, opened.flags.supported(self.proto_minor), opened.backing)),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -401,9 +805,19 @@ impl ReplyWrite {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<u32> for ReplyWrite {
+    fn result(self, result: Result<u32, Errno>) {
+        match result {
+            Ok(size) => self.written(size),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -440,9 +854,19 @@ impl ReplyStatfs {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Statfs> for ReplyStatfs {
+    fn result(self, result: Result<Statfs, Errno>) {
+        match result {
+            Ok(st) => self.statfs(st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -452,35 +876,70 @@ impl ReplyStatfs {
 #[derive(Debug)]
 pub struct ReplyCreate {
     reply: ReplyRaw<(fuse_entry_out, fuse_open_out)>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    proto_minor: u32,
 }
 
 impl Reply for ReplyCreate {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
-        ReplyCreate { reply: Reply::new(unique, sender) }
+        ReplyCreate { reply: Reply::new(unique, sender), entry_ttl: DEFAULT_TTL, attr_ttl: DEFAULT_TTL, proto_minor: u32::MAX }
     }
 }
 
 impl ReplyCreate {
+    /// Set the TTLs used for results that don't specify one
+    pub fn default_ttl(mut self, entry_ttl: Duration, attr_ttl: Duration) -> ReplyCreate {
+        self.entry_ttl = entry_ttl;
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    /// Set the protocol minor version of the kernel driver, to drop open flags it doesn't know
+    /// (all flags are sent by default)
+    pub fn proto_minor(mut self, proto_minor: u32) -> ReplyCreate {
+        self.proto_minor = proto_minor;
+        self
+    }
+
     /// Reply to a request with the given entry
-    pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+    pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: OpenFlags) {
+        let open = fuse_open_out_from(fh, flags.supported(self.proto_minor), None);
+        self.send(ttl, ttl, attr, generation, open);
+    }
+
+    fn send(self, entry_ttl: &Duration, attr_ttl: &Duration, attr: &FileAttr, generation: u64, open: fuse_open_out) {
         self.reply.ok(&(fuse_entry_out {
             nodeid: attr.ino,
             generation: generation,
-            entry_valid: ttl.as_secs(),
-            attr_valid: ttl.as_secs(),
-            entry_valid_nsec: ttl.subsec_nanos(),
-            attr_valid_nsec: ttl.subsec_nanos(),
+            entry_valid: entry_ttl.as_secs(),
+            attr_valid: attr_ttl.as_secs(),
+            entry_valid_nsec: entry_ttl.subsec_nanos(),
+            attr_valid_nsec: attr_ttl.subsec_nanos(),
             attr: fuse_attr_from_attr(attr),
-        }, fuse_open_out {
-            fh: fh,
-            open_flags: flags,
-            padding: 0,
-        }));
+        }, open));
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Created> for ReplyCreate {
+    fn result(self, result: Result<Created, Errno>) {
+        match result {
+            Ok(created) => {
+                let (entry_ttl, attr_ttl) = match created.ttl {
+                    Some(ttl) => (ttl, ttl),
+                    None => (self.entry_ttl, self.attr_ttl),
+                };
+                let open = fuse_open_out_from(created.fh, created.flags.supported(self.proto_minor), created.backing);
+                self.send(&entry_ttl, &attr_ttl, &created.attr, created.generation, open);
+            }
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -511,9 +970,19 @@ impl ReplyLock {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Lock> for ReplyLock {
+    fn result(self, result: Result<Lock, Errno>) {
+        match result {
+            Ok(lock) => self.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -539,9 +1008,114 @@ impl ReplyBmap {
         });
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<u64> for ReplyBmap {
+    fn result(self, result: Result<u64, Errno>) {
+        match result {
+            Ok(block) => self.bmap(block),
+            Err(err) => self.error(err),
+        }
+    }
+}
+
+///
+/// Ioctl Reply
+///
+#[cfg(not(target_os = "macos"))]
+#[derive(Debug)]
+pub struct ReplyIoctl {
+    reply: ReplyRaw<fuse_ioctl_out>,
+}
+
+#[cfg(not(target_os = "macos"))]
+impl Reply for ReplyIoctl {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyIoctl {
+        ReplyIoctl { reply: Reply::new(unique, sender) }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyIoctl {
+    /// Reply to a request with the return value of the ioctl and the data to copy to the caller
+    pub fn ioctl(mut self, result: i32, data: &[u8]) {
+        let out = fuse_ioctl_out { result, flags: 0, in_iovs: 0, out_iovs: 0 };
+        as_bytes(&out, |bytes| self.reply.send(0, &[bytes[0], data]));
+    }
+
+    /// Reply to an unrestricted ioctl request, asking the kernel to retry it with the given
+    /// memory areas of the caller copied in and out
+    pub fn retry(mut self, input: &[IoctlIovec], output: &[IoctlIovec]) {
+        let out = fuse_ioctl_out { result: 0, flags: FUSE_IOCTL_RETRY, in_iovs: input.len() as u32, out_iovs: output.len() as u32 };
+        let mut iovs = Vec::with_capacity((input.len() + output.len()) * mem::size_of::<fuse_ioctl_iovec>());
+        for iov in input.iter().chain(output) {
+            as_bytes(&fuse_ioctl_iovec { base: iov.base, len: iov.len }, |bytes| iovs.extend_from_slice(bytes[0]));
+        }
+        as_bytes(&out, |bytes| self.reply.send(0, &[bytes[0], &iovs]));
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyResult<Ioctl> for ReplyIoctl {
+    fn result(self, result: Result<Ioctl, Errno>) {
+        match result {
+            Ok(Ioctl::Done(value, data)) => self.ioctl(value, &data),
+            Ok(Ioctl::Retry(input, output)) => self.retry(&input, &output),
+            Err(err) => self.error(err),
+        }
+    }
+}
+
+///
+/// Lseek Reply
+///
+#[cfg(not(target_os = "macos"))]
+#[derive(Debug)]
+pub struct ReplyLseek {
+    reply: ReplyRaw<fuse_lseek_out>,
+}
+
+#[cfg(not(target_os = "macos"))]
+impl Reply for ReplyLseek {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyLseek {
+        ReplyLseek { reply: Reply::new(unique, sender) }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyLseek {
+    /// Reply to a request with the resulting offset
+    pub fn offset(self, offset: i64) {
+        self.reply.ok(&fuse_lseek_out {
+            offset: offset as u64,
+        });
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyResult<i64> for ReplyLseek {
+    fn result(self, result: Result<i64, Errno>) {
+        match result {
+            Ok(offset) => self.offset(offset),
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -566,27 +1140,127 @@ impl ReplyDirectory {
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
     /// value to request the next entries in further readdir calls
-    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
-        let name = name.as_ref().as_bytes();
-        let entlen = mem::size_of::<fuse_dirent>() + name.len();
-        let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
-        let padlen = entsize - entlen;
-        if self.data.len() + entsize > self.data.capacity() { return true; }
-        unsafe {
-            let p = self.data.as_mut_ptr().offset(self.data.len() as isize);
-            let pdirent: *mut fuse_dirent = mem::transmute(p);
-            (*pdirent).ino = ino;
-            (*pdirent).off = offset as u64;
-            (*pdirent).namelen = name.len() as u32;
-            (*pdirent).typ = mode_from_kind_and_perm(kind, 0) >> 12;
-            let p = p.offset(mem::size_of_val(&*pdirent) as isize);
-            ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
-            let p = p.offset(name.len() as isize);
-            ptr::write_bytes(p, 0u8, padlen);
-            let newlen = self.data.len() + entsize;
-            self.data.set_len(newlen);
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: DirOffset, kind: FileType, name: T) -> bool {
+        let size = self.data.capacity();
+        ll::push_dirent(&mut self.data, 0, ino, offset.raw(), mode_from_kind_and_perm(kind, 0) >> 12, name.as_ref().as_bytes(), size)
+    }
+
+    /// Add a directory entry to the reply buffer. Returns true if the buffer is full.
+    pub fn add_entry(&mut self, entry: &DirEntry) -> bool {
+        self.add(entry.ino, entry.offset, entry.kind, &entry.name)
+    }
+
+    /// Add entries to the reply buffer until it is full. Returns the number of entries that
+    /// were added. Entries that didn't fit are requested again by the kernel using the offset
+    /// of the last added entry.
+    pub fn add_entries<I: IntoIterator<Item = DirEntry>>(&mut self, entries: I) -> usize {
+        let mut count = 0;
+        for entry in entries {
+            if self.add_entry(&entry) { break; }
+            count += 1;
         }
-        false
+        count
+    }
+
+    /// Add entries from a stream to the reply buffer until it is full or the stream ends.
+    /// Returns the number of entries that were added.
+    #[cfg(feature = "stream")]
+    pub async fn add_stream<S: Stream<Item = DirEntry>>(&mut self, entries: S) -> usize {
+        let mut entries = Box::pin(entries);
+        let mut count = 0;
+        while let Some(entry) = future::poll_fn(|cx| entries.as_mut().poll_next(cx)).await {
+            if self.add_entry(&entry) { break; }
+            count += 1;
+        }
+        count
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub fn ok(mut self) {
+        self.reply.send(0, &[&self.data]);
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+impl ReplyResult<Vec<DirEntry>> for ReplyDirectory {
+    fn result(mut self, result: Result<Vec<DirEntry>, Errno>) {
+        match result {
+            Ok(entries) => {
+                self.add_entries(entries);
+                self.ok();
+            }
+            Err(err) => self.error(err),
+        }
+    }
+}
+
+///
+/// Directory reply with the attributes of the entries (readdirplus)
+///
+#[cfg(not(target_os = "macos"))]
+#[derive(Debug)]
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<()>,
+    data: Vec<u8>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyDirectoryPlus {
+    /// Creates a new ReplyDirectoryPlus with a specified buffer size.
+    pub fn new<S: ReplySender>(unique: u64, sender: S, size: usize) -> ReplyDirectoryPlus {
+        ReplyDirectoryPlus {
+            reply: Reply::new(unique, sender),
+            data: Vec::with_capacity(size),
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Set the TTLs used for entries that don't specify one
+    pub fn default_ttl(mut self, entry_ttl: Duration, attr_ttl: Duration) -> ReplyDirectoryPlus {
+        self.entry_ttl = entry_ttl;
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    /// Add a directory entry with its attributes to the reply buffer. Returns true if the
+    /// buffer is full.
+    pub fn add_entry(&mut self, entry: &DirEntryPlus) -> bool {
+        let size = self.data.capacity();
+        let (entry_ttl, attr_ttl) = match entry.entry.ttl {
+            Some(ttl) => (ttl, ttl),
+            None => (self.entry_ttl, self.attr_ttl),
+        };
+        let out = fuse_entry_out {
+            nodeid: entry.entry.attr.ino,
+            generation: entry.entry.generation,
+            entry_valid: entry_ttl.as_secs(),
+            attr_valid: attr_ttl.as_secs(),
+            entry_valid_nsec: entry_ttl.subsec_nanos(),
+            attr_valid_nsec: attr_ttl.subsec_nanos(),
+            attr: fuse_attr_from_attr(&entry.entry.attr),
+        };
+        let dirent = &entry.dirent;
+        let typ = mode_from_kind_and_perm(dirent.kind, 0) >> 12;
+        as_bytes(&out, |bytes| ll::push_direntplus(&mut self.data, 0, bytes[0], dirent.ino, dirent.offset.raw(), typ, dirent.name.as_bytes(), size))
+    }
+
+    /// Add entries to the reply buffer until it is full. Returns the number of entries that
+    /// were added.
+    pub fn add_entries<I: IntoIterator<Item = DirEntryPlus>>(&mut self, entries: I) -> usize {
+        let mut count = 0;
+        for entry in entries {
+            if self.add_entry(&entry) { break; }
+            count += 1;
+        }
+        count
     }
 
     /// Reply to a request with the filled directory buffer
@@ -594,9 +1268,23 @@ impl ReplyDirectory {
         self.reply.send(0, &[&self.data]);
     }
 
-    /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl ReplyResult<Vec<DirEntryPlus>> for ReplyDirectoryPlus {
+    fn result(mut self, result: Result<Vec<DirEntryPlus>, Errno>) {
+        match result {
+            Ok(entries) => {
+                self.add_entries(entries);
+                self.ok();
+            }
+            Err(err) => self.error(err),
+        }
     }
 }
 
@@ -606,15 +1294,24 @@ impl ReplyDirectory {
 #[derive(Debug)]
 pub struct ReplyXattr {
     reply: ReplyRaw<fuse_getxattr_out>,
+    /// Size of the buffer the kernel provided for the data (0 if it asked for the size only),
+    /// if known
+    requested: Option<u32>,
 }
 
 impl Reply for ReplyXattr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyXattr {
-        ReplyXattr { reply: Reply::new(unique, sender) }
+        ReplyXattr { reply: Reply::new(unique, sender), requested: None }
     }
 }
 
 impl ReplyXattr {
+    /// Set the size the request asked for, which makes `data` check the size of the data
+    pub fn requested(mut self, size: u32) -> ReplyXattr {
+        self.requested = Some(size);
+        self
+    }
+
     /// Reply to a request with the size of the xattr.
     pub fn size(self, size: u32) {
         self.reply.ok(&fuse_getxattr_out {
@@ -623,29 +1320,52 @@ impl ReplyXattr {
         });
     }
 
-    /// Reply to a request with the data in the xattr.
+    /// Reply to a request with the data in the xattr. If the requested size is known, this
+    /// replies with the size of the data if the request asked for the size only, or with
+    /// ERANGE if the data doesn't fit.
     pub fn data(mut self, data: &[u8]) {
-        self.reply.send(0, &[data]);
+        match self.requested {
+            Some(0) => self.size(data.len() as u32),
+            Some(size) if data.len() > size as usize => self.error(Errno::ERANGE),
+            _ => self.reply.send(0, &[data]),
+        }
+    }
+
+    /// Reply to a request with the given error code (an `Errno`, a raw errno, an `io::Error` or
+    /// an `io::ErrorKind`)
+    pub fn error<E: Into<Errno>>(self, err: E) {
+        self.reply.error(err.into());
     }
+}
 
-    /// Reply to a request with the given error code.
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+impl ReplyResult<Xattr> for ReplyXattr {
+    fn result(self, result: Result<Xattr, Errno>) {
+        match result {
+            Ok(Xattr::Size(size)) => self.size(size),
+            Ok(Xattr::Data(data)) => self.data(&data),
+            Err(err) => self.error(err),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::thread;
+    use std::sync::Arc;
     use std::sync::mpsc::{channel, Sender};
     use std::time::{Duration, UNIX_EPOCH};
     use super::as_bytes;
     use super::{Reply, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
     use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
-    use super::ReplyXattr;
+    use super::{ReplyXattr, ReplyResult, ReplyBytes, ReplyTo};
+    use fuse_abi::fuse_opcode;
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
-    use crate::{FileType, FileAttr};
+    #[cfg(not(target_os = "macos"))]
+    use super::{ReplyDirectoryPlus, ReplyLseek};
+    use crate::{Errno, FileType, FileAttr, Attr, DirEntry, DirOffset, Opened, OpenFlags, Xattr};
+    #[cfg(not(target_os = "macos"))]
+    use crate::{DirEntryPlus, Entry};
 
     #[allow(dead_code)]
     #[repr(C)]
@@ -718,6 +1438,18 @@ mod test {
         reply.error(66);
     }
 
+    #[test]
+    fn reply_io_error() {
+        // ENOENT, from the error number of an OS error
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, sender);
+        reply.error(std::io::Error::from_raw_os_error(libc::ENOENT));
+    }
+
     #[test]
     fn reply_empty() {
         let sender = AssertSender {
@@ -759,7 +1491,7 @@ mod test {
                 ]
             } else {
                 vec![
-                    vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -767,7 +1499,7 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
         };
@@ -795,13 +1527,14 @@ mod test {
                 ]
             } else {
                 vec![
-                    vec![0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,
-                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
         };
@@ -813,6 +1546,41 @@ mod test {
         reply.attr(&ttl, &attr);
     }
 
+    #[test]
+    fn reply_attr_default_ttl() {
+        let sender = AssertSender {
+            expected: if cfg!(target_os = "macos") {
+                vec![
+                    vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                         0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,  0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
+                ]
+            } else {
+                vec![
+                    vec![0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,
+                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ]
+            }
+        };
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, sender);
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let ttl = Duration::new(0x8765, 0x4321);
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+        reply.default_ttl(ttl).result(Ok(Attr::new(attr)));
+    }
+
     #[test]
     #[cfg(target_os = "macos")]
     fn reply_xtimes() {
@@ -837,7 +1605,7 @@ mod test {
             ]
         };
         let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
-        reply.opened(0x1122, 0x33);
+        reply.opened(0x1122, OpenFlags::from_bits(0x33));
     }
 
     #[test]
@@ -852,6 +1620,19 @@ mod test {
         reply.written(0x1122);
     }
 
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn reply_lseek() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyLseek = Reply::new(0xdeadbeef, sender);
+        reply.offset(0x11223344);
+    }
+
     #[test]
     fn reply_statfs() {
         let sender = AssertSender {
@@ -887,7 +1668,7 @@ mod test {
                 ]
             } else {
                 vec![
-                    vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -895,8 +1676,8 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
         };
@@ -905,7 +1686,7 @@ mod test {
         let ttl = Duration::new(0x8765, 0x4321);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
             kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
-        reply.created(&ttl, &attr, 0xaa, 0xbb, 0xcc);
+        reply.created(&ttl, &attr, 0xaa, 0xbb, OpenFlags::from_bits(0xcc));
     }
 
     #[test]
@@ -945,11 +1726,94 @@ mod test {
             ]
         };
         let mut reply = ReplyDirectory::new(0xdeadbeef, sender, 4096);
-        reply.add(0xaabb, 1, FileType::Directory, "hello");
-        reply.add(0xccdd, 2, FileType::RegularFile, "world.rs");
+        reply.add(0xaabb, DirOffset::from_raw(1), FileType::Directory, "hello");
+        reply.add(0xccdd, DirOffset::from_raw(2), FileType::RegularFile, "world.rs");
+        reply.ok();
+    }
+
+    #[test]
+    fn reply_directory_result() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x05, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00 ,0x00, 0x00],
+            ]
+        };
+        // Buffer only fits the first entry, the second one is left for the next readdir call
+        let reply = ReplyDirectory::new(0xdeadbeef, sender, 32);
+        reply.result(Ok(vec![
+            DirEntry { ino: 0xaabb, offset: DirOffset::from_raw(1), kind: FileType::Directory, name: "hello".into() },
+            DirEntry { ino: 0xccdd, offset: DirOffset::from_raw(2), kind: FileType::RegularFile, name: "world.rs".into() },
+        ]));
+    }
+
+    #[test]
+    fn reply_directory_add_entries() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x05, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00 ,0x00, 0x00],
+            ]
+        };
+        let mut reply = ReplyDirectory::new(0xdeadbeef, sender, 32);
+        let entries = (1..4).map(|i| DirEntry { ino: 0xaabb, offset: DirOffset::from_raw(i), kind: FileType::Directory, name: "hello".into() });
+        assert_eq!(reply.add_entries(entries), 1);
         reply.ok();
     }
 
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn reply_directory_plus() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        struct Collect(Sender<Vec<u8>>);
+        impl super::ReplySender for Collect {
+            fn send(&self, data: &[&[u8]]) {
+                self.0.send(data.concat()).unwrap();
+            }
+        }
+        // Every entry takes 128 bytes of entry attributes and 32 bytes of directory entry
+        let mut reply = ReplyDirectoryPlus::new(0xdeadbeef, Collect(tx), 320).default_ttl(Duration::from_secs(3), Duration::from_secs(4));
+        let entry = |ino, name: &str| DirEntryPlus {
+            dirent: DirEntry { ino, offset: DirOffset::from_raw(ino), kind: FileType::RegularFile, name: name.into() },
+            entry: Entry::new(FileAttr::builder(ino, FileType::RegularFile).build(), 7),
+        };
+        assert_eq!(reply.add_entries(vec![entry(0x11, "hello"), entry(0x22, "world"), entry(0x33, "again")]), 2);
+        reply.ok();
+        let data = rx.recv().unwrap();
+        assert_eq!(data.len(), 16 + 320);
+        // Entry attributes: nodeid, generation, entry and attribute TTL
+        assert_eq!(&data[16..48], &[0x11, 0, 0, 0, 0, 0, 0, 0,  7, 0, 0, 0, 0, 0, 0, 0,  3, 0, 0, 0, 0, 0, 0, 0,  4, 0, 0, 0, 0, 0, 0, 0]);
+        // Directory entry: ino, offset, namelen, type, name
+        assert_eq!(&data[16 + 128..16 + 160], &[0x11, 0, 0, 0, 0, 0, 0, 0,  0x11, 0, 0, 0, 0, 0, 0, 0,  5, 0, 0, 0, 8, 0, 0, 0,  b'h', b'e', b'l', b'l', b'o', 0, 0, 0]);
+        assert_eq!(&data[16 + 160..16 + 168], &[0x22, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reply_bytes() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad],
+                vec![0xbe, 0xef],
+            ]
+        };
+        let reply: ReplyBytes = Reply::new(0xdeadbeef, sender);
+        reply.data(&[&[0xde, 0xad], &[0xbe, 0xef]]);
+    }
+
+    #[test]
+    fn reply_result_error() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
+        reply.result(Err::<Opened, _>(Errno::ENOENT));
+    }
+
     impl super::ReplySender for Sender<()> {
         fn send(&self, _: &[&[u8]]) {
             Sender::send(self, ()).unwrap()
@@ -980,6 +1844,30 @@ mod test {
         reply.data(&vec![0x11, 0x22, 0x33, 0x44]);
     }
 
+    #[test]
+    fn reply_xattr_requested() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        ReplyXattr::new(0xdeadbeef, sender).requested(0).data(&[0x11, 0x22, 0x33, 0x44]);
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xde, 0xff, 0xff, 0xff,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+            ]
+        };
+        ReplyXattr::new(0xdeadbeef, sender).requested(3).data(&[0x11, 0x22, 0x33, 0x44]);
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x61, 0x00],
+            ]
+        };
+        ReplyXattr::new(0xdeadbeef, sender).requested(4).result(Ok(Xattr::names(["a"])));
+    }
+
     #[test]
     fn async_reply() {
         let (tx, rx) = channel::<()>();
@@ -989,4 +1877,19 @@ mod test {
         });
         rx.recv().unwrap();
     }
+
+    #[test]
+    fn reply_dropped() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xfb, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, ReplyTo::new(sender, fuse_opcode::FUSE_LOOKUP, Arc::default()));
+        drop(reply);
+        let (tx, rx) = channel::<()>();
+        let reply: ReplyEmpty = Reply::new(0xdeadbeef, ReplyTo::new(tx, fuse_opcode::FUSE_FLUSH, Arc::default()));
+        drop(reply);
+        rx.try_recv().unwrap();
+    }
 }
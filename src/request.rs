@@ -6,44 +6,132 @@
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
 use std::convert::TryFrom;
+use std::mem;
+#[cfg(not(target_os = "macos"))]
+use std::os::unix::ffi::OsStrExt;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use libc::{EIO, ENOSYS, EPROTO};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+use libc::{EAGAIN, EIO, ENOSYS, EPROTO};
 use fuse_abi::*;
 use fuse_abi::consts::*;
 use log::{debug, error, warn};
-use std::sync::Arc;
+#[cfg(not(target_os = "macos"))]
+use zerocopy::FromBytes;
+use std::sync::{Arc, OnceLock, RwLock, RwLockWriteGuard};
 use std::sync::atomic::Ordering;
 
-use crate::channel::ChannelSender;
+use crate::attr::system_time;
+use crate::direct;
+use crate::groups;
+use crate::idmap::IdMap;
+use crate::cancel::CancellationToken;
+use crate::interrupt::Interrupt;
+use crate::jsonlog::{JsonLog, LogEntry};
+use crate::executor::Executor;
 use crate::ll;
-use crate::reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory};
-use crate::session::{MAX_WRITE_SIZE, Session};
-use crate::Filesystem;
-
-/// We generally support async reads
-#[cfg(not(target_os = "macos"))]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
-// TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
-
-/// On macOS, we additionally support case insensitiveness, volume renames and xtimes
-/// TODO: we should eventually let the filesystem implementation decide which flags to set
+use crate::metrics::Metrics;
+use crate::stats::SessionStats;
+use crate::tasks::TaskHandle;
+use crate::observer::{Observer, ReplySummary, RequestSummary};
+use crate::pidns::PidNamespace;
+use crate::resend::{self, Received, ReplyCache};
+use crate::timeout::Sleep;
+use crate::reply::{Reply, ReplyRaw, ReplyResult, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
+use crate::reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory, ReplyXattr, ReplySender, ReplyTo};
 #[cfg(target_os = "macos")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
-// TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
+use crate::reply::ReplyXTimes;
+use crate::channel::Transport;
+use crate::session::{ConnectionInfo, Session, Unimplemented};
+use crate::{DirEntry, DirOffset, Errno, FileType, Filesystem, InitFlags, OpenFlags};
+#[cfg(not(target_os = "macos"))]
+use crate::{DirEntryPlus, Entry, Ioctl, IoctlFlags};
+#[cfg(not(target_os = "macos"))]
+use crate::reply::{ReplyIoctl, ReplyLseek, ReplyStatx, ReplyDirectoryPlus};
 
 /// Request data structure
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Request {
-    /// Channel sender for sending the reply
-    ch: ChannelSender,
+    /// Sender for sending the reply (the kernel channel, or a mock channel in tests)
+    ch: Arc<dyn ReplySender + Sync>,
     /// Parsed request
     request: ll::Request,
+    /// Connection parameters of the session
+    conn: Arc<RwLock<ConnectionInfo>>,
+    /// Interruption state, set by the session while dispatching
+    interrupt: Arc<Interrupt>,
+    /// Operations the filesystem doesn't implement, shared with the session
+    unimplemented: Arc<Unimplemented>,
+    /// Components of the session to tell about this request and its reply
+    hooks: Hooks,
+    /// Time the request was received
+    received: Instant,
+    /// Time the session gives up on the request, if it has a timeout
+    deadline: Option<Instant>,
+    /// Mapping of the ids of the mount to the ids of the filesystem
+    id_map: Option<Arc<IdMap>>,
+    /// Pid namespace the pid of the caller is in
+    pid_ns: Option<PidNamespace>,
+    /// Supplementary groups of the caller, once read
+    groups: OnceLock<Option<Vec<u32>>>,
+    /// Spawner of background tasks of the session
+    tasks: Option<TaskHandle>,
+}
+
+/// Components of a session that are told about requests and their replies
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Hooks {
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    pub(crate) json_log: Option<Arc<JsonLog>>,
+    pub(crate) observer: Option<(Arc<dyn Observer>, Arc<dyn Executor>)>,
+    pub(crate) stats: Arc<SessionStats>,
+    pub(crate) replies: Option<Arc<ReplyCache>>,
+}
+
+/// Bookkeeping of the session when a request is replied to
+#[derive(Debug)]
+pub(crate) struct Completion {
+    entry: LogEntry,
+    received: Instant,
+    hooks: Hooks,
+    summary: Option<Arc<RequestSummary>>,
+}
+
+impl Completion {
+    /// Count, log and observe the request, given its serialized reply. Returns the replies to
+    /// resent copies of the request that wait for it.
+    pub(crate) fn replied(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        // The reply header is always sent in one piece, followed by the reply data
+        let errno = data.first().and_then(|header| header.get(4..8))
+            .map_or(0, |error| -i32::from_ne_bytes([error[0], error[1], error[2], error[3]]));
+        let size = data.iter().map(|d| d.len()).sum::<usize>().saturating_sub(mem::size_of::<fuse_out_header>());
+        let latency = self.received.elapsed();
+        self.hooks.stats.reply(size + mem::size_of::<fuse_out_header>(), errno);
+        let resent = match self.hooks.replies {
+            Some(ref replies) if resend::is_applied_once(self.entry.opcode) => replies.insert(self.entry.unique, data),
+            _ => Vec::new(),
+        };
+        if let Some(ref metrics) = self.hooks.metrics {
+            metrics.reply(self.entry.opcode, errno != 0, latency);
+        }
+        if let Some(ref json_log) = self.hooks.json_log {
+            json_log.log(&LogEntry { size, latency, errno, ..self.entry });
+        }
+        if let (Some((observer, executor)), Some(summary)) = (&self.hooks.observer, &self.summary) {
+            let (observer, summary) = (observer.clone(), summary.clone());
+            let reply = ReplySummary { error: if errno == 0 { None } else { Some(Errno::from_raw(errno)) }, size, latency };
+            executor.spawn(Box::pin(async move { observer.observe(&summary, &reply).await }));
+        }
+        resent
+    }
 }
 
 impl Request {
     /// Create a new request from the given data
-    pub fn new(ch: ChannelSender, data: &[u8]) -> Option<Request> {
+    pub fn new(ch: Arc<dyn ReplySender + Sync>, data: &[u8]) -> Option<Request> {
         let request = match ll::Request::try_from(data) {
             Ok(request) => request,
             Err(err) => {
@@ -53,29 +141,153 @@ impl Request {
             }
         };
 
-        Some(Self {ch, request})
+        Some(Self {ch, request, conn: Arc::default(), interrupt: Arc::default(), unimplemented: Arc::default(), hooks: Hooks::default(), received: Instant::now(), deadline: None, id_map: None, pid_ns: None, groups: OnceLock::new(), tasks: None})
     }
 
     /// Dispatch request to the given filesystem.
     /// This calls the appropriate filesystem operation method for the
     /// request and sends back the returned reply to the kernel
-    pub async fn dispatch<FS: Filesystem + Send + Sync + 'static>(self, se: Arc<Session<FS>>) {
-        let req = &self;
-        debug!("{}", req.request);
+    pub async fn dispatch<FS: Filesystem + Send + Sync + 'static, T: Transport>(mut self, se: Arc<Session<FS, T>>) {
+        self.conn = se.conn.clone();
+        self.unimplemented = se.unimplemented.clone();
+        self.hooks = se.hooks();
+        self.id_map = se.id_map.clone();
+        self.pid_ns = se.pid_ns;
+        self.tasks = Some(se.task_handle());
+        self.deadline = se.timeouts.get(self.request.opcode()).map(|timeout| self.received + timeout);
+        if let Some(ref metrics) = self.hooks.metrics { metrics.request(self.request.opcode()); }
+        self.hooks.stats.request(self.request.header().len as usize);
+        let _in_flight = self.hooks.stats.clone().dispatch();
+        // A resent request that is still being dispatched or was replied to before gets the
+        // same reply as the original, so that it isn't applied twice
+        if let Some(ref replies) = self.hooks.replies {
+            if resend::is_applied_once(self.request.opcode()) {
+                match replies.receive(self.request.unique()) {
+                    Received::New => {},
+                    Received::InFlight => {
+                        debug!("{} (resent, still being dispatched)", self.request.redacted(se.redaction));
+                        return;
+                    }
+                    Received::Replied(reply) => {
+                        debug!("{} (resent, replied before)", self.request.redacted(se.redaction));
+                        self.ch.send(&[&reply]);
+                        return;
+                    }
+                }
+            }
+        }
+        // The user may have to wait for its turn, or may not be admitted at all
+        let _admitted = match se.admission {
+            Some(ref admission) => match admission.admit(self.request.opcode(), self.request.uid()).await {
+                Ok(admitted) => admitted,
+                Err(err) => {
+                    debug!("{} (not admitted)", self.request.redacted(se.redaction));
+                    self.reply::<ReplyEmpty>().error(err);
+                    return;
+                }
+            },
+            None => None,
+        };
+        // The request can be interrupted until it is replied to
+        let (interrupt, _registered) = se.interrupts.register(self.request.unique(), self.received, &se.shutdown);
+        self.interrupt = interrupt;
+        debug!("{}", self.request.redacted(se.redaction));
+
+        // A panicking filesystem must neither take down the session nor leave the kernel
+        // request pending. Every operation creates its reply before calling the filesystem,
+        // so unwinding drops the reply, which replies with EIO. Likewise, giving up on the
+        // operation at its deadline drops the reply, which replies with ETIMEDOUT then.
+        let mut operation = Box::pin(self.dispatch_operation(&se));
+        let mut timeout = self.deadline.map(Sleep::until);
+        let result = future::poll_fn(|cx| {
+            match panic::catch_unwind(AssertUnwindSafe(|| operation.as_mut().poll(cx))) {
+                Ok(Poll::Ready(())) => Poll::Ready(Ok(true)),
+                Ok(Poll::Pending) => match timeout.as_mut().map(|timeout| Pin::new(timeout).poll(cx)) {
+                    Some(Poll::Ready(())) => Poll::Ready(Ok(false)),
+                    _ => Poll::Pending,
+                },
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        }).await;
+        drop(operation);
+        if let Ok(false) = result {
+            warn!("Timed out handling {}", self.request.redacted(se.redaction));
+        }
+        if let Err(payload) = result {
+            let message = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!("Filesystem panicked handling {}: {}", self.request.redacted(se.redaction), message);
+        }
+    }
+
+    /// Reply ENOSYS to this request if the filesystem doesn't implement its operation, or if
+    /// the session caches ENOSYS and the filesystem replied ENOSYS to the same operation before.
+    /// Returns true if the request was replied to and doesn't need to be dispatched.
+    pub(crate) fn reply_unimplemented<FS: Filesystem + Send + Sync + 'static, T: Transport>(&self, se: &Session<FS, T>) -> bool {
+        let opcode = self.request.opcode();
+        let cached = se.cache_enosys && se.unimplemented.contains(opcode);
+        if !cached && se.filesystem.operations().contains_opcode(opcode) {
+            return false;
+        }
+        debug!("{} (not implemented)", self.request.redacted(se.redaction));
+        let hooks = se.hooks();
+        if let Some(ref metrics) = hooks.metrics { metrics.request(opcode); }
+        hooks.stats.request(self.request.header().len as usize);
+        let reply: ReplyEmpty = Reply::new(self.request.unique(), self.reply_to().completion(Some(self.completion(hooks))));
+        reply.error(ENOSYS);
+        true
+    }
+
+    /// Call the filesystem operation method for the request and reply with its result
+    async fn dispatch_operation<FS: Filesystem + Send + Sync + 'static, T: Transport>(&self, se: &Session<FS, T>) {
+        let req = self;
 
         match req.request.operation() {
             // Filesystem initialization
-            ll::Operation::Init { arg } => {
+            ll::Operation::Init { arg, flags2 } => {
                 let reply: ReplyRaw<fuse_init_out> = req.reply();
-                // We don't support ABI versions before 7.6
-                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
+                // We don't support ABI versions before 7.6. Kernel drivers speaking the latest
+                // ABI send its arguments since 7.12, older ones send shorter arguments.
+                #[cfg(target_os = "macos")]
+                let min_minor = 6;
+                #[cfg(not(target_os = "macos"))]
+                let min_minor = 12;
+                if arg.major < 7 || (arg.major == 7 && arg.minor < min_minor) {
                     error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
+                    se.proto_major.store(arg.major, Ordering::Relaxed);
+                    se.proto_minor.store(arg.minor, Ordering::Relaxed);
+                    se.unsupported_abi.store(true, Ordering::Release);
                     reply.error(EPROTO);
                     return;
                 }
+                // Passthrough needs the extended init reply of kernels sending flags2
+                #[cfg(not(target_os = "macos"))]
+                let passthrough = se.passthrough && flags2 & FUSE_PASSTHROUGH != 0;
+                #[cfg(target_os = "macos")]
+                let passthrough = { let _ = flags2; false };
+                #[cfg(not(target_os = "macos"))]
+                let create_supp_group = se.create_supp_group && flags2 & FUSE_CREATE_SUPP_GROUP != 0;
+                #[cfg(target_os = "macos")]
+                let create_supp_group = false;
+                // Enable the wanted features reported as capable, as far as the filesystem
+                // implements their operations
+                let want = InitFlags::from_bits(arg.flags) & se.filesystem.operations().init_flags(se.init_flags);
                 // Remember ABI version supported by kernel
                 se.proto_major.store(arg.major, Ordering::Relaxed);
                 se.proto_minor.store(arg.minor, Ordering::Relaxed);
+                {
+                    let mut conn = se.conn.write().unwrap();
+                    conn.proto_major = arg.major;
+                    conn.proto_minor = arg.minor;
+                    conn.capable = InitFlags::from_bits(arg.flags);
+                    conn.want = want;
+                    conn.max_readahead = arg.max_readahead;
+                    conn.max_write = se.max_write();
+                    conn.passthrough = passthrough;
+                    conn.capable2 = flags2;
+                    conn.create_supp_group = create_supp_group;
+                }
 
                 // Call filesystem init method and give it a chance to return an error
                 let res = se.filesystem.init(req).await;
@@ -83,51 +295,98 @@ impl Request {
                     reply.error(err);
                     return;
                 }
-                // Reply with our desired version and settings. If the kernel supports a
-                // larger major version, it'll re-send a matching init message. If it
-                // supports only lower major versions, we replied with an error above.
+                // Reply with our desired version and settings, as adjusted by the filesystem's
+                // init. If the kernel supports a larger major version, it'll re-send a matching
+                // init message. If it supports only lower major versions, we replied with an
+                // error above.
+                let conn = *se.conn.read().unwrap();
                 let init = fuse_init_out {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
-                    max_readahead: arg.max_readahead,       // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS,          // use features given in INIT_FLAGS and reported as capable
+                    max_readahead: conn.max_readahead,      // accept any readahead size by default
+                    flags: conn.want.bits(),                // use features wanted by the session and reported as capable
+                    #[cfg(target_os = "macos")]
                     unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32,       // use a max write size that fits into the session's buffer
+                    #[cfg(not(target_os = "macos"))]
+                    max_background: 0,                      // let the kernel use its defaults
+                    #[cfg(not(target_os = "macos"))]
+                    congestion_threshold: 0,
+                    max_write: conn.max_write,              // use a max write size that fits into the session's buffer
                 };
                 debug!("INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}", init.major, init.minor, init.flags, init.max_readahead, init.max_write);
                 se.initialized.store(true, Ordering::Relaxed);
+                #[cfg(not(target_os = "macos"))]
+                if passthrough || create_supp_group {
+                    debug!("INIT response: passthrough {}, supplementary group {}", passthrough, create_supp_group);
+                    let want2 = if passthrough { FUSE_PASSTHROUGH } else { 0 } | if create_supp_group { FUSE_CREATE_SUPP_GROUP } else { 0 };
+                    reply.cast().ok(&fuse_init_out_ext {
+                        major: init.major,
+                        minor: init.minor,
+                        max_readahead: init.max_readahead,
+                        flags: init.flags | FUSE_INIT_EXT,
+                        max_background: 0,
+                        congestion_threshold: 0,
+                        max_write: init.max_write,
+                        time_gran: 0,
+                        max_pages: 0,
+                        map_alignment: 0,
+                        flags2: want2,
+                        max_stack_depth: if passthrough { 1 } else { 0 },  // backing files may not be on a FUSE filesystem
+                        unused: [0; 6],
+                    });
+                    se.lifecycle.mounted(se.mountpoint());
+                    return;
+                }
                 reply.ok(&init);
+                se.lifecycle.mounted(se.mountpoint());
             }
             // Any operation is invalid before initialization
             _ if !se.initialized.load(Ordering::Relaxed) => {
-                warn!("Ignoring FUSE operation before init: {}", req.request);
+                warn!("Ignoring FUSE operation before init: {}", req.request.redacted(se.redaction));
                 req.reply::<ReplyEmpty>().error(EIO);
             }
             // Filesystem destroyed
             ll::Operation::Destroy => {
+                let reply = req.reply::<ReplyEmpty>();
                 se.filesystem.destroy(req).await;
+                // Requests still being dispatched can give up, their replies won't be read
+                se.shutdown.cancel();
                 se.destroyed.store(true, Ordering::Relaxed);
-                req.reply::<ReplyEmpty>().ok();
+                reply.ok();
             }
             // Any operation is invalid after destroy
             _ if se.destroyed.load(Ordering::Relaxed) => {
-                warn!("Ignoring FUSE operation after destroy: {}", req.request);
+                warn!("Ignoring FUSE operation after destroy: {}", req.request.redacted(se.redaction));
                 req.reply::<ReplyEmpty>().error(EIO);
             }
 
-            ll::Operation::Interrupt { .. } => {
-                // TODO: handle FUSE_INTERRUPT
-                req.reply::<ReplyEmpty>().error(ENOSYS);
+            ll::Operation::Interrupt { arg } => {
+                // Interrupts are not replied to. If the interrupted request isn't known (yet),
+                // the kernel resends the interrupt as long as the request is pending.
+                if !se.interrupts.interrupt(arg.unique) {
+                    req.reply::<ReplyEmpty>().error(EAGAIN);
+                }
             }
 
             ll::Operation::Lookup { name } => {
-                se.filesystem.lookup(req, req.request.nodeid(), &name, req.reply()).await;
+                let reply = req.reply::<ReplyEntry>().default_ttl(se.entry_ttl, se.attr_ttl);
+                let result = se.map_ids(se.filesystem.lookup(req, req.request.nodeid(), &name).await);
+                if se.dot_entries { se.parents.looked_up(req.request.nodeid(), name, &result); }
+                reply.result(result);
             }
             ll::Operation::Forget { arg } => {
+                if se.dot_entries { se.parents.forgotten(req.request.nodeid()); }
                 se.filesystem.forget(req, req.request.nodeid(), arg.nlookup).await; // no reply
             }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.chunks_exact(mem::size_of::<fuse_forget_one>()).filter_map(|node| fuse_forget_one::read_from_bytes(node).ok()) {
+                    if se.dot_entries { se.parents.forgotten(node.nodeid); }
+                    se.filesystem.forget(req, node.nodeid, node.nlookup).await; // no reply
+                }
+            }
             ll::Operation::GetAttr => {
-                se.filesystem.getattr(req, req.request.nodeid(), req.reply()).await;
+                req.reply::<ReplyAttr>().default_ttl(se.attr_ttl).result(se.map_ids(se.filesystem.getattr(req, req.request.nodeid()).await));
             }
             ll::Operation::SetAttr { arg } => {
                 let mode = match arg.valid & FATTR_MODE {
@@ -136,11 +395,11 @@ impl Request {
                 };
                 let uid = match arg.valid & FATTR_UID {
                     0 => None,
-                    _ => Some(arg.uid),
+                    _ => Some(se.id_map.as_ref().map_or(arg.uid, |id_map| id_map.uid_inside(arg.uid))),
                 };
                 let gid = match arg.valid & FATTR_GID {
                     0 => None,
-                    _ => Some(arg.gid),
+                    _ => Some(se.id_map.as_ref().map_or(arg.gid, |id_map| id_map.gid_inside(arg.gid))),
                 };
                 let size = match arg.valid & FATTR_SIZE {
                     0 => None,
@@ -148,11 +407,11 @@ impl Request {
                 };
                 let atime = match arg.valid & FATTR_ATIME {
                     0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.atime, arg.atimensec)),
+                    _ => Some(system_time(arg.atime as i64, arg.atimensec as i64)),
                 };
                 let mtime = match arg.valid & FATTR_MTIME {
                     0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.mtime, arg.mtimensec)),
+                    _ => Some(system_time(arg.mtime as i64, arg.mtimensec as i64)),
                 };
                 let fh = match arg.valid & FATTR_FH {
                     0 => None,
@@ -163,15 +422,15 @@ impl Request {
                 fn get_macos_setattr(arg: &fuse_setattr_in) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>, Option<u32>) {
                     let crtime = match arg.valid & FATTR_CRTIME {
                         0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
+                        _ => Some(system_time(arg.crtime as i64, arg.crtimensec as i64)),
                     };
                     let chgtime = match arg.valid & FATTR_CHGTIME {
                         0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
+                        _ => Some(system_time(arg.chgtime as i64, arg.chgtimensec as i64)),
                     };
                     let bkuptime = match arg.valid & FATTR_BKUPTIME {
                         0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
+                        _ => Some(system_time(arg.bkuptime as i64, arg.bkuptimensec as i64)),
                     };
                     let flags = match arg.valid & FATTR_FLAGS {
                         0 => None,
@@ -184,78 +443,106 @@ impl Request {
                 fn get_macos_setattr(_arg: &fuse_setattr_in) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>, Option<u32>) {
                     (None, None, None, None)
                 }
-                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(req, req.request.nodeid(), mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, req.reply()).await;
+                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(&arg);
+                req.reply::<ReplyAttr>().default_ttl(se.attr_ttl).result(se.map_ids(se.filesystem.setattr(req, req.request.nodeid(), mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await));
             }
             ll::Operation::ReadLink => {
-                se.filesystem.readlink(req, req.request.nodeid(), req.reply()).await;
+                req.reply::<ReplyData>().result(se.filesystem.readlink(req, req.request.nodeid()).await);
             }
             ll::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(req, req.request.nodeid(), &name, arg.mode, arg.rdev, req.reply()).await;
+                req.reply::<ReplyEntry>().default_ttl(se.entry_ttl, se.attr_ttl).result(se.map_ids(se.filesystem.mknod(req, req.request.nodeid(), &name, arg.mode, arg.rdev).await));
             }
             ll::Operation::MkDir { arg, name } => {
-                se.filesystem.mkdir(req, req.request.nodeid(), &name, arg.mode, req.reply()).await;
+                let reply = req.reply::<ReplyEntry>().default_ttl(se.entry_ttl, se.attr_ttl);
+                let result = se.map_ids(se.filesystem.mkdir(req, req.request.nodeid(), &name, arg.mode).await);
+                if se.dot_entries { se.parents.looked_up(req.request.nodeid(), name, &result); }
+                reply.result(result);
             }
             ll::Operation::Unlink { name } => {
-                se.filesystem.unlink(req, req.request.nodeid(), &name, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.unlink(req, req.request.nodeid(), &name).await);
             }
             ll::Operation::RmDir { name } => {
-                se.filesystem.rmdir(req, req.request.nodeid(), &name, req.reply()).await;
+                let reply = req.reply::<ReplyEmpty>();
+                let result = se.filesystem.rmdir(req, req.request.nodeid(), &name).await;
+                if se.dot_entries && result.is_ok() { se.parents.removed(req.request.nodeid(), name); }
+                reply.result(result);
             }
             ll::Operation::SymLink { name, link } => {
-                se.filesystem.symlink(req, req.request.nodeid(), &name, &Path::new(link), req.reply()).await;
+                req.reply::<ReplyEntry>().default_ttl(se.entry_ttl, se.attr_ttl).result(se.map_ids(se.filesystem.symlink(req, req.request.nodeid(), &name, &Path::new(link)).await));
             }
             ll::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(req, req.request.nodeid(), &name, arg.newdir, &newname, req.reply()).await;
+                let reply = req.reply::<ReplyEmpty>();
+                let result = se.filesystem.rename(req, req.request.nodeid(), &name, arg.newdir, &newname).await;
+                if se.dot_entries && result.is_ok() { se.parents.renamed(req.request.nodeid(), name, arg.newdir, newname); }
+                reply.result(result);
             }
             ll::Operation::Link { arg, name } => {
-                se.filesystem.link(req, arg.oldnodeid, req.request.nodeid(), &name, req.reply()).await;
+                req.reply::<ReplyEntry>().default_ttl(se.entry_ttl, se.attr_ttl).result(se.map_ids(se.filesystem.link(req, arg.oldnodeid, req.request.nodeid(), &name).await));
             }
             ll::Operation::Open { arg } => {
-                se.filesystem.open(req, req.request.nodeid(), arg.flags, req.reply()).await;
+                let reply = req.reply::<ReplyOpen>().proto_minor(se.proto_minor.load(Ordering::Relaxed));
+                let direct_io = se.direct_io_on_o_direct && direct::is_direct(arg.flags);
+                reply.result(se.filesystem.open(req, req.request.nodeid(), arg.flags).await.map(|mut opened| {
+                    if direct_io { opened.flags.insert(OpenFlags::DIRECT_IO); }
+                    opened
+                }));
             }
             ll::Operation::Read { arg } => {
-                se.filesystem.read(req, req.request.nodeid(), arg.fh, arg.offset as i64, arg.size, req.reply()).await;
+                // Never ask for more than the mount allows, nor reply more than the kernel asked for
+                let size = arg.size.min(se.conn.read().unwrap().max_read);
+                req.reply::<ReplyData>().max_size(size as usize).result(se.filesystem.read(req, req.request.nodeid(), arg.fh, arg.offset as i64, size).await);
             }
             ll::Operation::Write { arg, data } => {
                 assert!(data.len() == arg.size as usize);
-                se.filesystem.write(req, req.request.nodeid(), arg.fh, arg.offset as i64, data, arg.write_flags, req.reply()).await;
+                req.reply::<ReplyWrite>().result(se.filesystem.write(req, req.request.nodeid(), arg.fh, arg.offset as i64, data, arg.write_flags).await);
             }
             ll::Operation::Flush { arg } => {
-                se.filesystem.flush(req, req.request.nodeid(), arg.fh, arg.lock_owner, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.flush(req, req.request.nodeid(), arg.fh, arg.lock_owner).await);
             }
             ll::Operation::Release { arg } => {
                 let flush = match arg.release_flags & FUSE_RELEASE_FLUSH {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem.release(req, req.request.nodeid(), arg.fh, arg.flags, arg.lock_owner, flush, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.release(req, req.request.nodeid(), arg.fh, arg.flags, arg.lock_owner, flush).await);
             }
             ll::Operation::FSync { arg } => {
                 let datasync = match arg.fsync_flags & 1 {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem.fsync(req, req.request.nodeid(), arg.fh, datasync, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.fsync(req, req.request.nodeid(), arg.fh, datasync).await);
             }
             ll::Operation::OpenDir { arg } => {
-                se.filesystem.opendir(req, req.request.nodeid(), arg.flags, req.reply()).await;
+                req.reply::<ReplyOpen>().proto_minor(se.proto_minor.load(Ordering::Relaxed)).result(se.filesystem.opendir(req, req.request.nodeid(), arg.flags).await);
             }
             ll::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(req, req.request.nodeid(), arg.fh, arg.offset as i64, ReplyDirectory::new(req.request.unique(), req.ch, arg.size as usize)).await;
+                let mut reply = ReplyDirectory::new(req.request.unique(), req.reply_to(), arg.size as usize);
+                if !se.dot_entries {
+                    reply.result(se.filesystem.readdir(req, req.request.nodeid(), arg.fh, DirOffset::from_raw(arg.offset)).await);
+                    return;
+                }
+                // `.` and `..` come first at raw offsets 1 and 2, the filesystem's offsets follow them
+                if arg.offset < 1 { reply.add(req.request.nodeid(), DirOffset::from_raw(1), FileType::Directory, "."); }
+                if arg.offset < 2 { reply.add(se.parents.get(req.request.nodeid()), DirOffset::from_raw(2), FileType::Directory, ".."); }
+                let offset = DirOffset::from_raw(arg.offset.saturating_sub(2));
+                let result = se.filesystem.readdir(req, req.request.nodeid(), arg.fh, offset).await;
+                reply.result(result.map(|entries| entries.into_iter().map(|entry| {
+                    DirEntry { offset: DirOffset::from_raw(entry.offset.raw().wrapping_add(2)), ..entry }
+                }).collect()));
             }
             ll::Operation::ReleaseDir { arg } => {
-                se.filesystem.releasedir(req, req.request.nodeid(), arg.fh, arg.flags, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.releasedir(req, req.request.nodeid(), arg.fh, arg.flags).await);
             }
             ll::Operation::FSyncDir { arg } => {
                 let datasync = match arg.fsync_flags & 1 {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem.fsyncdir(req, req.request.nodeid(), arg.fh, datasync, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.fsyncdir(req, req.request.nodeid(), arg.fh, datasync).await);
             }
             ll::Operation::StatFs => {
-                se.filesystem.statfs(req, req.request.nodeid(), req.reply()).await;
+                req.reply::<ReplyStatfs>().result(se.filesystem.statfs(req, req.request.nodeid()).await);
             }
             ll::Operation::SetXAttr { arg, name, value } => {
                 assert!(value.len() == arg.size as usize);
@@ -265,47 +552,162 @@ impl Request {
                 #[cfg(not(target_os = "macos"))]
                 #[inline]
                 fn get_position (_arg: &fuse_setxattr_in) -> u32 { 0 }
-                se.filesystem.setxattr(req, req.request.nodeid(), name, value, arg.flags, get_position(arg), req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.setxattr(req, req.request.nodeid(), name, value, arg.flags, get_position(&arg)).await);
             }
             ll::Operation::GetXAttr { arg, name } => {
-                se.filesystem.getxattr(req, req.request.nodeid(), name, arg.size, req.reply()).await;
+                req.reply::<ReplyXattr>().requested(arg.size).result(se.filesystem.getxattr(req, req.request.nodeid(), name, arg.size).await);
             }
             ll::Operation::ListXAttr { arg } => {
-                se.filesystem.listxattr(req, req.request.nodeid(), arg.size, req.reply()).await;
+                req.reply::<ReplyXattr>().requested(arg.size).result(se.filesystem.listxattr(req, req.request.nodeid(), arg.size).await);
             }
             ll::Operation::RemoveXAttr { name } => {
-                se.filesystem.removexattr(req, req.request.nodeid(), name, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.removexattr(req, req.request.nodeid(), name).await);
             }
             ll::Operation::Access { arg } => {
-                se.filesystem.access(req, req.request.nodeid(), arg.mask, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.access(req, req.request.nodeid(), arg.mask).await);
             }
             ll::Operation::Create { arg, name } => {
-                se.filesystem.create(req, req.request.nodeid(), &name, arg.mode, arg.flags, req.reply()).await;
+                let reply = req.reply::<ReplyCreate>().default_ttl(se.entry_ttl, se.attr_ttl).proto_minor(se.proto_minor.load(Ordering::Relaxed));
+                let direct_io = se.direct_io_on_o_direct && direct::is_direct(arg.flags);
+                reply.result(se.map_ids(se.filesystem.create(req, req.request.nodeid(), &name, arg.mode, arg.flags).await).map(|mut created| {
+                    if direct_io { created.flags.insert(OpenFlags::DIRECT_IO); }
+                    created
+                }));
             }
             ll::Operation::GetLk { arg } => {
-                se.filesystem.getlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, req.reply()).await;
+                req.reply::<ReplyLock>().result(se.filesystem.getlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid).await);
             }
             ll::Operation::SetLk { arg } => {
-                se.filesystem.setlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, false, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.setlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, false).await);
             }
             ll::Operation::SetLkW { arg } => {
-                se.filesystem.setlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, true, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.setlk(req, req.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, true).await);
             }
             ll::Operation::BMap { arg } => {
-                se.filesystem.bmap(req, req.request.nodeid(), arg.blocksize, arg.block, req.reply()).await;
+                req.reply::<ReplyBmap>().result(se.filesystem.bmap(req, req.request.nodeid(), arg.blocksize, arg.block).await);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::IoCtl { arg, data } => {
+                let reply = req.reply::<ReplyIoctl>();
+                let flags = IoctlFlags::from_bits(arg.flags);
+                let result = se.filesystem.ioctl(req, req.request.nodeid(), arg.fh, flags, arg.cmd, arg.arg, data, arg.out_size).await;
+                reply.result(match result {
+                    // The kernel only retries unrestricted ioctls and fails others with EIO
+                    Ok(Ioctl::Retry(..)) if !flags.contains(IoctlFlags::UNRESTRICTED) => {
+                        error!("Filesystem asked to retry a restricted ioctl: {}", req.request.redacted(se.redaction));
+                        Err(Errno::EIO)
+                    }
+                    result => result,
+                });
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::FAllocate { arg } => {
+                req.reply::<ReplyEmpty>().result(se.filesystem.fallocate(req, req.request.nodeid(), arg.fh, arg.offset as i64, arg.length as i64, arg.mode as i32).await);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::ReadDirPlus { arg } => {
+                let mut reply = ReplyDirectoryPlus::new(req.request.unique(), req.reply_to(), arg.size as usize).default_ttl(se.entry_ttl, se.attr_ttl);
+                let mut offset = arg.offset;
+                if se.dot_entries {
+                    // Like readdir, with negative entries for `.` and `..` that the kernel doesn't look up
+                    let dot = |ino, offset, name: &str| DirEntryPlus {
+                        dirent: DirEntry { ino, offset: DirOffset::from_raw(offset), kind: FileType::Directory, name: name.into() },
+                        entry: Entry::negative(None),
+                    };
+                    if arg.offset < 1 { reply.add_entry(&dot(req.request.nodeid(), 1, ".")); }
+                    if arg.offset < 2 { reply.add_entry(&dot(se.parents.get(req.request.nodeid()), 2, "..")); }
+                    offset = offset.saturating_sub(2);
+                }
+                let result = se.filesystem.readdirplus(req, req.request.nodeid(), arg.fh, DirOffset::from_raw(offset)).await
+                    .and_then(|entries| entries.into_iter().map(|entry| se.map_ids(Ok(entry))).collect::<Result<Vec<_>, _>>());
+                let mut entries = match result {
+                    Ok(entries) => entries,
+                    Err(err) => return reply.error(err),
+                };
+                if se.dot_entries {
+                    for entry in &mut entries {
+                        entry.dirent.offset = DirOffset::from_raw(entry.dirent.offset.raw().wrapping_add(2));
+                    }
+                }
+                // The kernel counts a lookup for every entry except `.`, `..` and negative entries
+                let counted = |entry: &DirEntryPlus| !entry.entry.is_negative() && !matches!(entry.dirent.name.as_bytes(), b"." | b"..");
+                let mut sent = 0;
+                while sent < entries.len() && !reply.add_entry(&entries[sent]) {
+                    sent += 1;
+                }
+                if se.dot_entries {
+                    for entry in entries[..sent].iter().filter(|entry| counted(entry)) {
+                        se.parents.looked_up(req.request.nodeid(), &entry.dirent.name, &Ok(entry.entry));
+                    }
+                }
+                reply.ok();
+                // Entries that didn't fit are not known to the kernel, it won't forget them
+                for entry in entries[sent..].iter().filter(|entry| counted(entry)) {
+                    se.filesystem.forget(req, entry.entry.attr.ino, 1).await;
+                }
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::Rename2 { arg, name, newname } => {
+                let reply = req.reply::<ReplyEmpty>();
+                let result = se.filesystem.rename2(req, req.request.nodeid(), name, arg.newdir, newname, arg.flags).await;
+                if se.dot_entries && result.is_ok() {
+                    match arg.flags & RENAME_EXCHANGE {
+                        0 => se.parents.renamed(req.request.nodeid(), name, arg.newdir, newname),
+                        _ => se.parents.exchanged(req.request.nodeid(), name, arg.newdir, newname),
+                    }
+                }
+                reply.result(result);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::LSeek { arg } => {
+                req.reply::<ReplyLseek>().result(se.filesystem.lseek(req, req.request.nodeid(), arg.fh, arg.offset as i64, arg.whence as i32).await);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::CopyFileRange { arg } => {
+                req.reply::<ReplyWrite>().result(se.filesystem.copy_file_range(req, req.request.nodeid(), arg.fh_in, arg.off_in as i64, arg.nodeid_out, arg.fh_out, arg.off_out as i64, arg.len, arg.flags).await);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::SyncFs => {
+                req.reply::<ReplyEmpty>().result(se.filesystem.syncfs(req, req.request.nodeid()).await);
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::TmpFile { arg, .. } => {
+                let reply = req.reply::<ReplyCreate>().default_ttl(se.entry_ttl, se.attr_ttl).proto_minor(se.proto_minor.load(Ordering::Relaxed));
+                let direct_io = se.direct_io_on_o_direct && direct::is_direct(arg.flags);
+                reply.result(se.map_ids(se.filesystem.tmpfile(req, req.request.nodeid(), arg.mode, arg.flags).await).map(|mut created| {
+                    if direct_io { created.flags.insert(OpenFlags::DIRECT_IO); }
+                    created
+                }));
+            }
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::StatX { arg } => {
+                let fh = match arg.getattr_flags & FUSE_GETATTR_FH {
+                    0 => None,
+                    _ => Some(arg.fh),
+                };
+                req.reply::<ReplyStatx>().default_ttl(se.attr_ttl).result(se.map_ids(se.filesystem.statx(req, req.request.nodeid(), fh, arg.sx_flags, arg.sx_mask).await));
+            }
+            // Polling needs wakeup notifications, which sessions don't send. The kernel driver
+            // treats files as always ready on ENOSYS.
+            #[cfg(not(target_os = "macos"))]
+            ll::Operation::Poll { .. } => {
+                req.reply::<ReplyEmpty>().error(ENOSYS);
             }
 
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName { name } => {
-                se.filesystem.setvolname(req, name, req.reply()).await;
+                req.reply::<ReplyEmpty>().result(se.filesystem.setvolname(req, name).await);
             }
             #[cfg(target_os = "macos")]
             ll::Operation::GetXTimes => {
-                se.filesystem.getxtimes(req, req.request.nodeid(), req.reply()).await;
+                req.reply::<ReplyXTimes>().result(se.filesystem.getxtimes(req, req.request.nodeid()).await);
             }
             #[cfg(target_os = "macos")]
             ll::Operation::Exchange { arg, oldname, newname } => {
-                se.filesystem.exchange(req, arg.olddir, &oldname, arg.newdir, &newname, arg.options, req.reply()).await;
+                let reply = req.reply::<ReplyEmpty>();
+                let result = se.filesystem.exchange(req, arg.olddir, &oldname, arg.newdir, &newname, arg.options).await;
+                if se.dot_entries && result.is_ok() { se.parents.exchanged(arg.olddir, oldname, arg.newdir, newname); }
+                reply.result(result);
             }
         }
     }
@@ -313,7 +715,30 @@ impl Request {
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.reply_to())
+    }
+
+    /// Create a sender for replying to this request
+    fn reply_to(&self) -> ReplyTo<Arc<dyn ReplySender + Sync>> {
+        let completion = self.completion(self.hooks.clone());
+        ReplyTo::new(self.ch.clone(), self.request.opcode(), self.unimplemented.clone()).completion(Some(completion)).deadline(self.deadline)
+    }
+
+    /// Create the bookkeeping for replying to this request
+    fn completion(&self, hooks: Hooks) -> Completion {
+        let entry = LogEntry {
+            unique: self.request.unique(),
+            opcode: self.request.opcode(),
+            ino: self.request.nodeid(),
+            uid: self.request.uid(),
+            gid: self.request.gid(),
+            pid: self.request.pid(),
+            size: 0,
+            latency: Duration::default(),
+            errno: 0,
+        };
+        let summary = hooks.observer.as_ref().map(|_| Arc::new(RequestSummary::new(&self.request)));
+        Completion { entry, received: self.received, hooks, summary }
     }
 
     /// Returns the unique identifier of this request
@@ -323,24 +748,615 @@ impl Request {
         self.request.unique()
     }
 
-    /// Returns the uid of this request
+    /// Returns true if the kernel sent this request again, after it was sent to a daemon that
+    /// didn't reply to it (see `Notifier::resend`). The filesystem may have applied it before.
+    #[inline]
+    pub fn is_resend(&self) -> bool {
+        self.request.unique() & resend::UNIQUE_RESEND != 0
+    }
+
+    /// Returns the uid of this request (mapped to the ids of the filesystem on ID-mapped mounts)
     #[inline]
     #[allow(dead_code)]
     pub fn uid(&self) -> u32 {
-        self.request.uid()
+        self.id_map.as_ref().map_or(self.request.uid(), |id_map| id_map.uid_inside(self.request.uid()))
     }
 
-    /// Returns the gid of this request
+    /// Returns the gid of this request (mapped to the ids of the filesystem on ID-mapped mounts)
     #[inline]
     #[allow(dead_code)]
     pub fn gid(&self) -> u32 {
-        self.request.gid()
+        self.id_map.as_ref().map_or(self.request.gid(), |id_map| id_map.gid_inside(self.request.gid()))
     }
 
-    /// Returns the pid of this request
+    /// Returns the group of the parent directory on create, mkdir, mknod, symlink and tmpfile
+    /// requests, if the caller is a member of it only through a supplementary group and the
+    /// session negotiated `ConnectionInfo::create_supp_group` (mapped to the ids of the
+    /// filesystem on ID-mapped mounts). Filesystems creating inodes with the credentials of the
+    /// caller add it to their groups, so that inodes in setgid directories get the group of the
+    /// directory and keep their setgid bit like POSIX requires.
+    pub fn supp_group(&self) -> Option<u32> {
+        #[cfg(not(target_os = "macos"))]
+        let group = self.request.supp_groups().first().copied();
+        #[cfg(target_os = "macos")]
+        let group = None;
+        group.map(|gid| self.id_map.as_ref().map_or(gid, |id_map| id_map.gid_inside(gid)))
+    }
+
+    /// Returns the pid of this request, in the pid namespace of the process that opened the
+    /// channel to the kernel driver. It's 0 if the calling process isn't visible there.
     #[inline]
     #[allow(dead_code)]
     pub fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns the pid of this request as seen in /proc of the daemon, translated from the pid
+    /// namespace of the session if the daemon is in another one (see `PidNamespace::translate`).
+    /// Returns none if the calling process isn't visible or its pid can't be translated, so that
+    /// per-process policies don't apply to the wrong process.
+    pub fn local_pid(&self) -> Option<u32> {
+        match self.pid_ns {
+            Some(ref pid_ns) => pid_ns.translate(self.request.pid()),
+            None => Some(self.request.pid()).filter(|&pid| pid != 0),
+        }
+    }
+
+    /// Returns the supplementary groups of the calling process (mapped to the ids of the
+    /// filesystem on ID-mapped mounts), e.g. for checking permissions in userspace. They are
+    /// read from /proc once per request. Returns none if the process isn't visible, exited or
+    /// changed its user or group since it made the request, and on systems without /proc.
+    pub fn supplementary_groups(&self) -> Option<&[u32]> {
+        self.groups.get_or_init(|| {
+            let groups = groups::supplementary_groups(self.local_pid()?, self.request.uid(), self.request.gid())?;
+            Some(groups.into_iter().map(|gid| self.id_map.as_ref().map_or(gid, |id_map| id_map.gid_inside(gid))).collect())
+        }).as_deref()
+    }
+
+    /// Returns the connection parameters negotiated with the kernel driver
+    pub fn connection(&self) -> ConnectionInfo {
+        *self.conn.read().unwrap()
+    }
+
+    /// Enable the given capability flags instead of the ones the session negotiated, as far as
+    /// the kernel supports them (`ConnectionInfo::capable`). Flags without a constant in
+    /// `InitFlags` can be enabled with `InitFlags::from_bits`. Only has an effect in
+    /// `Filesystem::init`.
+    pub fn set_want(&self, want: InitFlags) {
+        if let Some(mut conn) = self.init_connection() {
+            conn.want = want & conn.capable;
+        }
+    }
+
+    /// Lower the maximum readahead size the kernel uses from the size it proposed. Only has an
+    /// effect in `Filesystem::init`.
+    pub fn set_max_readahead(&self, max_readahead: u32) {
+        if let Some(mut conn) = self.init_connection() {
+            conn.max_readahead = conn.max_readahead.min(max_readahead);
+        }
+    }
+
+    /// Lower the maximum size of write requests from the size that fits into the session's
+    /// buffer (at least 4k). Only has an effect in `Filesystem::init`.
+    pub fn set_max_write(&self, max_write: u32) {
+        if let Some(mut conn) = self.init_connection() {
+            conn.max_write = conn.max_write.min(max_write.max(4096));
+        }
+    }
+
+    /// Returns the connection parameters for adjusting them, if this is the init request
+    fn init_connection(&self) -> Option<RwLockWriteGuard<'_, ConnectionInfo>> {
+        if !matches!(self.request.operation(), ll::Operation::Init { .. }) {
+            warn!("Ignoring change of init parameters outside of init: {}", self.request);
+            return None;
+        }
+        Some(self.conn.write().unwrap())
+    }
+
+    /// Returns true if the kernel interrupted this request because the process that triggered
+    /// it gave up on it (e.g. it was killed). The reply is ignored then.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupt.is_interrupted()
+    }
+
+    /// Waits until the kernel interrupts this request. Long running operations can select on
+    /// this to bail out early (usually with EINTR) if the calling process gave up.
+    pub async fn interrupted(&self) {
+        self.interrupt.wait().await
+    }
+
+    /// Returns the time the session gives up on this request and replies ETIMEDOUT, if the
+    /// operation has a timeout. Filesystems can pass it on to their own backend calls, so that
+    /// these give up at the same time.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the time left until the deadline of this request (zero if it has passed), if
+    /// the operation has a timeout
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the cancellation token of this request. It is cancelled when the kernel
+    /// interrupts the request, the filesystem is destroyed or the session ends. Long running
+    /// operations can check or wait for it (or hand it to tasks they spawn) to abandon work
+    /// whose reply nobody reads.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.interrupt.token().clone()
+    }
+
+    /// Returns a handle for spawning background tasks (e.g. at init) that are cancelled when
+    /// the filesystem is destroyed or the session ends (none if the request isn't dispatched
+    /// by a session)
+    pub fn tasks(&self) -> Option<TaskHandle> {
+        self.tasks.clone()
+    }
+
+    /// Returns true if the kernel checks file permissions itself before sending requests
+    /// (filesystem mounted with the `default_permissions` option)
+    pub fn default_permissions(&self) -> bool {
+        self.conn.read().unwrap().default_permissions
+    }
+
+    /// Returns the umask of the process that triggered this request, if the kernel sent it.
+    /// Kernels with protocol 7.12 or later send it with mkdir requests. Since this crate
+    /// doesn't negotiate FUSE_DONT_MASK, it has already been applied to the given mode.
+    pub fn umask(&self) -> Option<u32> {
+        match self.request.operation() {
+            ll::Operation::MkDir { arg, .. } if self.conn.read().unwrap().proto_minor >= 12 => Some(arg.umask()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    /// Create a getattr request of the root inode from the given user, for calling filesystem
+    /// methods in tests
+    pub(crate) fn test(uid: u32, gid: u32) -> Request {
+        let header = fuse_in_header { len: 40, opcode: fuse_opcode::FUSE_GETATTR as u32, unique: 1, nodeid: FUSE_ROOT_ID, uid, gid, pid: 1, total_extlen: 0, padding: 0 };
+        let data = unsafe { std::slice::from_raw_parts(&header as *const fuse_in_header as *const u8, std::mem::size_of::<fuse_in_header>()) };
+        Request::new(Arc::new(crate::channel::ChannelSender::null()), data).unwrap()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{OsStr, OsString};
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    #[cfg(not(target_os = "macos"))]
+    use std::time::{Duration, UNIX_EPOCH};
+    use async_trait::async_trait;
+    #[cfg(not(target_os = "macos"))]
+    use zerocopy::FromBytes;
+    use crate::{block_on, Attr, Created, Data, Entry, Errno, FileAttr, FileType, Filesystem, InitFlags, MockChannel, OpenFlags, Opened, Request, FUSE_ROOT_ID};
+    #[cfg(not(target_os = "macos"))]
+    use crate::{DirEntry, DirEntryPlus, DirOffset, Ioctl, IoctlFlags, IoctlIovec};
+
+    struct Panicking;
+
+    #[async_trait]
+    impl Filesystem for Panicking {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            panic!("lookup of {:?}", name);
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::Directory).build()))
+        }
+
+        async fn open(&self, _req: &Request, ino: u64, _flags: u32) -> Result<Opened, Errno> {
+            panic!("open of {}", ino);
+        }
+
+        async fn create(&self, _req: &Request, _parent: u64, name: &OsStr, _mode: u32, _flags: u32) -> Result<Created, Errno> {
+            panic!("create of {:?}", name);
+        }
+
+        async fn destroy(&self, _req: &Request) {
+            panic!("destroy");
+        }
+    }
+
+    struct Tuning;
+
+    #[async_trait]
+    impl Filesystem for Tuning {
+        async fn init(&self, req: &Request) -> Result<(), Errno> {
+            assert_eq!(req.connection().max_readahead, 128 * 1024);
+            req.set_want(req.connection().want | InitFlags::FLOCK_LOCKS | InitFlags::from_bits(1 << 25));
+            req.set_max_readahead(16 * 1024);
+            req.set_max_write(64 * 1024);
+            Ok(())
+        }
+
+        async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+            // Ignored after init
+            req.set_max_write(4096);
+            Ok(Attr::new(FileAttr::builder(ino, FileType::Directory).build()))
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    struct Device;
+
+    #[cfg(not(target_os = "macos"))]
+    #[async_trait]
+    impl Filesystem for Device {
+        async fn ioctl(&self, _req: &Request, _ino: u64, _fh: u64, flags: IoctlFlags, cmd: u32, _arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+            match cmd {
+                // Reverse the input
+                1 => Ok(Ioctl::Done(data.len() as i32, data.iter().rev().take(out_size as usize).copied().collect())),
+                // Fetch the buffer the argument points to
+                2 if data.is_empty() => Ok(Ioctl::Retry(vec![IoctlIovec { base: 0x1000, len: 8 }], vec![])),
+                2 => Ok(Ioctl::Done(flags.contains(IoctlFlags::DIR) as i32, data.to_vec())),
+                _ => Err(Errno::ENOTTY),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn ioctl() {
+        let ch = MockChannel::new(Device);
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.ioctl(FUSE_ROOT_ID, 0, IoctlFlags::empty(), 1, b"abc", 2)), Ok(Ioctl::Done(3, b"cb".to_vec())));
+        assert_eq!(block_on(ch.ioctl(FUSE_ROOT_ID, 0, IoctlFlags::DIR, 3, b"", 0)), Err(Errno::ENOTTY));
+        // Only unrestricted ioctls can be retried
+        assert_eq!(block_on(ch.ioctl(FUSE_ROOT_ID, 0, IoctlFlags::empty(), 2, b"", 0)), Err(Errno::EIO));
+        let unrestricted = IoctlFlags::UNRESTRICTED | IoctlFlags::DIR;
+        let retry = block_on(ch.ioctl(FUSE_ROOT_ID, 0, unrestricted, 2, b"", 0)).unwrap();
+        assert_eq!(retry, Ioctl::Retry(vec![IoctlIovec { base: 0x1000, len: 8 }], vec![]));
+        assert_eq!(block_on(ch.ioctl(FUSE_ROOT_ID, 0, unrestricted, 2, b"12345678", 0)), Ok(Ioctl::Done(1, b"12345678".to_vec())));
+    }
+
+    /// Filesystem that unlinks a file once
+    #[derive(Default)]
+    struct Unlinking {
+        unlinks: Mutex<u32>,
+    }
+
+    #[async_trait]
+    impl Filesystem for Unlinking {
+        async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            let mut unlinks = self.unlinks.lock().unwrap();
+            *unlinks += 1;
+            if *unlinks == 1 { Ok(()) } else { Err(Errno::ENOENT) }
+        }
+    }
+
+    /// Serialize an unlink request of the given unique id
+    fn unlink_request(unique: u64, name: &[u8]) -> Vec<u8> {
+        let len = 40 + name.len() as u32 + 1;
+        let opcode = fuse_abi::fuse_opcode::FUSE_UNLINK as u32;
+        [&len.to_ne_bytes()[..], &opcode.to_ne_bytes(), &unique.to_ne_bytes(), &FUSE_ROOT_ID.to_ne_bytes(), &[0; 16], name, &[0]].concat()
+    }
+
+    #[test]
+    fn resend() {
+        let ch = MockChannel::new(Unlinking::default()).resend_replies(4);
+        block_on(ch.init()).unwrap();
+        let reply = block_on(ch.send(&unlink_request(100, b"foo"))).unwrap();
+        assert_eq!(reply.result(), Ok(&[][..]));
+        // The resent request gets the reply sent before, without unlinking again
+        let resent = block_on(ch.send(&unlink_request(100 | 1 << 63, b"foo"))).unwrap();
+        assert_eq!((resent.unique(), resent.result()), (100 | 1 << 63, Ok(&[][..])));
+        assert_eq!(*ch.session().filesystem.unlinks.lock().unwrap(), 1);
+        // Resent requests that weren't replied to before are dispatched
+        let resent = block_on(ch.send(&unlink_request(101 | 1 << 63, b"foo"))).unwrap();
+        assert_eq!(resent.result(), Err(Errno::ENOENT));
+        assert_eq!(*ch.session().filesystem.unlinks.lock().unwrap(), 2);
+    }
+
+    /// Filesystem that unlinks a file once it's told to, telling when it started
+    struct SlowUnlinking {
+        started: Mutex<mpsc::Sender<()>>,
+        proceed: Mutex<mpsc::Receiver<()>>,
+    }
+
+    #[async_trait]
+    impl Filesystem for SlowUnlinking {
+        async fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.proceed.lock().unwrap().recv().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resend_in_flight() {
+        let ((started, wait), (proceed, wait_proceed)) = (mpsc::channel(), mpsc::channel());
+        let ch = MockChannel::new(SlowUnlinking { started: Mutex::new(started), proceed: Mutex::new(wait_proceed) }).resend_replies(4);
+        block_on(ch.init()).unwrap();
+        thread::scope(|scope| {
+            let original = scope.spawn(|| block_on(ch.send(&unlink_request(100, b"foo"))).unwrap());
+            wait.recv().unwrap();
+            // The resent request isn't dispatched again, it gets the reply of the original
+            assert!(block_on(ch.send(&unlink_request(100 | 1 << 63, b"foo"))).is_none());
+            proceed.send(()).unwrap();
+            assert_eq!(original.join().unwrap().result(), Ok(&[][..]));
+        });
+        let resent = ch.reply(100 | 1 << 63).unwrap();
+        assert_eq!((resent.unique(), resent.result()), (100 | 1 << 63, Ok(&[][..])));
+        assert!(wait.try_recv().is_err());
+    }
+
+    /// Filesystem that creates directories in the supplementary group of the caller
+    #[cfg(not(target_os = "macos"))]
+    struct Groups;
+
+    #[cfg(not(target_os = "macos"))]
+    #[async_trait]
+    impl Filesystem for Groups {
+        async fn mkdir(&self, req: &Request, _parent: u64, _name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+            let gid = req.supp_group().unwrap_or(req.gid());
+            let perm = 0o777 & !req.umask().unwrap_or(0) as u16;
+            Ok(Entry::new(FileAttr::builder(2, FileType::Directory).gid(gid).perm(perm).build(), 0))
+        }
+    }
+
+    /// Serialize a mkdir request of the given user followed by the given extension
+    #[cfg(not(target_os = "macos"))]
+    fn mkdir_request(uid: u32, gid: u32, extension: &[u32]) -> Vec<u8> {
+        let len = 40 + 8 + 8 + extension.len() as u32 * 4;
+        let opcode = fuse_abi::fuse_opcode::FUSE_MKDIR as u32;
+        let extlen = (extension.len() as u16 * 4).div_ceil(8);
+        let mut data = [&len.to_ne_bytes()[..], &opcode.to_ne_bytes(), &3u64.to_ne_bytes(), &FUSE_ROOT_ID.to_ne_bytes()].concat();
+        data.extend_from_slice(&[&uid.to_ne_bytes()[..], &gid.to_ne_bytes(), &1u32.to_ne_bytes(), &extlen.to_ne_bytes(), &[0; 2]].concat());
+        data.extend_from_slice(&[&0o755u32.to_ne_bytes()[..], &[0; 4], b"dir\0\0\0\0\0"].concat());
+        for value in extension {
+            data.extend_from_slice(&value.to_ne_bytes());
+        }
+        data
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn supp_group() {
+        let ch = MockChannel::new(Groups).create_supp_group(true);
+        let flags = InitFlags::ASYNC_READ.bits() | fuse_abi::consts::FUSE_INIT_EXT;
+        let mut init = [fuse_abi::FUSE_KERNEL_VERSION, fuse_abi::FUSE_KERNEL_MINOR_VERSION, 128 * 1024, flags, fuse_abi::consts::FUSE_CREATE_SUPP_GROUP].iter()
+            .flat_map(|value| value.to_ne_bytes()).collect::<Vec<u8>>();
+        init.extend_from_slice(&[0; 11 * 4]);
+        let reply = block_on(ch.request(fuse_abi::fuse_opcode::FUSE_INIT as u32, 0, &init)).unwrap();
+        let (out, _) = fuse_abi::fuse_init_out_ext::read_from_prefix(reply.result().unwrap()).unwrap();
+        // Without passthrough, there are no backing files to stack on
+        assert_eq!((out.flags2, out.max_stack_depth), (fuse_abi::consts::FUSE_CREATE_SUPP_GROUP, 0));
+        assert!(ch.session().connection().create_supp_group);
+        let gid = |data: &[u8]| {
+            let reply = block_on(ch.send(data)).unwrap();
+            let (out, _) = fuse_abi::fuse_entry_out::read_from_prefix(reply.result().unwrap()).unwrap();
+            out.attr.gid
+        };
+        // The group of the parent directory follows the arguments in an extension
+        assert_eq!(gid(&mkdir_request(1000, 1000, &[16, fuse_abi::consts::FUSE_EXT_GROUPS, 1, 100])), 100);
+        // Without it, the directory gets the group of the caller
+        assert_eq!(gid(&mkdir_request(1000, 1000, &[])), 1000);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn umask() {
+        let mode = |ch: &MockChannel<Groups>| {
+            let mut data = mkdir_request(1000, 1000, &[]);
+            data[44..48].copy_from_slice(&0o027u32.to_ne_bytes());
+            let reply = block_on(ch.send(&data)).unwrap();
+            let (out, _) = fuse_abi::fuse_entry_out::read_from_prefix(reply.result().unwrap()).unwrap();
+            out.attr.mode & 0o777
+        };
+        // Kernels speaking 7.12 or later send the umask
+        let ch = MockChannel::new(Groups);
+        let init: Vec<u8> = [7u32, 12, 128 * 1024, 0].iter().flat_map(|value| value.to_ne_bytes()).collect();
+        block_on(ch.request(fuse_abi::fuse_opcode::FUSE_INIT as u32, 0, &init)).unwrap().result().unwrap();
+        assert_eq!(mode(&ch), 0o750);
+        // Older kernels send shorter arguments, without the umask
+        let ch = MockChannel::new(Groups);
+        let init: Vec<u8> = [7u32, 11, 128 * 1024, 0].iter().flat_map(|value| value.to_ne_bytes()).collect();
+        let reply = block_on(ch.request(fuse_abi::fuse_opcode::FUSE_INIT as u32, 0, &init)).unwrap();
+        assert_eq!(reply.result().unwrap_err(), Errno::EPROTO);
+    }
+
+    #[test]
+    fn init_parameters() {
+        let ch = MockChannel::new(Tuning);
+        let conn = block_on(ch.init_capable(InitFlags::ASYNC_READ | InitFlags::FLOCK_LOCKS)).unwrap();
+        // Flags the kernel doesn't support aren't enabled
+        assert_eq!(conn.want, InitFlags::ASYNC_READ | InitFlags::FLOCK_LOCKS);
+        assert_eq!((conn.max_readahead, conn.max_write), (16 * 1024, 64 * 1024));
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+        assert_eq!(ch.session().connection().max_write, 64 * 1024);
+    }
+
+    #[test]
+    fn panicking_filesystem() {
+        let ch = MockChannel::new(Panicking);
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.lookup(FUSE_ROOT_ID, "foo")).unwrap_err(), Errno::EIO);
+        // Operations that adjust the reply of the filesystem reply too
+        assert_eq!(block_on(ch.open(FUSE_ROOT_ID, 0)).unwrap_err(), Errno::EIO);
+        let arg = [&[0; std::mem::size_of::<fuse_abi::fuse_create_in>()][..], b"foo\0"].concat();
+        let reply = block_on(ch.request(fuse_abi::fuse_opcode::FUSE_CREATE as u32, FUSE_ROOT_ID, &arg)).unwrap();
+        assert_eq!(reply.result().unwrap_err(), Errno::EIO);
+        // The session keeps working after a panic
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+        let reply = block_on(ch.request(fuse_abi::fuse_opcode::FUSE_DESTROY as u32, 0, &[])).unwrap();
+        assert_eq!(reply.result().unwrap_err(), Errno::EIO);
+    }
+
+    /// Filesystem that replies more data than asked for, remembering the sizes asked for
+    #[derive(Default)]
+    struct Oversized(Mutex<Vec<u32>>);
+
+    #[async_trait]
+    impl Filesystem for Oversized {
+        async fn read(&self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, size: u32) -> Result<Data, Errno> {
+            self.0.lock().unwrap().push(size);
+            Ok(vec![0; 65536].into())
+        }
+    }
+
+    #[test]
+    fn max_read() {
+        let options: Vec<OsString> = vec!["-o".into(), "max_read=4096".into()];
+        let ch = MockChannel::with_options(Oversized::default(), &options);
+        assert_eq!(block_on(ch.init()).unwrap().max_read, 4096);
+        assert_eq!(block_on(ch.read(FUSE_ROOT_ID, 0, 0, 8192)).unwrap().len(), 4096);
+        assert_eq!(block_on(ch.read(FUSE_ROOT_ID, 0, 0, 1024)).unwrap().len(), 1024);
+        assert_eq!(*ch.session().filesystem.0.lock().unwrap(), [4096, 1024]);
+
+        // Without the option, reads are limited to the session's buffer only
+        let ch = MockChannel::new(Oversized::default());
+        assert_eq!(block_on(ch.init()).unwrap().max_read, 16 * 1024 * 1024);
+        assert_eq!(block_on(ch.read(FUSE_ROOT_ID, 0, 0, 8192)).unwrap().len(), 8192);
+    }
+
+    /// Filesystem recording the lookups it's asked to forget
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct Forgets(Mutex<Vec<(u64, u64)>>);
+
+    #[cfg(not(target_os = "macos"))]
+    #[async_trait]
+    impl Filesystem for Forgets {
+        async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+            self.0.lock().unwrap().push((ino, nlookup));
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn batch_forget() {
+        let ch = MockChannel::new(Forgets::default());
+        block_on(ch.init()).unwrap();
+        let mut data: Vec<u8> = [2u32, 0].iter().flat_map(|value| value.to_ne_bytes()).collect();
+        data.extend([2u64, 3, 4, 1].iter().flat_map(|value| value.to_ne_bytes()));
+        assert!(block_on(ch.request(fuse_abi::fuse_opcode::FUSE_BATCH_FORGET as u32, 0, &data)).is_none());
+        assert_eq!(*ch.session().filesystem.0.lock().unwrap(), [(2, 3), (4, 1)]);
+        // Fewer inodes than counted are malformed
+        data[..4].copy_from_slice(&3u32.to_ne_bytes());
+        assert!(block_on(ch.request(fuse_abi::fuse_opcode::FUSE_BATCH_FORGET as u32, 0, &data)).is_none());
+        assert_eq!(ch.session().filesystem.0.lock().unwrap().len(), 2);
+    }
+
+    /// Filesystem of directories containing the directories `x` and `y`, whose inodes are the
+    /// inode of their parent followed by a digit, recording the lookups it's asked to forget
+    #[cfg(not(target_os = "macos"))]
+    #[derive(Default)]
+    struct Listing(Mutex<Vec<(u64, u64)>>);
+
+    #[cfg(not(target_os = "macos"))]
+    #[async_trait]
+    impl Filesystem for Listing {
+        async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+            self.0.lock().unwrap().push((ino, nlookup));
+        }
+
+        async fn readdirplus(&self, _req: &Request, ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+            let entries = ["x", "y"].iter().enumerate().map(|(i, name)| {
+                let ino = ino * 10 + i as u64 + 1;
+                let dirent = DirEntry { ino, offset: DirOffset::from_raw(i as u64 + 1), kind: FileType::Directory, name: name.into() };
+                DirEntryPlus { dirent, entry: Entry::new(FileAttr::builder(ino, FileType::Directory).build(), 0) }
+            });
+            Ok(entries.skip(offset.raw() as usize).collect())
+        }
+
+        async fn rename2(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: u32) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        async fn lseek(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+            match whence {
+                libc::SEEK_DATA => Ok(offset),
+                _ => Err(Errno::ENXIO),
+            }
+        }
+
+        async fn statx(&self, _req: &Request, ino: u64, _fh: Option<u64>, _flags: u32, _mask: u32) -> Result<Attr, Errno> {
+            // Only the root directory has a known creation time
+            let crtime = if ino == FUSE_ROOT_ID { UNIX_EPOCH + Duration::new(100, 5) } else { UNIX_EPOCH };
+            Ok(Attr::new(FileAttr::builder(ino, FileType::CharDevice).perm(0o640).rdev(0x12345).crtime(crtime).build()))
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn readdirplus() {
+        let ch = MockChannel::new(Listing::default()).dot_entries(true);
+        block_on(ch.init()).unwrap();
+        let names = |entries: &[DirEntryPlus]| entries.iter().map(|entry| (entry.dirent.ino, entry.entry.attr.ino, entry.dirent.name.to_str().unwrap().to_string())).collect::<Vec<_>>();
+        let entries = block_on(ch.readdirplus(FUSE_ROOT_ID, 0, 0, 4096)).unwrap();
+        assert_eq!(names(&entries), [(1, 0, ".".into()), (1, 0, "..".into()), (11, 11, "x".into()), (12, 12, "y".to_string())]);
+        assert_eq!(entries[3].dirent.offset.raw(), 4);
+        // Listed directories are looked up, their parents are known
+        assert_eq!(ch.session().parents.len(), 2);
+        block_on(ch.readdirplus(11, 0, 2, 4096)).unwrap();
+        assert_eq!(ch.session().parents.get(111), 11);
+        assert!(ch.session().filesystem.0.lock().unwrap().is_empty());
+        // Entries that don't fit are forgotten right away
+        let entries = block_on(ch.readdirplus(12, 0, 2, 200)).unwrap();
+        assert_eq!(names(&entries), [(121, 121, "x".to_string())]);
+        assert_eq!(*ch.session().filesystem.0.lock().unwrap(), [(122, 1)]);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn rename2() {
+        let ch = MockChannel::new(Listing::default()).dot_entries(true);
+        block_on(ch.init()).unwrap();
+        block_on(ch.readdirplus(FUSE_ROOT_ID, 0, 2, 4096)).unwrap();
+        block_on(ch.readdirplus(11, 0, 2, 4096)).unwrap();
+        // Exchanged directories swap their parents
+        block_on(ch.rename2(11, "x", FUSE_ROOT_ID, "y", fuse_abi::consts::RENAME_EXCHANGE)).unwrap();
+        assert_eq!((ch.session().parents.get(111), ch.session().parents.get(12)), (FUSE_ROOT_ID, 11));
+        block_on(ch.rename2(FUSE_ROOT_ID, "y", 11, "z", 0)).unwrap();
+        assert_eq!(ch.session().parents.get(111), 11);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn lseek() {
+        let ch = MockChannel::new(Listing::default()).cache_enosys(true);
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.lseek(FUSE_ROOT_ID, 0, 10, libc::SEEK_DATA)), Ok(10));
+        assert_eq!(block_on(ch.lseek(FUSE_ROOT_ID, 0, 10, libc::SEEK_HOLE)), Err(Errno::ENXIO));
+        // Operations the filesystem doesn't implement fail like any other
+        let syncfs = || block_on(ch.request(fuse_abi::fuse_opcode::FUSE_SYNCFS as u32, FUSE_ROOT_ID, &[0; 8])).unwrap().result().map(|_| ());
+        assert_eq!(syncfs(), Err(Errno::ENOSYS));
+        assert_eq!(syncfs(), Err(Errno::ENOSYS));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn statx() {
+        let ch = MockChannel::new(Listing::default());
+        block_on(ch.init()).unwrap();
+        let statx = |ino| {
+            let reply = block_on(ch.request(fuse_abi::fuse_opcode::FUSE_STATX as u32, ino, &[0; 24])).unwrap();
+            fuse_abi::fuse_statx_out::read_from_prefix(reply.result().unwrap()).unwrap().0.stat
+        };
+        let stat = statx(FUSE_ROOT_ID);
+        assert_eq!((stat.mask, stat.ino, stat.mode as u32), (0xfff, FUSE_ROOT_ID, libc::S_IFCHR | 0o640));
+        assert_eq!((stat.btime.tv_sec, stat.btime.tv_nsec), (100, 5));
+        assert_eq!((stat.rdev_major, stat.rdev_minor), (0x123, 0x45));
+        // Without a creation time, the reply doesn't claim to have one
+        assert_eq!(statx(2).mask, 0x7ff);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn direct_io_on_o_direct() {
+        let ch = MockChannel::new(Oversized::default()).direct_io_on_o_direct(true);
+        block_on(ch.init()).unwrap();
+        let flags = (libc::O_RDONLY | libc::O_DIRECT) as u32;
+        assert_eq!(block_on(ch.open(FUSE_ROOT_ID, flags)).unwrap().flags, OpenFlags::DIRECT_IO);
+        assert_eq!(block_on(ch.open(FUSE_ROOT_ID, libc::O_RDONLY as u32)).unwrap().flags, OpenFlags::empty());
+
+        // Without the option, the flags of the filesystem are replied as they are
+        let ch = MockChannel::new(Oversized::default());
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.open(FUSE_ROOT_ID, flags)).unwrap().flags, OpenFlags::empty());
+    }
 }
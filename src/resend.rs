@@ -0,0 +1,206 @@
+//! Resent requests
+//!
+//! Requests the kernel driver sent to a daemon that went away before replying (e.g. a daemon
+//! that crashed or handed the session over to another one) stay pending in the kernel. Once
+//! the channel is served again, `Notifier::resend` (ABI 7.40) asks the kernel to send all
+//! pending requests again, with `FUSE_UNIQUE_RESEND` set in their unique id.
+//!
+//! A resent request may have been applied by the filesystem before, only its reply got lost,
+//! and operations like mkdir, unlink or write must not be applied twice. With
+//! `Mount::resend_replies`, the session keeps track of the requests that change the filesystem.
+//! A resent request that is still being dispatched gets the reply of the original request
+//! once it's done, instead of being dispatched again. The session remembers the replies of the
+//! latest of these requests as well, and answers a resent request it replied to before with the
+//! same reply. The replies are handed over to another process with the session state (see
+//! `Handoff`), so that it recognizes the requests applied before.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Mutex;
+
+use fuse_abi::fuse_opcode;
+use fuse_abi::fuse_opcode::*;
+
+/// Bit of the unique id of requests that the kernel sends again (7.40)
+pub(crate) const UNIQUE_RESEND: u64 = 1 << 63;
+
+/// Returns true if requests of the given opcode change the filesystem or allocate file handles,
+/// so that dispatching them twice has another result than dispatching them once
+pub(crate) fn is_applied_once(opcode: fuse_opcode) -> bool {
+    match opcode {
+        FUSE_SETATTR | FUSE_MKNOD | FUSE_MKDIR | FUSE_SYMLINK | FUSE_LINK | FUSE_UNLINK | FUSE_RMDIR
+            | FUSE_RENAME | FUSE_OPEN | FUSE_OPENDIR | FUSE_CREATE | FUSE_WRITE | FUSE_SETXATTR
+            | FUSE_REMOVEXATTR => true,
+        #[cfg(not(target_os = "macos"))]
+        FUSE_RENAME2 | FUSE_COPY_FILE_RANGE | FUSE_TMPFILE => true,
+        _ => false,
+    }
+}
+
+/// Outcome of receiving a request that is applied once
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Received {
+    /// The request is new (or its original got lost) and is dispatched
+    New,
+    /// The request is a resent copy of a request still being dispatched, and gets its reply
+    InFlight,
+    /// The request is a resent copy of a request replied to before, and gets the given reply
+    Replied(Vec<u8>),
+}
+
+/// Requests that are applied once being dispatched, and the replies sent to the latest of them
+pub(crate) struct ReplyCache {
+    capacity: usize,
+    replies: Mutex<Replies>,
+}
+
+/// Cached replies and their unique ids from the oldest to the latest, and the unique ids of
+/// requests being dispatched with the unique ids of resent copies waiting for their replies
+#[derive(Default)]
+struct Replies {
+    by_unique: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+    in_flight: HashMap<u64, Vec<u64>>,
+}
+
+impl ReplyCache {
+    /// Create a cache keeping the replies of the given number of requests
+    pub(crate) fn new(capacity: usize) -> ReplyCache {
+        ReplyCache { capacity, replies: Mutex::default() }
+    }
+
+    /// Create a cache keeping the replies of the given number of requests, starting with the
+    /// given serialized replies (from the oldest to the latest)
+    pub(crate) fn with_replies(capacity: usize, replies: &[Vec<u8>]) -> ReplyCache {
+        let cache = ReplyCache::new(capacity);
+        for reply in replies {
+            if let Some(unique) = reply.get(8..16) {
+                cache.remember(u64::from_ne_bytes(<[u8; 8]>::try_from(unique).unwrap()), reply.clone());
+            }
+        }
+        cache
+    }
+
+    /// Returns the capacity of the cache
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Register the request of the given unique id as being dispatched, unless it's a resent
+    /// copy of a request that is being dispatched or was replied to already
+    pub(crate) fn receive(&self, unique: u64) -> Received {
+        let mut replies = self.replies.lock().unwrap();
+        let original = unique & !UNIQUE_RESEND;
+        if unique & UNIQUE_RESEND != 0 {
+            if let Some(reply) = replies.by_unique.get(&original) {
+                return Received::Replied(with_unique(reply, unique));
+            }
+            if let Some(waiting) = replies.in_flight.get_mut(&original) {
+                waiting.push(unique);
+                return Received::InFlight;
+            }
+        }
+        replies.in_flight.insert(original, Vec::new());
+        Received::New
+    }
+
+    /// Remember the given serialized reply to the request of the given unique id, dropping the
+    /// oldest reply if the cache is full. Returns the replies to the resent copies of the
+    /// request that wait for it.
+    pub(crate) fn insert(&self, unique: u64, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        let reply = data.concat();
+        let waiting = self.replies.lock().unwrap().in_flight.remove(&(unique & !UNIQUE_RESEND)).unwrap_or_default();
+        let resent = waiting.into_iter().map(|unique| with_unique(&reply, unique)).collect();
+        self.remember(unique, reply);
+        resent
+    }
+
+    fn remember(&self, unique: u64, reply: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut replies = self.replies.lock().unwrap();
+        if replies.by_unique.insert(unique & !UNIQUE_RESEND, reply).is_none() {
+            replies.order.push_back(unique & !UNIQUE_RESEND);
+        }
+        while replies.order.len() > self.capacity {
+            if let Some(oldest) = replies.order.pop_front() {
+                replies.by_unique.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns the remembered replies from the oldest to the latest
+    pub(crate) fn replies(&self) -> Vec<Vec<u8>> {
+        let replies = self.replies.lock().unwrap();
+        replies.order.iter().filter_map(|unique| replies.by_unique.get(unique).cloned()).collect()
+    }
+}
+
+/// Returns the given serialized reply with the unique id in its header set to the given one
+fn with_unique(reply: &[u8], unique: u64) -> Vec<u8> {
+    let mut reply = reply.to_vec();
+    if let Some(header) = reply.get_mut(8..16) {
+        header.copy_from_slice(&unique.to_ne_bytes());
+    }
+    reply
+}
+
+impl fmt::Debug for ReplyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.replies.lock().unwrap().order.len();
+        write!(f, "ReplyCache({} of {} replies)", len, self.capacity)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Received, ReplyCache, UNIQUE_RESEND};
+
+    fn reply(unique: u64, error: i32) -> Vec<u8> {
+        [&16u32.to_ne_bytes()[..], &error.to_ne_bytes(), &unique.to_ne_bytes()].concat()
+    }
+
+    #[test]
+    fn cache() {
+        let cache = ReplyCache::new(2);
+        assert_eq!((cache.receive(1), cache.receive(2)), (Received::New, Received::New));
+        assert!(cache.insert(1, &[&reply(1, 0)]).is_empty());
+        cache.insert(2, &[&reply(2, -17)]);
+        assert_eq!(cache.receive(2 | UNIQUE_RESEND), Received::Replied(reply(2 | UNIQUE_RESEND, -17)));
+        assert_eq!(cache.replies(), [reply(1, 0), reply(2, -17)]);
+        // The oldest reply is dropped
+        cache.insert(3, &[&reply(3, 0)]);
+        assert_eq!(cache.receive(1 | UNIQUE_RESEND), Received::New);
+        assert_eq!(cache.receive(3 | UNIQUE_RESEND), Received::Replied(reply(3 | UNIQUE_RESEND, 0)));
+        assert_eq!(format!("{:?}", cache), "ReplyCache(2 of 2 replies)");
+        // Nothing is kept without capacity
+        let cache = ReplyCache::new(0);
+        cache.insert(1, &[&reply(1, 0)]);
+        assert!(cache.replies().is_empty());
+    }
+
+    #[test]
+    fn in_flight() {
+        let cache = ReplyCache::new(0);
+        assert_eq!(cache.receive(5), Received::New);
+        // Resent copies of a request being dispatched wait for its reply
+        assert_eq!(cache.receive(5 | UNIQUE_RESEND), Received::InFlight);
+        assert_eq!(cache.insert(5, &[&reply(5, -2)]), [reply(5 | UNIQUE_RESEND, -2)]);
+        // Resent requests whose originals got lost are dispatched
+        assert_eq!(cache.receive(6 | UNIQUE_RESEND), Received::New);
+        assert!(cache.insert(6 | UNIQUE_RESEND, &[&reply(6 | UNIQUE_RESEND, 0)]).is_empty());
+        assert_eq!(cache.receive(5 | UNIQUE_RESEND), Received::New);
+    }
+
+    #[test]
+    fn with_replies() {
+        let cache = ReplyCache::with_replies(2, &[reply(1, 0), reply(2 | UNIQUE_RESEND, -17), reply(3, 0)]);
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(cache.replies(), [reply(2 | UNIQUE_RESEND, -17), reply(3, 0)]);
+        assert_eq!(cache.receive(2 | UNIQUE_RESEND), Received::Replied(reply(2 | UNIQUE_RESEND, -17)));
+        assert_eq!(cache.receive(1 | UNIQUE_RESEND), Received::New);
+    }
+}
@@ -0,0 +1,432 @@
+//! Routing filesystem
+//!
+//! A `Router` mounts several filesystems under one mountpoint: every route is a directory in the
+//! root of the router (e.g. `/logs` and `/data`), which shows the root directory of the route's
+//! filesystem. Routers can be nested to route deeper paths.
+//!
+//! The inode numbers of the routed filesystems are mapped to inode numbers of the router using an
+//! `InodeTable`, so each filesystem keeps its own inode space. Lookups and forgets are passed on
+//! to the routed filesystems, while their root inodes are never forgotten as usual. File handles
+//! are passed through unchanged, since the inode of a request determines its filesystem.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::Path;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use fuse_abi::FUSE_ROOT_ID;
+use log::warn;
+
+use crate::inode::{InodeTable, UNKNOWN_INO};
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+
+/// Filesystem of a route
+type Routed = Box<dyn Filesystem + Send + Sync>;
+
+/// Filesystem routing the directories in its root to other filesystems
+pub struct Router {
+    routes: Vec<(OsString, Routed)>,
+    /// Maps inodes of the router to route indexes and the inodes of their filesystems
+    table: InodeTable<(usize, u64)>,
+    attr: FileAttr,
+}
+
+impl Router {
+    /// Create a router without routes
+    pub fn new() -> Router {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let attr = FileAttr::builder(FUSE_ROOT_ID, FileType::Directory).time(SystemTime::now()).uid(uid).gid(gid).perm(0o555).build();
+        Router { routes: Vec::new(), table: InodeTable::new((usize::MAX, FUSE_ROOT_ID)), attr }
+    }
+
+    /// Add a route showing the given filesystem as the directory of the given name
+    pub fn route<N: Into<OsString>, FS: Filesystem + Send + Sync + 'static>(mut self, name: N, filesystem: FS) -> Router {
+        self.routes.push((name.into(), Box::new(filesystem)));
+        self.attr.nlink = self.routes.len() as u32 + 2;
+        self
+    }
+
+    /// Returns the filesystem of the given inode and the inode number in it. Fails with EPERM
+    /// for the root inode of the router, which can't be modified.
+    fn resolve(&self, ino: u64) -> Result<(usize, &Routed, u64), Errno> {
+        if ino == FUSE_ROOT_ID { return Err(Errno::EPERM); }
+        let (index, ino) = self.table.get(ino).ok_or(Errno::ENOENT)?;
+        Ok((index, &self.routes[index].1, ino))
+    }
+
+    /// Returns the filesystem of two inodes, which must be the same (EXDEV otherwise)
+    fn resolve_both(&self, ino: u64, other: u64) -> Result<(usize, &Routed, u64, u64), Errno> {
+        let (index, fs, ino) = self.resolve(ino)?;
+        let (other_index, _, other) = self.resolve(other)?;
+        if index != other_index { return Err(Errno::EXDEV); }
+        Ok((index, fs, ino, other))
+    }
+
+    /// Maps the inode number of the given entry of a filesystem to the router
+    fn entry(&self, index: usize, entry: Entry) -> Entry {
+        if entry.is_negative() { return entry; }
+        let (ino, generation) = self.table.lookup(&(index, entry.attr.ino));
+        Entry { ttl: entry.ttl, attr: FileAttr { ino, ..entry.attr }, generation }
+    }
+
+    fn attr(&self, ino: u64, attr: Attr) -> Attr {
+        Attr { ttl: attr.ttl, attr: FileAttr { ino, ..attr.attr } }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&OsString> = self.routes.iter().map(|(name, _)| name).collect();
+        f.debug_struct("Router").field("routes", &names).finish()
+    }
+}
+
+#[async_trait]
+impl Filesystem for Router {
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        for (_, fs) in &self.routes {
+            fs.init(req).await?;
+        }
+        Ok(())
+    }
+
+    async fn destroy(&self, req: &Request) {
+        for (_, fs) in &self.routes {
+            fs.destroy(req).await;
+        }
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        if parent == FUSE_ROOT_ID {
+            let index = self.routes.iter().position(|(route, _)| route == name).ok_or(Errno::ENOENT)?;
+            // Roots are not counted by lookups
+            let attr = self.routes[index].1.getattr(req, FUSE_ROOT_ID).await?;
+            return Ok(self.entry(index, Entry { ttl: attr.ttl, attr: attr.attr, generation: 0 }));
+        }
+        let (index, fs, parent) = self.resolve(parent)?;
+        Ok(self.entry(index, fs.lookup(req, parent, name).await?))
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        match self.table.get(ino) {
+            Some((index, routed)) if routed != FUSE_ROOT_ID => self.routes[index].1.forget(req, routed, nlookup).await,
+            Some(_) => (),
+            None => warn!("Forget of unknown inode {}", ino),
+        }
+        self.table.forget(ino, nlookup);
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(Attr::new(self.attr)); }
+        let (_, fs, routed) = self.resolve(ino)?;
+        Ok(self.attr(ino, fs.getattr(req, routed).await?))
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        let (_, fs, routed) = self.resolve(ino)?;
+        Ok(self.attr(ino, fs.setattr(req, routed, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await?))
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.readlink(req, ino).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        let (index, fs, parent) = self.resolve(parent)?;
+        Ok(self.entry(index, fs.mknod(req, parent, name, mode, rdev).await?))
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        let (index, fs, parent) = self.resolve(parent)?;
+        Ok(self.entry(index, fs.mkdir(req, parent, name, mode).await?))
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let (_, fs, parent) = self.resolve(parent)?;
+        fs.unlink(req, parent, name).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let (_, fs, parent) = self.resolve(parent)?;
+        fs.rmdir(req, parent, name).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        let (index, fs, parent) = self.resolve(parent)?;
+        Ok(self.entry(index, fs.symlink(req, parent, name, link).await?))
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        let (_, fs, parent, newparent) = self.resolve_both(parent, newparent)?;
+        fs.rename(req, parent, name, newparent, newname).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        let (index, fs, ino, newparent) = self.resolve_both(ino, newparent)?;
+        Ok(self.entry(index, fs.link(req, ino, newparent, newname).await?))
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.open(req, ino, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.read(req, ino, fh, offset, size).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.write(req, ino, fh, offset, data, flags).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.flush(req, ino, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.release(req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.fsync(req, ino, fh, datasync).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(Opened::default()); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.opendir(req, ino, flags).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        if ino == FUSE_ROOT_ID {
+            let dots = [(FUSE_ROOT_ID, OsStr::new(".")), (FUSE_ROOT_ID, OsStr::new(".."))];
+            let routes = self.routes.iter().enumerate().map(|(index, (name, _))| {
+                (self.table.ino(&(index, FUSE_ROOT_ID)).unwrap_or(UNKNOWN_INO), name.as_os_str())
+            });
+            // Routes are only added, so their number tells listings apart
+            let verifier = self.routes.len() as u64;
+            let position = offset.position(verifier)?;
+            return Ok(dots.iter().copied().chain(routes).enumerate().skip(position as usize).map(|(i, (ino, name))| {
+                DirEntry { ino, offset: DirOffset::new(i as u64 + 1, verifier), kind: FileType::Directory, name: name.into() }
+            }).collect());
+        }
+        let (index, fs, routed) = self.resolve(ino)?;
+        let entries = fs.readdir(req, routed, fh, offset).await?;
+        Ok(entries.into_iter().map(|entry| {
+            let ino = self.table.ino(&(index, entry.ino)).unwrap_or(UNKNOWN_INO);
+            DirEntry { ino, ..entry }
+        }).collect())
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(()); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.releasedir(req, ino, fh, flags).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(()); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.fsyncdir(req, ino, fh, datasync).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(Statfs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 });
+        }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.statfs(req, ino).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.setxattr(req, ino, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        if ino == FUSE_ROOT_ID { return Err(Errno::ENOATTR); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.getxattr(req, ino, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(Xattr::Data(Vec::new())); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.listxattr(req, ino, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.removexattr(req, ino, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(()); }
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.access(req, ino, mask).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let (index, fs, parent) = self.resolve(parent)?;
+        let created = fs.create(req, parent, name, mode, flags).await?;
+        let entry = self.entry(index, Entry { ttl: created.ttl, attr: created.attr, generation: created.generation });
+        Ok(Created { ttl: entry.ttl, attr: entry.attr, generation: entry.generation, ..created })
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.getlk(req, ino, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.bmap(req, ino, blocksize, idx).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.ioctl(req, ino, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.fallocate(req, ino, fh, offset, length, mode).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        if ino == FUSE_ROOT_ID {
+            // The routes are listed without attributes, their roots are looked up as usual
+            let entries = self.readdir(req, ino, fh, offset).await?;
+            return Ok(entries.into_iter().map(|dirent| DirEntryPlus { dirent, entry: Entry::negative(None) }).collect());
+        }
+        let (index, fs, routed) = self.resolve(ino)?;
+        let entries = fs.readdirplus(req, routed, fh, offset).await?;
+        Ok(entries.into_iter().map(|entry| {
+            let ino = self.table.ino(&(index, entry.dirent.ino)).unwrap_or(UNKNOWN_INO);
+            DirEntryPlus { dirent: DirEntry { ino, ..entry.dirent }, entry: self.entry(index, entry.entry) }
+        }).collect())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        let (_, fs, parent, newparent) = self.resolve_both(parent, newparent)?;
+        fs.rename2(req, parent, name, newparent, newname, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        let (_, fs, ino) = self.resolve(ino)?;
+        fs.lseek(req, ino, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        let (_, fs, ino_in, ino_out) = self.resolve_both(ino_in, ino_out)?;
+        fs.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    /// Synchronizes the filesystems of all routes that implement syncfs
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, _ino: u64) -> Result<(), Errno> {
+        for (_, fs) in &self.routes {
+            match fs.syncfs(req, FUSE_ROOT_ID).await {
+                Err(err) if err != Errno::ENOSYS => return Err(err),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        let (index, fs, parent) = self.resolve(parent)?;
+        let created = fs.tmpfile(req, parent, mode, flags).await?;
+        let entry = self.entry(index, Entry { ttl: created.ttl, attr: created.attr, generation: created.generation });
+        Ok(Created { ttl: entry.ttl, attr: entry.attr, generation: entry.generation, ..created })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        if ino == FUSE_ROOT_ID { return Ok(Attr::new(self.attr)); }
+        let (_, fs, routed) = self.resolve(ino)?;
+        Ok(self.attr(ino, fs.statx(req, routed, fh, flags, mask).await?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::time::UNIX_EPOCH;
+    use async_trait::async_trait;
+    use fuse_abi::FUSE_ROOT_ID;
+    use crate::{block_on, Attr, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::Router;
+
+    /// Filesystem with a single file `file` of the given size as inode 2
+    struct Single(u64);
+
+    #[async_trait]
+    impl Filesystem for Single {
+        async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            if parent == FUSE_ROOT_ID && name == "missing" { return Ok(Entry::negative(None)); }
+            if parent != FUSE_ROOT_ID || name != "file" { return Err(Errno::ENOENT); }
+            Ok(Entry::new(FileAttr::builder(2, FileType::RegularFile).time(UNIX_EPOCH).size(self.0).build(), 0))
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            let kind = if ino == FUSE_ROOT_ID { FileType::Directory } else { FileType::RegularFile };
+            Ok(Attr::new(FileAttr::builder(ino, kind).time(UNIX_EPOCH).size(self.0).build()))
+        }
+    }
+
+    #[test]
+    fn routes() {
+        let req = Request::test(0, 0);
+        let router = Router::new().route("logs", Single(1)).route("data", Single(2));
+        let logs = block_on(router.lookup(&req, FUSE_ROOT_ID, OsStr::new("logs"))).unwrap().attr.ino;
+        let data = block_on(router.lookup(&req, FUSE_ROOT_ID, OsStr::new("data"))).unwrap().attr.ino;
+        assert_ne!(logs, data);
+        let logs_file = block_on(router.lookup(&req, logs, OsStr::new("file"))).unwrap().attr;
+        let data_file = block_on(router.lookup(&req, data, OsStr::new("file"))).unwrap().attr;
+        assert_ne!(logs_file.ino, data_file.ino);
+        assert_eq!((logs_file.size, data_file.size), (1, 2));
+        assert_eq!(block_on(router.getattr(&req, data_file.ino)).unwrap().attr.size, 2);
+        assert!(block_on(router.lookup(&req, data, OsStr::new("missing"))).unwrap().is_negative());
+        assert_eq!(block_on(router.lookup(&req, FUSE_ROOT_ID, OsStr::new("other"))).unwrap_err(), Errno::ENOENT);
+        assert_eq!(block_on(router.rename(&req, logs, OsStr::new("file"), data, OsStr::new("file"))), Err(Errno::EXDEV));
+        assert_eq!(block_on(router.mkdir(&req, FUSE_ROOT_ID, OsStr::new("dir"), 0o755)).unwrap_err(), Errno::EPERM);
+    }
+
+    #[test]
+    fn root_readdir() {
+        let req = Request::test(0, 0);
+        let router = Router::new().route("logs", Single(1)).route("data", Single(2));
+        let entries = block_on(router.readdir(&req, FUSE_ROOT_ID, 0, DirOffset::START)).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.to_str().unwrap()).collect();
+        assert_eq!(names, [".", "..", "logs", "data"]);
+        assert_eq!(block_on(router.readdir(&req, FUSE_ROOT_ID, 0, entries[2].offset)).unwrap().len(), 1);
+        assert_eq!(block_on(router.getattr(&req, FUSE_ROOT_ID)).unwrap().attr.nlink, 4);
+    }
+}
@@ -0,0 +1,337 @@
+//! Sandboxing of the filesystem daemon
+//!
+//! A FUSE daemon parses requests of any process accessing the mountpoint, so it's worth
+//! restricting what a compromised daemon could do. Once the filesystem is mounted, the daemon
+//! only needs the fd to the kernel driver and the files it serves. `Sandbox` limits the files
+//! it can open to the given paths with a Landlock ruleset, and fails system calls that no
+//! filesystem needs (mounting, loading kernel modules, tracing other processes, ...) with EPERM
+//! with a seccomp filter. Both can't be undone and are inherited by child processes.
+//!
+//! Landlock restricts the calling thread and the threads it creates afterwards, so the sandbox
+//! should be applied before other threads are started (e.g. by mounting with `Mount::run` from
+//! the main thread). The seccomp filter is applied to all threads of the process. Kernels
+//! without Landlock or seccomp support are left unrestricted, with a warning.
+//!
+//! A sandboxed daemon can't unmount the filesystem through fusermount, so it's left to the
+//! kernel (when the fd is closed) or to `fusermount -u` run from outside.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use libc::{c_int, c_long, c_uint, c_ulong};
+use log::{debug, warn};
+
+// Landlock ABI (linux/landlock.h)
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Access rights of Landlock ABI 1 (executing, reading and writing files, changing directories)
+const LANDLOCK_ACCESS_FS_V1: u64 = (1 << 13) - 1;
+/// Access rights that apply to files (other rights apply to directories only)
+const LANDLOCK_ACCESS_FILE: u64 = LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+// Seccomp filters (linux/seccomp.h, linux/filter.h, linux/audit.h)
+const SECCOMP_SET_MODE_FILTER: c_uint = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: c_ulong = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+/// Offsets of the system call number and architecture in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// Bit of the x32 system calls, which x86_64 processes shouldn't make
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls failed by the seccomp filter
+const DENIED_SYSCALLS: &[c_long] = &[
+    libc::SYS_acct, libc::SYS_add_key, libc::SYS_bpf, libc::SYS_chroot, libc::SYS_clock_settime,
+    libc::SYS_delete_module, libc::SYS_finit_module, libc::SYS_init_module, libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load, libc::SYS_keyctl, libc::SYS_mount, libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open, libc::SYS_pivot_root, libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev, libc::SYS_ptrace, libc::SYS_reboot, libc::SYS_request_key,
+    libc::SYS_setns, libc::SYS_settimeofday, libc::SYS_swapoff, libc::SYS_swapon,
+    libc::SYS_unshare, libc::SYS_userfaultfd,
+];
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+/// Returns the seccomp filter program failing the denied system calls of the given architecture
+/// and killing processes making system calls of other architectures
+fn seccomp_program(arch: u32) -> Vec<SockFilter> {
+    let denied = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut program = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(BPF_RET_K, denied),
+    ];
+    for &nr in DENIED_SYSCALLS {
+        program.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+        program.push(stmt(BPF_RET_K, denied));
+    }
+    program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    program
+}
+
+/// Returns the Landlock ABI version supported by the kernel, none if Landlock isn't supported
+/// or disabled
+fn landlock_abi() -> Option<c_long> {
+    let abi = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, std::ptr::null::<LandlockRulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION) };
+    if abi > 0 { Some(abi) } else { None }
+}
+
+/// Returns the access rights handled by rulesets of the given Landlock ABI version
+fn handled_access(abi: c_long) -> u64 {
+    let mut access = LANDLOCK_ACCESS_FS_V1;
+    if abi >= 2 {
+        access |= LANDLOCK_ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= LANDLOCK_ACCESS_FS_TRUNCATE;
+    }
+    access
+}
+
+/// Sandbox restricting the files a filesystem daemon can access and the system calls it can make
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sandbox {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    landlock: bool,
+    seccomp: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+impl Sandbox {
+    /// Create a sandbox with Landlock and seccomp that only allows reading and writing
+    /// /dev/fuse until further paths are added
+    pub fn new() -> Sandbox {
+        Sandbox { read_only: Vec::new(), read_write: vec![PathBuf::from("/dev/fuse")], landlock: true, seccomp: true }
+    }
+
+    /// Allow reading the files beneath the given path (e.g. the backing directory of a read-only
+    /// filesystem, or configuration files)
+    pub fn read_only<P: AsRef<Path>>(mut self, path: P) -> Sandbox {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allow reading, writing, creating and removing the files beneath the given path (e.g. the
+    /// backing directory of the filesystem)
+    pub fn read_write<P: AsRef<Path>>(mut self, path: P) -> Sandbox {
+        self.read_write.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Restrict the accessible files with Landlock (enabled by default)
+    pub fn landlock(mut self, landlock: bool) -> Sandbox {
+        self.landlock = landlock;
+        self
+    }
+
+    /// Fail system calls that filesystems don't need with a seccomp filter (enabled by default)
+    pub fn seccomp(mut self, seccomp: bool) -> Sandbox {
+        self.seccomp = seccomp;
+        self
+    }
+
+    /// Sandbox the calling thread (and the whole process for seccomp). Parts the kernel doesn't
+    /// support are skipped with a warning.
+    pub fn apply(&self) -> io::Result<()> {
+        if self.landlock || self.seccomp {
+            // Required for unprivileged processes, and keeps setuid binaries from escaping
+            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if self.landlock {
+            self.apply_landlock()?;
+        }
+        if self.seccomp {
+            self.apply_seccomp()?;
+        }
+        Ok(())
+    }
+
+    fn apply_landlock(&self) -> io::Result<()> {
+        let abi = match landlock_abi() {
+            Some(abi) => abi,
+            None => {
+                warn!("Landlock not supported by the kernel, not restricting file access");
+                return Ok(());
+            }
+        };
+        let handled = handled_access(abi);
+        let attr = LandlockRulesetAttr { handled_access_fs: handled };
+        let ruleset = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr, mem::size_of::<LandlockRulesetAttr>(), 0) };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = ruleset as c_int;
+        let rules = self.read_only.iter().map(|path| (path, LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR))
+            .chain(self.read_write.iter().map(|path| (path, handled)));
+        let mut result = Ok(());
+        for (path, access) in rules {
+            result = add_rule(ruleset, path, access & handled);
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() && unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } < 0 {
+            result = Err(io::Error::last_os_error());
+        }
+        unsafe { libc::close(ruleset); }
+        if result.is_ok() {
+            debug!("Restricted file access with Landlock ABI {}", abi);
+        }
+        result
+    }
+
+    fn apply_seccomp(&self) -> io::Result<()> {
+        let arch = match AUDIT_ARCH {
+            Some(arch) => arch,
+            None => {
+                warn!("Seccomp filter not available on this architecture, not restricting system calls");
+                return Ok(());
+            }
+        };
+        let program = seccomp_program(arch);
+        let prog = SockFprog { len: program.len() as u16, filter: program.as_ptr() };
+        if unsafe { libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC, &prog) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINVAL) || err.raw_os_error() == Some(libc::ENOSYS) {
+                warn!("Seccomp filters not supported by the kernel, not restricting system calls");
+                return Ok(());
+            }
+            return Err(err);
+        }
+        debug!("Restricted system calls with seccomp");
+        Ok(())
+    }
+}
+
+/// Allow the given access beneath the given path in the given Landlock ruleset. Paths that
+/// don't exist are skipped.
+fn add_rule(ruleset: c_int, path: &Path, access: u64) -> io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::NotFound {
+            debug!("Not allowing access to missing {}", path.display());
+            return Ok(());
+        }
+        return Err(err);
+    }
+    // Rights of directories can't be granted on files
+    let is_dir = path.is_dir();
+    let allowed_access = if is_dir { access } else { access & LANDLOCK_ACCESS_FILE };
+    let attr = LandlockPathBeneathAttr { allowed_access, parent_fd: fd };
+    let rc = unsafe { libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset, LANDLOCK_RULE_PATH_BENEATH, &attr, 0) };
+    let result = if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+    unsafe { libc::close(fd); }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::thread;
+    use super::{handled_access, jump, landlock_abi, seccomp_program, stmt, Sandbox, BPF_JMP_JEQ_K, BPF_RET_K, DENIED_SYSCALLS};
+    use super::{LANDLOCK_ACCESS_FS_TRUNCATE, LANDLOCK_ACCESS_FS_V1, SECCOMP_RET_ALLOW};
+
+    #[test]
+    fn program() {
+        let program = seccomp_program(0xc000_003e);
+        assert_eq!(program.len(), 7 + 2 * DENIED_SYSCALLS.len());
+        assert_eq!(program[1], jump(BPF_JMP_JEQ_K, 0xc000_003e, 1, 0));
+        assert!(program.contains(&jump(BPF_JMP_JEQ_K, libc::SYS_mount as u32, 0, 1)));
+        assert_eq!(program.last(), Some(&stmt(BPF_RET_K, SECCOMP_RET_ALLOW)));
+    }
+
+    #[test]
+    fn access() {
+        assert_eq!(handled_access(1), LANDLOCK_ACCESS_FS_V1);
+        assert_eq!(handled_access(3) & LANDLOCK_ACCESS_FS_TRUNCATE, LANDLOCK_ACCESS_FS_TRUNCATE);
+    }
+
+    #[test]
+    fn landlock() {
+        if landlock_abi().is_none() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("async-fuse-sandbox-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"data").unwrap();
+        let sandbox = Sandbox::new().read_only(&dir).seccomp(false);
+        let file = dir.join("file");
+        // Landlock only restricts the sandboxed thread
+        let (inside, outside) = thread::spawn(move || {
+            sandbox.apply().unwrap();
+            (fs::read(file).unwrap(), fs::read_dir("/").map(|_| ()).map_err(|err| err.kind()))
+        }).join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((inside, outside), (b"data".to_vec(), Err(io::ErrorKind::PermissionDenied)));
+    }
+}
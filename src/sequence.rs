@@ -0,0 +1,644 @@
+//! Model-based operation sequences
+//!
+//! `ModelCheck` generates random sequences of operations on a small tree of files and
+//! directories (with proptest), runs every sequence against a fresh filesystem through a mock
+//! channel and cross-checks each result with an in-memory model of POSIX semantics. When a
+//! result differs, proptest shrinks the sequence to a minimal one that still fails, which is
+//! returned with the first differing operation.
+//!
+//! Paths are built from three names only, so that operations hit the same files and
+//! directories again and again: creating what exists, removing non-empty directories, renaming
+//! over files and directories or into subdirectories of themselves. The sequence is applied
+//! like the kernel driver does, resolving paths by lookups from the root and checking what the
+//! VFS checks itself (e.g. EEXIST before mknod and mkdir, EISDIR before unlink) before sending
+//! a request. All lookups of an operation are forgotten after it, so that inode tables drop
+//! the inodes and have to look them up again in the next operation, which is where ordering
+//! bugs between lookup, forget, rename and unlink show up.
+//!
+//! Files are created with mknod, so filesystems under test need to implement lookup, getattr,
+//! mknod, mkdir, unlink, rmdir, rename, read, write and readdir. `operations` and
+//! `check_sequence` are public for filesystems driving sequences from their own proptest tests.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+
+use crate::{block_on, Errno, FileType, Filesystem, MockChannel, FUSE_ROOT_ID};
+
+/// Names that paths of operations are built from
+static NAMES: [&str; 3] = ["a", "b", "c"];
+
+/// Operation of a sequence on absolute paths (like "/a/b")
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Create an empty regular file
+    Create(String),
+    /// Create a directory
+    Mkdir(String),
+    /// Remove a file
+    Unlink(String),
+    /// Remove an empty directory
+    Rmdir(String),
+    /// Rename the first path to the second, replacing what's there
+    Rename(String, String),
+    /// Write data at an offset of a file
+    Write(String, u64, Vec<u8>),
+    /// Read the whole content of a file
+    Read(String),
+    /// List the names in a directory
+    Readdir(String),
+    /// Get the type and size of a file or directory
+    Getattr(String),
+}
+
+/// Successful result of an operation
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Outcome {
+    Done,
+    Written(u32),
+    Data(Vec<u8>),
+    Names(Vec<String>),
+    /// Type and size (of regular files only, the size of directories isn't specified)
+    Stat(FileType, Option<u64>),
+}
+
+/// Returns a strategy for paths of the given minimal number of components (up to three)
+fn path(min: usize) -> impl Strategy<Value = String> {
+    vec(select(&NAMES[..]), min..=3).prop_map(|names| format!("/{}", names.join("/")))
+}
+
+/// Returns a strategy for sequences of up to the given number of operations
+pub fn operations(max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    let op = prop_oneof![
+        3 => path(1).prop_map(Op::Create),
+        3 => path(1).prop_map(Op::Mkdir),
+        1 => path(1).prop_map(Op::Unlink),
+        1 => path(1).prop_map(Op::Rmdir),
+        2 => (path(1), path(1)).prop_map(|(from, to)| Op::Rename(from, to)),
+        1 => (path(1), 0..8u64, vec(any::<u8>(), 1..8)).prop_map(|(path, offset, data)| Op::Write(path, offset, data)),
+        1 => path(1).prop_map(Op::Read),
+        1 => path(0).prop_map(Op::Readdir),
+        1 => path(1).prop_map(Op::Getattr),
+    ];
+    vec(op, 0..=max_len)
+}
+
+/// Returns the path of the directory containing the given path
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+/// Returns true if the given path is below the given directory
+fn is_within(path: &str, dir: &str) -> bool {
+    dir != "/" && path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+/// Node of the model
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+}
+
+/// In-memory tree of files and directories by their paths (the root included)
+#[derive(Debug)]
+struct Model {
+    nodes: BTreeMap<String, Node>,
+}
+
+impl Model {
+    fn new() -> Model {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("/".to_string(), Node::Dir);
+        Model { nodes }
+    }
+
+    /// Returns the node at the given path, if any, or the error of resolving its parent
+    fn lookup(&self, path: &str) -> Result<Option<&Node>, Errno> {
+        if path != "/" {
+            match self.lookup(parent_of(path))? {
+                None => return Err(Errno::ENOENT),
+                Some(Node::File(_)) => return Err(Errno::ENOTDIR),
+                Some(Node::Dir) => (),
+            }
+        }
+        Ok(self.nodes.get(path))
+    }
+
+    /// Returns the names in the given directory, sorted
+    fn names(&self, dir: &str) -> Vec<String> {
+        self.nodes.keys()
+            .filter(|path| *path != "/" && parent_of(path) == dir)
+            .map(|path| path[path.rfind('/').unwrap() + 1..].to_string())
+            .collect()
+    }
+
+    fn apply(&mut self, op: &Op) -> Result<Outcome, Errno> {
+        match op {
+            Op::Create(path) | Op::Mkdir(path) => {
+                if self.lookup(path)?.is_some() {
+                    return Err(Errno::EEXIST);
+                }
+                let node = if let Op::Create(_) = op { Node::File(Vec::new()) } else { Node::Dir };
+                self.nodes.insert(path.clone(), node);
+                Ok(Outcome::Done)
+            }
+            Op::Unlink(path) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::Dir) => Err(Errno::EISDIR),
+                Some(Node::File(_)) => {
+                    self.nodes.remove(path);
+                    Ok(Outcome::Done)
+                }
+            },
+            Op::Rmdir(path) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::File(_)) => Err(Errno::ENOTDIR),
+                Some(Node::Dir) if !self.names(path).is_empty() => Err(Errno::ENOTEMPTY),
+                Some(Node::Dir) => {
+                    self.nodes.remove(path);
+                    Ok(Outcome::Done)
+                }
+            },
+            Op::Rename(from, to) => {
+                // Both parents are resolved before the source is looked up
+                let source = self.lookup(from)?.cloned();
+                let target = self.lookup(to)?.cloned();
+                let source = source.ok_or(Errno::ENOENT)?;
+                if is_within(to, from) {
+                    return Err(Errno::EINVAL);
+                }
+                if is_within(from, to) {
+                    return Err(Errno::ENOTEMPTY);
+                }
+                if from == to {
+                    return Ok(Outcome::Done);
+                }
+                match (&source, &target) {
+                    (Node::Dir, Some(Node::File(_))) => return Err(Errno::ENOTDIR),
+                    (Node::File(_), Some(Node::Dir)) => return Err(Errno::EISDIR),
+                    (Node::Dir, Some(Node::Dir)) if !self.names(to).is_empty() => return Err(Errno::ENOTEMPTY),
+                    _ => (),
+                }
+                self.nodes.remove(to);
+                let moved: Vec<String> = self.nodes.keys().filter(|path| *path == from || is_within(path, from)).cloned().collect();
+                for path in moved {
+                    let node = self.nodes.remove(&path).unwrap();
+                    self.nodes.insert(format!("{}{}", to, &path[from.len()..]), node);
+                }
+                Ok(Outcome::Done)
+            }
+            Op::Write(path, offset, data) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::Dir) => Err(Errno::EISDIR),
+                Some(Node::File(_)) => {
+                    if let Some(Node::File(content)) = self.nodes.get_mut(path) {
+                        let end = *offset as usize + data.len();
+                        if content.len() < end {
+                            content.resize(end, 0);
+                        }
+                        content[*offset as usize..end].copy_from_slice(data);
+                    }
+                    Ok(Outcome::Written(data.len() as u32))
+                }
+            },
+            Op::Read(path) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::Dir) => Err(Errno::EISDIR),
+                Some(Node::File(content)) => Ok(Outcome::Data(content.clone())),
+            },
+            Op::Readdir(path) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::File(_)) => Err(Errno::ENOTDIR),
+                Some(Node::Dir) => Ok(Outcome::Names(self.names(path))),
+            },
+            Op::Getattr(path) => match self.lookup(path)? {
+                None => Err(Errno::ENOENT),
+                Some(Node::File(content)) => Ok(Outcome::Stat(FileType::RegularFile, Some(content.len() as u64))),
+                Some(Node::Dir) => Ok(Outcome::Stat(FileType::Directory, None)),
+            },
+        }
+    }
+}
+
+/// Path resolved by lookups: the parent directory, the last name and its inode and type
+struct Resolved<'a> {
+    parent: u64,
+    name: &'a str,
+    node: Option<(u64, FileType)>,
+}
+
+/// Applies operations to a filesystem like the kernel driver does
+struct Kernel<'a, FS: Filesystem + Send + Sync + 'static> {
+    ch: &'a MockChannel<FS>,
+    /// Inodes looked up by the current operation
+    lookups: Vec<u64>,
+}
+
+impl<FS: Filesystem + Send + Sync + 'static> Kernel<'_, FS> {
+    /// Look up the given name in the given directory, returning None if it doesn't exist
+    async fn entry(&mut self, parent: u64, name: &str) -> Result<Option<(u64, FileType)>, Errno> {
+        match self.ch.lookup(parent, name).await {
+            Ok(entry) if entry.is_negative() => Ok(None),
+            Ok(entry) => {
+                self.lookups.push(entry.attr.ino);
+                Ok(Some((entry.attr.ino, entry.attr.kind)))
+            }
+            Err(Errno::ENOENT) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Resolve the given path by looking up its components from the root
+    async fn resolve<'p>(&mut self, path: &'p str) -> Result<Resolved<'p>, Errno> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let (name, parents) = match names.split_last() {
+            Some((name, parents)) => (*name, parents),
+            None => return Ok(Resolved { parent: FUSE_ROOT_ID, name: "", node: Some((FUSE_ROOT_ID, FileType::Directory)) }),
+        };
+        let mut parent = FUSE_ROOT_ID;
+        for dir in parents {
+            parent = match self.entry(parent, dir).await? {
+                None => return Err(Errno::ENOENT),
+                Some((_, kind)) if kind != FileType::Directory => return Err(Errno::ENOTDIR),
+                Some((ino, _)) => ino,
+            };
+        }
+        let node = self.entry(parent, name).await?;
+        Ok(Resolved { parent, name, node })
+    }
+
+    /// Resolve the given path to an existing node of the given type, failing with the given
+    /// error if it has another type
+    async fn existing(&mut self, path: &str, kind: FileType, other: Errno) -> Result<u64, Errno> {
+        match self.resolve(path).await?.node {
+            None => Err(Errno::ENOENT),
+            Some((_, found)) if (found == FileType::Directory) != (kind == FileType::Directory) => Err(other),
+            Some((ino, _)) => Ok(ino),
+        }
+    }
+
+    async fn apply(&mut self, op: &Op) -> Result<Outcome, Errno> {
+        let ch = self.ch;
+        match op {
+            Op::Create(path) | Op::Mkdir(path) => {
+                let resolved = self.resolve(path).await?;
+                if resolved.node.is_some() {
+                    return Err(Errno::EEXIST);
+                }
+                let entry = match op {
+                    Op::Create(_) => ch.mknod(resolved.parent, resolved.name, FileType::RegularFile.mode() | 0o644, 0).await?,
+                    _ => ch.mkdir(resolved.parent, resolved.name, 0o755).await?,
+                };
+                self.lookups.push(entry.attr.ino);
+                Ok(Outcome::Done)
+            }
+            Op::Unlink(path) => {
+                let resolved = self.resolve(path).await?;
+                match resolved.node {
+                    None => Err(Errno::ENOENT),
+                    Some((_, FileType::Directory)) => Err(Errno::EISDIR),
+                    Some(_) => ch.unlink(resolved.parent, resolved.name).await.map(|()| Outcome::Done),
+                }
+            }
+            Op::Rmdir(path) => {
+                let resolved = self.resolve(path).await?;
+                match resolved.node {
+                    None => Err(Errno::ENOENT),
+                    Some((_, FileType::Directory)) => ch.rmdir(resolved.parent, resolved.name).await.map(|()| Outcome::Done),
+                    Some(_) => Err(Errno::ENOTDIR),
+                }
+            }
+            Op::Rename(from, to) => {
+                let source = self.resolve(from).await?;
+                let target = self.resolve(to).await?;
+                let (source_ino, source_kind) = source.node.ok_or(Errno::ENOENT)?;
+                if is_within(to, from) {
+                    return Err(Errno::EINVAL);
+                }
+                if is_within(from, to) {
+                    return Err(Errno::ENOTEMPTY);
+                }
+                match target.node {
+                    Some((ino, _)) if ino == source_ino => return Ok(Outcome::Done),
+                    Some((_, kind)) if source_kind == FileType::Directory && kind != FileType::Directory => return Err(Errno::ENOTDIR),
+                    Some((_, kind)) if source_kind != FileType::Directory && kind == FileType::Directory => return Err(Errno::EISDIR),
+                    _ => (),
+                }
+                ch.rename(source.parent, source.name, target.parent, target.name).await.map(|()| Outcome::Done)
+            }
+            Op::Write(path, offset, data) => {
+                let ino = self.existing(path, FileType::RegularFile, Errno::EISDIR).await?;
+                let opened = ch.open(ino, libc::O_WRONLY as u32).await?;
+                let written = ch.write(ino, opened.fh, *offset, data).await;
+                ch.release(ino, opened.fh).await?;
+                Ok(Outcome::Written(written?))
+            }
+            Op::Read(path) => {
+                let ino = self.existing(path, FileType::RegularFile, Errno::EISDIR).await?;
+                let opened = ch.open(ino, libc::O_RDONLY as u32).await?;
+                let data = ch.read(ino, opened.fh, 0, 1 << 16).await;
+                ch.release(ino, opened.fh).await?;
+                Ok(Outcome::Data(data?))
+            }
+            Op::Readdir(path) => {
+                let ino = self.existing(path, FileType::Directory, Errno::ENOTDIR).await?;
+                let opened = ch.opendir(ino, libc::O_RDONLY as u32).await?;
+                let mut names = Vec::new();
+                let mut offset = 0;
+                // Bounded, in case the filesystem doesn't advance through the entries
+                for _ in 0..64 {
+                    let entries = ch.readdir(ino, opened.fh, offset, 4096).await?;
+                    match entries.last() {
+                        None => break,
+                        Some(last) => offset = last.offset.raw(),
+                    }
+                    names.extend(entries.into_iter()
+                        .map(|entry| entry.name.to_string_lossy().into_owned())
+                        .filter(|name| name != "." && name != ".."));
+                }
+                ch.releasedir(ino, opened.fh).await?;
+                names.sort();
+                Ok(Outcome::Names(names))
+            }
+            Op::Getattr(path) => {
+                let ino = match self.resolve(path).await?.node {
+                    None => return Err(Errno::ENOENT),
+                    Some((ino, _)) => ino,
+                };
+                let attr = ch.getattr(ino).await?.attr;
+                let size = if attr.kind == FileType::Directory { None } else { Some(attr.size) };
+                Ok(Outcome::Stat(attr.kind, size))
+            }
+        }
+    }
+
+    /// Forget all lookups of the current operation
+    async fn forget(&mut self) {
+        for ino in self.lookups.drain(..) {
+            self.ch.forget(ino, 1).await;
+        }
+    }
+}
+
+/// Apply the given operations to the filesystem of the given initialized mock channel (which
+/// must be empty) and to the model, and return a description of the first operation whose
+/// result differs
+pub async fn check_sequence<FS: Filesystem + Send + Sync + 'static>(ch: &MockChannel<FS>, ops: &[Op]) -> Result<(), String> {
+    let mut model = Model::new();
+    let mut kernel = Kernel { ch, lookups: Vec::new() };
+    for (i, op) in ops.iter().enumerate() {
+        let expected = model.apply(op);
+        let result = kernel.apply(op).await;
+        kernel.forget().await;
+        if result != expected {
+            return Err(format!("operation {} {:?}: filesystem returned {:?}, model expected {:?}", i + 1, op, result, expected));
+        }
+    }
+    Ok(())
+}
+
+/// Model-based check of a filesystem with random operation sequences
+pub struct ModelCheck<F> {
+    new_filesystem: F,
+    cases: u32,
+    max_len: usize,
+}
+
+impl<FS, F> ModelCheck<F>
+where
+    FS: Filesystem + Send + Sync + 'static,
+    F: Fn() -> FS,
+{
+    /// Create a check of filesystems created by the given function (once per sequence), running
+    /// 256 sequences of up to 32 operations
+    pub fn new(new_filesystem: F) -> ModelCheck<F> {
+        ModelCheck { new_filesystem, cases: 256, max_len: 32 }
+    }
+
+    /// Set the number of sequences to run
+    pub fn cases(mut self, cases: u32) -> ModelCheck<F> {
+        self.cases = cases;
+        self
+    }
+
+    /// Set the maximum number of operations of a sequence
+    pub fn max_len(mut self, max_len: usize) -> ModelCheck<F> {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Run the sequences. Returns the first differing operation of the minimal failing
+    /// sequence and the sequence if any sequence failed.
+    pub fn run(&self) -> Result<(), String> {
+        let config = Config { cases: self.cases, failure_persistence: None, ..Config::default() };
+        let mut runner = TestRunner::new(config);
+        runner.run(&operations(self.max_len), |ops| {
+            let ch = MockChannel::new((self.new_filesystem)());
+            block_on(async {
+                ch.init().await.map_err(|err| format!("init failed: {:?}", err))?;
+                check_sequence(&ch, &ops).await
+            }).map_err(TestCaseError::fail)
+        }).map_err(|err| match err {
+            TestError::Fail(reason, ops) => format!("{} in sequence {:?}", reason.message(), ops),
+            TestError::Abort(reason) => reason.message().to_string(),
+        })
+    }
+}
+
+impl<F> fmt::Debug for ModelCheck<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelCheck").field("cases", &self.cases).field("max_len", &self.max_len).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::ffi::{OsStr, OsString};
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::{block_on, Attr, Data, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::{check_sequence, ModelCheck, Op};
+
+    enum Inode {
+        File(Vec<u8>),
+        Dir(BTreeMap<OsString, u64>),
+    }
+
+    /// In-memory filesystem, optionally removing non-empty directories
+    struct Tree {
+        inodes: Mutex<HashMap<u64, Inode>>,
+        rmdir_nonempty: bool,
+    }
+
+    impl Tree {
+        fn new(rmdir_nonempty: bool) -> Tree {
+            let mut inodes = HashMap::new();
+            inodes.insert(FUSE_ROOT_ID, Inode::Dir(BTreeMap::new()));
+            Tree { inodes: Mutex::new(inodes), rmdir_nonempty }
+        }
+
+        fn attr(inodes: &HashMap<u64, Inode>, ino: u64) -> Result<FileAttr, Errno> {
+            match inodes.get(&ino) {
+                Some(Inode::File(data)) => Ok(FileAttr::builder(ino, FileType::RegularFile).size(data.len() as u64).build()),
+                Some(Inode::Dir(_)) => Ok(FileAttr::builder(ino, FileType::Directory).build()),
+                None => Err(Errno::ENOENT),
+            }
+        }
+
+        fn dir(inodes: &mut HashMap<u64, Inode>, ino: u64) -> Result<&mut BTreeMap<OsString, u64>, Errno> {
+            match inodes.get_mut(&ino) {
+                Some(Inode::Dir(entries)) => Ok(entries),
+                _ => Err(Errno::ENOTDIR),
+            }
+        }
+
+        fn insert(&self, parent: u64, name: &OsStr, inode: Inode) -> Result<Entry, Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = inodes.keys().max().unwrap() + 1;
+            let entries = Tree::dir(&mut inodes, parent)?;
+            if entries.contains_key(name) {
+                return Err(Errno::EEXIST);
+            }
+            entries.insert(name.to_os_string(), ino);
+            inodes.insert(ino, inode);
+            Ok(Entry::new(Tree::attr(&inodes, ino)?, 0))
+        }
+    }
+
+    #[async_trait]
+    impl Filesystem for Tree {
+        async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = *Tree::dir(&mut inodes, parent)?.get(name).ok_or(Errno::ENOENT)?;
+            Ok(Entry::new(Tree::attr(&inodes, ino)?, 0))
+        }
+
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(Tree::attr(&self.inodes.lock().unwrap(), ino)?))
+        }
+
+        async fn mknod(&self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _rdev: u32) -> Result<Entry, Errno> {
+            self.insert(parent, name, Inode::File(Vec::new()))
+        }
+
+        async fn mkdir(&self, _req: &Request, parent: u64, name: &OsStr, _mode: u32) -> Result<Entry, Errno> {
+            self.insert(parent, name, Inode::Dir(BTreeMap::new()))
+        }
+
+        async fn unlink(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = Tree::dir(&mut inodes, parent)?.remove(name).ok_or(Errno::ENOENT)?;
+            inodes.remove(&ino);
+            Ok(())
+        }
+
+        async fn rmdir(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = *Tree::dir(&mut inodes, parent)?.get(name).ok_or(Errno::ENOENT)?;
+            if !self.rmdir_nonempty && !Tree::dir(&mut inodes, ino)?.is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            Tree::dir(&mut inodes, parent)?.remove(name);
+            inodes.remove(&ino);
+            Ok(())
+        }
+
+        async fn rename(&self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = *Tree::dir(&mut inodes, parent)?.get(name).ok_or(Errno::ENOENT)?;
+            if let Some(&target) = Tree::dir(&mut inodes, newparent)?.get(newname) {
+                if let Some(Inode::Dir(entries)) = inodes.get(&target) {
+                    if !entries.is_empty() {
+                        return Err(Errno::ENOTEMPTY);
+                    }
+                }
+                inodes.remove(&target);
+            }
+            Tree::dir(&mut inodes, parent)?.remove(name);
+            Tree::dir(&mut inodes, newparent)?.insert(newname.to_os_string(), ino);
+            Ok(())
+        }
+
+        async fn read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+            match self.inodes.lock().unwrap().get(&ino) {
+                Some(Inode::File(data)) => {
+                    let data = &data[(offset as usize).min(data.len())..];
+                    Ok(Data::from(data[..data.len().min(size as usize)].to_vec()))
+                }
+                _ => Err(Errno::EISDIR),
+            }
+        }
+
+        async fn write(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32) -> Result<u32, Errno> {
+            match self.inodes.lock().unwrap().get_mut(&ino) {
+                Some(Inode::File(content)) => {
+                    let end = offset as usize + data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[offset as usize..end].copy_from_slice(data);
+                    Ok(data.len() as u32)
+                }
+                _ => Err(Errno::EISDIR),
+            }
+        }
+
+        async fn readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let entries: Vec<(OsString, u64)> = Tree::dir(&mut inodes, ino)?.iter().map(|(name, &ino)| (name.clone(), ino)).collect();
+            Ok(entries.into_iter().enumerate().skip(offset.position(0)? as usize).map(|(i, (name, ino))| {
+                let kind = Tree::attr(&inodes, ino).map(|attr| attr.kind).unwrap_or(FileType::RegularFile);
+                DirEntry { ino, offset: DirOffset::new(i as u64 + 1, 0), kind, name }
+            }).collect())
+        }
+    }
+
+    #[test]
+    fn sequence() {
+        let ch = MockChannel::new(Tree::new(false));
+        block_on(ch.init()).unwrap();
+        let ops = [
+            Op::Mkdir("/a".into()),
+            Op::Create("/a/b".into()),
+            Op::Write("/a/b".into(), 2, b"xy".to_vec()),
+            Op::Rename("/a".into(), "/a/c".into()),
+            Op::Rename("/a/b".into(), "/a".into()),
+            Op::Rmdir("/a".into()),
+            Op::Rename("/a".into(), "/c".into()),
+            Op::Read("/c/b".into()),
+            Op::Getattr("/c/b".into()),
+            Op::Unlink("/c".into()),
+            Op::Readdir("/".into()),
+        ];
+        assert_eq!(block_on(check_sequence(&ch, &ops)), Ok(()));
+
+        // Results that differ from the model are reported
+        let ch = MockChannel::new(Tree::new(true));
+        block_on(ch.init()).unwrap();
+        let err = block_on(check_sequence(&ch, &ops)).unwrap_err();
+        assert!(err.starts_with("operation 6 Rmdir(\"/a\"): filesystem returned Ok(Done)"), "{}", err);
+    }
+
+    #[test]
+    fn model() {
+        ModelCheck::new(|| Tree::new(false)).cases(64).run().unwrap();
+        // Failing sequences are shrunk to (about) creating a directory and something in it,
+        // and removing the directory
+        let err = ModelCheck::new(|| Tree::new(true)).run().unwrap_err();
+        let (operation, sequence) = err.split_at(err.find(" in sequence ").unwrap());
+        assert!(operation.contains(": filesystem returned Ok(Done), model expected Err(Errno(39))"), "{}", err);
+        assert!(sequence.starts_with(" in sequence [Mkdir(") || sequence.contains(", Mkdir("), "{}", err);
+        assert!(sequence.ends_with(&format!("{}]", &operation[operation.find("Rmdir(").unwrap()..operation.find(':').unwrap()])), "{}", err);
+        assert!(sequence.matches("), ").count() < 4, "{}", err);
+    }
+}
@@ -5,35 +5,290 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
-use std::ffi::OsString;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
 use std::io;
-use std::fmt;
-use std::path::{PathBuf, Path};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::Path;
+#[cfg(feature = "tokio")]
+use std::path::PathBuf;
+use fuse_abi::fuse_opcode;
 use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::{error, info};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU32};
+use log::{debug, info};
+#[cfg(feature = "tokio")]
+use log::error;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::channel::{self, Channel};
-use crate::request::Request;
-use crate::Filesystem;
+#[cfg(feature = "tokio")]
+use crate::channel;
+use crate::admission::Admission;
+use crate::timeout::Timeouts;
+use crate::idmap::{HasAttr, IdMap};
+use crate::handoff::{Handoff, SessionState};
+use crate::health::HealthMonitor;
+use crate::cancel::CancellationToken;
+use crate::channel::{Channel, Transport, TransportSender};
+use crate::error::MountError;
+use crate::executor::{block_on, default_executor, Executor};
+use crate::interrupt::Interrupts;
+use crate::jsonlog::JsonLog;
+use crate::lifecycle::Lifecycle;
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+use crate::observer::Observer;
+use crate::pidns::PidNamespace;
+#[cfg(target_os = "linux")]
+use crate::passthrough::BackingFiles;
+use crate::resend::ReplyCache;
+use crate::record::{Recorder, RecordingSender};
+use crate::redact::Redaction;
+use crate::reply::{ReplySender, DEFAULT_TTL};
+use crate::request::{Hooks, Request};
+use crate::stats::SessionStats;
+use crate::tasks::{TaskHandle, Tasks};
+use crate::{Entry, Errno, FileType, Filesystem, InitFlags, FUSE_ROOT_ID};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// The max size of read requests passed to the filesystem, which the session buffers the
+/// reply data of. Reads can be limited further with the `max_read` mount option.
+pub const MAX_READ_SIZE: usize = 16 * 1024 * 1024;
 
-/// The session data structure
+/// The block size of filesystems mounted as block device (`blkdev` mount option) if no
+/// `blksize` option is given, which is the kernel's default
+pub const DEFAULT_BLKSIZE: u32 = 512;
+
+/// Extra space in the buffer for reading a request from the kernel. Since the kernel may
+/// send up to max write bytes in a write request, the buffer needs room for that value
+/// plus the request header and arguments.
+pub(crate) const BUFFER_HEADROOM: usize = 4096;
+
+/// Capability flags a session enables by default if the kernel supports them. We generally
+/// support async reads.
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_INIT_FLAGS: InitFlags = InitFlags::ASYNC_READ;
+// TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
+
+/// Capability flags a session enables by default if the kernel supports them. On macOS, we
+/// additionally support case insensitiveness, volume renames and xtimes.
+#[cfg(target_os = "macos")]
+pub const DEFAULT_INIT_FLAGS: InitFlags = InitFlags::from_bits(InitFlags::ASYNC_READ.bits() | InitFlags::CASE_INSENSITIVE.bits() | InitFlags::VOL_RENAME.bits() | InitFlags::XTIMES.bits());
+
+/// Limits the number of concurrently dispatched requests
+#[derive(Debug)]
+struct Workers {
+    max: usize,
+    busy: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl Workers {
+    fn new(max: usize) -> Workers {
+        Workers { max, busy: Mutex::new(0), idle: Condvar::new() }
+    }
+
+    /// Waits until a worker is available (blocking) and occupies it until the returned
+    /// guard is dropped
+    fn acquire(self: &Arc<Self>) -> WorkerGuard {
+        let mut busy = self.busy.lock().unwrap();
+        while *busy >= self.max {
+            busy = self.idle.wait(busy).unwrap();
+        }
+        *busy += 1;
+        WorkerGuard(self.clone())
+    }
+}
+
+/// Occupied worker, released when dropped
+#[derive(Debug)]
+struct WorkerGuard(Arc<Workers>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.idle.notify_one();
+    }
+}
+
+/// Operations the filesystem replied ENOSYS to, by opcode
+#[derive(Debug, Default)]
+pub(crate) struct Unimplemented {
+    opcodes: [AtomicU64; 2],
+}
+
+impl Unimplemented {
+    /// Remember that the operation with the given opcode is not implemented
+    pub(crate) fn insert(&self, opcode: fuse_opcode) {
+        let opcode = opcode as usize;
+        if opcode < 128 {
+            self.opcodes[opcode / 64].fetch_or(1 << (opcode % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns true if the operation with the given opcode is not implemented
+    pub(crate) fn contains(&self, opcode: fuse_opcode) -> bool {
+        let opcode = opcode as usize;
+        opcode < 128 && self.opcodes[opcode / 64].load(Ordering::Relaxed) & (1 << (opcode % 64)) != 0
+    }
+}
+
+/// Parents of directories known to the kernel driver, for the `..` entries of directory
+/// listings. Directories are added when looked up or created, moved when renamed and dropped
+/// when removed or forgotten, so that there's an entry for each directory the kernel driver
+/// may list.
+#[derive(Debug, Default)]
+pub(crate) struct Parents {
+    inner: Mutex<ParentsInner>,
+}
+
+#[derive(Debug, Default)]
+struct ParentsInner {
+    /// Parent and name of directories, by inode number
+    parents: HashMap<u64, (u64, OsString)>,
+    /// Inode numbers of directories, by parent and name
+    children: HashMap<(u64, OsString), u64>,
+}
+
+impl ParentsInner {
+    fn insert(&mut self, ino: u64, parent: u64, name: OsString) {
+        self.remove(ino);
+        if let Some(replaced) = self.children.insert((parent, name.clone()), ino) {
+            self.parents.remove(&replaced);
+        }
+        self.parents.insert(ino, (parent, name));
+    }
+
+    fn remove(&mut self, ino: u64) {
+        if let Some(key) = self.parents.remove(&ino) {
+            self.children.remove(&key);
+        }
+    }
+}
+
+impl Parents {
+    /// Remember the parent of the directory in the given result of a lookup or mkdir of the
+    /// given name in the given parent
+    pub(crate) fn looked_up(&self, parent: u64, name: &OsStr, result: &Result<Entry, Errno>) {
+        if let Ok(entry) = result {
+            if entry.attr.kind == FileType::Directory {
+                self.inner.lock().unwrap().insert(entry.attr.ino, parent, name.to_os_string());
+            }
+        }
+    }
+
+    /// Move the directory of the given name in the given parent after it was renamed. A
+    /// directory replaced by it is dropped.
+    pub(crate) fn renamed(&self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) {
+        let mut inner = self.inner.lock().unwrap();
+        let moved = inner.children.remove(&(parent, name.to_os_string()));
+        if let Some(replaced) = inner.children.remove(&(newparent, newname.to_os_string())) {
+            inner.parents.remove(&replaced);
+        }
+        if let Some(ino) = moved {
+            inner.insert(ino, newparent, newname.to_os_string());
+        }
+    }
+
+    /// Swap the directories of the given names after they were exchanged
+    pub(crate) fn exchanged(&self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) {
+        let mut inner = self.inner.lock().unwrap();
+        let first = inner.children.remove(&(parent, name.to_os_string()));
+        let second = inner.children.remove(&(newparent, newname.to_os_string()));
+        // Both are dropped before either is inserted, so that they don't drop each other
+        for ino in first.iter().chain(second.iter()) {
+            inner.parents.remove(ino);
+        }
+        if let Some(ino) = first {
+            inner.insert(ino, newparent, newname.to_os_string());
+        }
+        if let Some(ino) = second {
+            inner.insert(ino, parent, name.to_os_string());
+        }
+    }
+
+    /// Drop the directory of the given name in the given parent after it was removed
+    pub(crate) fn removed(&self, parent: u64, name: &OsStr) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(ino) = inner.children.remove(&(parent, name.to_os_string())) {
+            inner.parents.remove(&ino);
+        }
+    }
+
+    /// Drop the given inode after the kernel driver forgot it, it looks it up again before
+    /// listing it
+    pub(crate) fn forgotten(&self, ino: u64) {
+        self.inner.lock().unwrap().remove(ino);
+    }
+
+    /// Returns the parent of the given directory. The root directory is its own parent.
+    /// Directories the kernel driver knows without a lookup in this session (after resuming a
+    /// session handed off by another daemon) are reported in the root directory as well, since
+    /// their parents can't be known without asking the filesystem.
+    pub(crate) fn get(&self, ino: u64) -> u64 {
+        let parent = self.inner.lock().unwrap().parents.get(&ino).map(|(parent, _)| *parent);
+        parent.unwrap_or_else(|| {
+            if ino != FUSE_ROOT_ID {
+                debug!("Parent of directory {} is unknown, listing the root directory as its parent", ino);
+            }
+            FUSE_ROOT_ID
+        })
+    }
+
+    /// Returns the number of known directories
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().unwrap().parents.len()
+    }
+}
+
+/// Connection parameters negotiated with the kernel driver
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// FUSE protocol major version of the kernel driver
+    pub proto_major: u32,
+    /// FUSE protocol minor version of the kernel driver
+    pub proto_minor: u32,
+    /// Capability flags supported by the kernel driver, including flags without a constant in
+    /// `InitFlags` (`capable.bits()` are the raw flags of the init request)
+    pub capable: InitFlags,
+    /// Capability flags enabled for this session
+    pub want: InitFlags,
+    /// Maximum readahead size
+    pub max_readahead: u32,
+    /// Maximum size of write requests
+    pub max_write: u32,
+    /// Maximum size of read requests (the `max_read` mount option, up to 16M)
+    pub max_read: u32,
+    /// True if the kernel checks file permissions itself (`default_permissions` mount option)
+    pub default_permissions: bool,
+    /// True if files can be opened with a backing file (see `BackingFiles`)
+    pub passthrough: bool,
+    /// Block size of the device, if the filesystem is mounted as block device (`blkdev` mount
+    /// option), e.g. for attributes and `bmap`
+    pub blksize: Option<u32>,
+    /// Raw capability flags above bit 31 supported by the kernel driver (`flags2` of the init
+    /// request of ABI 7.36 and later, 0 for older kernels)
+    pub capable2: u32,
+    /// True if the kernel sends the group of the parent directory with requests creating
+    /// inodes, if the caller is a member of it through a supplementary group only (see
+    /// `Request::supp_group`)
+    pub create_supp_group: bool,
+}
+
+/// The session data structure. Requests are received through a transport, the channel to the
+/// kernel driver by default.
 #[derive(Debug)]
-pub struct Session<FS: Filesystem + Send + Sync + 'static> {
+pub struct Session<FS: Filesystem + Send + Sync + 'static, T: Transport = Channel> {
     /// Filesystem operation implementations
     pub filesystem: FS,
-    /// Communication channel to the kernel driver
-    ch: Channel,
+    /// Transport of requests and replies (none if driven by a `MockChannel`)
+    ch: Option<Arc<T>>,
     /// FUSE protocol major version
     pub proto_major: AtomicU32,
     /// FUSE protocol minor version
@@ -42,27 +297,255 @@ pub struct Session<FS: Filesystem + Send + Sync + 'static> {
     pub initialized: AtomicBool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: AtomicBool,
+    /// Time the kernel may cache names if the filesystem doesn't specify one
+    pub entry_ttl: Duration,
+    /// Time the kernel may cache attributes if the filesystem doesn't specify one
+    pub attr_ttl: Duration,
+    /// Maximum size of write requests (4k up to MAX_WRITE_SIZE)
+    pub max_write: u32,
+    /// Maximum number of concurrently dispatched requests (0 for unlimited)
+    pub workers: usize,
+    /// Capability flags to enable if the kernel supports them
+    pub init_flags: InitFlags,
+    /// Executor that runs dispatched requests
+    pub executor: Arc<dyn Executor>,
+    /// Dispatch every request to completion in the receive loop instead of spawning it on the
+    /// executor (off by default). Requests are handled one at a time in the order they were
+    /// received, without the overhead of a task per request. Interrupts are only received
+    /// after the operation completed, and the filesystem's futures can't depend on a runtime
+    /// of the executor.
+    pub inline_dispatch: bool,
+    /// Recorder of received requests and sent replies (none by default)
+    pub recorder: Option<Arc<Recorder>>,
+    /// Metrics of received requests and sent replies (none by default)
+    pub metrics: Option<Arc<Metrics>>,
+    /// Cumulative counters of received requests and sent replies (always kept)
+    pub stats: Arc<SessionStats>,
+    /// Log of completed operations as JSON lines (none by default)
+    pub json_log: Option<Arc<JsonLog>>,
+    /// Observer of completed operations (none by default)
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Per-user limits of requests (none by default)
+    pub admission: Option<Arc<Admission>>,
+    /// Timeouts of operations, after which the session gives up on them (none by default)
+    pub timeouts: Timeouts,
+    /// Hooks called when the filesystem is mounted and unmounted (none by default)
+    pub lifecycle: Lifecycle,
+    /// Redaction of file names in the log output of dispatched requests (off by default)
+    pub redaction: Redaction,
+    /// Mapping of the ids of the mount to the ids of the filesystem (none by default)
+    pub id_map: Option<Arc<IdMap>>,
+    /// Pid namespace the kernel driver tells the pids of callers in, that of the process that
+    /// opened the channel (the session's own by default)
+    pub pid_ns: Option<PidNamespace>,
+    /// Reply ENOSYS right away to operations the filesystem replied ENOSYS to before, without
+    /// dispatching them (like the kernel does for some operations, off by default)
+    pub cache_enosys: bool,
+    /// Add the `.` and `..` entries to directory listings at offsets 0 and 1, followed by the
+    /// entries of the filesystem with their offsets shifted by 2 (off by default)
+    pub dot_entries: bool,
+    /// Open files that are opened with O_DIRECT with `OpenFlags::DIRECT_IO`, so that the kernel
+    /// bypasses its page cache for them entirely (off by default)
+    pub direct_io_on_o_direct: bool,
+    /// Negotiate passthrough of reads and writes to backing files at init (off by default)
+    pub passthrough: bool,
+    /// Negotiate that the kernel sends the supplementary group of callers that matches the
+    /// group of the parent directory with requests creating inodes (off by default)
+    pub create_supp_group: bool,
+    /// Replies of the latest requests that change the filesystem, for answering them again if
+    /// the kernel resends them (none by default)
+    pub(crate) resend_replies: Option<Arc<ReplyCache>>,
+    /// Connection parameters, shared with requests
+    pub(crate) conn: Arc<RwLock<ConnectionInfo>>,
+    /// Requests being dispatched, which can be interrupted
+    pub(crate) interrupts: Arc<Interrupts>,
+    /// True while the session loop is running
+    pub(crate) running: Arc<AtomicBool>,
+    /// True if the kernel driver's ABI version was rejected at init
+    pub(crate) unsupported_abi: AtomicBool,
+    /// Cancelled when the filesystem is destroyed or the session ends, parent of the
+    /// cancellation tokens of all requests
+    pub(crate) shutdown: CancellationToken,
+    /// Operations the filesystem doesn't implement
+    pub(crate) unimplemented: Arc<Unimplemented>,
+    /// Parents of directories, if the session adds `.` and `..` entries
+    pub(crate) parents: Parents,
+    /// Background tasks of the filesystem
+    pub(crate) tasks: Arc<Tasks>,
 }
 
 impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
-    pub fn new(filesystem: FS, mountpoint: &Path, options: &[OsString]) -> io::Result<Session<FS>> {
+    pub fn new(filesystem: FS, mountpoint: &Path, options: &[OsString]) -> Result<Session<FS>, MountError> {
         info!("Mounting {}", mountpoint.display());
-        Channel::new(mountpoint, options).map(|ch| {
-            Session {
-                filesystem: filesystem,
-                ch: ch,
-                proto_major: AtomicU32::new(0),
-                proto_minor: AtomicU32::new(0),
-                initialized: AtomicBool::new(false),
-                destroyed: AtomicBool::new(false),
-            }
-        })
+        let ch = Channel::new(mountpoint, options)?;
+        Ok(Session::with_transport(filesystem, ch, options))
+    }
+
+    /// Create a new session for a filesystem that was mounted to the given mountpoint by
+    /// someone else, e.g. by the system for an Android app. The given fd is the channel to the
+    /// kernel driver (an opened /dev/fuse), the options are those the filesystem was mounted
+    /// with. The session doesn't unmount the filesystem when it ends.
+    pub fn from_fd(filesystem: FS, fd: OwnedFd, mountpoint: &Path, options: &[OsString]) -> Session<FS> {
+        info!("Serving {} from fd {}", mountpoint.display(), fd.as_raw_fd());
+        Session::with_transport(filesystem, Channel::from_fd(fd, mountpoint), options)
+    }
+
+    /// Create a new session resuming to serve a filesystem that another process served, from
+    /// the fd to the kernel driver and the session state it handed over (see `Handoff`). The
+    /// kernel initialized the connection already, so the filesystem isn't initialized again.
+    /// The session answers resent requests with the replies handed over, if the other process
+    /// remembered them.
+    pub fn resume_from_fd(filesystem: FS, fd: OwnedFd, state: &SessionState) -> Session<FS> {
+        info!("Resuming {} from fd {}", state.mountpoint.display(), fd.as_raw_fd());
+        let mut se = Session::with_transport(filesystem, Channel::from_fd(fd, &state.mountpoint), &[]);
+        se.proto_major = AtomicU32::new(state.conn.proto_major);
+        se.proto_minor = AtomicU32::new(state.conn.proto_minor);
+        se.initialized = AtomicBool::new(true);
+        se.init_flags = state.conn.want;
+        se.max_write = state.conn.max_write;
+        se.conn = Arc::new(RwLock::new(state.conn));
+        se.resend_replies = state.resend_replies.map(|capacity| Arc::new(ReplyCache::with_replies(capacity, &state.replies)));
+        se
+    }
+
+    /// Returns a handle for handing the session over to another process, e.g. a new version
+    /// of the daemon (none if the session isn't mounted)
+    pub fn handoff(&self) -> Option<Handoff> {
+        self.ch.as_ref().map(|ch| Handoff::new(ch.clone(), self.conn.clone(), self.resend_replies.clone()))
+    }
+
+    /// Returns a handle for registering backing files to open files with, if passthrough is
+    /// enabled (none if the session isn't mounted)
+    #[cfg(target_os = "linux")]
+    pub fn backing_files(&self) -> Option<BackingFiles> {
+        self.ch.as_ref().map(|ch| BackingFiles::new(ch.clone(), self.conn.clone()))
+    }
+
+    /// Returns a handle for sending notifications (e.g. cache invalidations) to the kernel
+    /// driver (none if the session isn't mounted)
+    pub fn notifier(&self) -> Option<Notifier> {
+        self.ch.as_ref().map(|ch| Notifier::new(ch.clone(), self.conn.clone()))
+    }
+}
+
+impl<FS: Filesystem + Send + Sync + 'static, T: Transport> Session<FS, T> {
+    /// Create a new session receiving requests through the given transport instead of a
+    /// kernel channel. The options are those the filesystem was mounted with.
+    pub fn with_transport(filesystem: FS, transport: T, options: &[OsString]) -> Session<FS, T> {
+        Session::with_channel(filesystem, Some(Arc::new(transport)), options)
+    }
+
+    /// Create a new session that isn't mounted. Requests must be passed to it by other
+    /// means than a kernel channel, like a `MockChannel` does in tests.
+    pub(crate) fn unmounted(filesystem: FS, options: &[OsString]) -> Session<FS, T> {
+        Session::with_channel(filesystem, None, options)
+    }
+
+    fn with_channel(filesystem: FS, ch: Option<Arc<T>>, options: &[OsString]) -> Session<FS, T> {
+        let max_read = option_value(options, "max_read").and_then(|max| max.parse().ok()).filter(|&max| max > 0);
+        let conn = ConnectionInfo {
+            max_read: max_read.map_or(MAX_READ_SIZE as u32, |max: u32| max.min(MAX_READ_SIZE as u32)),
+            default_permissions: has_option(options, "default_permissions"),
+            blksize: has_option(options, "blkdev").then(|| option_value(options, "blksize").and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_BLKSIZE)),
+            ..Default::default()
+        };
+        Session {
+            filesystem: filesystem,
+            ch: ch,
+            proto_major: AtomicU32::new(0),
+            proto_minor: AtomicU32::new(0),
+            initialized: AtomicBool::new(false),
+            destroyed: AtomicBool::new(false),
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
+            max_write: MAX_WRITE_SIZE as u32,
+            workers: 0,
+            inline_dispatch: false,
+            init_flags: DEFAULT_INIT_FLAGS,
+            executor: default_executor(),
+            recorder: None,
+            metrics: None,
+            stats: Arc::default(),
+            json_log: None,
+            observer: None,
+            admission: None,
+            timeouts: Timeouts::default(),
+            lifecycle: Lifecycle::new(),
+            redaction: Redaction::Off,
+            id_map: None,
+            pid_ns: PidNamespace::current().ok(),
+            cache_enosys: false,
+            dot_entries: false,
+            direct_io_on_o_direct: false,
+            passthrough: false,
+            create_supp_group: false,
+            resend_replies: None,
+            conn: Arc::new(RwLock::new(conn)),
+            interrupts: Arc::default(),
+            running: Arc::default(),
+            unsupported_abi: AtomicBool::new(false),
+            shutdown: CancellationToken::new(),
+            unimplemented: Arc::default(),
+            parents: Parents::default(),
+            tasks: Arc::default(),
+        }
     }
 
-    /// Return path of the mounted filesystem
+    /// Returns the connection parameters negotiated with the kernel driver
+    pub fn connection(&self) -> ConnectionInfo {
+        *self.conn.read().unwrap()
+    }
+
+    /// Return path of the mounted filesystem (empty if the session isn't mounted)
     pub fn mountpoint(&self) -> &Path {
-        &self.ch.mountpoint()
+        self.ch.as_ref().map_or(Path::new(""), |ch| ch.mountpoint())
+    }
+
+    /// Drop the privileges of the daemon to the given user and group once the channel is open,
+    /// e.g. after mounting as root with `allow_other`, optionally changing the root directory to
+    /// the given path first. Supplementary groups are replaced by the given group. Without
+    /// privileges, the session may not be able to unmount the filesystem when it ends, which is
+    /// then left to `fusermount -u` or `umount` run by root.
+    pub fn drop_privileges(&self, uid: u32, gid: u32, root: Option<&Path>) -> io::Result<()> {
+        info!("Dropping privileges to uid {} gid {}", uid, gid);
+        drop_privileges(uid, gid, root)
+    }
+
+    /// Returns a handle for checking the health of the session, which is stalled if a
+    /// dispatched request is pending for longer than the given time
+    pub fn health(&self, max_age: Duration) -> HealthMonitor {
+        HealthMonitor::new(self.running.clone(), self.interrupts.clone(), max_age)
+    }
+
+    /// Returns a handle for spawning tasks that are cancelled when the session ends
+    pub(crate) fn task_handle(&self) -> TaskHandle {
+        TaskHandle::new(self.executor.clone(), self.shutdown.clone(), self.tasks.clone())
+    }
+
+    /// Returns the components to tell about requests and replies
+    pub(crate) fn hooks(&self) -> Hooks {
+        Hooks {
+            metrics: self.metrics.clone(),
+            json_log: self.json_log.clone(),
+            observer: self.observer.as_ref().map(|observer| (observer.clone(), self.executor.clone())),
+            stats: self.stats.clone(),
+            replies: self.resend_replies.clone(),
+        }
+    }
+
+    /// Map the ids of the attributes in the given result of the filesystem to the ids of the
+    /// mount, if the mount is ID-mapped
+    pub(crate) fn map_ids<A: HasAttr>(&self, result: Result<A, Errno>) -> Result<A, Errno> {
+        match self.id_map {
+            Some(ref id_map) => id_map.outside(result),
+            None => result,
+        }
+    }
+
+    /// Returns the maximum size of write requests, limited to the supported range
+    pub(crate) fn max_write(&self) -> u32 {
+        self.max_write.clamp(4096, MAX_WRITE_SIZE as u32)
     }
 
     /// Run the session loop that receives kernel requests and dispatches them to method
@@ -70,75 +553,225 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     /// having multiple buffers (which take up much memory), but the filesystem methods
     /// may run concurrent by spawning threads.
     pub fn run(self) -> io::Result<()> {
-        // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
         let se = Arc::new(self);
-        loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match se.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(se.ch.sender(), &buffer) {
-                    // Dispatch request
-                    Some(req) => {
-                        let se = se.clone();
-                        tokio::spawn( async move { req.dispatch(se).await });
-                    },
-                    // Quit loop on illegal request
-                    None => break,
+        let workers = match se.workers {
+            0 => None,
+            max => Some(Arc::new(Workers::new(max))),
+        };
+        let executor = se.executor.clone();
+        let ch = se.ch.clone().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "session is not mounted"))?;
+        let sender: Arc<dyn ReplySender + Sync> = match se.recorder {
+            Some(ref recorder) => Arc::new(RecordingSender::new(TransportSender(ch.clone()), recorder.clone())),
+            None => Arc::new(TransportSender(ch.clone())),
+        };
+        se.running.store(true, Ordering::Release);
+        let res = receive_loop(&*ch, se.max_write() as usize + BUFFER_HEADROOM, |data| {
+            if let Some(ref recorder) = se.recorder { recorder.request(data); }
+            match Request::new(sender.clone(), data) {
+                // Operation known to be unimplemented, no need to dispatch it
+                Some(ref req) if req.reply_unimplemented(&se) => true,
+                // Dispatch request right away in inline mode
+                Some(req) if se.inline_dispatch => {
+                    block_on(req.dispatch(se.clone()));
+                    true
                 },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
-                }
+                // Dispatch request
+                Some(req) => {
+                    let se = se.clone();
+                    let worker = workers.as_ref().map(|workers| workers.acquire());
+                    executor.spawn(Box::pin(async move {
+                        req.dispatch(se).await;
+                        drop(worker);
+                    }));
+                    true
+                },
+                // Quit loop on illegal request
+                None => false,
             }
+        });
+        se.running.store(false, Ordering::Release);
+        // Nobody reads the replies of requests still being dispatched anymore
+        se.shutdown.cancel();
+        // Background tasks of the filesystem must not outlive it
+        se.tasks.wait();
+        if se.unsupported_abi.load(Ordering::Acquire) {
+            let (major, minor) = (se.proto_major.load(Ordering::Relaxed), se.proto_minor.load(Ordering::Relaxed));
+            return Err(MountError::UnsupportedAbi { major, minor }.into());
         }
-        Ok(())
+        res
     }
 }
 
-impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
+/// Receive kernel requests from the given transport and pass them to the given function until
+/// the filesystem is unmounted or the function returns false.
+pub(crate) fn receive_loop<T: Transport, F: FnMut(&[u8]) -> bool>(ch: &T, size: usize, mut f: F) -> io::Result<()> {
+    // Buffer for receiving requests from the kernel. Only one is allocated and
+    // it is reused immediately after dispatching to conserve memory and allocations.
+    let mut buffer: Vec<u8> = Vec::with_capacity(size);
+    loop {
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        match ch.receive(&mut buffer) {
+            Ok(()) => if !f(&buffer) { break },
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => continue,
+                // Interrupted system call, retry
+                Some(EINTR) => continue,
+                // Explicitly try again
+                Some(EAGAIN) => continue,
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => break,
+                // Unhandled error
+                _ => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+impl<FS: Filesystem + Send + Sync + 'static, T: Transport> Session<FS, T> {
     /// Run the session loop in a background thread
-    pub unsafe fn spawn(self) -> io::Result<BackgroundSession> {
+    pub fn spawn(self) -> io::Result<BackgroundSession> {
         BackgroundSession::new(self)
     }
 }
 
-impl<FS: Filesystem + Send + Sync + 'static> Drop for Session<FS> {
+/// Change the root directory and drop privileges to the given user and group. Groups are
+/// changed first, since changing them requires the privileges of the user.
+pub(crate) fn drop_privileges(uid: u32, gid: u32, root: Option<&Path>) -> io::Result<()> {
+    if let Some(root) = root {
+        let root = CString::new(root.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        if unsafe { libc::chroot(root.as_ptr()) } < 0 || unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let groups = [gid as libc::gid_t];
+    if unsafe { libc::setgroups(1, groups.as_ptr()) } < 0 || unsafe { libc::setgid(gid) } < 0 || unsafe { libc::setuid(uid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Make sure the privileges can't be regained
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::from_raw_os_error(libc::EPERM));
+    }
+    Ok(())
+}
+
+impl<FS: Filesystem + Send + Sync + 'static, T: Transport> Drop for Session<FS, T> {
     fn drop(&mut self) {
-        info!("Unmounted {}", self.mountpoint().display());
+        if let Some(ch) = self.ch.take() {
+            let mountpoint = ch.mountpoint().to_path_buf();
+            // Close the channel (unmounting the filesystem) before calling the hook
+            drop(ch);
+            info!("Unmounted {}", mountpoint.display());
+            self.lifecycle.unmounted(&mountpoint);
+        }
+    }
+}
+
+/// Returns the mount options given with `-o` in the given options
+pub(crate) fn mount_options(options: &[OsString]) -> Vec<String> {
+    let mut mount_options = Vec::new();
+    let mut args = options.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        let opts = match arg.strip_prefix("-o") {
+            Some("") => args.next().map(|opts| opts.into_owned()).unwrap_or_default(),
+            Some(opts) => opts.to_string(),
+            None => continue,
+        };
+        mount_options.extend(split_options(&opts));
     }
+    mount_options
+}
+
+/// Split the given comma separated mount options. Like libfuse does, a backslash escapes the
+/// following character, so that values can contain commas.
+fn split_options(opts: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    let mut option = String::new();
+    let mut chars = opts.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => option.extend(chars.next()),
+            ',' => options.push(std::mem::take(&mut option)),
+            c => option.push(c),
+        }
+    }
+    options.push(option);
+    options.retain(|opt| !opt.is_empty());
+    options
+}
+
+/// Escape commas and backslashes in the given mount option value with a backslash, so that
+/// libfuse doesn't split the option
+pub(crate) fn escape_option(value: &OsStr) -> OsString {
+    let mut escaped = Vec::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        if byte == b',' || byte == b'\\' {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    OsString::from_vec(escaped)
+}
+
+/// Returns true if the given mount option is set in the given `-o` options
+fn has_option(options: &[OsString], name: &str) -> bool {
+    mount_options(options).iter().any(|opt| opt == name)
+}
+
+/// Returns the value of the given `name=value` mount option in the given `-o` options (the
+/// last one if given more than once)
+fn option_value(options: &[OsString], name: &str) -> Option<String> {
+    mount_options(options).into_iter().rev()
+        .find_map(|opt| opt.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value.to_string()))
 }
 
 /// The background session data structure
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
 pub struct BackgroundSession {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
     /// handle of the background session
     pub handle: tokio::task::JoinHandle<Result<(), std::io::Error>>,
+    /// Cumulative counters of the session
+    pub stats: Arc<SessionStats>,
+    /// Cancelled when the session ends
+    ended: CancellationToken,
 }
 
+#[cfg(feature = "tokio")]
 impl BackgroundSession {
     /// Create a new background session for the given session by running its
     /// session loop in a background thread. If the returned handle is dropped,
-    /// the filesystem is unmounted and the given session ends.
-    pub unsafe fn new<FS: Filesystem + Send + Sync + 'static>(se: Session<FS>) -> io::Result<BackgroundSession> {
+    /// the filesystem is unmounted and the given session ends. The session owns
+    /// the channel and runs on a blocking thread of the Tokio runtime, so this
+    /// must be called from within the runtime. Outside of it, an error is returned
+    /// and the session is dropped.
+    pub fn new<FS: Filesystem + Send + Sync + 'static, T: Transport>(se: Session<FS, T>) -> io::Result<BackgroundSession> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(io::Error::other)?;
         let mountpoint = se.mountpoint().to_path_buf();
-        let handle = tokio::spawn ( async move { se.run() } );
-        Ok(BackgroundSession { mountpoint: mountpoint, handle: handle })
+        let stats = se.stats.clone();
+        let ended = se.shutdown.clone();
+        let handle = runtime.spawn_blocking(move || se.run());
+        Ok(BackgroundSession { mountpoint, handle, stats, ended })
+    }
+
+    /// Waits until the filesystem is unmounted, i.e. the kernel driver closed the connection
+    /// (after unmounting with `fusermount -u` or `umount`) or destroyed the filesystem
+    pub async fn wait_unmounted(&self) {
+        self.ended.cancelled().await
     }
 }
 
+#[cfg(feature = "tokio")]
 impl Drop for BackgroundSession {
     fn drop(&mut self) {
+        if self.ended.is_cancelled() {
+            return;
+        }
         info!("Unmounting {}", self.mountpoint.display());
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
@@ -149,10 +782,363 @@ impl Drop for BackgroundSession {
     }
 }
 
-// replace with #[derive(Debug)] if Debug ever gets implemented for
-// thread_scoped::JoinGuard
-impl fmt::Debug for BackgroundSession {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "BackgroundSession {{ mountpoint: {:?}, guard: JoinGuard<()> }}", self.mountpoint)
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::ffi::{OsStr, OsString};
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use fuse_abi::{fuse_getxattr_in, fuse_in_header, fuse_init_in, fuse_opcode};
+    use crate::{block_on, Attr, DirEntry, DirOffset, Entry, Errno, FileAttr, FileType, Filesystem, Health, InitFlags, Lifecycle, MockChannel, MockReply, MountError, OperationSet, Request, ThreadExecutor, Transport, Xattr, FUSE_ROOT_ID};
+    use super::{drop_privileges, escape_option, has_option, mount_options, option_value, Session, Workers};
+
+    #[test]
+    fn options() {
+        let options: Vec<OsString> = vec!["-o".into(), "ro,default_permissions".into(), "-oallow_other".into()];
+        assert!(has_option(&options, "default_permissions"));
+        assert!(has_option(&options, "allow_other"));
+        assert!(has_option(&options, "ro"));
+        assert!(!has_option(&options, "default"));
+        assert!(!has_option(&["default_permissions".into()], "default_permissions"));
+        let options: Vec<OsString> = vec!["-o".into(), "max_read=4096,ro".into(), "-omax_read=8192".into()];
+        assert_eq!(option_value(&options, "max_read").as_deref(), Some("8192"));
+        assert_eq!(option_value(&options, "ro"), None);
+        assert_eq!(option_value(&options, "max"), None);
+    }
+
+    #[test]
+    fn escaped_options() {
+        let name = escape_option(OsStr::new(r"a,b\c"));
+        assert_eq!(name, OsStr::new(r"a\,b\\c"));
+        let mut option = OsString::from("-ofsname=");
+        option.push(&name);
+        let options: Vec<OsString> = vec![option, "-o".into(), r"ro,subtype=x\,y,,".into()];
+        assert_eq!(mount_options(&options), [r"fsname=a,b\c", "ro", "subtype=x,y"]);
+    }
+
+    #[test]
+    fn blkdev() {
+        let options: Vec<OsString> = vec!["-o".into(), "blkdev,fsname=/dev/loop0,blksize=4096".into()];
+        assert_eq!(Session::<NoXattr>::unmounted(NoXattr::default(), &options).connection().blksize, Some(4096));
+        let options: Vec<OsString> = vec!["-o".into(), "blkdev,fsname=/dev/loop0".into()];
+        assert_eq!(Session::<NoXattr>::unmounted(NoXattr::default(), &options).connection().blksize, Some(512));
+        assert_eq!(Session::<NoXattr>::unmounted(NoXattr::default(), &[]).connection().blksize, None);
+    }
+
+    #[test]
+    fn workers() {
+        let workers = Arc::new(Workers::new(2));
+        let first = workers.acquire();
+        let second = workers.acquire();
+        assert_eq!(*workers.busy.lock().unwrap(), 2);
+        let waiting = {
+            let workers = workers.clone();
+            std::thread::spawn(move || drop(workers.acquire()))
+        };
+        drop(first);
+        waiting.join().unwrap();
+        drop(second);
+        assert_eq!(*workers.busy.lock().unwrap(), 0);
+    }
+
+    /// Filesystem without xattr support that counts how often it is asked for one
+    #[derive(Default)]
+    struct NoXattr(AtomicUsize);
+
+    #[async_trait]
+    impl Filesystem for NoXattr {
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::Directory).build()))
+        }
+
+        async fn getxattr(&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(Errno::ENOSYS)
+        }
+    }
+
+    fn getxattr<FS: Filesystem + Send + Sync + 'static>(ch: &MockChannel<FS>) -> Errno {
+        let arg = [0; std::mem::size_of::<fuse_getxattr_in>()];
+        let reply = block_on(ch.request(fuse_opcode::FUSE_GETXATTR as u32, FUSE_ROOT_ID, &[&arg[..], b"user.foo\0"].concat()));
+        reply.unwrap().result().unwrap_err()
+    }
+
+    #[test]
+    fn cache_enosys() {
+        let ch = MockChannel::new(NoXattr::default()).cache_enosys(true);
+        block_on(ch.init()).unwrap();
+        assert_eq!(getxattr(&ch), Errno::ENOSYS);
+        assert_eq!(getxattr(&ch), Errno::ENOSYS);
+        assert_eq!(ch.session().filesystem.0.load(Ordering::Relaxed), 1);
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+
+        // Without caching, every request is dispatched
+        let ch = MockChannel::new(NoXattr::default());
+        block_on(ch.init()).unwrap();
+        assert_eq!(getxattr(&ch), Errno::ENOSYS);
+        assert_eq!(getxattr(&ch), Errno::ENOSYS);
+        assert_eq!(ch.session().filesystem.0.load(Ordering::Relaxed), 2);
+    }
+
+    /// Filesystem that declares to implement getattr only
+    #[derive(Default)]
+    struct GetattrOnly(NoXattr);
+
+    #[async_trait]
+    impl Filesystem for GetattrOnly {
+        fn operations(&self) -> OperationSet {
+            OperationSet::GETATTR
+        }
+
+        async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+            self.0.getattr(req, ino).await
+        }
+
+        async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+            self.0.getxattr(req, ino, name, size).await
+        }
+    }
+
+    #[test]
+    fn operations() {
+        let ch = MockChannel::new(GetattrOnly::default()).init_flags(InitFlags::ASYNC_READ | InitFlags::POSIX_LOCKS);
+        let conn = block_on(ch.init_capable(InitFlags::from_bits(!0))).unwrap();
+        // The kernel handles locks itself and doesn't need to send opens
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(conn.want, InitFlags::ASYNC_READ | InitFlags::NO_OPEN_SUPPORT | InitFlags::NO_OPENDIR_SUPPORT);
+        #[cfg(target_os = "macos")]
+        assert_eq!(conn.want, InitFlags::ASYNC_READ);
+        // Other operations than getattr aren't dispatched
+        assert_eq!(getxattr(&ch), Errno::ENOSYS);
+        assert_eq!(ch.session().filesystem.0.0.load(Ordering::Relaxed), 0);
+        assert!(block_on(ch.getattr(FUSE_ROOT_ID)).is_ok());
+    }
+
+    /// Filesystem of directories containing two files, whose inodes depend on their names only
+    struct Tree;
+
+    #[async_trait]
+    impl Filesystem for Tree {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            let ino = match name.to_str() {
+                Some("other") => 5,
+                Some("deep") | Some("moved") => 6,
+                _ => 2,
+            };
+            Ok(Entry::new(FileAttr::builder(ino, FileType::Directory).build(), 0))
+        }
+
+        async fn rename(&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        async fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        async fn readdir(&self, _req: &Request, _ino: u64, _fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+            let entries = ["a", "b"].iter().enumerate().map(|(i, name)| DirEntry { ino: 3 + i as u64, offset: DirOffset::new(i as u64 + 1, 0), kind: FileType::RegularFile, name: name.into() });
+            Ok(entries.skip(offset.position(0)? as usize).collect())
+        }
+    }
+
+    #[test]
+    fn dot_entries() {
+        let ch = MockChannel::new(Tree).dot_entries(true);
+        block_on(ch.init()).unwrap();
+        let names = |entries: &[DirEntry]| entries.iter().map(|entry| (entry.ino, entry.name.to_str().unwrap().to_string())).collect::<Vec<_>>();
+        let entries = block_on(ch.readdir(FUSE_ROOT_ID, 0, 0, 4096)).unwrap();
+        assert_eq!(names(&entries), [(1, ".".into()), (1, "..".into()), (3, "a".into()), (4, "b".to_string())]);
+        assert_eq!((entries[0].offset.raw(), entries[1].offset.raw()), (1, 2));
+        // The parent of a directory is known once it's looked up
+        block_on(ch.lookup(FUSE_ROOT_ID, "sub")).unwrap();
+        assert_eq!(names(&block_on(ch.readdir(2, 0, 1, 4096)).unwrap()), [(1, "..".into()), (3, "a".into()), (4, "b".to_string())]);
+        assert_eq!(names(&block_on(ch.readdir(2, 0, entries[2].offset.raw(), 4096)).unwrap()), [(4, "b".to_string())]);
+        let parent = |ino| block_on(ch.readdir(ino, 0, 1, 4096)).unwrap()[0].ino;
+        block_on(ch.lookup(2, "deep")).unwrap();
+        block_on(ch.lookup(FUSE_ROOT_ID, "other")).unwrap();
+        assert_eq!(parent(6), 2);
+        // Renaming a directory moves it, replacing a directory drops that
+        block_on(ch.rename(2, "deep", 5, "moved")).unwrap();
+        assert_eq!(parent(6), 5);
+        block_on(ch.rename(FUSE_ROOT_ID, "sub", FUSE_ROOT_ID, "other")).unwrap();
+        assert_eq!(ch.session().parents.len(), 2);
+        // Removed and forgotten directories are dropped
+        block_on(ch.rmdir(5, "moved")).unwrap();
+        assert_eq!(ch.session().parents.len(), 1);
+        block_on(ch.forget(2, 1));
+        assert_eq!(ch.session().parents.len(), 0);
+
+        // Without the option, the entries of the filesystem are listed only
+        let ch = MockChannel::new(Tree);
+        block_on(ch.init()).unwrap();
+        let entries = block_on(ch.readdir(FUSE_ROOT_ID, 0, 0, 4096)).unwrap();
+        assert_eq!(names(&entries), [(3, "a".into()), (4, "b".to_string())]);
+        assert_eq!(entries[0].offset, DirOffset::new(1, 0));
+    }
+
+    #[test]
+    fn from_fd() {
+        // A connected socket pair stands in for /dev/fuse, with the kernel at the other end
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) }, 0);
+        let (kernel, fd) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let mut se = Session::from_fd(NoXattr::default(), fd, Path::new("/mnt"), &[]);
+        se.executor = Arc::new(ThreadExecutor);
+        assert_eq!(se.mountpoint(), Path::new("/mnt"));
+        let session = thread::spawn(move || se.run());
+
+        let header = fuse_in_header { len: 56, opcode: fuse_opcode::FUSE_INIT as u32, unique: 1, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let arg = fuse_init_in { major: 7, minor: 12, max_readahead: 4096, flags: 0 };
+        let request = unsafe { [as_bytes(&header), as_bytes(&arg)].concat() };
+        assert_eq!(unsafe { libc::write(kernel.as_raw_fd(), request.as_ptr() as *const _, request.len()) }, 56);
+        let mut reply = [0u8; 256];
+        let len = unsafe { libc::read(kernel.as_raw_fd(), reply.as_mut_ptr() as *mut _, reply.len()) };
+        assert!(len >= 16);
+        // Successful reply (error 0) to the init request (unique 1)
+        assert_eq!(&reply[4..16], &[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // The session ends when the other end goes away, without unmounting anything
+        drop(kernel);
+        session.join().unwrap().unwrap();
+    }
+
+    /// Transport handing out queued requests and collecting the replies
+    #[derive(Debug, Default)]
+    struct QueueTransport {
+        requests: Mutex<VecDeque<Vec<u8>>>,
+        replies: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl Transport for QueueTransport {
+        fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+            match self.requests.lock().unwrap().pop_front() {
+                Some(request) => { buffer.clear(); buffer.extend_from_slice(&request); Ok(()) },
+                None => Err(io::Error::from_raw_os_error(libc::ENODEV)),
+            }
+        }
+
+        fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+            self.replies.lock().unwrap().push(data.concat());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transport() {
+        let header = fuse_in_header { len: 56, opcode: fuse_opcode::FUSE_INIT as u32, unique: 1, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let arg = fuse_init_in { major: 7, minor: 12, max_readahead: 4096, flags: 0 };
+        let transport = QueueTransport::default();
+        transport.requests.lock().unwrap().push_back(unsafe { [as_bytes(&header), as_bytes(&arg)].concat() });
+        let mut se = Session::with_transport(NoXattr::default(), transport, &[]);
+        se.executor = Arc::new(ThreadExecutor);
+        assert_eq!(se.mountpoint(), Path::new(""));
+        let ch = se.ch.clone().unwrap();
+        let health = se.health(Duration::from_secs(1));
+        // The session ends when the transport runs out of requests
+        se.run().unwrap();
+        assert_eq!(health.check(), Health::Stopped);
+
+        // Wait for the init request dispatched to another thread to be replied to
+        while ch.replies.lock().unwrap().is_empty() { thread::yield_now(); }
+        let reply = MockReply::new(ch.replies.lock().unwrap()[0].clone());
+        assert_eq!(reply.unique(), 1);
+        assert!(reply.result().is_ok());
+    }
+
+    #[test]
+    fn inline_dispatch() {
+        let header = fuse_in_header { len: 56, opcode: fuse_opcode::FUSE_INIT as u32, unique: 1, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let arg = fuse_init_in { major: 7, minor: 12, max_readahead: 4096, flags: 0 };
+        let transport = QueueTransport::default();
+        transport.requests.lock().unwrap().push_back(unsafe { [as_bytes(&header), as_bytes(&arg)].concat() });
+        let header = fuse_in_header { len: 40, opcode: fuse_opcode::FUSE_GETATTR as u32, unique: 2, nodeid: FUSE_ROOT_ID, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        transport.requests.lock().unwrap().push_back(unsafe { as_bytes(&header) }.to_vec());
+        let mut se = Session::with_transport(NoXattr::default(), transport, &[]);
+        se.inline_dispatch = true;
+        let ch = se.ch.clone().unwrap();
+        se.run().unwrap();
+        // Every request was replied to in order before the session ended
+        let replies = ch.replies.lock().unwrap();
+        let uniques: Vec<_> = replies.iter().map(|reply| MockReply::new(reply.clone()).unique()).collect();
+        assert_eq!(uniques, [1, 2]);
+    }
+
+    #[test]
+    fn unsupported_abi() {
+        let header = fuse_in_header { len: 56, opcode: fuse_opcode::FUSE_INIT as u32, unique: 1, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let arg = fuse_init_in { major: 7, minor: 5, max_readahead: 4096, flags: 0 };
+        let transport = QueueTransport::default();
+        transport.requests.lock().unwrap().push_back(unsafe { [as_bytes(&header), as_bytes(&arg)].concat() });
+        let mut se = Session::with_transport(NoXattr::default(), transport, &[]);
+        se.executor = Arc::new(block_on);
+        let err = se.run().unwrap_err();
+        let err = err.get_ref().and_then(|err| err.downcast_ref::<MountError>());
+        assert!(matches!(err, Some(MountError::UnsupportedAbi { major: 7, minor: 5 })));
+    }
+
+    #[test]
+    fn lifecycle() {
+        let header = fuse_in_header { len: 56, opcode: fuse_opcode::FUSE_INIT as u32, unique: 1, nodeid: 0, uid: 0, gid: 0, pid: 0, total_extlen: 0, padding: 0 };
+        let arg = fuse_init_in { major: 7, minor: 12, max_readahead: 4096, flags: 0 };
+        let transport = QueueTransport::default();
+        transport.requests.lock().unwrap().push_back(unsafe { [as_bytes(&header), as_bytes(&arg)].concat() });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (mounted, unmounted) = (events.clone(), events.clone());
+        let mut se = Session::with_transport(NoXattr::default(), transport, &[]);
+        se.executor = Arc::new(block_on);
+        se.lifecycle = Lifecycle::new()
+            .on_mounted(move |_| mounted.lock().unwrap().push("mounted"))
+            .on_unmounted(move |_| unmounted.lock().unwrap().push("unmounted"));
+        // The session ends and is dropped when the transport runs out of requests
+        se.run().unwrap();
+        assert_eq!(*events.lock().unwrap(), ["mounted", "unmounted"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn wait_unmounted() {
+        // The session ends right away since the transport has no requests
+        let se = Session::with_transport(NoXattr::default(), QueueTransport::default(), &[]);
+        let background = super::BackgroundSession::new(se).unwrap();
+        background.wait_unmounted().await;
+        background.wait_unmounted().await;
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn background_without_runtime() {
+        let se = Session::with_transport(NoXattr::default(), QueueTransport::default(), &[]);
+        let err = super::BackgroundSession::new(se).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    unsafe fn as_bytes<T>(value: &T) -> &[u8] {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    }
+
+    #[test]
+    fn privileges() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        // Dropping privileges affects the whole process, so do it in a child
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let dropped = drop_privileges(65534, 65533, Some(Path::new("/"))).is_ok();
+            let ids = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getgroups(0, std::ptr::null_mut())) };
+            let regained = unsafe { libc::setuid(0) } == 0;
+            unsafe { libc::_exit(if dropped && ids == (65534, 65534, 65533, 1) && !regained { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert_eq!(libc::WEXITSTATUS(status), 0);
     }
 }
@@ -0,0 +1,156 @@
+//! Session statistics
+//!
+//! Every session keeps a few cumulative counters (requests, bytes received and sent, errors by
+//! errno, requests in flight) that a daemon can report without setting up `Metrics`. They're
+//! shared with the session as `Session::stats`, and with `BackgroundSession::stats`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Errno;
+
+/// Number of errnos replies are counted for (higher errnos are counted as the last one)
+const ERRNOS: usize = 256;
+
+/// Cumulative counters of a session
+pub struct SessionStats {
+    requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    in_flight: AtomicU64,
+    errors: Vec<AtomicU64>,
+}
+
+impl SessionStats {
+    /// Create zeroed counters
+    pub fn new() -> SessionStats {
+        SessionStats {
+            requests: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            errors: (0..ERRNOS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Count a received request of the given length
+    pub(crate) fn request(&self, len: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count a request being dispatched, until the returned guard is dropped
+    pub(crate) fn dispatch(self: Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    /// Count a sent reply of the given length with the given error (0 for success)
+    pub(crate) fn reply(&self, len: usize, errno: i32) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+        if errno > 0 {
+            self.errors[(errno as usize).min(ERRNOS - 1)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of received requests
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes received from the kernel driver (requests with headers)
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes sent to the kernel driver (replies with headers)
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests being dispatched to the filesystem
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of replies with each error that was replied at least once
+    pub fn errors(&self) -> Vec<(Errno, u64)> {
+        self.errors.iter().enumerate().filter_map(|(errno, count)| {
+            let count = count.load(Ordering::Relaxed);
+            if count == 0 { return None; }
+            Some((Errno::from_raw(errno as i32), count))
+        }).collect()
+    }
+
+    /// Returns the total number of replies with an error
+    pub fn error_count(&self) -> u64 {
+        self.errors.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> SessionStats {
+        SessionStats::new()
+    }
+}
+
+impl fmt::Debug for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("SessionStats")
+            .field("requests", &self.requests())
+            .field("bytes_in", &self.bytes_in())
+            .field("bytes_out", &self.bytes_out())
+            .field("in_flight", &self.in_flight())
+            .field("errors", &self.errors())
+            .finish()
+    }
+}
+
+/// Request being dispatched
+#[derive(Debug)]
+pub(crate) struct InFlight(Arc<SessionStats>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use async_trait::async_trait;
+    use crate::{block_on, Entry, Errno, FileAttr, FileType, Filesystem, MockChannel, Request};
+
+    /// Filesystem with a file named `foo` of inode 2 in the root directory
+    struct Foo;
+
+    #[async_trait]
+    impl Filesystem for Foo {
+        async fn lookup(&self, _req: &Request, _parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+            match name.to_str() {
+                Some("foo") => Ok(Entry::new(FileAttr::builder(2, FileType::RegularFile).build(), 0)),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mock = MockChannel::new(Foo);
+        block_on(mock.init()).unwrap();
+        block_on(mock.lookup(1, "foo")).unwrap();
+        assert_eq!(block_on(mock.lookup(1, "bar")), Err(Errno::ENOENT));
+        assert_eq!(block_on(mock.getattr(2)), Err(Errno::ENOSYS));
+        let stats = &mock.session().stats;
+        assert_eq!(stats.requests(), 4);
+        assert_eq!(stats.in_flight(), 0);
+        assert_eq!(stats.errors(), [(Errno::ENOENT, 1), (Errno::ENOSYS, 1)]);
+        assert_eq!(stats.error_count(), 2);
+        // Requests: 40 bytes headers, 16 bytes init argument, 4 bytes names with nul
+        assert_eq!(stats.bytes_in(), 4 * 40 + 16 + 4 + 4);
+        assert!(stats.bytes_out() > 4 * 16);
+    }
+}
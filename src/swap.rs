@@ -0,0 +1,320 @@
+//! Swapping filesystems at runtime
+//!
+//! A filesystem may need to be replaced while it's mounted, e.g. to apply a new configuration
+//! or to fail over to another backend, without unmounting it and disturbing the processes using
+//! it. `Swappable` passes every operation to the filesystem installed when the operation
+//! starts, and a `SwapHandle` installs another one. Requests in flight finish with the
+//! filesystem they started with, which is dropped once they're done.
+//!
+//! The kernel keeps inode numbers and file handles across the swap, so the new filesystem has
+//! to know them (e.g. by sharing an `InodeTable` and the open files with the old one). It isn't
+//! initialized by the session, and only the filesystem installed when the session ends is
+//! destroyed.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use async_trait::async_trait;
+
+#[cfg(target_os = "macos")]
+use crate::XTimes;
+#[cfg(not(target_os = "macos"))]
+use crate::DirEntryPlus;
+use crate::{Attr, Created, Data, DirEntry, DirOffset, Entry, Errno, Filesystem, Ioctl, IoctlFlags, Lock, Opened, Request, Statfs, Xattr};
+
+/// Filesystem passing operations to a filesystem that can be swapped at runtime. Filesystems
+/// of different types can be swapped with `Swappable<dyn Filesystem + Send + Sync>`.
+pub struct Swappable<FS: ?Sized> {
+    current: Arc<RwLock<Arc<FS>>>,
+}
+
+impl<FS: ?Sized> Swappable<FS> {
+    /// Pass operations to the given filesystem until another one is installed
+    pub fn new(filesystem: Arc<FS>) -> Swappable<FS> {
+        Swappable { current: Arc::new(RwLock::new(filesystem)) }
+    }
+
+    /// Returns a handle for installing other filesystems
+    pub fn handle(&self) -> SwapHandle<FS> {
+        SwapHandle { current: self.current.clone() }
+    }
+
+    /// Returns the installed filesystem
+    pub fn current(&self) -> Arc<FS> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl<FS: ?Sized> fmt::Debug for Swappable<FS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str("Swappable")
+    }
+}
+
+/// Handle for installing another filesystem in a `Swappable`
+pub struct SwapHandle<FS: ?Sized> {
+    current: Arc<RwLock<Arc<FS>>>,
+}
+
+impl<FS: ?Sized> SwapHandle<FS> {
+    /// Install the given filesystem for operations starting from now on, and return the
+    /// previously installed one
+    pub fn swap(&self, filesystem: Arc<FS>) -> Arc<FS> {
+        std::mem::replace(&mut *self.current.write().unwrap(), filesystem)
+    }
+
+    /// Returns the installed filesystem
+    pub fn current(&self) -> Arc<FS> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl<FS: ?Sized> Clone for SwapHandle<FS> {
+    fn clone(&self) -> SwapHandle<FS> {
+        SwapHandle { current: self.current.clone() }
+    }
+}
+
+impl<FS: ?Sized> fmt::Debug for SwapHandle<FS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str("SwapHandle")
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync + ?Sized> Filesystem for Swappable<FS> {
+    async fn init(&self, req: &Request) -> Result<(), Errno> {
+        self.current().init(req).await
+    }
+
+    async fn destroy(&self, req: &Request) {
+        self.current().destroy(req).await
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        self.current().lookup(req, parent, name).await
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        self.current().forget(req, ino, nlookup).await
+    }
+
+    async fn getattr(&self, req: &Request, ino: u64) -> Result<Attr, Errno> {
+        self.current().getattr(req, ino).await
+    }
+
+    async fn setattr(&self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Attr, Errno> {
+        self.current().setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags).await
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.current().readlink(req, ino).await
+    }
+
+    async fn mknod(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<Entry, Errno> {
+        self.current().mknod(req, parent, name, mode, rdev).await
+    }
+
+    async fn mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> Result<Entry, Errno> {
+        self.current().mkdir(req, parent, name, mode).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.current().unlink(req, parent, name).await
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        self.current().rmdir(req, parent, name).await
+    }
+
+    async fn symlink(&self, req: &Request, parent: u64, name: &OsStr, link: &Path) -> Result<Entry, Errno> {
+        self.current().symlink(req, parent, name, link).await
+    }
+
+    async fn rename(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), Errno> {
+        self.current().rename(req, parent, name, newparent, newname).await
+    }
+
+    async fn link(&self, req: &Request, ino: u64, newparent: u64, newname: &OsStr) -> Result<Entry, Errno> {
+        self.current().link(req, ino, newparent, newname).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.current().open(req, ino, flags).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data, Errno> {
+        self.current().read(req, ino, fh, offset, size).await
+    }
+
+    async fn write(&self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, Errno> {
+        self.current().write(req, ino, fh, offset, data, flags).await
+    }
+
+    async fn flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        self.current().flush(req, ino, fh, lock_owner).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), Errno> {
+        self.current().release(req, ino, fh, flags, lock_owner, flush).await
+    }
+
+    async fn fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.current().fsync(req, ino, fh, datasync).await
+    }
+
+    async fn opendir(&self, req: &Request, ino: u64, flags: u32) -> Result<Opened, Errno> {
+        self.current().opendir(req, ino, flags).await
+    }
+
+    async fn readdir(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntry>, Errno> {
+        self.current().readdir(req, ino, fh, offset).await
+    }
+
+    async fn releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32) -> Result<(), Errno> {
+        self.current().releasedir(req, ino, fh, flags).await
+    }
+
+    async fn fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        self.current().fsyncdir(req, ino, fh, datasync).await
+    }
+
+    async fn statfs(&self, req: &Request, ino: u64) -> Result<Statfs, Errno> {
+        self.current().statfs(req, ino).await
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32) -> Result<(), Errno> {
+        self.current().setxattr(req, ino, name, value, flags, position).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32) -> Result<Xattr, Errno> {
+        self.current().getxattr(req, ino, name, size).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, size: u32) -> Result<Xattr, Errno> {
+        self.current().listxattr(req, ino, size).await
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.current().removexattr(req, ino, name).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, mask: u32) -> Result<(), Errno> {
+        self.current().access(req, ino, mask).await
+    }
+
+    async fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.current().create(req, parent, name, mode, flags).await
+    }
+
+    async fn getlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<Lock, Errno> {
+        self.current().getlk(req, ino, fh, lock_owner, start, end, typ, pid).await
+    }
+
+    async fn setlk(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool) -> Result<(), Errno> {
+        self.current().setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep).await
+    }
+
+    async fn bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64) -> Result<u64, Errno> {
+        self.current().bmap(req, ino, blocksize, idx).await
+    }
+
+    async fn ioctl(&self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, data: &[u8], out_size: u32) -> Result<Ioctl, Errno> {
+        self.current().ioctl(req, ino, fh, flags, cmd, arg, data, out_size).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn fallocate(&self, req: &Request, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<(), Errno> {
+        self.current().fallocate(req, ino, fh, offset, length, mode).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn readdirplus(&self, req: &Request, ino: u64, fh: u64, offset: DirOffset) -> Result<Vec<DirEntryPlus>, Errno> {
+        self.current().readdirplus(req, ino, fh, offset).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn rename2(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), Errno> {
+        self.current().rename2(req, parent, name, newparent, newname, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn lseek(&self, req: &Request, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64, Errno> {
+        self.current().lseek(req, ino, fh, offset, whence).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn copy_file_range(&self, req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u64) -> Result<u32, Errno> {
+        self.current().copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn syncfs(&self, req: &Request, ino: u64) -> Result<(), Errno> {
+        self.current().syncfs(req, ino).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn tmpfile(&self, req: &Request, parent: u64, mode: u32, flags: u32) -> Result<Created, Errno> {
+        self.current().tmpfile(req, parent, mode, flags).await
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    async fn statx(&self, req: &Request, ino: u64, fh: Option<u64>, flags: u32, mask: u32) -> Result<Attr, Errno> {
+        self.current().statx(req, ino, fh, flags, mask).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request, name: &OsStr) -> Result<(), Errno> {
+        self.current().setvolname(req, name).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(&self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64) -> Result<(), Errno> {
+        self.current().exchange(req, parent, name, newparent, newname, options).await
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request, ino: u64) -> Result<XTimes, Errno> {
+        self.current().getxtimes(req, ino).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use crate::{block_on, Attr, Errno, FileAttr, FileType, Filesystem, Request};
+    use super::Swappable;
+
+    /// Filesystem whose files have the given size
+    struct Sizes(u64);
+
+    #[async_trait]
+    impl Filesystem for Sizes {
+        async fn getattr(&self, _req: &Request, ino: u64) -> Result<Attr, Errno> {
+            Ok(Attr::new(FileAttr::builder(ino, FileType::RegularFile).size(self.0).build()))
+        }
+    }
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    #[test]
+    fn swap() {
+        let fs: Swappable<dyn Filesystem + Send + Sync> = Swappable::new(Arc::new(Sizes(1)));
+        let handle = fs.handle();
+        let size = |fs: &Swappable<_>| block_on(Filesystem::getattr(fs, &Request::test(0, 0), 2)).map(|attr| attr.attr.size);
+        assert_eq!(size(&fs), Ok(1));
+        let old = handle.swap(Arc::new(Sizes(2)));
+        assert_eq!(size(&fs), Ok(2));
+        assert_eq!(block_on(old.getattr(&Request::test(0, 0), 2)).unwrap().attr.size, 1);
+        // Filesystems of other types can be installed
+        handle.swap(Arc::new(Empty));
+        assert_eq!(size(&fs), Err(Errno::ENOSYS));
+    }
+}
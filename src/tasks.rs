@@ -0,0 +1,152 @@
+//! Background tasks of a filesystem
+//!
+//! Filesystems often run maintenance tasks next to serving requests, e.g. evicting caches or
+//! renewing leases. Tasks spawned on a runtime directly outlive the session and may access the
+//! filesystem after it was destroyed. Tasks spawned with the `TaskHandle` of a request
+//! (`Request::tasks`, e.g. at init) are cancelled when the filesystem is destroyed or the
+//! session ends, and the session loop doesn't return before all of them are gone.
+
+use std::fmt;
+use std::future::{self, Future};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Poll;
+
+use crate::cancel::CancellationToken;
+use crate::executor::Executor;
+
+/// Number of running tasks of a session
+#[derive(Debug, Default)]
+pub(crate) struct Tasks {
+    running: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl Tasks {
+    /// Waits until no task is running
+    pub(crate) fn wait(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running > 0 {
+            running = self.idle.wait(running).unwrap();
+        }
+    }
+}
+
+/// Running task, counted until dropped
+struct Running(Arc<Tasks>);
+
+impl Running {
+    fn new(tasks: Arc<Tasks>) -> Running {
+        *tasks.running.lock().unwrap() += 1;
+        Running(tasks)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// Handle for spawning tasks whose lifetime is tied to a session
+#[derive(Clone)]
+pub struct TaskHandle {
+    executor: Arc<dyn Executor>,
+    shutdown: CancellationToken,
+    tasks: Arc<Tasks>,
+}
+
+impl TaskHandle {
+    pub(crate) fn new(executor: Arc<dyn Executor>, shutdown: CancellationToken, tasks: Arc<Tasks>) -> TaskHandle {
+        TaskHandle { executor, shutdown, tasks }
+    }
+
+    /// Spawn the given task on the executor of the session. The task is dropped when the
+    /// filesystem is destroyed or the session ends, if it didn't complete before. Returns false
+    /// without spawning it if that happened already.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, task: F) -> bool {
+        // Count the task before checking for shutdown, so that the session waits for it
+        let running = Running::new(self.tasks.clone());
+        if self.shutdown.is_cancelled() {
+            return false;
+        }
+        let shutdown = self.shutdown.clone();
+        self.executor.spawn(Box::pin(async move {
+            let _running = running;
+            let mut task = Box::pin(task);
+            let mut cancelled = Box::pin(shutdown.cancelled());
+            future::poll_fn(|cx| {
+                if cancelled.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                task.as_mut().poll(cx)
+            }).await
+        }));
+        true
+    }
+
+    /// Returns the token that is cancelled when the tasks are, e.g. to hand it to work that
+    /// isn't spawned with this handle
+    pub fn cancellation(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+}
+
+impl fmt::Debug for TaskHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("TaskHandle").field("shutdown", &self.shutdown).field("tasks", &self.tasks).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Errno, Filesystem, MockChannel, Request, ThreadExecutor};
+
+    /// Sets the flag when dropped
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Filesystem spawning a task at init that never completes
+    #[derive(Default)]
+    struct Maintained {
+        dropped: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Filesystem for Maintained {
+        async fn init(&self, req: &Request) -> Result<(), Errno> {
+            let flag = DropFlag(self.dropped.clone());
+            assert!(req.tasks().unwrap().spawn(async move {
+                let _flag = flag;
+                future::pending::<()>().await
+            }));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cancelled_on_destroy() {
+        let mock = MockChannel::new(Maintained::default()).executor(ThreadExecutor);
+        block_on(mock.init()).unwrap();
+        assert!(!mock.session().filesystem.dropped.load(Ordering::SeqCst));
+        block_on(mock.request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])).unwrap().result().unwrap();
+        mock.session().tasks.wait();
+        assert!(mock.session().filesystem.dropped.load(Ordering::SeqCst));
+        // No tasks are spawned after destroying the filesystem
+        assert!(!mock.session().task_handle().spawn(async {}));
+    }
+}
@@ -0,0 +1,429 @@
+//! Conformance tests
+//!
+//! `Conformance` mounts a filesystem in a temporary directory and runs a battery of checks of
+//! POSIX semantics against it through the file APIs of the standard library, in the spirit of
+//! pjdfstest: creating, renaming, linking and unlinking files and directories, permission bits,
+//! timestamps and extended attributes. Filesystems run it from a plain Rust test (on a
+//! multi-threaded Tokio runtime) and assert that all checks passed with `Report::assert_passed`.
+//! Mounting needs /dev/fuse and the permission to mount.
+//!
+//! Every check runs in its own directory below the mountpoint, so that checks don't depend on
+//! each other. Checks that hit an operation the filesystem doesn't implement (ENOSYS or
+//! EOPNOTSUPP) are reported as unsupported instead of failed, and checks can be skipped by
+//! name. `check_dir` runs the checks against any directory, e.g. of a filesystem of the host to
+//! compare with.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use std::{fmt, process};
+
+use crate::{Filesystem, Mount};
+
+/// Check of a POSIX semantic, run in an empty directory
+type Check = fn(&Path) -> Result<(), Failure>;
+
+/// Names and functions of all checks, in the order they run
+const CHECKS: &[(&str, Check)] = &[
+    ("create", create),
+    ("unlink", unlink),
+    ("mkdir", mkdir),
+    ("rename", rename),
+    ("link", link),
+    ("symlink", symlink),
+    ("permissions", permissions),
+    ("timestamps", timestamps),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ("xattrs", xattrs),
+];
+
+/// Number of mountpoints created by this process, for naming the next one
+static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Reason a check didn't pass
+#[derive(Debug)]
+enum Failure {
+    /// The filesystem doesn't implement an operation the check needs
+    Unsupported,
+    /// The filesystem violated the semantic
+    Failed(String),
+}
+
+/// Fail the check with the given message unless the given condition holds
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            return Err(Failure::Failed(format!($($arg)*)));
+        }
+    };
+}
+
+/// Returns the result of the given operation, or the failure of the check if it failed
+fn io<T>(operation: &str, result: io::Result<T>) -> Result<T, Failure> {
+    result.map_err(|err| match err.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Failure::Unsupported,
+        _ => Failure::Failed(format!("{}: {}", operation, err)),
+    })
+}
+
+/// Fails the check unless the given result is the given error
+fn expect_error<T>(operation: &str, result: io::Result<T>, errnos: &[i32]) -> Result<(), Failure> {
+    match result {
+        Ok(_) => Err(Failure::Failed(format!("{} succeeded, expected error {:?}", operation, errnos))),
+        Err(err) if err.raw_os_error().is_some_and(|errno| errnos.contains(&errno)) => Ok(()),
+        Err(err) => Err(Failure::Failed(format!("{}: {}, expected error {:?}", operation, err, errnos))),
+    }
+}
+
+/// Results of the checks of a filesystem
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// Checks that passed
+    pub passed: Vec<&'static str>,
+    /// Checks that failed, with the reason
+    pub failed: Vec<(&'static str, String)>,
+    /// Checks that need operations the filesystem doesn't implement
+    pub unsupported: Vec<&'static str>,
+    /// Checks that were skipped
+    pub skipped: Vec<&'static str>,
+}
+
+impl Report {
+    /// Returns true if no check failed
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Panics with the reasons of the failed checks if any check failed
+    pub fn assert_passed(&self) {
+        assert!(self.is_ok(), "Conformance checks failed:\n{}", self);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for name in &self.passed {
+            writeln!(f, "{}: passed", name)?;
+        }
+        for (name, reason) in &self.failed {
+            writeln!(f, "{}: FAILED: {}", name, reason)?;
+        }
+        for name in &self.unsupported {
+            writeln!(f, "{}: unsupported", name)?;
+        }
+        for name in &self.skipped {
+            writeln!(f, "{}: skipped", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the conformance checks, except the given ones, against the given directory. Every check
+/// runs in a new subdirectory, which is removed if the check passed.
+pub fn check_dir(dir: &Path, skip: &[&str]) -> Report {
+    let mut report = Report::default();
+    for &(name, check) in CHECKS {
+        if skip.contains(&name) {
+            report.skipped.push(name);
+            continue;
+        }
+        let path = dir.join(name);
+        let result = match fs::create_dir(&path) {
+            Ok(()) => check(&path),
+            Err(err) => Err(Failure::Failed(format!("creating directory of the check: {}", err))),
+        };
+        match result {
+            Ok(()) => {
+                report.passed.push(name);
+                let _ = fs::remove_dir_all(&path);
+            }
+            Err(Failure::Unsupported) => report.unsupported.push(name),
+            Err(Failure::Failed(reason)) => report.failed.push((name, reason)),
+        }
+    }
+    report
+}
+
+/// Conformance test of a filesystem, mounting it in a temporary directory
+#[derive(Debug)]
+pub struct Conformance<FS> {
+    filesystem: FS,
+    options: Vec<OsString>,
+    skip: Vec<&'static str>,
+}
+
+impl<FS: Filesystem + Send + Sync + 'static> Conformance<FS> {
+    /// Test the given filesystem, whose root directory must be empty and writable
+    pub fn new(filesystem: FS) -> Conformance<FS> {
+        Conformance { filesystem, options: Vec::new(), skip: Vec::new() }
+    }
+
+    /// Mount the filesystem with the given options (e.g. `default_permissions`)
+    pub fn options(mut self, options: &[OsString]) -> Conformance<FS> {
+        self.options = options.to_vec();
+        self
+    }
+
+    /// Skip the check of the given name (create, unlink, mkdir, rename, link, symlink,
+    /// permissions, timestamps or xattrs)
+    pub fn skip(mut self, check: &'static str) -> Conformance<FS> {
+        self.skip.push(check);
+        self
+    }
+
+    /// Mount the filesystem, run the checks against it and unmount it. Fails if the filesystem
+    /// can't be mounted or unmounted.
+    pub async fn run(self) -> io::Result<Report> {
+        let mountpoint = std::env::temp_dir().join(format!("async-fuse-testkit-{}-{}", process::id(), MOUNTS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&mountpoint)?;
+        let handle = match Mount::new(self.filesystem, &mountpoint).options(&self.options).spawn().await {
+            Ok(handle) => handle,
+            Err(err) => {
+                let _ = fs::remove_dir(&mountpoint);
+                return Err(err);
+            }
+        };
+        let (dir, skip) = (mountpoint.clone(), self.skip);
+        let report = tokio::task::spawn_blocking(move || check_dir(&dir, &skip)).await;
+        let unmounted = handle.unmount().await;
+        let _ = fs::remove_dir(&mountpoint);
+        unmounted?;
+        report.map_err(io::Error::other)
+    }
+}
+
+/// Returns the contents of the given file
+fn contents(path: &Path) -> Result<Vec<u8>, Failure> {
+    io(&format!("reading {}", path.display()), fs::read(path))
+}
+
+/// Returns the inode number of the given file
+fn ino(path: &Path) -> Result<u64, Failure> {
+    Ok(io(&format!("stat {}", path.display()), fs::symlink_metadata(path))?.ino())
+}
+
+fn create(dir: &Path) -> Result<(), Failure> {
+    let path = dir.join("file");
+    let mut file = io("creating a file", File::create_new(&path))?;
+    let meta = io("stat of a created file", fs::metadata(&path))?;
+    ensure!(meta.is_file() && meta.len() == 0, "created file is {:?} with {} bytes", meta.file_type(), meta.len());
+    expect_error("creating an existing file exclusively", File::create_new(&path), &[libc::EEXIST])?;
+    io("writing", file.write_all(b"hello"))?;
+    drop(file);
+    ensure!(contents(&path)? == b"hello", "read other data than written");
+    // Opening with O_CREAT without O_TRUNC keeps the data
+    drop(io("opening an existing file with O_CREAT", OpenOptions::new().write(true).create(true).truncate(false).open(&path))?);
+    ensure!(contents(&path)? == b"hello", "opening with O_CREAT changed the data");
+    let names: Vec<PathBuf> = io("reading the directory", fs::read_dir(dir))?.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+    ensure!(names == [path], "directory lists {:?}", names);
+    Ok(())
+}
+
+fn unlink(dir: &Path) -> Result<(), Failure> {
+    let path = dir.join("file");
+    io("creating a file", fs::write(&path, b"data"))?;
+    let mut file = io("opening", File::open(&path))?;
+    io("unlinking", fs::remove_file(&path))?;
+    expect_error("stat of an unlinked file", fs::metadata(&path), &[libc::ENOENT])?;
+    expect_error("unlinking a missing file", fs::remove_file(&path), &[libc::ENOENT])?;
+    // Open files stay readable after they're unlinked
+    let mut data = Vec::new();
+    io("reading an unlinked open file", file.read_to_end(&mut data))?;
+    ensure!(data == b"data", "unlinked open file has other data");
+    io("creating a directory", fs::create_dir(dir.join("dir")))?;
+    expect_error("unlinking a directory", fs::remove_file(dir.join("dir")), &[libc::EISDIR, libc::EPERM])?;
+    Ok(())
+}
+
+fn mkdir(dir: &Path) -> Result<(), Failure> {
+    let path = dir.join("dir");
+    io("creating a directory", fs::create_dir(&path))?;
+    ensure!(io("stat of a created directory", fs::metadata(&path))?.is_dir(), "created directory isn't a directory");
+    expect_error("creating an existing directory", fs::create_dir(&path), &[libc::EEXIST])?;
+    io("creating a file in the directory", fs::write(path.join("file"), b""))?;
+    expect_error("removing a non-empty directory", fs::remove_dir(&path), &[libc::ENOTEMPTY, libc::EEXIST])?;
+    expect_error("creating a file in a missing directory", fs::write(dir.join("missing/file"), b""), &[libc::ENOENT])?;
+    expect_error("creating a directory below a file", fs::create_dir(path.join("file/dir")), &[libc::ENOTDIR])?;
+    io("unlinking", fs::remove_file(path.join("file")))?;
+    io("removing an empty directory", fs::remove_dir(&path))?;
+    expect_error("stat of a removed directory", fs::metadata(&path), &[libc::ENOENT])?;
+    Ok(())
+}
+
+fn rename(dir: &Path) -> Result<(), Failure> {
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    io("creating a file", fs::write(&a, b"a"))?;
+    let ino_a = ino(&a)?;
+    io("renaming a file", fs::rename(&a, &b))?;
+    expect_error("stat of the old name", fs::metadata(&a), &[libc::ENOENT])?;
+    ensure!(ino(&b)? == ino_a && contents(&b)? == b"a", "renamed file changed");
+    // Renaming over an existing file replaces it
+    io("creating a file", fs::write(&a, b"new"))?;
+    io("renaming over an existing file", fs::rename(&a, &b))?;
+    ensure!(contents(&b)? == b"new", "renaming over a file didn't replace it");
+    // Renaming a name to itself does nothing
+    io("renaming a file to itself", fs::rename(&b, &b))?;
+    ensure!(contents(&b)? == b"new", "renaming a file to itself changed it");
+    // Files and directories don't replace each other, non-empty directories aren't replaced
+    let (d, e) = (dir.join("d"), dir.join("e"));
+    io("creating a directory", fs::create_dir(&d))?;
+    io("creating a directory", fs::create_dir(&e))?;
+    expect_error("renaming a file over a directory", fs::rename(&b, &d), &[libc::EISDIR])?;
+    expect_error("renaming a directory over a file", fs::rename(&d, &b), &[libc::ENOTDIR])?;
+    io("creating a file in a directory", fs::write(e.join("file"), b""))?;
+    expect_error("renaming over a non-empty directory", fs::rename(&d, &e), &[libc::ENOTEMPTY, libc::EEXIST])?;
+    expect_error("renaming a directory into itself", fs::rename(&e, e.join("sub")), &[libc::EINVAL])?;
+    // Renaming into another directory moves the entry, renaming over an empty directory
+    // replaces it
+    io("moving a file into a directory", fs::rename(&b, d.join("b")))?;
+    ensure!(contents(&d.join("b"))? == b"new", "moved file changed");
+    io("unlinking", fs::remove_file(e.join("file")))?;
+    io("renaming over an empty directory", fs::rename(&d, &e))?;
+    ensure!(contents(&e.join("b"))? == b"new", "renamed directory lost its entries");
+    expect_error("stat of a renamed directory", fs::metadata(&d), &[libc::ENOENT])?;
+    Ok(())
+}
+
+fn link(dir: &Path) -> Result<(), Failure> {
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    io("creating a file", fs::write(&a, b"data"))?;
+    io("linking", fs::hard_link(&a, &b))?;
+    let meta = io("stat of a link", fs::metadata(&b))?;
+    ensure!(meta.ino() == ino(&a)? && meta.nlink() == 2, "link has inode {} and {} links", meta.ino(), meta.nlink());
+    expect_error("linking to an existing name", fs::hard_link(&a, &b), &[libc::EEXIST])?;
+    io("unlinking", fs::remove_file(&a))?;
+    let meta = io("stat of a link", fs::metadata(&b))?;
+    ensure!(meta.nlink() == 1, "unlinked link left {} links", meta.nlink());
+    ensure!(contents(&b)? == b"data", "link has other data");
+    io("creating a directory", fs::create_dir(dir.join("dir")))?;
+    expect_error("linking a directory", fs::hard_link(dir.join("dir"), dir.join("dir2")), &[libc::EPERM])?;
+    Ok(())
+}
+
+fn symlink(dir: &Path) -> Result<(), Failure> {
+    let (target, path) = (dir.join("target"), dir.join("symlink"));
+    io("creating a symlink", std::os::unix::fs::symlink("target", &path))?;
+    ensure!(io("stat of a symlink", fs::symlink_metadata(&path))?.file_type().is_symlink(), "symlink isn't a symlink");
+    ensure!(io("reading a symlink", fs::read_link(&path))? == Path::new("target"), "symlink has another target");
+    expect_error("following a dangling symlink", fs::metadata(&path), &[libc::ENOENT])?;
+    io("creating a file", fs::write(&target, b"data"))?;
+    ensure!(contents(&path)? == b"data", "symlink doesn't resolve to its target");
+    expect_error("creating an existing symlink", std::os::unix::fs::symlink("target", &path), &[libc::EEXIST])?;
+    io("unlinking a symlink", fs::remove_file(&path))?;
+    ensure!(contents(&target)? == b"data", "unlinking a symlink changed its target");
+    Ok(())
+}
+
+fn permissions(dir: &Path) -> Result<(), Failure> {
+    let path = dir.join("file");
+    io("creating a file", fs::write(&path, b"data"))?;
+    for mode in [0o640, 0o755, 0o4711, 0o000] {
+        io("chmod", fs::set_permissions(&path, fs::Permissions::from_mode(mode)))?;
+        let actual = io("stat", fs::metadata(&path))?.mode() & 0o7777;
+        ensure!(actual == mode, "chmod {:o} resulted in mode {:o}", mode, actual);
+    }
+    // Permissions don't apply to root
+    if unsafe { libc::geteuid() } != 0 {
+        expect_error("opening a file with mode 000", File::open(&path), &[libc::EACCES])?;
+        io("chmod", fs::set_permissions(dir, fs::Permissions::from_mode(0o500)))?;
+        let created = fs::write(dir.join("other"), b"");
+        io("chmod", fs::set_permissions(dir, fs::Permissions::from_mode(0o755)))?;
+        expect_error("creating a file in a read-only directory", created, &[libc::EACCES])?;
+    }
+    io("chmod", fs::set_permissions(&path, fs::Permissions::from_mode(0o644)))?;
+    Ok(())
+}
+
+fn timestamps(dir: &Path) -> Result<(), Failure> {
+    let past = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let set_mtime = |path: &Path| io("setting mtime", File::open(path).and_then(|file| file.set_modified(past)));
+    let mtime = |path: &Path| io("stat", fs::metadata(path).and_then(|meta| meta.modified()));
+    let path = dir.join("file");
+    io("creating a file", fs::write(&path, b""))?;
+    set_mtime(&path)?;
+    ensure!(mtime(&path)? == past, "mtime set to {:?} is {:?}", past, mtime(&path)?);
+    // Writing and truncating update mtime
+    io("writing", OpenOptions::new().write(true).open(&path).and_then(|mut file| file.write_all(b"data")))?;
+    ensure!(mtime(&path)? > past, "writing didn't update mtime");
+    set_mtime(&path)?;
+    io("truncating", OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(2)))?;
+    ensure!(mtime(&path)? > past, "truncating didn't update mtime");
+    // Creating, renaming and removing entries update mtime of the directory
+    let changes: [(&str, &dyn Fn() -> io::Result<()>); 4] = [
+        ("creating a file", &|| fs::write(dir.join("new"), b"")),
+        ("creating a directory", &|| fs::create_dir(dir.join("sub"))),
+        ("renaming", &|| fs::rename(dir.join("new"), dir.join("renamed"))),
+        ("unlinking", &|| fs::remove_file(dir.join("renamed"))),
+    ];
+    for (operation, change) in changes {
+        set_mtime(dir)?;
+        io(operation, change())?;
+        ensure!(mtime(dir)? > past, "{} didn't update mtime of the directory", operation);
+    }
+    // Changing the mode updates ctime, but not mtime
+    set_mtime(&path)?;
+    let ctime = io("stat", fs::metadata(&path))?.ctime();
+    io("chmod", fs::set_permissions(&path, fs::Permissions::from_mode(0o600)))?;
+    let meta = io("stat", fs::metadata(&path))?;
+    ensure!(meta.ctime() >= ctime && mtime(&path)? == past, "chmod changed mtime");
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn xattrs(dir: &Path) -> Result<(), Failure> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = dir.join("file");
+    io("creating a file", fs::write(&path, b""))?;
+    let file = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let check = |result: libc::ssize_t| if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result as usize) };
+    let set = |name: &[u8], value: &[u8], flags: i32| check(unsafe { libc::setxattr(file.as_ptr(), name.as_ptr() as *const libc::c_char, value.as_ptr() as *const libc::c_void, value.len(), flags) } as libc::ssize_t);
+    let get = |name: &[u8]| {
+        let mut value = [0; 64];
+        let len = check(unsafe { libc::getxattr(file.as_ptr(), name.as_ptr() as *const libc::c_char, value.as_mut_ptr() as *mut libc::c_void, value.len()) })?;
+        Ok(value[..len].to_vec())
+    };
+    let list = || {
+        let mut names = [0; 256];
+        let len = check(unsafe { libc::listxattr(file.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) })?;
+        Ok::<_, io::Error>(names[..len].split(|&byte| byte == 0).filter(|name| !name.is_empty()).map(<[u8]>::to_vec).collect::<Vec<_>>())
+    };
+    let remove = |name: &[u8]| check(unsafe { libc::removexattr(file.as_ptr(), name.as_ptr() as *const libc::c_char) } as libc::ssize_t);
+
+    io("setting an attribute", set(b"user.test\0", b"value", 0))?;
+    ensure!(io("getting an attribute", get(b"user.test\0"))? == b"value", "attribute has another value");
+    ensure!(io("listing attributes", list())?.contains(&b"user.test".to_vec()), "attribute isn't listed");
+    expect_error("creating an existing attribute", set(b"user.test\0", b"other", libc::XATTR_CREATE), &[libc::EEXIST])?;
+    expect_error("replacing a missing attribute", set(b"user.missing\0", b"other", libc::XATTR_REPLACE), &[libc::ENODATA])?;
+    io("replacing an attribute", set(b"user.test\0", b"other", libc::XATTR_REPLACE))?;
+    ensure!(io("getting an attribute", get(b"user.test\0"))? == b"other", "replaced attribute has the old value");
+    io("removing an attribute", remove(b"user.test\0"))?;
+    expect_error("getting a removed attribute", get(b"user.test\0"), &[libc::ENODATA])?;
+    expect_error("removing a missing attribute", remove(b"user.test\0"), &[libc::ENODATA])?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{check_dir, CHECKS};
+
+    #[test]
+    fn host() {
+        // The checks pass on the filesystem of the temporary directory of the host
+        let dir = std::env::temp_dir().join(format!("async-fuse-testkit-host-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        let report = check_dir(&dir, &["symlink"]);
+        let _ = fs::remove_dir_all(&dir);
+        report.assert_passed();
+        assert_eq!(report.passed.len() + report.unsupported.len(), CHECKS.len() - 1);
+        assert_eq!(report.skipped, ["symlink"]);
+        assert!(report.to_string().contains("create: passed"));
+    }
+}
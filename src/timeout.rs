@@ -0,0 +1,217 @@
+//! Operation timeouts
+//!
+//! A session can give up on operations that take too long, replying ETIMEDOUT instead of
+//! leaving the calling process waiting for a stuck backend. The deadline of a request is
+//! handed to the filesystem (`Request::deadline`), so that it can pass it on to its own
+//! backend calls and give up at the same time.
+//!
+//! Since the session doesn't depend on a specific async runtime, deadlines are watched by a
+//! timer thread of its own.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use fuse_abi::fuse_opcode;
+
+use crate::metrics::operation_name;
+
+/// Timeouts of operations
+#[derive(Clone, Debug, Default)]
+pub struct Timeouts {
+    all: Option<Duration>,
+    operations: HashMap<u32, Duration>,
+}
+
+impl Timeouts {
+    /// Create timeouts of no operation
+    pub fn new() -> Timeouts {
+        Timeouts::default()
+    }
+
+    /// Set the timeout of all operations that don't have one of their own. Init, destroy,
+    /// forget and interrupt never time out unless they're given a timeout of their own.
+    pub fn all(mut self, timeout: Duration) -> Timeouts {
+        self.all = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the operation of the given name in lower case (e.g. `read`, like in
+    /// `Metrics`). Panics if there's no operation of the given name.
+    pub fn operation(mut self, name: &str, timeout: Duration) -> Timeouts {
+        let opcode = (0..64).find(|&opcode| fuse_opcode::try_from(opcode).is_ok_and(|opcode| operation_name(opcode) == name))
+            .unwrap_or_else(|| panic!("Unknown FUSE operation {}", name));
+        self.operations.insert(opcode, timeout);
+        self
+    }
+
+    /// Returns the timeout of the given operation
+    pub(crate) fn get(&self, opcode: fuse_opcode) -> Option<Duration> {
+        match (self.operations.get(&(opcode as u32)), opcode) {
+            (Some(timeout), _) => Some(*timeout),
+            (None, fuse_opcode::FUSE_INIT) | (None, fuse_opcode::FUSE_DESTROY) |
+            (None, fuse_opcode::FUSE_FORGET) | (None, fuse_opcode::FUSE_INTERRUPT) => None,
+            #[cfg(not(target_os = "macos"))]
+            (None, fuse_opcode::FUSE_BATCH_FORGET) => None,
+            (None, _) => self.all,
+        }
+    }
+}
+
+/// Deadline to wake a task at
+#[derive(Debug)]
+struct Wakeup(Instant, Waker);
+
+impl PartialEq for Wakeup {
+    fn eq(&self, other: &Wakeup) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Wakeup {}
+
+impl PartialOrd for Wakeup {
+    fn partial_cmp(&self, other: &Wakeup) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Wakeup {
+    // Reversed, so that the earliest deadline is on top of the heap
+    fn cmp(&self, other: &Wakeup) -> Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+/// Timer thread waking tasks at their deadlines
+#[derive(Debug, Default)]
+struct Timer {
+    wakeups: Mutex<BinaryHeap<Wakeup>>,
+    changed: Condvar,
+}
+
+impl Timer {
+    /// Returns the timer, starting its thread on first use
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<&'static Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            let timer: &'static Timer = Box::leak(Box::default());
+            thread::Builder::new().name("fuse-timer".into()).spawn(move || timer.run()).expect("failed to start timer thread");
+            timer
+        })
+    }
+
+    fn run(&self) {
+        let mut wakeups = self.wakeups.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while wakeups.peek().is_some_and(|wakeup| wakeup.0 <= now) {
+                wakeups.pop().unwrap().1.wake();
+            }
+            wakeups = match wakeups.peek() {
+                Some(wakeup) => { let timeout = wakeup.0 - now; self.changed.wait_timeout(wakeups, timeout).unwrap().0 },
+                None => self.changed.wait(wakeups).unwrap(),
+            };
+        }
+    }
+
+    fn wake_at(&self, deadline: Instant, waker: Waker) {
+        self.wakeups.lock().unwrap().push(Wakeup(deadline, waker));
+        self.changed.notify_one();
+    }
+}
+
+/// Future that is ready at the given deadline
+#[derive(Debug)]
+pub(crate) struct Sleep {
+    deadline: Instant,
+    registered: bool,
+}
+
+impl Sleep {
+    pub(crate) fn until(deadline: Instant) -> Sleep {
+        Sleep { deadline, registered: false }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // Tasks of requests are polled with the same waker, so registering once is enough
+        if !self.registered {
+            Timer::get().wake_at(self.deadline, cx.waker().clone());
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::future;
+    use std::time::{Duration, Instant};
+    use async_trait::async_trait;
+    use fuse_abi::fuse_opcode;
+    use crate::{block_on, Attr, Entry, Errno, Filesystem, MockChannel, Request, FUSE_ROOT_ID};
+    use super::{Sleep, Timeouts};
+
+    #[test]
+    fn timeouts() {
+        let timeouts = Timeouts::new().all(Duration::from_secs(5)).operation("read", Duration::from_secs(30));
+        assert_eq!(timeouts.get(fuse_opcode::FUSE_READ), Some(Duration::from_secs(30)));
+        assert_eq!(timeouts.get(fuse_opcode::FUSE_LOOKUP), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.get(fuse_opcode::FUSE_FORGET), None);
+        assert_eq!(Timeouts::new().get(fuse_opcode::FUSE_LOOKUP), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown FUSE operation")]
+    fn unknown_operation() {
+        Timeouts::new().operation("frobnicate", Duration::from_secs(1));
+    }
+
+    #[test]
+    fn sleep() {
+        let started = Instant::now();
+        block_on(Sleep::until(started + Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    struct Stuck;
+
+    #[async_trait]
+    impl Filesystem for Stuck {
+        async fn lookup(&self, req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+            let remaining = req.remaining().unwrap();
+            assert!(remaining > Duration::from_millis(500) && remaining <= Duration::from_secs(1));
+            Err(Errno::ENOENT)
+        }
+
+        async fn getattr(&self, req: &Request, _ino: u64) -> Result<Attr, Errno> {
+            assert!(req.deadline().is_some());
+            future::pending().await
+        }
+    }
+
+    #[test]
+    fn session() {
+        let timeouts = Timeouts::new().all(Duration::from_secs(1)).operation("getattr", Duration::from_millis(20));
+        let ch = MockChannel::new(Stuck).timeouts(timeouts);
+        block_on(ch.init()).unwrap();
+        assert_eq!(block_on(ch.lookup(FUSE_ROOT_ID, "foo")).unwrap_err(), Errno::ENOENT);
+        let started = Instant::now();
+        assert_eq!(block_on(ch.getattr(FUSE_ROOT_ID)).unwrap_err(), Errno::ETIMEDOUT);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,285 @@
+//! Invalidation of cached inodes and entries on out-of-band changes
+//!
+//! The kernel caches attributes, data and directory entries of a filesystem that passes a
+//! local directory tree through (with attribute and entry timeouts, or `KEEP_CACHE`), so
+//! changes made to the backing tree directly aren't visible through the mountpoint until the
+//! cache expires. `InvalidationWatch` watches the backing tree with inotify and invalidates
+//! changed inodes and entries as they happen, through a `Notifier` of the session (or a
+//! `CacheInvalidator` of a `CachedFs`). Paths are mapped to the inode numbers the filesystem
+//! assigned with a resolver function, e.g. the `InodeTable` of the filesystem.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use libc::{c_int, c_void};
+use log::{debug, warn};
+
+use crate::cache::CacheInvalidator;
+use crate::notify::Notifier;
+
+/// Changes of watched directories and their entries
+const WATCH_MASK: u32 = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+
+/// Target of invalidations
+pub trait Invalidate: Send + 'static {
+    /// Invalidate the cached attributes and data of the given inode
+    fn inval_inode(&self, ino: u64) -> io::Result<()>;
+
+    /// Invalidate the cached directory entry of the given name in the given directory
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()>;
+}
+
+impl Invalidate for Notifier {
+    fn inval_inode(&self, ino: u64) -> io::Result<()> {
+        Notifier::inval_inode(self, ino, 0, 0)
+    }
+
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        Notifier::inval_entry(self, parent, name)
+    }
+}
+
+impl Invalidate for CacheInvalidator {
+    fn inval_inode(&self, ino: u64) -> io::Result<()> {
+        CacheInvalidator::inval_inode(self, ino);
+        Ok(())
+    }
+
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        CacheInvalidator::inval_entry(self, parent, name);
+        Ok(())
+    }
+}
+
+/// Watch of a backing directory tree invalidating changed inodes and entries, until dropped
+#[derive(Debug)]
+pub struct InvalidationWatch {
+    stop: OwnedFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InvalidationWatch {
+    /// Watch the directory tree at the given path. Changes are invalidated on the given target,
+    /// with inode numbers given by the resolver for paths relative to the root of the tree (an
+    /// empty path for the root itself). The resolver returns none for paths the filesystem
+    /// didn't look up, which the kernel can't have cached.
+    pub fn new<I, R>(root: &Path, target: I, resolve: R) -> io::Result<InvalidationWatch>
+        where I: Invalidate, R: Fn(&Path) -> Option<u64> + Send + 'static
+    {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (stopped, stop) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let mut watcher = Watcher { inotify, root: root.to_owned(), dirs: HashMap::new(), target, resolve };
+        watcher.add_tree(PathBuf::new())?;
+        let thread = thread::Builder::new().name("fuse-watch".into()).spawn(move || watcher.run(stopped))?;
+        Ok(InvalidationWatch { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for InvalidationWatch {
+    fn drop(&mut self) {
+        unsafe { libc::write(self.stop.as_raw_fd(), [0u8].as_ptr() as *const c_void, 1) };
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Watching thread state
+struct Watcher<I, R> {
+    inotify: OwnedFd,
+    root: PathBuf,
+    /// Relative paths of watched directories by watch descriptor
+    dirs: HashMap<c_int, PathBuf>,
+    target: I,
+    resolve: R,
+}
+
+impl<I: Invalidate, R: Fn(&Path) -> Option<u64>> Watcher<I, R> {
+    /// Watch the directory at the given relative path and its subdirectories
+    fn add_tree(&mut self, path: PathBuf) -> io::Result<()> {
+        let cpath = CString::new(self.root.join(&path).into_os_string().into_vec())?;
+        let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), cpath.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for entry in std::fs::read_dir(self.root.join(&path))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                // Directories removed meanwhile are skipped
+                if let Err(err) = self.add_tree(path.join(entry.file_name())) {
+                    debug!("Not watching {}: {}", path.join(entry.file_name()).display(), err);
+                }
+            }
+        }
+        self.dirs.insert(wd, path);
+        Ok(())
+    }
+
+    /// Stop watching the directory at the given relative path and its subdirectories
+    fn remove_tree(&mut self, path: &Path) {
+        let inotify = self.inotify.as_raw_fd();
+        self.dirs.retain(|&wd, dir| {
+            if !dir.starts_with(path) { return true; }
+            unsafe { libc::inotify_rm_watch(inotify, wd) };
+            false
+        });
+    }
+
+    /// Handle events until the given fd gets readable
+    fn run(mut self, stopped: OwnedFd) {
+        // Buffer aligned for inotify_event
+        let mut buf = vec![0u64; 1024];
+        loop {
+            let mut fds = [
+                libc::pollfd { fd: self.inotify.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: stopped.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue; }
+                warn!("Failed to wait for inotify events: {}", err);
+                return;
+            }
+            if fds[1].revents != 0 {
+                return;
+            }
+            let len = unsafe { libc::read(self.inotify.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len() * 8) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted { continue; }
+                warn!("Failed to read inotify events: {}", err);
+                return;
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len as usize) };
+            let mut pos = 0;
+            while pos + mem::size_of::<libc::inotify_event>() <= bytes.len() {
+                let event = unsafe { std::ptr::read_unaligned(bytes[pos..].as_ptr() as *const libc::inotify_event) };
+                pos += mem::size_of::<libc::inotify_event>();
+                let name = &bytes[pos..pos + event.len as usize];
+                pos += event.len as usize;
+                let name = OsStr::from_bytes(&name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())]);
+                self.handle(event.wd, event.mask, name);
+            }
+        }
+    }
+
+    /// Invalidate what the given event changed
+    fn handle(&mut self, wd: c_int, mask: u32, name: &OsStr) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            warn!("Missed inotify events, cached inodes may be stale");
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            self.dirs.remove(&wd);
+            return;
+        }
+        let dir = match self.dirs.get(&wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let path = if name.is_empty() { dir.clone() } else { dir.join(name) };
+        if mask & (libc::IN_MODIFY | libc::IN_ATTRIB) != 0 {
+            if let Some(ino) = (self.resolve)(&path) {
+                self.invalidated(&path, self.target.inval_inode(ino));
+            }
+        }
+        if mask & (libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO) != 0 && !name.is_empty() {
+            if let Some(parent) = (self.resolve)(&dir) {
+                self.invalidated(&path, self.target.inval_entry(parent, name));
+            }
+            if mask & libc::IN_ISDIR != 0 {
+                if mask & libc::IN_MOVED_FROM != 0 {
+                    self.remove_tree(&path);
+                } else if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    if let Err(err) = self.add_tree(path.clone()) {
+                        debug!("Not watching {}: {}", path.display(), err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn invalidated(&self, path: &Path, res: io::Result<()>) {
+        match res {
+            // The kernel dropped the inode or entry from its cache already
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+            Err(err) => warn!("Failed to invalidate {}: {}", path.display(), err),
+            Ok(()) => debug!("Invalidated {}", path.display()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::path::Path;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+    use super::{Invalidate, InvalidationWatch};
+
+    #[derive(Debug, PartialEq)]
+    enum Inval {
+        Inode(u64),
+        Entry(u64, String),
+    }
+
+    struct Recorder(Sender<Inval>);
+
+    impl Invalidate for Recorder {
+        fn inval_inode(&self, ino: u64) -> io::Result<()> {
+            self.0.send(Inval::Inode(ino)).unwrap();
+            Ok(())
+        }
+
+        fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+            self.0.send(Inval::Entry(parent, name.to_str().unwrap().into())).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Wait for the given invalidation, skipping others (e.g. a write modifying a file twice)
+    fn expect(rx: &Receiver<Inval>, inval: Inval) {
+        while rx.recv_timeout(Duration::from_secs(5)).unwrap() != inval {}
+    }
+
+    #[test]
+    fn invalidate() {
+        let dir = std::env::temp_dir().join(format!("async-fuse-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"").unwrap();
+        let (tx, rx) = channel();
+        let resolve = |path: &Path| match path.to_str().unwrap() {
+            "" => Some(1),
+            "sub" => Some(2),
+            "sub/file" => Some(3),
+            _ => None,
+        };
+        let watch = InvalidationWatch::new(&dir, Recorder(tx), resolve).unwrap();
+        std::fs::write(dir.join("sub/file"), b"data").unwrap();
+        expect(&rx, Inval::Inode(3));
+        std::fs::write(dir.join("new"), b"").unwrap();
+        expect(&rx, Inval::Entry(1, "new".into()));
+        // New directories are watched
+        std::fs::create_dir(dir.join("sub/deeper")).unwrap();
+        expect(&rx, Inval::Entry(2, "deeper".into()));
+        std::fs::rename(dir.join("sub/file"), dir.join("sub/deeper/file")).unwrap();
+        expect(&rx, Inval::Entry(2, "file".into()));
+        drop(watch);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! Extended attribute namespaces
+//!
+//! Extended attributes live in namespaces with different semantics: `user.*` attributes are
+//! plain data, `trusted.*` and `security.*` attributes are reserved to privileged processes and
+//! security modules, and POSIX ACLs are stored as `system.posix_acl_access` and
+//! `system.posix_acl_default`. `XattrRouter` passes the extended attribute operations of each
+//! namespace to a handler of its own, and fails operations of namespaces without a handler with
+//! EOPNOTSUPP (like filesystems not supporting them do), while handlers fail operations on
+//! missing attributes with ENODATA.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use async_trait::async_trait;
+
+use crate::layer::Wrapper;
+use crate::{Errno, Filesystem, OperationSet, Request, Xattr};
+
+/// Namespace of extended attributes
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum XattrNamespace {
+    /// User attributes (`user.*`)
+    User,
+    /// Trusted attributes (`trusted.*`)
+    Trusted,
+    /// Security attributes (`security.*`, e.g. SELinux labels or file capabilities)
+    Security,
+    /// POSIX access control lists (`system.posix_acl_access` and `system.posix_acl_default`)
+    PosixAcl,
+    /// Other system attributes (`system.*`)
+    System,
+}
+
+impl XattrNamespace {
+    /// Returns the namespace of the attribute of the given name, none if it has no known prefix
+    pub fn of(name: &OsStr) -> Option<XattrNamespace> {
+        let name = name.as_bytes();
+        if name == b"system.posix_acl_access" || name == b"system.posix_acl_default" {
+            Some(XattrNamespace::PosixAcl)
+        } else if name.starts_with(b"user.") {
+            Some(XattrNamespace::User)
+        } else if name.starts_with(b"trusted.") {
+            Some(XattrNamespace::Trusted)
+        } else if name.starts_with(b"security.") {
+            Some(XattrNamespace::Security)
+        } else if name.starts_with(b"system.") {
+            Some(XattrNamespace::System)
+        } else {
+            None
+        }
+    }
+}
+
+/// Handler of the extended attributes of a namespace. Attributes are passed with their full
+/// names, including the namespace prefix.
+#[async_trait]
+pub trait XattrHandler: Send + Sync + 'static {
+    /// Get the value of an attribute. Fails with ENODATA by default.
+    async fn get(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENODATA)
+    }
+
+    /// Set the value of an attribute, with the flags of setxattr (`XATTR_CREATE` or
+    /// `XATTR_REPLACE`). Fails with EOPNOTSUPP by default.
+    async fn set(&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32) -> Result<(), Errno> {
+        Err(Errno::EOPNOTSUPP)
+    }
+
+    /// List the names of the attributes of a file. Lists none by default.
+    async fn list(&self, _req: &Request, _ino: u64) -> Result<Vec<OsString>, Errno> {
+        Ok(Vec::new())
+    }
+
+    /// Remove an attribute. Fails with EOPNOTSUPP by default.
+    async fn remove(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::EOPNOTSUPP)
+    }
+}
+
+/// Filesystem wrapper passing extended attribute operations to the handlers of their namespaces
+pub struct XattrRouter<FS> {
+    inner: FS,
+    handlers: HashMap<XattrNamespace, Box<dyn XattrHandler>>,
+}
+
+impl<FS: Filesystem + Send + Sync> XattrRouter<FS> {
+    /// Wrap the given filesystem, without supporting any namespace yet
+    pub fn new(inner: FS) -> XattrRouter<FS> {
+        XattrRouter { inner, handlers: HashMap::new() }
+    }
+
+    /// Handle the attributes of the given namespace with the given handler
+    pub fn namespace<H: XattrHandler>(mut self, namespace: XattrNamespace, handler: H) -> XattrRouter<FS> {
+        self.handlers.insert(namespace, Box::new(handler));
+        self
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    /// Returns the handler of the attribute of the given name
+    fn handler(&self, name: &OsStr) -> Result<&dyn XattrHandler, Errno> {
+        XattrNamespace::of(name).and_then(|namespace| self.handlers.get(&namespace)).map(|handler| &**handler).ok_or(Errno::EOPNOTSUPP)
+    }
+}
+
+impl<FS: fmt::Debug> fmt::Debug for XattrRouter<FS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("XattrRouter").field("inner", &self.inner).field("namespaces", &self.handlers.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem + Send + Sync> Wrapper for XattrRouter<FS> {
+    type Inner = FS;
+
+    fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    fn operations(&self) -> OperationSet {
+        self.inner.operations() | OperationSet::SETXATTR | OperationSet::GETXATTR | OperationSet::LISTXATTR | OperationSet::REMOVEXATTR
+    }
+
+    async fn setxattr(&self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32) -> Result<(), Errno> {
+        self.handler(name)?.set(req, ino, name, value, flags).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr, _size: u32) -> Result<Xattr, Errno> {
+        Ok(Xattr::Data(self.handler(name)?.get(req, ino, name).await?))
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64, _size: u32) -> Result<Xattr, Errno> {
+        let mut names = Vec::new();
+        for handler in self.handlers.values() {
+            names.extend(handler.list(req, ino).await?);
+        }
+        names.sort();
+        Ok(Xattr::names(names))
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        self.handler(name)?.remove(req, ino, name).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::{block_on, Errno, Filesystem, Request, Xattr};
+    use super::{XattrHandler, XattrNamespace, XattrRouter};
+
+    #[test]
+    fn namespaces() {
+        assert_eq!(XattrNamespace::of(OsStr::new("user.foo")), Some(XattrNamespace::User));
+        assert_eq!(XattrNamespace::of(OsStr::new("security.selinux")), Some(XattrNamespace::Security));
+        assert_eq!(XattrNamespace::of(OsStr::new("system.posix_acl_access")), Some(XattrNamespace::PosixAcl));
+        assert_eq!(XattrNamespace::of(OsStr::new("system.nfs4_acl")), Some(XattrNamespace::System));
+        assert_eq!(XattrNamespace::of(OsStr::new("trusted.overlay.opaque")), Some(XattrNamespace::Trusted));
+        assert_eq!(XattrNamespace::of(OsStr::new("foo")), None);
+        assert_eq!(XattrNamespace::of(OsStr::new("user")), None);
+    }
+
+    struct Empty;
+
+    #[async_trait]
+    impl Filesystem for Empty {}
+
+    /// Attributes kept in memory
+    #[derive(Default)]
+    struct Memory(Mutex<HashMap<OsString, Vec<u8>>>);
+
+    #[async_trait]
+    impl XattrHandler for Memory {
+        async fn get(&self, _req: &Request, _ino: u64, name: &OsStr) -> Result<Vec<u8>, Errno> {
+            self.0.lock().unwrap().get(name).cloned().ok_or(Errno::ENODATA)
+        }
+
+        async fn set(&self, _req: &Request, _ino: u64, name: &OsStr, value: &[u8], _flags: u32) -> Result<(), Errno> {
+            self.0.lock().unwrap().insert(name.to_os_string(), value.to_vec());
+            Ok(())
+        }
+
+        async fn list(&self, _req: &Request, _ino: u64) -> Result<Vec<OsString>, Errno> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    /// Security label of every file
+    struct Label;
+
+    #[async_trait]
+    impl XattrHandler for Label {
+        async fn get(&self, _req: &Request, _ino: u64, name: &OsStr) -> Result<Vec<u8>, Errno> {
+            match name.to_str() {
+                Some("security.selinux") => Ok(b"system_u:object_r:fusefs_t:s0".to_vec()),
+                _ => Err(Errno::ENODATA),
+            }
+        }
+
+        async fn list(&self, _req: &Request, _ino: u64) -> Result<Vec<OsString>, Errno> {
+            Ok(vec!["security.selinux".into()])
+        }
+    }
+
+    #[test]
+    fn routes() {
+        let req = Request::test(0, 0);
+        let fs = XattrRouter::new(Empty).namespace(XattrNamespace::User, Memory::default()).namespace(XattrNamespace::Security, Label);
+        let name = |name| OsStr::new(name);
+        assert_eq!(block_on(Filesystem::setxattr(&fs, &req, 2, name("user.foo"), b"bar", 0, 0)), Ok(()));
+        assert_eq!(block_on(Filesystem::getxattr(&fs, &req, 2, name("user.foo"), 0)), Ok(Xattr::Data(b"bar".to_vec())));
+        assert_eq!(block_on(Filesystem::getxattr(&fs, &req, 2, name("user.baz"), 0)), Err(Errno::ENODATA));
+        assert!(block_on(Filesystem::getxattr(&fs, &req, 2, name("security.selinux"), 0)).is_ok());
+        assert_eq!(block_on(Filesystem::setxattr(&fs, &req, 2, name("security.selinux"), b"", 0, 0)), Err(Errno::EOPNOTSUPP));
+        assert_eq!(block_on(Filesystem::listxattr(&fs, &req, 2, 0)), Ok(Xattr::names(["security.selinux", "user.foo"])));
+        // Namespaces without handler and unknown namespaces aren't supported
+        assert_eq!(block_on(Filesystem::getxattr(&fs, &req, 2, name("trusted.foo"), 0)), Err(Errno::EOPNOTSUPP));
+        assert_eq!(block_on(Filesystem::getxattr(&fs, &req, 2, name("system.posix_acl_access"), 0)), Err(Errno::EOPNOTSUPP));
+        assert_eq!(block_on(Filesystem::removexattr(&fs, &req, 2, name("foo"))), Err(Errno::EOPNOTSUPP));
+    }
+}